@@ -0,0 +1,170 @@
+//! Call the gRPC service of the daemon from node, over [`talpid_ipc`].
+//!
+//! Calls run on the Tokio runtime of napi-rs, so they never block the event loop. Requests and
+//! responses are passed as buffers with encoded protobuf messages, which the caller encodes and
+//! decodes with the generated classes of `management_interface.proto`. Methods are named by
+//! their path, e.g. `/mullvad_daemon.management_interface.ManagementService/GetDevice`.
+
+use bytes::Bytes;
+use futures::StreamExt;
+use napi::{
+    JsFunction, Status,
+    bindgen_prelude::Buffer,
+    threadsafe_function::{
+        ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+    },
+    tokio::task::JoinHandle,
+};
+use napi_derive::napi;
+use std::{sync::Mutex, time::Duration};
+use talpid_ipc::Endpoint;
+use talpid_ipc_ffi::grpc::{self, GrpcClient};
+
+/// Turn a failed call into a JavaScript error, whose message starts with the name of the gRPC
+/// status code, e.g. `Unavailable`.
+fn to_error(status: tonic::Status) -> napi::Error {
+    napi::Error::new(
+        Status::GenericFailure,
+        format!("{:?}: {}", status.code(), status.message()),
+    )
+}
+
+fn method_path(method: &str) -> napi::Result<tonic::codegen::http::uri::PathAndQuery> {
+    grpc::method_path(method).ok_or_else(|| {
+        napi::Error::new(
+            Status::InvalidArg,
+            format!("Not the path of a method: {method}"),
+        )
+    })
+}
+
+fn timeout(timeout_ms: Option<u32>) -> Option<Duration> {
+    timeout_ms.map(|timeout| Duration::from_millis(u64::from(timeout)))
+}
+
+/// A connection to the daemon. It is closed by [`IpcClient::close`], or when it is garbage
+/// collected.
+#[napi]
+pub struct IpcClient {
+    client: Mutex<Option<GrpcClient>>,
+}
+
+impl IpcClient {
+    fn get(&self) -> napi::Result<GrpcClient> {
+        self.client
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| to_error(tonic::Status::cancelled("The client has been closed")))
+    }
+}
+
+/// Connect to the daemon at `path`, i.e. a socket path or pipe name. If a timeout is given,
+/// keep trying while nothing is listening yet, see [`Endpoint::connect_when_ready`].
+#[napi]
+pub async fn connect(path: String, timeout_ms: Option<u32>) -> napi::Result<IpcClient> {
+    // The channel connects by itself, this only waits for the daemon to listen
+    if let Some(timeout) = timeout(timeout_ms) {
+        Endpoint::connect_when_ready(&path, timeout)
+            .await
+            .map_err(|error| to_error(tonic::Status::unavailable(error.to_string())))?;
+    }
+    let client = GrpcClient::connect(&path).await.map_err(to_error)?;
+    Ok(IpcClient {
+        client: Mutex::new(Some(client)),
+    })
+}
+
+#[napi]
+impl IpcClient {
+    /// Call the method at the path `method` with the encoded message `request`, and resolve
+    /// with the encoded response.
+    #[napi]
+    pub async fn call(
+        &self,
+        method: String,
+        request: Buffer,
+        timeout_ms: Option<u32>,
+    ) -> napi::Result<Buffer> {
+        let path = method_path(&method)?;
+        let client = self.get()?;
+        let request = Bytes::from(Vec::from(request));
+        let response = client
+            .call(path, request, timeout(timeout_ms))
+            .await
+            .map_err(to_error)?;
+        Ok(Buffer::from(response.to_vec()))
+    }
+
+    /// Call the method at the path `method`, which streams its responses, and call `callback`
+    /// with each of them, or with the error that ends the call.
+    #[napi(
+        ts_args_type = "method: string, request: Buffer, callback: (error: Error | null, response: Buffer | null) => void"
+    )]
+    pub fn subscribe(
+        &self,
+        method: String,
+        request: Buffer,
+        callback: JsFunction,
+    ) -> napi::Result<Subscription> {
+        let path = method_path(&method)?;
+        let client = self.get()?;
+        let request = Bytes::from(Vec::from(request));
+        let callback: ThreadsafeFunction<Vec<u8>, ErrorStrategy::CalleeHandled> = callback
+            .create_threadsafe_function(0, |cx: ThreadSafeCallContext<Vec<u8>>| {
+                Ok(vec![Buffer::from(cx.value)])
+            })?;
+
+        let task = napi::tokio::spawn(async move {
+            let mut responses = match client.subscribe(path, request).await {
+                Ok(responses) => responses,
+                Err(status) => {
+                    callback.call(
+                        Err(to_error(status)),
+                        ThreadsafeFunctionCallMode::NonBlocking,
+                    );
+                    return;
+                }
+            };
+            while let Some(response) = responses.next().await {
+                let response = response.map(|response| response.to_vec()).map_err(to_error);
+                let failed = response.is_err();
+                callback.call(response, ThreadsafeFunctionCallMode::NonBlocking);
+                if failed {
+                    return;
+                }
+            }
+        });
+        Ok(Subscription {
+            task: Mutex::new(Some(task)),
+        })
+    }
+
+    /// Close the connection. Later calls fail, but calls that are outstanding are still
+    /// answered.
+    #[napi]
+    pub fn close(&self) {
+        self.client.lock().unwrap().take();
+    }
+}
+
+/// A call started by [`IpcClient::subscribe`].
+#[napi]
+pub struct Subscription {
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[napi]
+impl Subscription {
+    /// Cancel the call. Returns `true` when called the first time, and `false` afterwards.
+    #[napi]
+    pub fn stop(&self) -> bool {
+        match self.task.lock().unwrap().take() {
+            Some(task) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,79 @@
+// This module is the CJS entry point for the library.
+
+// The Rust addon.
+import * as addon from './load.cjs';
+
+declare const clientBrand: unique symbol;
+
+/** Connection to the daemon, owned by the addon. */
+type ClientHandle = {
+  readonly [clientBrand]: never;
+  call(method: string, request: Uint8Array, timeoutMs?: number): Promise<Buffer>;
+  subscribe(
+    method: string,
+    request: Uint8Array,
+    cb: (error: Error | null, response: Buffer | null) => void,
+  ): { stop(): boolean };
+  close(): void;
+};
+
+// Use this declaration to assign types to the addon's exports,
+// which otherwise by default are `any`.
+declare module './load.cjs' {
+  function connect(path: string, timeoutMs?: number): Promise<ClientHandle>;
+}
+
+/**
+ * Connection to the gRPC service of the daemon. Requests and responses are encoded protobuf
+ * messages, e.g. from `serializeBinary()` of the generated classes, and methods are named by
+ * their path, e.g. `/mullvad_daemon.management_interface.ManagementService/GetDevice`.
+ */
+export class IpcClient {
+  private constructor(private readonly handle: ClientHandle) {}
+
+  /**
+   * Connect to the daemon at `path`.
+   * @param path socket path or pipe name of the daemon.
+   * @param timeoutMs if given, keep trying for this long while nothing is listening yet.
+   */
+  public static async connect(path: string, timeoutMs?: number): Promise<IpcClient> {
+    return new IpcClient(await addon.connect(path, timeoutMs));
+  }
+
+  /**
+   * Call a method and wait for the response. Rejects if the daemon failed to handle the
+   * request, if the connection failed, or if `timeoutMs` passes first. The message of the
+   * error starts with the name of the gRPC status code.
+   */
+  public call(method: string, request: Uint8Array, timeoutMs?: number): Promise<Uint8Array> {
+    return this.handle.call(method, request, timeoutMs);
+  }
+
+  /**
+   * Call a method that streams its responses, e.g. `EventsListen`, and call `cb` with each of
+   * them. `onError` is called with the error that ends the call, if any.
+   * @returns a function that stops the call, and returns whether it was still active.
+   */
+  public subscribe(
+    method: string,
+    request: Uint8Array,
+    cb: (response: Uint8Array) => void,
+    onError?: (error: Error) => void,
+  ): () => boolean {
+    const subscription = this.handle.subscribe(method, request, (error, response) => {
+      if (error) {
+        onError?.(error);
+      } else if (response) {
+        cb(response);
+      }
+    });
+    return () => subscription.stop();
+  }
+
+  /**
+   * Close the connection. Later calls are rejected, but outstanding calls are still answered.
+   */
+  public close(): void {
+    this.handle.close();
+  }
+}
@@ -0,0 +1,26 @@
+// This module loads the platform-specific build of the addon on
+// the current system, or the debug build if there is none.
+
+/* eslint-disable @typescript-eslint/no-require-imports */
+import * as fs from 'fs';
+import * as path from 'path';
+
+const platforms: Record<string, string> = {
+  'linux-x64': 'linux-x64-gnu',
+  'linux-arm64': 'linux-arm64-gnu',
+  'darwin-x64': 'darwin-x64',
+  'darwin-arm64': 'darwin-arm64',
+  'win32-x64': 'win32-x64-msvc',
+  'win32-arm64': 'win32-arm64-msvc',
+};
+
+function addonPath(): string {
+  const platform = platforms[`${process.platform}-${process.arch}`];
+  const release = platform && path.join(__dirname, '..', 'dist', platform, 'index.node');
+  if (release && fs.existsSync(release)) {
+    return release;
+  }
+  return path.join(__dirname, '..', 'debug', 'index.node');
+}
+
+module.exports = require(addonPath());
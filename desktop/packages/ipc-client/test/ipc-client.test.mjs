@@ -0,0 +1,93 @@
+// Tests the addon against a gRPC server that repeats requests. They are skipped unless the addon
+// has been built, e.g. with `npm run build-debug`.
+
+import grpc from '@grpc/grpc-js';
+import fs from 'fs';
+import os from 'os';
+import path from 'path';
+import { after, before, describe, it } from 'node:test';
+import assert from 'node:assert/strict';
+import { fileURLToPath } from 'url';
+
+const packageDir = path.join(path.dirname(fileURLToPath(import.meta.url)), '..');
+const built =
+  fs.existsSync(path.join(packageDir, 'debug', 'index.node')) ||
+  fs.existsSync(path.join(packageDir, 'dist'));
+
+const passThrough = (buffer) => buffer;
+const method = (name, responseStream) => ({
+  path: `/talpid_ipc.test.Test/${name}`,
+  requestStream: false,
+  responseStream,
+  requestSerialize: passThrough,
+  requestDeserialize: passThrough,
+  responseSerialize: passThrough,
+  responseDeserialize: passThrough,
+});
+
+describe('IpcClient', { skip: !built || process.platform === 'win32' }, () => {
+  let IpcClient;
+  let server;
+  let dir;
+  let socket;
+
+  before(async () => {
+    ({ IpcClient } = await import('../lib/index.mjs'));
+    dir = fs.mkdtempSync(path.join(os.tmpdir(), 'ipc-client-'));
+    socket = path.join(dir, 'socket');
+    server = new grpc.Server();
+    server.addService(
+      { repeat: method('Repeat', false), events: method('Events', true) },
+      {
+        repeat: (call, callback) => {
+          if (call.request.length === 0) {
+            callback({ code: grpc.status.INVALID_ARGUMENT, details: 'Nothing to repeat' });
+          } else {
+            callback(null, Buffer.concat([call.request, call.request]));
+          }
+        },
+        events: (call) => call.write(Buffer.from('ready')),
+      },
+    );
+    await new Promise((resolve, reject) =>
+      server.bindAsync(`unix:${socket}`, grpc.ServerCredentials.createInsecure(), (error) =>
+        error ? reject(error) : resolve(),
+      ),
+    );
+  });
+
+  after(() => {
+    server.forceShutdown();
+    fs.rmSync(dir, { recursive: true, force: true });
+  });
+
+  it('calls methods', async () => {
+    const client = await IpcClient.connect(socket);
+    const response = await client.call('/talpid_ipc.test.Test/Repeat', Buffer.from('ab'));
+    assert.equal(Buffer.from(response).toString(), 'abab');
+    await assert.rejects(client.call('/talpid_ipc.test.Test/Repeat', Buffer.alloc(0)), {
+      message: /^InvalidArgument: Nothing to repeat/,
+    });
+    await assert.rejects(client.call('Repeat', Buffer.from('ab')));
+    client.close();
+    await assert.rejects(client.call('/talpid_ipc.test.Test/Repeat', Buffer.from('ab')));
+  });
+
+  it('streams responses', async () => {
+    const client = await IpcClient.connect(socket);
+    const response = await new Promise((resolve, reject) => {
+      const stop = client.subscribe(
+        '/talpid_ipc.test.Test/Events',
+        Buffer.alloc(0),
+        (response) => {
+          assert.equal(stop(), true);
+          assert.equal(stop(), false);
+          resolve(response);
+        },
+        reject,
+      );
+    });
+    assert.equal(Buffer.from(response).toString(), 'ready');
+    client.close();
+  });
+});
@@ -174,9 +174,6 @@ fn get_log_dir(config: &cli::Config) -> Result<Option<PathBuf>, String> {
 }
 
 async fn run_standalone(log_dir: Option<PathBuf>) -> Result<(), String> {
-    #[cfg(not(windows))]
-    cleanup_old_rpc_socket(mullvad_paths::get_rpc_socket_path()).await;
-
     if !running_as_admin() {
         log::warn!("Running daemon as a non-administrator user, clients might refuse to connect");
     }
@@ -1,21 +1,10 @@
 pub mod client;
 pub mod types;
 
-use parity_tokio_ipc::Endpoint as IpcEndpoint;
 #[cfg(unix)]
 use std::{env, fs, os::unix::fs::PermissionsExt};
-use std::{
-    future::Future,
-    io,
-    pin::Pin,
-    task::{Context, Poll},
-};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-#[cfg(not(target_os = "android"))]
-use tonic::transport::{Endpoint, Uri};
-use tonic::transport::{Server, server::Connected};
-#[cfg(not(target_os = "android"))]
-use tower::service_fn;
+use std::{future::Future, io};
+use talpid_ipc::Endpoint as IpcEndpoint;
 
 pub use tonic::{Code, Request, Response, Status, async_trait, transport::Channel};
 
@@ -29,6 +18,15 @@ use std::sync::LazyLock;
 static MULLVAD_MANAGEMENT_SOCKET_GROUP: LazyLock<Option<String>> =
     LazyLock::new(|| env::var("MULLVAD_MANAGEMENT_SOCKET_GROUP").ok());
 
+/// Number of named pipe instances that wait for clients at the same time.
+#[cfg(windows)]
+const PENDING_PIPE_INSTANCES: usize = 4;
+
+/// Name of the management interface socket in the `Sockets` dictionary of the launch daemon
+/// plist, if the daemon is socket-activated by launchd.
+#[cfg(target_os = "macos")]
+const LAUNCHD_SOCKET_NAME: &str = "ManagementInterface";
+
 pub const CUSTOM_LIST_LIST_NOT_FOUND_DETAILS: &[u8] = b"custom_list_list_not_found";
 pub const CUSTOM_LIST_LIST_EXISTS_DETAILS: &[u8] = b"custom_list_list_exists";
 pub const CUSTOM_LIST_LIST_NAME_TOO_LONG_DETAILS: &[u8] = b"custom_list_list_name_too_long";
@@ -122,21 +120,20 @@ impl From<tonic::Status> for Error {
     }
 }
 
+impl From<talpid_ipc::grpc::Error> for Error {
+    fn from(error: talpid_ipc::grpc::Error) -> Self {
+        match error {
+            talpid_ipc::grpc::Error::Listen(error) => Error::StartServerError(error),
+            talpid_ipc::grpc::Error::Transport(error) => Error::GrpcTransportError(error),
+        }
+    }
+}
+
 #[cfg(not(target_os = "android"))]
 #[deprecated(note = "Prefer MullvadProxyClient")]
 pub async fn new_rpc_client() -> Result<ManagementServiceClient, Error> {
-    use futures::TryFutureExt;
-
     let ipc_path = mullvad_paths::get_rpc_socket_path();
-
-    // The URI will be ignored
-    let channel = Endpoint::from_static("lttp://[::]:50051")
-        .connect_with_connector(service_fn(move |_: Uri| {
-            IpcEndpoint::connect(ipc_path.clone()).map_ok(hyper_util::rt::tokio::TokioIo::new)
-        }))
-        .await
-        .map_err(Error::GrpcTransportError)?;
-
+    let channel = talpid_ipc::grpc::connect(ipc_path).await?;
     Ok(ManagementServiceClient::new(channel))
 }
 
@@ -145,13 +142,39 @@ pub use client::MullvadProxyClient;
 
 pub type ServerJoinHandle = tokio::task::JoinHandle<()>;
 
+/// Keep serving when accepting a connection fails temporarily, e.g. because the daemon is out of
+/// file descriptors. The server would otherwise stop at the first error.
+const ACCEPT_ERROR_POLICY: talpid_ipc::accept::AcceptErrorPolicy =
+    talpid_ipc::accept::AcceptErrorPolicy::RetryWithBackoff {
+        initial: std::time::Duration::from_millis(10),
+        max: std::time::Duration::from_secs(1),
+    };
+
+/// Most that a client can make the daemon buffer for a connection. Requests are small, so this
+/// only stops clients that send far more than they should.
+const CONNECTION_MEMORY_LIMIT: usize = 1024 * 1024;
+
 pub fn spawn_rpc_server<T: ManagementService, F: Future<Output = ()> + Send + 'static>(
     service: T,
     abort_rx: F,
     rpc_socket_path: impl AsRef<std::path::Path>,
 ) -> std::result::Result<ServerJoinHandle, Error> {
-    use futures::stream::TryStreamExt;
-    use parity_tokio_ipc::SecurityAttributes;
+    use talpid_ipc::SecurityAttributes;
+
+    // When socket-activated, the permissions of the socket are managed by the service manager
+    #[cfg(target_os = "linux")]
+    let activated = IpcEndpoint::from_systemd().map_err(Error::StartServerError)?;
+    #[cfg(target_os = "macos")]
+    let activated =
+        IpcEndpoint::from_launchd(LAUNCHD_SOCKET_NAME).map_err(Error::StartServerError)?;
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    if let Some(mut endpoint) = activated {
+        endpoint.set_accept_error_policy(ACCEPT_ERROR_POLICY);
+        endpoint.set_memory_limit(Some(CONNECTION_MEMORY_LIMIT));
+        endpoint.set_liveness_responder(true);
+        let incoming = endpoint.incoming().map_err(Error::StartServerError)?;
+        return Ok(serve_rpc(service, incoming, abort_rx));
+    }
 
     let mut endpoint = IpcEndpoint::new(rpc_socket_path.as_ref().to_string_lossy().to_string());
     endpoint.set_security_attributes(
@@ -160,6 +183,27 @@ pub fn spawn_rpc_server<T: ManagementService, F: Future<Output = ()> + Send + 's
             .set_mode(0o766)
             .map_err(Error::SecurityAttributes)?,
     );
+    // The GUI, the CLI and the tray icon may all connect at the same time on startup
+    #[cfg(windows)]
+    endpoint.set_pending_pipe_instances(PENDING_PIPE_INSTANCES);
+    endpoint.set_accept_error_policy(ACCEPT_ERROR_POLICY);
+    endpoint.set_memory_limit(Some(CONNECTION_MEMORY_LIMIT));
+    // Lets watchdogs check that the daemon answers, see `talpid_ipc::health::check_liveness`.
+    // gRPC clients start with the HTTP/2 preface, which never looks like a probe.
+    endpoint.set_liveness_responder(true);
+    // A daemon that crashed leaves its socket behind, which is replaced once it is clear that
+    // the daemon is gone
+    #[cfg(unix)]
+    let incoming = {
+        let (incoming, outcome) = endpoint
+            .bind_or_takeover()
+            .map_err(Error::StartServerError)?;
+        if let talpid_ipc::takeover::BindOutcome::TookOver(shutdown) = outcome {
+            log::warn!("{shutdown}");
+        }
+        incoming
+    };
+    #[cfg(windows)]
     let incoming = endpoint.incoming().map_err(Error::StartServerError)?;
 
     #[cfg(unix)]
@@ -173,51 +217,25 @@ pub fn spawn_rpc_server<T: ManagementService, F: Future<Output = ()> + Send + 's
             .map_err(Error::PermissionsError)?;
     }
 
-    Ok(tokio::spawn(async move {
-        if let Err(execution_error) = Server::builder()
-            .add_service(ManagementServiceServer::new(service))
-            .serve_with_incoming_shutdown(incoming.map_ok(StreamBox), abort_rx)
-            .await
-            .map_err(Error::GrpcTransportError)
+    Ok(serve_rpc(service, incoming, abort_rx))
+}
+
+fn serve_rpc<T: ManagementService, F: Future<Output = ()> + Send + 'static>(
+    service: T,
+    incoming: talpid_ipc::Incoming,
+    abort_rx: F,
+) -> ServerJoinHandle {
+    tokio::spawn(async move {
+        if let Err(execution_error) = talpid_ipc::grpc::serve_incoming(
+            incoming,
+            ManagementServiceServer::new(service),
+            abort_rx,
+        )
+        .await
+        .map_err(Error::from)
         {
             log::error!("Management server panic: {execution_error}");
         }
         log::trace!("gRPC server is shutting down");
-    }))
-}
-
-#[derive(Debug)]
-struct StreamBox<T: AsyncRead + AsyncWrite>(pub T);
-impl<T: AsyncRead + AsyncWrite> Connected for StreamBox<T> {
-    type ConnectInfo = Option<()>;
-
-    fn connect_info(&self) -> Self::ConnectInfo {
-        None
-    }
-}
-impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for StreamBox<T> {
-    fn poll_read(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &mut ReadBuf<'_>,
-    ) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.0).poll_read(cx, buf)
-    }
-}
-impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for StreamBox<T> {
-    fn poll_write(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &[u8],
-    ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.0).poll_write(cx, buf)
-    }
-
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.0).poll_flush(cx)
-    }
-
-    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.0).poll_shutdown(cx)
-    }
+    })
 }
@@ -4,6 +4,7 @@ pub use dbus;
 use dbus::blocking::SyncConnection;
 use std::sync::{Arc, LazyLock, Mutex};
 pub mod network_manager;
+pub mod polkit;
 pub mod systemd;
 pub mod systemd_resolved;
 
@@ -0,0 +1,147 @@
+//! Authorization checks through polkit (`org.freedesktop.PolicyKit1`).
+use dbus::{
+    arg::{self, PropMap, Variant},
+    blocking::{Proxy, SyncConnection},
+};
+use std::{
+    collections::HashMap,
+    io,
+    os::fd::{IntoRawFd, OwnedFd},
+    sync::Arc,
+    time::Duration,
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to create a DBus connection")]
+    ConnectError(#[source] dbus::Error),
+
+    #[error("Failed to check authorization")]
+    CheckAuthorizationError(#[source] dbus::Error),
+
+    #[error("Failed to duplicate the pidfd of the process")]
+    DuplicatePidfdError(#[source] io::Error),
+}
+
+const POLKIT_BUS: &str = "org.freedesktop.PolicyKit1";
+const POLKIT_PATH: &str = "/org/freedesktop/PolicyKit1/Authority";
+const AUTHORITY_INTERFACE: &str = "org.freedesktop.PolicyKit1.Authority";
+const CHECK_AUTHORIZATION_METHOD: &str = "CheckAuthorization";
+
+/// `CheckAuthorizationFlags::AllowUserInteraction`
+const ALLOW_USER_INTERACTION: u32 = 0x1;
+
+const RPC_TIMEOUT: Duration = Duration::from_secs(5);
+/// The user may take a while to authenticate with the agent.
+const INTERACTIVE_RPC_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// A process to check the authorization of.
+#[derive(Debug)]
+pub struct UnixProcess {
+    pub pid: u32,
+    /// Start time of the process, in clock ticks since boot, as found in `/proc/<pid>/stat`.
+    /// Together with the PID, this identifies the process even if the PID is reused.
+    pub start_time: u64,
+    pub uid: u32,
+    /// A pidfd of the process. polkit 121 and later identify the process by it, which cannot
+    /// come to refer to another process at all. Older versions ignore it, and fall back to
+    /// the PID and start time.
+    pub pidfd: Option<OwnedFd>,
+}
+
+/// Returns whether `process` is authorized to perform the polkit action `action_id`. If
+/// `allow_user_interaction` is set, the authentication agent may ask the user to authenticate,
+/// in which case this blocks until they have done so.
+pub fn check_authorization(
+    process: &UnixProcess,
+    action_id: &str,
+    allow_user_interaction: bool,
+) -> Result<bool> {
+    Polkit::new()?.check_authorization(process, action_id, allow_user_interaction)
+}
+
+struct Polkit {
+    pub dbus_connection: Arc<SyncConnection>,
+}
+
+impl Polkit {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            dbus_connection: crate::get_connection().map_err(Error::ConnectError)?,
+        })
+    }
+
+    fn check_authorization(
+        &self,
+        process: &UnixProcess,
+        action_id: &str,
+        allow_user_interaction: bool,
+    ) -> Result<bool> {
+        let subject = ("unix-process", subject_details(process)?);
+
+        let (flags, timeout) = if allow_user_interaction {
+            (ALLOW_USER_INTERACTION, INTERACTIVE_RPC_TIMEOUT)
+        } else {
+            (0, RPC_TIMEOUT)
+        };
+        let details: HashMap<&str, &str> = HashMap::new();
+        let cancellation_id = "";
+
+        let ((is_authorized, _is_challenge, _details),): ((bool, bool, HashMap<String, String>),) =
+            self.as_authority_object(timeout)
+                .method_call(
+                    AUTHORITY_INTERFACE,
+                    CHECK_AUTHORIZATION_METHOD,
+                    (subject, action_id, details, flags, cancellation_id),
+                )
+                .map_err(Error::CheckAuthorizationError)?;
+        Ok(is_authorized)
+    }
+
+    fn as_authority_object(&self, timeout: Duration) -> Proxy<'_, &SyncConnection> {
+        Proxy::new(POLKIT_BUS, POLKIT_PATH, timeout, &self.dbus_connection)
+    }
+}
+
+/// Details of the `unix-process` subject that identifies `process`.
+fn subject_details(process: &UnixProcess) -> Result<PropMap> {
+    let mut details = PropMap::new();
+    details.insert("pid".to_owned(), Variant(Box::new(process.pid)));
+    details.insert(
+        "start-time".to_owned(),
+        Variant(Box::new(process.start_time)),
+    );
+    details.insert("uid".to_owned(), Variant(Box::new(process.uid as i32)));
+    if let Some(pidfd) = &process.pidfd {
+        let pidfd = pidfd.try_clone().map_err(Error::DuplicatePidfdError)?;
+        // SAFETY: The descriptor was just duplicated, and is owned by nothing else
+        let pidfd = unsafe { arg::OwnedFd::new(pidfd.into_raw_fd()) };
+        details.insert("pidfd".to_owned(), Variant(Box::new(pidfd)));
+    }
+    Ok(details)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_subject_details() {
+        let mut process = UnixProcess {
+            pid: std::process::id(),
+            start_time: 1234,
+            uid: 1000,
+            pidfd: None,
+        };
+        let details = subject_details(&process).unwrap();
+        assert_eq!(arg::prop_cast::<u32>(&details, "pid"), Some(&process.pid));
+        assert_eq!(arg::prop_cast::<u64>(&details, "start-time"), Some(&1234));
+        assert_eq!(arg::prop_cast::<i32>(&details, "uid"), Some(&1000));
+        assert!(!details.contains_key("pidfd"));
+
+        process.pidfd = Some(OwnedFd::from(std::fs::File::open("/dev/null").unwrap()));
+        assert!(subject_details(&process).unwrap().contains_key("pidfd"));
+    }
+}
@@ -0,0 +1,121 @@
+//! Round-trip latency and throughput of frames on each backend, against an echo server, and
+//! the cost of accepting connections.
+//!
+//! Run with `cargo bench -p talpid-ipc --features echo,testing`, and add `tcp` to include
+//! loopback TCP.
+//! Compare against a saved baseline with `-- --save-baseline <name>` and `-- --baseline <name>`
+//! to catch regressions in the framing or the transports.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use futures::StreamExt;
+use talpid_ipc::{
+    Endpoint,
+    echo::{EchoClient, EchoServer, echo},
+    frame::{Frame, FramedConnection},
+    testing::EphemeralPath,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    runtime::Runtime,
+};
+
+/// Payload sizes to measure. Small frames measure latency, and large ones throughput.
+const PAYLOAD_LENS: [usize; 4] = [16, 4 * 1024, 64 * 1024, 1024 * 1024];
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn bench_round_trips<T>(
+    c: &mut Criterion,
+    backend: &str,
+    runtime: &Runtime,
+    mut client: EchoClient<T>,
+) where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut group = c.benchmark_group(backend);
+    for len in PAYLOAD_LENS {
+        let frame = Frame::data(vec![0u8; len]);
+        // The payload is transferred once in each direction
+        group.throughput(Throughput::Bytes(2 * len as u64));
+        group.bench_with_input(BenchmarkId::new("round_trip", len), &frame, |b, frame| {
+            b.iter(|| runtime.block_on(client.round_trip(frame)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// In-memory pipe, as a baseline for the cost of the framing alone.
+fn duplex(c: &mut Criterion) {
+    let runtime = runtime();
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    runtime.spawn(echo(FramedConnection::new(server)));
+    bench_round_trips(c, "duplex", &runtime, EchoClient::new(client));
+}
+
+/// Unix domain socket or named pipe.
+fn endpoint(c: &mut Criterion) {
+    let runtime = runtime();
+    let ephemeral = EphemeralPath::new().unwrap();
+    let path = ephemeral.path();
+
+    let (_server, client) = runtime.block_on(async {
+        let incoming = Endpoint::new(path.to_owned()).incoming().unwrap();
+        let server = EchoServer::spawn(incoming);
+        (server, Endpoint::connect(path).await.unwrap())
+    });
+    bench_round_trips(c, "endpoint", &runtime, EchoClient::new(client));
+}
+
+/// Connecting to and accepting on a Unix domain socket or named pipe, as when several frontends
+/// reconnect at once. Each connection sends a frame, so that the buffers of both ends are used.
+fn accept(c: &mut Criterion) {
+    let runtime = runtime();
+    let ephemeral = EphemeralPath::new().unwrap();
+    let path = ephemeral.path();
+
+    let mut incoming = runtime
+        .block_on(async { Endpoint::new(path.to_owned()).incoming() })
+        .unwrap();
+    let frame = Frame::data(&b"hello"[..]);
+    c.bench_function("accept", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let (client, server) = tokio::join!(Endpoint::connect(path), incoming.next());
+                let mut client = FramedConnection::new(client.unwrap());
+                let mut server = FramedConnection::new(server.unwrap().unwrap());
+                client.write_frame(&frame).await.unwrap();
+                server.read_frame().await.unwrap().unwrap();
+            })
+        })
+    });
+}
+
+/// Loopback TCP, as accepted next to the endpoint.
+#[cfg(feature = "tcp")]
+fn tcp(c: &mut Criterion) {
+    use std::net::Ipv4Addr;
+    use talpid_ipc::tcp::{self, AuthToken, TcpIncoming};
+
+    let runtime = runtime();
+    let token = AuthToken::generate();
+    let (_server, client) = runtime.block_on(async {
+        let incoming = TcpIncoming::bind((Ipv4Addr::LOCALHOST, 0).into(), token.clone())
+            .await
+            .unwrap();
+        let address = incoming.local_addr().unwrap();
+        let server = EchoServer::spawn(incoming);
+        (server, tcp::connect(address, &token).await.unwrap())
+    });
+    bench_round_trips(c, "tcp", &runtime, EchoClient::new(client));
+}
+
+#[cfg(not(feature = "tcp"))]
+criterion_group!(benches, duplex, endpoint, accept);
+#[cfg(feature = "tcp")]
+criterion_group!(benches, duplex, endpoint, accept, tcp);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+fn main() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    // Generated files must not be written to the source tree
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    println!("cargo::rerun-if-changed=src");
+    cbindgen::Builder::new()
+        .with_autogen_warning("// This file is generated by the build script of talpid-ipc-ffi.")
+        .with_crate(&crate_dir)
+        .with_language(cbindgen::Language::C)
+        .with_include_guard("TALPID_IPC_H")
+        .generate()
+        .expect("failed to generate bindings")
+        .write_to_file(format!("{out_dir}/talpid_ipc.h"));
+}
@@ -0,0 +1,231 @@
+//! Calls to the gRPC service of the daemon, with messages that are already encoded.
+//!
+//! The bindings do not know the messages of the daemon. Callers encode them with the protobuf
+//! library of their language, from `management_interface.proto`, and name the method that they
+//! call by its path, e.g. `/mullvad_daemon.management_interface.ManagementService/GetDevice`.
+
+use bytes::{Buf, BufMut, Bytes};
+use futures::{StreamExt, TryFutureExt, stream::BoxStream};
+use std::{path::Path, time::Duration};
+use tonic::{
+    Request, Status,
+    client::Grpc,
+    codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder},
+    codegen::http::uri::PathAndQuery,
+    transport::Channel,
+};
+
+/// Messages of an open call, see [`GrpcClient::subscribe`].
+pub type MessageStream = BoxStream<'static, Result<Bytes, Status>>;
+
+/// Passes encoded messages through as they are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawCodec;
+
+impl Codec for RawCodec {
+    type Encode = Bytes;
+    type Decode = Bytes;
+    type Encoder = RawCodec;
+    type Decoder = RawCodec;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        RawCodec
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        RawCodec
+    }
+}
+
+impl Encoder for RawCodec {
+    type Item = Bytes;
+    type Error = Status;
+
+    fn encode(&mut self, item: Bytes, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        dst.put(item);
+        Ok(())
+    }
+}
+
+impl Decoder for RawCodec {
+    type Item = Bytes;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<Bytes>, Status> {
+        Ok(Some(src.copy_to_bytes(src.remaining())))
+    }
+}
+
+/// A connection to a gRPC server, which reconnects by itself, see [`talpid_ipc::grpc`].
+#[derive(Debug, Clone)]
+pub struct GrpcClient {
+    channel: Channel,
+}
+
+impl GrpcClient {
+    /// Connect to the server at `path`, i.e. a socket path or pipe name.
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self, Status> {
+        let channel = talpid_ipc::grpc::connect(path)
+            .map_err(|error| Status::unavailable(error.to_string()))
+            .await?;
+        Ok(GrpcClient { channel })
+    }
+
+    /// Call the unary method at `path` with `request`, and return its response. If `timeout`
+    /// is set, the call is cancelled and fails with [`tonic::Code::DeadlineExceeded`] once it
+    /// passes.
+    pub async fn call(
+        &self,
+        path: PathAndQuery,
+        request: Bytes,
+        timeout: Option<Duration>,
+    ) -> Result<Bytes, Status> {
+        let mut request = Request::new(request);
+        if let Some(timeout) = timeout {
+            request.set_timeout(timeout);
+        }
+        let call = async {
+            let mut grpc = self.ready().await?;
+            grpc.unary(request, path, RawCodec).await
+        };
+        let response = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, call)
+                .await
+                .map_err(|_| Status::deadline_exceeded("The server did not answer in time"))??,
+            None => call.await?,
+        };
+        Ok(response.into_inner())
+    }
+
+    /// Call the server streaming method at `path` with `request`, and return the messages that
+    /// the server sends. Dropping the stream cancels the call.
+    pub async fn subscribe(
+        &self,
+        path: PathAndQuery,
+        request: Bytes,
+    ) -> Result<MessageStream, Status> {
+        let mut grpc = self.ready().await?;
+        let response = grpc
+            .server_streaming(Request::new(request), path, RawCodec)
+            .await?;
+        Ok(response.into_inner().boxed())
+    }
+
+    async fn ready(&self) -> Result<Grpc<Channel>, Status> {
+        let mut grpc = Grpc::new(self.channel.clone());
+        grpc.ready()
+            .await
+            .map_err(|error| Status::unavailable(error.to_string()))?;
+        Ok(grpc)
+    }
+}
+
+/// Parse the path of a method, e.g. `/package.Service/Method`.
+pub fn method_path(method: &str) -> Option<PathAndQuery> {
+    PathAndQuery::try_from(method)
+        .ok()
+        .filter(|path| path.path().starts_with('/') && path.query().is_none())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use futures::{
+        future::{self, BoxFuture},
+        stream,
+    };
+    use std::{
+        convert::Infallible,
+        task::{Context, Poll},
+    };
+    use talpid_ipc::Endpoint;
+    use tokio::runtime::Handle;
+    use tonic::{
+        body::BoxBody,
+        codegen::http,
+        server::{NamedService, ServerStreamingService, UnaryService},
+    };
+
+    /// Answers with the request twice, or fails if it is empty.
+    pub const REPEAT: &str = "/talpid_ipc.test.Test/Repeat";
+    /// Streams `ready`, and then nothing.
+    pub const EVENTS: &str = "/talpid_ipc.test.Test/Events";
+
+    /// Serve [`REPEAT`] and [`EVENTS`] on `path`, on the runtime of `handle`.
+    pub fn spawn_server(handle: &Handle, path: &str) {
+        let _guard = handle.enter();
+        let incoming = Endpoint::new(path.to_owned()).incoming().unwrap();
+        handle.spawn(talpid_ipc::grpc::serve_incoming(
+            incoming,
+            TestService,
+            future::pending(),
+        ));
+    }
+
+    #[derive(Clone)]
+    struct TestService;
+
+    impl NamedService for TestService {
+        const NAME: &'static str = "talpid_ipc.test.Test";
+    }
+
+    impl tower::Service<http::Request<BoxBody>> for TestService {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = BoxFuture<'static, Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+            Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(RawCodec);
+                Ok(match request.uri().path() {
+                    REPEAT => grpc.unary(Repeat, request).await,
+                    EVENTS => grpc.server_streaming(Events, request).await,
+                    _ => Status::unimplemented("Unknown method").into_http(),
+                })
+            })
+        }
+    }
+
+    struct Repeat;
+
+    impl UnaryService<Bytes> for Repeat {
+        type Response = Bytes;
+        type Future = future::Ready<Result<tonic::Response<Bytes>, Status>>;
+
+        fn call(&mut self, request: Request<Bytes>) -> Self::Future {
+            let request = request.into_inner();
+            future::ready(if request.is_empty() {
+                Err(Status::invalid_argument("Nothing to repeat"))
+            } else {
+                Ok(tonic::Response::new(
+                    [request.clone(), request].concat().into(),
+                ))
+            })
+        }
+    }
+
+    struct Events;
+
+    impl ServerStreamingService<Bytes> for Events {
+        type Response = Bytes;
+        type ResponseStream = MessageStream;
+        type Future = future::Ready<Result<tonic::Response<MessageStream>, Status>>;
+
+        fn call(&mut self, _: Request<Bytes>) -> Self::Future {
+            let events = stream::once(future::ready(Ok(Bytes::from_static(b"ready"))))
+                .chain(stream::pending());
+            future::ready(Ok(tonic::Response::new(events.boxed())))
+        }
+    }
+
+    #[test]
+    fn test_method_path() {
+        assert!(method_path(REPEAT).is_some());
+        assert!(method_path("Repeat").is_none());
+        assert!(method_path("/talpid_ipc.test.Test/Repeat?query").is_none());
+    }
+}
@@ -0,0 +1,489 @@
+//! C API for talking to the gRPC service of the daemon, so that the iOS and Android apps can
+//! share the transport of the desktop clients instead of reimplementing it in Swift and Kotlin.
+//!
+//! Requests, responses and events are passed as encoded protobuf messages, see [`grpc`]. The
+//! apps encode them with the protobuf library of their language, from
+//! `management_interface.proto`, and name methods by their path:
+//!
+//! 1. [`talpid_ipc_connect`] connects to the daemon and returns a client.
+//! 2. [`talpid_ipc_send_request`] calls a method and blocks until it has been answered.
+//! 3. [`talpid_ipc_subscribe`] calls a method that streams its responses, such as
+//!    `EventsListen`, and [`talpid_ipc_poll_event`] returns the next message that it streamed.
+//! 4. [`talpid_ipc_buffer_free`] frees a response or event, and [`talpid_ipc_client_free`]
+//!    closes the connection.
+//!
+//! The functions block the calling thread, so they should not be called from the UI thread.
+//! A client may be used from several threads at once, and calls from different threads are
+//! answered in whatever order the daemon completes them. The connections of all clients are
+//! driven by one runtime that is started by the first call to [`talpid_ipc_connect`].
+//!
+//! The build script generates the C header `talpid_ipc.h` into `OUT_DIR`, from where the build
+//! of an app can copy it. With the `uniffi` feature, the library also exports asynchronous
+//! bindings for Kotlin and Swift, see [`uniffi_api`].
+
+use crate::grpc::{GrpcClient, MessageStream};
+use bytes::Bytes;
+use futures::StreamExt;
+use std::{
+    ffi::{CStr, c_char},
+    ptr,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+use tokio::runtime::Runtime;
+use tonic::{Code, Status};
+
+pub mod grpc;
+#[cfg(feature = "uniffi")]
+pub mod uniffi_api;
+
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!();
+
+/// Outcome of a call to the API.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TalpidIpcStatus {
+    Ok = 0,
+    /// A pointer was null, or a string was not valid UTF-8 or the path of a method.
+    InvalidArgument = 1,
+    /// Could not connect to the daemon, or the connection failed.
+    Io = 2,
+    /// The daemon failed to handle the request. The buffer holds its error message.
+    Remote = 3,
+    /// The call was cancelled, or no call is streaming to [`talpid_ipc_poll_event`].
+    Closed = 4,
+    /// The daemon did not answer in time. For [`talpid_ipc_poll_event`], this means that no
+    /// event arrived.
+    Timeout = 5,
+}
+
+impl From<&Status> for TalpidIpcStatus {
+    fn from(status: &Status) -> Self {
+        match status.code() {
+            Code::Unavailable => TalpidIpcStatus::Io,
+            Code::Cancelled => TalpidIpcStatus::Closed,
+            Code::DeadlineExceeded => TalpidIpcStatus::Timeout,
+            _ => TalpidIpcStatus::Remote,
+        }
+    }
+}
+/// Bytes owned by the library, which must be freed with [`talpid_ipc_buffer_free`]. An empty
+/// buffer has a null `data` pointer.
+#[repr(C)]
+pub struct TalpidIpcBuffer {
+    data: *mut u8,
+    len: usize,
+}
+
+impl TalpidIpcBuffer {
+    fn empty() -> Self {
+        TalpidIpcBuffer {
+            data: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    fn new(bytes: Vec<u8>) -> Self {
+        if bytes.is_empty() {
+            return TalpidIpcBuffer::empty();
+        }
+        let bytes = Box::into_raw(bytes.into_boxed_slice());
+        TalpidIpcBuffer {
+            len: bytes.len(),
+            data: bytes.cast(),
+        }
+    }
+}
+
+/// A connection to the daemon, created by [`talpid_ipc_connect`].
+pub struct TalpidIpcClient {
+    client: GrpcClient,
+    /// The call that [`talpid_ipc_poll_event`] returns the messages of.
+    events: Mutex<Option<MessageStream>>,
+}
+
+/// The runtime that drives all connections, or `None` if it could not be started.
+fn runtime() -> Option<&'static Runtime> {
+    static RUNTIME: OnceLock<Option<Runtime>> = OnceLock::new();
+    RUNTIME
+        .get_or_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .thread_name("talpid-ipc-ffi")
+                .enable_all()
+                .build()
+                .inspect_err(|error| log::error!("Failed to start IPC runtime: {error}"))
+                .ok()
+        })
+        .as_ref()
+}
+
+/// Convert a timeout in milliseconds, where 0 means none, into a [`Duration`].
+fn timeout(timeout_ms: u32) -> Option<Duration> {
+    (timeout_ms != 0).then(|| Duration::from_millis(u64::from(timeout_ms)))
+}
+
+/// The status and the buffer to return for a failed call. The buffer holds the error message
+/// of the daemon if it failed to handle the call, and is empty otherwise.
+fn failure(status: &Status) -> (TalpidIpcStatus, Vec<u8>) {
+    let code = TalpidIpcStatus::from(status);
+    match code {
+        TalpidIpcStatus::Remote => (code, status.message().as_bytes().to_vec()),
+        _ => (code, vec![]),
+    }
+}
+
+/// Read a null terminated string, or return `None` if it is not valid UTF-8.
+///
+/// # Safety
+///
+/// `string` must be non-null and point to a null terminated string.
+unsafe fn read_str<'a>(string: *const c_char) -> Option<&'a str> {
+    // SAFETY: The caller guarantees that `string` points to a null terminated string.
+    unsafe { CStr::from_ptr(string) }.to_str().ok()
+}
+
+/// Read `len` bytes at `bytes`, which may be null if `len` is 0, e.g. for an empty message.
+///
+/// # Safety
+///
+/// `bytes` must be valid for reads of `len` bytes, unless `len` is 0.
+unsafe fn read_bytes<'a>(bytes: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        return &[];
+    }
+    // SAFETY: The caller guarantees that `bytes` is valid for reads of `len` bytes.
+    unsafe { std::slice::from_raw_parts(bytes, len) }
+}
+
+/// Connect to the daemon at `path`, i.e. a socket path or pipe name, and store the client in
+/// `client_out`.
+///
+/// # Safety
+///
+/// `path` must point to a null terminated string, and `client_out` must be valid for writes.
+/// The client must be freed with [`talpid_ipc_client_free`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn talpid_ipc_connect(
+    path: *const c_char,
+    client_out: *mut *mut TalpidIpcClient,
+) -> TalpidIpcStatus {
+    if path.is_null() || client_out.is_null() {
+        return TalpidIpcStatus::InvalidArgument;
+    }
+    // SAFETY: The caller guarantees that `path` points to a null terminated string.
+    let Some(path) = (unsafe { read_str(path) }) else {
+        return TalpidIpcStatus::InvalidArgument;
+    };
+    let Some(runtime) = runtime() else {
+        return TalpidIpcStatus::Io;
+    };
+
+    match runtime.block_on(GrpcClient::connect(path)) {
+        Ok(client) => {
+            let client = Box::new(TalpidIpcClient {
+                client,
+                events: Mutex::new(None),
+            });
+            // SAFETY: The caller guarantees that `client_out` is valid for writes.
+            unsafe { client_out.write(Box::into_raw(client)) };
+            TalpidIpcStatus::Ok
+        }
+        Err(status) => {
+            log::error!("Failed to connect to {path}: {}", status.message());
+            TalpidIpcStatus::from(&status)
+        }
+    }
+}
+
+/// Call the method at the path `method` with the encoded message of `request_len` bytes at
+/// `request`, and wait for the response, or for `timeout_ms` milliseconds if that is not 0. If
+/// the daemon is slower than that, the call is cancelled and [`TalpidIpcStatus::Timeout`] is
+/// returned.
+///
+/// On success, the encoded response is stored in `response_out`. If the daemon failed to handle
+/// the request, its error message is stored there instead. Otherwise, the buffer is empty.
+///
+/// # Safety
+///
+/// `client` must have been returned by [`talpid_ipc_connect`] and not yet been freed. `method`
+/// must point to a null terminated string, `request` must be valid for reads of `request_len`
+/// bytes and may only be null if `request_len` is 0, and `response_out` must be valid for
+/// writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn talpid_ipc_send_request(
+    client: *const TalpidIpcClient,
+    method: *const c_char,
+    request: *const u8,
+    request_len: usize,
+    timeout_ms: u32,
+    response_out: *mut TalpidIpcBuffer,
+) -> TalpidIpcStatus {
+    if client.is_null()
+        || method.is_null()
+        || (request.is_null() && request_len != 0)
+        || response_out.is_null()
+    {
+        return TalpidIpcStatus::InvalidArgument;
+    }
+    // SAFETY: The caller guarantees that `client` is a live client, that `method` points to a
+    // null terminated string and that `request` is valid for reads of `request_len` bytes.
+    let (client, method, request) =
+        unsafe { (&*client, read_str(method), read_bytes(request, request_len)) };
+    let Some(path) = method.and_then(grpc::method_path) else {
+        return TalpidIpcStatus::InvalidArgument;
+    };
+    let Some(runtime) = runtime() else {
+        return TalpidIpcStatus::Io;
+    };
+
+    let request = Bytes::copy_from_slice(request);
+    let result = runtime.block_on(client.client.call(path, request, timeout(timeout_ms)));
+    let (status, response) = match result {
+        Ok(response) => (TalpidIpcStatus::Ok, response.to_vec()),
+        Err(status) => {
+            log::debug!("IPC request failed: {status}");
+            failure(&status)
+        }
+    };
+    // SAFETY: The caller guarantees that `response_out` is valid for writes.
+    unsafe { response_out.write(TalpidIpcBuffer::new(response)) };
+    status
+}
+
+/// Call the method at the path `method`, which streams its responses, with the encoded message
+/// of `request_len` bytes at `request`. [`talpid_ipc_poll_event`] then returns the responses.
+/// This cancels the call that was streaming to it before, if any.
+///
+/// # Safety
+///
+/// `client` must have been returned by [`talpid_ipc_connect`] and not yet been freed. `method`
+/// must point to a null terminated string, and `request` must be valid for reads of
+/// `request_len` bytes. It may only be null if `request_len` is 0.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn talpid_ipc_subscribe(
+    client: *const TalpidIpcClient,
+    method: *const c_char,
+    request: *const u8,
+    request_len: usize,
+) -> TalpidIpcStatus {
+    if client.is_null() || method.is_null() || (request.is_null() && request_len != 0) {
+        return TalpidIpcStatus::InvalidArgument;
+    }
+    // SAFETY: The caller guarantees that `client` is a live client, that `method` points to a
+    // null terminated string and that `request` is valid for reads of `request_len` bytes.
+    let (client, method, request) =
+        unsafe { (&*client, read_str(method), read_bytes(request, request_len)) };
+    let Some(path) = method.and_then(grpc::method_path) else {
+        return TalpidIpcStatus::InvalidArgument;
+    };
+    let Some(runtime) = runtime() else {
+        return TalpidIpcStatus::Io;
+    };
+
+    let request = Bytes::copy_from_slice(request);
+    match runtime.block_on(client.client.subscribe(path, request)) {
+        Ok(events) => {
+            *client.events.lock().unwrap() = Some(events);
+            TalpidIpcStatus::Ok
+        }
+        Err(status) => {
+            log::debug!("Failed to subscribe: {status}");
+            TalpidIpcStatus::from(&status)
+        }
+    }
+}
+
+/// Wait for the next response of the call started by [`talpid_ipc_subscribe`], for at most
+/// `timeout_ms` milliseconds. If it is 0, only return a response that has already arrived.
+/// Responses are not lost between polls. Only one thread at a time can poll a client, others
+/// wait for it.
+///
+/// The encoded response is stored in `event_out`. If none arrived in time,
+/// [`TalpidIpcStatus::Timeout`] is returned, and [`TalpidIpcStatus::Closed`] once the call has
+/// ended or if there is none. If the daemon failed the call, its error message is stored in
+/// `event_out` instead. Otherwise, the buffer is empty.
+///
+/// # Safety
+///
+/// `client` must have been returned by [`talpid_ipc_connect`] and not yet been freed.
+/// `event_out` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn talpid_ipc_poll_event(
+    client: *const TalpidIpcClient,
+    timeout_ms: u32,
+    event_out: *mut TalpidIpcBuffer,
+) -> TalpidIpcStatus {
+    if client.is_null() || event_out.is_null() {
+        return TalpidIpcStatus::InvalidArgument;
+    }
+    // SAFETY: The caller guarantees that `client` is a live client.
+    let client = unsafe { &*client };
+    let Some(runtime) = runtime() else {
+        return TalpidIpcStatus::Io;
+    };
+
+    let mut events = client.events.lock().unwrap();
+    let (status, event) = match events.as_mut() {
+        Some(stream) => {
+            let next = runtime.block_on(tokio::time::timeout(
+                Duration::from_millis(u64::from(timeout_ms)),
+                stream.next(),
+            ));
+            match next {
+                Ok(Some(Ok(event))) => (TalpidIpcStatus::Ok, event.to_vec()),
+                Ok(Some(Err(status))) => {
+                    *events = None;
+                    failure(&status)
+                }
+                Ok(None) => {
+                    *events = None;
+                    (TalpidIpcStatus::Closed, vec![])
+                }
+                Err(_) => (TalpidIpcStatus::Timeout, vec![]),
+            }
+        }
+        None => (TalpidIpcStatus::Closed, vec![]),
+    };
+    // SAFETY: The caller guarantees that `event_out` is valid for writes.
+    unsafe { event_out.write(TalpidIpcBuffer::new(event)) };
+    status
+}
+
+/// Free a buffer returned by the API. Freeing an empty buffer does nothing.
+///
+/// # Safety
+///
+/// `buffer` must have been returned by the API, and must not be used or freed again.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn talpid_ipc_buffer_free(buffer: TalpidIpcBuffer) {
+    if buffer.data.is_null() {
+        return;
+    }
+    let bytes = ptr::slice_from_raw_parts_mut(buffer.data, buffer.len);
+    // SAFETY: The buffer was created by `TalpidIpcBuffer::new` from a boxed slice of this length.
+    drop(unsafe { Box::from_raw(bytes) });
+}
+
+/// Close the connection of `client` and free it. Passing null does nothing.
+///
+/// # Safety
+///
+/// `client` must be null or have been returned by [`talpid_ipc_connect`], and no other thread
+/// may be using it. It must not be used or freed again.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn talpid_ipc_client_free(client: *mut TalpidIpcClient) {
+    if client.is_null() {
+        return;
+    }
+    // SAFETY: The caller guarantees that `client` was created by `talpid_ipc_connect` and is
+    // no longer used.
+    drop(unsafe { Box::from_raw(client) });
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::ffi::CString;
+
+    fn take(buffer: TalpidIpcBuffer) -> Vec<u8> {
+        let bytes = if buffer.data.is_null() {
+            vec![]
+        } else {
+            // SAFETY: The buffer was just returned by the API.
+            unsafe { std::slice::from_raw_parts(buffer.data, buffer.len) }.to_vec()
+        };
+        // SAFETY: The buffer was just returned by the API, and is not used again.
+        unsafe { talpid_ipc_buffer_free(buffer) };
+        bytes
+    }
+
+    fn call(
+        client: *const TalpidIpcClient,
+        method: &str,
+        request: &[u8],
+    ) -> (TalpidIpcStatus, Vec<u8>) {
+        let method = CString::new(method).unwrap();
+        // Empty messages are passed with a null pointer, as C callers do
+        let request_ptr = match request.is_empty() {
+            true => ptr::null(),
+            false => request.as_ptr(),
+        };
+        let mut buffer = TalpidIpcBuffer::empty();
+        // SAFETY: All pointers are valid, or null with a length of 0.
+        let status = unsafe {
+            talpid_ipc_send_request(
+                client,
+                method.as_ptr(),
+                request_ptr,
+                request.len(),
+                0,
+                &mut buffer,
+            )
+        };
+        (status, take(buffer))
+    }
+
+    #[test]
+    fn test_request_and_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("socket").to_string_lossy().into_owned();
+        grpc::test::spawn_server(runtime().unwrap().handle(), &path);
+
+        let path = CString::new(path).unwrap();
+        let mut client = ptr::null_mut();
+        // SAFETY: All pointers are valid.
+        let status = unsafe { talpid_ipc_connect(path.as_ptr(), &mut client) };
+        assert_eq!(status, TalpidIpcStatus::Ok);
+
+        assert_eq!(
+            call(client, grpc::test::REPEAT, b"ab"),
+            (TalpidIpcStatus::Ok, b"abab".to_vec())
+        );
+        assert_eq!(
+            call(client, grpc::test::REPEAT, b""),
+            (TalpidIpcStatus::Remote, b"Nothing to repeat".to_vec())
+        );
+        assert_eq!(
+            call(client, "Repeat", b"ab"),
+            (TalpidIpcStatus::InvalidArgument, vec![])
+        );
+
+        let mut buffer = TalpidIpcBuffer::empty();
+        // SAFETY: All pointers are valid.
+        let status = unsafe { talpid_ipc_poll_event(client, 0, &mut buffer) };
+        assert_eq!(status, TalpidIpcStatus::Closed);
+        assert!(take(buffer).is_empty());
+
+        let events = CString::new(grpc::test::EVENTS).unwrap();
+        // SAFETY: All pointers are valid, or null with a length of 0.
+        let status = unsafe { talpid_ipc_subscribe(client, events.as_ptr(), ptr::null(), 0) };
+        assert_eq!(status, TalpidIpcStatus::Ok);
+
+        let mut buffer = TalpidIpcBuffer::empty();
+        // SAFETY: All pointers are valid.
+        let status = unsafe { talpid_ipc_poll_event(client, 1000, &mut buffer) };
+        assert_eq!(status, TalpidIpcStatus::Ok);
+        assert_eq!(take(buffer), b"ready");
+
+        let mut buffer = TalpidIpcBuffer::empty();
+        // SAFETY: All pointers are valid.
+        let status = unsafe { talpid_ipc_poll_event(client, 0, &mut buffer) };
+        assert_eq!(status, TalpidIpcStatus::Timeout);
+        assert!(take(buffer).is_empty());
+
+        // SAFETY: The client is not used again.
+        unsafe { talpid_ipc_client_free(client) };
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let mut client = ptr::null_mut();
+        // SAFETY: Null pointers are rejected.
+        let status = unsafe { talpid_ipc_connect(ptr::null(), &mut client) };
+        assert_eq!(status, TalpidIpcStatus::InvalidArgument);
+        // SAFETY: Null pointers are ignored.
+        unsafe { talpid_ipc_client_free(ptr::null_mut()) };
+    }
+}
@@ -0,0 +1,210 @@
+//! UniFFI bindings for the client, for Kotlin and Swift.
+//!
+//! Unlike the C API, the generated wrappers are asynchronous: calls are `suspend` functions in
+//! Kotlin and `async` functions in Swift, and objects are freed by the garbage collector or by
+//! reference counting. Messages are encoded protobuf messages, like in the C API.
+//!
+//! Generate the bindings with the `uniffi-bindgen` binary of this crate, e.g.
+//! `cargo run -p talpid-ipc-ffi --features uniffi --bin uniffi-bindgen -- generate --library
+//! <path to the library> --language kotlin --out-dir <dir>`.
+
+use crate::grpc::{self, GrpcClient, MessageStream};
+use bytes::Bytes;
+use futures::StreamExt;
+use std::{sync::Arc, time::Duration};
+use tonic::{Code, Status};
+
+/// Why a call failed.
+#[derive(Debug, PartialEq, Eq, thiserror::Error, uniffi::Error)]
+pub enum IpcError {
+    #[error("Not the path of a method: {method}")]
+    InvalidArgument { method: String },
+
+    #[error("IPC connection failed: {message}")]
+    Io { message: String },
+
+    #[error("Server failed to handle the request ({code:?}): {message}")]
+    Remote { code: RemoteCode, message: String },
+
+    #[error("Call was cancelled")]
+    Closed,
+
+    #[error("Timed out waiting for a response")]
+    Timeout,
+}
+
+/// Why the daemon failed to handle a call, i.e. the gRPC status codes that the other variants of
+/// [`IpcError`] do not cover.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum RemoteCode {
+    Unknown,
+    InvalidArgument,
+    NotFound,
+    AlreadyExists,
+    PermissionDenied,
+    ResourceExhausted,
+    FailedPrecondition,
+    Aborted,
+    OutOfRange,
+    Unimplemented,
+    Internal,
+    DataLoss,
+    Unauthenticated,
+}
+
+impl From<Status> for IpcError {
+    fn from(status: Status) -> Self {
+        let message = status.message().to_owned();
+        let code = match status.code() {
+            Code::Unavailable => return IpcError::Io { message },
+            Code::Cancelled => return IpcError::Closed,
+            Code::DeadlineExceeded => return IpcError::Timeout,
+            Code::InvalidArgument => RemoteCode::InvalidArgument,
+            Code::NotFound => RemoteCode::NotFound,
+            Code::AlreadyExists => RemoteCode::AlreadyExists,
+            Code::PermissionDenied => RemoteCode::PermissionDenied,
+            Code::ResourceExhausted => RemoteCode::ResourceExhausted,
+            Code::FailedPrecondition => RemoteCode::FailedPrecondition,
+            Code::Aborted => RemoteCode::Aborted,
+            Code::OutOfRange => RemoteCode::OutOfRange,
+            Code::Unimplemented => RemoteCode::Unimplemented,
+            Code::Internal => RemoteCode::Internal,
+            Code::DataLoss => RemoteCode::DataLoss,
+            Code::Unauthenticated => RemoteCode::Unauthenticated,
+            Code::Ok | Code::Unknown => RemoteCode::Unknown,
+        };
+        IpcError::Remote { code, message }
+    }
+}
+
+fn method_path(method: &str) -> Result<tonic::codegen::http::uri::PathAndQuery, IpcError> {
+    grpc::method_path(method).ok_or_else(|| IpcError::InvalidArgument {
+        method: method.to_owned(),
+    })
+}
+
+/// A connection to the daemon.
+#[derive(uniffi::Object)]
+pub struct IpcConnection {
+    client: GrpcClient,
+}
+
+#[uniffi::export(async_runtime = "tokio")]
+impl IpcConnection {
+    /// Connect to the daemon at `path`, i.e. a socket path or pipe name.
+    #[uniffi::constructor]
+    pub async fn connect(path: String) -> Result<Arc<Self>, IpcError> {
+        let client = GrpcClient::connect(path).await?;
+        Ok(Arc::new(IpcConnection { client }))
+    }
+
+    /// Call the method at the path `method` with the encoded message `request`, and wait for
+    /// the encoded response.
+    pub async fn call(&self, method: String, request: Vec<u8>) -> Result<Vec<u8>, IpcError> {
+        self.call_with_timeout(method, request, None).await
+    }
+
+    /// Like [`Self::call`], but fail with [`IpcError::Timeout`] and cancel the call if the
+    /// daemon takes longer than `timeout_ms` milliseconds to answer.
+    pub async fn call_with_timeout(
+        &self,
+        method: String,
+        request: Vec<u8>,
+        timeout_ms: Option<u64>,
+    ) -> Result<Vec<u8>, IpcError> {
+        let response = self
+            .client
+            .call(
+                method_path(&method)?,
+                Bytes::from(request),
+                timeout_ms.map(Duration::from_millis),
+            )
+            .await?;
+        Ok(response.to_vec())
+    }
+
+    /// Call the method at the path `method`, which streams its responses, e.g. `EventsListen`.
+    pub async fn subscribe(
+        &self,
+        method: String,
+        request: Vec<u8>,
+    ) -> Result<Arc<IpcSubscription>, IpcError> {
+        let responses = self
+            .client
+            .subscribe(method_path(&method)?, Bytes::from(request))
+            .await?;
+        Ok(Arc::new(IpcSubscription {
+            responses: tokio::sync::Mutex::new(responses),
+        }))
+    }
+}
+
+/// Responses streamed by the daemon, see [`IpcConnection::subscribe`]. Dropping it cancels the
+/// call.
+#[derive(uniffi::Object)]
+pub struct IpcSubscription {
+    responses: tokio::sync::Mutex<MessageStream>,
+}
+
+#[uniffi::export(async_runtime = "tokio")]
+impl IpcSubscription {
+    /// Wait for the next response. Returns `None` once the call has ended.
+    pub async fn next(&self) -> Result<Option<Vec<u8>>, IpcError> {
+        match self.responses.lock().await.next().await {
+            Some(response) => Ok(Some(response?.to_vec())),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::grpc::test::{EVENTS, REPEAT, spawn_server};
+
+    #[tokio::test]
+    async fn test_call_and_subscribe() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("socket").to_string_lossy().into_owned();
+        spawn_server(&tokio::runtime::Handle::current(), &path);
+        let connection = IpcConnection::connect(path).await.unwrap();
+
+        let response = connection.call(REPEAT.to_owned(), b"ab".to_vec()).await;
+        assert_eq!(response.unwrap(), b"abab");
+        let response = connection.call(REPEAT.to_owned(), vec![]).await;
+        assert_eq!(
+            response.unwrap_err(),
+            IpcError::Remote {
+                code: RemoteCode::InvalidArgument,
+                message: "Nothing to repeat".to_owned(),
+            }
+        );
+        let response = connection.call("Repeat".to_owned(), vec![]).await;
+        assert_eq!(
+            response.unwrap_err(),
+            IpcError::InvalidArgument {
+                method: "Repeat".to_owned()
+            }
+        );
+
+        let subscription = connection
+            .subscribe(EVENTS.to_owned(), vec![])
+            .await
+            .unwrap();
+        assert_eq!(subscription.next().await.unwrap(), Some(b"ready".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("socket").to_string_lossy().into_owned();
+        spawn_server(&tokio::runtime::Handle::current(), &path);
+        let connection = IpcConnection::connect(path).await.unwrap();
+
+        // The stream never ends, so its method never answers as a unary call would
+        let response = connection
+            .call_with_timeout(EVENTS.to_owned(), vec![], Some(100))
+            .await;
+        assert_eq!(response.unwrap_err(), IpcError::Timeout);
+    }
+}
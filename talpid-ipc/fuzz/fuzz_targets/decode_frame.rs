@@ -0,0 +1,24 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use talpid_ipc::{frame, fuzz};
+
+fuzz_target!(|data: &[u8]| {
+    let lenient = fuzz::decode_frames(data, false);
+    let strict = fuzz::decode_frames(data, true);
+
+    // Strict mode only rejects more
+    if let Ok(frames) = &strict {
+        assert_eq!(lenient.as_ref().ok(), Some(frames));
+    }
+
+    // Decoded frames survive being encoded again
+    if let Ok(frames) = lenient {
+        let mut encoded = BytesMut::new();
+        for frame in &frames {
+            frame::encode(frame, &mut encoded).unwrap();
+        }
+        assert_eq!(fuzz::decode_frames(&encoded, false).unwrap(), frames);
+    }
+});
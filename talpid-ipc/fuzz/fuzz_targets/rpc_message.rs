@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use talpid_ipc::fuzz;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = fuzz::decode_message(data);
+});
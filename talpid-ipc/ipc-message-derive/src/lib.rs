@@ -0,0 +1,192 @@
+//! This `proc-macro` crate exports the [`IpcMessage`] derive macro, see the trait documentation
+//! in `talpid_ipc::rpc` for more information.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use syn::{DeriveInput, parse_macro_input};
+
+/// Derive macro for the `IpcMessage` trait on enums. Every variant must be given a unique tag
+/// with `#[ipc(tag = N)]`, and must either be a unit variant or have exactly one unnamed field.
+#[proc_macro_derive(IpcMessage, attributes(ipc))]
+pub fn ipc_message_derive(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+
+    inner::derive(input).into()
+}
+
+mod inner {
+    use proc_macro2::TokenStream;
+    use quote::{TokenStreamExt, quote};
+    use std::collections::HashMap;
+    use syn::{DeriveInput, Error, Fields, LitInt, Variant, spanned::Spanned};
+
+    pub(crate) fn derive(input: DeriveInput) -> TokenStream {
+        if let syn::Data::Enum(data) = &input.data {
+            derive_for_enum(&input, data).unwrap_or_else(Error::into_compile_error)
+        } else {
+            syn::Error::new(
+                input.span(),
+                "Deriving `IpcMessage` is only supported for enums",
+            )
+            .into_compile_error()
+        }
+    }
+
+    pub(crate) fn derive_for_enum(
+        input: &DeriveInput,
+        data: &syn::DataEnum,
+    ) -> syn::Result<TokenStream> {
+        let my_type = &input.ident;
+        let mut tags = HashMap::new();
+        let mut all_tags = quote! {};
+        let mut tag_arms = quote! {};
+        let mut serialize_arms = quote! {};
+        let mut deserialize_arms = quote! {};
+
+        for variant in &data.variants {
+            let name = &variant.ident;
+            let tag = variant_tag(variant)?;
+            if let Some(previous) = tags.insert(tag, name) {
+                return Err(syn::Error::new(
+                    variant.span(),
+                    format!("Tag {tag} is already used by `{previous}`"),
+                ));
+            }
+
+            all_tags.append_all(quote! { #tag, });
+            match &variant.fields {
+                Fields::Unit => {
+                    tag_arms.append_all(quote! { Self::#name => #tag, });
+                    serialize_arms.append_all(quote! {
+                        Self::#name => (#tag, ()).serialize(serializer),
+                    });
+                    deserialize_arms.append_all(quote! {
+                        #tag => {
+                            next::<(), A>(&mut seq, &self)?;
+                            ::core::result::Result::Ok(#my_type::#name)
+                        }
+                    });
+                }
+                Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                    tag_arms.append_all(quote! { Self::#name(_) => #tag, });
+                    serialize_arms.append_all(quote! {
+                        Self::#name(value) => (#tag, value).serialize(serializer),
+                    });
+                    deserialize_arms.append_all(quote! {
+                        #tag => next(&mut seq, &self).map(#my_type::#name),
+                    });
+                }
+                _ => {
+                    return Err(syn::Error::new(
+                        variant.span(),
+                        "Only unit variants and variants with one unnamed field are supported",
+                    ));
+                }
+            }
+        }
+
+        // Messages are encoded as a tuple of the tag and the content of the variant, so that
+        // renaming a variant does not change the encoding.
+        //
+        // The generated code refers to `serde` and `talpid_ipc`, which the crate that derives the
+        // trait must depend on.
+        Ok(quote! {
+            impl ::talpid_ipc::rpc::IpcMessage for #my_type {
+                const TAGS: &'static [u16] = &[#all_tags];
+
+                fn tag(&self) -> u16 {
+                    match self {
+                        #tag_arms
+                    }
+                }
+            }
+
+            impl ::serde::Serialize for #my_type {
+                fn serialize<S: ::serde::Serializer>(
+                    &self,
+                    serializer: S,
+                ) -> ::core::result::Result<S::Ok, S::Error> {
+                    use ::serde::Serialize;
+                    match self {
+                        #serialize_arms
+                    }
+                }
+            }
+
+            impl<'de> ::serde::Deserialize<'de> for #my_type {
+                fn deserialize<D: ::serde::Deserializer<'de>>(
+                    deserializer: D,
+                ) -> ::core::result::Result<Self, D::Error> {
+                    struct Visitor;
+
+                    /// Read the content of a variant.
+                    fn next<'de, T, A>(
+                        seq: &mut A,
+                        visitor: &Visitor,
+                    ) -> ::core::result::Result<T, A::Error>
+                    where
+                        T: ::serde::Deserialize<'de>,
+                        A: ::serde::de::SeqAccess<'de>,
+                    {
+                        seq.next_element()?
+                            .ok_or_else(|| ::serde::de::Error::invalid_length(1, visitor))
+                    }
+
+                    impl<'de> ::serde::de::Visitor<'de> for Visitor {
+                        type Value = #my_type;
+
+                        fn expecting(
+                            &self,
+                            formatter: &mut ::core::fmt::Formatter<'_>,
+                        ) -> ::core::fmt::Result {
+                            formatter.write_str(concat!("a tagged ", stringify!(#my_type)))
+                        }
+
+                        fn visit_seq<A: ::serde::de::SeqAccess<'de>>(
+                            self,
+                            mut seq: A,
+                        ) -> ::core::result::Result<Self::Value, A::Error> {
+                            let tag: u16 = seq
+                                .next_element()?
+                                .ok_or_else(|| ::serde::de::Error::invalid_length(0, &self))?;
+                            match tag {
+                                #deserialize_arms
+                                _ => ::core::result::Result::Err(::serde::de::Error::custom(
+                                    format_args!("Unknown tag {tag}"),
+                                )),
+                            }
+                        }
+                    }
+
+                    deserializer.deserialize_tuple(2, Visitor)
+                }
+            }
+        })
+    }
+
+    /// Return the tag given to `variant` with `#[ipc(tag = N)]`.
+    fn variant_tag(variant: &Variant) -> syn::Result<u16> {
+        let mut tag = None;
+        for attr in variant
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("ipc"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("tag") {
+                    let value: LitInt = meta.value()?.parse()?;
+                    tag = Some(value.base10_parse::<u16>()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("Unknown attribute, expected `tag`"))
+                }
+            })?;
+        }
+        tag.ok_or_else(|| {
+            syn::Error::new(
+                variant.span(),
+                "Missing stable tag, add `#[ipc(tag = N)]` to the variant",
+            )
+        })
+    }
+}
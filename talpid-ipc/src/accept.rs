@@ -0,0 +1,349 @@
+//! Handling of errors that occur while accepting connections.
+//!
+//! Most accept errors only concern a single connection, or a shortage of resources that passes,
+//! such as running out of file descriptors. Consumers of [`crate::Incoming`] often stop at the
+//! first error, so an [`AcceptErrorPolicy`] lets the stream deal with such transient errors
+//! itself. Fatal errors are always yielded.
+//!
+//! The handling of errors is separate from the listener, behind the [`Acceptor`] trait, so that
+//! it can be tested with scripted errors instead of a listener that has run out of descriptors.
+
+use crate::{
+    EndpointError,
+    backoff::{Backoff, ExponentialBackoff},
+    imp,
+};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+/// What to do when accepting a connection fails with a transient error. See
+/// [`crate::Endpoint::set_accept_error_policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AcceptErrorPolicy {
+    /// Yield every error from the stream of incoming connections.
+    #[default]
+    Fail,
+    /// Log transient errors and keep accepting.
+    Continue,
+    /// Log transient errors and pause accepting for `initial`, doubling the pause after each
+    /// consecutive error up to `max`. This avoids spinning while e.g. no descriptors are
+    /// available for new connections. See [`crate::Endpoint::set_accept_backoff`] for other
+    /// delays.
+    RetryWithBackoff { initial: Duration, max: Duration },
+}
+
+impl AcceptErrorPolicy {
+    /// Delays to retry after, if any.
+    pub(crate) fn backoff(&self) -> Option<Arc<dyn Backoff>> {
+        match *self {
+            AcceptErrorPolicy::RetryWithBackoff { initial, max } => {
+                Some(Arc::new(ExponentialBackoff::new(initial, max)))
+            }
+            AcceptErrorPolicy::Fail | AcceptErrorPolicy::Continue => None,
+        }
+    }
+}
+
+/// Something that connections are accepted from, i.e. a listener.
+pub(crate) trait Acceptor {
+    type Connection;
+
+    /// Accept the next connection. Returns `None` once no more connections can be accepted.
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<Self::Connection>>>;
+}
+
+impl Acceptor for imp::Incoming {
+    type Connection = imp::Connection;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<imp::Connection>>> {
+        imp::Incoming::poll_accept(self, cx)
+    }
+}
+
+/// Applies an [`AcceptErrorPolicy`] to the errors of an [`Acceptor`].
+pub(crate) struct AcceptRetry {
+    policy: AcceptErrorPolicy,
+    /// Decides how long to pause accepting after a transient error.
+    backoff: Option<Arc<dyn Backoff>>,
+    /// Fires when accepting should resume after a transient error.
+    paused: Option<Pin<Box<tokio::time::Sleep>>>,
+    /// Number of consecutive transient errors.
+    errors: u32,
+}
+
+impl AcceptRetry {
+    /// Handle errors according to `policy`, pausing as decided by `backoff` if given, instead
+    /// of the backoff of the policy.
+    pub(crate) fn new(policy: AcceptErrorPolicy, backoff: Option<Arc<dyn Backoff>>) -> Self {
+        AcceptRetry {
+            policy,
+            backoff: backoff.or_else(|| policy.backoff()),
+            paused: None,
+            errors: 0,
+        }
+    }
+
+    /// Accept the next connection from `acceptor`, skipping the errors that the policy says to
+    /// skip. `skipped` is called with every skipped error.
+    pub(crate) fn poll_accept<A: Acceptor>(
+        &mut self,
+        acceptor: &mut A,
+        cx: &mut Context<'_>,
+        mut skipped: impl FnMut(&io::Error),
+    ) -> Poll<Option<io::Result<A::Connection>>> {
+        loop {
+            if let Some(paused) = &mut self.paused {
+                ready!(paused.as_mut().poll(cx));
+                self.paused = None;
+            }
+            match ready!(acceptor.poll_accept(cx)) {
+                Some(Ok(connection)) => {
+                    self.errors = 0;
+                    return Poll::Ready(Some(Ok(connection)));
+                }
+                Some(Err(error)) if self.skip(&error) => skipped(&error),
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+
+    /// Returns whether `error` should be skipped instead of yielded, and if so, pauses
+    /// accepting if the policy says to.
+    fn skip(&mut self, error: &io::Error) -> bool {
+        if !is_transient(error) {
+            return false;
+        }
+        let attempt = self.errors.saturating_add(1);
+        let delay = match &self.backoff {
+            Some(backoff) => match backoff.delay(attempt) {
+                Some(delay) => Some(delay),
+                None => return false,
+            },
+            None if self.policy == AcceptErrorPolicy::Continue => None,
+            None => return false,
+        };
+
+        self.errors = attempt;
+        if let Some(delay) = delay {
+            log::warn!("Failed to accept IPC connection, retrying in {delay:?}: {error}");
+            self.paused = Some(Box::pin(tokio::time::sleep(delay)));
+        } else {
+            log::warn!("Failed to accept IPC connection: {error}");
+        }
+        true
+    }
+}
+
+/// Whether a failure to accept a connection may go away on its own, so that the server should
+/// keep accepting. Other errors mean that the listener is unusable.
+pub fn is_transient(error: &io::Error) -> bool {
+    let error = EndpointError::of(error).map_or(error, EndpointError::io_error);
+    if matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::OutOfMemory
+            | io::ErrorKind::TimedOut
+    ) {
+        return true;
+    }
+    error.raw_os_error().is_some_and(is_transient_os_error)
+}
+
+#[cfg(unix)]
+fn is_transient_os_error(code: i32) -> bool {
+    matches!(
+        code,
+        libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM | libc::EPROTO
+    )
+}
+
+#[cfg(windows)]
+fn is_transient_os_error(code: i32) -> bool {
+    use windows_sys::Win32::Foundation::{
+        ERROR_NO_DATA, ERROR_NO_SYSTEM_RESOURCES, ERROR_NOT_ENOUGH_MEMORY, ERROR_OUTOFMEMORY,
+    };
+    [
+        ERROR_NO_DATA,
+        ERROR_NO_SYSTEM_RESOURCES,
+        ERROR_NOT_ENOUGH_MEMORY,
+        ERROR_OUTOFMEMORY,
+    ]
+    .contains(&(code as u32))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::VecDeque;
+
+    enum Step {
+        Accept(u32),
+        Fail(io::Error),
+        /// Nothing is accepted for a while.
+        Delay(Duration),
+    }
+
+    /// Acceptor that goes through a script, and then stops.
+    struct ScriptedAcceptor {
+        steps: VecDeque<Step>,
+        delay: Option<Pin<Box<tokio::time::Sleep>>>,
+    }
+
+    impl ScriptedAcceptor {
+        fn new(steps: impl IntoIterator<Item = Step>) -> Self {
+            ScriptedAcceptor {
+                steps: steps.into_iter().collect(),
+                delay: None,
+            }
+        }
+    }
+
+    impl Acceptor for ScriptedAcceptor {
+        type Connection = u32;
+
+        fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<u32>>> {
+            loop {
+                if let Some(delay) = &mut self.delay {
+                    ready!(delay.as_mut().poll(cx));
+                    self.delay = None;
+                }
+                return Poll::Ready(match self.steps.pop_front() {
+                    Some(Step::Accept(connection)) => Some(Ok(connection)),
+                    Some(Step::Fail(error)) => Some(Err(error)),
+                    Some(Step::Delay(delay)) => {
+                        self.delay = Some(Box::pin(tokio::time::sleep(delay)));
+                        continue;
+                    }
+                    None => None,
+                });
+            }
+        }
+    }
+
+    fn transient() -> Step {
+        Step::Fail(io::Error::from(io::ErrorKind::ConnectionAborted))
+    }
+
+    fn fatal() -> Step {
+        Step::Fail(io::Error::from(io::ErrorKind::PermissionDenied))
+    }
+
+    /// Accept the next connection, returning it and how many errors were skipped.
+    async fn accept(
+        retry: &mut AcceptRetry,
+        acceptor: &mut ScriptedAcceptor,
+    ) -> (Option<io::Result<u32>>, usize) {
+        let mut skipped = 0;
+        let result =
+            std::future::poll_fn(|cx| retry.poll_accept(acceptor, cx, |_| skipped += 1)).await;
+        (result, skipped)
+    }
+
+    #[tokio::test]
+    async fn test_fail_policy() {
+        let mut retry = AcceptRetry::new(AcceptErrorPolicy::Fail, None);
+        let mut acceptor = ScriptedAcceptor::new([transient(), Step::Accept(1)]);
+        let (result, skipped) = accept(&mut retry, &mut acceptor).await;
+        assert!(result.unwrap().is_err());
+        assert_eq!(skipped, 0);
+        assert_eq!(
+            accept(&mut retry, &mut acceptor).await.0.unwrap().unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_continue_policy() {
+        let mut retry = AcceptRetry::new(AcceptErrorPolicy::Continue, None);
+        let mut acceptor = ScriptedAcceptor::new([
+            transient(),
+            transient(),
+            Step::Accept(1),
+            fatal(),
+            Step::Accept(2),
+        ]);
+        let (result, skipped) = accept(&mut retry, &mut acceptor).await;
+        assert_eq!(result.unwrap().unwrap(), 1);
+        assert_eq!(skipped, 2);
+        let (result, skipped) = accept(&mut retry, &mut acceptor).await;
+        assert_eq!(
+            result.unwrap().unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(skipped, 0);
+        assert_eq!(
+            accept(&mut retry, &mut acceptor).await.0.unwrap().unwrap(),
+            2
+        );
+        assert!(accept(&mut retry, &mut acceptor).await.0.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backoff_policy() {
+        let policy = AcceptErrorPolicy::RetryWithBackoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(3),
+        };
+        let mut retry = AcceptRetry::new(policy, None);
+        let mut acceptor = ScriptedAcceptor::new([
+            transient(),
+            transient(),
+            transient(),
+            Step::Delay(Duration::from_secs(10)),
+            Step::Accept(1),
+            transient(),
+            Step::Accept(2),
+        ]);
+
+        // A burst of errors pauses for 1, 2 and 3 seconds
+        let started = tokio::time::Instant::now();
+        let (result, skipped) = accept(&mut retry, &mut acceptor).await;
+        assert_eq!(result.unwrap().unwrap(), 1);
+        assert_eq!(skipped, 3);
+        assert_eq!(started.elapsed(), Duration::from_secs(1 + 2 + 3 + 10));
+
+        // Accepting a connection starts over from the initial pause
+        let started = tokio::time::Instant::now();
+        assert_eq!(
+            accept(&mut retry, &mut acceptor).await.0.unwrap().unwrap(),
+            2
+        );
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backoff_gives_up() {
+        let mut backoff = ExponentialBackoff::new(Duration::from_secs(1), Duration::from_secs(1));
+        backoff.set_max_attempts(2);
+        let mut retry = AcceptRetry::new(AcceptErrorPolicy::Fail, Some(Arc::new(backoff)));
+        let mut acceptor = ScriptedAcceptor::new([transient(), transient(), transient()]);
+        let (result, skipped) = accept(&mut retry, &mut acceptor).await;
+        assert!(result.unwrap().is_err());
+        assert_eq!(skipped, 2);
+    }
+
+    #[test]
+    fn test_classification() {
+        assert!(is_transient(&io::Error::from(
+            io::ErrorKind::ConnectionAborted
+        )));
+        assert!(!is_transient(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+        #[cfg(unix)]
+        {
+            assert!(is_transient(&io::Error::from_raw_os_error(libc::EMFILE)));
+            assert!(!is_transient(&io::Error::from_raw_os_error(libc::EBADF)));
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Placement and access control of the socket when running as part of an Android app.
+//!
+//! Every Android app runs as its own user, so the daemon and the user interface of the app share
+//! a UID that no other app has. The socket is placed in the private data directory of the app,
+//! and only peers running as that UID are let in.
+
+#[cfg(feature = "server")]
+use crate::SecurityAttributes;
+use crate::{Connection, Endpoint};
+use std::{io, path::Path};
+
+/// Only the app itself may connect to the socket.
+#[cfg(feature = "server")]
+const APP_SOCKET_MODE: u32 = 0o600;
+
+impl Endpoint {
+    /// Create an endpoint for a socket named `name` in the data directory of the app, such as the
+    /// one returned by `Context.getDataDir()`. Only the user of the app may connect to it.
+    pub fn android(data_dir: impl AsRef<Path>, name: &str) -> io::Result<Endpoint> {
+        let path = data_dir.as_ref().join(name);
+        let path = path.to_str().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Socket path is not valid UTF-8",
+            )
+        })?;
+        let endpoint = Endpoint::new(path.to_owned());
+        #[cfg(feature = "server")]
+        let endpoint = endpoint
+            .with_security_attributes(SecurityAttributes::empty().set_mode(APP_SOCKET_MODE)?);
+        Ok(endpoint)
+    }
+}
+
+impl Connection {
+    /// Return whether the peer belongs to the same app as this process, i.e. runs as the same
+    /// UID. The file mode of the socket already ensures this, unless the socket was created
+    /// elsewhere.
+    pub fn is_same_app(&self) -> io::Result<bool> {
+        // SAFETY: Getting the UID of the current process has no preconditions
+        let uid = unsafe { libc::geteuid() };
+        Ok(self.peer_credentials()?.uid() == uid)
+    }
+}
@@ -0,0 +1,57 @@
+//! Placement of the socket in the app group container shared by a sandboxed app and the daemon.
+//!
+//! The sandbox only lets an app reach sockets inside its own containers. The group container of
+//! a user, `~/Library/Group Containers/<group ID>`, is shared by all apps in the group, so the
+//! daemon binds the socket there on behalf of the user.
+
+use crate::Endpoint;
+#[cfg(feature = "server")]
+use crate::SecurityAttributes;
+use std::{
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+/// Only the owner of the container may connect to the socket.
+#[cfg(feature = "server")]
+const APP_GROUP_SOCKET_MODE: u32 = 0o600;
+
+impl Endpoint {
+    /// Create an endpoint for a socket named `name` in the group container `group_id` of the user
+    /// with the home directory `home`. The socket is owned by the owner of the container, so
+    /// the daemon must run as that user or as root.
+    ///
+    /// The container is created by macOS when an app in the group first uses it. An error is
+    /// returned if it does not exist yet, or if it is a symbolic link.
+    pub fn app_group(home: impl AsRef<Path>, group_id: &str, name: &str) -> io::Result<Endpoint> {
+        let container = app_group_container(home.as_ref(), group_id);
+        let metadata = fs::symlink_metadata(&container)?;
+        if !metadata.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "App group container is not a directory",
+            ));
+        }
+
+        let path = container.join(name);
+        let path = path.to_str().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Socket path is not valid UTF-8",
+            )
+        })?;
+        let endpoint = Endpoint::new(path.to_owned());
+        #[cfg(feature = "server")]
+        let endpoint = endpoint.with_security_attributes(
+            SecurityAttributes::empty()
+                .set_mode(APP_GROUP_SOCKET_MODE)?
+                .set_owner(metadata.uid(), metadata.gid()),
+        );
+        Ok(endpoint)
+    }
+}
+
+fn app_group_container(home: &Path, group_id: &str) -> PathBuf {
+    home.join("Library").join("Group Containers").join(group_id)
+}
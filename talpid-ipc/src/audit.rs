@@ -0,0 +1,514 @@
+//! Events about who connects to an endpoint, for administrators to review.
+//!
+//! [`IpcMetrics`](crate::metrics::IpcMetrics) counts connections, but does not say whose they
+//! were. An [`IpcEventSink`] set with [`Endpoint::set_event_sink`] is told about every
+//! connection that is admitted or rejected, fails the handshake or is closed, together with who
+//! the peer is. Embedders can implement the trait to forward the events to syslog, the Windows
+//! Event Log or their own telemetry, or use one of the sinks here:
+//!
+//! - [`LogSink`] logs every event.
+//! - [`AuditLog`], with the `audit` feature, writes one line per event to a file, from a thread
+//!   of its own, so recording never blocks accepting. Once the file reaches
+//!   [`AuditLogOptions::set_max_size`], it is rotated, and only the newest
+//!   [`AuditLogOptions::set_max_files`] old files are kept.
+//!
+//! Events only carry what is known about the peer without looking up its process, since that
+//! would block accepting. [`AuditLog`] looks up the rest with [`PeerInfo::complete`] on its
+//! thread.
+//!
+//! [`Endpoint::set_event_sink`]: crate::Endpoint::set_event_sink
+
+use crate::{
+    ConnectionId, log_limit,
+    metrics::{HandshakeFailureReason, PeerInfo},
+};
+#[cfg(feature = "audit")]
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::{fmt, time::SystemTime};
+#[cfg(feature = "audit")]
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Size at which the log is rotated, unless [`AuditLogOptions::set_max_size`] is called.
+#[cfg(feature = "audit")]
+pub const DEFAULT_MAX_SIZE: u64 = 1024 * 1024;
+
+/// Number of rotated files that are kept, unless [`AuditLogOptions::set_max_files`] is called.
+#[cfg(feature = "audit")]
+pub const DEFAULT_MAX_FILES: usize = 3;
+
+/// Number of events that may wait to be written, unless [`AuditLogOptions::set_queue_len`] is
+/// called.
+#[cfg(feature = "audit")]
+pub const DEFAULT_QUEUE_LEN: usize = 1024;
+
+/// Receives the events of the connections accepted on an endpoint. It is called from within
+/// `poll` functions, so it must not block.
+pub trait IpcEventSink: Send + Sync {
+    fn event(&self, event: &AuditEvent);
+}
+
+/// What happened to a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditEventKind {
+    /// The peer was admitted.
+    Connected,
+    /// The peer was not allowed to connect, for the given reason.
+    Rejected(HandshakeFailureReason),
+    /// An admitted peer does not speak the protocol, uses another version of it, or fails to
+    /// authenticate. Only reported for handshakes performed with
+    /// [`FramedConnection::accept_handshake`] or
+    /// [`FramedConnection::authenticate_client`][authenticate_client].
+    ///
+    /// [`FramedConnection::accept_handshake`]: crate::frame::FramedConnection::accept_handshake
+    /// [authenticate_client]: crate::frame::FramedConnection::authenticate_client
+    HandshakeFailed(HandshakeFailureReason),
+    /// An admitted connection was closed.
+    Disconnected,
+    /// An admitted connection was handed over to another process, which serves it from now on.
+    HandedOver,
+}
+
+impl AuditEventKind {
+    /// Whether the event is about a peer that was turned away.
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            AuditEventKind::Rejected(_) | AuditEventKind::HandshakeFailed(_)
+        )
+    }
+}
+
+impl fmt::Display for AuditEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditEventKind::Connected => f.write_str("connected"),
+            AuditEventKind::Rejected(reason) => write!(f, "rejected: {reason}"),
+            AuditEventKind::HandshakeFailed(reason) => write!(f, "failed the handshake: {reason}"),
+            AuditEventKind::Disconnected => f.write_str("disconnected"),
+            AuditEventKind::HandedOver => f.write_str("handed over to another process"),
+        }
+    }
+}
+
+/// An event reported to an [`IpcEventSink`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEvent {
+    pub time: SystemTime,
+    /// ID of the connection. Rejected connections do not get one.
+    pub connection: Option<ConnectionId>,
+    pub peer: PeerInfo,
+    pub kind: AuditEventKind,
+}
+
+impl AuditEvent {
+    pub(crate) fn new(
+        connection: Option<ConnectionId>,
+        peer: PeerInfo,
+        kind: AuditEventKind,
+    ) -> Self {
+        AuditEvent {
+            time: SystemTime::now(),
+            connection,
+            peer,
+            kind,
+        }
+    }
+}
+
+/// Formats the event without its time, which loggers add by themselves.
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.connection {
+            Some(id) => write!(f, "connection {id} ")?,
+            None => f.write_str("connection ")?,
+        }
+        write!(f, "{} ({})", self.kind, self.peer)
+    }
+}
+
+/// [`IpcEventSink`] that logs every event, failures as warnings. Only a few failures are logged
+/// per peer and minute, so that a peer cannot fill the log by reconnecting.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogSink;
+
+impl IpcEventSink for LogSink {
+    fn event(&self, event: &AuditEvent) {
+        if event.kind.is_failure() {
+            log_limit::log(
+                log::Level::Warn,
+                || log_limit::peer_key(&event.peer),
+                format_args!("IPC {event}"),
+            );
+        } else {
+            log::info!("IPC {event}");
+        }
+    }
+}
+
+/// Options of an [`AuditLog`].
+#[cfg(feature = "audit")]
+#[derive(Debug, Clone)]
+pub struct AuditLogOptions {
+    max_size: u64,
+    max_files: usize,
+    queue_len: usize,
+}
+
+#[cfg(feature = "audit")]
+impl AuditLogOptions {
+    pub fn new() -> Self {
+        AuditLogOptions {
+            max_size: DEFAULT_MAX_SIZE,
+            max_files: DEFAULT_MAX_FILES,
+            queue_len: DEFAULT_QUEUE_LEN,
+        }
+    }
+
+    /// Rotate the log once writing an event would make it larger than `max_size` bytes.
+    pub fn set_max_size(&mut self, max_size: u64) {
+        self.max_size = max_size;
+    }
+
+    /// Keep `max_files` rotated files, named after the log with `.1` for the newest, `.2` for
+    /// the one before and so on. With 0, the log is truncated instead.
+    pub fn set_max_files(&mut self, max_files: usize) {
+        self.max_files = max_files;
+    }
+
+    /// Let at most `queue_len` events wait to be written. Events recorded while the queue is
+    /// full are dropped, and counted in [`AuditLog::dropped`], so that a flood of connections
+    /// cannot use up memory.
+    pub fn set_queue_len(&mut self, queue_len: usize) {
+        self.queue_len = queue_len;
+    }
+}
+
+#[cfg(feature = "audit")]
+impl Default for AuditLogOptions {
+    fn default() -> Self {
+        AuditLogOptions::new()
+    }
+}
+
+/// [`IpcEventSink`] that appends events to a file. Events that have been recorded are all
+/// written when the log is dropped. How many events were dropped since the last one that was
+/// written is written along with the next one.
+#[cfg(feature = "audit")]
+pub struct AuditLog {
+    sender: Option<mpsc::SyncSender<AuditEvent>>,
+    writer: Option<JoinHandle<()>>,
+    dropped: Arc<AtomicU64>,
+}
+
+#[cfg(feature = "audit")]
+impl AuditLog {
+    /// Append to the log at `path`, creating it if needed. On Unix, only the owner of a new
+    /// file may read it.
+    pub fn open(path: impl Into<PathBuf>, options: AuditLogOptions) -> io::Result<Self> {
+        let path = path.into();
+        let file = open_file(&path)?;
+        let size = file.metadata()?.len();
+        let dropped = Arc::new(AtomicU64::new(0));
+        let (sender, receiver) = mpsc::sync_channel(options.queue_len);
+        let mut writer = Writer {
+            path,
+            file,
+            size,
+            options,
+            dropped: dropped.clone(),
+            reported_dropped: 0,
+        };
+        let writer = thread::Builder::new()
+            .name("ipc-audit-log".to_owned())
+            .spawn(move || {
+                for mut event in receiver {
+                    event.peer.complete();
+                    if let Err(error) = writer.write(&event) {
+                        log::warn!("Failed to write to the IPC audit log: {error}");
+                    }
+                }
+                if let Err(error) = writer.write_dropped() {
+                    log::warn!("Failed to write to the IPC audit log: {error}");
+                }
+            })?;
+        Ok(AuditLog {
+            sender: Some(sender),
+            writer: Some(writer),
+            dropped,
+        })
+    }
+
+    /// Record `event`. It is written in the background, unless too many events are waiting
+    /// already, see [`AuditLogOptions::set_queue_len`].
+    pub fn record(&self, event: AuditEvent) {
+        if let Some(sender) = &self.sender
+            && let Err(mpsc::TrySendError::Full(_)) = sender.try_send(event)
+        {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of events that were dropped because too many were waiting to be written.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "audit")]
+impl IpcEventSink for AuditLog {
+    fn event(&self, event: &AuditEvent) {
+        self.record(event.clone());
+    }
+}
+
+#[cfg(feature = "audit")]
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        // Let the writer finish what has been recorded
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(feature = "audit")]
+struct Writer {
+    path: PathBuf,
+    file: File,
+    /// Size of `file`.
+    size: u64,
+    options: AuditLogOptions,
+    dropped: Arc<AtomicU64>,
+    /// Value of `dropped` when it was last written.
+    reported_dropped: u64,
+}
+
+#[cfg(feature = "audit")]
+impl Writer {
+    fn write(&mut self, event: &AuditEvent) -> io::Result<()> {
+        self.write_dropped()?;
+        self.write_line(format!("{} {event}\n", format_time(event.time)))
+    }
+
+    /// Write how many events have been dropped since this was last called, if any.
+    fn write_dropped(&mut self) -> io::Result<()> {
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped == self.reported_dropped {
+            return Ok(());
+        }
+        let count = dropped - self.reported_dropped;
+        self.reported_dropped = dropped;
+        let time = format_time(SystemTime::now());
+        self.write_line(format!("{time} dropped {count} events\n"))
+    }
+
+    fn write_line(&mut self, line: String) -> io::Result<()> {
+        let len = line.len() as u64;
+        if self.size > 0 && self.size + len > self.options.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Move the current file, and the rotated ones, one step down, and start a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{n}"));
+            PathBuf::from(path)
+        };
+        if self.options.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for n in (1..self.options.max_files).rev() {
+                match fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                    _ => (),
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+            self.file = open_file(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "audit")]
+fn format_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(feature = "audit")]
+fn open_file(path: &Path) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(kind: AuditEventKind) -> AuditEvent {
+        let peer = PeerInfo {
+            user: Some("1000".to_owned()),
+            pid: Some(42),
+            exe: Some("/usr/bin/test".into()),
+        };
+        AuditEvent::new(None, peer, kind)
+    }
+
+    #[test]
+    fn test_format() {
+        let event = event(AuditEventKind::Rejected(
+            HandshakeFailureReason::QuotaExceeded,
+        ));
+        assert_eq!(
+            event.to_string(),
+            "connection rejected: too many connections \
+             (user 1000, PID 42, executable /usr/bin/test)"
+        );
+        #[cfg(feature = "audit")]
+        assert_eq!(
+            format_time(SystemTime::UNIX_EPOCH),
+            "1970-01-01T00:00:00.000Z"
+        );
+    }
+
+    #[cfg(feature = "audit")]
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let event_len = format!("{}\n", event(AuditEventKind::Connected)).len();
+        let line_len = (format_time(SystemTime::now()).len() + 1 + event_len) as u64;
+        let mut options = AuditLogOptions::new();
+        options.set_max_size(2 * line_len);
+        options.set_max_files(2);
+
+        let log = AuditLog::open(&path, options).unwrap();
+        for _ in 0..7 {
+            log.record(event(AuditEventKind::Connected));
+        }
+        drop(log);
+
+        let lines = |path: &Path| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&dir.path().join("audit.log.1")), 2);
+        assert_eq!(lines(&dir.path().join("audit.log.2")), 2);
+        assert!(!dir.path().join("audit.log.3").exists());
+    }
+
+    #[cfg(feature = "audit")]
+    #[test]
+    fn test_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let mut options = AuditLogOptions::new();
+        options.set_queue_len(1);
+
+        let log = AuditLog::open(&path, options).unwrap();
+        for _ in 0..1000 {
+            log.record(event(AuditEventKind::Connected));
+        }
+        let dropped = log.dropped();
+        drop(log);
+
+        let log = fs::read_to_string(&path).unwrap();
+        let (reports, events): (Vec<_>, Vec<_>) =
+            log.lines().partition(|line| line.contains(" dropped "));
+        let reported: u64 = reports
+            .iter()
+            .map(|line| line.split(' ').nth(2).unwrap().parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(reported, dropped);
+        assert_eq!(events.len() as u64 + dropped, 1000);
+    }
+
+    #[cfg(all(unix, feature = "audit", feature = "client"))]
+    #[tokio::test]
+    async fn test_endpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("audit.log");
+        let log = std::sync::Arc::new(AuditLog::open(&log_path, AuditLogOptions::new()).unwrap());
+        let mut endpoint = crate::testing::EphemeralEndpoint::with_options(|endpoint| {
+            endpoint.set_event_sink(log.clone())
+        })
+        .unwrap();
+
+        let (_client, connection) = endpoint.connected_pair().await.unwrap();
+        let id = connection.id();
+        drop(connection);
+        drop(endpoint);
+        drop(std::sync::Arc::into_inner(log).unwrap());
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(&format!("connection {id} connected")));
+        assert!(lines[1].contains(&format!("connection {id} disconnected")));
+        // SAFETY: Getting the effective UID has no preconditions
+        let uid = unsafe { libc::geteuid() };
+        assert!(lines[0].contains(&format!("user {uid}")));
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_handshake_failure() {
+        use crate::{frame::FramedConnection, handshake::Capabilities};
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Default)]
+        struct Events(Mutex<Vec<AuditEventKind>>);
+
+        impl IpcEventSink for Events {
+            fn event(&self, event: &AuditEvent) {
+                self.0.lock().unwrap().push(event.kind.clone());
+            }
+        }
+
+        let events = Arc::new(Events::default());
+        let mut endpoint = crate::testing::EphemeralEndpoint::with_options(|endpoint| {
+            endpoint.set_event_sink(events.clone())
+        })
+        .unwrap();
+
+        let (client, server) = endpoint.connected_pair().await.unwrap();
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        let (_, server_result) = tokio::join!(
+            client.handshake(1, Capabilities::empty()),
+            server.accept_handshake(2, Capabilities::empty()),
+        );
+        assert!(server_result.is_err());
+        drop(server);
+
+        let events = events.0.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0], AuditEventKind::Connected);
+        assert_eq!(
+            events[1],
+            AuditEventKind::HandshakeFailed(HandshakeFailureReason::IncompatibleVersion {
+                theirs: 1,
+                ours: 2
+            })
+        );
+        assert_eq!(events[2], AuditEventKind::Disconnected);
+    }
+}
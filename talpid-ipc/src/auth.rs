@@ -0,0 +1,914 @@
+//! Mutual authentication with a secret that is specific to the installation.
+//!
+//! The permissions of the socket or pipe decide who may connect, but a misconfigured directory,
+//! descriptor or service can let an unauthorized client in, or let another process serve the
+//! endpoint in place of the daemon. [`FramedConnection::authenticate`] adds a check that does not
+//! depend on the OS: each end sends a random challenge, and answers with an HMAC-SHA256 of both
+//! challenges, keyed with an [`InstallSecret`]. Neither end completes the exchange unless the
+//! peer knows the secret.
+//!
+//! The daemon should generate the secret when it is installed, and write it with
+//! [`InstallSecret::write_to`] to a file that only the daemon and authorized users may read.
+//!
+//! Since every answer covers the challenges of both ends, an answer is only valid for the
+//! session that it was given in. A transcript of an exchange, e.g. from a capture of debug
+//! traffic, is of no use for opening a new session, since the other end picks a new challenge.
+//! Answers also depend on which end gives them, so an answer cannot be sent back to the end that
+//! asked for it. Both challenges are bound into the [`AuthSession`] that a successful exchange
+//! returns, from which keys for the session can be derived.
+//!
+//! # Resumption
+//!
+//! A server that shares a [`TicketIssuer`] between its connections hands out a
+//! [`ResumptionTicket`] with every session, if the client authenticates with
+//! [`FramedConnection::authenticate_for_ticket`] and the server with
+//! [`FramedConnection::accept_authentication`]. The client can present the ticket with
+//! [`FramedConnection::resume`] when it reconnects, e.g. after the daemon has restarted, which
+//! does not need the secret. Every resumption returns a new ticket.
+//!
+//! Like a full exchange, a resumption covers a challenge from each end, and both ends prove that
+//! they know the key of the ticket by answering for both challenges. A resumption that was
+//! captured is therefore of no use to anyone else, even after a restart.
+//!
+//! Tickets are only valid until they expire, and only once. They are checked with a key that is
+//! derived from the secret, so they remain valid when the daemon restarts, but which tickets
+//! have been used is only remembered until then. A client that has kept a ticket that it already
+//! used can use it again after a restart, so the lifetime should be kept short.
+
+use crate::{
+    Error,
+    context::{self, Operation},
+    frame::FramedConnection,
+    metrics::HandshakeFailureReason,
+};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Length of an [`InstallSecret`] in bytes.
+pub const SECRET_LEN: usize = 32;
+
+/// Mode of the file that [`InstallSecret::write_to`] writes.
+#[cfg(unix)]
+const SECRET_MODE: u32 = 0o640;
+
+/// Length of a challenge in bytes.
+const CHALLENGE_LEN: usize = 32;
+
+/// Length of an answer in bytes, i.e. of an HMAC-SHA256.
+const ANSWER_LEN: usize = 32;
+
+/// Length of the ID of a ticket in bytes.
+const TICKET_ID_LEN: usize = 16;
+
+/// Length of a ticket in bytes: when it expires, in seconds since the Unix epoch, its ID and a
+/// MAC of both.
+const TICKET_LEN: usize = 8 + TICKET_ID_LEN + ANSWER_LEN;
+
+/// Length of the key that comes with a ticket, which is also the length of every key that is
+/// derived with HMAC-SHA256.
+pub(crate) const KEY_LEN: usize = 32;
+
+/// How long tickets are valid, unless [`TicketIssuer::set_lifetime`] is called.
+pub const DEFAULT_TICKET_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// The modes of [`FramedConnection::accept_authentication`], sent by the client.
+const MODE_FULL: u8 = 0;
+const MODE_RESUME: u8 = 1;
+
+/// Whether the server accepted a ticket.
+const TICKET_REJECTED: u8 = 0;
+const TICKET_ACCEPTED: u8 = 1;
+
+/// Purpose of the session key that the key of a new ticket is masked with.
+const TICKET_MASK: &str = "talpid-ipc ticket";
+
+const SESSION_LABEL: &[u8] = b"talpid-ipc session";
+const TICKET_KEY_LABEL: &[u8] = b"talpid-ipc ticket key";
+const RESUMPTION_LABEL: &[u8] = b"talpid-ipc resumption";
+
+pub(crate) type HmacSha256 = Hmac<Sha256>;
+
+/// HMAC of the concatenation of `parts`, keyed with `key`.
+pub(crate) fn hmac(key: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+pub(crate) fn to_key(mac: HmacSha256) -> [u8; KEY_LEN] {
+    mac.finalize().into_bytes().into()
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+fn xor(a: [u8; KEY_LEN], b: [u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+fn with_context(error: Error) -> Error {
+    match error {
+        Error::Io(error) => Error::Io(context::with_context(error, Operation::Handshake, None)),
+        error => error,
+    }
+}
+
+/// Secret that both ends must know to [`FramedConnection::authenticate`]. It is zeroed when
+/// dropped.
+#[derive(Clone, PartialEq, Eq)]
+pub struct InstallSecret([u8; SECRET_LEN]);
+
+impl InstallSecret {
+    /// Generate a random secret.
+    pub fn generate() -> Self {
+        InstallSecret(random())
+    }
+
+    pub fn from_bytes(secret: [u8; SECRET_LEN]) -> Self {
+        InstallSecret(secret)
+    }
+
+    /// Read a secret written by [`Self::write_to`].
+    pub fn read_from(path: impl AsRef<Path>) -> io::Result<Self> {
+        let secret = Zeroizing::new(fs::read(path)?);
+        let secret = <[u8; SECRET_LEN]>::try_from(secret.as_slice())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid secret file"))?;
+        Ok(InstallSecret(secret))
+    }
+
+    /// Write the secret to `path`, replacing any previous secret. On Unix, only the owner and
+    /// the group of the file may read it, so the group should be the one of the authorized
+    /// users. On Windows, the file inherits the permissions of its directory.
+    ///
+    /// The secret is first written to a new file next to `path`, which is never opened through
+    /// a symbolic link, and is then renamed over `path`, so that clients never read half of it.
+    pub fn write_to(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        // Left behind by a write that was interrupted. Removing a link does not follow it
+        match fs::remove_file(&temp_path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => (),
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(SECRET_MODE).custom_flags(libc::O_NOFOLLOW);
+        }
+        let result = options.open(&temp_path).and_then(|mut file| {
+            // The mode given when creating the file is masked by the umask
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                file.set_permissions(fs::Permissions::from_mode(SECRET_MODE))?;
+            }
+            io::Write::write_all(&mut file, &self.0)?;
+            file.sync_all()?;
+            fs::rename(&temp_path, path)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    /// HMAC of `label` and the challenges of the client and the server, in that order.
+    fn mac(&self, label: &[u8], challenges: &Challenges) -> HmacSha256 {
+        hmac(&self.0, &[label, &challenges.client, &challenges.server])
+    }
+}
+
+impl Drop for InstallSecret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for InstallSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("InstallSecret(..)")
+    }
+}
+
+/// The challenges of both ends of an exchange.
+struct Challenges {
+    client: [u8; CHALLENGE_LEN],
+    server: [u8; CHALLENGE_LEN],
+}
+
+/// A session that both ends have authenticated, see [`FramedConnection::authenticate`].
+#[derive(Clone)]
+pub struct AuthSession {
+    key: [u8; SECRET_LEN],
+}
+
+impl AuthSession {
+    fn new(secret: &InstallSecret, challenges: &Challenges) -> Self {
+        AuthSession {
+            key: to_key(secret.mac(SESSION_LABEL, challenges)),
+        }
+    }
+
+    /// Derive a key for `purpose` that is unique to this session. Both ends derive the same key.
+    pub fn derive_key(&self, purpose: &str) -> [u8; SECRET_LEN] {
+        to_key(hmac(&self.key, &[purpose.as_bytes()]))
+    }
+}
+
+impl Drop for AuthSession {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl fmt::Debug for AuthSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthSession(..)")
+    }
+}
+
+/// Which end of the connection is authenticating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthRole {
+    Client,
+    Server,
+}
+
+impl AuthRole {
+    fn label(self) -> &'static [u8] {
+        match self {
+            AuthRole::Client => b"talpid-ipc client",
+            AuthRole::Server => b"talpid-ipc server",
+        }
+    }
+
+    fn peer(self) -> AuthRole {
+        match self {
+            AuthRole::Client => AuthRole::Server,
+            AuthRole::Server => AuthRole::Client,
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> FramedConnection<T> {
+    /// Prove to the peer that this end knows `secret`, and check that the peer does as well.
+    /// The peer must call `authenticate` with the same secret and the other `role`, at the same
+    /// point of the connection, e.g. right after the [`handshake`](Self::handshake). Fails with
+    /// [`Error::AuthenticationFailed`] if the peer does not know the secret, in which case the
+    /// connection should be closed.
+    ///
+    /// Returns the session, which is bound to the challenges of both ends.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, secret), err)
+    )]
+    pub async fn authenticate(
+        &mut self,
+        secret: &InstallSecret,
+        role: AuthRole,
+    ) -> Result<AuthSession, Error> {
+        let challenge: [u8; CHALLENGE_LEN] = random();
+        self.write_raw(&challenge).await.map_err(with_context)?;
+
+        let peer_challenge = self.read_raw(CHALLENGE_LEN).await.map_err(with_context)?;
+        // A peer that sends our own challenge back may be trying to get us to answer for it
+        if *peer_challenge == challenge {
+            return Err(Error::AuthenticationFailed);
+        }
+        let peer_challenge = peer_challenge[..].try_into().unwrap();
+        let challenges = match role {
+            AuthRole::Client => Challenges {
+                client: challenge,
+                server: peer_challenge,
+            },
+            AuthRole::Server => Challenges {
+                client: peer_challenge,
+                server: challenge,
+            },
+        };
+        let answer = to_key(secret.mac(role.label(), &challenges));
+        self.write_raw(&answer).await.map_err(with_context)?;
+
+        let peer_answer = self.read_raw(ANSWER_LEN).await.map_err(with_context)?;
+        // Compares in constant time
+        secret
+            .mac(role.peer().label(), &challenges)
+            .verify_slice(&peer_answer)
+            .map_err(|_| Error::AuthenticationFailed)?;
+        Ok(AuthSession::new(secret, &challenges))
+    }
+
+    /// Like [`Self::authenticate`] as the client, but also receive a ticket for resuming the
+    /// session later. The server must call [`Self::accept_authentication`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, secret), err)
+    )]
+    pub async fn authenticate_for_ticket(
+        &mut self,
+        secret: &InstallSecret,
+    ) -> Result<(AuthSession, ResumptionTicket), Error> {
+        let challenge: [u8; CHALLENGE_LEN] = random();
+        let mut hello = vec![MODE_FULL];
+        hello.extend_from_slice(&challenge);
+        self.write_raw(&hello).await.map_err(with_context)?;
+
+        let mut reply = self
+            .read_raw(CHALLENGE_LEN + ANSWER_LEN + TICKET_LEN + KEY_LEN)
+            .await
+            .map_err(with_context)?;
+        let challenges = Challenges {
+            client: challenge,
+            server: reply.split_to(CHALLENGE_LEN)[..].try_into().unwrap(),
+        };
+        secret
+            .mac(AuthRole::Server.label(), &challenges)
+            .verify_slice(&reply.split_to(ANSWER_LEN))
+            .map_err(|_| Error::AuthenticationFailed)?;
+        let answer = to_key(secret.mac(AuthRole::Client.label(), &challenges));
+        self.write_raw(&answer).await.map_err(with_context)?;
+
+        let session = AuthSession::new(secret, &challenges);
+        let ticket = ResumptionTicket::unmask(&reply, &session);
+        Ok((session, ticket))
+    }
+
+    /// Resume a session with a ticket from an earlier one, instead of authenticating again. The
+    /// server must call [`Self::accept_authentication`]. Fails with
+    /// [`Error::AuthenticationFailed`] if the server rejects the ticket, e.g. because it has
+    /// expired, in which case the client should connect again and authenticate.
+    ///
+    /// Returns the session, and a new ticket to use the next time.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, ticket), err)
+    )]
+    pub async fn resume(
+        &mut self,
+        ticket: &ResumptionTicket,
+    ) -> Result<(AuthSession, ResumptionTicket), Error> {
+        let challenge: [u8; CHALLENGE_LEN] = random();
+        let mut hello = vec![MODE_RESUME];
+        hello.extend_from_slice(&ticket.ticket);
+        hello.extend_from_slice(&challenge);
+        self.write_raw(&hello).await.map_err(with_context)?;
+
+        let status = self.read_raw(1).await.map_err(with_context)?;
+        if status[0] != TICKET_ACCEPTED {
+            return Err(Error::AuthenticationFailed);
+        }
+        let mut reply = self
+            .read_raw(CHALLENGE_LEN + ANSWER_LEN + TICKET_LEN + KEY_LEN)
+            .await
+            .map_err(with_context)?;
+        let challenges = Challenges {
+            client: challenge,
+            server: reply.split_to(CHALLENGE_LEN)[..].try_into().unwrap(),
+        };
+        ticket
+            .mac(AuthRole::Server.label(), &challenges)
+            .verify_slice(&reply.split_to(ANSWER_LEN))
+            .map_err(|_| Error::AuthenticationFailed)?;
+        let answer = to_key(ticket.mac(AuthRole::Client.label(), &challenges));
+        self.write_raw(&answer).await.map_err(with_context)?;
+
+        let session = AuthSession {
+            key: to_key(ticket.mac(SESSION_LABEL, &challenges)),
+        };
+        let ticket = ResumptionTicket::unmask(&reply, &session);
+        Ok((session, ticket))
+    }
+
+    /// Authenticate a client that calls [`Self::authenticate_for_ticket`] or [`Self::resume`],
+    /// and give it a ticket from `issuer`. Fails with [`Error::AuthenticationFailed`] if the
+    /// client does not know `secret`, or presents a ticket that is invalid, has expired or has
+    /// already been used.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, secret, issuer), err)
+    )]
+    pub async fn accept_authentication(
+        &mut self,
+        secret: &InstallSecret,
+        issuer: &TicketIssuer,
+    ) -> Result<AuthSession, Error> {
+        let mode = self.read_raw(1).await.map_err(with_context)?;
+        match mode[0] {
+            MODE_FULL => {
+                let client_challenge = self.read_raw(CHALLENGE_LEN).await.map_err(with_context)?;
+                let challenges = Challenges {
+                    client: client_challenge[..].try_into().unwrap(),
+                    server: random(),
+                };
+                let session = AuthSession::new(secret, &challenges);
+                let mut reply = challenges.server.to_vec();
+                reply.extend_from_slice(&to_key(secret.mac(AuthRole::Server.label(), &challenges)));
+                issuer.issue(&session, &mut reply);
+                self.write_raw(&reply).await.map_err(with_context)?;
+
+                let answer = self.read_raw(ANSWER_LEN).await.map_err(with_context)?;
+                secret
+                    .mac(AuthRole::Client.label(), &challenges)
+                    .verify_slice(&answer)
+                    .map_err(|_| Error::AuthenticationFailed)?;
+                Ok(session)
+            }
+            MODE_RESUME => {
+                let mut hello = self
+                    .read_raw(TICKET_LEN + CHALLENGE_LEN)
+                    .await
+                    .map_err(with_context)?;
+                let Some(ticket) = issuer.check(&hello.split_to(TICKET_LEN)) else {
+                    self.write_raw(&[TICKET_REJECTED])
+                        .await
+                        .map_err(with_context)?;
+                    return Err(Error::AuthenticationFailed);
+                };
+                let challenges = Challenges {
+                    client: hello[..].try_into().unwrap(),
+                    server: random(),
+                };
+                let session = AuthSession {
+                    key: to_key(ticket.mac(SESSION_LABEL, &challenges)),
+                };
+                let mut reply = vec![TICKET_ACCEPTED];
+                reply.extend_from_slice(&challenges.server);
+                reply.extend_from_slice(&to_key(ticket.mac(AuthRole::Server.label(), &challenges)));
+                // The new ticket is of no use to anyone who does not know the key of this one
+                issuer.issue(&session, &mut reply);
+                self.write_raw(&reply).await.map_err(with_context)?;
+
+                let answer = self.read_raw(ANSWER_LEN).await.map_err(with_context)?;
+                ticket
+                    .mac(AuthRole::Client.label(), &challenges)
+                    .verify_slice(&answer)
+                    .map_err(|_| Error::AuthenticationFailed)?;
+                // Another connection may have used the ticket in the meantime
+                if !issuer.redeem(&ticket) {
+                    return Err(Error::AuthenticationFailed);
+                }
+                Ok(session)
+            }
+            _ => Err(Error::Protocol("Unknown authentication mode")),
+        }
+    }
+}
+
+impl FramedConnection<crate::Connection> {
+    /// Like [`Self::accept_authentication`], but if the client fails to authenticate, report it
+    /// to the [`IpcMetrics`] and event sink of the endpoint that accepted the connection,
+    /// together with who the client is. Servers should use this instead of
+    /// `accept_authentication`.
+    ///
+    /// [`IpcMetrics`]: crate::metrics::IpcMetrics
+    pub async fn authenticate_client(
+        &mut self,
+        secret: &InstallSecret,
+        issuer: &TicketIssuer,
+    ) -> Result<AuthSession, Error> {
+        let result = self.accept_authentication(secret, issuer).await;
+        if let Err(Error::AuthenticationFailed) = result {
+            self.get_ref()
+                .report_handshake_failure(HandshakeFailureReason::AuthenticationFailed);
+        }
+        result
+    }
+}
+
+/// Lets a client resume a session, see [`FramedConnection::resume`].
+#[derive(Clone)]
+pub struct ResumptionTicket {
+    ticket: [u8; TICKET_LEN],
+    /// Proves that the client was given the ticket.
+    key: [u8; KEY_LEN],
+}
+
+impl ResumptionTicket {
+    /// HMAC of `label` and the challenges of the client and the server, keyed with the key of the
+    /// ticket.
+    fn mac(&self, label: &[u8], challenges: &Challenges) -> HmacSha256 {
+        ticket_mac(&self.key, label, challenges)
+    }
+
+    /// Read a ticket and its key, which is masked with a key of `session`, from `bytes`.
+    fn unmask(bytes: &[u8], session: &AuthSession) -> Self {
+        let (ticket, masked_key) = bytes.split_at(TICKET_LEN);
+        ResumptionTicket {
+            ticket: ticket.try_into().unwrap(),
+            key: xor(
+                masked_key.try_into().unwrap(),
+                session.derive_key(TICKET_MASK),
+            ),
+        }
+    }
+
+    /// When the server stops accepting the ticket.
+    pub fn expires_at(&self) -> SystemTime {
+        let secs = u64::from_be_bytes(self.ticket[..8].try_into().unwrap());
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    /// Whether the ticket has expired. Resuming with it would fail.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at() <= SystemTime::now()
+    }
+}
+
+impl fmt::Debug for ResumptionTicket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResumptionTicket")
+            .field("expires_at", &self.expires_at())
+            .finish_non_exhaustive()
+    }
+}
+
+fn ticket_mac(key: &[u8; KEY_LEN], label: &[u8], challenges: &Challenges) -> HmacSha256 {
+    hmac(key, &[label, &challenges.client, &challenges.server])
+}
+
+/// A ticket that a client has presented, and that is valid unless it has been used.
+struct PresentedTicket {
+    id: [u8; TICKET_ID_LEN],
+    expires: u64,
+    key: [u8; KEY_LEN],
+}
+
+impl PresentedTicket {
+    fn mac(&self, label: &[u8], challenges: &Challenges) -> HmacSha256 {
+        ticket_mac(&self.key, label, challenges)
+    }
+}
+
+/// Issues and checks the tickets of a server. Share one between all connections.
+pub struct TicketIssuer {
+    /// Key that tickets are checked with, derived from the secret.
+    key: [u8; KEY_LEN],
+    lifetime: Duration,
+    /// IDs of the tickets that have been used, and when they expire.
+    used: Mutex<HashMap<[u8; TICKET_ID_LEN], u64>>,
+}
+
+impl TicketIssuer {
+    /// Issue tickets that are valid for [`DEFAULT_TICKET_LIFETIME`]. Tickets remain valid for
+    /// every issuer that is created with the same `secret`.
+    pub fn new(secret: &InstallSecret) -> Self {
+        TicketIssuer {
+            key: to_key(hmac(&secret.0, &[TICKET_KEY_LABEL])),
+            lifetime: DEFAULT_TICKET_LIFETIME,
+            used: Mutex::default(),
+        }
+    }
+
+    /// Issue tickets that are valid for `lifetime`, rounded down to whole seconds.
+    pub fn set_lifetime(&mut self, lifetime: Duration) {
+        self.lifetime = lifetime;
+    }
+
+    /// Append a new ticket, and its key masked with a key of `session`, to `reply`.
+    fn issue(&self, session: &AuthSession, reply: &mut Vec<u8>) {
+        let expires = now_secs() + self.lifetime.as_secs();
+        let id: [u8; TICKET_ID_LEN] = random();
+        let body = [&expires.to_be_bytes()[..], &id[..]].concat();
+        let mac = to_key(hmac(&self.key, &[&body[..]]));
+        let key = to_key(hmac(&self.key, &[RESUMPTION_LABEL, &body[..]]));
+        reply.extend_from_slice(&body);
+        reply.extend_from_slice(&mac);
+        reply.extend_from_slice(&xor(key, session.derive_key(TICKET_MASK)));
+    }
+
+    /// Check that `ticket` was issued with the key of this issuer, and has neither expired nor
+    /// been used.
+    fn check(&self, ticket: &[u8]) -> Option<PresentedTicket> {
+        let (body, mac) = ticket.split_at(8 + TICKET_ID_LEN);
+        hmac(&self.key, &[body]).verify_slice(mac).ok()?;
+        let ticket = PresentedTicket {
+            id: body[8..].try_into().unwrap(),
+            expires: u64::from_be_bytes(body[..8].try_into().unwrap()),
+            key: to_key(hmac(&self.key, &[RESUMPTION_LABEL, body])),
+        };
+        let now = now_secs();
+        if ticket.expires <= now {
+            return None;
+        }
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, &mut expires| expires > now);
+        (!used.contains_key(&ticket.id)).then_some(ticket)
+    }
+
+    /// Mark `ticket` as used, once the client has proven that it was given the ticket. Returns
+    /// `false` if it already has been.
+    fn redeem(&self, ticket: &PresentedTicket) -> bool {
+        let mut used = self.used.lock().unwrap();
+        used.insert(ticket.id, ticket.expires).is_none()
+    }
+}
+
+/// Seconds since the Unix epoch.
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::duplex;
+
+    async fn authenticate(
+        client_secret: &InstallSecret,
+        server_secret: &InstallSecret,
+    ) -> (Result<AuthSession, Error>, Result<AuthSession, Error>) {
+        let (client, server) = duplex(256);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        tokio::join!(
+            client.authenticate(client_secret, AuthRole::Client),
+            server.authenticate(server_secret, AuthRole::Server),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let secret = InstallSecret::generate();
+        let (client_result, server_result) = authenticate(&secret, &secret).await;
+        let client_session = client_result.unwrap();
+        let server_session = server_result.unwrap();
+        assert_eq!(
+            client_session.derive_key("test"),
+            server_session.derive_key("test")
+        );
+
+        // Every session is bound to new challenges
+        let (client_result, _) = authenticate(&secret, &secret).await;
+        assert_ne!(
+            client_result.unwrap().derive_key("test"),
+            client_session.derive_key("test")
+        );
+
+        let (client_result, server_result) =
+            authenticate(&secret, &InstallSecret::generate()).await;
+        assert!(matches!(client_result, Err(Error::AuthenticationFailed)));
+        assert!(matches!(server_result, Err(Error::AuthenticationFailed)));
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_report_failure() {
+        use crate::metrics::IpcCounters;
+        use std::sync::Arc;
+
+        let counters = Arc::new(IpcCounters::default());
+        let mut endpoint = crate::testing::EphemeralEndpoint::with_options(|endpoint| {
+            endpoint.set_metrics(counters.clone())
+        })
+        .unwrap();
+        let (client, server) = endpoint.connected_pair().await.unwrap();
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        let secret = InstallSecret::generate();
+        let (_, server_result) = tokio::join!(
+            client.authenticate_for_ticket(&InstallSecret::generate()),
+            server.authenticate_client(&secret, &TicketIssuer::new(&secret)),
+        );
+        assert!(matches!(server_result, Err(Error::AuthenticationFailed)));
+        assert_eq!(counters.snapshot().handshake_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn test_same_role() {
+        // An end that answers as the client cannot pass for the server
+        let secret = InstallSecret::generate();
+        let (client, server) = duplex(256);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        let (client_result, server_result) = tokio::join!(
+            client.authenticate(&secret, AuthRole::Client),
+            server.authenticate(&secret, AuthRole::Client),
+        );
+        assert!(matches!(client_result, Err(Error::AuthenticationFailed)));
+        assert!(matches!(server_result, Err(Error::AuthenticationFailed)));
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        // Record the answer of a client, and replay it to the server of a new session
+        let secret = InstallSecret::generate();
+        let (client, server) = duplex(256);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        let (_, transcript) = tokio::join!(client.authenticate(&secret, AuthRole::Client), async {
+            let challenge = server.read_raw(CHALLENGE_LEN).await.unwrap();
+            server.write_raw(&[1u8; CHALLENGE_LEN]).await.unwrap();
+            let answer = server.read_raw(ANSWER_LEN).await.unwrap();
+            (challenge, answer)
+        },);
+
+        let (attacker, server) = duplex(256);
+        let mut attacker = FramedConnection::new(attacker);
+        let mut server = FramedConnection::new(server);
+        let (_, server_result) = tokio::join!(
+            async {
+                attacker.write_raw(&transcript.0).await.unwrap();
+                attacker.read_raw(CHALLENGE_LEN).await.unwrap();
+                attacker.write_raw(&transcript.1).await.unwrap();
+            },
+            server.authenticate(&secret, AuthRole::Server),
+        );
+        assert!(matches!(server_result, Err(Error::AuthenticationFailed)));
+    }
+
+    async fn resume(
+        ticket: &ResumptionTicket,
+        secret: &InstallSecret,
+        issuer: &TicketIssuer,
+    ) -> (
+        Result<(AuthSession, ResumptionTicket), Error>,
+        Result<AuthSession, Error>,
+    ) {
+        let (client, server) = duplex(256);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        tokio::join!(
+            client.resume(ticket),
+            server.accept_authentication(secret, issuer),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let secret = InstallSecret::generate();
+        let issuer = TicketIssuer::new(&secret);
+        let (client, server) = duplex(256);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        let (client_result, server_result) = tokio::join!(
+            client.authenticate_for_ticket(&secret),
+            server.accept_authentication(&secret, &issuer),
+        );
+        let (_, ticket) = client_result.unwrap();
+        server_result.unwrap();
+        assert!(!ticket.is_expired());
+
+        let (client_result, server_result) = resume(&ticket, &secret, &issuer).await;
+        let (client_session, new_ticket) = client_result.unwrap();
+        assert_eq!(
+            client_session.derive_key("test"),
+            server_result.unwrap().derive_key("test")
+        );
+
+        // Tickets can only be used once
+        let (client_result, server_result) = resume(&ticket, &secret, &issuer).await;
+        assert!(matches!(client_result, Err(Error::AuthenticationFailed)));
+        assert!(matches!(server_result, Err(Error::AuthenticationFailed)));
+
+        // The new ticket remains valid after a restart
+        let issuer = TicketIssuer::new(&secret);
+        let (client_result, _) = resume(&new_ticket, &secret, &issuer).await;
+        client_result.unwrap();
+
+        // But not for another installation
+        let other_secret = InstallSecret::generate();
+        let (client_result, _) = resume(
+            &new_ticket,
+            &other_secret,
+            &TicketIssuer::new(&other_secret),
+        )
+        .await;
+        assert!(matches!(client_result, Err(Error::AuthenticationFailed)));
+    }
+
+    #[tokio::test]
+    async fn test_replayed_resumption() {
+        let secret = InstallSecret::generate();
+        let issuer = TicketIssuer::new(&secret);
+        let (client, server) = duplex(256);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        let (client_result, _) = tokio::join!(
+            client.authenticate_for_ticket(&secret),
+            server.accept_authentication(&secret, &issuer),
+        );
+        let (_, ticket) = client_result.unwrap();
+
+        // Record what the client sends while resuming, as it passes through to the server
+        let (client, proxy_client) = duplex(256);
+        let (proxy_server, server) = duplex(256);
+        let mut client = FramedConnection::new(client);
+        let mut proxy_client = FramedConnection::new(proxy_client);
+        let mut proxy_server = FramedConnection::new(proxy_server);
+        let mut server = FramedConnection::new(server);
+        let proxy = async {
+            let hello = proxy_client
+                .read_raw(1 + TICKET_LEN + CHALLENGE_LEN)
+                .await
+                .unwrap();
+            proxy_server.write_raw(&hello).await.unwrap();
+            let reply = proxy_server
+                .read_raw(1 + CHALLENGE_LEN + ANSWER_LEN + TICKET_LEN + KEY_LEN)
+                .await
+                .unwrap();
+            proxy_client.write_raw(&reply).await.unwrap();
+            let answer = proxy_client.read_raw(ANSWER_LEN).await.unwrap();
+            proxy_server.write_raw(&answer).await.unwrap();
+            (hello, answer)
+        };
+        let (client_result, server_result, transcript) = tokio::join!(
+            client.resume(&ticket),
+            server.accept_authentication(&secret, &issuer),
+            proxy,
+        );
+        client_result.unwrap();
+        server_result.unwrap();
+
+        // Replaying it after a restart fails, although the new issuer accepts the ticket
+        let issuer = TicketIssuer::new(&secret);
+        let (attacker, server) = duplex(256);
+        let mut attacker = FramedConnection::new(attacker);
+        let mut server = FramedConnection::new(server);
+        let (_, server_result) = tokio::join!(
+            async {
+                attacker.write_raw(&transcript.0).await.unwrap();
+                let reply = attacker.read_raw(1).await.unwrap();
+                assert_eq!(reply[0], TICKET_ACCEPTED);
+                attacker
+                    .read_raw(CHALLENGE_LEN + ANSWER_LEN + TICKET_LEN + KEY_LEN)
+                    .await
+                    .unwrap();
+                attacker.write_raw(&transcript.1).await.unwrap();
+            },
+            server.accept_authentication(&secret, &issuer),
+        );
+        assert!(matches!(server_result, Err(Error::AuthenticationFailed)));
+    }
+
+    #[tokio::test]
+    async fn test_expired_ticket() {
+        let secret = InstallSecret::generate();
+        let mut issuer = TicketIssuer::new(&secret);
+        issuer.set_lifetime(Duration::ZERO);
+        let (client, server) = duplex(256);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        let (client_result, _) = tokio::join!(
+            client.authenticate_for_ticket(&secret),
+            server.accept_authentication(&secret, &issuer),
+        );
+        let (_, ticket) = client_result.unwrap();
+        assert!(ticket.is_expired());
+
+        let (client_result, _) = resume(&ticket, &secret, &issuer).await;
+        assert!(matches!(client_result, Err(Error::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn test_secret_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret");
+        let secret = InstallSecret::generate();
+        secret.write_to(&path).unwrap();
+        assert_eq!(InstallSecret::read_from(&path).unwrap(), secret);
+
+        std::fs::write(&path, b"short").unwrap();
+        let error = InstallSecret::read_from(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_secret_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret");
+        let target = dir.path().join("target");
+        // The temporary file of the write must not be followed to somewhere else
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        std::os::unix::fs::symlink(&target, &temp_path).unwrap();
+
+        InstallSecret::generate().write_to(&path).unwrap();
+
+        assert!(!target.exists());
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, SECRET_MODE);
+    }
+}
@@ -0,0 +1,177 @@
+//! Delays between retries.
+//!
+//! Every retry loop in this crate asks a [`Backoff`] how long to wait before the next attempt:
+//! retrying transient accept errors ([`crate::Endpoint::set_accept_backoff`]), waiting for a
+//! server to start listening ([`crate::Endpoint::connect_with_backoff`]), waiting for a busy
+//! pipe, and recreating pipe instances on Windows. The policies are stateless, so that one can
+//! be shared by any number of loops. Each loop counts its own consecutive failures.
+//!
+//! When the daemon restarts, all of its clients lose their connections at the same time. If
+//! they all retried after the same delays, they would keep hitting the server at the same time,
+//! e.g. competing for a single pending pipe instance. Connecting therefore waits for delays
+//! with [`Jitter`] by default, so that the attempts of different clients spread out.
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+/// Decides how long to wait before retrying something that has failed.
+pub trait Backoff: fmt::Debug + Send + Sync {
+    /// Return the delay before retrying after `attempt` consecutive failures, starting at 1, or
+    /// `None` to give up and return the last error.
+    fn delay(&self, attempt: u32) -> Option<Duration>;
+
+    /// Shorten each delay by a random fraction of up to `ratio`. See [`Jitter`].
+    fn with_jitter(self, ratio: f64) -> Jitter<Self>
+    where
+        Self: Sized,
+    {
+        Jitter::new(self, ratio)
+    }
+}
+
+/// Wait `initial` after the first failure, doubling the delay after each further failure up
+/// to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExponentialBackoff {
+    initial: Duration,
+    max: Duration,
+    max_attempts: Option<u32>,
+}
+
+impl ExponentialBackoff {
+    pub const fn new(initial: Duration, max: Duration) -> Self {
+        ExponentialBackoff {
+            initial,
+            max,
+            max_attempts: None,
+        }
+    }
+
+    /// Give up after `attempts` consecutive failures. By default, there is no limit.
+    pub fn set_max_attempts(&mut self, attempts: u32) {
+        self.max_attempts = Some(attempts);
+    }
+}
+
+impl Backoff for ExponentialBackoff {
+    fn delay(&self, attempt: u32) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| attempt > max) {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        Some(self.initial.saturating_mul(factor).min(self.max))
+    }
+}
+
+/// Wait the same time after every failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConstantBackoff {
+    delay: Duration,
+    max_attempts: Option<u32>,
+}
+
+impl ConstantBackoff {
+    pub const fn new(delay: Duration) -> Self {
+        ConstantBackoff {
+            delay,
+            max_attempts: None,
+        }
+    }
+
+    /// Give up after `attempts` consecutive failures. By default, there is no limit.
+    pub fn set_max_attempts(&mut self, attempts: u32) {
+        self.max_attempts = Some(attempts);
+    }
+}
+
+impl Backoff for ConstantBackoff {
+    fn delay(&self, attempt: u32) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| attempt > max) {
+            return None;
+        }
+        Some(self.delay)
+    }
+}
+
+/// Shortens the delays of another policy by a random fraction of up to `ratio`, which is
+/// clamped to between 0 and 1. With a ratio of 0.5, a delay of 100 ms becomes anything from 50
+/// to 100 ms.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Jitter<B> {
+    inner: B,
+    ratio: f64,
+}
+
+impl<B> Jitter<B> {
+    pub const fn new(inner: B, ratio: f64) -> Self {
+        Jitter { inner, ratio }
+    }
+}
+
+impl<B: Backoff> Backoff for Jitter<B> {
+    fn delay(&self, attempt: u32) -> Option<Duration> {
+        let delay = self.inner.delay(attempt)?;
+        let ratio = self.ratio.clamp(0.0, 1.0);
+        Some(delay.mul_f64(1.0 - ratio * random_fraction()))
+    }
+}
+
+/// Return a random number in `[0, 1)`. This does not need to be unpredictable, only to differ
+/// between processes and calls, so the random keys of the standard library hasher do.
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    // Use the 53 bits that fit in the mantissa
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exponential() {
+        let mut backoff =
+            ExponentialBackoff::new(Duration::from_millis(100), Duration::from_millis(350));
+        let delays: Vec<_> = (1..=4).map(|attempt| backoff.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 350, 350].map(|ms| Some(Duration::from_millis(ms)))
+        );
+        assert_eq!(backoff.delay(u32::MAX), Some(Duration::from_millis(350)));
+
+        backoff.set_max_attempts(2);
+        assert!(backoff.delay(2).is_some());
+        assert_eq!(backoff.delay(3), None);
+    }
+
+    #[test]
+    fn test_constant() {
+        let mut backoff = ConstantBackoff::new(Duration::from_millis(50));
+        assert_eq!(backoff.delay(1), Some(Duration::from_millis(50)));
+        assert_eq!(backoff.delay(1000), Some(Duration::from_millis(50)));
+        backoff.set_max_attempts(0);
+        assert_eq!(backoff.delay(1), None);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut inner = ConstantBackoff::new(Duration::from_millis(100));
+        let backoff = inner.with_jitter(0.5);
+        let delays: Vec<_> = (1..=100).map(|_| backoff.delay(1).unwrap()).collect();
+        assert!(delays.iter().all(|delay| {
+            (Duration::from_millis(50)..=Duration::from_millis(100)).contains(delay)
+        }));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+
+        assert_eq!(
+            inner.with_jitter(0.0).delay(1),
+            Some(Duration::from_millis(100))
+        );
+        inner.set_max_attempts(1);
+        assert_eq!(inner.with_jitter(1.0).delay(2), None);
+    }
+}
@@ -0,0 +1,208 @@
+//! Connect to an endpoint, send frames read from stdin, and print the frames that arrive.
+//!
+//! Meant for diagnosing communication with the daemon by hand. Every line of input is either a
+//! JSON document, which is sent as the body of an RPC request, or with `--hex`, the payload of a
+//! data frame in hex, which is sent as is. Data frames that arrive are decoded as RPC messages
+//! when possible, and their bodies are pretty-printed as JSON, or as a hex dump if they are
+//! not JSON.
+//!
+//! Once stdin is closed, a goodbye is sent, and frames are printed until the server closes the
+//! connection.
+
+use clap::Parser;
+use serde_json::Value;
+use std::{fmt::Write, process::ExitCode};
+use talpid_ipc::{
+    Endpoint, Error,
+    frame::{Frame, FrameKind, FramedConnection, RejectReason},
+    handshake::Capabilities,
+    rpc::{Message, MessageKind},
+};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+#[derive(Parser)]
+#[command(about = "Send frames to an IPC endpoint and print the frames that it sends")]
+struct Args {
+    /// Path of the socket, or name of the pipe, to connect to
+    path: String,
+
+    /// Read frame payloads in hex instead of JSON request bodies
+    #[arg(long)]
+    hex: bool,
+
+    /// Perform the handshake with this protocol version before sending anything
+    #[arg(long, value_name = "VERSION")]
+    handshake: Option<u16>,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    match run(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("ipc-cat: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<(), Error> {
+    let mut connection = FramedConnection::new(Endpoint::connect(&args.path).await?);
+    if let Some(version) = args.handshake {
+        let capabilities = connection.handshake(version, Capabilities::empty()).await?;
+        eprintln!("Handshake succeeded, common capabilities: {capabilities:?}");
+    }
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdin_open = true;
+    let mut next_id = 1;
+    loop {
+        tokio::select! {
+            line = lines.next_line(), if stdin_open => {
+                let Some(line) = line? else {
+                    stdin_open = false;
+                    connection.send_goodbye().await?;
+                    continue;
+                };
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                match encode_line(line, args.hex, next_id) {
+                    Ok(frame) => connection.write_frame(&frame).await?,
+                    Err(error) => eprintln!("Skipping invalid input: {error}"),
+                }
+                next_id += 1;
+            }
+            frame = connection.read_frame() => match frame? {
+                Some(frame) => print!("{}", describe(&frame)),
+                None => break,
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Turn a line of input into a frame. JSON documents become requests with the ID `id`.
+fn encode_line(line: &str, hex: bool, id: u64) -> Result<Frame, String> {
+    if hex {
+        decode_hex(line).map(Frame::data)
+    } else {
+        let body: Value = serde_json::from_str(line).map_err(|error| error.to_string())?;
+        Ok(Message::new(MessageKind::Request, id, body.to_string()).to_frame())
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = hex.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("Odd number of hex digits".to_owned());
+    }
+    digits
+        .chunks_exact(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("Invalid hex: {}", String::from_utf8_lossy(pair)))
+        })
+        .collect()
+}
+
+/// Describe a received frame, one line for the frame and then its contents.
+fn describe(frame: &Frame) -> String {
+    let mut out = String::new();
+    match frame.kind {
+        FrameKind::Goodbye => {
+            let _ = writeln!(out, "<- Goodbye: {}", frame.goodbye_reason());
+        }
+        FrameKind::Reject => {
+            let reason = frame.payload.first().copied().map(RejectReason::from);
+            let _ = writeln!(
+                out,
+                "<- Reject: {}",
+                reason.unwrap_or(RejectReason::Other(0))
+            );
+        }
+        FrameKind::Data => match Message::from_frame(frame) {
+            Ok(message) => {
+                let _ = writeln!(out, "<- {:?} #{}", message.kind, message.id);
+                write_body(&mut out, &message.body);
+            }
+            Err(_) => {
+                let _ = writeln!(out, "<- Data, {} bytes", frame.payload.len());
+                write_hex_dump(&mut out, &frame.payload);
+            }
+        },
+        kind => {
+            let _ = writeln!(out, "<- {kind:?}, {} bytes", frame.payload.len());
+            write_hex_dump(&mut out, &frame.payload);
+        }
+    }
+    out
+}
+
+/// Write a message body as pretty-printed JSON, as text, or as a hex dump, whichever works.
+fn write_body(out: &mut String, body: &[u8]) {
+    if body.is_empty() {
+        return;
+    }
+    if let Ok(json) = serde_json::from_slice::<Value>(body) {
+        let json = serde_json::to_string_pretty(&json).unwrap_or_default();
+        for line in json.lines() {
+            let _ = writeln!(out, "    {line}");
+        }
+    } else if let Ok(text) = std::str::from_utf8(body) {
+        let _ = writeln!(out, "    {text}");
+    } else {
+        write_hex_dump(out, body);
+    }
+}
+
+fn write_hex_dump(out: &mut String, bytes: &[u8]) {
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{byte:02x}")).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| match byte {
+                b' '..=b'~' => char::from(byte),
+                _ => '.',
+            })
+            .collect();
+        let _ = writeln!(
+            out,
+            "    {:08x}  {:<47}  |{ascii}|",
+            line * 16,
+            hex.join(" ")
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("00 ff\t1A").unwrap(), [0x00, 0xff, 0x1a]);
+        assert!(decode_hex("abc").is_err());
+        assert!(decode_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_describe() {
+        let request = encode_line(r#"{"Ping": null}"#, false, 7).unwrap();
+        assert_eq!(
+            describe(&request),
+            "<- Request #7\n    {\n      \"Ping\": null\n    }\n"
+        );
+        let raw = encode_line("00ff", true, 8).unwrap();
+        assert_eq!(
+            describe(&raw),
+            format!(
+                "<- Data, 2 bytes\n    00000000  00 ff{}  |..|\n",
+                " ".repeat(42)
+            )
+        );
+    }
+}
@@ -0,0 +1,323 @@
+//! A memory budget shared by all connections of a server.
+//!
+//! [`FramedConnection::set_memory_limit`] bounds what a single connection may buffer, but not
+//! how much many connections buffer together. A [`MemoryBudget`] does: every connection that has
+//! joined it with [`FramedConnection::set_memory_budget`] asks it for room before buffering more.
+//! What happens once the total would exceed the budget is decided by the [`SlowConsumerPolicy`]
+//! of the budget. By default, the connections that buffer the most are disconnected first, like
+//! subscribers under [`SlowConsumerPolicy::Disconnect`]. Total buffering is therefore bounded by
+//! the budget, however many clients stop reading at once. [`MemoryBudget::of_physical_memory`]
+//! creates a budget of a fraction of the memory of the machine.
+//!
+//! A connection that is disconnected is woken up even if it is waiting for its peer, frees its
+//! buffers, tells the peer why with [`GoodbyeReason::MemoryExceeded`], and fails with
+//! [`Error::MemoryBudgetExceeded`]. If the connection is served by an [`IpcServer`], it is
+//! disconnected by the server as well, so that the handler is dropped along with its buffers
+//! even if it is not reading from the connection.
+//!
+//! [`FramedConnection::set_memory_limit`]: crate::frame::FramedConnection::set_memory_limit
+//! [`FramedConnection::set_memory_budget`]: crate::frame::FramedConnection::set_memory_budget
+//! [`GoodbyeReason::MemoryExceeded`]: crate::frame::GoodbyeReason::MemoryExceeded
+//! [`Error::MemoryBudgetExceeded`]: crate::Error::MemoryBudgetExceeded
+//! [`IpcServer`]: crate::server::IpcServer
+
+use crate::{events::SlowConsumerPolicy, imp};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{Notify, futures::Notified};
+use tokio_util::sync::CancellationToken;
+
+/// Limits the number of bytes that all connections that share it buffer together.
+pub struct MemoryBudget {
+    limit: usize,
+    state: Mutex<State>,
+    /// Notified whenever a connection uses less than before, for those that wait for room.
+    released: Notify,
+}
+
+struct State {
+    /// Sum of the usage of all members.
+    used: usize,
+    members: HashMap<u64, Member>,
+    next_id: u64,
+    policy: SlowConsumerPolicy,
+}
+
+struct Member {
+    used: usize,
+    evicted: CancellationToken,
+    /// Cancelled along with `evicted`, e.g. to have a server disconnect the connection.
+    linked: Option<CancellationToken>,
+}
+
+/// The outcome of asking a [`MemoryBudget`] for room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reservation {
+    Granted,
+    /// There is no room, and the policy does not disconnect anyone to make room.
+    Refused,
+    /// The connection that asked has been disconnected.
+    Evicted,
+}
+
+impl MemoryBudget {
+    /// Create a budget of `limit` bytes.
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(MemoryBudget {
+            limit,
+            state: Mutex::new(State {
+                used: 0,
+                members: HashMap::new(),
+                next_id: 0,
+                policy: SlowConsumerPolicy::Disconnect,
+            }),
+            released: Notify::new(),
+        })
+    }
+
+    /// Create a budget of `fraction` of the physical memory of the machine, e.g. `0.05` for 5
+    /// percent. The fraction is clamped to between 0 and 1.
+    pub fn of_physical_memory(fraction: f64) -> io::Result<Arc<Self>> {
+        let memory = imp::physical_memory()? as f64;
+        Ok(MemoryBudget::new(
+            (memory * fraction.clamp(0.0, 1.0)) as usize,
+        ))
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Decide what happens when a connection asks for more room than the budget has left:
+    ///
+    /// - [`SlowConsumerPolicy::Disconnect`], the default: the connections that buffer the most,
+    ///   which may include the one that asks, are disconnected until there is room.
+    /// - [`SlowConsumerPolicy::DropNewest`]: nobody is disconnected. A frame that does not fit
+    ///   is not queued, and fails with [`crate::Error::MemoryBudgetFull`], while reading waits
+    ///   until other connections have freed enough memory.
+    /// - [`SlowConsumerPolicy::DropOldest`]: like `DropNewest`, but the connection first drops
+    ///   the frames that it has queued and not yet started to write, to make room for the new
+    ///   one. This only suits connections whose frames may be lost, such as those of events.
+    pub fn set_slow_consumer_policy(&self, policy: SlowConsumerPolicy) {
+        self.state.lock().unwrap().policy = policy;
+    }
+
+    /// Number of bytes that are buffered by all connections together.
+    pub fn used(&self) -> usize {
+        self.state.lock().unwrap().used
+    }
+
+    /// Number of connections that count against the budget.
+    pub fn connection_count(&self) -> usize {
+        self.state.lock().unwrap().members.len()
+    }
+
+    pub(crate) fn join(self: &Arc<Self>) -> BudgetShare {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        let evicted = CancellationToken::new();
+        state.members.insert(
+            id,
+            Member {
+                used: 0,
+                evicted: evicted.clone(),
+                linked: None,
+            },
+        );
+        BudgetShare {
+            budget: self.clone(),
+            id,
+            evicted,
+        }
+    }
+}
+
+impl State {
+    /// Set the usage of member `id` to `used`. Returns `true` if it uses less than before.
+    fn set_used(&mut self, id: u64, used: usize) -> bool {
+        let Some(member) = self.members.get_mut(&id) else {
+            return false;
+        };
+        let released = used < member.used;
+        self.used = self.used - member.used + used;
+        member.used = used;
+        released
+    }
+
+    /// Disconnect member `id`. What it used is no longer counted, since it is about to be freed.
+    fn evict(&mut self, id: u64) {
+        if let Some(member) = self.members.remove(&id) {
+            self.used -= member.used;
+            member.evicted.cancel();
+            if let Some(linked) = member.linked {
+                linked.cancel();
+            }
+        }
+    }
+}
+
+/// The part of a [`MemoryBudget`] that a single connection uses. It leaves the budget when
+/// dropped.
+pub(crate) struct BudgetShare {
+    budget: Arc<MemoryBudget>,
+    id: u64,
+    evicted: CancellationToken,
+}
+
+impl BudgetShare {
+    /// Ask for the usage of this connection to grow to `used` bytes. Under
+    /// [`SlowConsumerPolicy::Disconnect`], connections that use more are disconnected until there
+    /// is room, which may be this connection if it uses the most. Other policies refuse instead.
+    pub(crate) fn reserve(&self, used: usize) -> Reservation {
+        let mut state = self.budget.state.lock().unwrap();
+        let Some(current) = state.members.get(&self.id).map(|member| member.used) else {
+            return Reservation::Evicted;
+        };
+        while state.used - current + used > self.budget.limit {
+            if state.policy != SlowConsumerPolicy::Disconnect {
+                return Reservation::Refused;
+            }
+            // The worst offender, counting what this connection asks for
+            let worst = state
+                .members
+                .iter()
+                .map(|(&id, member)| (if id == self.id { used } else { member.used }, id))
+                .max();
+            match worst {
+                Some((_, id)) if id != self.id => {
+                    log::warn!("Disconnecting IPC connection to stay within the memory budget");
+                    state.evict(id);
+                    self.budget.released.notify_waiters();
+                }
+                _ => {
+                    state.evict(self.id);
+                    self.budget.released.notify_waiters();
+                    return Reservation::Evicted;
+                }
+            }
+        }
+        state.set_used(self.id, used);
+        Reservation::Granted
+    }
+
+    /// Tell the budget that this connection now uses `used` bytes. This is for memory that is
+    /// freed, or that is already in use and has to be counted whether or not there is room.
+    pub(crate) fn release(&self, used: usize) {
+        if self.budget.state.lock().unwrap().set_used(self.id, used) {
+            self.budget.released.notify_waiters();
+        }
+    }
+
+    /// Number of bytes that this connection may use without disconnecting anyone.
+    pub(crate) fn available(&self) -> usize {
+        let state = self.budget.state.lock().unwrap();
+        let current = state.members.get(&self.id).map_or(0, |member| member.used);
+        self.budget.limit.saturating_sub(state.used - current)
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        self.budget.limit
+    }
+
+    pub(crate) fn policy(&self) -> SlowConsumerPolicy {
+        self.budget.state.lock().unwrap().policy
+    }
+
+    pub(crate) fn is_evicted(&self) -> bool {
+        self.evicted.is_cancelled()
+    }
+
+    /// Cancelled when this connection is disconnected.
+    pub(crate) fn evicted(&self) -> CancellationToken {
+        self.evicted.clone()
+    }
+
+    /// Cancel `token` as well when this connection is disconnected.
+    pub(crate) fn link(&self, token: CancellationToken) {
+        if self.is_evicted() {
+            token.cancel();
+        } else if let Some(member) = self.budget.state.lock().unwrap().members.get_mut(&self.id) {
+            member.linked = Some(token);
+        }
+    }
+
+    /// Completes once another connection has used less than before, so that there may be room.
+    /// Create it before checking for room, so that no release is missed.
+    pub(crate) fn released(&self) -> Notified<'_> {
+        self.budget.released.notified()
+    }
+}
+
+impl Drop for BudgetShare {
+    fn drop(&mut self) {
+        let mut state = self.budget.state.lock().unwrap();
+        if let Some(member) = state.members.remove(&self.id) {
+            state.used -= member.used;
+            self.budget.released.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn test_worst_offender_is_evicted() {
+        let budget = MemoryBudget::new(100);
+        let small = budget.join();
+        let large = budget.join();
+        let growing = budget.join();
+
+        assert_eq!(small.reserve(10), Reservation::Granted);
+        assert_eq!(large.reserve(60), Reservation::Granted);
+        assert_eq!(growing.reserve(20), Reservation::Granted);
+        assert_eq!(budget.used(), 90);
+
+        // The large connection is evicted to make room
+        assert_eq!(growing.reserve(50), Reservation::Granted);
+        assert!(large.is_evicted());
+        assert!(!small.is_evicted());
+        assert_eq!(budget.used(), 60);
+
+        // Now the growing connection is the worst offender
+        assert_eq!(growing.reserve(100), Reservation::Evicted);
+        assert!(growing.is_evicted());
+        assert_eq!(budget.used(), 10);
+
+        drop(small);
+        assert_eq!(budget.used(), 0);
+        assert_eq!(budget.connection_count(), 0);
+    }
+
+    #[test]
+    fn test_refused_without_disconnecting() {
+        let budget = MemoryBudget::new(100);
+        budget.set_slow_consumer_policy(SlowConsumerPolicy::DropNewest);
+        let large = budget.join();
+        let growing = budget.join();
+
+        assert_eq!(large.reserve(80), Reservation::Granted);
+        assert_eq!(growing.reserve(50), Reservation::Refused);
+        assert!(!large.is_evicted());
+        assert!(!growing.is_evicted());
+        assert_eq!(budget.used(), 80);
+
+        let released = growing.released();
+        large.release(10);
+        assert!(released.now_or_never().is_some());
+        assert_eq!(growing.reserve(50), Reservation::Granted);
+    }
+
+    #[test]
+    fn test_of_physical_memory() {
+        let budget = MemoryBudget::of_physical_memory(0.5).unwrap();
+        assert!(budget.limit() > 0);
+        assert!(MemoryBudget::of_physical_memory(2.0).unwrap().limit() >= budget.limit());
+    }
+}
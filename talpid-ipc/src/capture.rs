@@ -0,0 +1,214 @@
+//! Opt-in capture of the frames sent and received on a [`FramedConnection`], for attaching to
+//! problem reports.
+//!
+//! Every frame is written to the capture file as a hex dump followed by its payload as text.
+//! Since the payloads may contain secrets, the text is passed through a list of [`Redactor`]s
+//! first, and the hex dump is produced from the redacted text rather than from the original
+//! payload. Binary payloads are therefore only captured approximately.
+//!
+//! The file is written on a thread of its own, so that capturing never holds up a connection.
+//!
+//! [`FramedConnection`]: crate::frame::FramedConnection
+
+pub use crate::frame::Direction;
+use crate::{
+    frame::Frame,
+    spool::{self, Spool},
+};
+use regex::Regex;
+use std::{
+    borrow::Cow,
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::LazyLock,
+    time::Instant,
+};
+
+/// Number of payload bytes on each line of the hex dump.
+const HEX_DUMP_WIDTH: usize = 16;
+
+/// Removes sensitive information from the text representation of a payload.
+pub trait Redactor: Send + Sync {
+    fn redact<'a>(&self, text: &'a str) -> Cow<'a, str>;
+}
+
+/// Replaces all matches of a regular expression.
+pub struct RegexRedactor {
+    regex: Regex,
+    replacement: &'static str,
+}
+
+impl RegexRedactor {
+    pub fn new(pattern: &str, replacement: &'static str) -> Result<Self, regex::Error> {
+        Ok(RegexRedactor {
+            regex: Regex::new(pattern)?,
+            replacement,
+        })
+    }
+}
+
+impl Redactor for RegexRedactor {
+    fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        self.regex.replace_all(text, self.replacement)
+    }
+}
+
+/// Redacts account numbers.
+pub struct AccountNumberRedactor;
+
+impl Redactor for AccountNumberRedactor {
+    fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        static RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\d{16}").unwrap());
+        RE.replace_all(text, "[REDACTED ACCOUNT NUMBER]")
+    }
+}
+
+/// Redacts base64-encoded WireGuard keys.
+pub struct WireguardKeyRedactor;
+
+impl Redactor for WireguardKeyRedactor {
+    fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        static RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"[A-Za-z0-9+/]{42}[AEIMQUYcgkosw048]=").unwrap());
+        RE.replace_all(text, "[REDACTED KEY]")
+    }
+}
+
+/// Destination of captured frames. It can be shared by several connections. Frames that are
+/// captured faster than they can be written are dropped once [`spool::DEFAULT_QUEUE_SIZE`]
+/// bytes are waiting, and the capture says how many were dropped in their place. Everything
+/// that was captured has been written once it is dropped.
+pub struct TrafficCapture {
+    spool: Spool,
+    redactors: Vec<Box<dyn Redactor>>,
+    started: Instant,
+}
+
+impl TrafficCapture {
+    /// Capture frames to a newly created file at `path`, redacting account numbers and WireGuard
+    /// keys.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Self::new(Box::new(BufWriter::new(file)))
+    }
+
+    /// Capture frames to `output`, redacting account numbers and WireGuard keys.
+    pub fn new(output: Box<dyn Write + Send>) -> io::Result<Self> {
+        let spool = Spool::spawn(
+            "ipc-traffic-capture",
+            "IPC traffic capture",
+            output,
+            spool::DEFAULT_QUEUE_SIZE,
+            Box::new(|count| format!("dropped {count} frames\n\n").into_bytes()),
+        )?;
+        Ok(TrafficCapture {
+            spool,
+            redactors: vec![
+                Box::new(AccountNumberRedactor),
+                Box::new(WireguardKeyRedactor),
+            ],
+            started: Instant::now(),
+        })
+    }
+
+    /// Additionally apply `redactor` to all captured payloads.
+    pub fn with_redactor(mut self, redactor: impl Redactor + 'static) -> Self {
+        self.redactors.push(Box::new(redactor));
+        self
+    }
+
+    /// Queue a frame to be written to the capture. Failing to write it is logged, but
+    /// otherwise ignored.
+    pub fn record(&self, direction: Direction, frame: &Frame) {
+        self.spool.write(self.format(direction, frame).into_bytes());
+    }
+
+    /// Number of frames that were dropped because too many were waiting to be written.
+    pub fn dropped(&self) -> u64 {
+        self.spool.dropped()
+    }
+
+    fn format(&self, direction: Direction, frame: &Frame) -> String {
+        let mut text = String::from_utf8_lossy(&frame.payload);
+        for redactor in &self.redactors {
+            if let Cow::Owned(redacted) = redactor.redact(&text) {
+                text = Cow::Owned(redacted);
+            }
+        }
+
+        let arrow = match direction {
+            Direction::Sent => ">",
+            Direction::Received => "<",
+        };
+        let mut entry = format!(
+            "{:.3}s {arrow} {:?} ({} bytes)\n",
+            self.started.elapsed().as_secs_f64(),
+            frame.kind,
+            frame.payload.len(),
+        );
+        for (line, chunk) in text.as_bytes().chunks(HEX_DUMP_WIDTH).enumerate() {
+            let _ = write!(entry, "  {:08x} ", line * HEX_DUMP_WIDTH);
+            for byte in chunk {
+                let _ = write!(entry, " {byte:02x}");
+            }
+            entry.push_str(&"   ".repeat(HEX_DUMP_WIDTH - chunk.len()));
+            entry.push_str("  ");
+            entry.extend(chunk.iter().map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            }));
+            entry.push('\n');
+        }
+        let _ = writeln!(entry, "  text: {text}");
+        entry
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redaction() {
+        let capture = TrafficCapture::new(Box::new(io::sink()))
+            .unwrap()
+            .with_redactor(RegexRedactor::new("secret", "[REDACTED]").unwrap());
+        let payload = r#"{"account":"1234567890123456","key":"yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=","note":"secret"}"#;
+        let entry = capture.format(Direction::Sent, &Frame::data(payload));
+
+        assert!(!entry.contains("1234567890123456"));
+        assert!(!entry.contains("yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk="));
+        assert!(!entry.contains("secret"));
+        assert!(entry.contains(
+            r#"text: {"account":"[REDACTED ACCOUNT NUMBER]","key":"[REDACTED KEY]","note":"[REDACTED]"}"#
+        ));
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        let capture = TrafficCapture::new(Box::new(io::sink())).unwrap();
+        let entry = capture.format(Direction::Received, &Frame::data(&b"hi\n"[..]));
+        assert!(entry.contains("< Data (3 bytes)\n"));
+        assert!(entry.contains("  00000000  68 69 0a"));
+        assert!(entry.ends_with("  hi.\n  text: hi\n\n"));
+    }
+
+    #[test]
+    fn test_written_when_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture");
+        let capture = TrafficCapture::create(&path).unwrap();
+        capture.record(Direction::Sent, &Frame::data("hello"));
+        drop(capture);
+        assert!(
+            std::fs::read_to_string(&path)
+                .unwrap()
+                .contains("text: hello")
+        );
+    }
+}
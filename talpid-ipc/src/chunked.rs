@@ -0,0 +1,372 @@
+//! Streaming of large payloads in chunks, with flow control.
+//!
+//! Payloads such as problem report archives can be many megabytes, and neither end should have
+//! to hold them in memory in full. A chunked transfer reads the payload from an [`AsyncRead`] on
+//! one end and writes it to an [`AsyncWrite`] on the other, one [`FrameKind::Chunk`] frame at a
+//! time. Unlike [`crate::shm`], it works on any connection, and the length of the payload does
+//! not have to be known in advance.
+//!
+//! The receiver grants the sender a window of bytes that it may send, and extends the window as
+//! it writes out what it has received. A receiver that writes slowly, e.g. to a slow disk,
+//! therefore holds the sender back instead of buffering what it cannot write yet.
+//!
+//! Each end finishes by ending or aborting the transfer, and then reads until the peer has done
+//! the same. Nothing of the transfer is left on the connection afterwards, so it can carry other
+//! frames again, even if the transfer failed.
+
+use crate::{
+    Error,
+    frame::{Frame, FrameKind, FramedConnection},
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Number of bytes that the sender may send ahead of what the receiver has written, unless
+/// configured otherwise.
+pub const DEFAULT_WINDOW: u32 = 256 * 1024;
+
+/// Largest chunk that is sent at once, unless configured otherwise.
+pub const DEFAULT_CHUNK_LEN: usize = 64 * 1024;
+
+const OP_START: u8 = 0;
+const OP_WINDOW: u8 = 1;
+const OP_DATA: u8 = 2;
+const OP_END: u8 = 3;
+const OP_ABORT: u8 = 4;
+
+/// Control messages and data of a chunked transfer, sent in [`FrameKind::Chunk`] frames.
+#[derive(Debug, PartialEq, Eq)]
+enum Message {
+    /// Sent by the sender to start a transfer.
+    Start,
+    /// The sender may send this many more bytes.
+    Window(u32),
+    /// The next part of the payload.
+    Data(Bytes),
+    /// Sent by the sender once the payload is complete, and answered by the receiver once it
+    /// has written all of it.
+    End,
+    /// Either end gives up on the transfer.
+    Abort,
+}
+
+impl Message {
+    fn into_frame(self) -> Frame {
+        let mut payload = BytesMut::new();
+        match self {
+            Message::Start => payload.put_u8(OP_START),
+            Message::Window(len) => {
+                payload.put_u8(OP_WINDOW);
+                payload.put_u32(len);
+            }
+            Message::Data(data) => {
+                payload.reserve(1 + data.len());
+                payload.put_u8(OP_DATA);
+                payload.extend_from_slice(&data);
+            }
+            Message::End => payload.put_u8(OP_END),
+            Message::Abort => payload.put_u8(OP_ABORT),
+        }
+        Frame::new(FrameKind::Chunk, payload.freeze())
+    }
+
+    fn from_frame(frame: Frame) -> Result<Self, Error> {
+        if frame.kind != FrameKind::Chunk {
+            return Err(Error::Protocol("Expected a chunked transfer frame"));
+        }
+        let mut payload = frame.payload;
+        if !payload.has_remaining() {
+            return Err(Error::Protocol("Empty chunked transfer frame"));
+        }
+        let message = match payload.get_u8() {
+            OP_START => Message::Start,
+            OP_WINDOW if payload.remaining() == 4 => Message::Window(payload.get_u32()),
+            OP_DATA if payload.has_remaining() => Message::Data(payload),
+            OP_END => Message::End,
+            OP_ABORT => Message::Abort,
+            _ => return Err(Error::Protocol("Malformed chunked transfer frame")),
+        };
+        Ok(message)
+    }
+}
+
+/// Options for [`send`] and [`receive`].
+#[derive(Debug, Clone)]
+pub struct TransferOptions {
+    window: u32,
+    chunk_len: usize,
+    max_len: Option<u64>,
+}
+
+impl TransferOptions {
+    pub fn new() -> Self {
+        TransferOptions::default()
+    }
+
+    /// Set how many bytes the receiver lets the sender send ahead of what it has written. This
+    /// bounds how much of the payload is buffered by the receiver. The default is
+    /// [`DEFAULT_WINDOW`], and values below 1 are treated as 1.
+    pub fn set_window(&mut self, window: u32) {
+        self.window = window.max(1);
+    }
+
+    /// Set the largest chunk that the sender sends at once. It must not exceed the payload limit
+    /// of the receiver, see [`FramedConnection::set_max_payload_len`]. The default is
+    /// [`DEFAULT_CHUNK_LEN`], and values below 1 are treated as 1.
+    pub fn set_chunk_len(&mut self, chunk_len: usize) {
+        self.chunk_len = chunk_len.max(1);
+    }
+
+    /// Make the receiver abort transfers that exceed `max_len` bytes. By default, payloads of
+    /// any length are received.
+    pub fn set_max_len(&mut self, max_len: Option<u64>) {
+        self.max_len = max_len;
+    }
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        TransferOptions {
+            window: DEFAULT_WINDOW,
+            chunk_len: DEFAULT_CHUNK_LEN,
+            max_len: None,
+        }
+    }
+}
+
+/// Send everything that `reader` yields to the peer, which must call [`receive`]. Returns the
+/// number of bytes that were sent once the peer has written all of them.
+///
+/// Fails with [`Error::TransferAborted`] if the peer aborts the transfer. If reading fails, the
+/// transfer is aborted and the error is returned.
+pub async fn send<T, R>(
+    connection: &mut FramedConnection<T>,
+    mut reader: R,
+    options: &TransferOptions,
+) -> Result<u64, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    write(connection, Message::Start).await?;
+    let mut buf = vec![0; options.chunk_len];
+    // Number of bytes that the peer lets us send
+    let mut credit = 0u64;
+    let mut sent = 0u64;
+    loop {
+        while credit == 0 {
+            match read(connection).await? {
+                Message::Window(len) => credit += u64::from(len),
+                Message::Abort => {
+                    write(connection, Message::Abort).await?;
+                    return Err(Error::TransferAborted);
+                }
+                _ => return Err(Error::Protocol("Expected a chunked transfer window")),
+            }
+        }
+        let len = usize::try_from(credit).unwrap_or(usize::MAX).min(buf.len());
+        let read = match reader.read(&mut buf[..len]).await {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(error) => {
+                abort(connection).await?;
+                return Err(error.into());
+            }
+        };
+        let data = Bytes::copy_from_slice(&buf[..read]);
+        write(connection, Message::Data(data)).await?;
+        credit -= read as u64;
+        sent += read as u64;
+    }
+
+    write(connection, Message::End).await?;
+    match drain(connection).await? {
+        Message::End => Ok(sent),
+        _ => Err(Error::TransferAborted),
+    }
+}
+
+/// Receive a payload sent by the peer using [`send`], and write it to `writer`. Returns the
+/// number of bytes that were received.
+///
+/// Fails with [`Error::TransferAborted`] if the peer aborts the transfer, and with
+/// [`Error::BulkTooLarge`] if the payload exceeds [`TransferOptions::set_max_len`]. If writing
+/// fails, the transfer is aborted and the error is returned.
+pub async fn receive<T, W>(
+    connection: &mut FramedConnection<T>,
+    mut writer: W,
+    options: &TransferOptions,
+) -> Result<u64, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if read(connection).await? != Message::Start {
+        return Err(Error::Protocol("Expected the start of a chunked transfer"));
+    }
+    write(connection, Message::Window(options.window)).await?;
+    // Number of bytes that the peer may still send
+    let mut credit = u64::from(options.window);
+    // Number of bytes that have been written since the window was last extended
+    let mut written = 0u32;
+    let mut received = 0u64;
+    loop {
+        let data = match read(connection).await? {
+            Message::Data(data) => data,
+            Message::End => break,
+            Message::Abort => {
+                write(connection, Message::Abort).await?;
+                return Err(Error::TransferAborted);
+            }
+            _ => return Err(Error::Protocol("Expected a chunk of the transfer")),
+        };
+        let len = data.len() as u64;
+        if len > credit {
+            return Err(Error::Protocol("Chunked transfer exceeds the window"));
+        }
+        credit -= len;
+        received += len;
+        if let Some(max) = options.max_len
+            && received > max
+        {
+            abort(connection).await?;
+            return Err(Error::BulkTooLarge { len: received, max });
+        }
+        if let Err(error) = writer.write_all(&data).await {
+            abort(connection).await?;
+            return Err(error.into());
+        }
+
+        // Extend the window once half of it has been written, so that the sender does not run
+        // dry while the update is on its way
+        written += data.len() as u32;
+        if written >= options.window / 2 {
+            write(connection, Message::Window(written)).await?;
+            credit += u64::from(written);
+            written = 0;
+        }
+    }
+
+    if let Err(error) = writer.flush().await {
+        write(connection, Message::Abort).await?;
+        return Err(error.into());
+    }
+    write(connection, Message::End).await?;
+    Ok(received)
+}
+
+/// Abort the transfer, and wait for the peer to finish its part of it.
+async fn abort<T: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut FramedConnection<T>,
+) -> Result<(), Error> {
+    write(connection, Message::Abort).await?;
+    drain(connection).await?;
+    Ok(())
+}
+
+/// Skip messages of the transfer until the peer ends or aborts it, and return which it did.
+async fn drain<T: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut FramedConnection<T>,
+) -> Result<Message, Error> {
+    loop {
+        match read(connection).await? {
+            message @ (Message::End | Message::Abort) => return Ok(message),
+            Message::Window(_) | Message::Data(_) => (),
+            Message::Start => return Err(Error::Protocol("Unexpected start of a transfer")),
+        }
+    }
+}
+
+async fn write<T: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut FramedConnection<T>,
+    message: Message,
+) -> Result<(), Error> {
+    connection.write_frame(&message.into_frame()).await
+}
+
+async fn read<T: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut FramedConnection<T>,
+) -> Result<Message, Error> {
+    let frame = connection.read_frame().await?.ok_or(Error::UnexpectedEof)?;
+    Message::from_frame(frame)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let messages = [
+            Message::Start,
+            Message::Window(4096),
+            Message::Data(Bytes::from_static(b"abc")),
+            Message::End,
+            Message::Abort,
+        ];
+        for message in messages {
+            let frame = Message::into_frame(message);
+            let decoded = Message::from_frame(frame.clone()).unwrap();
+            assert_eq!(decoded.into_frame(), frame);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut sender = FramedConnection::new(client);
+        let mut receiver = FramedConnection::new(server);
+        let mut options = TransferOptions::new();
+        options.set_window(32 * 1024);
+        options.set_chunk_len(8 * 1024);
+
+        let payload: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+        let mut received = Vec::new();
+        let (sent, receive_len) = tokio::join!(
+            send(&mut sender, &payload[..], &options),
+            receive(&mut receiver, &mut received, &options),
+        );
+        assert_eq!(sent.unwrap(), payload.len() as u64);
+        assert_eq!(receive_len.unwrap(), payload.len() as u64);
+        assert_eq!(received, payload);
+
+        // Nothing of the transfer is left on the connection
+        sender
+            .write_frame(&Frame::data(&b"next"[..]))
+            .await
+            .unwrap();
+        let frame = receiver.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame, Frame::data(&b"next"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_too_large() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut sender = FramedConnection::new(client);
+        let mut receiver = FramedConnection::new(server);
+        let mut options = TransferOptions::new();
+        options.set_chunk_len(1024);
+        options.set_max_len(Some(4096));
+
+        let payload = vec![0; 64 * 1024];
+        let (sent, received) = tokio::join!(
+            send(&mut sender, &payload[..], &options),
+            receive(&mut receiver, tokio::io::sink(), &options),
+        );
+        assert!(matches!(sent, Err(Error::TransferAborted)));
+        assert!(matches!(
+            received,
+            Err(Error::BulkTooLarge {
+                len: 5120,
+                max: 4096
+            })
+        ));
+
+        receiver
+            .write_frame(&Frame::data(&b"next"[..]))
+            .await
+            .unwrap();
+        let frame = sender.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame, Frame::data(&b"next"[..]));
+    }
+}
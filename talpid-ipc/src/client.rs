@@ -0,0 +1,881 @@
+//! Typed client for servers built on [`crate::rpc`].
+//!
+//! The connection is driven by a background task, which writes requests, matches responses with
+//! the calls that are waiting for them, and hands events to subscribers. This lets any number of
+//! tasks call the server through a shared reference to the client, and their calls are answered
+//! in whatever order the server completes them. A call that is abandoned, or that times out, is
+//! cancelled on the server.
+//!
+//! Clones of the client share its connection, so components that are independent of each
+//! other, such as a status poller and an event listener, can each hold their own client without
+//! opening a socket each.
+//!
+//! A client that reconnects can resume receiving events where an earlier client left off, using
+//! [`IpcClient::resume_token`] and [`IpcClient::resume`].
+
+use crate::{
+    Endpoint, Error,
+    codec::{Codec, JsonCodec},
+    events::{ResumeToken, Resumption},
+    frame::{FlushMode, FramedConnection, GoodbyeReason},
+    rpc::{self, FromServer, Message, MessageKind},
+};
+use bytes::Bytes;
+use futures::{Stream, stream};
+use serde::{Serialize, de::DeserializeOwned};
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    path::Path,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{OwnedSemaphorePermit, Semaphore, broadcast, mpsc, oneshot},
+};
+
+/// Number of requests that may be waiting to be written before callers have to wait.
+const REQUEST_QUEUE_LEN: usize = 32;
+
+/// Number of events that are buffered for each subscriber. Subscribers that fall further behind
+/// miss events.
+const EVENT_BUFFER_LEN: usize = 64;
+
+/// Number of responses to a streaming call that are buffered. Once that many are waiting to be
+/// read, the client stops reading from the connection until the stream is read again, so that
+/// the server has to wait rather than the client buffering without bounds. This also holds up
+/// other calls and events.
+const STREAM_BUFFER_LEN: usize = 32;
+
+/// Sends requests of type `Req` and receives responses of type `Resp` and events of type
+/// `Event`.
+///
+/// Clones share the connection, the window of outstanding calls and the IDs of requests. The
+/// connection is closed once every clone has been dropped.
+pub struct IpcClient<Req, Resp, Event = (), C = JsonCodec> {
+    requests: mpsc::Sender<Outgoing>,
+    cancels: mpsc::UnboundedSender<Cancel>,
+    events: broadcast::Sender<Bytes>,
+    codec: Arc<C>,
+    next_id: Arc<AtomicU64>,
+    in_flight: Arc<Semaphore>,
+    request_timeout: Option<Duration>,
+    /// Why the server closed the connection, once it has said goodbye.
+    goodbye: Arc<OnceLock<GoodbyeReason>>,
+    /// Sequence number of the latest event that has been received, or 0 if none has.
+    last_event: Arc<AtomicU64>,
+    _types: PhantomData<fn(Req) -> (Resp, Event)>,
+}
+
+// Not derived, since that would require the message types to be `Clone`
+impl<Req, Resp, Event, C> Clone for IpcClient<Req, Resp, Event, C> {
+    fn clone(&self) -> Self {
+        IpcClient {
+            requests: self.requests.clone(),
+            cancels: self.cancels.clone(),
+            events: self.events.clone(),
+            codec: self.codec.clone(),
+            next_id: self.next_id.clone(),
+            in_flight: self.in_flight.clone(),
+            request_timeout: self.request_timeout,
+            goodbye: self.goodbye.clone(),
+            last_event: self.last_event.clone(),
+            _types: PhantomData,
+        }
+    }
+}
+
+/// A message on its way to the driver task.
+struct Outgoing {
+    message: Message,
+    /// Where the answer goes, or `None` if the message is not answered.
+    response: Option<PendingCall>,
+}
+
+/// Where the answer to a request goes.
+enum PendingCall {
+    /// The caller expects a single response.
+    Single(oneshot::Sender<Message>),
+    /// The caller expects a stream of responses.
+    Stream(mpsc::Sender<Message>),
+}
+
+/// A call that has been sent. Dropping it before the call has been answered cancels the request
+/// on the server. Either way, its place in the window is freed once the driver has seen that.
+struct OutstandingCall {
+    id: u64,
+    cancels: mpsc::UnboundedSender<Cancel>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for OutstandingCall {
+    fn drop(&mut self) {
+        // The driver ignores calls that have already been answered. It is gone if the connection
+        // has been closed.
+        let _ = self.cancels.send(Cancel {
+            id: self.id,
+            _permit: self.permit.take(),
+        });
+    }
+}
+
+/// A call that has been dropped, on its way to the driver task. It keeps its place in the window
+/// until the driver has handled it, so that no more cancellations than calls in the window can be
+/// waiting, even though they are sent without waiting.
+struct Cancel {
+    id: u64,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<Req, Resp, Event> IpcClient<Req, Resp, Event, JsonCodec>
+where
+    Req: Serialize,
+    Resp: DeserializeOwned,
+    Event: DeserializeOwned,
+{
+    /// Connect to the endpoint at `path`, and use JSON to encode messages. No handshake is
+    /// performed. Use [`Self::new`] for that.
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::connect_with_flush_mode(path, FlushMode::default()).await
+    }
+
+    /// Like [`Self::connect`], but write calls that are made at once as `mode` says, e.g.
+    /// [`FlushMode::AutoFlush`] for a CLI that makes one call at a time.
+    pub async fn connect_with_flush_mode(
+        path: impl AsRef<Path>,
+        mode: FlushMode,
+    ) -> Result<Self, Error> {
+        let mut connection = FramedConnection::new(Endpoint::connect(path).await?);
+        connection.set_flush_mode(mode);
+        Ok(Self::new(connection, JsonCodec))
+    }
+}
+
+impl<Req, Resp, Event, C> IpcClient<Req, Resp, Event, C>
+where
+    Req: Serialize,
+    Resp: DeserializeOwned,
+    Event: DeserializeOwned,
+    C: Codec,
+{
+    /// Use `connection` to talk to the server, and encode messages with `codec`. This spawns a
+    /// task that drives the connection until the client and all of its clones are dropped, or
+    /// the connection is closed.
+    pub fn new<T>(connection: FramedConnection<T>, codec: C) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (requests_tx, requests_rx) = mpsc::channel(REQUEST_QUEUE_LEN);
+        let (cancels_tx, cancels_rx) = mpsc::unbounded_channel();
+        let (events, _) = broadcast::channel(EVENT_BUFFER_LEN);
+        let goodbye = Arc::new(OnceLock::new());
+        let last_event = Arc::new(AtomicU64::new(0));
+        tokio::spawn(drive(
+            connection,
+            requests_rx,
+            cancels_rx,
+            Events {
+                subscribers: events.clone(),
+                last: last_event.clone(),
+            },
+            goodbye.clone(),
+        ));
+        IpcClient {
+            requests: requests_tx,
+            cancels: cancels_tx,
+            events,
+            codec: Arc::new(codec),
+            // 0 is reserved for messages that do not belong to a request
+            next_id: Arc::new(AtomicU64::new(1)),
+            in_flight: Arc::new(Semaphore::new(rpc::DEFAULT_MAX_IN_FLIGHT)),
+            request_timeout: None,
+            goodbye,
+            last_event,
+            _types: PhantomData,
+        }
+    }
+
+    /// Set the number of calls that may be outstanding at once. Further calls wait until one of
+    /// them has been answered. This should not exceed the window of the server, see
+    /// [`rpc::ServeOptions::set_max_in_flight`]. The default is [`rpc::DEFAULT_MAX_IN_FLIGHT`],
+    /// and values below 1 are treated as 1. Calls that are already outstanding are not counted
+    /// against the new window.
+    ///
+    /// This only applies to this client and to clones that are made of it afterwards. Existing
+    /// clones keep sharing the previous window.
+    pub fn set_max_in_flight(&mut self, max_in_flight: usize) {
+        self.in_flight = Arc::new(Semaphore::new(max_in_flight.max(1)));
+    }
+
+    /// Fail calls with [`Error::RequestTimeout`] if the server takes longer than `timeout` to
+    /// answer them, and cancel them on the server. For streaming calls, this limits the time
+    /// until each response. By default, calls wait indefinitely. Like [`Self::set_max_in_flight`],
+    /// this only applies to this client and to clones that are made of it afterwards.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.request_timeout = timeout;
+    }
+
+    /// Why the server closed the connection, if it said goodbye. Calls fail with
+    /// [`Error::Goodbye`] rather than [`Error::Closed`] once it has.
+    pub fn goodbye_reason(&self) -> Option<GoodbyeReason> {
+        self.goodbye.get().copied()
+    }
+
+    /// Send a request and wait for the response. Fails with [`Error::Remote`] if the server
+    /// failed to handle it, and with [`Error::Closed`] or [`Error::Goodbye`] if the connection
+    /// was closed before the response arrived.
+    pub async fn call(&self, request: Req) -> Result<Resp, Error> {
+        self.call_with_timeout(request, self.request_timeout).await
+    }
+
+    /// Like [`Self::call`], but with a timeout that overrides the one set by
+    /// [`Self::set_request_timeout`].
+    pub async fn call_with_timeout(
+        &self,
+        request: Req,
+        timeout: Option<Duration>,
+    ) -> Result<Resp, Error> {
+        let (response_tx, response_rx) = oneshot::channel();
+        let _call = self.send(request, PendingCall::Single(response_tx)).await?;
+
+        let response = with_timeout(timeout, response_rx)
+            .await?
+            .map_err(|_| closed_error(&self.goodbye))?;
+        match response.kind {
+            MessageKind::Response => self.codec.decode(&response.body),
+            MessageKind::Error => Err(remote_error(&response)),
+            MessageKind::StreamStart => Err(Error::Protocol("Expected a single response")),
+            _ => Err(Error::Protocol("Expected a response")),
+        }
+    }
+
+    /// Send a request that the server answers with a stream of responses. The stream ends when
+    /// the server has sent all responses, and fails with [`Error::Remote`] if the server fails
+    /// part of the way. A server that answers with a single response yields a stream of one.
+    ///
+    /// Responses that are not read hold up the connection once a few of them are waiting, so
+    /// the stream must be read while waiting for other calls on the same connection.
+    pub async fn call_streaming(
+        &self,
+        request: Req,
+    ) -> Result<impl Stream<Item = Result<Resp, Error>> + Send + 'static, Error> {
+        let (responses_tx, responses_rx) = mpsc::channel(STREAM_BUFFER_LEN);
+        let call = self
+            .send(request, PendingCall::Stream(responses_tx))
+            .await?;
+
+        let codec = self.codec.clone();
+        let timeout = self.request_timeout;
+        let goodbye = self.goodbye.clone();
+        // The call is dropped when the stream ends
+        Ok(stream::unfold(Some((responses_rx, call)), move |call| {
+            let codec = codec.clone();
+            let goodbye = goodbye.clone();
+            async move {
+                let (mut responses, call) = call?;
+                loop {
+                    let message = match with_timeout(timeout, responses.recv()).await {
+                        Ok(Some(message)) => message,
+                        Ok(None) => return Some((Err(closed_error(&goodbye)), None)),
+                        Err(error) => return Some((Err(error), None)),
+                    };
+                    let item = match message.kind {
+                        MessageKind::StreamStart => continue,
+                        MessageKind::StreamChunk => {
+                            return Some((codec.decode(&message.body), Some((responses, call))));
+                        }
+                        MessageKind::Response => codec.decode(&message.body),
+                        MessageKind::Error => Err(remote_error(&message)),
+                        MessageKind::StreamEnd => return None,
+                        _ => Err(Error::Protocol("Expected a response")),
+                    };
+                    return Some((item, None));
+                }
+            }
+        }))
+    }
+
+    /// Token that identifies the latest event that has been received, or `None` if no events
+    /// with sequence numbers have been received. Pass it to [`Self::resume`] on the client of
+    /// the next connection to receive the events that were missed in between.
+    pub fn resume_token(&self) -> Option<ResumeToken> {
+        match self.last_event.load(Ordering::Relaxed) {
+            0 => None,
+            seq => Some(ResumeToken::from_u64(seq)),
+        }
+    }
+
+    /// Ask the server to replay the events that were sent after the one identified by `token`,
+    /// which was returned by [`Self::resume_token`] of an earlier client. Subscribe before
+    /// calling this, and call it before anything else, so that no events are missed or
+    /// received twice.
+    ///
+    /// Returns [`Resumption::Incomplete`] if some of the events could not be replayed, in which
+    /// case the current state should be fetched again. This requires the server to use
+    /// [`rpc::serve_broadcast`].
+    pub async fn resume(&self, token: ResumeToken) -> Result<Resumption, Error> {
+        // Events up to the token are skipped if the server replays them anyway
+        self.last_event.fetch_max(token.as_u64(), Ordering::Relaxed);
+        let body = self.codec.encode(&token.as_u64())?;
+        let (response_tx, response_rx) = oneshot::channel();
+        let _call = self
+            .send_message(MessageKind::Resume, body, PendingCall::Single(response_tx))
+            .await?;
+
+        let response = with_timeout(self.request_timeout, response_rx)
+            .await?
+            .map_err(|_| closed_error(&self.goodbye))?;
+        match response.kind {
+            MessageKind::Response => match self.codec.decode(&response.body)? {
+                true => Ok(Resumption::Complete),
+                false => Ok(Resumption::Incomplete),
+            },
+            MessageKind::Error => Err(remote_error(&response)),
+            _ => Err(Error::Protocol("Expected a response")),
+        }
+    }
+
+    /// Wait for room in the window, and hand a request to the driver task. The returned call
+    /// must be held until it has been answered.
+    async fn send(&self, request: Req, response: PendingCall) -> Result<OutstandingCall, Error> {
+        let body = self.codec.encode(&request)?;
+        self.send_message(MessageKind::Request, body, response)
+            .await
+    }
+
+    /// Like [`Self::send`], but for any kind of message that is answered like a request.
+    async fn send_message(
+        &self,
+        kind: MessageKind,
+        body: Bytes,
+        response: PendingCall,
+    ) -> Result<OutstandingCall, Error> {
+        let permit = self
+            .in_flight
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| closed_error(&self.goodbye))?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = Message::new(kind, id, body);
+        self.requests
+            .send(Outgoing {
+                message,
+                response: Some(response),
+            })
+            .await
+            .map_err(|_| closed_error(&self.goodbye))?;
+        Ok(OutstandingCall {
+            id,
+            cancels: self.cancels.clone(),
+            permit: Some(permit),
+        })
+    }
+
+    /// Only receive events whose [`rpc::EventKind`] is one of `kinds`, or all events if `kinds`
+    /// is `None`. The server applies the filter to the whole connection, so it affects all
+    /// subscriptions of this client.
+    pub async fn set_event_filter<K: Into<String>>(
+        &self,
+        kinds: Option<impl IntoIterator<Item = K>>,
+    ) -> Result<(), Error> {
+        let kinds: Option<Vec<String>> =
+            kinds.map(|kinds| kinds.into_iter().map(Into::into).collect());
+        let message = Message::new(
+            MessageKind::Subscribe,
+            rpc::NO_REQUEST,
+            self.codec.encode(&kinds)?,
+        );
+        self.requests
+            .send(Outgoing {
+                message,
+                response: None,
+            })
+            .await
+            .map_err(|_| closed_error(&self.goodbye))
+    }
+
+    /// Like [`Self::subscribe`], but only receive events whose kind is one of `kinds`. See
+    /// [`Self::set_event_filter`].
+    pub async fn subscribe_to<K: Into<String>>(
+        &self,
+        kinds: impl IntoIterator<Item = K>,
+    ) -> Result<impl Stream<Item = Result<Event, Error>> + Send + 'static, Error> {
+        // Subscribe first, so that no events are missed once the filter applies
+        let events = self.subscribe();
+        self.set_event_filter(Some(kinds)).await?;
+        Ok(events)
+    }
+
+    /// Receive the events that the server pushes from now on. Events that cannot be decoded,
+    /// e.g. because the server is newer than the client, are skipped. The stream ends when the
+    /// connection is closed.
+    pub fn subscribe(&self) -> impl Stream<Item = Result<Event, Error>> + Send + 'static {
+        let codec = self.codec.clone();
+        stream::unfold(self.events.subscribe(), move |mut events| {
+            let codec = codec.clone();
+            async move {
+                loop {
+                    match events.recv().await {
+                        Ok(event) => match codec.decode(&event) {
+                            Ok(event) => return Some((Ok(event), events)),
+                            Err(error) => log::debug!("Skipping undecodable IPC event: {error}"),
+                        },
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            log::warn!("IPC event subscriber missed {missed} events");
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            }
+        })
+    }
+}
+
+async fn with_timeout<F: Future>(timeout: Option<Duration>, future: F) -> Result<F::Output, Error> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| Error::RequestTimeout),
+        None => Ok(future.await),
+    }
+}
+
+/// Where the driver task delivers events.
+struct Events {
+    subscribers: broadcast::Sender<Bytes>,
+    /// Sequence number of the latest event, shared with the client.
+    last: Arc<AtomicU64>,
+}
+
+/// Error for a call that failed because the connection was closed.
+fn closed_error(goodbye: &OnceLock<GoodbyeReason>) -> Error {
+    match goodbye.get() {
+        Some(reason) => Error::Goodbye(*reason),
+        None => Error::Closed,
+    }
+}
+
+fn remote_error(message: &Message) -> Error {
+    Error::Remote(String::from_utf8_lossy(&message.body).into_owned())
+}
+
+/// Write requests and dispatch incoming messages until every clone of the client is dropped, or
+/// the connection is closed. Calls that are still waiting for a response then fail.
+async fn drive<T>(
+    mut connection: FramedConnection<T>,
+    mut requests: mpsc::Receiver<Outgoing>,
+    mut cancels: mpsc::UnboundedReceiver<Cancel>,
+    events: Events,
+    goodbye: Arc<OnceLock<GoodbyeReason>>,
+) where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut pending: HashMap<u64, PendingCall> = HashMap::new();
+    let result = loop {
+        tokio::select! {
+            // Requests are written before cancellations, which may refer to them
+            biased;
+
+            outgoing = requests.recv() => {
+                let Some(outgoing) = outgoing else {
+                    break Ok(());
+                };
+                // Calls that are made at once are written together, as the flush mode allows, and
+                // the last of them flushes the others
+                let frame = outgoing.message.to_frame();
+                let written = if requests.is_empty() {
+                    connection.write_frame(&frame).await
+                } else {
+                    connection.feed_frame(&frame).await
+                };
+                if let Err(error) = written {
+                    break Err(error);
+                }
+                if let Some(response) = outgoing.response {
+                    pending.insert(outgoing.message.id, response);
+                }
+            }
+            Some(cancel) = cancels.recv() => {
+                let id = cancel.id;
+                if pending.remove(&id).is_none() {
+                    continue;
+                }
+                let cancel = Message::new(MessageKind::Cancel, id, Bytes::new());
+                if let Err(error) = connection.write_priority_frame(&cancel.to_frame()).await {
+                    break Err(error);
+                }
+            }
+            frame = connection.read_frame() => {
+                let frame = match frame {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break Ok(()),
+                    Err(error) => break Err(error),
+                };
+                match FromServer::of(&frame) {
+                    Ok(Some(FromServer::Goodbye(reason))) => {
+                        // Responses to requests that the server has already read may follow
+                        log::debug!("IPC server said goodbye: {reason}");
+                        let _ = goodbye.set(reason);
+                    }
+                    Ok(Some(FromServer::Event(message))) => {
+                        // Events that are replayed after resuming may have been received already
+                        if message.id != rpc::NO_REQUEST
+                            && events.last.fetch_max(message.id, Ordering::Relaxed) >= message.id
+                        {
+                            continue;
+                        }
+                        // Nobody may be subscribed
+                        let _ = events.subscribers.send(message.body);
+                    }
+                    Ok(Some(FromServer::Answer(message))) => {
+                        dispatch(&mut pending, message).await;
+                    }
+                    Ok(None) => (),
+                    Err(error) => break Err(error),
+                }
+            }
+        }
+    };
+    if let Err(error) = result {
+        log::debug!("IPC client connection failed: {error}");
+    }
+}
+
+/// Hand a response to the call that is waiting for it, waiting for room if it is a stream whose
+/// responses are not being read. The call is forgotten once its last response has arrived.
+async fn dispatch(pending: &mut HashMap<u64, PendingCall>, message: Message) {
+    let id = message.id;
+    match pending.remove(&id) {
+        // A call that expects a single response fails if it gets a stream, and the rest of the
+        // stream is discarded
+        Some(PendingCall::Single(response)) => {
+            // The caller may have given up
+            let _ = response.send(message);
+        }
+        Some(PendingCall::Stream(responses)) => {
+            let last = message.kind.is_final();
+            if responses.send(message).await.is_ok() && !last {
+                pending.insert(id, PendingCall::Stream(responses));
+            }
+        }
+        None => log::debug!("Discarding response to unknown request {id}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc;
+    use futures::{StreamExt, future};
+
+    #[tokio::test]
+    async fn test_call_and_subscribe() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (events_tx, events_rx) = mpsc::channel::<String>(EVENT_BUFFER_LEN);
+        let events = stream::unfold(events_rx, |mut events| async move {
+            events.recv().await.map(|event| (event, events))
+        });
+        tokio::spawn(rpc::serve(
+            FramedConnection::new(server),
+            JsonCodec,
+            |request: u32| async move {
+                match request {
+                    0 => Err("Zero is not allowed".to_owned()),
+                    n => Ok(n * 2),
+                }
+            },
+            Box::pin(events),
+        ));
+
+        let client: IpcClient<u32, u32, String> =
+            IpcClient::new(FramedConnection::new(client), JsonCodec);
+        assert_eq!(client.call(21).await.unwrap(), 42);
+        assert!(matches!(client.call(0).await, Err(Error::Remote(_))));
+
+        let mut subscription = Box::pin(client.subscribe());
+        events_tx.send("connected".to_owned()).await.unwrap();
+        assert_eq!(subscription.next().await.unwrap().unwrap(), "connected");
+    }
+
+    #[tokio::test]
+    async fn test_call_streaming() {
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(rpc::serve_streaming(
+            FramedConnection::new(server),
+            JsonCodec,
+            |request: u32| async move {
+                let responses = stream::iter(0..request).map(Ok);
+                Ok(rpc::Reply::Stream(Box::pin(responses)))
+            },
+            stream::pending::<()>(),
+        ));
+
+        let client: IpcClient<u32, u32> = IpcClient::new(FramedConnection::new(client), JsonCodec);
+        let responses = client.call_streaming(3).await.unwrap();
+        let responses: Vec<u32> = responses.map(Result::unwrap).collect().await;
+        assert_eq!(responses, [0, 1, 2]);
+        assert!(matches!(
+            client.call(3).await,
+            Err(Error::Protocol("Expected a single response"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_streaming_backpressure() {
+        use std::sync::atomic::AtomicUsize;
+
+        const LEN: usize = 1000;
+        let (client, server) = tokio::io::duplex(1024);
+        let produced = Arc::new(AtomicUsize::new(0));
+        let counter = produced.clone();
+        tokio::spawn(rpc::serve_streaming(
+            FramedConnection::new(server),
+            JsonCodec,
+            move |_: u32| {
+                let counter = counter.clone();
+                async move {
+                    let responses = stream::iter(0..LEN as u32).map(move |response| {
+                        counter.fetch_add(1, Ordering::Relaxed);
+                        Ok(response)
+                    });
+                    Ok(rpc::Reply::Stream(Box::pin(responses)))
+                }
+            },
+            stream::pending::<()>(),
+        ));
+
+        let client: IpcClient<u32, u32> = IpcClient::new(FramedConnection::new(client), JsonCodec);
+        let responses = client.call_streaming(0).await.unwrap();
+        // The server has to wait while the responses are not read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(produced.load(Ordering::Relaxed) < LEN);
+
+        let responses: Vec<u32> = responses.map(Result::unwrap).collect().await;
+        assert_eq!(responses.len(), LEN);
+    }
+
+    #[tokio::test]
+    async fn test_out_of_order_responses() {
+        let (client, server) = tokio::io::duplex(1024);
+        let unblock = Arc::new(tokio::sync::Notify::new());
+        tokio::spawn(rpc::serve(
+            FramedConnection::new(server),
+            JsonCodec,
+            move |request: u32| {
+                let unblock = unblock.clone();
+                async move {
+                    // The slow request is only answered once the fast one has been handled
+                    match request {
+                        0 => unblock.notified().await,
+                        _ => unblock.notify_one(),
+                    }
+                    Ok(request)
+                }
+            },
+            stream::pending::<()>(),
+        ));
+
+        let client: IpcClient<u32, u32> = IpcClient::new(FramedConnection::new(client), JsonCodec);
+        let (slow, fast) = tokio::join!(client.call(0), client.call(1));
+        assert_eq!(slow.unwrap(), 0);
+        assert_eq!(fast.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_clones_share_connection() {
+        let (client, server) = tokio::io::duplex(1024);
+        let served = tokio::spawn(rpc::serve(
+            FramedConnection::new(server),
+            JsonCodec,
+            |request: u32| async move { Ok::<_, String>(request + 1) },
+            stream::pending::<()>(),
+        ));
+
+        let client: IpcClient<u32, u32> = IpcClient::new(FramedConnection::new(client), JsonCodec);
+        let clone = client.clone();
+        let call = tokio::spawn(async move { clone.call(1).await });
+        assert_eq!(client.call(2).await.unwrap(), 3);
+        assert_eq!(call.await.unwrap().unwrap(), 2);
+
+        // The connection stays open until the last clone is gone
+        let clone = client.clone();
+        drop(client);
+        assert_eq!(clone.call(3).await.unwrap(), 4);
+        drop(clone);
+        served.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout_cancels_request() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (dropped_tx, dropped_rx) = oneshot::channel::<()>();
+        let mut dropped_tx = Some(dropped_tx);
+        tokio::spawn(rpc::serve(
+            FramedConnection::new(server),
+            JsonCodec,
+            move |request: u32| {
+                // Dropping the sender tells the test that the handler was aborted
+                let dropped = dropped_tx.take();
+                async move {
+                    if request == 0 {
+                        let _dropped = dropped;
+                        future::pending::<()>().await;
+                    }
+                    Ok(request)
+                }
+            },
+            stream::pending::<()>(),
+        ));
+
+        let mut client: IpcClient<u32, u32> =
+            IpcClient::new(FramedConnection::new(client), JsonCodec);
+        client.set_request_timeout(Some(Duration::from_secs(10)));
+        assert!(matches!(client.call(0).await, Err(Error::RequestTimeout)));
+        dropped_rx.await.unwrap_err();
+        assert_eq!(client.call(1).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_event_filter() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (events_tx, events_rx) = mpsc::channel::<String>(EVENT_BUFFER_LEN);
+        let events = stream::unfold(events_rx, |mut events| async move {
+            events.recv().await.map(|event| (event, events))
+        });
+        tokio::spawn(rpc::serve(
+            FramedConnection::new(server),
+            JsonCodec,
+            |request: u32| async move { Ok(request) },
+            Box::pin(events),
+        ));
+
+        let client: IpcClient<u32, u32, String> =
+            IpcClient::new(FramedConnection::new(client), JsonCodec);
+        let mut subscription = Box::pin(client.subscribe_to(["tunnel"]).await.unwrap());
+        // The filter has been applied once a later request has been answered
+        client.call(1).await.unwrap();
+
+        events_tx.send("settings".to_owned()).await.unwrap();
+        events_tx.send("tunnel".to_owned()).await.unwrap();
+        assert_eq!(subscription.next().await.unwrap().unwrap(), "tunnel");
+    }
+
+    #[tokio::test]
+    async fn test_goodbye_on_shutdown() {
+        let (client, server) = tokio::io::duplex(1024);
+        let shutdown = crate::shutdown::ShutdownHandle::new();
+        let signal = shutdown.register();
+        let mut options = rpc::ServeOptions::new();
+        options.set_shutdown_signal(&signal);
+        let server = tokio::spawn(async move {
+            rpc::serve_with_options(
+                FramedConnection::new(server),
+                JsonCodec,
+                &options,
+                |request: u32| async move { Ok(rpc::Reply::Single(request)) },
+                stream::pending::<()>(),
+            )
+            .await
+        });
+
+        let client: IpcClient<u32, u32> = IpcClient::new(FramedConnection::new(client), JsonCodec);
+        assert_eq!(client.call(1).await.unwrap(), 1);
+
+        drop(signal);
+        shutdown.drain(std::time::Duration::from_secs(10)).await;
+        server.await.unwrap().unwrap();
+        while client.goodbye_reason().is_none() {
+            tokio::task::yield_now().await;
+        }
+        assert!(matches!(
+            client.call(2).await,
+            Err(Error::Goodbye(GoodbyeReason::ShuttingDown))
+        ));
+        assert_eq!(client.goodbye_reason(), Some(GoodbyeReason::ShuttingDown));
+    }
+
+    #[cfg(feature = "tower")]
+    #[tokio::test(start_paused = true)]
+    async fn test_tower_middleware() {
+        use std::time::Duration;
+
+        let (client, server) = tokio::io::duplex(1024);
+        let middleware = tower::ServiceBuilder::new()
+            .load_shed()
+            .concurrency_limit(1)
+            .timeout(Duration::from_secs(1));
+        let handler = |request: u32| async move {
+            if request == 0 {
+                future::pending::<()>().await;
+            }
+            Ok(rpc::Reply::Single(request))
+        };
+        tokio::spawn(async move {
+            rpc::serve_layered(
+                FramedConnection::new(server),
+                JsonCodec,
+                &rpc::ServeOptions::new(),
+                middleware,
+                handler,
+                stream::pending::<()>(),
+            )
+            .await
+        });
+
+        let client: IpcClient<u32, u32> = IpcClient::new(FramedConnection::new(client), JsonCodec);
+        let slow = tokio::spawn({
+            let client = client.clone();
+            async move { client.call(0).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // The slow request holds the only permit, so others are shed
+        assert!(matches!(client.call(1).await, Err(Error::Remote(_))));
+        // Until it times out
+        assert!(matches!(slow.await.unwrap(), Err(Error::Remote(_))));
+        assert_eq!(client.call(1).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let mut broadcaster = crate::events::EventBroadcaster::new();
+        broadcaster.set_replay_last(10);
+        let connect = || {
+            let (client, server) = tokio::io::duplex(1024);
+            let broadcaster = broadcaster.clone();
+            tokio::spawn(async move {
+                rpc::serve_broadcast(
+                    FramedConnection::new(server),
+                    JsonCodec,
+                    &rpc::ServeOptions::new(),
+                    |request: u32| async move { Ok(rpc::Reply::Single(request)) },
+                    &broadcaster,
+                )
+                .await
+            });
+            IpcClient::<u32, u32, String>::new(FramedConnection::new(client), JsonCodec)
+        };
+
+        let client = connect();
+        let mut events = Box::pin(client.subscribe());
+        // Events are sent once the server is serving the connection
+        client.call(1).await.unwrap();
+        broadcaster.send("a".to_owned());
+        assert_eq!(events.next().await.unwrap().unwrap(), "a");
+        let token = client.resume_token().unwrap();
+        drop(client);
+
+        broadcaster.send("b".to_owned());
+        let client = connect();
+        let mut events = Box::pin(client.subscribe());
+        assert_eq!(client.resume(token).await.unwrap(), Resumption::Complete);
+        broadcaster.send("c".to_owned());
+        // Neither the event that was already received nor the replayed one is repeated
+        assert_eq!(events.next().await.unwrap().unwrap(), "b");
+        assert_eq!(events.next().await.unwrap().unwrap(), "c");
+    }
+}
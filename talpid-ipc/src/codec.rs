@@ -0,0 +1,30 @@
+//! Encoding of typed messages as frame payloads.
+
+use crate::Error;
+use bytes::Bytes;
+use serde::{Serialize, de::DeserializeOwned};
+
+/// Turns messages into bytes and back.
+pub trait Codec: Send + Sync + 'static {
+    fn encode<T: Serialize>(&self, message: &T) -> Result<Bytes, Error>;
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error>;
+}
+
+/// Encodes messages as JSON. Fields that the receiving type does not have are ignored, so types
+/// can gain fields without breaking older peers. Fields that older peers do not send should
+/// have `#[serde(default)]`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, message: &T) -> Result<Bytes, Error> {
+        serde_json::to_vec(message)
+            .map(Bytes::from)
+            .map_err(|error| Error::Codec(Box::new(error)))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        serde_json::from_slice(bytes).map_err(|error| Error::Codec(Box::new(error)))
+    }
+}
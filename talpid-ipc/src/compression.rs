@@ -0,0 +1,76 @@
+//! Per-frame deflate compression, used when both ends advertise [`Capabilities::DEFLATE`].
+//!
+//! Only payloads of at least [`THRESHOLD`] bytes are compressed, so that small control messages
+//! are not delayed for no gain. A payload is sent uncompressed if compressing it does not make it
+//! smaller.
+//!
+//! [`Capabilities::DEFLATE`]: crate::handshake::Capabilities::DEFLATE
+
+use crate::Error;
+use bytes::Bytes;
+use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
+use std::io::{Read, Write};
+
+/// Payloads smaller than this are never compressed.
+pub const THRESHOLD: usize = 4 * 1024;
+
+/// Upper bound of the size of a decompressed payload, regardless of the payload size limit of
+/// the connection. This prevents a peer from exhausting our memory with a small, highly
+/// compressible frame.
+pub const MAX_DECOMPRESSED_LEN: usize = 64 * 1024 * 1024;
+
+/// Compress `payload`, or return `None` if the result would not be smaller.
+pub(crate) fn compress(payload: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder =
+        DeflateEncoder::new(Vec::with_capacity(payload.len() / 2), Compression::fast());
+    encoder.write_all(payload).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < payload.len()).then_some(compressed)
+}
+
+/// Decompress a payload produced by [`compress`], which must not exceed `max_len` bytes once
+/// decompressed.
+pub(crate) fn decompress(payload: &[u8], max_len: usize) -> Result<Bytes, Error> {
+    let mut decompressed = vec![];
+    DeflateDecoder::new(payload)
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|_| Error::Protocol("Invalid compressed frame"))?;
+    if decompressed.len() > max_len {
+        return Err(Error::FrameExceedsLimit {
+            len: decompressed.len(),
+            max: max_len,
+        });
+    }
+    Ok(Bytes::from(decompressed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let payload = "relay".repeat(THRESHOLD);
+        let compressed = compress(payload.as_bytes()).unwrap();
+        assert!(compressed.len() < payload.len());
+        assert_eq!(
+            decompress(&compressed, MAX_DECOMPRESSED_LEN).unwrap(),
+            payload.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_incompressible() {
+        let payload: Vec<u8> = (0..=255).collect();
+        assert!(compress(&payload).is_none());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(
+            decompress(&[0xff; 16], MAX_DECOMPRESSED_LEN),
+            Err(Error::Protocol(_))
+        ));
+    }
+}
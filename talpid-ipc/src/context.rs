@@ -0,0 +1,162 @@
+//! Which endpoint, and which operation on it, an I/O error came from.
+//!
+//! Errors of the OS say nothing about what they are about, so "Permission denied" could be about
+//! the socket, its directory or the DACL of the pipe. Errors returned when listening on, accepting
+//! on or connecting to an endpoint therefore carry an [`EndpointError`], which names both. The
+//! [`io::ErrorKind`] of the original error is kept, so matching on it works as before, but its
+//! raw OS error code is only available through [`EndpointError::io_error`].
+
+use std::{error::Error as StdError, fmt, io, sync::Arc};
+
+/// What was being done with an endpoint when an error occurred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// Binding the socket, or creating an instance of the pipe.
+    Bind,
+    /// Changing the owner of the socket file.
+    Chown,
+    /// Changing the mode of the socket file.
+    Chmod,
+    /// Removing a socket file, e.g. one that was left behind.
+    Remove,
+    /// Accepting a connection.
+    Accept,
+    /// Connecting to the endpoint.
+    Connect,
+    /// Exchanging hellos with the peer, see [`crate::handshake`].
+    Handshake,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operation::Bind => "bind",
+            Operation::Chown => "change the owner of",
+            Operation::Chmod => "change the mode of",
+            Operation::Remove => "remove",
+            Operation::Accept => "accept a connection on",
+            Operation::Connect => "connect to",
+            Operation::Handshake => "perform the handshake on",
+        })
+    }
+}
+
+/// An I/O error, together with the endpoint and operation that it came from.
+#[derive(Debug)]
+pub struct EndpointError {
+    operation: Operation,
+    path: Option<Arc<str>>,
+    error: io::Error,
+}
+
+impl EndpointError {
+    /// Return the context of `error`, if it has any.
+    pub fn of(error: &io::Error) -> Option<&EndpointError> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    pub fn operation(&self) -> Operation {
+        self.operation
+    }
+
+    /// Socket path or pipe name of the endpoint. This is `None` for handshakes, since a
+    /// connection may not know which endpoint it belongs to.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// Return the error as it was returned by the OS.
+    pub fn io_error(&self) -> &io::Error {
+        &self.error
+    }
+}
+
+impl fmt::Display for EndpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "Failed to {} {path}: {}", self.operation, self.error),
+            None => write!(f, "Failed to {} connection: {}", self.operation, self.error),
+        }
+    }
+}
+
+impl StdError for EndpointError {
+    // The original error is already part of the message
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.error.source()
+    }
+}
+
+/// Attach `operation` and `path` to `error`, unless it already has a context. The path is
+/// shared, so that errors of a listener do not copy it.
+pub(crate) fn with_context(
+    error: io::Error,
+    operation: Operation,
+    path: Option<Arc<str>>,
+) -> io::Error {
+    if EndpointError::of(&error).is_some() {
+        return error;
+    }
+    io::Error::new(
+        error.kind(),
+        EndpointError {
+            operation,
+            path,
+            error,
+        },
+    )
+}
+
+/// Attaches a context to the error of a result, see [`with_context`].
+pub(crate) trait ResultExt<T> {
+    fn context(self, operation: Operation, path: &str) -> io::Result<T>;
+}
+
+impl<T> ResultExt<T> for io::Result<T> {
+    fn context(self, operation: Operation, path: &str) -> io::Result<T> {
+        self.map_err(|error| with_context(error, operation, Some(path.into())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_context() {
+        let error = io::Error::from(io::ErrorKind::PermissionDenied);
+        let error = with_context(error, Operation::Chmod, Some("/run/daemon.sock".into()));
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(
+            error
+                .to_string()
+                .starts_with("Failed to change the mode of /run/daemon.sock: ")
+        );
+
+        // The innermost context is the most specific one
+        let error = with_context(error, Operation::Bind, Some("/run/daemon.sock".into()));
+        let context = EndpointError::of(&error).unwrap();
+        assert_eq!(context.operation(), Operation::Chmod);
+        assert_eq!(context.path(), Some("/run/daemon.sock"));
+    }
+
+    #[cfg(all(unix, feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_endpoint_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("socket");
+        let path = path.to_string_lossy().into_owned();
+
+        let error = crate::Endpoint::new(path.clone()).incoming().err().unwrap();
+        let context = EndpointError::of(&error).unwrap();
+        assert_eq!(context.operation(), Operation::Bind);
+        assert_eq!(context.path(), Some(&*path));
+        assert!(error.to_string().contains(&path));
+
+        let error = crate::Endpoint::connect(&path).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let context = EndpointError::of(&error).unwrap();
+        assert_eq!(context.operation(), Operation::Connect);
+        assert_eq!(context.io_error().raw_os_error(), Some(libc::ENOENT));
+    }
+}
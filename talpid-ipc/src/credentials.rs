@@ -0,0 +1,257 @@
+//! Credentials of the process on the other end of a connection.
+
+use std::{
+    collections::HashSet,
+    io,
+    os::fd::{AsRawFd, RawFd},
+};
+
+/// User, group and process of a connected peer, as reported by the kernel when the connection
+/// was established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    uid: u32,
+    gid: u32,
+    pid: Option<i32>,
+}
+
+impl PeerCredentials {
+    /// Effective user ID of the peer.
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// Effective group ID of the peer.
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// Process ID of the peer, if the platform reports it.
+    pub fn pid(&self) -> Option<i32> {
+        self.pid
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl PeerCredentials {
+    pub(crate) fn from_unix_credentials(credentials: &nix::sys::socket::UnixCredentials) -> Self {
+        PeerCredentials {
+            uid: credentials.uid(),
+            gid: credentials.gid(),
+            pid: Some(credentials.pid()),
+        }
+    }
+}
+
+#[cfg(all(target_os = "macos", feature = "xpc"))]
+impl PeerCredentials {
+    pub(crate) fn from_xpc(uid: u32, gid: u32, pid: i32) -> Self {
+        PeerCredentials {
+            uid,
+            gid,
+            pid: Some(pid),
+        }
+    }
+}
+
+/// Users and groups that are allowed to connect. A peer is allowed if either its UID or its GID
+/// is in the list. See [`crate::Endpoint::set_peer_allowlist`].
+#[derive(Debug, Clone, Default)]
+pub struct PeerAllowlist {
+    uids: HashSet<u32>,
+    gids: HashSet<u32>,
+}
+
+impl PeerAllowlist {
+    /// Create an allowlist that allows no one.
+    pub fn new() -> Self {
+        PeerAllowlist::default()
+    }
+
+    /// Allow peers running as `uid`.
+    pub fn allow_uid(mut self, uid: u32) -> Self {
+        self.uids.insert(uid);
+        self
+    }
+
+    /// Allow peers whose effective group is `gid`.
+    pub fn allow_gid(mut self, gid: u32) -> Self {
+        self.gids.insert(gid);
+        self
+    }
+
+    /// Return whether a peer with `credentials` is allowed. On Linux, a peer whose IDs are not
+    /// mapped into the user namespace of this process is never allowed, see [`crate::userns`].
+    pub fn allows(&self, credentials: &PeerCredentials) -> bool {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if credentials.is_unmapped() {
+            return false;
+        }
+        self.uids.contains(&credentials.uid) || self.gids.contains(&credentials.gid)
+    }
+}
+
+impl crate::Connection {
+    /// Return the credentials of the peer.
+    pub fn peer_credentials(&self) -> io::Result<PeerCredentials> {
+        peer_credentials(self.inner.as_raw_fd())
+    }
+}
+
+/// Return the credentials of the peer of a connected socket.
+pub(crate) fn peer_credentials(socket: RawFd) -> io::Result<PeerCredentials> {
+    imp::peer_credentials(socket)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod imp {
+    use super::PeerCredentials;
+    use nix::sys::socket::{getsockopt, sockopt};
+    use std::{
+        io,
+        os::fd::{BorrowedFd, RawFd},
+    };
+
+    pub fn peer_credentials(socket: RawFd) -> io::Result<PeerCredentials> {
+        // SAFETY: The descriptor is owned by the connection, which outlives this call
+        let socket = unsafe { BorrowedFd::borrow_raw(socket) };
+        let credentials = getsockopt(&socket, sockopt::PeerCredentials)?;
+        Ok(PeerCredentials::from_unix_credentials(&credentials))
+    }
+}
+
+/// `getpeereid` is available on macOS and most BSDs, but only macOS reports the peer PID through
+/// a separate socket option.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd"
+))]
+mod imp {
+    use super::PeerCredentials;
+    use std::{io, os::fd::RawFd};
+
+    pub fn peer_credentials(socket: RawFd) -> io::Result<PeerCredentials> {
+        let mut uid = 0;
+        let mut gid = 0;
+        // SAFETY: `socket` is a valid descriptor and the out pointers are valid
+        if unsafe { libc::getpeereid(socket, &mut uid, &mut gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCredentials {
+            uid,
+            gid,
+            pid: peer_pid(socket),
+        })
+    }
+
+    #[cfg(target_os = "macos")]
+    fn peer_pid(socket: RawFd) -> Option<i32> {
+        let mut pid: libc::pid_t = 0;
+        let mut len = std::mem::size_of::<libc::pid_t>() as libc::socklen_t;
+        // SAFETY: `pid` and `len` describe a buffer of the size expected by `LOCAL_PEERPID`
+        let result = unsafe {
+            libc::getsockopt(
+                socket,
+                libc::SOL_LOCAL,
+                libc::LOCAL_PEERPID,
+                (&mut pid as *mut libc::pid_t).cast(),
+                &mut len,
+            )
+        };
+        (result == 0).then_some(pid)
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn peer_pid(_socket: RawFd) -> Option<i32> {
+        None
+    }
+}
+
+#[cfg(target_os = "openbsd")]
+mod imp {
+    use super::PeerCredentials;
+    use std::{io, mem, os::fd::RawFd};
+
+    pub fn peer_credentials(socket: RawFd) -> io::Result<PeerCredentials> {
+        // SAFETY: `sockpeercred` is plain data, for which all zeroes is a valid value
+        let mut credentials: libc::sockpeercred = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockpeercred>() as libc::socklen_t;
+        // SAFETY: `credentials` and `len` describe a buffer of the size expected by
+        // `SO_PEERCRED`
+        let result = unsafe {
+            libc::getsockopt(
+                socket,
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                (&mut credentials as *mut libc::sockpeercred).cast(),
+                &mut len,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCredentials {
+            uid: credentials.uid,
+            gid: credentials.gid,
+            pid: Some(credentials.pid),
+        })
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+mod imp {
+    use super::PeerCredentials;
+    use std::{io, os::fd::RawFd};
+
+    pub fn peer_credentials(_socket: RawFd) -> io::Result<PeerCredentials> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Peer credentials are not supported on this platform",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allowlist() {
+        let credentials = PeerCredentials {
+            uid: 1000,
+            gid: 100,
+            pid: None,
+        };
+        assert!(!PeerAllowlist::new().allows(&credentials));
+        assert!(PeerAllowlist::new().allow_uid(1000).allows(&credentials));
+        assert!(PeerAllowlist::new().allow_gid(100).allows(&credentials));
+        assert!(!PeerAllowlist::new().allow_uid(0).allows(&credentials));
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_peer_credentials() {
+        let mut endpoint = crate::testing::EphemeralEndpoint::new().unwrap();
+        let (client, server) = endpoint.connected_pair().await.unwrap();
+
+        // SAFETY: Getting the IDs of the current process has no preconditions
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        for connection in [client, server] {
+            let credentials = connection.peer_credentials().unwrap();
+            assert_eq!(credentials.uid(), uid);
+            assert_eq!(credentials.gid(), gid);
+            #[cfg(any(target_os = "linux", target_os = "macos", target_os = "openbsd"))]
+            assert_eq!(credentials.pid(), Some(std::process::id() as i32));
+        }
+    }
+}
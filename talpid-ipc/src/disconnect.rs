@@ -0,0 +1,32 @@
+//! Notification of peers that disappear while data is being written to them.
+//!
+//! Without this, a client that goes away surfaces as an `EPIPE` or `ERROR_BROKEN_PIPE` error
+//! from whichever write happens to come next, which is hard to tell apart from other failures.
+//! A [`DisconnectCallback`] installed with [`Endpoint::set_disconnect_callback`] is instead told
+//! about it exactly once per connection.
+//!
+//! [`Endpoint::set_disconnect_callback`]: crate::Endpoint::set_disconnect_callback
+
+use crate::ConnectionId;
+use std::{io, sync::Arc};
+
+/// Called when the peer of an accepted connection has disappeared. It is called from within
+/// `poll` functions, so it must not block.
+pub type DisconnectCallback = Arc<dyn Fn(&Disconnect) + Send + Sync>;
+
+/// Describes a connection whose peer has disappeared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnect {
+    pub connection: ConnectionId,
+    /// Kind of the error that revealed the disconnect.
+    pub kind: io::ErrorKind,
+}
+
+/// Whether `error` means that the peer has closed its end of the connection.
+pub fn is_peer_gone(error: &io::Error) -> bool {
+    // `ERROR_BROKEN_PIPE` and `ERROR_NO_DATA` are both mapped to `BrokenPipe` on Windows
+    matches!(
+        error.kind(),
+        io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::NotConnected
+    )
+}
@@ -0,0 +1,284 @@
+//! Waiting for a server to appear, without polling.
+//!
+//! Frontends that are started at login may come up before the daemon. Instead of retrying
+//! periodically, [`Endpoint::connect_when_created`] watches the directory of the socket and
+//! only tries to connect when something in it has changed: through inotify on Linux and
+//! kqueue on macOS.
+//!
+//! Changes in the named pipe namespace on Windows cannot be watched, so there it retries after
+//! the same delays as [`Endpoint::connect_when_ready`].
+
+use crate::{Connection, Endpoint};
+use std::{io, path::Path, time::Duration};
+
+/// How long to keep retrying after the directory has changed. A socket file exists for a brief
+/// moment before its server listens on it.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+const SETTLE_TIMEOUT: Duration = Duration::from_millis(500);
+
+impl Endpoint {
+    /// Connect to `path`, waiting for as long as it takes until a server listens on it. Fails
+    /// if the directory of the socket does not exist, or if connecting fails for any other
+    /// reason than the server not listening yet.
+    pub async fn connect_when_created(path: impl AsRef<Path>) -> io::Result<Connection> {
+        imp::connect_when_created(path.as_ref()).await
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+mod imp {
+    use super::*;
+    use tokio::io::unix::AsyncFd;
+
+    pub(super) async fn connect_when_created(path: &Path) -> io::Result<Connection> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        // Watch before the first attempt, so that a socket that is created in between is not
+        // missed
+        let watcher = AsyncFd::new(watch::DirWatcher::new(dir)?)?;
+        let mut settle = false;
+        loop {
+            let result = if settle {
+                Endpoint::connect_when_ready(path, SETTLE_TIMEOUT).await
+            } else {
+                Endpoint::connect(path).await
+            };
+            match result {
+                Ok(connection) => return Ok(connection),
+                Err(error) if crate::is_not_listening(&error) => (),
+                Err(error) => return Err(error),
+            }
+            let mut guard = watcher.readable().await?;
+            match guard.try_io(|watcher| watcher.get_ref().drain()) {
+                Ok(result) => result?,
+                Err(_would_block) => continue,
+            }
+            log::trace!("{} changed, trying to connect", dir.display());
+            settle = true;
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    mod watch {
+        use std::{
+            ffi::CString,
+            io,
+            os::{
+                fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+                unix::ffi::OsStrExt,
+            },
+            path::Path,
+        };
+
+        /// An inotify instance that watches a directory for files being created or moved in.
+        pub(super) struct DirWatcher {
+            fd: OwnedFd,
+        }
+
+        impl DirWatcher {
+            pub(super) fn new(dir: &Path) -> io::Result<Self> {
+                let dir = CString::new(dir.as_os_str().as_bytes())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid path"))?;
+                // SAFETY: `inotify_init1` only takes flags
+                let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // SAFETY: The kernel just created the descriptor, and nothing else owns it
+                let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                // SAFETY: `fd` is an inotify instance, and `dir` is a valid C string
+                let watch = unsafe {
+                    libc::inotify_add_watch(
+                        fd.as_raw_fd(),
+                        dir.as_ptr(),
+                        libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_ONLYDIR,
+                    )
+                };
+                if watch < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(DirWatcher { fd })
+            }
+
+            /// Read all pending events. Fails with [`io::ErrorKind::WouldBlock`] if there are
+            /// none.
+            pub(super) fn drain(&self) -> io::Result<()> {
+                let mut buf = [0u8; 4096];
+                let mut drained = false;
+                loop {
+                    // SAFETY: `buf` is valid for writes of its length
+                    let len = unsafe {
+                        libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len())
+                    };
+                    if len > 0 {
+                        drained = true;
+                        continue;
+                    }
+                    let error = io::Error::last_os_error();
+                    return match error.kind() {
+                        io::ErrorKind::WouldBlock if drained => Ok(()),
+                        _ => Err(error),
+                    };
+                }
+            }
+        }
+
+        impl AsRawFd for DirWatcher {
+            fn as_raw_fd(&self) -> RawFd {
+                self.fd.as_raw_fd()
+            }
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    mod watch {
+        use std::{
+            ffi::CString,
+            io,
+            os::{
+                fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+                unix::ffi::OsStrExt,
+            },
+            path::Path,
+            ptr,
+        };
+
+        /// A kqueue that watches a directory for writes, i.e. entries being added or removed.
+        pub(super) struct DirWatcher {
+            kqueue: OwnedFd,
+            /// The directory, which must stay open for as long as it is watched.
+            _dir: OwnedFd,
+        }
+
+        impl DirWatcher {
+            pub(super) fn new(dir: &Path) -> io::Result<Self> {
+                let path = CString::new(dir.as_os_str().as_bytes())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid path"))?;
+                // SAFETY: `path` is a valid C string
+                let dir = unsafe {
+                    libc::open(
+                        path.as_ptr(),
+                        libc::O_EVTONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+                    )
+                };
+                if dir < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // SAFETY: The descriptor was just opened, and nothing else owns it
+                let dir = unsafe { OwnedFd::from_raw_fd(dir) };
+
+                // SAFETY: `kqueue` takes no arguments
+                let kqueue = unsafe { libc::kqueue() };
+                if kqueue < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // SAFETY: See above
+                let kqueue = unsafe { OwnedFd::from_raw_fd(kqueue) };
+                crate::imp::set_cloexec(kqueue.as_raw_fd())?;
+
+                // SAFETY: An all-zero `kevent` is valid
+                let mut change: libc::kevent = unsafe { std::mem::zeroed() };
+                change.ident = dir.as_raw_fd() as libc::uintptr_t;
+                change.filter = libc::EVFILT_VNODE;
+                change.flags = libc::EV_ADD | libc::EV_CLEAR;
+                change.fflags = libc::NOTE_WRITE;
+                // SAFETY: `change` is a single valid change, and no events are returned
+                let result = unsafe {
+                    libc::kevent(
+                        kqueue.as_raw_fd(),
+                        &change,
+                        1,
+                        ptr::null_mut(),
+                        0,
+                        ptr::null(),
+                    )
+                };
+                if result < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(DirWatcher { kqueue, _dir: dir })
+            }
+
+            /// Retrieve all pending events. Fails with [`io::ErrorKind::WouldBlock`] if there
+            /// are none.
+            pub(super) fn drain(&self) -> io::Result<()> {
+                let timeout = libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: 0,
+                };
+                // SAFETY: An all-zero `kevent` is valid
+                let mut event: libc::kevent = unsafe { std::mem::zeroed() };
+                // SAFETY: `event` has room for one event, and the call does not block
+                let result = unsafe {
+                    libc::kevent(
+                        self.kqueue.as_raw_fd(),
+                        ptr::null(),
+                        0,
+                        &mut event,
+                        1,
+                        &timeout,
+                    )
+                };
+                match result {
+                    0 => Err(io::ErrorKind::WouldBlock.into()),
+                    1.. => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                }
+            }
+        }
+
+        impl AsRawFd for DirWatcher {
+            fn as_raw_fd(&self) -> RawFd {
+                self.kqueue.as_raw_fd()
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use super::*;
+    use crate::backoff::{ExponentialBackoff, Jitter};
+
+    /// There is nothing to watch, so keep retrying after up to a second.
+    const RETRY_BACKOFF: Jitter<ExponentialBackoff> = Jitter::new(
+        ExponentialBackoff::new(Duration::from_millis(20), Duration::from_secs(1)),
+        0.5,
+    );
+
+    pub(super) async fn connect_when_created(path: &Path) -> io::Result<Connection> {
+        Endpoint::connect_with_backoff(path, &RETRY_BACKOFF).await
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_connect_when_created() {
+        use futures::StreamExt;
+
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+
+        let connect = tokio::spawn(Endpoint::connect_when_created(path.clone()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!connect.is_finished());
+
+        let mut incoming = Endpoint::new(path).incoming().unwrap();
+        let _server = incoming.next().await.unwrap().unwrap();
+        connect.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_missing_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("socket");
+        let error = Endpoint::connect_when_created(path).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,126 @@
+//! An echo server and a client for it, for benchmarks and tests.
+//!
+//! The server sends every frame back as it is, so the time that a client spends on a round trip
+//! is spent on the transport and the framing alone. [`EchoServer::spawn`] serves any stream of
+//! connections, e.g. an [`crate::Incoming`] or a [`crate::tcp::TcpIncoming`], which lets the same
+//! benchmark run on every backend.
+
+use crate::{
+    Error,
+    frame::{Frame, FramedConnection},
+};
+use futures::{Stream, StreamExt};
+use std::{io, pin::pin};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task::JoinHandle,
+};
+
+/// Send every frame on `connection` back, until the peer closes it.
+pub async fn echo<T: AsyncRead + AsyncWrite + Unpin>(
+    mut connection: FramedConnection<T>,
+) -> Result<(), Error> {
+    while let Some(frame) = connection.read_frame().await? {
+        connection.write_frame(&frame).await?;
+    }
+    Ok(())
+}
+
+/// Echoes frames on every connection that it accepts. Stops accepting when dropped.
+pub struct EchoServer {
+    task: JoinHandle<()>,
+}
+
+impl EchoServer {
+    /// Accept connections from `incoming` and [`echo`] on each of them, until `incoming` ends
+    /// or fails.
+    pub fn spawn<S, T>(incoming: S) -> Self
+    where
+        S: Stream<Item = io::Result<T>> + Send + 'static,
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let task = tokio::spawn(async move {
+            let mut incoming = pin!(incoming);
+            while let Some(connection) = incoming.next().await {
+                let connection = match connection {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        log::debug!("Echo server failed to accept a connection: {error}");
+                        break;
+                    }
+                };
+                tokio::spawn(async move {
+                    if let Err(error) = echo(FramedConnection::new(connection)).await {
+                        log::debug!("Echo server connection failed: {error}");
+                    }
+                });
+            }
+        });
+        EchoServer { task }
+    }
+}
+
+impl Drop for EchoServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Sends frames to an echo server and waits for them to come back.
+pub struct EchoClient<T> {
+    connection: FramedConnection<T>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> EchoClient<T> {
+    /// Talk to the echo server at the other end of `io`.
+    pub fn new(io: T) -> Self {
+        EchoClient {
+            connection: FramedConnection::new(io),
+        }
+    }
+
+    /// Send `frame` and wait for it to be echoed. Fails with [`Error::Protocol`] if something
+    /// else comes back.
+    pub async fn round_trip(&mut self, frame: &Frame) -> Result<(), Error> {
+        self.connection.write_frame(frame).await?;
+        match self.connection.read_frame().await? {
+            Some(echoed)
+                if echoed.kind == frame.kind && echoed.payload.len() == frame.payload.len() =>
+            {
+                Ok(())
+            }
+            Some(_) => Err(Error::Protocol("Echo server sent a different frame")),
+            None => Err(Error::Closed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(echo(FramedConnection::new(server)));
+        let mut client = EchoClient::new(client);
+        for len in [0, 1, 100_000] {
+            client
+                .round_trip(&Frame::data(vec![7u8; len]))
+                .await
+                .unwrap();
+        }
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_endpoint() {
+        let endpoint = crate::testing::EphemeralEndpoint::new().unwrap();
+        let mut client = EchoClient::new(endpoint.connect().await.unwrap());
+        let _server = EchoServer::spawn(endpoint);
+        client
+            .round_trip(&Frame::data(&b"hello"[..]))
+            .await
+            .unwrap();
+    }
+}
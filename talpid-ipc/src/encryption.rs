@@ -0,0 +1,743 @@
+//! Encrypting connections with keys of an authenticated session.
+//!
+//! Endpoints are only reachable from the same machine, and their permissions decide who may
+//! connect, so connections are not encrypted by default. Deployments that must encrypt all IPC
+//! can have each accepted connection authenticated and encrypted with
+//! [`Endpoint::set_encryption`], and connect to it with [`Endpoint::connect_encrypted`]. Both
+//! return a [`Connection`] as usual, so the framing, and anything built on top of it, works on an
+//! encrypted connection as on any other. Other streams can be wrapped in an [`EncryptedStream`]
+//! once both ends have authenticated with [`FramedConnection::authenticate`] or its variants.
+//!
+//! The encryption is not TLS: rustls does not support external pre-shared keys, and
+//! certificates would need a key pair to be provisioned next to the [`InstallSecret`]. Instead,
+//! each direction is encrypted with ChaCha20-Poly1305, under a key that is derived from the
+//! [`AuthSession`]. Bytes are sent in records of at most [`MAX_RECORD_LEN`] bytes, each
+//! prefixed with its length and numbered by its nonce, so records that are modified, reordered
+//! or replayed fail to decrypt, which fails the stream with [`io::ErrorKind::InvalidData`].
+//!
+//! Shutting down the stream sends a close record, which is encrypted like any other. A peer
+//! that reaches the end of the stream without having received it fails with
+//! [`io::ErrorKind::UnexpectedEof`], even at the end of a record, so that a stream that was cut
+//! short cannot be mistaken for one that was closed.
+//!
+//! Sessions of the GUI can last for days, so each end can rotate the key that it sends with
+//! after a number of bytes or an amount of time, see [`EncryptedStream::set_rekey_limit`]. It
+//! sends a rekey record, and continues with the next key, which is derived from the previous
+//! one. The peer does the same once it has decrypted the record, so no round trip is needed, and
+//! the old keys cannot be derived from the new ones. Keys that are no longer used are zeroed.
+//! Older peers fail on rekey records, so keys are only rotated if both ends offered
+//! [`Capabilities::REKEY`] in the [handshake](crate::handshake).
+//!
+//! [`InstallSecret`]: crate::auth::InstallSecret
+
+use crate::{
+    Connection, Endpoint, Error,
+    auth::{self, AuthRole, AuthSession, InstallSecret, KEY_LEN},
+    frame::FramedConnection,
+    handshake::Capabilities,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use std::{
+    io,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Instant,
+};
+use zeroize::Zeroize;
+
+/// Maximum number of plaintext bytes in a record.
+pub const MAX_RECORD_LEN: usize = 16 * 1024;
+
+/// Length of the authentication tag of a record.
+const TAG_LEN: usize = 16;
+
+/// Length of the length prefix of a record.
+const PREFIX_LEN: usize = 2;
+
+/// Bit of the length prefix that marks a control record, whose plaintext is one of the control
+/// bytes below, and whose prefix is authenticated with it.
+pub const CONTROL_FLAG: u16 = 1 << 15;
+
+/// Control byte of a record after which the key of its direction is rotated.
+const CONTROL_REKEY: u8 = 1;
+
+/// Control byte of the last record of a direction.
+const CONTROL_CLOSE: u8 = 2;
+
+/// Number of bytes to read from the underlying stream at a time.
+const READ_CHUNK_LEN: usize = 8 * 1024;
+
+/// How long a peer has to authenticate before its connection is closed, see
+/// [`Endpoint::set_encryption`].
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CLIENT_TO_SERVER: &str = "talpid-ipc client to server";
+const SERVER_TO_CLIENT: &str = "talpid-ipc server to client";
+
+const REKEY_LABEL: &[u8] = b"talpid-ipc rekey";
+
+/// One direction of a [`RecordLayer`]. The cipher zeroes its copy of the key when dropped, and
+/// the direction zeroes its own, including when it is replaced by the next key.
+struct Direction {
+    cipher: ChaCha20Poly1305,
+    /// Key of `cipher`, from which the next key is derived.
+    key: [u8; KEY_LEN],
+    /// Number of records so far with this key, which is the nonce of the next one.
+    counter: u64,
+}
+
+impl Direction {
+    fn new(session: &AuthSession, purpose: &str) -> Self {
+        Self::with_key(session.derive_key(purpose))
+    }
+
+    fn with_key(key: [u8; KEY_LEN]) -> Self {
+        Direction {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            key,
+            counter: 0,
+        }
+    }
+
+    /// Continue with the next key.
+    fn rekey(&mut self) {
+        let mut key = auth::to_key(auth::hmac(&self.key, &[REKEY_LABEL]));
+        // Dropping the previous direction zeroes its key
+        *self = Self::with_key(key);
+        key.zeroize();
+    }
+
+    fn next_nonce(&mut self) -> io::Result<Nonce> {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("Too many records were sent with the same key"))?;
+        Ok(nonce.into())
+    }
+}
+
+impl Drop for Direction {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The records of an encrypted stream, apart from the stream that they are sent over, so that
+/// a [`Connection`] can be encrypted in place.
+pub(crate) struct RecordLayer {
+    send: Direction,
+    receive: Direction,
+    /// Records that are yet to be read from the stream.
+    read_buf: BytesMut,
+    /// Decrypted bytes that are yet to be read.
+    plaintext: Bytes,
+    /// Records that are yet to be written to the stream.
+    write_buf: BytesMut,
+    /// Whether the peer supports rekey records.
+    rekey_supported: bool,
+    rekey_limit: Option<u64>,
+    rekey_interval: Option<Duration>,
+    /// Number of plaintext bytes that have been sent with the current key.
+    sent_with_key: u64,
+    /// When the current key for sending was first used.
+    key_since: Instant,
+    /// Whether the close record has been queued.
+    sent_close: bool,
+    /// Whether the peer has sent its close record.
+    received_close: bool,
+}
+
+impl RecordLayer {
+    fn new(
+        buffered: BytesMut,
+        session: &AuthSession,
+        role: AuthRole,
+        capabilities: Capabilities,
+    ) -> Self {
+        let (send, receive) = match role {
+            AuthRole::Client => (CLIENT_TO_SERVER, SERVER_TO_CLIENT),
+            AuthRole::Server => (SERVER_TO_CLIENT, CLIENT_TO_SERVER),
+        };
+        RecordLayer {
+            send: Direction::new(session, send),
+            receive: Direction::new(session, receive),
+            read_buf: buffered,
+            plaintext: Bytes::new(),
+            write_buf: BytesMut::new(),
+            rekey_supported: capabilities.contains(Capabilities::REKEY),
+            rekey_limit: None,
+            rekey_interval: None,
+            sent_with_key: 0,
+            key_since: Instant::now(),
+            sent_close: false,
+            received_close: false,
+        }
+    }
+
+    fn rekey_due(&self) -> bool {
+        if !self.rekey_supported {
+            return false;
+        }
+        self.rekey_limit
+            .is_some_and(|limit| self.sent_with_key >= limit)
+            || self
+                .rekey_interval
+                .is_some_and(|interval| self.key_since.elapsed() >= interval)
+    }
+
+    /// Queue a control record with the control byte `control`.
+    fn queue_control(&mut self, control: u8) -> io::Result<()> {
+        let prefix = ((1 + TAG_LEN) as u16 | CONTROL_FLAG).to_be_bytes();
+        let nonce = self.send.next_nonce()?;
+        let payload = Payload {
+            msg: &[control],
+            aad: &prefix,
+        };
+        let record = self
+            .send
+            .cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| io::Error::other("Failed to encrypt record"))?;
+        self.write_buf.extend_from_slice(&prefix);
+        self.write_buf.extend_from_slice(&record);
+        Ok(())
+    }
+
+    /// Queue a record that tells the peer that the key is rotated, and rotate it.
+    fn queue_rekey(&mut self) -> io::Result<()> {
+        self.queue_control(CONTROL_REKEY)?;
+        self.send.rekey();
+        self.sent_with_key = 0;
+        self.key_since = Instant::now();
+        Ok(())
+    }
+
+    /// Decrypt the first record in `read_buf`, if it is complete, and return its plaintext,
+    /// which is empty for control records.
+    fn decrypt_record(&mut self) -> io::Result<Option<Bytes>> {
+        let Some(prefix) = self.read_buf.get(..PREFIX_LEN) else {
+            return Ok(None);
+        };
+        let prefix = u16::from_be_bytes([prefix[0], prefix[1]]);
+        let len = usize::from(prefix & !CONTROL_FLAG);
+        if self.read_buf.len() < PREFIX_LEN + len {
+            return Ok(None);
+        }
+        self.read_buf.advance(PREFIX_LEN);
+        let record = self.read_buf.split_to(len);
+        let nonce = self.receive.next_nonce()?;
+        let control = prefix & CONTROL_FLAG != 0;
+        let prefix = prefix.to_be_bytes();
+        let record = Payload {
+            msg: &record,
+            aad: if control { &prefix } else { &[] },
+        };
+        let plaintext = self
+            .receive
+            .cipher
+            .decrypt(&nonce, record)
+            .map_err(|_| invalid("Failed to decrypt record"))?;
+        if !control {
+            return Ok(Some(plaintext.into()));
+        }
+        match plaintext[..] {
+            [CONTROL_REKEY] => self.receive.rekey(),
+            [CONTROL_CLOSE] => self.received_close = true,
+            _ => return Err(invalid("Received unknown control record")),
+        }
+        Ok(Some(Bytes::new()))
+    }
+
+    /// Write the records in `write_buf` to `io`.
+    fn poll_write_records<T: AsyncWrite + Unpin>(
+        &mut self,
+        io: &mut T,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let written = ready!(Pin::new(&mut *io).poll_write(cx, &self.write_buf))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    pub(crate) fn poll_read<T: AsyncRead + Unpin>(
+        &mut self,
+        io: &mut T,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.plaintext.is_empty() {
+            if self.received_close {
+                if !self.read_buf.is_empty() {
+                    return Poll::Ready(Err(invalid("Received data after the close record")));
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(plaintext) = self.decrypt_record()? {
+                self.plaintext = plaintext;
+                continue;
+            }
+            let mut chunk = [0u8; READ_CHUNK_LEN];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut *io).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Stream ended without a close record",
+                )));
+            }
+            self.read_buf.extend_from_slice(chunk.filled());
+        }
+        let len = buf.remaining().min(self.plaintext.len());
+        buf.put_slice(&self.plaintext.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+
+    pub(crate) fn poll_write<T: AsyncWrite + Unpin>(
+        &mut self,
+        io: &mut T,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.sent_close {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        // Only encrypt more once the previous records are written, so that at most one record
+        // is buffered
+        ready!(self.poll_write_records(io, cx))?;
+        if self.rekey_due() {
+            self.queue_rekey()?;
+        }
+        let len = buf.len().min(MAX_RECORD_LEN);
+        let nonce = self.send.next_nonce()?;
+        let record = self
+            .send
+            .cipher
+            .encrypt(&nonce, &buf[..len])
+            .map_err(|_| io::Error::other("Failed to encrypt record"))?;
+        debug_assert_eq!(record.len(), len + TAG_LEN);
+        self.sent_with_key += len as u64;
+        let prefix = u16::try_from(record.len()).expect("records fit in the prefix");
+        self.write_buf.extend_from_slice(&prefix.to_be_bytes());
+        self.write_buf.extend_from_slice(&record);
+        // The record is buffered, so the bytes are written even if this is pending
+        if let Poll::Ready(Err(error)) = self.poll_write_records(io, cx) {
+            return Poll::Ready(Err(error));
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    pub(crate) fn poll_flush<T: AsyncWrite + Unpin>(
+        &mut self,
+        io: &mut T,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_records(io, cx))?;
+        Pin::new(io).poll_flush(cx)
+    }
+
+    /// Send the close record, and shut down `io` once it has been written.
+    pub(crate) fn poll_shutdown<T: AsyncWrite + Unpin>(
+        &mut self,
+        io: &mut T,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.sent_close {
+            self.queue_control(CONTROL_CLOSE)?;
+            self.sent_close = true;
+        }
+        ready!(self.poll_write_records(io, cx))?;
+        Pin::new(io).poll_shutdown(cx)
+    }
+}
+
+/// A byte stream that is encrypted with the keys of an [`AuthSession`].
+pub struct EncryptedStream<T> {
+    io: T,
+    records: RecordLayer,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> EncryptedStream<T> {
+    /// Encrypt `io` with the keys of `session`, as `role`. The peer must do the same with the
+    /// other role. Prefer [`FramedConnection::into_encrypted`] after authenticating, which keeps
+    /// the bytes that were read ahead.
+    ///
+    /// `capabilities` are those that the [handshake](crate::handshake) found both ends to
+    /// support, which decide whether keys may be rotated.
+    pub fn new(io: T, session: &AuthSession, role: AuthRole, capabilities: Capabilities) -> Self {
+        EncryptedStream {
+            io,
+            records: RecordLayer::new(BytesMut::new(), session, role, capabilities),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Rotate the key for sending once `limit` bytes have been sent with it. By default, keys
+    /// are never rotated.
+    ///
+    /// Peers that do not support rotating keys would fail to read the stream once a key has
+    /// been rotated, so this has no effect unless both ends offered [`Capabilities::REKEY`].
+    pub fn set_rekey_limit(&mut self, limit: Option<u64>) {
+        self.records.rekey_limit = limit;
+    }
+
+    /// Rotate the key for sending once it has been used for `interval`. This is checked when
+    /// sending, so a stream that is idle keeps its key until it sends again. By default, keys
+    /// are never rotated. See [`Self::set_rekey_limit`].
+    pub fn set_rekey_interval(&mut self, interval: Option<Duration>) {
+        self.records.rekey_interval = interval;
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for EncryptedStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.records.poll_read(&mut this.io, cx, buf)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for EncryptedStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.records.poll_write(&mut this.io, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.records.poll_flush(&mut this.io, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.records.poll_shutdown(&mut this.io, cx)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> FramedConnection<T> {
+    /// Encrypt the connection with the keys of `session`, which this end took part in as
+    /// `role`, and return it as a plain stream, to be framed again. Input that was read ahead
+    /// while authenticating is kept. Nothing may be buffered for writing, which is the case
+    /// after authenticating. Keys are rotated if the [handshake](crate::handshake) negotiated
+    /// [`Capabilities::REKEY`].
+    pub fn into_encrypted(self, session: &AuthSession, role: AuthRole) -> EncryptedStream<T> {
+        let capabilities = self.capabilities();
+        let (io, buffered) = self.into_parts();
+        EncryptedStream {
+            io,
+            records: RecordLayer::new(buffered, session, role, capabilities),
+        }
+    }
+}
+
+impl FramedConnection<Connection> {
+    /// Like [`Self::into_encrypted`], but encrypt the connection in place, so that it remains a
+    /// [`Connection`].
+    pub fn into_encrypted_connection(self, session: &AuthSession, role: AuthRole) -> Connection {
+        let capabilities = self.capabilities();
+        let (mut connection, buffered) = self.into_parts();
+        connection.set_records(RecordLayer::new(buffered, session, role, capabilities));
+        connection
+    }
+}
+
+impl Endpoint {
+    /// Authenticate every accepted connection with `secret`, and encrypt it, before passing it
+    /// through the layers that are added after this. Peers that do not authenticate within a
+    /// few seconds are disconnected. Clients connect with [`Self::connect_encrypted`].
+    pub fn set_encryption(&mut self, secret: Arc<InstallSecret>) {
+        self.add_layer(move |connection: Connection| {
+            let secret = secret.clone();
+            async move {
+                tokio::time::timeout(AUTH_TIMEOUT, encrypt(connection, &secret, AuthRole::Server))
+                    .await
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::TimedOut, "Peer did not authenticate")
+                    })?
+                    .map_err(into_io_error)
+            }
+        });
+    }
+
+    /// Connect to an endpoint that encrypts its connections, see [`Self::set_encryption`].
+    pub async fn connect_encrypted(
+        path: impl AsRef<Path>,
+        secret: &InstallSecret,
+    ) -> Result<Connection, Error> {
+        let connection = Self::connect(path).await?;
+        encrypt(connection, secret, AuthRole::Client).await
+    }
+}
+
+/// Authenticate with `secret` as `role`, and encrypt `connection` with the keys of the session.
+async fn encrypt(
+    connection: Connection,
+    secret: &InstallSecret,
+    role: AuthRole,
+) -> Result<Connection, Error> {
+    let mut connection = FramedConnection::new(connection);
+    let session = connection.authenticate(secret, role).await?;
+    Ok(connection.into_encrypted_connection(&session, role))
+}
+
+fn into_io_error(error: Error) -> io::Error {
+    match error {
+        Error::Io(error) => error,
+        error => io::Error::other(error),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{auth::InstallSecret, frame::Frame};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    /// Connect two ends that offer `client_offers` and `server_offers` in the handshake, and
+    /// encrypt the connection.
+    async fn encrypted_pair(
+        client_offers: Capabilities,
+        server_offers: Capabilities,
+    ) -> (
+        FramedConnection<EncryptedStream<tokio::io::DuplexStream>>,
+        FramedConnection<EncryptedStream<tokio::io::DuplexStream>>,
+    ) {
+        let secret = InstallSecret::generate();
+        let (client, server) = duplex(64 * 1024);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        let (client_hello, server_hello) = tokio::join!(
+            client.handshake(1, client_offers),
+            server.handshake(1, server_offers),
+        );
+        client_hello.unwrap();
+        server_hello.unwrap();
+        let (client_session, server_session) = tokio::join!(
+            client.authenticate(&secret, AuthRole::Client),
+            server.authenticate(&secret, AuthRole::Server),
+        );
+        let client = client.into_encrypted(&client_session.unwrap(), AuthRole::Client);
+        let server = server.into_encrypted(&server_session.unwrap(), AuthRole::Server);
+        (FramedConnection::new(client), FramedConnection::new(server))
+    }
+
+    #[tokio::test]
+    async fn test_frames() {
+        let (mut client, mut server) =
+            encrypted_pair(Capabilities::empty(), Capabilities::empty()).await;
+        let large = Frame::data(vec![7u8; 3 * MAX_RECORD_LEN + 1]);
+        let (write_result, read_result) = tokio::join!(
+            async {
+                client.write_frame(&Frame::data(&b"hello"[..])).await?;
+                client.write_frame(&large).await
+            },
+            async {
+                let small = server.read_frame().await?;
+                Ok::<_, crate::Error>((small, server.read_frame().await?))
+            },
+        );
+        write_result.unwrap();
+        let (small, received) = read_result.unwrap();
+        assert_eq!(small, Some(Frame::data(&b"hello"[..])));
+        assert_eq!(received, Some(large));
+
+        server
+            .write_frame(&Frame::data(&b"world"[..]))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.read_frame().await.unwrap(),
+            Some(Frame::data(&b"world"[..]))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rekey() {
+        let (mut client, mut server) =
+            encrypted_pair(Capabilities::REKEY, Capabilities::REKEY).await;
+        let first_key = client.get_ref().records.send.key;
+        client
+            .get_mut()
+            .set_rekey_limit(Some(MAX_RECORD_LEN as u64));
+        let large = Frame::data(vec![7u8; 4 * MAX_RECORD_LEN]);
+        let (written, received) = tokio::join!(client.write_frame(&large), server.read_frame());
+        written.unwrap();
+        assert_eq!(received.unwrap(), Some(large));
+        let key = client.get_ref().records.send.key;
+        assert_ne!(key, first_key);
+        assert_eq!(server.get_ref().records.receive.key, key);
+        // The other direction keeps its key
+        assert_eq!(
+            server.get_ref().records.send.key,
+            client.get_ref().records.receive.key
+        );
+
+        client.get_mut().set_rekey_limit(None);
+        client
+            .get_mut()
+            .set_rekey_interval(Some(Duration::from_secs(60 * 60)));
+        tokio::time::advance(Duration::from_secs(60 * 60)).await;
+        let small = Frame::data(&b"hello"[..]);
+        client.write_frame(&small).await.unwrap();
+        assert_eq!(server.read_frame().await.unwrap(), Some(small));
+        assert_ne!(client.get_ref().records.send.key, key);
+        assert_eq!(
+            server.get_ref().records.receive.key,
+            client.get_ref().records.send.key
+        );
+    }
+
+    #[test]
+    fn test_key_zeroed() {
+        let mut direction = std::mem::ManuallyDrop::new(Direction::with_key([7; KEY_LEN]));
+        direction.rekey();
+        let key = direction.key;
+        assert_ne!(key, [7; KEY_LEN]);
+        assert_eq!(key, auth::to_key(auth::hmac(&[7; KEY_LEN], &[REKEY_LABEL])));
+        // SAFETY: Only the key is read afterwards, which is plain bytes that are not freed
+        unsafe { std::mem::ManuallyDrop::drop(&mut direction) };
+        assert_eq!(direction.key, [0; KEY_LEN]);
+    }
+
+    #[tokio::test]
+    async fn test_rekey_not_negotiated() {
+        let (mut client, mut server) =
+            encrypted_pair(Capabilities::REKEY, Capabilities::empty()).await;
+        let first_key = client.get_ref().records.send.key;
+        client.get_mut().set_rekey_limit(Some(1));
+        let frame = Frame::data(&b"hello"[..]);
+        client.write_frame(&frame).await.unwrap();
+        client.write_frame(&frame).await.unwrap();
+        assert_eq!(server.read_frame().await.unwrap(), Some(frame.clone()));
+        assert_eq!(server.read_frame().await.unwrap(), Some(frame));
+        assert_eq!(client.get_ref().records.send.key, first_key);
+    }
+
+    #[tokio::test]
+    async fn test_truncation() {
+        let (client, server) = encrypted_pair(Capabilities::empty(), Capabilities::empty()).await;
+        let mut client = client.into_inner();
+        let mut server = server.into_inner();
+        client.write_all(b"hello").await.unwrap();
+        client.flush().await.unwrap();
+        // The stream ends at the end of a record, but without the close record
+        drop(client.io);
+        let mut received = Vec::new();
+        let error = server.read_to_end(&mut received).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(received, b"hello");
+
+        let (client, server) = encrypted_pair(Capabilities::empty(), Capabilities::empty()).await;
+        let mut client = client.into_inner();
+        let mut server = server.into_inner();
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+        assert!(client.write_all(b"more").await.is_err());
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_endpoint() {
+        let secret = Arc::new(InstallSecret::generate());
+        let mut endpoint = crate::testing::EphemeralEndpoint::with_options(|endpoint| {
+            endpoint.set_encryption(secret.clone());
+        })
+        .unwrap();
+        let path = endpoint.path().to_owned();
+        let (client, server) = tokio::join!(
+            Endpoint::connect_encrypted(&path, &secret),
+            endpoint.accept()
+        );
+        let mut client = FramedConnection::new(client.unwrap());
+        let mut server = FramedConnection::new(server.unwrap());
+        client
+            .write_frame(&Frame::data(&b"hello"[..]))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.read_frame().await.unwrap(),
+            Some(Frame::data(&b"hello"[..]))
+        );
+        client.close().await.unwrap();
+        assert_eq!(
+            server.read_frame().await.unwrap(),
+            Some(Frame::goodbye(crate::frame::GoodbyeReason::Unspecified))
+        );
+        assert_eq!(server.read_frame().await.unwrap(), None);
+
+        // A client that does not know the secret is not let through
+        let other = InstallSecret::generate();
+        let (client, ()) = tokio::join!(Endpoint::connect_encrypted(&path, &other), async {
+            let _ = tokio::time::timeout(Duration::from_millis(100), endpoint.accept()).await;
+        });
+        assert!(client.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tampering() {
+        let secret = InstallSecret::generate();
+        let session = {
+            let (client, server) = duplex(256);
+            let mut client = FramedConnection::new(client);
+            let mut server = FramedConnection::new(server);
+            let (session, _) = tokio::join!(
+                client.authenticate(&secret, AuthRole::Client),
+                server.authenticate(&secret, AuthRole::Server),
+            );
+            session.unwrap()
+        };
+        let (client, mut relay) = duplex(256);
+        let mut client =
+            EncryptedStream::new(client, &session, AuthRole::Client, Capabilities::empty());
+        client.write_all(b"hello").await.unwrap();
+        client.flush().await.unwrap();
+
+        let mut record = [0u8; PREFIX_LEN + 5 + TAG_LEN];
+        relay.read_exact(&mut record).await.unwrap();
+        assert!(!record.windows(5).any(|window| window == b"hello"));
+        record[PREFIX_LEN] ^= 1;
+
+        let (server, mut relay) = duplex(256);
+        let mut server =
+            EncryptedStream::new(server, &session, AuthRole::Server, Capabilities::empty());
+        relay.write_all(&record).await.unwrap();
+        let error = server.read(&mut [0u8; 5]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,555 @@
+//! Fan-out of events to every connected client.
+//!
+//! The daemon sends each event once to an [`EventBroadcaster`], and every connection that has
+//! subscribed receives it through its own queue. A [`Subscription`] is a stream of events, and
+//! can be passed to [`crate::rpc::serve`] as the events of a connection.
+//!
+//! The broadcaster can retain recent events, and replay them to new subscribers. A client that
+//! connects after an event was sent, e.g. because the daemon was restarted, then still learns
+//! about the current state.
+//!
+//! Every event is given a sequence number. A client that reconnects, e.g. after the connection
+//! was lost, can resume where it left off with the [`ResumeToken`] of the last event that it
+//! received, and is sent the retained events that it missed in between. See
+//! [`EventBroadcaster::resume`].
+//!
+//! The queue of each subscriber is bounded. What happens when a subscriber falls behind and its
+//! queue fills up is decided by the [`SlowConsumerPolicy`], so that a client that has stopped
+//! reading cannot make the daemon buffer events without limit.
+
+use futures::Stream;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Number of events that are queued for each subscriber unless configured otherwise.
+pub const DEFAULT_QUEUE_LEN: usize = 64;
+
+/// Classifies events, so that clients can choose which ones to receive, and so that the latest
+/// event of each kind can be replayed.
+pub trait EventKind {
+    /// Name of the kind of this event, e.g. the name of its enum variant.
+    fn event_kind(&self) -> &str;
+}
+
+/// There is only one kind of empty event.
+impl EventKind for () {
+    fn event_kind(&self) -> &str {
+        ""
+    }
+}
+
+/// A string event is its own kind.
+impl EventKind for String {
+    fn event_kind(&self) -> &str {
+        self
+    }
+}
+
+/// Identifies the last event that a subscriber received, so that it can resume from there.
+///
+/// Sequence numbers start from the time at which the broadcaster was created, in microseconds.
+/// A token from an earlier broadcaster, e.g. one from before the daemon was restarted, is
+/// therefore older than every event of a later one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ResumeToken(u64);
+
+impl ResumeToken {
+    /// Recreate a token from [`Self::as_u64`].
+    pub fn from_u64(seq: u64) -> Self {
+        ResumeToken(seq)
+    }
+
+    /// Return the token as a number, e.g. to send it to the peer.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// Whether a resumed subscription receives every event that the subscriber missed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resumption {
+    /// All missed events that are retained are replayed. With
+    /// [`EventBroadcaster::set_replay_latest_of_each_kind`], events that were superseded by a
+    /// later one of the same kind are not.
+    Complete,
+    /// Some missed events are no longer retained, or the token belongs to another broadcaster.
+    /// The retained events are replayed as if the subscriber were new, and it should fetch the
+    /// current state again.
+    Incomplete,
+}
+
+/// What to do with a new event for a subscriber whose queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// The subscriber misses the new event.
+    #[default]
+    DropNewest,
+    /// The oldest queued event is dropped to make room for the new one.
+    DropOldest,
+    /// The queued events are dropped, and the subscription ends. The owner of the subscription
+    /// should close the connection of the subscriber, which [`rpc::serve_broadcast`] does with
+    /// [`GoodbyeReason::Evicted`]. See [`Subscription::is_disconnected`].
+    ///
+    /// [`rpc::serve_broadcast`]: crate::rpc::serve_broadcast
+    /// [`GoodbyeReason::Evicted`]: crate::frame::GoodbyeReason::Evicted
+    Disconnect,
+}
+
+/// Sends events to all subscribers. Clones share the same subscribers and retained events.
+pub struct EventBroadcaster<Event> {
+    shared: Arc<Mutex<Shared<Event>>>,
+    queue_len: usize,
+}
+
+struct Shared<Event> {
+    subscribers: Vec<Arc<Mutex<Queue<Event>>>>,
+    replay: Replay<Event>,
+    overflow: Overflow<Event>,
+    /// Retained events and their sequence numbers, oldest first.
+    history: VecDeque<(u64, Event)>,
+    /// Sequence number of the first event.
+    first_seq: u64,
+    /// Sequence number of the next event.
+    next_seq: u64,
+}
+
+/// Which events are replayed to new subscribers.
+enum Replay<Event> {
+    Nothing,
+    Last(usize),
+    LatestOfEachKind(fn(&Event) -> &str),
+}
+
+/// What to do when the queue of a subscriber is full.
+enum Overflow<Event> {
+    Policy(SlowConsumerPolicy),
+    CoalesceByKind(fn(&Event) -> &str),
+}
+
+/// Events that have not yet been received by a subscriber, and their sequence numbers.
+struct Queue<Event> {
+    events: VecDeque<(u64, Event)>,
+    capacity: usize,
+    /// Set when the subscription has ended, either because all broadcasters have been dropped,
+    /// or because the subscriber was disconnected.
+    closed: bool,
+    /// Set when the subscriber was disconnected for not keeping up.
+    disconnected: bool,
+    waker: Option<Waker>,
+}
+
+impl<Event> Queue<Event> {
+    /// Queue `event` according to `overflow`. Returns whether the event was queued.
+    fn push(&mut self, event: (u64, Event), overflow: &Overflow<Event>) -> bool {
+        if self.events.len() >= self.capacity {
+            match overflow {
+                Overflow::Policy(SlowConsumerPolicy::DropNewest) => return false,
+                Overflow::Policy(SlowConsumerPolicy::DropOldest) => {
+                    self.events.pop_front();
+                }
+                Overflow::Policy(SlowConsumerPolicy::Disconnect) => {
+                    self.events.clear();
+                    self.disconnected = true;
+                    self.close();
+                    return false;
+                }
+                Overflow::CoalesceByKind(kind_of) => {
+                    let kind = kind_of(&event.1);
+                    match self
+                        .events
+                        .iter()
+                        .position(|(_, queued)| kind_of(queued) == kind)
+                    {
+                        Some(index) => self.events.remove(index),
+                        None => self.events.pop_front(),
+                    };
+                }
+            }
+        }
+        self.events.push_back(event);
+        self.wake();
+        true
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<Event> Shared<Event> {
+    fn retain(&mut self, event: (u64, Event)) {
+        match self.replay {
+            Replay::Nothing => return,
+            Replay::Last(count) => {
+                if self.history.len() >= count {
+                    self.history.pop_front();
+                }
+            }
+            Replay::LatestOfEachKind(kind_of) => {
+                let kind = kind_of(&event.1);
+                self.history
+                    .retain(|(_, retained)| kind_of(retained) != kind);
+            }
+        }
+        self.history.push_back(event);
+    }
+
+    /// Whether every event after `seq` is retained.
+    fn retains_all_after(&self, seq: u64) -> bool {
+        let missed = seq + 1..self.next_seq;
+        if missed.is_empty() {
+            return true;
+        }
+        match self.replay {
+            Replay::Nothing => false,
+            Replay::Last(_) => self
+                .history
+                .front()
+                .is_some_and(|(first, _)| *first <= missed.start),
+            Replay::LatestOfEachKind(_) => true,
+        }
+    }
+}
+
+impl<Event> Drop for Shared<Event> {
+    fn drop(&mut self) {
+        // The last broadcaster is gone, so no more events will arrive
+        for subscriber in &self.subscribers {
+            subscriber.lock().unwrap().close();
+        }
+    }
+}
+
+impl<Event> Clone for EventBroadcaster<Event> {
+    fn clone(&self) -> Self {
+        EventBroadcaster {
+            shared: self.shared.clone(),
+            queue_len: self.queue_len,
+        }
+    }
+}
+
+impl<Event> Default for EventBroadcaster<Event> {
+    fn default() -> Self {
+        // 0 is reserved for events without a sequence number
+        let first_seq = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX))
+            .unwrap_or(0)
+            .max(1);
+        EventBroadcaster {
+            shared: Arc::new(Mutex::new(Shared {
+                subscribers: vec![],
+                replay: Replay::Nothing,
+                overflow: Overflow::Policy(SlowConsumerPolicy::default()),
+                history: VecDeque::new(),
+                first_seq,
+                next_seq: first_seq,
+            })),
+            queue_len: DEFAULT_QUEUE_LEN,
+        }
+    }
+}
+
+impl<Event: Clone> EventBroadcaster<Event> {
+    pub fn new() -> Self {
+        EventBroadcaster::default()
+    }
+
+    /// Set the number of events that are queued for each new subscriber. The default is
+    /// [`DEFAULT_QUEUE_LEN`], and values below 1 are treated as 1.
+    pub fn set_queue_len(&mut self, queue_len: usize) {
+        self.queue_len = queue_len.max(1);
+    }
+
+    /// Decide what happens when the queue of a subscriber is full. The default is
+    /// [`SlowConsumerPolicy::DropNewest`].
+    pub fn set_slow_consumer_policy(&mut self, policy: SlowConsumerPolicy) {
+        self.shared.lock().unwrap().overflow = Overflow::Policy(policy);
+    }
+
+    /// Retain the last `count` events, and replay them to new subscribers. By default, no
+    /// events are retained.
+    pub fn set_replay_last(&mut self, count: usize) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.replay = if count == 0 {
+            Replay::Nothing
+        } else {
+            Replay::Last(count)
+        };
+        while shared.history.len() > count {
+            shared.history.pop_front();
+        }
+    }
+
+    /// Receive all events that are sent from now on, preceded by any retained events.
+    pub fn subscribe(&self) -> Subscription<Event> {
+        let mut shared = self.shared.lock().unwrap();
+        let replayed = shared.history.clone();
+        self.add_subscriber(&mut shared, replayed)
+    }
+
+    /// Like [`Self::subscribe`], but only replay the retained events that were sent after the
+    /// event identified by `token`, e.g. because a client that has reconnected already received
+    /// the earlier ones. Returns whether all missed events could be replayed.
+    pub fn resume(&self, token: ResumeToken) -> (Subscription<Event>, Resumption) {
+        let mut shared = self.shared.lock().unwrap();
+        let seq = token.as_u64();
+        let ours = (shared.first_seq..shared.next_seq).contains(&seq);
+        let (replayed, resumption) = if ours && shared.retains_all_after(seq) {
+            let missed = shared
+                .history
+                .iter()
+                .filter(|(retained, _)| *retained > seq)
+                .cloned()
+                .collect();
+            (missed, Resumption::Complete)
+        } else {
+            (shared.history.clone(), Resumption::Incomplete)
+        };
+        (self.add_subscriber(&mut shared, replayed), resumption)
+    }
+
+    fn add_subscriber(
+        &self,
+        shared: &mut Shared<Event>,
+        replayed: VecDeque<(u64, Event)>,
+    ) -> Subscription<Event> {
+        let queue = Arc::new(Mutex::new(Queue {
+            // There is room for the replayed events on top of the usual queue
+            capacity: self.queue_len + replayed.len(),
+            events: replayed,
+            closed: false,
+            disconnected: false,
+            waker: None,
+        }));
+        shared.subscribers.push(queue.clone());
+        Subscription { queue, last: None }
+    }
+
+    /// Queue `event` for every subscriber, and forget subscribers that have been dropped or
+    /// disconnected. Subscribers whose queue is full are handled according to the
+    /// [`SlowConsumerPolicy`]. Returns the number of subscribers that the event was queued for.
+    pub fn send(&self, event: Event) -> usize {
+        let mut shared = self.shared.lock().unwrap();
+        let Shared {
+            subscribers,
+            overflow,
+            next_seq,
+            ..
+        } = &mut *shared;
+        let seq = *next_seq;
+        *next_seq += 1;
+        let mut delivered = 0;
+        subscribers.retain(|subscriber| {
+            // Only the broadcaster holds on to the queue once the subscription has been dropped
+            if Arc::strong_count(subscriber) == 1 {
+                return false;
+            }
+            let mut queue = subscriber.lock().unwrap();
+            if queue.push((seq, event.clone()), overflow) {
+                delivered += 1;
+            } else if queue.closed {
+                log::warn!("Disconnecting IPC event subscriber that is not keeping up");
+            } else {
+                log::warn!("IPC event subscriber is not keeping up, dropping event");
+            }
+            !queue.closed
+        });
+        shared.retain((seq, event));
+        delivered
+    }
+
+    /// Number of subscribers, including ones that have been dropped since the last event was
+    /// sent.
+    pub fn subscriber_count(&self) -> usize {
+        self.shared.lock().unwrap().subscribers.len()
+    }
+}
+
+impl<Event: Clone + EventKind> EventBroadcaster<Event> {
+    /// Retain the latest event of each [`EventKind`], and replay them to new subscribers in the
+    /// order in which they were sent. This replaces [`Self::set_replay_last`].
+    pub fn set_replay_latest_of_each_kind(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.replay = Replay::LatestOfEachKind(Event::event_kind);
+        let history = std::mem::take(&mut shared.history);
+        for event in history {
+            shared.retain(event);
+        }
+    }
+
+    /// When the queue of a subscriber is full, replace a queued event of the same
+    /// [`EventKind`] with the new one, or drop the oldest event if there is none. Subscribers
+    /// that mostly care about the latest state then lose as little as possible. This replaces
+    /// [`Self::set_slow_consumer_policy`].
+    pub fn set_coalesce_by_kind(&mut self) {
+        self.shared.lock().unwrap().overflow = Overflow::CoalesceByKind(Event::event_kind);
+    }
+}
+
+/// Events sent by an [`EventBroadcaster`]. The stream ends once all broadcasters have been
+/// dropped, or when the subscriber is disconnected for not keeping up.
+pub struct Subscription<Event> {
+    queue: Arc<Mutex<Queue<Event>>>,
+    /// Sequence number of the last event that was received.
+    last: Option<u64>,
+}
+
+impl<Event> Subscription<Event> {
+    /// Token that identifies the last event that was received from this subscription, or `None`
+    /// if none has been received yet.
+    pub fn resume_token(&self) -> Option<ResumeToken> {
+        self.last.map(ResumeToken)
+    }
+
+    /// Whether the subscription ended because the subscriber did not keep up, under
+    /// [`SlowConsumerPolicy::Disconnect`].
+    pub fn is_disconnected(&self) -> bool {
+        self.queue.lock().unwrap().disconnected
+    }
+
+    /// Like [`Stream::poll_next`], but also return the sequence number of the event.
+    pub(crate) fn poll_next_sequenced(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<(u64, Event)>> {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some((seq, event)) = queue.events.pop_front() {
+            self.last = Some(seq);
+            return Poll::Ready(Some((seq, event)));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<Event> Stream for Subscription<Event> {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.get_mut()
+            .poll_next_sequenced(cx)
+            .map(|event| event.map(|(_, event)| event))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_broadcast() {
+        let mut broadcaster = EventBroadcaster::new();
+        broadcaster.set_queue_len(1);
+        let mut first = broadcaster.subscribe();
+        let second = broadcaster.subscribe();
+
+        assert_eq!(broadcaster.send(1), 2);
+        assert_eq!(first.next().await, Some(1));
+
+        // The second subscriber still has the first event queued
+        drop(second);
+        assert_eq!(broadcaster.send(2), 1);
+        assert_eq!(broadcaster.subscriber_count(), 1);
+        assert_eq!(first.next().await, Some(2));
+
+        drop(broadcaster);
+        assert_eq!(first.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let mut broadcaster = EventBroadcaster::new();
+        broadcaster.set_replay_last(2);
+        for event in 1..=3 {
+            broadcaster.send(event);
+        }
+        let replayed: Vec<_> = broadcaster.subscribe().take(2).collect().await;
+        assert_eq!(replayed, [2, 3]);
+
+        let mut broadcaster = EventBroadcaster::new();
+        broadcaster.set_replay_latest_of_each_kind();
+        for event in ["tunnel", "settings", "tunnel"] {
+            broadcaster.send(event.to_owned());
+        }
+        let replayed: Vec<_> = broadcaster.subscribe().take(2).collect().await;
+        assert_eq!(replayed, ["settings", "tunnel"]);
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer_policy() {
+        let mut broadcaster = EventBroadcaster::new();
+        broadcaster.set_queue_len(2);
+        broadcaster.set_slow_consumer_policy(SlowConsumerPolicy::DropOldest);
+        let subscription = broadcaster.subscribe();
+        for event in ["a", "b", "c"] {
+            broadcaster.send(event.to_owned());
+        }
+        assert_eq!(subscription.take(2).collect::<Vec<_>>().await, ["b", "c"]);
+
+        broadcaster.set_coalesce_by_kind();
+        let subscription = broadcaster.subscribe();
+        for event in ["a", "b", "a"] {
+            broadcaster.send(event.to_owned());
+        }
+        assert_eq!(subscription.take(2).collect::<Vec<_>>().await, ["b", "a"]);
+
+        broadcaster.set_slow_consumer_policy(SlowConsumerPolicy::Disconnect);
+        let subscription = broadcaster.subscribe();
+        for event in ["a", "b"] {
+            broadcaster.send(event.to_owned());
+        }
+        assert_eq!(broadcaster.send("c".to_owned()), 0);
+        assert!(subscription.is_disconnected());
+        assert_eq!(subscription.collect::<Vec<_>>().await, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let mut broadcaster = EventBroadcaster::new();
+        broadcaster.set_replay_last(2);
+        let mut subscription = broadcaster.subscribe();
+        broadcaster.send(1);
+        assert_eq!(subscription.next().await, Some(1));
+        let token = subscription.resume_token().unwrap();
+        drop(subscription);
+
+        broadcaster.send(2);
+        let (resumed, resumption) = broadcaster.resume(token);
+        assert_eq!(resumption, Resumption::Complete);
+        assert_eq!(resumed.take(1).collect::<Vec<_>>().await, [2]);
+
+        // Event 2 is no longer retained
+        broadcaster.send(3);
+        broadcaster.send(4);
+        let (resumed, resumption) = broadcaster.resume(token);
+        assert_eq!(resumption, Resumption::Incomplete);
+        assert_eq!(resumed.take(2).collect::<Vec<_>>().await, [3, 4]);
+
+        // The token of another broadcaster is not recognized. Its sequence numbers start later.
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let mut other = EventBroadcaster::new();
+        other.set_replay_last(2);
+        other.send(5);
+        let (resumed, resumption) = other.resume(token);
+        assert_eq!(resumption, Resumption::Incomplete);
+        assert_eq!(resumed.take(1).collect::<Vec<_>>().await, [5]);
+    }
+}
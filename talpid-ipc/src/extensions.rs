@@ -0,0 +1,139 @@
+//! Data that is attached to a connection, keyed by its type.
+//!
+//! Layers that serve a connection often learn something about it that later layers need, such
+//! as the identity that a peer authenticated as, the quota that it counts against, or what it
+//! has subscribed to. Each layer stores a value of its own type in the [`Extensions`] of the
+//! connection, see [`Connection::extensions_mut`], instead of the server keeping maps from
+//! [`ConnectionId`]s to such state, which would have to be cleaned up when connections close.
+//!
+//! [`Connection::extensions_mut`]: crate::Connection::extensions_mut
+//! [`ConnectionId`]: crate::ConnectionId
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+};
+
+/// A map that holds at most one value of each type.
+#[derive(Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Extensions::default()
+    }
+
+    /// Store `value`, and return the value of the same type that was stored before, if any.
+    /// Layers should use types of their own, rather than e.g. `String`, so that they do not
+    /// overwrite each other's values.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.values
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .map(|previous| *downcast(previous))
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.values
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    /// Return the value of type `T`, storing the one returned by `default` first if there is
+    /// none.
+    pub fn get_or_insert_with<T: Any + Send + Sync>(
+        &mut self,
+        default: impl FnOnce() -> T,
+    ) -> &mut T {
+        self.values
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(default()))
+            .downcast_mut()
+            .expect("Values are stored under their own type")
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.values
+            .remove(&TypeId::of::<T>())
+            .map(|value| *downcast(value))
+    }
+
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+}
+
+impl fmt::Debug for Extensions {
+    // The values need not implement `Debug`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.values.len())
+            .finish_non_exhaustive()
+    }
+}
+
+fn downcast<T: Any>(value: Box<dyn Any + Send + Sync>) -> Box<T> {
+    value
+        .downcast()
+        .unwrap_or_else(|_| unreachable!("Values are stored under their own type"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Identity(&'static str);
+
+    #[derive(Debug, PartialEq)]
+    struct Subscriptions(Vec<&'static str>);
+
+    #[test]
+    fn test_extensions() {
+        let mut extensions = Extensions::new();
+        assert_eq!(extensions.insert(Identity("alice")), None);
+        assert_eq!(extensions.insert(Identity("bob")), Some(Identity("alice")));
+        assert_eq!(extensions.get::<Identity>(), Some(&Identity("bob")));
+        assert!(!extensions.contains::<Subscriptions>());
+
+        extensions
+            .get_or_insert_with(|| Subscriptions(vec![]))
+            .0
+            .push("tunnel_state");
+        extensions
+            .get_mut::<Subscriptions>()
+            .unwrap()
+            .0
+            .push("settings");
+        assert_eq!(
+            extensions.get::<Subscriptions>(),
+            Some(&Subscriptions(vec!["tunnel_state", "settings"]))
+        );
+        assert_eq!(extensions.len(), 2);
+
+        assert_eq!(extensions.remove::<Identity>(), Some(Identity("bob")));
+        assert_eq!(extensions.remove::<Identity>(), None);
+        extensions.clear();
+        assert!(extensions.is_empty());
+    }
+}
@@ -0,0 +1,425 @@
+//! Sending files over a connection.
+//!
+//! The GUI hands files such as problem reports and log bundles to the daemon over its connection,
+//! rather than writing them to a temporary path that other users can read. [`send_file`] offers
+//! the file with its length, and streams it with [`crate::chunked`] once the receiver has
+//! accepted it. [`receive_file`] writes it to a new file that only its owner can read, and
+//! compares its SHA-256 digest with the one that the sender computed. Files that are not received
+//! in full, or whose digest does not match, are removed.
+
+use crate::{
+    Error,
+    chunked::{self, TransferOptions},
+    frame::{Frame, FrameKind, FramedConnection},
+};
+use bytes::{Buf, Bytes};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt, io,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+};
+
+/// Length of the digest that is sent after the file.
+const DIGEST_LEN: usize = 32;
+
+const ACCEPTED: u8 = 1;
+const REFUSED: u8 = 0;
+
+/// Called as a file is being sent or received. It is called from within `poll` functions, so it
+/// must not block.
+pub type ProgressCallback = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/// How much of a file has been sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub transferred: u64,
+    /// Length of the file, as announced by the sender.
+    pub total: u64,
+}
+
+/// Options for [`send_file`] and [`receive_file`].
+#[derive(Clone, Default)]
+pub struct FileTransferOptions {
+    transfer: TransferOptions,
+    max_len: Option<u64>,
+    progress: Option<ProgressCallback>,
+}
+
+impl FileTransferOptions {
+    pub fn new() -> Self {
+        FileTransferOptions::default()
+    }
+
+    /// Set the window and chunk length of the underlying transfer.
+    pub fn set_transfer_options(&mut self, transfer: TransferOptions) {
+        self.transfer = transfer;
+    }
+
+    /// Make the receiver refuse files of more than `max_len` bytes. By default, files of any
+    /// length are received.
+    pub fn set_max_len(&mut self, max_len: Option<u64>) {
+        self.max_len = max_len;
+    }
+
+    /// Report the progress of the transfer to `callback`.
+    pub fn set_progress_callback(&mut self, callback: ProgressCallback) {
+        self.progress = Some(callback);
+    }
+}
+
+impl fmt::Debug for FileTransferOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileTransferOptions")
+            .field("transfer", &self.transfer)
+            .field("max_len", &self.max_len)
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
+}
+
+/// Send the file at `path` to the peer, which must call [`receive_file`]. Returns the length of
+/// the file once the peer has received and verified it.
+///
+/// Fails with [`Error::BulkRejected`] if the peer refuses the file, with
+/// [`Error::TransferAborted`] if it stops receiving it, and with
+/// [`Error::IntegrityCheckFailed`] if what it received does not match what was sent.
+pub async fn send_file<T>(
+    connection: &mut FramedConnection<T>,
+    path: impl AsRef<Path>,
+    options: &FileTransferOptions,
+) -> Result<u64, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let file = File::open(path).await?;
+    let total = file.metadata().await?.len();
+    connection
+        .write_frame(&Frame::data(total.to_be_bytes().to_vec()))
+        .await?;
+    if read_payload(connection, 1).await?[0] != ACCEPTED {
+        return Err(Error::BulkRejected);
+    }
+
+    // Only what has been announced is sent, even if the file grows in the meantime
+    let mut reader = Tracked::new(file.take(total), total, options.progress.clone());
+    let sent = chunked::send(connection, &mut reader, &options.transfer).await?;
+    let digest = reader.hasher.finalize();
+    connection
+        .write_frame(&Frame::data(digest.to_vec()))
+        .await?;
+    if read_payload(connection, 1).await?[0] != ACCEPTED {
+        return Err(Error::IntegrityCheckFailed);
+    }
+    Ok(sent)
+}
+
+/// Receive a file sent by the peer using [`send_file`], and write it to `path`, which must not
+/// exist yet. Returns the length of the file.
+///
+/// Fails with [`Error::BulkTooLarge`] if the file exceeds [`FileTransferOptions::set_max_len`],
+/// and with [`Error::IntegrityCheckFailed`] if it was not received intact. The file is removed
+/// unless it was received in full, including if the returned future is dropped.
+pub async fn receive_file<T>(
+    connection: &mut FramedConnection<T>,
+    path: impl AsRef<Path>,
+    options: &FileTransferOptions,
+) -> Result<u64, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let path = path.as_ref();
+    let total = read_payload(connection, 8).await?.get_u64();
+    if let Some(max) = options.max_len
+        && total > max
+    {
+        reply(connection, REFUSED).await?;
+        return Err(Error::BulkTooLarge { len: total, max });
+    }
+    let file = match create(path).await {
+        Ok(file) => file,
+        Err(error) => {
+            reply(connection, REFUSED).await?;
+            return Err(error.into());
+        }
+    };
+    let partial = PartialFile { path: Some(path) };
+    reply(connection, ACCEPTED).await?;
+
+    let received = receive_into(connection, file, total, options).await?;
+    partial.keep();
+    Ok(received)
+}
+
+/// Removes a file that is being received when dropped, unless it was received in full.
+struct PartialFile<'a> {
+    path: Option<&'a Path>,
+}
+
+impl PartialFile<'_> {
+    fn keep(mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for PartialFile<'_> {
+    fn drop(&mut self) {
+        if let Some(path) = self.path {
+            // The file is incomplete or corrupt
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+async fn receive_into<T>(
+    connection: &mut FramedConnection<T>,
+    file: File,
+    total: u64,
+    options: &FileTransferOptions,
+) -> Result<u64, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut transfer = options.transfer.clone();
+    transfer.set_max_len(Some(total));
+    let mut writer = Tracked::new(file, total, options.progress.clone());
+    let received = chunked::receive(connection, &mut writer, &transfer).await?;
+    let digest = writer.hasher.finalize();
+    if read_payload(connection, DIGEST_LEN).await? != digest[..] || received != total {
+        reply(connection, REFUSED).await?;
+        return Err(Error::IntegrityCheckFailed);
+    }
+    writer.inner.sync_all().await?;
+    reply(connection, ACCEPTED).await?;
+    Ok(received)
+}
+
+/// Create a new file at `path` that only its owner can access.
+async fn create(path: &Path) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path).await
+}
+
+async fn reply<T: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut FramedConnection<T>,
+    verdict: u8,
+) -> Result<(), Error> {
+    connection.write_frame(&Frame::data(vec![verdict])).await
+}
+
+/// Read a data frame with a payload of exactly `len` bytes.
+async fn read_payload<T: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut FramedConnection<T>,
+    len: usize,
+) -> Result<Bytes, Error> {
+    let frame = connection.read_frame().await?.ok_or(Error::UnexpectedEof)?;
+    if frame.kind != FrameKind::Data || frame.payload.len() != len {
+        return Err(Error::Protocol("Unexpected frame in file transfer"));
+    }
+    Ok(frame.payload)
+}
+
+/// Hashes and counts the bytes that are read from or written to `inner`, and reports the
+/// progress.
+struct Tracked<T> {
+    inner: T,
+    hasher: Sha256,
+    progress: Progress,
+    callback: Option<ProgressCallback>,
+}
+
+impl<T> Tracked<T> {
+    fn new(inner: T, total: u64, callback: Option<ProgressCallback>) -> Self {
+        Tracked {
+            inner,
+            hasher: Sha256::new(),
+            progress: Progress {
+                transferred: 0,
+                total,
+            },
+            callback,
+        }
+    }
+
+    fn track(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.hasher.update(bytes);
+        self.progress.transferred += bytes.len() as u64;
+        if let Some(callback) = &self.callback {
+            callback(&self.progress);
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Tracked<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.track(&buf.filled()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Tracked<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.track(&buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_file_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("report.zip");
+        let destination = dir.path().join("received.zip");
+        let contents: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &contents).unwrap();
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut sender = FramedConnection::new(client);
+        let mut receiver = FramedConnection::new(server);
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let mut options = FileTransferOptions::new();
+        options.set_progress_callback({
+            let reported = reported.clone();
+            Arc::new(move |progress| reported.lock().unwrap().push(*progress))
+        });
+
+        let (sent, received) = tokio::join!(
+            send_file(&mut sender, &source, &FileTransferOptions::new()),
+            receive_file(&mut receiver, &destination, &options),
+        );
+        assert_eq!(sent.unwrap(), contents.len() as u64);
+        assert_eq!(received.unwrap(), contents.len() as u64);
+        assert_eq!(std::fs::read(&destination).unwrap(), contents);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(&destination).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+
+        let reported = reported.lock().unwrap();
+        assert!(reported.is_sorted_by_key(|progress| progress.transferred));
+        assert_eq!(
+            reported.last(),
+            Some(&Progress {
+                transferred: contents.len() as u64,
+                total: contents.len() as u64,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_too_large() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("logs.tar");
+        let destination = dir.path().join("received.tar");
+        std::fs::write(&source, vec![0; 4096]).unwrap();
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut sender = FramedConnection::new(client);
+        let mut receiver = FramedConnection::new(server);
+        let mut options = FileTransferOptions::new();
+        options.set_max_len(Some(1024));
+
+        let (sent, received) = tokio::join!(
+            send_file(&mut sender, &source, &options),
+            receive_file(&mut receiver, &destination, &options),
+        );
+        assert!(matches!(sent, Err(Error::BulkRejected)));
+        assert!(matches!(
+            received,
+            Err(Error::BulkTooLarge {
+                len: 4096,
+                max: 1024
+            })
+        ));
+        assert!(!destination.exists());
+    }
+
+    #[tokio::test]
+    async fn test_digest_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("received.zip");
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut sender = FramedConnection::new(client);
+        let mut receiver = FramedConnection::new(server);
+
+        let send = async {
+            let contents = [7u8; 1024];
+            sender
+                .write_frame(&Frame::data(1024u64.to_be_bytes().to_vec()))
+                .await?;
+            assert_eq!(read_payload(&mut sender, 1).await?[0], ACCEPTED);
+            chunked::send(&mut sender, &contents[..], &TransferOptions::default()).await?;
+            sender
+                .write_frame(&Frame::data(vec![0; DIGEST_LEN]))
+                .await?;
+            read_payload(&mut sender, 1).await
+        };
+        let (verdict, received) = tokio::join!(
+            send,
+            receive_file(&mut receiver, &destination, &FileTransferOptions::new()),
+        );
+        assert_eq!(verdict.unwrap()[0], REFUSED);
+        assert!(matches!(received, Err(Error::IntegrityCheckFailed)));
+        assert!(!destination.exists());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_while_receiving() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("received.zip");
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut sender = FramedConnection::new(client);
+        let mut receiver = FramedConnection::new(server);
+
+        sender
+            .write_frame(&Frame::data(1024u64.to_be_bytes().to_vec()))
+            .await
+            .unwrap();
+        let options = FileTransferOptions::new();
+        let mut receive = Box::pin(receive_file(&mut receiver, &destination, &options));
+        tokio::select! {
+            _ = &mut receive => unreachable!("Nothing was sent"),
+            verdict = read_payload(&mut sender, 1) => assert_eq!(verdict.unwrap()[0], ACCEPTED),
+        }
+        assert!(destination.exists());
+        drop(receive);
+        assert!(!destination.exists());
+    }
+}
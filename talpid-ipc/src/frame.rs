@@ -0,0 +1,2234 @@
+//! Length-delimited framing on top of a byte stream.
+//!
+//! Every frame starts with a fixed-size header: the payload length as a big-endian `u32`, one
+//! byte identifying the [`FrameKind`], and one byte of flags describing how the payload is
+//! encoded. The payload follows immediately after the header.
+//!
+//! Payloads of 4 GiB or more do not fit in the length of the header. If both ends support
+//! [`Capabilities::LONG_FRAMES`], such frames have [`FLAG_LONG`] set, and the header is extended
+//! by the upper 32 bits of the length, to [`LONG_HEADER_LEN`] bytes. Other frames keep the short
+//! header.
+//!
+//! Like any other frame, a long frame is sent and received as a whole, so both ends hold the
+//! whole payload in memory. The receiver only accepts one once the limit of
+//! [`FramedConnection::set_max_payload_len`] has been raised past 4 GiB, and only if its memory
+//! limit leaves room for it. Transfers that should not be held in memory, such as large files,
+//! belong in [`crate::chunked`] instead.
+//!
+//! If both ends support [`Capabilities::CHECKSUM`], every frame has [`FLAG_CHECKSUM`] set, and
+//! is followed by a CRC-32C of its header and payload, as a big-endian `u32`. Frames that were
+//! corrupted in transit, e.g. by filter drivers that interpose on pipes, then fail with
+//! [`Error::FrameCorrupted`] instead of being passed on, and the connection is closed.
+//!
+//! By default, the parser accepts any well-formed frame. In strict mode, enabled with
+//! [`FramedConnection::set_strict`], it also rejects frames that no correct peer sends, and
+//! reports every malformed frame as an [`Error::Malformed`] describing what was wrong with it.
+//! Once a malformed frame has been received, the connection is closed.
+//!
+//! Either way, frames whose payload exceeds a limit are rejected before any memory is allocated
+//! for them. See [`FramedConnection::set_max_payload_len`].
+//!
+//! Control frames, such as pings and goodbyes, are queued separately from other frames, and are
+//! written ahead of any queued data. Health checks therefore stay accurate while large responses
+//! are being sent. See [`FrameKind::is_priority`].
+//!
+//! Producers that must not wait for the peer, such as event emitters, can queue frames with
+//! [`FramedConnection::try_feed_frame`]. It fails once the queue reaches a high-water mark, and
+//! [`FramedConnection::poll_write_ready`] signals when there is room again.
+//!
+//! How much memory a single peer can make a connection use is bounded by
+//! [`FramedConnection::set_memory_limit`], which counts both the frame that is being received
+//! and the frames that are queued to be sent, and closes the connection once it is exceeded.
+//! How much all connections use together is bounded by a [`crate::budget::MemoryBudget`].
+
+#[cfg(feature = "server")]
+use crate::server::ConnectionControl;
+use crate::{
+    Error,
+    budget::{BudgetShare, MemoryBudget, Reservation},
+    checksum,
+    events::SlowConsumerPolicy,
+    handshake::Capabilities,
+    pool::{PooledBuffer, READ_BUFFERS, WRITE_BUFFERS},
+    stats::ConnectionCounters,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+/// Size of the frame header in bytes.
+pub const HEADER_LEN: usize = 6;
+
+/// Queued frames are written without waiting for a flush once they exceed this many bytes.
+pub const COALESCE_LIMIT: usize = 64 * 1024;
+
+/// Size of the frame header in bytes if [`FLAG_LONG`] is set.
+pub const LONG_HEADER_LEN: usize = HEADER_LEN + 4;
+
+/// Header flag indicating that the payload is compressed.
+pub(crate) const FLAG_COMPRESSED: u8 = 1 << 0;
+
+/// Header flag indicating that the header is followed by the upper 32 bits of the payload
+/// length, as a big-endian `u32`.
+pub const FLAG_LONG: u8 = 1 << 1;
+
+/// Header flag indicating that the payload is followed by a checksum of the frame.
+pub const FLAG_CHECKSUM: u8 = 1 << 2;
+
+/// Size of the checksum that follows the payload if [`FLAG_CHECKSUM`] is set.
+pub const CHECKSUM_LEN: usize = 4;
+
+/// Largest payload that is accepted unless configured otherwise.
+pub const DEFAULT_MAX_PAYLOAD_LEN: usize = 8 * 1024 * 1024;
+
+/// Number of queued bytes above which producers are held back, unless configured otherwise.
+pub const DEFAULT_WRITE_HIGH_WATER_MARK: usize = 1024 * 1024;
+
+/// How long a connection that exceeded its memory limit or budget waits for the peer to take
+/// the goodbye that tells it so.
+const MEMORY_GOODBYE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Identifies what a frame contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    /// Application data.
+    Data = 0,
+    /// Control message of a bulk transfer. See [`crate::shm`].
+    Bulk = 1,
+    /// Health check. It is answered by [`FramedConnection::read_frame`] with a pong carrying
+    /// the same payload. See [`crate::health`].
+    Ping = 2,
+    /// Answer to a ping.
+    Pong = 3,
+    /// The peer is about to close the connection, and will not send any new requests. See
+    /// [`crate::shutdown`].
+    Goodbye = 4,
+    /// The server refused to serve the connection, and is about to close it. The payload is a
+    /// single [`RejectReason`] byte.
+    Reject = 5,
+    /// Part of a chunked transfer. See [`crate::chunked`].
+    Chunk = 6,
+}
+
+impl FrameKind {
+    /// Whether frames of this kind are written ahead of queued data.
+    pub fn is_priority(self) -> bool {
+        match self {
+            FrameKind::Ping | FrameKind::Pong | FrameKind::Goodbye | FrameKind::Reject => true,
+            FrameKind::Data | FrameKind::Bulk | FrameKind::Chunk => false,
+        }
+    }
+
+    /// Whether a frame of this kind may have a payload of `len` bytes. Control frames are small,
+    /// and their payload has a fixed layout.
+    fn allows_payload_len(self, len: usize) -> bool {
+        match self {
+            // The reason is optional, since older peers do not send one
+            FrameKind::Goodbye => len <= 1,
+            FrameKind::Reject => len == 1,
+            FrameKind::Data
+            | FrameKind::Bulk
+            | FrameKind::Chunk
+            | FrameKind::Ping
+            | FrameKind::Pong => true,
+        }
+    }
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = Error;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            0 => Ok(FrameKind::Data),
+            1 => Ok(FrameKind::Bulk),
+            2 => Ok(FrameKind::Ping),
+            3 => Ok(FrameKind::Pong),
+            4 => Ok(FrameKind::Goodbye),
+            5 => Ok(FrameKind::Reject),
+            6 => Ok(FrameKind::Chunk),
+            other => Err(Error::UnknownFrameKind(other)),
+        }
+    }
+}
+
+/// Why a server rejected a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The user of the peer already has as many connections as it is allowed.
+    QuotaExceeded,
+    /// A reason that is unknown to this end.
+    Other(u8),
+}
+
+impl From<u8> for RejectReason {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => RejectReason::QuotaExceeded,
+            other => RejectReason::Other(other),
+        }
+    }
+}
+
+impl From<RejectReason> for u8 {
+    fn from(reason: RejectReason) -> Self {
+        match reason {
+            RejectReason::QuotaExceeded => 1,
+            RejectReason::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::QuotaExceeded => f.write_str("too many connections"),
+            RejectReason::Other(code) => write!(f, "reason {code}"),
+        }
+    }
+}
+
+/// Why a peer closed the connection, as given in its goodbye frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GoodbyeReason {
+    /// The peer did not give a reason, e.g. because it is a client that is done.
+    Unspecified,
+    /// The server is shutting down.
+    ShuttingDown,
+    /// The server disconnected this client, e.g. because it misbehaved.
+    Evicted,
+    /// The peer buffered more for the connection than its memory limit or budget allows, e.g.
+    /// because this end sent too much at once or stopped reading.
+    MemoryExceeded,
+    /// A reason that is unknown to this end.
+    Other(u8),
+}
+
+impl From<u8> for GoodbyeReason {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => GoodbyeReason::Unspecified,
+            1 => GoodbyeReason::ShuttingDown,
+            2 => GoodbyeReason::Evicted,
+            3 => GoodbyeReason::MemoryExceeded,
+            other => GoodbyeReason::Other(other),
+        }
+    }
+}
+
+impl From<GoodbyeReason> for u8 {
+    fn from(reason: GoodbyeReason) -> Self {
+        match reason {
+            GoodbyeReason::Unspecified => 0,
+            GoodbyeReason::ShuttingDown => 1,
+            GoodbyeReason::Evicted => 2,
+            GoodbyeReason::MemoryExceeded => 3,
+            GoodbyeReason::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for GoodbyeReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GoodbyeReason::Unspecified => f.write_str("no reason given"),
+            GoodbyeReason::ShuttingDown => f.write_str("the server is shutting down"),
+            GoodbyeReason::Evicted => f.write_str("disconnected by the server"),
+            GoodbyeReason::MemoryExceeded => f.write_str("too much memory was used"),
+            GoodbyeReason::Other(code) => write!(f, "reason {code}"),
+        }
+    }
+}
+
+/// What was wrong with a frame that was rejected in strict mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MalformedFrame {
+    /// The length prefix exceeds the limit.
+    TooLong { len: usize, max: usize },
+    /// The connection was closed after `received` of the `expected` bytes of a frame.
+    Truncated { expected: usize, received: usize },
+    /// The frame kind is unknown.
+    UnknownKind(u8),
+    /// Flags that are unknown are set.
+    UnknownFlags(u8),
+    /// A control frame has a payload of the wrong length.
+    InvalidPayloadLength { kind: FrameKind, len: usize },
+}
+
+impl fmt::Display for MalformedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MalformedFrame::TooLong { len, max } => {
+                write!(f, "payload of {len} bytes exceeds the limit of {max} bytes")
+            }
+            MalformedFrame::Truncated { expected, received } => {
+                write!(f, "truncated after {received} of {expected} bytes")
+            }
+            MalformedFrame::UnknownKind(kind) => write!(f, "unknown kind {kind}"),
+            MalformedFrame::UnknownFlags(flags) => write!(f, "unknown flags {flags:#04x}"),
+            MalformedFrame::InvalidPayloadLength { kind, len } => {
+                write!(f, "{kind:?} frame with a payload of {len} bytes")
+            }
+        }
+    }
+}
+
+/// Whether a frame was sent or received by this end, e.g. in a capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// How long frames queued by [`FramedConnection::feed_frame`] wait to be flushed by default.
+pub const DEFAULT_MAX_FLUSH_DELAY: Duration = Duration::from_millis(5);
+
+/// When [`FramedConnection::feed_frame`] flushes the frames that it has queued.
+/// [`FramedConnection::write_frame`] always flushes, since its callers wait for an answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushMode {
+    /// Flush every frame as it is fed. This has the lowest latency, e.g. for a CLI that
+    /// follows a few events.
+    AutoFlush,
+    /// Write queued frames once they exceed [`COALESCE_LIMIT`] bytes, and flush them at the
+    /// latest `max_delay` after the first of them was queued. The deadline is kept by the next
+    /// [`FramedConnection::feed_frame`], and while waiting for a frame from the peer. This has
+    /// the best throughput for connections that carry many frames, e.g. the event stream of the
+    /// GUI.
+    Batched { max_delay: Duration },
+}
+
+impl Default for FlushMode {
+    fn default() -> Self {
+        FlushMode::Batched {
+            max_delay: DEFAULT_MAX_FLUSH_DELAY,
+        }
+    }
+}
+
+/// A single frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub payload: Bytes,
+}
+
+impl Frame {
+    /// Create a frame of the given kind.
+    pub fn new(kind: FrameKind, payload: impl Into<Bytes>) -> Self {
+        Frame {
+            kind,
+            payload: payload.into(),
+        }
+    }
+
+    /// Create a frame containing application data.
+    pub fn data(payload: impl Into<Bytes>) -> Self {
+        Self::new(FrameKind::Data, payload)
+    }
+
+    /// Create a frame telling the peer why its connection is rejected.
+    pub fn reject(reason: RejectReason) -> Self {
+        Self::new(FrameKind::Reject, vec![u8::from(reason)])
+    }
+
+    pub(crate) fn reject_reason(&self) -> RejectReason {
+        RejectReason::from(self.payload.first().copied().unwrap_or(0))
+    }
+
+    /// Create a goodbye frame that tells the peer why the connection is being closed.
+    pub fn goodbye(reason: GoodbyeReason) -> Self {
+        match reason {
+            GoodbyeReason::Unspecified => Self::new(FrameKind::Goodbye, Bytes::new()),
+            reason => Self::new(FrameKind::Goodbye, vec![u8::from(reason)]),
+        }
+    }
+
+    /// Reason given by a goodbye frame.
+    pub fn goodbye_reason(&self) -> GoodbyeReason {
+        GoodbyeReason::from(self.payload.first().copied().unwrap_or(0))
+    }
+}
+
+/// Append an encoded frame to `dst`.
+pub fn encode(frame: &Frame, dst: &mut BytesMut) -> Result<(), Error> {
+    encode_with_flags(frame.kind, 0, &frame.payload, dst)
+}
+
+pub(crate) fn encode_with_flags(
+    kind: FrameKind,
+    flags: u8,
+    payload: &[u8],
+    dst: &mut BytesMut,
+) -> Result<(), Error> {
+    let len = payload.len() as u64;
+    if flags & FLAG_LONG == 0 && len > u64::from(u32::MAX) {
+        return Err(Error::FrameTooLarge(payload.len()));
+    }
+    dst.reserve(overhead(flags) + payload.len());
+    let start = dst.len();
+    dst.put_u32(len as u32);
+    dst.put_u8(kind as u8);
+    dst.put_u8(flags);
+    if flags & FLAG_LONG != 0 {
+        dst.put_u32((len >> 32) as u32);
+    }
+    dst.extend_from_slice(payload);
+    if flags & FLAG_CHECKSUM != 0 {
+        let checksum = checksum::crc32c(&dst[start..]);
+        dst.put_u32(checksum);
+    }
+    Ok(())
+}
+
+/// Like [`encode_with_flags`], but with the long header if the payload needs it, and with a
+/// checksum, as far as both ends support them.
+pub(crate) fn encode_negotiated(
+    capabilities: Capabilities,
+    kind: FrameKind,
+    flags: u8,
+    payload: &[u8],
+    dst: &mut BytesMut,
+) -> Result<(), Error> {
+    let flags = flags | negotiated_flags(capabilities, payload.len());
+    encode_with_flags(kind, flags, payload, dst)
+}
+
+/// The wire format of frames once the capabilities of both ends are known. [`FramedConnection`]
+/// and [`crate::sansio::Session`] both encode and decode frames through it, so that they cannot
+/// disagree about what a frame looks like.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FrameCodec {
+    pub(crate) capabilities: Capabilities,
+    pub(crate) max_payload_len: usize,
+    pub(crate) strict: bool,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        FrameCodec {
+            capabilities: Capabilities::empty(),
+            max_payload_len: DEFAULT_MAX_PAYLOAD_LEN,
+            strict: false,
+        }
+    }
+}
+
+impl FrameCodec {
+    /// Decode the frame at the start of `src` if it is complete, and decompress its payload.
+    pub(crate) fn decode(&self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        let Some((mut frame, compressed)) = self.decode_compressed(src)? else {
+            return Ok(None);
+        };
+        if compressed {
+            frame.payload = self.decompress(&frame.payload, self.max_payload_len)?;
+        }
+        Ok(Some(frame))
+    }
+
+    /// Like [`Self::decode`], but leave the payload compressed, and return whether it is. Its
+    /// flags are checked against the capabilities either way.
+    pub(crate) fn decode_compressed(
+        &self,
+        src: &mut BytesMut,
+    ) -> Result<Option<(Frame, bool)>, Error> {
+        let Some((frame, flags)) = decode_with_flags(src, self.max_payload_len, self.strict)?
+        else {
+            return Ok(None);
+        };
+        check_negotiated_flags(self.capabilities, flags)?;
+        Ok(Some((frame, flags & FLAG_COMPRESSED != 0)))
+    }
+
+    /// Decompress a payload returned by [`Self::decode_compressed`] to at most `max_len` bytes.
+    pub(crate) fn decompress(&self, payload: &[u8], max_len: usize) -> Result<Bytes, Error> {
+        decompress_payload(self.capabilities, payload, max_len)
+    }
+
+    /// Compress `payload` if both ends support it and it is worth it.
+    pub(crate) fn compress(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        compress_payload(self.capabilities, payload)
+    }
+
+    /// Number of bytes that a frame with a payload of `payload_len` bytes takes once encoded.
+    pub(crate) fn encoded_len(&self, payload_len: usize) -> usize {
+        overhead(negotiated_flags(self.capabilities, payload_len)) + payload_len
+    }
+
+    /// Encode a frame of `kind` with `payload` into `dst`, or with the `compressed` payload
+    /// returned by [`Self::compress`] instead, if there is one.
+    pub(crate) fn encode(
+        &self,
+        kind: FrameKind,
+        payload: &[u8],
+        compressed: Option<&[u8]>,
+        dst: &mut BytesMut,
+    ) -> Result<(), Error> {
+        match compressed {
+            Some(compressed) => {
+                encode_negotiated(self.capabilities, kind, FLAG_COMPRESSED, compressed, dst)
+            }
+            None => encode_negotiated(self.capabilities, kind, 0, payload, dst),
+        }
+    }
+}
+
+/// What the framing does with a frame that it has decoded, before the application sees it.
+#[derive(Debug)]
+pub(crate) enum Received {
+    /// Hand the frame to the application.
+    Deliver(Frame),
+    /// Answer a ping by sending this pong.
+    Answer(Frame),
+    /// Drop a pong.
+    Discard,
+}
+
+impl Received {
+    /// Decide what to do with `frame`. Fails with [`Error::Rejected`] if the peer rejected the
+    /// connection.
+    pub(crate) fn of(frame: Frame) -> Result<Self, Error> {
+        Ok(match frame.kind {
+            FrameKind::Reject => return Err(Error::Rejected(frame.reject_reason())),
+            FrameKind::Ping => Received::Answer(Frame::new(FrameKind::Pong, frame.payload)),
+            FrameKind::Pong => Received::Discard,
+            _ => Received::Deliver(frame),
+        })
+    }
+}
+
+/// Flags that a frame with a payload of `len` bytes is sent with, given the `capabilities` of
+/// both ends.
+fn negotiated_flags(capabilities: Capabilities, len: usize) -> u8 {
+    let mut flags = 0;
+    if len as u64 > u64::from(u32::MAX) && capabilities.contains(Capabilities::LONG_FRAMES) {
+        flags |= FLAG_LONG;
+    }
+    if capabilities.contains(Capabilities::CHECKSUM) {
+        flags |= FLAG_CHECKSUM;
+    }
+    flags
+}
+
+/// Size of a header with `flags`.
+const fn header_len(flags: u8) -> usize {
+    if flags & FLAG_LONG != 0 {
+        LONG_HEADER_LEN
+    } else {
+        HEADER_LEN
+    }
+}
+
+/// Number of bytes that a frame with `flags` takes besides its payload.
+const fn overhead(flags: u8) -> usize {
+    if flags & FLAG_CHECKSUM != 0 {
+        header_len(flags) + CHECKSUM_LEN
+    } else {
+        header_len(flags)
+    }
+}
+
+/// Fail unless both ends support the long header and checksums, if `flags` say that a frame
+/// has them. Once checksums have been negotiated, frames without one are treated as corrupted.
+pub(crate) fn check_negotiated_flags(capabilities: Capabilities, flags: u8) -> Result<(), Error> {
+    if flags & FLAG_LONG != 0 && !capabilities.contains(Capabilities::LONG_FRAMES) {
+        return Err(Error::Protocol(
+            "Received long frame without negotiating it",
+        ));
+    }
+    if flags & FLAG_CHECKSUM != 0 && !capabilities.contains(Capabilities::CHECKSUM) {
+        return Err(Error::Protocol("Received checksum without negotiating it"));
+    }
+    // Otherwise, flipping the flag in transit would turn off the check
+    if flags & FLAG_CHECKSUM == 0 && capabilities.contains(Capabilities::CHECKSUM) {
+        return Err(Error::FrameCorrupted);
+    }
+    Ok(())
+}
+
+/// Length of the frame at the start of `buf` as announced by its header, or `None` if not
+/// enough of the header has been received.
+fn announced_len(buf: &[u8]) -> Option<usize> {
+    let mut len = u64::from(u32::from_be_bytes(buf.get(..4)?.try_into().unwrap()));
+    let flags = buf.get(5).copied().unwrap_or(0);
+    if flags & FLAG_LONG != 0 {
+        let upper = buf.get(HEADER_LEN..LONG_HEADER_LEN)?;
+        len |= u64::from(u32::from_be_bytes(upper.try_into().unwrap())) << 32;
+    }
+    let len = usize::try_from(len).unwrap_or(usize::MAX);
+    Some(len.saturating_add(overhead(flags)))
+}
+
+/// Decode a frame from the beginning of `src`, consuming its bytes. Returns `None` if `src` does
+/// not yet contain a complete frame. Fails if the frame has any flags set, or if its payload is
+/// larger than [`DEFAULT_MAX_PAYLOAD_LEN`].
+pub fn decode(src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+    match decode_with_flags(src, DEFAULT_MAX_PAYLOAD_LEN, false)? {
+        Some((_frame, flags)) if flags != 0 => Err(Error::Protocol("Unexpected frame flags")),
+        decoded => Ok(decoded.map(|(frame, _flags)| frame)),
+    }
+}
+
+/// Like [`decode`], but in strict mode. Malformed frames are reported as
+/// [`Error::Malformed`].
+pub fn decode_strict(src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+    match decode_with_flags(src, DEFAULT_MAX_PAYLOAD_LEN, true)? {
+        Some((_frame, flags)) if flags != 0 => Err(MalformedFrame::UnknownFlags(flags).into()),
+        decoded => Ok(decoded.map(|(frame, _flags)| frame)),
+    }
+}
+
+/// Decode a frame along with its flags, rejecting payloads longer than `max_len`.
+pub(crate) fn decode_with_flags(
+    src: &mut BytesMut,
+    max_len: usize,
+    strict: bool,
+) -> Result<Option<(Frame, u8)>, Error> {
+    if src.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    let kind = match FrameKind::try_from(src[4]) {
+        Err(Error::UnknownFrameKind(kind)) if strict => {
+            return Err(MalformedFrame::UnknownKind(kind).into());
+        }
+        kind => kind?,
+    };
+    let flags = src[5];
+    if flags & !(FLAG_COMPRESSED | FLAG_LONG | FLAG_CHECKSUM) != 0 {
+        if strict {
+            return Err(MalformedFrame::UnknownFlags(flags).into());
+        }
+        return Err(Error::Protocol("Unknown frame flags"));
+    }
+    let Some(frame_len) = announced_len(src) else {
+        return Ok(None);
+    };
+    let len = frame_len - overhead(flags);
+    if len > max_len {
+        if strict {
+            return Err(MalformedFrame::TooLong { len, max: max_len }.into());
+        }
+        return Err(Error::FrameExceedsLimit { len, max: max_len });
+    }
+    if strict && !kind.allows_payload_len(len) {
+        return Err(MalformedFrame::InvalidPayloadLength { kind, len }.into());
+    }
+
+    if src.len() < frame_len {
+        src.reserve(frame_len - src.len());
+        return Ok(None);
+    }
+
+    if flags & FLAG_CHECKSUM != 0 {
+        let (checked, checksum) = src[..frame_len].split_at(frame_len - CHECKSUM_LEN);
+        if checksum::crc32c(checked) != u32::from_be_bytes(checksum.try_into().unwrap()) {
+            return Err(Error::FrameCorrupted);
+        }
+    }
+
+    src.advance(header_len(flags));
+    let payload = src.split_to(len).freeze();
+    src.advance(frame_len - header_len(flags) - len);
+    Ok(Some((Frame { kind, payload }, flags)))
+}
+
+/// Reads and writes [`Frame`]s over an underlying byte stream.
+pub struct FramedConnection<T> {
+    io: T,
+    read_buf: PooledBuffer,
+    write_buf: PooledBuffer,
+    /// Frames that are written before those in `write_buf`.
+    priority_buf: BytesMut,
+    /// Set while a frame in `write_buf` has been partially written, e.g. because the future
+    /// writing it was dropped. Priority frames then have to wait until it has been written.
+    write_buf_partial: bool,
+    flush_mode: FlushMode,
+    /// When the frames queued by `feed_frame` have to be flushed.
+    flush_deadline: Option<Instant>,
+    high_water_mark: usize,
+    capabilities: Capabilities,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    /// Set once a deadline has passed, since the framing may be out of sync from then on.
+    deadline_expired: bool,
+    max_payload_len: usize,
+    strict: bool,
+    /// Set once a malformed frame has been received in strict mode.
+    malformed: Option<MalformedFrame>,
+    /// Set once a frame with the wrong checksum has been received.
+    corrupted: bool,
+    memory_limit: Option<usize>,
+    budget: Option<BudgetShare>,
+    /// Set once the memory limit or the memory budget has been exceeded.
+    memory_exceeded: Option<MemoryExceeded>,
+    counters: Option<Arc<ConnectionCounters>>,
+    #[cfg(feature = "server")]
+    control: Option<ConnectionControl>,
+    /// Set once the server has disconnected the connection, and it has said goodbye.
+    evicted: bool,
+    /// Set once this end has said goodbye to the peer.
+    goodbye_sent: bool,
+    #[cfg(feature = "capture")]
+    capture: Option<std::sync::Arc<crate::capture::TrafficCapture>>,
+    /// The recorder, and the number of the connection in the recording.
+    #[cfg(feature = "replay")]
+    recorder: Option<(Arc<crate::replay::SessionRecorder>, u64)>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> FramedConnection<T> {
+    /// Wrap a byte stream.
+    pub fn new(io: T) -> Self {
+        FramedConnection {
+            io,
+            read_buf: READ_BUFFERS.take(),
+            write_buf: WRITE_BUFFERS.take(),
+            priority_buf: BytesMut::new(),
+            write_buf_partial: false,
+            flush_mode: FlushMode::default(),
+            flush_deadline: None,
+            high_water_mark: DEFAULT_WRITE_HIGH_WATER_MARK,
+            capabilities: Capabilities::empty(),
+            read_timeout: None,
+            write_timeout: None,
+            deadline_expired: false,
+            max_payload_len: DEFAULT_MAX_PAYLOAD_LEN,
+            strict: false,
+            malformed: None,
+            corrupted: false,
+            memory_limit: None,
+            budget: None,
+            memory_exceeded: None,
+            counters: None,
+            #[cfg(feature = "server")]
+            control: None,
+            evicted: false,
+            goodbye_sent: false,
+            #[cfg(feature = "capture")]
+            capture: None,
+            #[cfg(feature = "replay")]
+            recorder: None,
+        }
+    }
+
+    /// Fail with [`Error::Deadline`] if the rest of a frame does not arrive within `timeout`
+    /// after its first byte. Waiting for a frame to start is not limited, so idle peers are not
+    /// disconnected.
+    pub fn set_read_frame_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Fail with [`Error::Deadline`] if writing and flushing frames takes longer than `timeout`,
+    /// such as when the peer has stopped reading.
+    pub fn set_write_frame_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+
+    /// Fail with [`Error::FrameExceedsLimit`] when a frame whose payload is larger than
+    /// `max_len` bytes is received. The limit also applies to decompressed payloads. The default
+    /// is [`DEFAULT_MAX_PAYLOAD_LEN`].
+    ///
+    /// Since the rest of such a frame is never read, the framing is out of sync afterwards, and
+    /// the connection should be closed.
+    pub fn set_max_payload_len(&mut self, max_len: usize) {
+        self.max_payload_len = max_len;
+    }
+
+    /// Reject malformed frames with [`Error::Malformed`], and close the connection when one is
+    /// received. Payloads that exceed the limit set by [`Self::set_max_payload_len`] are then
+    /// reported as [`MalformedFrame::TooLong`]. This also rejects control frames whose payload
+    /// has the wrong length. Use this for connections from peers that are
+    /// not trusted.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Limit the number of bytes that may be buffered for the connection to `limit`, counting
+    /// the whole of the frame that is being received, as announced by its header, and the
+    /// frames that are queued to be sent, as well as decompressed payloads. Once the limit is
+    /// exceeded, the buffers are freed, the peer is told so with
+    /// [`GoodbyeReason::MemoryExceeded`], the connection is closed, and every operation fails with
+    /// [`Error::MemoryLimitExceeded`]. A frame that would exceed the limit is refused before any
+    /// memory is allocated for it, and a payload is never decompressed beyond the limit.
+    ///
+    /// The limit should leave room for the largest frame in each direction. By default, there is
+    /// no limit besides [`Self::set_max_payload_len`] and the high-water mark of the queue.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+    }
+
+    /// Number of bytes buffered for the connection, as counted against the memory limit. See
+    /// [`Self::set_memory_limit`].
+    pub fn buffered_len(&self) -> usize {
+        let receiving = match announced_len(&self.read_buf) {
+            Some(announced) => announced.max(self.read_buf.len()),
+            None => self.read_buf.len(),
+        };
+        receiving + self.queued_len()
+    }
+
+    /// Count the bytes buffered for the connection against `budget`, which is shared with other
+    /// connections. If the connections together would exceed it, the policy of the budget
+    /// decides what happens. By default, those that buffer the most are closed, and fail with
+    /// [`Error::MemoryBudgetExceeded`]. See [`crate::budget`].
+    pub fn set_memory_budget(&mut self, budget: &Arc<MemoryBudget>) {
+        let share = budget.join();
+        #[cfg(feature = "server")]
+        if let Some(control) = &self.control {
+            share.link(control.eviction().clone());
+        }
+        let reservation = share.reserve(self.buffered_len());
+        self.budget = Some(share);
+        match reservation {
+            Reservation::Granted => (),
+            // What is buffered already is counted, but nothing more fits until there is room
+            Reservation::Refused => self.count_buffered(),
+            Reservation::Evicted => {
+                let limit = budget.limit();
+                self.exceed_memory(MemoryExceeded::Budget { limit });
+            }
+        }
+    }
+
+    /// Fail if the memory limit or budget has been exceeded earlier, or would be by buffering
+    /// `additional` more bytes.
+    fn check_memory_limit(&mut self, additional: usize) -> Result<(), Error> {
+        if let Some(exceeded) = self.memory_exceeded {
+            return Err(exceeded.into());
+        }
+        let buffered = self.buffered_len().saturating_add(additional);
+        if let Some(limit) = self.memory_limit
+            && buffered > limit
+        {
+            return Err(self.exceed_memory(MemoryExceeded::Limit { buffered, limit }));
+        }
+        let Some(budget) = &self.budget else {
+            return Ok(());
+        };
+        let limit = budget.limit();
+        let reservation = if budget.is_evicted() {
+            Reservation::Evicted
+        } else {
+            budget.reserve(buffered)
+        };
+        match reservation {
+            Reservation::Granted => Ok(()),
+            Reservation::Evicted => Err(self.exceed_memory(MemoryExceeded::Budget { limit })),
+            // Reading waits for room in `fill_read_buf`
+            Reservation::Refused if additional == 0 => {
+                self.count_buffered();
+                Ok(())
+            }
+            Reservation::Refused => self.make_room(additional, limit),
+        }
+    }
+
+    /// Count what is actually buffered against the memory budget, rather than the whole of the
+    /// frame that is being received, whether or not there is room.
+    fn count_buffered(&self) {
+        if let Some(budget) = &self.budget {
+            budget.release(self.read_buf.len() + self.queued_len());
+        }
+    }
+
+    /// Find room for `additional` bytes in a memory budget that has none left for this
+    /// connection: under [`SlowConsumerPolicy::DropOldest`], by dropping the frames that are
+    /// queued and have not started to be written.
+    fn make_room(&mut self, additional: usize, limit: usize) -> Result<(), Error> {
+        let Some(budget) = &self.budget else {
+            return Ok(());
+        };
+        if budget.policy() == SlowConsumerPolicy::DropOldest
+            && !self.write_buf_partial
+            && !self.write_buf.is_empty()
+        {
+            log::debug!(
+                "Dropping {} queued bytes to stay within the memory budget",
+                self.write_buf.len()
+            );
+            self.write_buf.clear();
+            if budget.reserve(self.buffered_len() + additional) == Reservation::Granted {
+                return Ok(());
+            }
+        }
+        self.count_buffered();
+        Err(Error::MemoryBudgetFull { limit })
+    }
+
+    /// Tell the memory budget, if any, that the connection may buffer less than before.
+    fn release_memory(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(self.buffered_len());
+        }
+    }
+
+    /// Free the buffers, and fail every operation from now on.
+    fn exceed_memory(&mut self, exceeded: MemoryExceeded) -> Error {
+        log::debug!(
+            "Closing IPC connection with {} bytes buffered",
+            self.buffered_len()
+        );
+        self.memory_exceeded = Some(exceeded);
+        self.read_buf.clear();
+        *self.write_buf = BytesMut::new();
+        self.priority_buf = BytesMut::new();
+        if let Some(budget) = &self.budget {
+            budget.release(0);
+        }
+        exceeded.into()
+    }
+
+    /// Close the connection if `error` means that it has exceeded its memory limit or budget,
+    /// telling the peer why.
+    async fn close_if_over_limit(&mut self, error: Error) -> Error {
+        if matches!(
+            error,
+            Error::MemoryLimitExceeded { .. } | Error::MemoryBudgetExceeded { .. }
+        ) {
+            // The queued frames have been dropped, so the goodbye can only be sent if none of
+            // them was partially written. It is written past the limit, which it is too small to
+            // matter for.
+            let goodbye = Frame::goodbye(GoodbyeReason::MemoryExceeded);
+            let mut buf = BytesMut::new();
+            if !self.write_buf_partial
+                && self
+                    .codec()
+                    .encode(goodbye.kind, &goodbye.payload, None, &mut buf)
+                    .is_ok()
+            {
+                // The peer may have stopped reading
+                let _ = tokio::time::timeout(MEMORY_GOODBYE_TIMEOUT, self.io.write_all(&buf)).await;
+            }
+            let _ = self.io.shutdown().await;
+        }
+        error
+    }
+
+    /// Count the frames that are sent and received in `counters`, which are usually those of the
+    /// underlying [`crate::Connection`].
+    pub fn set_counters(&mut self, counters: Arc<ConnectionCounters>) {
+        self.counters = Some(counters);
+    }
+
+    /// Let the server that serves the connection reach it through the framing, see
+    /// [`crate::Connection::control`]. While waiting for a frame, the framing then sends the
+    /// keepalive pings of the reaper, and says goodbye to the peer and fails with
+    /// [`Error::Closed`] once the server disconnects it. Once the server starts shutting down,
+    /// it tells the peer with [`GoodbyeReason::ShuttingDown`] but keeps reading, so that the
+    /// requests of the peer are still answered until it closes its end. This also applies the
+    /// flush mode and the memory limit of the server, if any, see
+    /// [`crate::server::IpcServer::set_flush_mode`] and
+    /// [`crate::server::IpcServer::set_memory_limit`].
+    #[cfg(feature = "server")]
+    pub fn set_control(&mut self, control: ConnectionControl) {
+        control.attach();
+        if let Some(mode) = control.flush_mode() {
+            self.flush_mode = mode;
+        }
+        if let Some(limit) = control.memory_limit() {
+            self.memory_limit = Some(limit);
+        }
+        // Have the server drop the handler as well if the budget disconnects the connection
+        if let Some(budget) = &self.budget {
+            budget.link(control.eviction().clone());
+        }
+        self.control = Some(control);
+    }
+
+    /// Set when [`Self::feed_frame`] flushes. The default is [`FlushMode::Batched`] with
+    /// [`DEFAULT_MAX_FLUSH_DELAY`].
+    pub fn set_flush_mode(&mut self, mode: FlushMode) {
+        self.flush_mode = mode;
+    }
+
+    /// When the frames queued by [`Self::feed_frame`] have to be flushed, if any are queued.
+    /// Code that does not wait in [`Self::read_frame`] should flush them by then.
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        self.flush_deadline
+    }
+
+    /// Set how many bytes may be queued before [`Self::try_feed_frame`] fails and
+    /// [`Self::poll_write_ready`] returns `Poll::Pending`. The default is
+    /// [`DEFAULT_WRITE_HIGH_WATER_MARK`], and values below 1 are treated as 1.
+    pub fn set_write_high_water_mark(&mut self, high_water_mark: usize) {
+        self.high_water_mark = high_water_mark.max(1);
+    }
+
+    /// Read the next frame. Returns `None` if the peer closed the connection between two
+    /// frames.
+    ///
+    /// Pings are answered and pongs are discarded without returning them, so the connection
+    /// must be read from continuously for health checks by the peer to succeed.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, err)
+    )]
+    pub async fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
+        loop {
+            let Some(frame) = self.next_frame().await? else {
+                return Ok(None);
+            };
+            match Received::of(frame)? {
+                Received::Answer(pong) => self.write_frame(&pong).await?,
+                Received::Discard => (),
+                Received::Deliver(frame) => return Ok(Some(frame)),
+            }
+        }
+    }
+
+    /// Read the next frame of any kind. Fails with [`Error::Rejected`] if the server rejected
+    /// the connection.
+    pub(crate) async fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        if let Some(malformed) = self.malformed {
+            return Err(Error::Malformed(malformed));
+        }
+        if self.corrupted {
+            return Err(Error::FrameCorrupted);
+        }
+        if self.evicted {
+            return Err(Error::Closed);
+        }
+        match self.next_frame_inner().await {
+            Err(Error::Malformed(malformed)) => {
+                crate::log_limit::log(
+                    log::Level::Debug,
+                    || self.log_key("malformed"),
+                    format_args!("Closing IPC connection after malformed frame: {malformed}"),
+                );
+                self.malformed = Some(malformed);
+                // The peer is not told why, and may already be gone
+                let _ = self.io.shutdown().await;
+                Err(Error::Malformed(malformed))
+            }
+            Err(Error::FrameCorrupted) => {
+                crate::log_limit::log(
+                    log::Level::Warn,
+                    || self.log_key("corrupted"),
+                    format_args!("Closing IPC connection after frame with wrong checksum"),
+                );
+                // The length may have been corrupted as well, so the framing cannot be trusted
+                self.corrupted = true;
+                let _ = self.io.shutdown().await;
+                Err(Error::FrameCorrupted)
+            }
+            Err(error) => Err(self.close_if_over_limit(error).await),
+            result => result,
+        }
+    }
+
+    /// Key that messages about the peer are limited by. Unless the server has said who the
+    /// peer is, all peers share `fallback`.
+    fn log_key<'a>(&'a self, fallback: &'a str) -> &'a str {
+        #[cfg(feature = "server")]
+        if let Some(key) = self.control.as_ref().and_then(ConnectionControl::log_key) {
+            return key;
+        }
+        fallback
+    }
+
+    async fn next_frame_inner(&mut self) -> Result<Option<Frame>, Error> {
+        let mut deadline = None;
+        loop {
+            self.check_memory_limit(0)?;
+            if let Some((mut frame, compressed)) =
+                self.codec().decode_compressed(&mut self.read_buf)?
+            {
+                if compressed {
+                    frame.payload = self.decompress(&frame.payload)?;
+                    // Buffered as well until the frame has been returned
+                    self.check_memory_limit(frame.payload.len())?;
+                }
+                #[cfg(feature = "capture")]
+                if let Some(capture) = &self.capture {
+                    capture.record(Direction::Received, &frame);
+                }
+                #[cfg(feature = "replay")]
+                if let Some((recorder, connection)) = &self.recorder {
+                    recorder.record(*connection, Direction::Received, &frame);
+                }
+                if let Some(counters) = &self.counters {
+                    counters.record_frame_received();
+                }
+                #[cfg(feature = "server")]
+                if let Some(control) = &self.control {
+                    control.probe().mark_received();
+                }
+                self.release_memory();
+                if frame.kind == FrameKind::Reject {
+                    return Err(Error::Rejected(frame.reject_reason()));
+                }
+                return Ok(Some(frame));
+            }
+            if !self.read_buf.is_empty() && deadline.is_none() {
+                deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+            }
+            if self.fill_read_buf(deadline).await? == 0 {
+                if self.read_buf.is_empty() {
+                    return Ok(None);
+                }
+                if self.strict {
+                    return Err(self.truncated().into());
+                }
+                return Err(Error::UnexpectedEof);
+            }
+        }
+    }
+
+    /// Describe the partial frame in the read buffer.
+    fn truncated(&self) -> MalformedFrame {
+        truncated(&self.read_buf)
+    }
+
+    /// Read more bytes into the read buffer, returning how many were read.
+    async fn fill_read_buf(&mut self, deadline: Option<Instant>) -> Result<usize, Error> {
+        loop {
+            // Created first, so that memory that is freed meanwhile is noticed
+            let released = self.budget.as_ref().map(BudgetShare::released);
+            // Do not read further ahead than the memory limit and budget allow, but at least one
+            // byte, so that exceeding them is noticed. If the budget would rather refuse than
+            // disconnect anyone, wait for room instead.
+            let buffered = self.read_buf.len() + self.queued_len();
+            let limit_room = self
+                .memory_limit
+                .map_or(usize::MAX, |limit| limit.saturating_sub(buffered));
+            let budget_room = self.budget.as_ref().map_or(usize::MAX, |budget| {
+                budget.available().saturating_sub(buffered)
+            });
+            let wait_for_room = budget_room == 0
+                && limit_room > 0
+                && self
+                    .budget
+                    .as_ref()
+                    .is_some_and(|budget| budget.policy() != SlowConsumerPolicy::Disconnect);
+            let released = async move {
+                match released {
+                    Some(released) => released.await,
+                    None => std::future::pending().await,
+                }
+            };
+            let room = limit_room.min(budget_room).max(1);
+            let evicted = self.budget.as_ref().map(BudgetShare::evicted);
+            let read = self.io.read_buf(&mut (&mut *self.read_buf).limit(room));
+            let read = with_deadline(deadline, read, &mut self.deadline_expired, "reading");
+            let read = unless_evicted(evicted, read);
+            #[cfg(feature = "server")]
+            let requested = server_request(self.control.as_ref(), self.goodbye_sent);
+            #[cfg(not(feature = "server"))]
+            let requested = std::future::pending::<ServerRequest>();
+            let flush_due = flush_due(self.flush_deadline);
+            let read = tokio::select! {
+                biased;
+                request = requested => {
+                    #[cfg(feature = "server")]
+                    {
+                        self.handle_request(request).await?;
+                        continue;
+                    }
+                    #[cfg(not(feature = "server"))]
+                    match request {}
+                }
+                () = flush_due => {
+                    self.flush().await?;
+                    continue;
+                }
+                () = released, if wait_for_room => continue,
+                read = read, if !wait_for_room => read,
+            };
+            return match read {
+                Some(result) => Ok(result??),
+                None => self.check_memory_limit(0).map(|()| 0),
+            };
+        }
+    }
+
+    /// Do what the server asked for while this end was waiting for the peer. Both frames are
+    /// sent ahead of queued data, and only between frames.
+    #[cfg(feature = "server")]
+    async fn handle_request(&mut self, request: ServerRequest) -> Result<(), Error> {
+        match request {
+            ServerRequest::Ping => {
+                self.queue_frame(&Frame::new(FrameKind::Ping, Bytes::new()), true)?;
+                self.flush().await?;
+                if let Some(control) = &self.control {
+                    control.probe().mark_sent();
+                }
+                Ok(())
+            }
+            ServerRequest::Goodbye => {
+                // Disconnected by the memory budget rather than by the server itself
+                if let Some(limit) = self
+                    .budget
+                    .as_ref()
+                    .filter(|budget| budget.is_evicted())
+                    .map(BudgetShare::limit)
+                {
+                    let error = self.exceed_memory(MemoryExceeded::Budget { limit });
+                    return Err(self.close_if_over_limit(error).await);
+                }
+                self.evicted = true;
+                // The server drops the handler if this does not finish in time
+                let _ = self.send_goodbye_with_reason(GoodbyeReason::Evicted).await;
+                let _ = self.io.shutdown().await;
+                Err(Error::Closed)
+            }
+            ServerRequest::ShuttingDown => {
+                self.send_goodbye_with_reason(GoodbyeReason::ShuttingDown)
+                    .await
+            }
+        }
+    }
+
+    /// Write out the priority buffer and the write buffer, and flush the stream if `flush` is
+    /// set.
+    async fn write_out(&mut self, flush: bool) -> Result<(), Error> {
+        self.check_memory_limit(0)?;
+        let deadline = self.write_timeout.map(|timeout| Instant::now() + timeout);
+        let evicted = self.budget.as_ref().map(BudgetShare::evicted);
+        let io = &mut self.io;
+        let write_buf = &mut *self.write_buf;
+        let priority_buf = &mut self.priority_buf;
+        let write_buf_partial = &mut self.write_buf_partial;
+        let write = async move {
+            // Frames must not be interleaved, so priority frames can only go first when no frame
+            // has been partially written
+            if !*write_buf_partial {
+                io.write_all_buf(priority_buf).await?;
+            }
+            *write_buf_partial = !write_buf.is_empty();
+            io.write_all_buf(write_buf).await?;
+            *write_buf_partial = false;
+            io.write_all_buf(priority_buf).await?;
+            if flush {
+                io.flush().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        let write = with_deadline(deadline, write, &mut self.deadline_expired, "writing");
+        match unless_evicted(evicted, write).await {
+            Some(result) => result??,
+            None => return self.check_memory_limit(0),
+        }
+        if flush {
+            self.flush_deadline = None;
+        }
+        self.release_memory();
+        Ok(())
+    }
+
+    /// Write a frame and flush it, along with any frames queued by [`Self::feed_frame`], to the
+    /// underlying stream. The payload is compressed if [`Capabilities::DEFLATE`] has been
+    /// negotiated and the payload is large enough.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, err)
+    )]
+    pub async fn write_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        if let Err(error) = self.queue_frame(frame, frame.kind.is_priority()) {
+            return Err(self.close_if_over_limit(error).await);
+        }
+        self.flush().await
+    }
+
+    /// Like [`Self::write_frame`], but write the frame ahead of any queued data, as if it were a
+    /// control frame. This is for small, urgent frames, such as cancellations.
+    pub async fn write_priority_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        if let Err(error) = self.queue_frame(frame, true) {
+            return Err(self.close_if_over_limit(error).await);
+        }
+        self.flush().await
+    }
+
+    /// Queue a frame without flushing it, so that several small frames can be written with a
+    /// single system call. When queued frames are flushed depends on the [`FlushMode`], and they
+    /// are always flushed by [`Self::flush`] and [`Self::write_frame`].
+    pub async fn feed_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        if let Err(error) = self.queue_frame(frame, frame.kind.is_priority()) {
+            return Err(self.close_if_over_limit(error).await);
+        }
+        match self.flush_mode {
+            FlushMode::AutoFlush => self.flush().await,
+            FlushMode::Batched { max_delay } => {
+                let now = Instant::now();
+                let deadline = *self.flush_deadline.get_or_insert(now + max_delay);
+                if deadline <= now {
+                    self.flush().await
+                } else if self.queued_len() >= COALESCE_LIMIT {
+                    self.write_out(false).await
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Write all queued frames and flush the underlying stream.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        self.write_out(true).await
+    }
+
+    /// Number of bytes of queued frames that have not been written yet.
+    pub fn queued_len(&self) -> usize {
+        self.priority_buf.len() + self.write_buf.len()
+    }
+
+    /// Queue a frame without waiting, or fail with [`Error::WriteQueueFull`] if the queue has
+    /// reached the high-water mark set by [`Self::set_write_high_water_mark`]. The frame is
+    /// written by the next write or flush, or by [`Self::poll_write_ready`]. If the frame
+    /// exceeds the memory limit, the connection should be dropped, since it cannot be closed
+    /// without waiting.
+    pub fn try_feed_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        let queued = self.queued_len();
+        if queued >= self.high_water_mark {
+            return Err(Error::WriteQueueFull {
+                queued,
+                high_water_mark: self.high_water_mark,
+            });
+        }
+        self.queue_frame(frame, frame.kind.is_priority())?;
+        // Flushed while waiting for a frame, even if nothing else is fed
+        if let FlushMode::Batched { max_delay } = self.flush_mode {
+            self.flush_deadline
+                .get_or_insert_with(|| Instant::now() + max_delay);
+        }
+        Ok(())
+    }
+
+    /// Write queued frames until the queue is below the high-water mark. Returns
+    /// `Poll::Pending` while the peer is not reading fast enough, and `Poll::Ready` once
+    /// [`Self::try_feed_frame`] would accept another frame. The write timeout does not apply.
+    pub fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while self.queued_len() >= self.high_water_mark {
+            // Frames must not be interleaved, as in `write_out`
+            let data = self.write_buf_partial || self.priority_buf.is_empty();
+            let buf = if data {
+                &mut *self.write_buf
+            } else {
+                &mut self.priority_buf
+            };
+            let written = ready!(Pin::new(&mut self.io).poll_write(cx, buf))?;
+            if written == 0 {
+                return Poll::Ready(Err(Error::from(io::Error::from(io::ErrorKind::WriteZero))));
+            }
+            buf.advance(written);
+            if data {
+                self.write_buf_partial = !self.write_buf.is_empty();
+            }
+        }
+        self.release_memory();
+        Poll::Ready(Ok(()))
+    }
+
+    /// Wait until the queue is below the high-water mark. See [`Self::poll_write_ready`].
+    pub async fn write_ready(&mut self) -> Result<(), Error> {
+        std::future::poll_fn(|cx| self.poll_write_ready(cx)).await
+    }
+
+    fn queue_frame(&mut self, frame: &Frame, priority: bool) -> Result<(), Error> {
+        let codec = self.codec();
+        let compressed = codec.compress(&frame.payload);
+        let payload_len = compressed.as_ref().map_or(frame.payload.len(), Vec::len);
+        self.check_memory_limit(codec.encoded_len(payload_len))?;
+        #[cfg(feature = "capture")]
+        if let Some(capture) = &self.capture {
+            capture.record(Direction::Sent, frame);
+        }
+        #[cfg(feature = "replay")]
+        if let Some((recorder, connection)) = &self.recorder {
+            recorder.record(*connection, Direction::Sent, frame);
+        }
+        if let Some(counters) = &self.counters {
+            counters.record_frame_sent();
+        }
+        let dst = if priority {
+            &mut self.priority_buf
+        } else {
+            &mut *self.write_buf
+        };
+        codec.encode(frame.kind, &frame.payload, compressed.as_deref(), dst)
+    }
+
+    fn codec(&self) -> FrameCodec {
+        FrameCodec {
+            capabilities: self.capabilities,
+            max_payload_len: self.max_payload_len,
+            strict: self.strict,
+        }
+    }
+
+    /// Decompress a payload, but never beyond what the memory limit leaves room for.
+    fn decompress(&mut self, payload: &[u8]) -> Result<Bytes, Error> {
+        let codec = self.codec();
+        let Some(limit) = self.memory_limit else {
+            return codec.decompress(payload, self.max_payload_len);
+        };
+        let room = limit.saturating_sub(self.buffered_len());
+        if room >= self.max_payload_len {
+            return codec.decompress(payload, self.max_payload_len);
+        }
+        match codec.decompress(payload, room) {
+            Err(Error::FrameExceedsLimit { .. }) => {
+                let buffered = self.buffered_len() + room + 1;
+                Err(self.exceed_memory(MemoryExceeded::Limit { buffered, limit }))
+            }
+            result => result,
+        }
+    }
+
+    /// Tell the peer that this end is about to close the connection.
+    pub async fn send_goodbye(&mut self) -> Result<(), Error> {
+        self.send_goodbye_with_reason(GoodbyeReason::Unspecified)
+            .await
+    }
+
+    /// Like [`Self::send_goodbye`], but also tell the peer why, e.g. so that a frontend can tell
+    /// its user that the daemon is shutting down.
+    pub async fn send_goodbye_with_reason(&mut self, reason: GoodbyeReason) -> Result<(), Error> {
+        self.goodbye_sent = true;
+        self.write_frame(&Frame::goodbye(reason)).await
+    }
+
+    /// Whether this end has said goodbye to the peer, which the framing also does by itself
+    /// when the server shuts down, see [`Self::set_control`].
+    pub fn goodbye_sent(&self) -> bool {
+        self.goodbye_sent
+    }
+
+    /// Write the queued frames, say goodbye to the peer, and shut down the underlying stream, so
+    /// that the peer reads every frame before it sees the connection end. Dropping the
+    /// connection instead discards the queued frames. The write timeout applies to each step.
+    pub async fn close(self) -> Result<(), Error> {
+        self.close_with_reason(GoodbyeReason::Unspecified).await
+    }
+
+    /// Like [`Self::close`], but tell the peer why, see [`Self::send_goodbye_with_reason`].
+    pub async fn close_with_reason(mut self, reason: GoodbyeReason) -> Result<(), Error> {
+        // The goodbye would otherwise go ahead of the queued frames
+        self.flush().await?;
+        self.send_goodbye_with_reason(reason).await?;
+        let deadline = self.write_timeout.map(|timeout| Instant::now() + timeout);
+        let shutdown = self.io.shutdown();
+        with_deadline(deadline, shutdown, &mut self.deadline_expired, "writing").await??;
+        Ok(())
+    }
+
+    /// Write raw bytes after any queued frames, bypassing the framing, and flush them.
+    pub(crate) async fn write_raw(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.write_buf.extend_from_slice(bytes);
+        self.flush().await
+    }
+
+    /// Read exactly `len` raw bytes, bypassing the framing. Any bytes read beyond them remain
+    /// buffered for subsequent frames.
+    ///
+    /// Fails with [`Error::Rejected`] if the peer closes the connection after sending a reject
+    /// frame instead of the expected bytes.
+    pub(crate) async fn read_raw(&mut self, len: usize) -> Result<Bytes, Error> {
+        while self.read_buf.len() < len {
+            if self.fill_read_buf(None).await? == 0 {
+                return Err(self.buffered_rejection().unwrap_or(Error::UnexpectedEof));
+            }
+        }
+        Ok(self.read_buf.split_to(len).freeze())
+    }
+
+    /// Return the rejection if the buffered input is exactly one reject frame.
+    fn buffered_rejection(&self) -> Option<Error> {
+        rejection(&self.read_buf, self.max_payload_len)
+    }
+
+    /// Capabilities supported by both ends, as negotiated during the handshake. This is empty if
+    /// no handshake has been performed.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    pub(crate) fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    /// Record all frames sent and received from now on in `capture`.
+    #[cfg(feature = "capture")]
+    pub fn set_capture(&mut self, capture: std::sync::Arc<crate::capture::TrafficCapture>) {
+        self.capture = Some(capture);
+    }
+
+    /// Record all frames sent and received from now on in `recorder`, so that the session can
+    /// be replayed. See [`crate::replay`].
+    #[cfg(feature = "replay")]
+    pub fn set_recorder(&mut self, recorder: Arc<crate::replay::SessionRecorder>) {
+        let connection = recorder.next_connection();
+        self.recorder = Some((recorder, connection));
+    }
+
+    /// Whether bytes that have not yet been decoded into a frame are buffered.
+    pub fn has_buffered_input(&self) -> bool {
+        !self.read_buf.is_empty()
+    }
+
+    /// Return a reference to the underlying stream.
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Return a mutable reference to the underlying stream. Reading from or writing to it
+    /// directly may corrupt the framing.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// Return the underlying stream. Any buffered input is discarded.
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    /// Return the underlying stream, and the input that has been read from it but not decoded.
+    pub(crate) fn into_parts(mut self) -> (T, BytesMut) {
+        let buffered = self.read_buf.split();
+        (self.io, buffered)
+    }
+}
+
+impl FramedConnection<crate::Connection> {
+    /// Return the connection, with the input that has been read from it but not decoded put
+    /// back, so that it can be framed again, e.g. by the next [layer](crate::layer). Nothing
+    /// may be buffered for writing.
+    pub fn into_connection(self) -> crate::Connection {
+        let (mut connection, mut buffered) = self.into_parts();
+        // What is still unread comes after what the framing read
+        buffered.extend_from_slice(&connection.unread);
+        connection.unread = buffered;
+        connection
+    }
+}
+
+/// Return the rejection if `buf` is exactly one reject frame, which a server sends instead of
+/// its hello when it rejects a connection.
+pub(crate) fn rejection(buf: &[u8], max_len: usize) -> Option<Error> {
+    let mut buffered = BytesMut::from(buf);
+    match decode_with_flags(&mut buffered, max_len, false) {
+        Ok(Some((frame, 0))) if frame.kind == FrameKind::Reject && buffered.is_empty() => {
+            Some(Error::Rejected(frame.reject_reason()))
+        }
+        _ => None,
+    }
+}
+
+/// Describe the partial frame in `buf`, which the peer stopped sending in the middle of.
+pub(crate) fn truncated(buf: &[u8]) -> MalformedFrame {
+    let received = buf.len();
+    let expected = announced_len(buf).unwrap_or(HEADER_LEN);
+    MalformedFrame::Truncated { expected, received }
+}
+
+/// Compress `payload` if both ends support it and it is worth it.
+#[cfg(feature = "compression")]
+pub(crate) fn compress_payload(capabilities: Capabilities, payload: &[u8]) -> Option<Vec<u8>> {
+    if !capabilities.contains(Capabilities::DEFLATE)
+        || payload.len() < crate::compression::THRESHOLD
+    {
+        return None;
+    }
+    crate::compression::compress(payload)
+}
+
+#[cfg(not(feature = "compression"))]
+pub(crate) fn compress_payload(_capabilities: Capabilities, _payload: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+/// Decompress the payload of a frame that has [`FLAG_COMPRESSED`] set.
+#[cfg(feature = "compression")]
+pub(crate) fn decompress_payload(
+    capabilities: Capabilities,
+    payload: &[u8],
+    max_len: usize,
+) -> Result<Bytes, Error> {
+    if !capabilities.contains(Capabilities::DEFLATE) {
+        return Err(Error::Protocol(
+            "Received compressed frame without negotiating it",
+        ));
+    }
+    crate::compression::decompress(
+        payload,
+        max_len.min(crate::compression::MAX_DECOMPRESSED_LEN),
+    )
+}
+
+#[cfg(not(feature = "compression"))]
+pub(crate) fn decompress_payload(
+    _capabilities: Capabilities,
+    _payload: &[u8],
+    _max_len: usize,
+) -> Result<Bytes, Error> {
+    Err(Error::Protocol(
+        "Received compressed frame without negotiating it",
+    ))
+}
+
+/// Why a connection was closed to bound its memory use.
+#[derive(Debug, Clone, Copy)]
+enum MemoryExceeded {
+    Limit { buffered: usize, limit: usize },
+    Budget { limit: usize },
+}
+
+impl From<MemoryExceeded> for Error {
+    fn from(exceeded: MemoryExceeded) -> Self {
+        match exceeded {
+            MemoryExceeded::Limit { buffered, limit } => {
+                Error::MemoryLimitExceeded { buffered, limit }
+            }
+            MemoryExceeded::Budget { limit } => Error::MemoryBudgetExceeded { limit },
+        }
+    }
+}
+
+/// Something that the server asked a connection for through its [`ConnectionControl`]. Without
+/// the `server` feature, nothing can ask.
+enum ServerRequest {
+    #[cfg(feature = "server")]
+    Ping,
+    #[cfg(feature = "server")]
+    Goodbye,
+    #[cfg(feature = "server")]
+    ShuttingDown,
+}
+
+/// Wait until the server asks for something through `control`. Shutting down is only asked for
+/// if this end has not said goodbye already.
+#[cfg(feature = "server")]
+async fn server_request(control: Option<&ConnectionControl>, goodbye_sent: bool) -> ServerRequest {
+    let Some(control) = control else {
+        return std::future::pending().await;
+    };
+    let draining = async {
+        match control.draining() {
+            Some(draining) if !goodbye_sent => draining.cancelled().await,
+            _ => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        biased;
+        () = control.eviction().cancelled() => ServerRequest::Goodbye,
+        () = draining => ServerRequest::ShuttingDown,
+        () = std::future::poll_fn(|cx| control.probe().poll_request(cx)) => ServerRequest::Ping,
+    }
+}
+
+/// Wait until queued frames have to be flushed, or forever if nothing is queued.
+pub(crate) async fn flush_due(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Run `future` until it completes, or return `None` if `evicted` is cancelled first.
+async fn unless_evicted<F: Future>(
+    evicted: Option<CancellationToken>,
+    future: F,
+) -> Option<F::Output> {
+    let Some(evicted) = evicted else {
+        return Some(future.await);
+    };
+    tokio::select! {
+        output = future => Some(output),
+        () = evicted.cancelled() => None,
+    }
+}
+
+/// Run `future` until `deadline`. Once a deadline has passed, all further operations fail, since
+/// the framing may be out of sync.
+async fn with_deadline<F: Future>(
+    deadline: Option<Instant>,
+    future: F,
+    expired: &mut bool,
+    operation: &'static str,
+) -> Result<F::Output, Error> {
+    if *expired {
+        return Err(Error::Deadline(operation));
+    }
+    let Some(deadline) = deadline else {
+        return Ok(future.await);
+    };
+    tokio::time::timeout_at(deadline, future)
+        .await
+        .map_err(|_| {
+            *expired = true;
+            Error::Deadline(operation)
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut buf = BytesMut::new();
+        let frame = Frame::data(&b"hello"[..]);
+        encode(&frame, &mut buf).unwrap();
+        encode(&Frame::new(FrameKind::Bulk, Bytes::new()), &mut buf).unwrap();
+
+        assert_eq!(decode(&mut buf).unwrap(), Some(frame));
+        assert_eq!(
+            decode(&mut buf).unwrap(),
+            Some(Frame::new(FrameKind::Bulk, Bytes::new()))
+        );
+        assert_eq!(decode(&mut buf).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_reject() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+
+        server
+            .write_frame(&Frame::reject(RejectReason::QuotaExceeded))
+            .await
+            .unwrap();
+        drop(server);
+        assert!(matches!(
+            client.read_raw(10).await,
+            Err(Error::Rejected(RejectReason::QuotaExceeded))
+        ));
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_long_header() {
+        let mut buf = BytesMut::new();
+        encode_with_flags(FrameKind::Data, FLAG_LONG, b"hello", &mut buf).unwrap();
+        assert_eq!(buf.len(), LONG_HEADER_LEN + 5);
+        assert_eq!(
+            decode_with_flags(&mut buf, DEFAULT_MAX_PAYLOAD_LEN, true).unwrap(),
+            Some((Frame::data(&b"hello"[..]), FLAG_LONG))
+        );
+
+        let mut buf = BytesMut::from(&[0, 0, 0, 5, FrameKind::Data as u8, FLAG_LONG][..]);
+        assert_eq!(
+            decode_with_flags(&mut buf, usize::MAX, false).unwrap(),
+            None
+        );
+        // The upper half counts against the limit as well
+        buf.extend_from_slice(&[0, 0, 0, 1]);
+        assert!(matches!(
+            decode_with_flags(&mut buf, DEFAULT_MAX_PAYLOAD_LEN, false),
+            Err(Error::FrameExceedsLimit { len, .. }) if len == (1 << 32) + 5
+        ));
+        assert_eq!(
+            truncated(&buf),
+            MalformedFrame::Truncated {
+                expected: LONG_HEADER_LEN + (1 << 32) + 5,
+                received: LONG_HEADER_LEN,
+            }
+        );
+    }
+
+    /// A long frame is accepted once the limit has been raised, but has to fit in memory.
+    #[cfg(target_pointer_width = "64")]
+    #[tokio::test]
+    async fn test_long_frame_limit() {
+        let mut header = vec![0, 0, 0, 5, FrameKind::Data as u8, FLAG_LONG, 0, 0, 0, 1];
+        header.extend_from_slice(&[0; 100]);
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = FramedConnection::new(server);
+        server.set_capabilities(Capabilities::LONG_FRAMES);
+        server.set_max_payload_len(1 << 33);
+        client.write_all(&header).await.unwrap();
+        drop(client);
+        // The rest of the payload is waited for, rather than refused
+        assert!(matches!(
+            server.read_frame().await,
+            Err(Error::UnexpectedEof)
+        ));
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = FramedConnection::new(server);
+        server.set_capabilities(Capabilities::LONG_FRAMES);
+        server.set_max_payload_len(1 << 33);
+        server.set_memory_limit(Some(1024 * 1024));
+        client.write_all(&header).await.unwrap();
+        assert!(matches!(
+            server.read_frame().await,
+            Err(Error::MemoryLimitExceeded { limit: 1048576, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_long_frames_are_negotiated() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        let mut buf = BytesMut::new();
+        encode_with_flags(FrameKind::Data, FLAG_LONG, b"hello", &mut buf).unwrap();
+        client.write_raw(&buf).await.unwrap();
+        client.write_raw(&buf).await.unwrap();
+
+        server.set_capabilities(Capabilities::LONG_FRAMES);
+        assert_eq!(
+            server.read_frame().await.unwrap(),
+            Some(Frame::data(&b"hello"[..]))
+        );
+        server.set_capabilities(Capabilities::empty());
+        assert!(matches!(server.read_frame().await, Err(Error::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn test_checksum() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        client.set_capabilities(Capabilities::CHECKSUM);
+        server.set_capabilities(Capabilities::CHECKSUM);
+        client
+            .write_frame(&Frame::data(&b"hello"[..]))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.read_frame().await.unwrap(),
+            Some(Frame::data(&b"hello"[..]))
+        );
+
+        let mut buf = BytesMut::new();
+        encode_negotiated(
+            Capabilities::CHECKSUM,
+            FrameKind::Data,
+            0,
+            b"hello",
+            &mut buf,
+        )
+        .unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + 5 + CHECKSUM_LEN);
+        // A bit of the payload is flipped on the way
+        buf[HEADER_LEN] ^= 0x20;
+        client.write_raw(&buf).await.unwrap();
+        assert!(matches!(
+            server.read_frame().await,
+            Err(Error::FrameCorrupted)
+        ));
+        // The connection cannot be used anymore
+        assert!(matches!(
+            server.read_frame().await,
+            Err(Error::FrameCorrupted)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_missing_checksum() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        server.set_capabilities(Capabilities::CHECKSUM);
+
+        // The client sends a frame without a checksum, as if the flag had been lost on the way
+        client
+            .write_frame(&Frame::data(&b"hello"[..]))
+            .await
+            .unwrap();
+        assert!(matches!(
+            server.read_frame().await,
+            Err(Error::FrameCorrupted)
+        ));
+    }
+
+    #[test]
+    fn test_partial_frame() {
+        let mut buf = BytesMut::new();
+        encode(&Frame::data(&b"hello"[..]), &mut buf).unwrap();
+        let mut partial = buf.split_to(HEADER_LEN + 2);
+
+        assert_eq!(decode(&mut partial).unwrap(), None);
+        partial.unsplit(buf);
+        assert!(decode(&mut partial).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_unknown_flags() {
+        let mut buf = BytesMut::from(&[0, 0, 0, 0, 0, 0x80][..]);
+        assert!(matches!(decode(&mut buf), Err(Error::Protocol(_))));
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_compressed_roundtrip() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        client.set_capabilities(Capabilities::DEFLATE);
+        server.set_capabilities(Capabilities::DEFLATE);
+
+        let large = Frame::data("relay".repeat(crate::compression::THRESHOLD));
+        let small = Frame::data(&b"ping"[..]);
+        client.write_frame(&large).await.unwrap();
+        client.write_frame(&small).await.unwrap();
+
+        assert_eq!(server.read_frame().await.unwrap(), Some(large));
+        assert_eq!(server.read_frame().await.unwrap(), Some(small));
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_decompressed_memory_limit() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        client.set_capabilities(Capabilities::DEFLATE);
+        server.set_capabilities(Capabilities::DEFLATE);
+        server.set_memory_limit(Some(1024));
+
+        // Small on the wire, but not once decompressed
+        let large = Frame::data("relay".repeat(crate::compression::THRESHOLD));
+        client.write_frame(&large).await.unwrap();
+        assert!(matches!(
+            server.read_frame().await,
+            Err(Error::MemoryLimitExceeded { limit: 1024, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_coalescing() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+
+        client.feed_frame(&Frame::data(&b"one"[..])).await.unwrap();
+        client.feed_frame(&Frame::data(&b"two"[..])).await.unwrap();
+        assert_eq!(client.queued_len(), 2 * (HEADER_LEN + 3));
+
+        client.flush().await.unwrap();
+        assert_eq!(client.queued_len(), 0);
+        assert_eq!(
+            server.read_frame().await.unwrap(),
+            Some(Frame::data(&b"one"[..]))
+        );
+        assert_eq!(
+            server.read_frame().await.unwrap(),
+            Some(Frame::data(&b"two"[..]))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_mode() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        client.set_flush_mode(FlushMode::Batched {
+            max_delay: Duration::from_secs(1),
+        });
+
+        client.feed_frame(&Frame::data(&b"one"[..])).await.unwrap();
+        client.feed_frame(&Frame::data(&b"two"[..])).await.unwrap();
+        assert_eq!(client.queued_len(), 2 * (HEADER_LEN + 3));
+        // Writing a frame flushes what was queued before it, whatever the mode
+        client
+            .write_frame(&Frame::data(&b"three"[..]))
+            .await
+            .unwrap();
+        assert_eq!(client.queued_len(), 0);
+        for payload in [&b"one"[..], b"two", b"three"] {
+            assert_eq!(
+                server.read_frame().await.unwrap(),
+                Some(Frame::data(payload))
+            );
+        }
+
+        // The peer only answers once it has received the frame, which is flushed by the
+        // deadline while waiting for the answer
+        client.feed_frame(&Frame::data(&b"four"[..])).await.unwrap();
+        let answer = async {
+            let request = server.read_frame().await.unwrap();
+            server.write_frame(&Frame::data(&b"ack"[..])).await.unwrap();
+            request
+        };
+        let (answer, request) = tokio::join!(client.read_frame(), answer);
+        assert_eq!(request, Some(Frame::data(&b"four"[..])));
+        assert_eq!(answer.unwrap(), Some(Frame::data(&b"ack"[..])));
+
+        client.set_flush_mode(FlushMode::AutoFlush);
+        client.feed_frame(&Frame::data(&b"five"[..])).await.unwrap();
+        assert_eq!(client.queued_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+
+        client.feed_frame(&Frame::data(&b"last"[..])).await.unwrap();
+        client.close().await.unwrap();
+        assert_eq!(
+            server.read_frame().await.unwrap(),
+            Some(Frame::data(&b"last"[..]))
+        );
+        assert_eq!(
+            server.read_frame().await.unwrap(),
+            Some(Frame::goodbye(GoodbyeReason::Unspecified))
+        );
+        assert_eq!(server.read_frame().await.unwrap(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_deadline() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = FramedConnection::new(server);
+        server.set_read_frame_timeout(Some(Duration::from_secs(1)));
+
+        // Only half of a header
+        client.write_all(&[0, 0, 0]).await.unwrap();
+        assert!(matches!(
+            server.read_frame().await,
+            Err(Error::Deadline("reading"))
+        ));
+        assert!(matches!(server.read_frame().await, Err(Error::Deadline(_))));
+    }
+
+    #[tokio::test]
+    async fn test_strict() {
+        let mut buf = BytesMut::new();
+        encode(&Frame::new(FrameKind::Goodbye, &b"xy"[..]), &mut buf).unwrap();
+        assert!(matches!(
+            decode_strict(&mut buf),
+            Err(Error::Malformed(MalformedFrame::InvalidPayloadLength {
+                kind: FrameKind::Goodbye,
+                len: 2
+            }))
+        ));
+        let mut buf = BytesMut::new();
+        encode(&Frame::goodbye(GoodbyeReason::Evicted), &mut buf).unwrap();
+        assert_eq!(
+            decode_strict(&mut buf).unwrap().unwrap().goodbye_reason(),
+            GoodbyeReason::Evicted
+        );
+
+        let mut buf = BytesMut::from(&[0xff, 0xff, 0xff, 0xff, 0, 0][..]);
+        assert!(matches!(
+            decode_strict(&mut buf),
+            Err(Error::Malformed(MalformedFrame::TooLong { .. }))
+        ));
+        let mut buf = BytesMut::from(&[0, 0, 0, 0, 0xff, 0][..]);
+        assert!(matches!(
+            decode_strict(&mut buf),
+            Err(Error::Malformed(MalformedFrame::UnknownKind(0xff)))
+        ));
+
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = FramedConnection::new(server);
+        server.set_strict(true);
+        client.write_all(&[0, 0, 0, 5, 0, 0, b'h']).await.unwrap();
+        drop(client);
+        let truncated = MalformedFrame::Truncated {
+            expected: HEADER_LEN + 5,
+            received: HEADER_LEN + 1,
+        };
+        assert!(matches!(server.read_frame().await, Err(Error::Malformed(m)) if m == truncated));
+        assert!(matches!(
+            server.read_frame().await,
+            Err(Error::Malformed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_max_payload_len() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        server.set_max_payload_len(4);
+
+        client
+            .write_frame(&Frame::data(&b"four"[..]))
+            .await
+            .unwrap();
+        client
+            .write_frame(&Frame::data(&b"five!"[..]))
+            .await
+            .unwrap();
+        assert!(server.read_frame().await.unwrap().is_some());
+        assert!(matches!(
+            server.read_frame().await,
+            Err(Error::FrameExceedsLimit { len: 5, max: 4 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        server.set_memory_limit(Some(100));
+
+        client
+            .write_frame(&Frame::data(vec![0u8; 90]))
+            .await
+            .unwrap();
+        client
+            .write_frame(&Frame::data(vec![0u8; 200]))
+            .await
+            .unwrap();
+        assert!(server.read_frame().await.unwrap().is_some());
+        assert!(matches!(
+            server.read_frame().await,
+            Err(Error::MemoryLimitExceeded {
+                buffered: 206,
+                limit: 100
+            })
+        ));
+        // The server has told the client why, and closed the connection
+        let goodbye = client.read_frame().await.unwrap().unwrap();
+        assert_eq!(goodbye.goodbye_reason(), GoodbyeReason::MemoryExceeded);
+        assert!(client.read_frame().await.unwrap().is_none());
+
+        client.set_memory_limit(Some(100));
+        let frame = Frame::data(vec![0u8; 60]);
+        client.try_feed_frame(&frame).unwrap();
+        assert!(matches!(
+            client.try_feed_frame(&frame),
+            Err(Error::MemoryLimitExceeded {
+                buffered: 132,
+                limit: 100
+            })
+        ));
+        assert_eq!(client.buffered_len(), 0);
+        assert!(client.flush().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget() {
+        let budget = MemoryBudget::new(300);
+        let (small_client, small) = tokio::io::duplex(1024);
+        let (large_client, large) = tokio::io::duplex(1024);
+        let mut small_client = FramedConnection::new(small_client);
+        let mut large_client = FramedConnection::new(large_client);
+        let mut small = FramedConnection::new(small);
+        let mut large = FramedConnection::new(large);
+        small.set_memory_budget(&budget);
+        large.set_memory_budget(&budget);
+
+        let frame = Frame::data(vec![0u8; 50]);
+        small.try_feed_frame(&frame).unwrap();
+        large.try_feed_frame(&Frame::data(vec![0u8; 200])).unwrap();
+        assert_eq!(budget.used(), 262);
+
+        // The large connection is waiting for its peer when it is closed
+        let large_read = tokio::spawn(async move { large.read_frame().await });
+        tokio::task::yield_now().await;
+        small.try_feed_frame(&frame).unwrap();
+        assert!(matches!(
+            large_read.await.unwrap(),
+            Err(Error::MemoryBudgetExceeded { limit: 300 })
+        ));
+        assert_eq!(budget.used(), 112);
+        assert_eq!(budget.connection_count(), 1);
+
+        small.flush().await.unwrap();
+        assert_eq!(budget.used(), 0);
+        assert!(small_client.read_frame().await.unwrap().is_some());
+        let goodbye = large_client.read_frame().await.unwrap().unwrap();
+        assert_eq!(goodbye.goodbye_reason(), GoodbyeReason::MemoryExceeded);
+        assert!(large_client.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget_policy() {
+        let budget = MemoryBudget::new(300);
+        budget.set_slow_consumer_policy(SlowConsumerPolicy::DropNewest);
+        let (_small_client, small) = tokio::io::duplex(1024);
+        let (_large_client, large) = tokio::io::duplex(1024);
+        let mut small = FramedConnection::new(small);
+        let mut large = FramedConnection::new(large);
+        small.set_memory_budget(&budget);
+        large.set_memory_budget(&budget);
+
+        let frame = Frame::data(vec![0u8; 50]);
+        small.try_feed_frame(&frame).unwrap();
+        large.try_feed_frame(&Frame::data(vec![0u8; 200])).unwrap();
+        // Nobody is disconnected, but the frame that does not fit is refused
+        assert!(matches!(
+            small.try_feed_frame(&frame),
+            Err(Error::MemoryBudgetFull { limit: 300 })
+        ));
+        assert_eq!(budget.used(), 262);
+        assert_eq!(budget.connection_count(), 2);
+
+        // The frames that are queued are dropped to make room for the new one
+        budget.set_slow_consumer_policy(SlowConsumerPolicy::DropOldest);
+        small.try_feed_frame(&Frame::data(vec![0u8; 80])).unwrap();
+        assert_eq!(small.queued_len(), HEADER_LEN + 80);
+        assert_eq!(budget.used(), 292);
+        large.flush().await.unwrap();
+        assert_eq!(budget.used(), 86);
+    }
+
+    #[tokio::test]
+    async fn test_write_high_water_mark() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        client.set_write_high_water_mark(100);
+
+        let frame = Frame::data(vec![0u8; 200]);
+        client.try_feed_frame(&frame).unwrap();
+        assert_eq!(client.queued_len(), HEADER_LEN + 200);
+        assert!(matches!(
+            client.try_feed_frame(&frame),
+            Err(Error::WriteQueueFull {
+                queued: 206,
+                high_water_mark: 100
+            })
+        ));
+        // The peer is not reading, so the queue cannot drain
+        assert!(futures::FutureExt::now_or_never(client.write_ready()).is_none());
+
+        let (ready, received) = tokio::join!(
+            async {
+                client.write_ready().await?;
+                assert!(client.queued_len() < 100);
+                client.flush().await
+            },
+            server.read_frame(),
+        );
+        ready.unwrap();
+        assert_eq!(received.unwrap(), Some(frame));
+    }
+
+    #[tokio::test]
+    async fn test_priority_frames_go_first() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+
+        client.feed_frame(&Frame::data(&b"data"[..])).await.unwrap();
+        client.send_goodbye().await.unwrap();
+        assert_eq!(
+            server.read_frame().await.unwrap().unwrap().kind,
+            FrameKind::Goodbye
+        );
+        assert_eq!(
+            server.read_frame().await.unwrap(),
+            Some(Frame::data(&b"data"[..]))
+        );
+    }
+
+    #[test]
+    fn test_unknown_kind() {
+        let mut buf = BytesMut::from(&[0, 0, 0, 0, 0xff, 0][..]);
+        assert!(matches!(
+            decode(&mut buf),
+            Err(Error::UnknownFrameKind(0xff))
+        ));
+    }
+}
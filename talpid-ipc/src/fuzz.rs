@@ -0,0 +1,101 @@
+//! Entry points into the parsers of untrusted input, for fuzzing.
+//!
+//! Any local process that can connect to an endpoint can send arbitrary bytes to the daemon, so
+//! the frame decoder and the handshake parser are the local attack surface of this crate. The
+//! functions here run them on a byte slice, without any I/O or other state, so that the same
+//! input always gives the same result. They must return an error rather than panic, whatever
+//! the input.
+//!
+//! The targets in `talpid-ipc/fuzz` call these functions, which are only built with the `fuzz`
+//! feature. Run them with e.g. `cargo +nightly fuzz run decode_frame` in that directory.
+
+use crate::{
+    Error,
+    frame::{self, DEFAULT_MAX_PAYLOAD_LEN, FLAG_COMPRESSED, Frame},
+    handshake::{self, Capabilities, HELLO_LEN},
+};
+use bytes::BytesMut;
+
+/// Decode the complete frames at the start of `data`, like a connection that has received it,
+/// and stop at the first incomplete frame. Compressed payloads are decompressed. In `strict`
+/// mode, malformed frames are reported as [`Error::Malformed`], see
+/// [`frame::FramedConnection::set_strict`].
+pub fn decode_frames(data: &[u8], strict: bool) -> Result<Vec<Frame>, Error> {
+    let mut src = BytesMut::from(data);
+    let mut frames = Vec::new();
+    while let Some((mut frame, flags)) =
+        frame::decode_with_flags(&mut src, DEFAULT_MAX_PAYLOAD_LEN, strict)?
+    {
+        if flags & FLAG_COMPRESSED != 0 {
+            frame.payload = frame::decompress_payload(
+                Capabilities::DEFLATE,
+                &frame.payload,
+                DEFAULT_MAX_PAYLOAD_LEN,
+            )?;
+        }
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+/// Parse `data` as the hello of a peer that is expected to use protocol `version`, and return
+/// the capabilities that it offers. Fails with [`Error::UnexpectedEof`] if `data` is too short.
+pub fn parse_hello(data: &[u8], version: u16) -> Result<Capabilities, Error> {
+    let hello = data.get(..HELLO_LEN).ok_or(Error::UnexpectedEof)?;
+    handshake::check_hello(hello, version, Capabilities::all())
+}
+
+/// Decode the payload of a data frame as an RPC message.
+#[cfg(feature = "rpc")]
+pub fn decode_message(payload: &[u8]) -> Result<crate::rpc::Message, Error> {
+    crate::rpc::Message::from_frame(&Frame::data(payload.to_vec()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_frames() {
+        let mut encoded = BytesMut::new();
+        frame::encode(&Frame::data(&b"hello"[..]), &mut encoded).unwrap();
+        frame::encode(
+            &Frame::goodbye(frame::GoodbyeReason::ShuttingDown),
+            &mut encoded,
+        )
+        .unwrap();
+        // Half of another frame
+        frame::encode(&Frame::data(&b"world"[..]), &mut encoded).unwrap();
+        encoded.truncate(encoded.len() - 3);
+
+        let frames = decode_frames(&encoded, true).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], Frame::data(&b"hello"[..]));
+
+        assert!(decode_frames(&[0, 0, 0, 0, 0xff, 0], false).is_err());
+        // Rejects have a payload of one byte
+        let reject = [0, 0, 0, 2, 5, 0, 1, 1];
+        assert!(decode_frames(&reject, false).is_ok());
+        assert!(matches!(
+            decode_frames(&reject, true),
+            Err(Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_hello() {
+        let hello = handshake::encode_hello(3, Capabilities::application(1));
+        assert_eq!(
+            parse_hello(&hello, 3).unwrap(),
+            Capabilities::application(1)
+        );
+        assert!(matches!(
+            parse_hello(&hello, 4),
+            Err(Error::IncompatiblePeer { theirs: 3, ours: 4 })
+        ));
+        assert!(matches!(
+            parse_hello(&hello[..HELLO_LEN - 1], 3),
+            Err(Error::UnexpectedEof)
+        ));
+    }
+}
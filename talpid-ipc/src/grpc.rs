@@ -0,0 +1,334 @@
+//! Running tonic over endpoints.
+//!
+//! [`connect`] returns a [`Channel`] to the server at a path, and [`serve`] serves a tonic
+//! service on an [`Endpoint`]. Services can find out who is calling through the
+//! [`IpcConnectInfo`] in the extensions of each request, i.e.
+//! `request.extensions().get::<IpcConnectInfo>()`.
+//!
+//! Serving requires the `server` feature, and connecting the `client` feature.
+//!
+//! A channel reconnects by itself when the connection is lost, e.g. because the server
+//! restarted. Calls fail while it is disconnected. When reconnecting, the channel waits for a
+//! while for the server to start listening again, see [`RECONNECT_TIMEOUT`].
+//!
+//! Watchdogs can check that a gRPC server answers with [`check`].
+
+#[cfg(feature = "server")]
+use crate::Incoming;
+use crate::{Connection, ConnectionId, Endpoint};
+use futures::TryFutureExt;
+use hyper_util::rt::TokioIo;
+use std::{
+    convert::Infallible,
+    future::Future,
+    io,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tonic::{
+    body::BoxBody,
+    codegen::{
+        Service,
+        http::{Request, Response},
+    },
+    server::NamedService,
+    transport::{Channel, Server, Uri, server::Connected},
+};
+
+/// How long a channel waits for the server to start listening again when reconnecting.
+#[cfg(feature = "client")]
+pub const RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest flow control window that HTTP/2 allows.
+#[cfg(feature = "server")]
+const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+/// Ignored, since the connector decides where to connect. It has to be a valid URI.
+#[cfg(feature = "client")]
+const PLACEHOLDER_URI: &str = "lttp://[::]:50051";
+
+/// Method of a service that no server has, so that the server answers calls to it with
+/// [`tonic::Code::Unimplemented`] without involving any of its services.
+#[cfg(feature = "client")]
+const CHECK_METHOD: &str = "/talpid_ipc.health.Check/Check";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to listen on IPC endpoint")]
+    Listen(#[source] io::Error),
+
+    #[error("gRPC transport error")]
+    Transport(#[source] tonic::transport::Error),
+}
+
+/// The peer of a connection that a gRPC server accepted, as found when it was accepted.
+#[derive(Debug, Clone)]
+pub struct IpcConnectInfo {
+    id: ConnectionId,
+    #[cfg(unix)]
+    credentials: Option<crate::credentials::PeerCredentials>,
+    #[cfg(windows)]
+    identity: Option<crate::identity::PeerIdentity>,
+}
+
+impl IpcConnectInfo {
+    /// ID of the connection that the request arrived on.
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Credentials of the peer, or `None` if they could not be obtained.
+    #[cfg(unix)]
+    pub fn peer_credentials(&self) -> Option<&crate::credentials::PeerCredentials> {
+        self.credentials.as_ref()
+    }
+
+    /// Identity of the client, see [`Connection::peer_identity`].
+    #[cfg(windows)]
+    pub fn peer_identity(&self) -> Option<&crate::identity::PeerIdentity> {
+        self.identity.as_ref()
+    }
+}
+
+impl Connected for Connection {
+    type ConnectInfo = IpcConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        IpcConnectInfo {
+            id: self.id(),
+            #[cfg(unix)]
+            credentials: self
+                .peer_credentials()
+                .inspect_err(|error| log::debug!("Failed to get peer credentials: {error}"))
+                .ok(),
+            #[cfg(windows)]
+            identity: self.peer_identity().cloned(),
+        }
+    }
+}
+
+/// Connect to the gRPC server at `path`. Fails if nothing is listening.
+///
+/// Once connected, the channel does not fail for good when the connection is lost. It connects
+/// again for the next call, and waits for up to [`RECONNECT_TIMEOUT`] for the server to be
+/// listening, so that calls made while the server restarts succeed once it is back.
+#[cfg(feature = "client")]
+pub async fn connect(path: impl AsRef<Path>) -> Result<Channel, Error> {
+    let path: Arc<Path> = Arc::from(path.as_ref());
+    let connected = Arc::new(AtomicBool::new(false));
+    tonic::transport::Endpoint::from_static(PLACEHOLDER_URI)
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            let path = path.clone();
+            let reconnecting = connected.swap(true, Ordering::Relaxed);
+            async move {
+                let connection = if reconnecting {
+                    Endpoint::connect_when_ready(&*path, RECONNECT_TIMEOUT).await
+                } else {
+                    Endpoint::connect(&*path).await
+                };
+                connection.map(TokioIo::new)
+            }
+        }))
+        .map_err(Error::Transport)
+        .await
+}
+
+/// Connect to the gRPC server at `path` and make a call that the server answers by itself,
+/// returning the round-trip time of the call. Calls are answered by the same connection task as
+/// those of the services, so this finds out whether the server still serves them. Unlike
+/// [`crate::health::check`], this works with servers that speak gRPC. Wrap this in
+/// [`tokio::time::timeout`] to detect an unresponsive server.
+#[cfg(feature = "client")]
+pub async fn check(path: impl AsRef<Path>) -> Result<Duration, Error> {
+    let channel = connect(path).await?;
+    let request = Request::builder()
+        .uri(CHECK_METHOD)
+        .header("content-type", "application/grpc")
+        .body(tonic::body::empty_body())
+        .expect("The request is valid");
+    let sent = Instant::now();
+    tower::ServiceExt::oneshot(channel, request)
+        .await
+        .map_err(Error::Transport)?;
+    Ok(sent.elapsed())
+}
+
+/// Serve `service` on `endpoint` until an error occurs.
+#[cfg(feature = "server")]
+pub async fn serve<S>(endpoint: Endpoint, service: S) -> Result<(), Error>
+where
+    S: Service<Request<BoxBody>, Response = Response<BoxBody>, Error = Infallible>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    serve_with_shutdown(endpoint, service, std::future::pending()).await
+}
+
+/// Like [`serve`], but stop once `signal` completes.
+#[cfg(feature = "server")]
+pub async fn serve_with_shutdown<S, F>(
+    endpoint: Endpoint,
+    service: S,
+    signal: F,
+) -> Result<(), Error>
+where
+    S: Service<Request<BoxBody>, Response = Response<BoxBody>, Error = Infallible>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+    F: Future<Output = ()>,
+{
+    let incoming = endpoint.incoming().map_err(Error::Listen)?;
+    serve_incoming(incoming, service, signal).await
+}
+
+/// Like [`serve_with_shutdown`], but serve connections that are already being accepted, e.g.
+/// to change the owner of the socket after listening on it.
+///
+/// If a memory limit has been set with [`Endpoint::set_memory_limit`], the HTTP/2 flow control
+/// windows are set to it, so that a client cannot make the server buffer more than that for a
+/// connection, and for each of its calls. HTTP/2 always allows 64 KiB for each connection.
+#[cfg(feature = "server")]
+pub async fn serve_incoming<S, F>(incoming: Incoming, service: S, signal: F) -> Result<(), Error>
+where
+    S: Service<Request<BoxBody>, Response = Response<BoxBody>, Error = Infallible>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+    F: Future<Output = ()>,
+{
+    let mut server = Server::builder();
+    if let Some(limit) = incoming.memory_limit() {
+        let window = u32::try_from(limit)
+            .unwrap_or(u32::MAX)
+            .min(MAX_WINDOW_SIZE);
+        server = server
+            .initial_connection_window_size(window)
+            .initial_stream_window_size(window);
+    }
+    server
+        .add_service(service)
+        .serve_with_incoming_shutdown(incoming, signal)
+        .await
+        .map_err(Error::Transport)
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use crate::testing::EphemeralPath;
+    use futures::future;
+    use std::task::{Context, Poll};
+    use tokio::sync::oneshot;
+    use tonic::{Code, Status};
+
+    /// Succeeds if the connect info of the request is available to the service.
+    #[derive(Clone)]
+    struct TestService;
+
+    impl NamedService for TestService {
+        const NAME: &'static str = "talpid_ipc.test.Test";
+    }
+
+    impl Service<Request<BoxBody>> for TestService {
+        type Response = Response<BoxBody>;
+        type Error = Infallible;
+        type Future = future::Ready<Result<Response<BoxBody>, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<BoxBody>) -> Self::Future {
+            let status = match request.extensions().get::<IpcConnectInfo>() {
+                #[cfg(unix)]
+                Some(info) if info.peer_credentials().is_none() => {
+                    Status::internal("No peer credentials")
+                }
+                Some(_) => Status::ok(""),
+                None => Status::internal("No connect info"),
+            };
+            future::ready(Ok(status.into_http()))
+        }
+    }
+
+    /// Serve [`TestService`] on `path` until the returned sender is dropped.
+    fn spawn_server(path: &str) -> oneshot::Sender<()> {
+        let (stop, stopped) = oneshot::channel();
+        let incoming = Endpoint::new(path.to_owned()).incoming().unwrap();
+        tokio::spawn(serve_incoming(incoming, TestService, async {
+            let _ = stopped.await;
+        }));
+        stop
+    }
+
+    async fn call(channel: &Channel) -> Result<Code, tonic::transport::Error> {
+        let request = Request::builder()
+            .uri("/talpid_ipc.test.Test/Check")
+            .header("content-type", "application/grpc")
+            .body(tonic::body::empty_body())
+            .unwrap();
+        let response = tower::ServiceExt::oneshot(channel.clone(), request).await?;
+        Ok(Status::from_header_map(response.headers()).map_or(Code::Ok, |status| status.code()))
+    }
+
+    #[tokio::test]
+    async fn test_connect_info() {
+        let path = EphemeralPath::new().unwrap();
+        let _server = spawn_server(path.path());
+
+        let channel = connect(path.path()).await.unwrap();
+        assert_eq!(call(&channel).await.unwrap(), Code::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let path = EphemeralPath::new().unwrap();
+        let _server = spawn_server(path.path());
+        check(path.path()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_not_listening() {
+        let path = EphemeralPath::new().unwrap();
+        assert!(connect(path.path()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let path = EphemeralPath::new().unwrap();
+        let server = spawn_server(path.path());
+        let channel = connect(path.path()).await.unwrap();
+        call(&channel).await.unwrap();
+
+        drop(server);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let restart = {
+            let path = path.path().to_owned();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                spawn_server(&path)
+            })
+        };
+
+        // The call that finds the connection lost may fail, but the channel waits for the
+        // server to come back for the next one
+        let code = match call(&channel).await {
+            Ok(code) => code,
+            Err(_) => call(&channel).await.unwrap(),
+        };
+        assert_eq!(code, Code::Ok);
+        drop(restart.await.unwrap());
+    }
+}
@@ -0,0 +1,274 @@
+//! Optional handshake performed immediately after a connection has been established.
+//!
+//! Both ends send a hello consisting of a magic constant, their protocol version and the
+//! [`Capabilities`] they support, and then read the hello of the peer. This ensures that a
+//! mismatched client and server fail with a clear error instead of misinterpreting each other's
+//! frames. The hello is not framed, so a peer that speaks a different protocol is detected after
+//! reading only a few bytes.
+//!
+//! Capabilities allow a newer end to keep talking to an older one by not using features that the
+//! older one does not know about, rather than bumping the protocol version.
+
+use crate::{
+    Error,
+    context::{self, Operation},
+    frame::FramedConnection,
+    metrics::HandshakeFailureReason,
+};
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Magic constant that starts every hello.
+pub const MAGIC: [u8; 4] = *b"TIPC";
+
+/// Size of the hello in bytes.
+pub(crate) const HELLO_LEN: usize = MAGIC.len() + 2 + 4;
+
+bitflags::bitflags! {
+    /// Optional features supported by one end of a connection. The lower 16 bits are reserved
+    /// for features of the transport itself. The upper 16 bits may be defined by applications.
+    ///
+    /// Bits that are unknown to this end are retained, so that they can be negotiated by the
+    /// application.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct Capabilities: u32 {
+        /// Large frames may be compressed. See [`crate::compression`].
+        const DEFLATE = 1 << 0;
+        /// Frames may have payloads of 4 GiB or more. See [`crate::frame::FLAG_LONG`].
+        const LONG_FRAMES = 1 << 1;
+        /// Frames are followed by a checksum. See [`crate::frame::FLAG_CHECKSUM`].
+        const CHECKSUM = 1 << 2;
+        /// Encrypted streams may rotate their keys. See [`crate::encryption`].
+        const REKEY = 1 << 3;
+
+        const _ = !0;
+    }
+}
+
+impl Capabilities {
+    /// Return application-defined capability number `n`, which must be smaller than 16.
+    pub const fn application(n: u32) -> Self {
+        assert!(n < 16, "There are only 16 application capabilities");
+        Capabilities::from_bits_retain(1 << (16 + n))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> FramedConnection<T> {
+    /// Exchange hellos with the peer, which must also call `handshake`. Fails with
+    /// [`Error::IncompatiblePeer`] unless both ends use the same protocol `version`.
+    ///
+    /// Returns the capabilities supported by both ends, which are also available from
+    /// [`FramedConnection::capabilities`] afterwards.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self), err)
+    )]
+    pub async fn handshake(
+        &mut self,
+        version: u16,
+        capabilities: Capabilities,
+    ) -> Result<Capabilities, Error> {
+        let capabilities = offered(capabilities);
+        let with_context = |error| match error {
+            Error::Io(error) => Error::Io(context::with_context(error, Operation::Handshake, None)),
+            error => error,
+        };
+        self.write_raw(&encode_hello(version, capabilities))
+            .await
+            .map_err(with_context)?;
+
+        let peer_hello = self.read_raw(HELLO_LEN).await.map_err(with_context)?;
+        let common = check_hello(&peer_hello, version, capabilities)?;
+        self.set_capabilities(common);
+        Ok(common)
+    }
+}
+
+impl FramedConnection<crate::Connection> {
+    /// Like [`Self::handshake`], but if the peer does not speak the protocol or uses another
+    /// version, report it to the [`IpcMetrics`] of the endpoint that accepted the connection,
+    /// together with who the peer is. Servers should use this instead of `handshake`.
+    ///
+    /// [`IpcMetrics`]: crate::metrics::IpcMetrics
+    pub async fn accept_handshake(
+        &mut self,
+        version: u16,
+        capabilities: Capabilities,
+    ) -> Result<Capabilities, Error> {
+        let result = self.handshake(version, capabilities).await;
+        let reason = match &result {
+            Err(Error::UnrecognizedPeer) => HandshakeFailureReason::UnrecognizedPeer,
+            &Err(Error::IncompatiblePeer { theirs, ours }) => {
+                HandshakeFailureReason::IncompatibleVersion { theirs, ours }
+            }
+            _ => return result,
+        };
+        self.get_ref().report_handshake_failure(reason);
+        result
+    }
+}
+
+/// Remove the capabilities that this build cannot support from `capabilities`.
+pub(crate) fn offered(mut capabilities: Capabilities) -> Capabilities {
+    if !cfg!(feature = "compression") {
+        capabilities -= Capabilities::DEFLATE;
+    }
+    if !cfg!(feature = "encryption") {
+        capabilities -= Capabilities::REKEY;
+    }
+    // Such payloads could not be held in memory anyway
+    if usize::BITS < 64 {
+        capabilities -= Capabilities::LONG_FRAMES;
+    }
+    capabilities
+}
+
+/// Encode the hello of this end.
+pub(crate) fn encode_hello(version: u16, capabilities: Capabilities) -> BytesMut {
+    let mut hello = BytesMut::with_capacity(HELLO_LEN);
+    hello.put_slice(&MAGIC);
+    hello.put_u16(version);
+    hello.put_u32(capabilities.bits());
+    hello
+}
+
+/// Check the hello of the peer, which must be [`HELLO_LEN`] bytes long, against the protocol
+/// `version` of this end. Returns the capabilities supported by both ends.
+pub(crate) fn check_hello(
+    mut peer_hello: &[u8],
+    version: u16,
+    capabilities: Capabilities,
+) -> Result<Capabilities, Error> {
+    if peer_hello[..MAGIC.len()] != MAGIC {
+        return Err(Error::UnrecognizedPeer);
+    }
+    peer_hello.advance(MAGIC.len());
+    let theirs = peer_hello.get_u16();
+    if theirs != version {
+        return Err(Error::IncompatiblePeer {
+            theirs,
+            ours: version,
+        });
+    }
+    let theirs = Capabilities::from_bits_retain(peer_hello.get_u32());
+    Ok(capabilities & theirs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame::Frame;
+    use tokio::io::{AsyncWriteExt, duplex};
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (client, server) = duplex(64);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+
+        let (client_result, server_result) = tokio::join!(
+            async {
+                client.handshake(1, Capabilities::empty()).await?;
+                client.write_frame(&Frame::data(&b"hello"[..])).await
+            },
+            async {
+                server.handshake(1, Capabilities::empty()).await?;
+                server.read_frame().await
+            },
+        );
+        client_result.unwrap();
+        assert_eq!(server_result.unwrap(), Some(Frame::data(&b"hello"[..])));
+    }
+
+    #[tokio::test]
+    async fn test_capability_negotiation() {
+        const EVENTS_V2: Capabilities = Capabilities::application(0);
+        const SPLIT_TUNNEL: Capabilities = Capabilities::application(1);
+
+        let (client, server) = duplex(64);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+
+        let (client_result, server_result) = tokio::join!(
+            client.handshake(1, EVENTS_V2),
+            server.handshake(1, EVENTS_V2 | SPLIT_TUNNEL),
+        );
+        assert_eq!(client_result.unwrap(), EVENTS_V2);
+        assert_eq!(server_result.unwrap(), EVENTS_V2);
+        assert!(!server.capabilities().contains(SPLIT_TUNNEL));
+    }
+
+    #[tokio::test]
+    async fn test_version_mismatch() {
+        let (client, server) = duplex(64);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+
+        let (client_result, server_result) = tokio::join!(
+            client.handshake(1, Capabilities::empty()),
+            server.handshake(2, Capabilities::empty())
+        );
+        assert!(matches!(
+            client_result,
+            Err(Error::IncompatiblePeer { theirs: 2, ours: 1 })
+        ));
+        assert!(matches!(
+            server_result,
+            Err(Error::IncompatiblePeer { theirs: 1, ours: 2 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unrecognized_peer() {
+        let (mut client, server) = duplex(64);
+        let mut server = FramedConnection::new(server);
+
+        client.write_all(b"PRI * HTTP/2.0").await.unwrap();
+        assert!(matches!(
+            server.handshake(1, Capabilities::empty()).await,
+            Err(Error::UnrecognizedPeer)
+        ));
+    }
+
+    #[cfg(all(unix, feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_failure_is_reported() {
+        use crate::metrics::{HandshakeFailure, IpcMetrics};
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Default)]
+        struct Failures(Mutex<Vec<HandshakeFailure>>);
+
+        impl IpcMetrics for Failures {
+            fn handshake_failed(&self, failure: &HandshakeFailure) {
+                self.0.lock().unwrap().push(failure.clone());
+            }
+        }
+
+        let failures = Arc::new(Failures::default());
+        let mut endpoint = crate::testing::EphemeralEndpoint::with_options(|endpoint| {
+            endpoint.set_metrics(failures.clone())
+        })
+        .unwrap();
+
+        let (client, server) = endpoint.connected_pair().await.unwrap();
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        let (_, server_result) = tokio::join!(
+            client.handshake(1, Capabilities::empty()),
+            server.accept_handshake(2, Capabilities::empty()),
+        );
+        assert!(server_result.is_err());
+
+        let failures = failures.0.lock().unwrap();
+        assert_eq!(
+            failures[0].reason,
+            HandshakeFailureReason::IncompatibleVersion { theirs: 1, ours: 2 }
+        );
+        // SAFETY: Getting the effective UID has no preconditions
+        let uid = unsafe { libc::geteuid() };
+        assert_eq!(failures[0].peer.user, Some(uid.to_string()));
+        #[cfg(target_os = "linux")]
+        assert_eq!(failures[0].peer.pid, Some(std::process::id()));
+    }
+}
@@ -0,0 +1,339 @@
+//! Health checks that verify that the other end of a connection is responsive.
+//!
+//! A ping frame is answered with a pong by [`FramedConnection::read_frame`] itself, so any
+//! server that reads frames supports health checks without involving the application. This lets
+//! external watchdogs check that the IPC loop of the daemon is alive.
+//!
+//! Servers that perform a [handshake](crate::handshake) or speak a protocol of their own can
+//! still be checked by enabling [`crate::Endpoint::set_liveness_responder`]. A peer that starts a
+//! connection with [`LIVENESS_PROBE`] is then answered with [`LIVENESS_ANSWER`] by the
+//! connection itself, see [`check_liveness`]. Both are plain bytes, so that scripts can send the
+//! probe with e.g. `socat`.
+//!
+//! gRPC servers, see [`crate::grpc`], do not read frames, and are checked with `grpc::check`
+//! instead, or with a liveness probe.
+
+#[cfg(feature = "client")]
+use crate::Endpoint;
+#[cfg(feature = "server")]
+use crate::imp;
+use crate::{
+    Error,
+    frame::{Frame, FrameKind, FramedConnection},
+};
+#[cfg(feature = "client")]
+use std::path::Path;
+#[cfg(feature = "server")]
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+#[cfg(feature = "server")]
+use tokio::io::ReadBuf;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::Instant,
+};
+
+/// Sent by a peer to find out whether the server is alive. Frames never start with `0xff`,
+/// since their length would exceed any limit on payloads.
+pub const LIVENESS_PROBE: &[u8; 10] = b"\xffLIVENESS\n";
+
+/// Answer to [`LIVENESS_PROBE`].
+pub const LIVENESS_ANSWER: &[u8; 7] = b"\xffALIVE\n";
+
+/// Distinguishes the pongs of consecutive pings.
+static NEXT_PING_ID: AtomicU64 = AtomicU64::new(0);
+
+impl<T: AsyncRead + AsyncWrite + Unpin> FramedConnection<T> {
+    /// Send a ping and wait for the matching pong, returning the round-trip time.
+    ///
+    /// Pings from the peer are answered while waiting, but receiving any other frame fails, so
+    /// this should not be used while the peer may be sending application data.
+    pub async fn ping(&mut self) -> Result<Duration, Error> {
+        let id = NEXT_PING_ID
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let sent = Instant::now();
+        self.write_frame(&Frame::new(FrameKind::Ping, id.clone()))
+            .await?;
+
+        loop {
+            let frame = self.next_frame().await?.ok_or(Error::UnexpectedEof)?;
+            match frame.kind {
+                FrameKind::Pong if frame.payload == id => return Ok(sent.elapsed()),
+                FrameKind::Pong => (),
+                FrameKind::Ping => {
+                    self.write_frame(&Frame::new(FrameKind::Pong, frame.payload))
+                        .await?
+                }
+                _ => return Err(Error::Protocol("Expected a pong")),
+            }
+        }
+    }
+}
+
+/// Connect to the endpoint at `path` and ping it once, returning the round-trip time. Wrap this
+/// in [`tokio::time::timeout`] to detect an unresponsive server.
+///
+/// This does not perform a [handshake](crate::handshake), so it only works with servers that read
+/// frames without one. Check gRPC servers with `grpc::check` or [`check_liveness`] instead.
+#[cfg(feature = "client")]
+pub async fn check(path: impl AsRef<Path>) -> Result<Duration, Error> {
+    let connection = Endpoint::connect(path).await?;
+    FramedConnection::new(connection).ping().await
+}
+
+/// Connect to the endpoint at `path` and send it a [`LIVENESS_PROBE`], returning the time it
+/// took to be answered. The endpoint must answer probes, see
+/// [`Endpoint::set_liveness_responder`].
+#[cfg(feature = "client")]
+pub async fn check_liveness(path: impl AsRef<Path>) -> Result<Duration, Error> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut connection = Endpoint::connect(path).await?;
+    let sent = Instant::now();
+    connection.write_all(LIVENESS_PROBE).await?;
+    let mut answer = [0u8; LIVENESS_ANSWER.len()];
+    connection.read_exact(&mut answer).await?;
+    if answer != *LIVENESS_ANSWER {
+        return Err(Error::Protocol("Unexpected answer to liveness probe"));
+    }
+    Ok(sent.elapsed())
+}
+
+/// How long an accepted connection is given to send a [`LIVENESS_PROBE`] before it is passed
+/// on to the server. Probes that arrive later are answered once the server reads.
+#[cfg(feature = "server")]
+pub const PROBE_WINDOW: Duration = Duration::from_millis(50);
+
+/// How long answering a probe may take before the connection is dropped without an answer.
+#[cfg(feature = "server")]
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Looks for a [`LIVENESS_PROBE`] at the start of an accepted connection, and answers it.
+#[cfg(feature = "server")]
+#[derive(Debug)]
+pub(crate) enum LivenessResponder {
+    /// This many bytes of the probe have been received and held back.
+    Matching(usize),
+    /// Bytes that were held back, but turned out not to be a probe, and have yet to be read.
+    Replaying {
+        held: [u8; LIVENESS_PROBE.len()],
+        pos: usize,
+        len: usize,
+    },
+    /// The connection did not start with a probe.
+    Passthrough,
+    /// The probe was received, and this many bytes of the answer have been written.
+    Answering(usize),
+    /// The probe was answered, so the connection is treated as closed.
+    Answered,
+}
+
+#[cfg(feature = "server")]
+impl LivenessResponder {
+    pub(crate) fn new() -> Self {
+        LivenessResponder::Matching(0)
+    }
+
+    /// Like [`Self::new`], for a connection whose first bytes have already been read.
+    #[cfg(windows)]
+    pub(crate) fn starting_with(received: &[u8]) -> Self {
+        LivenessResponder::after(0, received)
+    }
+
+    /// State once `received` has been read after `matched` bytes of the probe.
+    fn after(matched: usize, received: &[u8]) -> Self {
+        if !received.is_empty() && LIVENESS_PROBE[matched..].starts_with(received) {
+            return match matched + received.len() {
+                len if len < LIVENESS_PROBE.len() => LivenessResponder::Matching(len),
+                _ => LivenessResponder::Answering(0),
+            };
+        }
+        let mut held = *LIVENESS_PROBE;
+        held[matched..matched + received.len()].copy_from_slice(received);
+        LivenessResponder::Replaying {
+            held,
+            pos: 0,
+            len: matched + received.len(),
+        }
+    }
+
+    /// Whether the start of the connection has been received, so that it is known whether it
+    /// was a probe.
+    fn is_decided(&self) -> bool {
+        !matches!(self, LivenessResponder::Matching(_))
+    }
+
+    /// Whether the connection was a probe, and has been answered.
+    fn is_answered(&self) -> bool {
+        matches!(self, LivenessResponder::Answered)
+    }
+
+    /// Read the start of the connection from `io`, until it is known whether it is a probe, and
+    /// answer the probe. Nothing is passed on, so this can be called before the server reads.
+    fn poll_probe(
+        &mut self,
+        io: &mut imp::Connection,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            match self {
+                LivenessResponder::Matching(matched) => {
+                    let matched = *matched;
+                    let mut chunk = [0u8; LIVENESS_PROBE.len()];
+                    let mut chunk = ReadBuf::new(&mut chunk[matched..]);
+                    ready!(Pin::new(&mut *io).poll_read(cx, &mut chunk))?;
+                    *self = LivenessResponder::after(matched, chunk.filled());
+                }
+                LivenessResponder::Answering(written) if *written < LIVENESS_ANSWER.len() => {
+                    let n =
+                        ready!(Pin::new(&mut *io).poll_write(cx, &LIVENESS_ANSWER[*written..]))?;
+                    if n == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                    }
+                    *written += n;
+                }
+                LivenessResponder::Answering(_) => {
+                    ready!(Pin::new(&mut *io).poll_flush(cx))?;
+                    *self = LivenessResponder::Answered;
+                }
+                _ => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+
+    /// Read from `io` into `buf`, unless the peer sent a probe, which is answered instead.
+    pub(crate) fn poll_read(
+        &mut self,
+        io: &mut imp::Connection,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if buf.remaining() == 0 {
+            // Nothing could be passed on, and returning here does not look like the end
+            return Poll::Ready(Ok(()));
+        }
+        ready!(self.poll_probe(io, cx))?;
+        match self {
+            LivenessResponder::Replaying { held, pos, len } => {
+                let n = (*len - *pos).min(buf.remaining());
+                buf.put_slice(&held[*pos..*pos + n]);
+                *pos += n;
+                if *pos == *len {
+                    *self = LivenessResponder::Passthrough;
+                }
+                Poll::Ready(Ok(()))
+            }
+            LivenessResponder::Passthrough => Pin::new(io).poll_read(cx, buf),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// Wait up to [`PROBE_WINDOW`] for an accepted `connection` to send a probe, and answer it.
+/// Returns the connection if it is not a probe, or if it is still undecided once the window has
+/// passed, so that probes are answered even if the server never reads.
+#[cfg(feature = "server")]
+pub(crate) async fn answer_probe(mut connection: crate::Connection) -> Option<crate::Connection> {
+    let Some(mut responder) = connection.liveness.take() else {
+        return Some(connection);
+    };
+    let probe = std::future::poll_fn(|cx| {
+        match ready!(responder.poll_probe(&mut connection.inner, cx)) {
+            // Pass the connection on, so that the server sees the failure when it reads
+            Err(_) => Poll::Ready(()),
+            Ok(()) if responder.is_decided() => Poll::Ready(()),
+            Ok(()) => Poll::Pending,
+        }
+    });
+    let _ = tokio::time::timeout(PROBE_WINDOW, probe).await;
+
+    if let LivenessResponder::Answering(_) = responder {
+        let answer = std::future::poll_fn(|cx| responder.poll_probe(&mut connection.inner, cx));
+        let _ = tokio::time::timeout(ANSWER_TIMEOUT, answer).await;
+        return None;
+    }
+    if responder.is_answered() {
+        return None;
+    }
+    connection.liveness = Some(responder);
+    Some(connection)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ping() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+
+        let server = tokio::spawn(async move { server.read_frame().await });
+        client.ping().await.unwrap();
+
+        client
+            .write_frame(&Frame::data(&b"hello"[..]))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.await.unwrap().unwrap(),
+            Some(Frame::data(&b"hello"[..]))
+        );
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_liveness() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut endpoint = crate::testing::EphemeralEndpoint::with_options(|endpoint| {
+            endpoint.set_liveness_responder(true)
+        })
+        .unwrap();
+        let path = endpoint.path().to_owned();
+        let server = tokio::spawn(async move {
+            let mut received = Vec::new();
+            for _ in 0..3 {
+                let mut connection = endpoint.accept().await.unwrap();
+                let mut data = Vec::new();
+                connection.read_to_end(&mut data).await.unwrap();
+                received.push(data);
+            }
+            received
+        });
+
+        // Probes are answered while accepting, without being passed on to the server
+        check_liveness(&path).await.unwrap();
+        // Bytes that only begin like a probe are passed on
+        let mut client = Endpoint::connect(&path).await.unwrap();
+        client.write_all(b"\xffLIVE").await.unwrap();
+        client.write_all(b"LY").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut client = Endpoint::connect(&path).await.unwrap();
+        client.write_all(&[0, 0, 0, 0]).await.unwrap();
+        client.shutdown().await.unwrap();
+        // Probes that arrive after the window are answered once the server reads
+        let mut client = Endpoint::connect(&path).await.unwrap();
+        client.write_all(&LIVENESS_PROBE[..5]).await.unwrap();
+        tokio::time::sleep(PROBE_WINDOW * 2).await;
+        client.write_all(&LIVENESS_PROBE[5..]).await.unwrap();
+        let mut answer = [0u8; LIVENESS_ANSWER.len()];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, *LIVENESS_ANSWER);
+
+        assert_eq!(
+            server.await.unwrap(),
+            [b"\xffLIVELY".to_vec(), vec![0, 0, 0, 0], b"".to_vec()]
+        );
+    }
+}
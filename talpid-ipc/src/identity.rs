@@ -0,0 +1,735 @@
+//! Identity of the client on the other end of a named pipe, as found in its access token.
+//!
+//! The security descriptor of the pipe decides who may open it. An [`SidAllowlist`] adds a
+//! second check at runtime, which also covers pipes whose descriptor is too permissive, e.g.
+//! because another process created the first instance.
+//!
+//! Clients can check the server in turn, with [`crate::ConnectOptions::require_server_user`] and
+//! friends. Any process may create an instance of a pipe whose name is not taken, or one whose
+//! descriptor lets it, so a process that squats the name of the daemon's pipe could otherwise
+//! impersonate the daemon. A valid signature alone proves little, so
+//! [`crate::ConnectOptions::require_server_signer`] also pins who signed the executable, by the
+//! subject or the thumbprint of the signing certificate.
+
+use crate::imp::Connection;
+#[cfg(feature = "server")]
+use std::time::Duration;
+use std::{
+    collections::HashSet,
+    ffi::{OsString, c_void},
+    io, iter, mem,
+    os::windows::{
+        ffi::{OsStrExt, OsStringExt},
+        io::{AsRawHandle, FromRawHandle, OwnedHandle, RawHandle},
+    },
+    path::{Path, PathBuf},
+    ptr, slice,
+    sync::Arc,
+};
+#[cfg(feature = "server")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+use windows_sys::{
+    Win32::{
+        Foundation::{HANDLE, LocalFree, PSID},
+        Security::{
+            Authorization::ConvertSidToStringSidW,
+            Cryptography::{
+                CERT_NAME_SIMPLE_DISPLAY_TYPE, CERT_SHA1_HASH_PROP_ID,
+                CertGetCertificateContextProperty, CertGetNameStringW,
+            },
+            GetTokenInformation, SID_AND_ATTRIBUTES, TOKEN_GROUPS, TOKEN_INFORMATION_CLASS,
+            TOKEN_QUERY, TOKEN_USER, TokenGroups, TokenUser,
+            WinTrust::{
+                WINTRUST_ACTION_GENERIC_VERIFY_V2, WINTRUST_DATA, WINTRUST_FILE_INFO,
+                WTD_CHOICE_FILE, WTD_REVOKE_NONE, WTD_STATEACTION_CLOSE, WTD_STATEACTION_VERIFY,
+                WTD_UI_NONE, WTHelperGetProvSignerFromChain, WTHelperProvDataFromStateData,
+                WinVerifyTrust,
+            },
+        },
+        System::{
+            Pipes::{
+                GetNamedPipeClientProcessId, GetNamedPipeClientSessionId,
+                GetNamedPipeServerProcessId,
+            },
+            Threading::{
+                GetCurrentProcess, GetCurrentThread, OpenProcess, OpenProcessToken,
+                OpenThreadToken, PROCESS_NAME_WIN32, PROCESS_QUERY_LIMITED_INFORMATION,
+                QueryFullProcessImageNameW,
+            },
+        },
+    },
+    core::PWSTR,
+};
+
+/// The group is enabled in the token.
+const SE_GROUP_ENABLED: u32 = 0x4;
+/// The group may only be used to deny access.
+const SE_GROUP_USE_FOR_DENY_ONLY: u32 = 0x10;
+
+/// Longest path of an executable that is looked up, in wide characters.
+const MAX_IMAGE_PATH_LEN: usize = 32 * 1024;
+
+/// Longest subject of a certificate that is looked up, in wide characters.
+const MAX_SUBJECT_LEN: usize = 1024;
+
+/// Length of the SHA-1 thumbprint of a certificate.
+pub const THUMBPRINT_LEN: usize = 20;
+
+/// Subject of the certificate that Mullvad signs its executables with.
+pub const MULLVAD_SIGNER_SUBJECT: &str = "Mullvad VPN AB";
+
+/// How long the client of an endpoint with an [`SidAllowlist`] has to send something. It can
+/// only be impersonated once something has been read from the pipe.
+#[cfg(feature = "server")]
+const IMPERSONATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most connections whose clients may be waited for at the same time, see
+/// [`IMPERSONATION_TIMEOUT`]. Further clients find every instance of the pipe busy, and wait
+/// until one is free, so that clients that never send anything cannot make the server hold on
+/// to any number of connections.
+#[cfg(feature = "server")]
+pub(crate) const MAX_IMPERSONATING: usize = 64;
+
+/// Users and groups that are allowed to connect, identified by SID strings such as `S-1-5-18`.
+/// A peer is allowed if its user or any of its enabled groups is in the list. See
+/// [`crate::Endpoint::set_sid_allowlist`].
+#[derive(Debug, Clone, Default)]
+pub struct SidAllowlist {
+    sids: HashSet<String>,
+}
+
+impl SidAllowlist {
+    /// Create an allowlist that allows no one.
+    pub fn new() -> Self {
+        SidAllowlist::default()
+    }
+
+    /// Allow the user or group `sid`.
+    pub fn allow_sid(mut self, sid: impl Into<String>) -> Self {
+        self.sids.insert(sid.into());
+        self
+    }
+
+    fn allows(&self, user: &str, groups: &[String]) -> bool {
+        self.sids.contains(user) || groups.iter().any(|group| self.sids.contains(group))
+    }
+}
+
+/// User and groups of a client, and whether they were allowed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    user: String,
+    groups: Vec<String>,
+    allowed: Option<bool>,
+}
+
+impl PeerIdentity {
+    /// SID of the user of the client.
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// SIDs of the enabled groups of the client.
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    /// Whether the client was allowed by the [`SidAllowlist`], or `None` if no allowlist was
+    /// configured.
+    pub fn allowed(&self) -> Option<bool> {
+        self.allowed
+    }
+
+    fn from_token(token: &OwnedHandle, allowlist: Option<&SidAllowlist>) -> io::Result<Self> {
+        let user = token_user(token)?;
+        let groups = token_groups(token)?;
+        let allowed = allowlist.map(|allowlist| allowlist.allows(&user, &groups));
+        Ok(PeerIdentity {
+            user,
+            groups,
+            allowed,
+        })
+    }
+}
+
+impl crate::Connection {
+    /// Identity of the client, as evaluated when the connection was accepted. `None` unless an
+    /// [`SidAllowlist`] has been configured.
+    pub fn peer_identity(&self) -> Option<&PeerIdentity> {
+        self.identity.as_ref()
+    }
+
+    /// Evaluate the identity of the client again, from the token obtained by impersonating it,
+    /// e.g. on a connection that was accepted without an [`SidAllowlist`].
+    ///
+    /// The client can only be impersonated once some data has been read from the pipe, e.g.
+    /// after the handshake. Returns the new identity, which also replaces the one returned by
+    /// [`Self::peer_identity`].
+    pub fn verify_peer_identity(&mut self) -> io::Result<&PeerIdentity> {
+        let token = self.impersonate_client(thread_token)??;
+        let identity = PeerIdentity::from_token(&token, self.sid_allowlist.as_deref())?;
+        Ok(self.identity.insert(identity))
+    }
+
+    /// Like [`Self::verify_peer_identity`], but first waits for the client to send something
+    /// if nothing has been read yet, like [`impersonate_first`]. What was read is put back, to
+    /// be read by the server.
+    #[cfg(feature = "server")]
+    pub(crate) async fn identify_client(&mut self) -> io::Result<()> {
+        if self.unread.is_empty() {
+            let first = read_first(self).await?;
+            self.unread.extend_from_slice(&[first]);
+        }
+        self.verify_peer_identity()?;
+        Ok(())
+    }
+
+    /// ID of the Remote Desktop Services session that the client runs in. On a machine with
+    /// several users logged on, this tells e.g. which of their user interfaces a notification
+    /// belongs to. Only the server end of a pipe knows the session of its client.
+    pub fn peer_session_id(&self) -> io::Result<u32> {
+        let Connection::Server(server) = &self.inner else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only the server end of a pipe can identify its client",
+            ));
+        };
+        let mut session_id = 0;
+        // SAFETY: The handle is a valid named pipe handle for the lifetime of `server`, and
+        // `session_id` is a valid out pointer
+        if unsafe { GetNamedPipeClientSessionId(server.as_raw_handle() as HANDLE, &mut session_id) }
+            == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(session_id)
+    }
+}
+
+/// Wait for the client of `connection` to send its first byte, and evaluate its identity from
+/// the token obtained by impersonating it. Unlike the token of the client process, this cannot
+/// belong to another process that reused the ID of the client after it exited. Returns the byte,
+/// which the server has yet to read.
+#[cfg(feature = "server")]
+pub(crate) async fn impersonate_first(
+    connection: &mut Connection,
+    allowlist: &SidAllowlist,
+) -> io::Result<(PeerIdentity, u8)> {
+    let first = read_first(connection).await?;
+    let token = crate::imp::impersonate(connection, thread_token)??;
+    Ok((PeerIdentity::from_token(&token, Some(allowlist))?, first))
+}
+
+/// Wait at most [`IMPERSONATION_TIMEOUT`] for the client to send its first byte, and return it.
+#[cfg(feature = "server")]
+async fn read_first(connection: &mut (impl AsyncRead + Unpin)) -> io::Result<u8> {
+    let mut first = [0u8; 1];
+    let read = tokio::time::timeout(IMPERSONATION_TIMEOUT, connection.read(&mut first))
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                "The client did not send anything to be impersonated by",
+            )
+        })??;
+    if read == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "The client closed the connection without sending anything",
+        ));
+    }
+    Ok(first[0])
+}
+
+/// Open the access token of the current thread, which is the token of the client while
+/// impersonating it.
+fn thread_token() -> io::Result<OwnedHandle> {
+    let mut token: HANDLE = 0;
+    // SAFETY: `token` is a valid out pointer
+    if unsafe { OpenThreadToken(GetCurrentThread(), TOKEN_QUERY, 1, &mut token) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: The handle was just opened, and is owned by nothing else
+    Ok(unsafe { OwnedHandle::from_raw_handle(token as RawHandle) })
+}
+
+/// Look up the identity of the client process of `connection`.
+pub(crate) fn client_identity(
+    connection: &Connection,
+    allowlist: Option<&Arc<SidAllowlist>>,
+) -> io::Result<PeerIdentity> {
+    let token = process_token(client_process_id(connection)?)?;
+    PeerIdentity::from_token(&token, allowlist.map(Arc::as_ref))
+}
+
+/// Who must have signed the executable of the server, see
+/// [`crate::ConnectOptions::require_server_signer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerSigner {
+    /// The simple display name of the subject of the signing certificate, usually its common
+    /// name, compared ignoring case.
+    Subject(String),
+    /// The SHA-1 thumbprint of the signing certificate, which only matches that certificate,
+    /// and not the one that replaces it once it expires.
+    Thumbprint([u8; THUMBPRINT_LEN]),
+}
+
+impl ServerSigner {
+    /// The signer of the executables of Mullvad.
+    pub fn mullvad() -> Self {
+        ServerSigner::Subject(MULLVAD_SIGNER_SUBJECT.to_owned())
+    }
+
+    fn matches(&self, certificate: &SigningCertificate) -> bool {
+        match self {
+            ServerSigner::Subject(subject) => certificate.subject.eq_ignore_ascii_case(subject),
+            ServerSigner::Thumbprint(thumbprint) => certificate.thumbprint == *thumbprint,
+        }
+    }
+}
+
+/// The certificate that an executable was signed with.
+#[derive(Debug)]
+struct SigningCertificate {
+    subject: String,
+    thumbprint: [u8; THUMBPRINT_LEN],
+}
+
+/// What a client requires of the server process, see [`crate::ConnectOptions`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ServerRequirements {
+    pub(crate) user: Option<String>,
+    pub(crate) image: Option<PathBuf>,
+    pub(crate) signer: Option<ServerSigner>,
+}
+
+impl ServerRequirements {
+    fn is_empty(&self) -> bool {
+        self.user.is_none() && self.image.is_none() && self.signer.is_none()
+    }
+
+    /// Fail with [`io::ErrorKind::PermissionDenied`] unless the server process of
+    /// `connection` meets the requirements.
+    pub(crate) fn verify(&self, connection: &Connection) -> io::Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let pid = server_process_id(connection)?;
+        if let Some(expected) = &self.user {
+            let user = token_user(&process_token(pid)?)?;
+            if user != *expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("The server process {pid} runs as {user}, not {expected}"),
+                ));
+            }
+        }
+        if self.image.is_none() && self.signer.is_none() {
+            return Ok(());
+        }
+        let image = process_image(pid)?;
+        if let Some(expected) = &self.image {
+            // Paths are case-insensitive
+            let matches =
+                image.to_string_lossy().to_lowercase() == expected.to_string_lossy().to_lowercase();
+            if !matches {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "The server process {pid} runs {}, not {}",
+                        image.display(),
+                        expected.display()
+                    ),
+                ));
+            }
+        }
+        if let Some(expected) = &self.signer {
+            let certificate = verify_signature(&image).map_err(|error| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("The executable of the server process {pid} is not signed: {error}"),
+                )
+            })?;
+            if !expected.matches(&certificate) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "The executable of the server process {pid} is signed by {}, not by \
+                         {expected:?}",
+                        certificate.subject
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Return the ID of the server process of `connection`, which must be the client end of a pipe.
+fn server_process_id(connection: &Connection) -> io::Result<u32> {
+    let Connection::Client(client) = connection else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Only the client end of a pipe can identify its server",
+        ));
+    };
+    let mut pid = 0;
+    // SAFETY: The handle is a valid named pipe handle for the lifetime of `client`
+    if unsafe { GetNamedPipeServerProcessId(client.as_raw_handle() as HANDLE, &mut pid) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(pid)
+}
+
+/// Fail unless the file at `path` has a valid Authenticode signature, and return the
+/// certificate that it was signed with. Revocation is not checked, since that may need the
+/// network.
+fn verify_signature(path: &Path) -> io::Result<SigningCertificate> {
+    let path: Vec<u16> = path
+        .as_os_str()
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect();
+    // SAFETY: These are plain C structs, for which all zeroes is a valid value
+    let mut file: WINTRUST_FILE_INFO = unsafe { mem::zeroed() };
+    file.cbStruct = mem::size_of::<WINTRUST_FILE_INFO>() as u32;
+    file.pcwszFilePath = path.as_ptr();
+    // SAFETY: See above
+    let mut data: WINTRUST_DATA = unsafe { mem::zeroed() };
+    data.cbStruct = mem::size_of::<WINTRUST_DATA>() as u32;
+    data.dwUIChoice = WTD_UI_NONE;
+    data.fdwRevocationChecks = WTD_REVOKE_NONE;
+    data.dwUnionChoice = WTD_CHOICE_FILE;
+    data.Anonymous.pFile = &mut file;
+    data.dwStateAction = WTD_STATEACTION_VERIFY;
+
+    let mut action = WINTRUST_ACTION_GENERIC_VERIFY_V2;
+    // SAFETY: `data` is initialized for verifying the file, and `file` and `path` outlive the
+    // call
+    let status = unsafe {
+        WinVerifyTrust(
+            0,
+            &mut action,
+            &mut data as *mut WINTRUST_DATA as *mut c_void,
+        )
+    };
+    let certificate = match status {
+        0 => signing_certificate(data.hWVTStateData),
+        status => Err(io::Error::from_raw_os_error(status)),
+    };
+    // Release the state that verifying allocated
+    data.dwStateAction = WTD_STATEACTION_CLOSE;
+    // SAFETY: As above, with the state that the first call returned
+    unsafe {
+        WinVerifyTrust(
+            0,
+            &mut action,
+            &mut data as *mut WINTRUST_DATA as *mut c_void,
+        )
+    };
+    certificate
+}
+
+/// Return the certificate of the first signer in `state`, which a successful verification
+/// returned.
+fn signing_certificate(state: HANDLE) -> io::Result<SigningCertificate> {
+    let missing = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The signature has no signing certificate",
+        )
+    };
+    // SAFETY: `state` is the state of a verification that has not been closed yet
+    let provider = unsafe { WTHelperProvDataFromStateData(state) };
+    if provider.is_null() {
+        return Err(missing());
+    }
+    // SAFETY: `provider` is valid until the state is closed
+    let signer = unsafe { WTHelperGetProvSignerFromChain(provider, 0, 0, 0) };
+    if signer.is_null() {
+        return Err(missing());
+    }
+    // SAFETY: `signer` is valid until the state is closed, and so is its chain of
+    // `csCertChain` certificates, the first of which is the one of the signer
+    let certificate = unsafe {
+        let signer = &*signer;
+        if signer.csCertChain == 0 || signer.pasCertChain.is_null() {
+            return Err(missing());
+        }
+        (*signer.pasCertChain).pCert
+    };
+    if certificate.is_null() {
+        return Err(missing());
+    }
+
+    let mut subject = vec![0u16; MAX_SUBJECT_LEN];
+    // SAFETY: `certificate` is valid until the state is closed, and `subject` is valid for
+    // writes of its length
+    let len = unsafe {
+        CertGetNameStringW(
+            certificate,
+            CERT_NAME_SIMPLE_DISPLAY_TYPE,
+            0,
+            ptr::null(),
+            subject.as_mut_ptr(),
+            subject.len() as u32,
+        )
+    };
+    // The length includes the terminating null
+    let subject = String::from_utf16_lossy(&subject[..(len as usize).saturating_sub(1)]);
+
+    let mut thumbprint = [0u8; THUMBPRINT_LEN];
+    let mut thumbprint_len = thumbprint.len() as u32;
+    // SAFETY: As above, and `thumbprint` is valid for writes of `thumbprint_len` bytes
+    let found = unsafe {
+        CertGetCertificateContextProperty(
+            certificate,
+            CERT_SHA1_HASH_PROP_ID,
+            thumbprint.as_mut_ptr().cast(),
+            &mut thumbprint_len,
+        )
+    };
+    if found == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if thumbprint_len as usize != THUMBPRINT_LEN {
+        return Err(missing());
+    }
+    Ok(SigningCertificate {
+        subject,
+        thumbprint,
+    })
+}
+
+/// Return the ID of the client process of `connection`.
+pub(crate) fn client_process_id(connection: &Connection) -> io::Result<u32> {
+    let Connection::Server(server) = connection else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Only the server end of a pipe can identify its client",
+        ));
+    };
+    let mut pid = 0;
+    // SAFETY: The handle is a valid named pipe handle for the lifetime of `server`
+    if unsafe { GetNamedPipeClientProcessId(server.as_raw_handle() as HANDLE, &mut pid) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(pid)
+}
+
+/// Return the path of the executable of the process `pid`.
+pub(crate) fn process_image(pid: u32) -> io::Result<PathBuf> {
+    let process = open_process(pid)?;
+    let mut path = vec![0u16; MAX_IMAGE_PATH_LEN];
+    let mut len = path.len() as u32;
+    // SAFETY: `process` is a valid process handle, `path` is valid for writes of `len` wide
+    // characters, and `len` is a valid in and out pointer
+    if unsafe {
+        QueryFullProcessImageNameW(
+            process.as_raw_handle() as HANDLE,
+            PROCESS_NAME_WIN32,
+            path.as_mut_ptr(),
+            &mut len,
+        )
+    } == 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    path.truncate(len as usize);
+    Ok(PathBuf::from(OsString::from_wide(&path)))
+}
+
+/// Return the SID of the user that runs the process `pid`.
+pub(crate) fn process_user(pid: u32) -> io::Result<String> {
+    token_user(&process_token(pid)?)
+}
+
+/// Open the access token of the process `pid`.
+fn process_token(pid: u32) -> io::Result<OwnedHandle> {
+    let process = open_process(pid)?;
+    let mut token: HANDLE = 0;
+    // SAFETY: `process` is a valid process handle, and `token` is a valid out pointer
+    if unsafe { OpenProcessToken(process.as_raw_handle() as HANDLE, TOKEN_QUERY, &mut token) } == 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: The handle was just opened, and is owned by nothing else
+    Ok(unsafe { OwnedHandle::from_raw_handle(token as RawHandle) })
+}
+
+fn open_process(pid: u32) -> io::Result<OwnedHandle> {
+    // SAFETY: Opening a process has no preconditions
+    let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+    if process == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: The handle was just opened, and is owned by nothing else
+    Ok(unsafe { OwnedHandle::from_raw_handle(process as RawHandle) })
+}
+
+/// Return the SID of the user that runs this process.
+pub(crate) fn current_user() -> io::Result<String> {
+    let mut token: HANDLE = 0;
+    // SAFETY: The pseudo handle of the current process is always valid, and `token` is a valid
+    // out pointer
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: The handle was just opened, and is owned by nothing else
+    let token = unsafe { OwnedHandle::from_raw_handle(token as RawHandle) };
+    token_user(&token)
+}
+
+/// Return the SID of the user of an access token.
+fn token_user(token: &OwnedHandle) -> io::Result<String> {
+    let buffer = token_information(token, TokenUser)?;
+    // SAFETY: The buffer starts with a `TOKEN_USER`, whose SID points into the buffer
+    let user = unsafe { &*buffer.as_ptr().cast::<TOKEN_USER>() };
+    sid_to_string(user.User.Sid)
+}
+
+/// Return the SIDs of the enabled groups of an access token.
+fn token_groups(token: &OwnedHandle) -> io::Result<Vec<String>> {
+    let buffer = token_information(token, TokenGroups)?;
+    // SAFETY: The buffer starts with a `TOKEN_GROUPS`, which is followed by `GroupCount` entries
+    // whose SIDs point into the buffer
+    let groups: &[SID_AND_ATTRIBUTES] = unsafe {
+        let groups = buffer.as_ptr().cast::<TOKEN_GROUPS>();
+        slice::from_raw_parts(
+            ptr::addr_of!((*groups).Groups).cast::<SID_AND_ATTRIBUTES>(),
+            (*groups).GroupCount as usize,
+        )
+    };
+    groups
+        .iter()
+        .filter(|group| {
+            group.Attributes & SE_GROUP_ENABLED != 0
+                && group.Attributes & SE_GROUP_USE_FOR_DENY_ONLY == 0
+        })
+        .map(|group| sid_to_string(group.Sid))
+        .collect()
+}
+
+/// Query a variable-length information class of an access token.
+fn token_information(token: &OwnedHandle, class: TOKEN_INFORMATION_CLASS) -> io::Result<Vec<u64>> {
+    let token = token.as_raw_handle() as HANDLE;
+    let mut len = 0;
+    // SAFETY: Querying the required length with an empty buffer is allowed
+    unsafe { GetTokenInformation(token, class, ptr::null_mut(), 0, &mut len) };
+    // `u64` elements ensure that the structures in the buffer are aligned
+    let mut buffer = vec![0u64; (len as usize).div_ceil(mem::size_of::<u64>())];
+    // SAFETY: `buffer` is at least `len` bytes long
+    let result =
+        unsafe { GetTokenInformation(token, class, buffer.as_mut_ptr().cast(), len, &mut len) };
+    if result == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(buffer)
+}
+
+fn sid_to_string(sid: PSID) -> io::Result<String> {
+    let mut string: PWSTR = ptr::null_mut();
+    // SAFETY: `sid` is a valid SID, and `string` is a valid out pointer
+    if unsafe { ConvertSidToStringSidW(sid, &mut string) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: On success, `string` is a null-terminated wide string allocated with `LocalAlloc`
+    let sid = unsafe {
+        let len = (0..).take_while(|&i| *string.add(i) != 0).count();
+        let sid = String::from_utf16_lossy(slice::from_raw_parts(string, len));
+        LocalFree(string as _);
+        sid
+    };
+    Ok(sid)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allowlist() {
+        let allowlist = SidAllowlist::new().allow_sid("S-1-5-32-544");
+        assert!(allowlist.allows("S-1-5-21-1-2-3-1001", &["S-1-5-32-544".to_owned()]));
+        assert!(!allowlist.allows("S-1-5-21-1-2-3-1001", &["S-1-5-32-545".to_owned()]));
+        assert!(
+            SidAllowlist::new()
+                .allow_sid("S-1-5-18")
+                .allows("S-1-5-18", &[])
+        );
+    }
+
+    #[test]
+    fn test_signer() {
+        let certificate = SigningCertificate {
+            subject: "Mullvad VPN AB".to_owned(),
+            thumbprint: [1; THUMBPRINT_LEN],
+        };
+        assert!(ServerSigner::mullvad().matches(&certificate));
+        assert!(ServerSigner::Subject("mullvad vpn ab".to_owned()).matches(&certificate));
+        assert!(!ServerSigner::Subject("Mullvad".to_owned()).matches(&certificate));
+        assert!(ServerSigner::Thumbprint([1; THUMBPRINT_LEN]).matches(&certificate));
+        assert!(!ServerSigner::Thumbprint([2; THUMBPRINT_LEN]).matches(&certificate));
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_verify_server() {
+        let path = crate::imp::ephemeral_pipe_name().unwrap();
+        let mut incoming = crate::Endpoint::new(path.clone()).incoming().unwrap();
+        tokio::spawn(async move {
+            use futures::StreamExt;
+            while let Some(connection) = incoming.next().await {
+                drop(connection);
+            }
+        });
+        let user = token_user(&process_token(std::process::id()).unwrap()).unwrap();
+
+        let mut options = crate::ConnectOptions::new();
+        options.require_server_user(user);
+        options.require_server_image(std::env::current_exe().unwrap());
+        crate::Endpoint::connect_with_options(&path, &options)
+            .await
+            .unwrap();
+
+        let mut options = crate::ConnectOptions::new();
+        options.require_server_image(r"C:\Windows\System32\cmd.exe");
+        let error = crate::Endpoint::connect_with_options(&path, &options)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_sid_allowlist() {
+        use futures::StreamExt;
+        use tokio::io::AsyncWriteExt;
+
+        let path = crate::imp::ephemeral_pipe_name().unwrap();
+        let mut endpoint = crate::Endpoint::new(path.clone());
+        endpoint.set_sid_allowlist(SidAllowlist::new().allow_sid(current_user().unwrap()));
+        let mut incoming = endpoint.incoming().unwrap();
+
+        let mut client = crate::Endpoint::connect(&path).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut connection = incoming.next().await.unwrap().unwrap();
+        assert_eq!(connection.peer_identity().unwrap().allowed(), Some(true));
+        // The byte that was read to impersonate the client is passed on
+        let mut greeting = [0u8; 5];
+        connection.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hello");
+
+        let path = crate::imp::ephemeral_pipe_name().unwrap();
+        let mut endpoint = crate::Endpoint::new(path.clone());
+        endpoint.set_sid_allowlist(SidAllowlist::new().allow_sid("S-1-5-21-1-2-3-1001"));
+        let mut incoming = endpoint.incoming().unwrap();
+        tokio::spawn(async move { while incoming.next().await.is_some() {} });
+
+        let mut client = crate::Endpoint::connect(&path).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut rest = Vec::new();
+        assert!(client.read_to_end(&mut rest).await.is_err() || rest.is_empty());
+    }
+}
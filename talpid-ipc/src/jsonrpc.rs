@@ -0,0 +1,579 @@
+//! JSON-RPC 2.0 on top of a plain connection, for tools and scripts that use an off-the-shelf
+//! JSON-RPC library rather than the native protocol of [`crate::rpc`].
+//!
+//! Messages are JSON documents separated by newlines, without any framing or handshake, so the
+//! mode is meant for an endpoint of its own. Requests are answered by the same handlers as in
+//! the native protocol. The `method` of a request names a variant of the request type, and its
+//! `params` are the contents of the variant, i.e. the request is decoded from
+//! `{"<method>": <params>}`, or from `"<method>"` if it has no params. The result of a response
+//! is the response encoded as JSON.
+//!
+//! A handler that fails is reported as an error with the code [`SERVER_ERROR`] and the message
+//! of the handler. A stream of responses, see [`Reply::Stream`], is sent as it becomes
+//! available, as notifications with the method `"stream"` and the params
+//! `{"id": <id of the request>, "result": <response>}`. The request is answered once the stream
+//! has ended, with the result `null`, or with the error that ended it. Notifications, i.e.
+//! requests without an ID, are handled but never answered. Batches are supported, and every
+//! request in them counts against [`DEFAULT_MAX_IN_FLIGHT`].
+//!
+//! Events are pushed as notifications with the method `"event"` and the event as its params.
+
+use crate::{
+    Error,
+    rpc::{DEFAULT_MAX_IN_FLIGHT, Reply},
+};
+use futures::{
+    FutureExt, Stream, StreamExt, future,
+    stream::{self, BoxStream, SelectAll},
+};
+use serde::{Deserialize, Deserializer, Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value, json};
+use std::{future::Future, sync::Arc};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    sync::Semaphore,
+};
+
+/// The message is not valid JSON.
+pub const PARSE_ERROR: i64 = -32700;
+/// The message is not a valid request object.
+pub const INVALID_REQUEST: i64 = -32600;
+/// The method and params do not decode into a request.
+pub const INVALID_PARAMS: i64 = -32602;
+/// The response could not be encoded.
+pub const INTERNAL_ERROR: i64 = -32603;
+/// The handler failed.
+pub const SERVER_ERROR: i64 = -32000;
+
+/// Longest message that is accepted, in bytes. Longer messages close the connection, since
+/// there is no way to skip them reliably.
+pub const MAX_MESSAGE_LEN: usize = crate::frame::DEFAULT_MAX_PAYLOAD_LEN;
+
+/// A request object, see the JSON-RPC 2.0 specification.
+#[derive(Deserialize)]
+struct RawRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+    /// `None` for notifications. An ID that is `null` is still an ID.
+    #[serde(default, deserialize_with = "present")]
+    id: Option<Value>,
+}
+
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+/// Something to write to the client while handling a message.
+enum Output {
+    /// A part of a stream of responses.
+    Notification(Value),
+    /// The answer to the message, or `None` if it is not answered.
+    Response(Option<Value>),
+    /// The message, which consisted of this many requests, has been handled.
+    Done(usize),
+}
+
+/// Answer JSON-RPC requests on `connection` with `handler`, and push `events` to the client,
+/// until the client closes the connection. Up to [`DEFAULT_MAX_IN_FLIGHT`] requests are
+/// handled concurrently, and each is answered as soon as it completes. This is the JSON-RPC
+/// counterpart of [`crate::rpc::serve`].
+pub async fn serve<T, Req, Resp, Event, H, F, E>(
+    connection: T,
+    mut handler: H,
+    events: E,
+) -> Result<(), Error>
+where
+    T: AsyncRead + AsyncWrite,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    Event: Serialize,
+    H: FnMut(Req) -> F,
+    F: Future<Output = Result<Resp, String>> + Send,
+    E: Stream<Item = Event> + Unpin,
+{
+    let handler = move |request| handler(request).map(|reply| reply.map(Reply::Single));
+    serve_streaming(connection, handler, events).await
+}
+
+/// Like [`serve`], but `handler` decides whether to answer each request with a single response
+/// or with a stream of them, like in [`crate::rpc::serve_streaming`].
+pub async fn serve_streaming<T, Req, Resp, Event, H, F, E>(
+    connection: T,
+    mut handler: H,
+    mut events: E,
+) -> Result<(), Error>
+where
+    T: AsyncRead + AsyncWrite,
+    Req: DeserializeOwned,
+    Resp: Serialize + Send,
+    Event: Serialize,
+    H: FnMut(Req) -> F,
+    F: Future<Output = Result<Reply<Resp>, String>> + Send,
+    E: Stream<Item = Event> + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(connection);
+    let mut reader = BufReader::new(reader);
+    // Kept across iterations, since reading may be interrupted in the middle of a message
+    let mut message = Vec::new();
+    let mut pending = SelectAll::new();
+    // Requests that have been read but not answered, counting every request of a batch. More
+    // are only read while there are fewer than the limit, and only the limit are handled at
+    // once, so that a large batch does not run all of its requests at the same time.
+    let mut requests = 0;
+    let permits = Arc::new(Semaphore::new(DEFAULT_MAX_IN_FLIGHT));
+    let mut reading = true;
+    let mut events_ended = false;
+
+    while reading || !pending.is_empty() {
+        tokio::select! {
+            read = read_message(&mut reader, &mut message),
+                if reading && requests < DEFAULT_MAX_IN_FLIGHT =>
+            {
+                if read? {
+                    if !message.iter().all(u8::is_ascii_whitespace) {
+                        let (count, outputs) = handle_message(&mut handler, &message, &permits);
+                        requests += count;
+                        pending.push(outputs);
+                    }
+                    message.clear();
+                } else {
+                    reading = false;
+                }
+            }
+            Some(output) = pending.next(), if !pending.is_empty() => match output {
+                Output::Notification(message) | Output::Response(Some(message)) => {
+                    write_message(&mut writer, &message).await?;
+                }
+                Output::Response(None) => (),
+                Output::Done(count) => requests -= count,
+            },
+            event = events.next(), if !events_ended => match event {
+                Some(event) => {
+                    let notification = event_notification(&event)?;
+                    write_message(&mut writer, &notification).await?;
+                }
+                None => events_ended = true,
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Read the next message into `message`. Returns `false` once the connection has been closed
+/// and nothing more is left.
+async fn read_message<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    message: &mut Vec<u8>,
+) -> Result<bool, Error> {
+    let limit = (MAX_MESSAGE_LEN + 1).saturating_sub(message.len());
+    let read = reader.take(limit as u64).read_until(b'\n', message).await?;
+    if message.len() > MAX_MESSAGE_LEN {
+        return Err(Error::FrameExceedsLimit {
+            len: message.len(),
+            max: MAX_MESSAGE_LEN,
+        });
+    }
+    Ok(read > 0 || !message.is_empty())
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Value,
+) -> Result<(), Error> {
+    let mut bytes = serde_json::to_vec(message).map_err(|error| Error::Codec(Box::new(error)))?;
+    bytes.push(b'\n');
+    writer.write_all(&bytes).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+fn to_json(value: &impl Serialize) -> Result<Value, Error> {
+    serde_json::to_value(value).map_err(|error| Error::Codec(Box::new(error)))
+}
+
+/// Handle a request or a batch of them. Returns the number of requests, and what to write to the
+/// client, which ends with [`Output::Done`].
+fn handle_message<'a, Req, Resp, H, F>(
+    handler: &mut H,
+    message: &[u8],
+    permits: &Arc<Semaphore>,
+) -> (usize, BoxStream<'a, Output>)
+where
+    Req: DeserializeOwned,
+    Resp: Serialize + Send + 'a,
+    H: FnMut(Req) -> F,
+    F: Future<Output = Result<Reply<Resp>, String>> + Send + 'a,
+{
+    let (count, outputs) = match serde_json::from_slice(message) {
+        Ok(Value::Array(batch)) if batch.is_empty() => {
+            let error = failure(Value::Null, INVALID_REQUEST, "Empty batch");
+            (1, answer(Some(error)))
+        }
+        Ok(Value::Array(batch)) => {
+            let count = batch.len();
+            let calls = batch
+                .into_iter()
+                .map(|request| handle_request(handler, request, permits))
+                .collect();
+            (count, answer_batch(calls))
+        }
+        Ok(request) => (1, handle_request(handler, request, permits)),
+        Err(error) => {
+            let error = failure(Value::Null, PARSE_ERROR, error.to_string());
+            (1, answer(Some(error)))
+        }
+    };
+    let done = stream::once(future::ready(Output::Done(count)));
+    (count, outputs.chain(done).boxed())
+}
+
+fn answer<'a>(response: Option<Value>) -> BoxStream<'a, Output> {
+    stream::once(future::ready(Output::Response(response))).boxed()
+}
+
+/// Pass on the notifications of the requests of a batch as they come, and answer all of the
+/// requests together, in the order of the batch, once they have been handled.
+fn answer_batch<'a>(calls: Vec<BoxStream<'a, Output>>) -> BoxStream<'a, Output> {
+    let calls = stream::select_all(
+        calls
+            .into_iter()
+            .enumerate()
+            .map(|(index, call)| call.map(move |output| (index, output))),
+    );
+    stream::unfold(Some((calls, Vec::new())), |state| async move {
+        let (mut calls, mut responses) = state?;
+        while let Some((index, output)) = calls.next().await {
+            match output {
+                Output::Response(response) => responses.extend(response.map(|r| (index, r))),
+                output => return Some((output, Some((calls, responses)))),
+            }
+        }
+        responses.sort_by_key(|(index, _)| *index);
+        let responses: Vec<_> = responses
+            .into_iter()
+            .map(|(_, response)| response)
+            .collect();
+        let responses = (!responses.is_empty()).then_some(Value::Array(responses));
+        Some((Output::Response(responses), None))
+    })
+    .boxed()
+}
+
+fn handle_request<'a, Req, Resp, H, F>(
+    handler: &mut H,
+    request: Value,
+    permits: &Arc<Semaphore>,
+) -> BoxStream<'a, Output>
+where
+    Req: DeserializeOwned,
+    Resp: Serialize + Send + 'a,
+    H: FnMut(Req) -> F,
+    F: Future<Output = Result<Reply<Resp>, String>> + Send + 'a,
+{
+    let request: RawRequest = match serde_json::from_value(request) {
+        Ok(request) => request,
+        Err(error) => {
+            return answer(Some(failure(
+                Value::Null,
+                INVALID_REQUEST,
+                error.to_string(),
+            )));
+        }
+    };
+    let id = request.id;
+    if request.jsonrpc != "2.0" {
+        let error = failure(
+            id.unwrap_or(Value::Null),
+            INVALID_REQUEST,
+            "Only JSON-RPC 2.0 is supported",
+        );
+        return answer(Some(error));
+    }
+
+    let native = match request.params {
+        None | Some(Value::Null) => Value::String(request.method),
+        Some(params) => Value::Object(Map::from_iter([(request.method, params)])),
+    };
+    let request = match serde_json::from_value(native) {
+        Ok(request) => request,
+        Err(error) => {
+            let message = format!("Invalid method or params: {error}");
+            return answer(id.map(|id| failure(id, INVALID_PARAMS, message)));
+        }
+    };
+    let reply = handler(request);
+    let permits = permits.clone();
+
+    stream::once(async move {
+        // Held until the request has been answered
+        let permit = permits.acquire_owned().await.ok();
+        (permit, reply.await)
+    })
+    .flat_map(move |(permit, reply)| {
+        let id = id.clone();
+        let responses = match reply {
+            Ok(Reply::Single(response)) => {
+                let result = to_json(&response).map_err(|error| error.to_string());
+                return answer(id.map(|id| match result {
+                    Ok(result) => json!({"jsonrpc": "2.0", "result": result, "id": id}),
+                    Err(message) => failure(id, INTERNAL_ERROR, message),
+                }));
+            }
+            Ok(Reply::Stream(responses)) => responses,
+            Err(message) => return answer(id.map(|id| failure(id, SERVER_ERROR, message))),
+        };
+        responses
+            .map(Some)
+            .chain(stream::iter([None]))
+            .scan(false, move |ended, response| {
+                let _permit = &permit;
+                let output = (!*ended).then(|| stream_output(id.as_ref(), response, ended));
+                future::ready(output)
+            })
+            .filter_map(future::ready)
+            .boxed()
+    })
+    .boxed()
+}
+
+/// What to send for a part of the stream of responses to the request `id`, or for the end of
+/// the stream if `response` is `None`. Sets `ended` if the stream fails. The parts are not sent
+/// if the request is a notification.
+fn stream_output<Resp: Serialize>(
+    id: Option<&Value>,
+    response: Option<Result<Resp, String>>,
+    ended: &mut bool,
+) -> Option<Output> {
+    let result = match response {
+        Some(Ok(response)) => {
+            to_json(&response).map_err(|error| (INTERNAL_ERROR, error.to_string()))
+        }
+        Some(Err(message)) => Err((SERVER_ERROR, message)),
+        None => {
+            let done = id.map(|id| json!({"jsonrpc": "2.0", "result": null, "id": id}));
+            return Some(Output::Response(done));
+        }
+    };
+    match result {
+        Ok(result) => {
+            let params = json!({"id": id?, "result": result});
+            Some(Output::Notification(
+                json!({"jsonrpc": "2.0", "method": "stream", "params": params}),
+            ))
+        }
+        Err((code, message)) => {
+            *ended = true;
+            Some(Output::Response(
+                id.map(|id| failure(id.clone(), code, message)),
+            ))
+        }
+    }
+}
+
+fn event_notification(event: &impl Serialize) -> Result<Value, Error> {
+    let params = to_json(event)?;
+    Ok(json!({"jsonrpc": "2.0", "method": "event", "params": params}))
+}
+
+fn failure(id: Value, code: i64, message: impl Into<String>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": {"code": code, "message": message.into()},
+        "id": id,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::DuplexStream;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    enum Request {
+        Double(u32),
+        Ping,
+    }
+
+    async fn handle(request: Request) -> Result<u32, String> {
+        match request {
+            Request::Double(0) => Err("Zero is not allowed".to_owned()),
+            Request::Double(n) => Ok(n * 2),
+            Request::Ping => Ok(1),
+        }
+    }
+
+    /// Run `client` against a server that answers with [`handle`] and pushes `events`.
+    async fn with_server<Fut>(
+        events: impl Stream<Item = String> + Unpin,
+        client: impl FnOnce(BufReader<DuplexStream>) -> Fut,
+    ) where
+        Fut: Future<Output = ()>,
+    {
+        let (client_end, server_end) = tokio::io::duplex(1024);
+        tokio::select! {
+            result = serve(server_end, handle, events) => panic!("Server stopped: {result:?}"),
+            () = client(BufReader::new(client_end)) => (),
+        }
+    }
+
+    /// Send `request` and return the next message that the server writes.
+    async fn exchange(client: &mut BufReader<DuplexStream>, request: &str) -> Value {
+        let request = format!("{request}\n");
+        client
+            .get_mut()
+            .write_all(request.as_bytes())
+            .await
+            .unwrap();
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_requests() {
+        with_server(stream::pending(), |mut client| async move {
+            let response = exchange(
+                &mut client,
+                r#"{"jsonrpc":"2.0","method":"Double","params":21,"id":1}"#,
+            );
+            assert_eq!(
+                response.await,
+                json!({"jsonrpc": "2.0", "result": 42, "id": 1})
+            );
+            let response = exchange(&mut client, r#"{"jsonrpc":"2.0","method":"Ping","id":"a"}"#);
+            assert_eq!(
+                response.await,
+                json!({"jsonrpc": "2.0", "result": 1, "id": "a"})
+            );
+
+            let response = exchange(
+                &mut client,
+                r#"{"jsonrpc":"2.0","method":"Double","params":0,"id":2}"#,
+            );
+            assert_eq!(
+                response.await["error"],
+                json!({"code": SERVER_ERROR, "message": "Zero is not allowed"})
+            );
+            let response = exchange(&mut client, r#"{"jsonrpc":"2.0","method":"Triple","id":3}"#);
+            assert_eq!(response.await["error"]["code"], INVALID_PARAMS);
+            let response = exchange(&mut client, "{");
+            let response = response.await;
+            assert_eq!(response["error"]["code"], PARSE_ERROR);
+            assert_eq!(response["id"], Value::Null);
+
+            // Notifications are not answered, so the next message answers the request after it
+            client
+                .get_mut()
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"Ping\"}\n")
+                .await
+                .unwrap();
+            let response = exchange(&mut client, r#"{"jsonrpc":"2.0","method":"Ping","id":4}"#);
+            assert_eq!(response.await["id"], 4);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        with_server(stream::pending(), |mut client| async move {
+            let batch = r#"[
+            {"jsonrpc":"2.0","method":"Double","params":1,"id":1},
+            {"jsonrpc":"2.0","method":"Ping"},
+            {"jsonrpc":"2.0","method":"Double","params":2,"id":2}
+        ]"#;
+            let response = exchange(&mut client, &batch.replace('\n', "")).await;
+            assert_eq!(
+                response,
+                json!([
+                    {"jsonrpc": "2.0", "result": 2, "id": 1},
+                    {"jsonrpc": "2.0", "result": 4, "id": 2},
+                ])
+            );
+            let response = exchange(&mut client, "[]").await;
+            assert_eq!(response["error"]["code"], INVALID_REQUEST);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let events = stream::iter(["connected".to_owned()]).chain(stream::pending());
+        with_server(events, |mut client| async move {
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            let event: Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(
+                event,
+                json!({"jsonrpc": "2.0", "method": "event", "params": "connected"})
+            );
+        })
+        .await;
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    enum Streaming {
+        Count(u32),
+        Wait,
+    }
+
+    #[tokio::test]
+    async fn test_streaming() {
+        let (client_end, server_end) = tokio::io::duplex(1024);
+        let handle = |request| async move {
+            match request {
+                Streaming::Count(n) => Ok(Reply::Stream(stream::iter((0..n).map(Ok)).boxed())),
+                Streaming::Wait => Err("Not streamed".to_owned()),
+            }
+        };
+        tokio::spawn(serve_streaming(server_end, handle, stream::pending::<()>()));
+        let mut client = BufReader::new(client_end);
+
+        let request = r#"{"jsonrpc":"2.0","method":"Count","params":2,"id":1}"#;
+        let response = exchange(&mut client, request).await;
+        assert_eq!(
+            response,
+            json!({"jsonrpc": "2.0", "method": "stream", "params": {"id": 1, "result": 0}})
+        );
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(response["params"]["result"], 1);
+        line.clear();
+        client.read_line(&mut line).await.unwrap();
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(response, json!({"jsonrpc": "2.0", "result": null, "id": 1}));
+    }
+
+    #[tokio::test]
+    async fn test_batch_in_flight() {
+        let (client_end, server_end) = tokio::io::duplex(64 * 1024);
+        let started = Arc::new(AtomicUsize::new(0));
+        let handle = {
+            let started = started.clone();
+            move |_: Streaming| {
+                let started = started.clone();
+                async move {
+                    started.fetch_add(1, Ordering::SeqCst);
+                    future::pending::<Result<Reply<u32>, String>>().await
+                }
+            }
+        };
+        tokio::spawn(serve_streaming(server_end, handle, stream::pending::<()>()));
+        let mut client = BufReader::new(client_end);
+
+        let batch: Vec<_> = (0..DEFAULT_MAX_IN_FLIGHT + 5)
+            .map(|id| json!({"jsonrpc": "2.0", "method": "Wait", "id": id}))
+            .collect();
+        let batch = format!("{}\n", Value::Array(batch));
+        client.get_mut().write_all(batch.as_bytes()).await.unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(started.load(Ordering::SeqCst), DEFAULT_MAX_IN_FLIGHT);
+    }
+}
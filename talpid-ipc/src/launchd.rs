@@ -0,0 +1,86 @@
+//! Socket activation by launchd. See `launch_activate_socket(3)`.
+
+use crate::Endpoint;
+use std::{
+    ffi::{CString, c_char, c_int},
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::net::UnixListener,
+    },
+    ptr, slice,
+};
+
+unsafe extern "C" {
+    fn launch_activate_socket(
+        name: *const c_char,
+        fds: *mut *mut c_int,
+        cnt: *mut libc::size_t,
+    ) -> c_int;
+}
+
+impl Endpoint {
+    /// Use the listening socket registered under `name` in the `Sockets` dictionary of the
+    /// launchd job of this process. Returns `None` if the process is not managed by launchd, or
+    /// if no socket is registered under that name.
+    ///
+    /// The permissions of the socket are managed by launchd, so security attributes are not
+    /// applied to it, and the socket file is not removed when the returned endpoint stops
+    /// listening.
+    pub fn from_launchd(name: &str) -> io::Result<Option<Endpoint>> {
+        let name = CString::new(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid socket name"))?;
+
+        let mut fds: *mut c_int = ptr::null_mut();
+        let mut count: libc::size_t = 0;
+        // SAFETY: `name` is a valid C string and the out pointers are valid
+        let result = unsafe { launch_activate_socket(name.as_ptr(), &mut fds, &mut count) };
+        match result {
+            0 => (),
+            // Not managed by launchd, or no socket with that name
+            libc::ESRCH | libc::ENOENT => return Ok(None),
+            errno => return Err(io::Error::from_raw_os_error(errno)),
+        }
+
+        // SAFETY: On success, `fds` points to `count` descriptors that are now owned by us
+        let listeners: Vec<UnixListener> = unsafe { slice::from_raw_parts(fds, count) }
+            .iter()
+            // SAFETY: Each descriptor is owned by us and claimed exactly once
+            .map(|&fd| unsafe { UnixListener::from_raw_fd(fd) })
+            .collect();
+        // SAFETY: The array was allocated with `malloc` by launchd and is no longer used
+        unsafe { libc::free(fds.cast()) };
+
+        // launchd may create several sockets for one name, e.g. for IPv4 and IPv6. A Unix socket
+        // is only ever registered once.
+        let Some(listener) = listeners.into_iter().next() else {
+            return Ok(None);
+        };
+        crate::imp::set_cloexec(listener.as_raw_fd())?;
+        let path = listener
+            .local_addr()?
+            .as_pathname()
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        log::debug!("Using launchd-activated IPC endpoint {path}");
+
+        let mut endpoint = Endpoint::new(path);
+        endpoint.prebound = Some(listener);
+        Ok(Some(endpoint))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_not_activated() {
+        // Tests are not run as a launchd job with sockets
+        assert!(Endpoint::from_launchd("Listeners").unwrap().is_none());
+        assert_eq!(
+            Endpoint::from_launchd("Listen\0ers").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
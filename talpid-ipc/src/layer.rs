@@ -0,0 +1,212 @@
+//! Layers that every accepted connection passes through before it is yielded.
+//!
+//! Servers often do the same things with every connection before serving it: authenticate the
+//! peer, log or count it, limit how often a peer may connect, or attach what they learned to
+//! its [`Extensions`](crate::extensions::Extensions). Each of these can be written as a
+//! [`ConnectionLayer`] and added to the endpoint with [`Endpoint::add_layer`], instead of every
+//! server doing them in its handler.
+//!
+//! Layers are called in the order in which they were added. A layer returns the connection to
+//! pass it on to the next one, or fails to close it. Connections pass through the layers
+//! concurrently, so a slow peer does not hold up the others, but it does hold on to its permit
+//! and quota. Up to [`MAX_LAYERING`] connections pass through the layers at the same time, and
+//! each of them is closed if it has not passed through all of them within [`LAYER_TIMEOUT`].
+//!
+//! Some settings of the endpoint are layers as well: [`Endpoint::set_connection_quota`] is
+//! checked before all other layers, and [`Endpoint::set_encryption`] and [`authenticate`] where
+//! they are added.
+//!
+//! [`Endpoint::add_layer`]: crate::Endpoint::add_layer
+//! [`Endpoint::set_connection_quota`]: crate::Endpoint::set_connection_quota
+//! [`Endpoint::set_encryption`]: crate::Endpoint::set_encryption
+
+use crate::{
+    Connection, Rejection, frame::RejectReason, health, log_limit, quota::ConnectionQuota,
+};
+use std::{future::Future, io, pin::Pin, sync::Arc, time::Duration};
+
+/// Number of connections that may pass through the layers at the same time. No more are
+/// accepted until one of them has passed through or been closed.
+pub const MAX_LAYERING: usize = 64;
+
+/// Time that a connection may take to pass through all layers, including answering a liveness
+/// probe, before it is closed.
+pub const LAYER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Future returned by a [`ConnectionLayer`].
+pub type LayerFuture = Pin<Box<dyn Future<Output = io::Result<Connection>> + Send>>;
+
+/// Processes accepted connections, see [`crate::layer`].
+pub trait ConnectionLayer: Send + Sync {
+    /// Process `connection`, and return it to pass it on, or fail to close it. The error is
+    /// logged.
+    fn layer(&self, connection: Connection) -> LayerFuture;
+}
+
+impl<F, Fut> ConnectionLayer for F
+where
+    F: Fn(Connection) -> Fut + Send + Sync,
+    Fut: Future<Output = io::Result<Connection>> + Send + 'static,
+{
+    fn layer(&self, connection: Connection) -> LayerFuture {
+        Box::pin(self(connection))
+    }
+}
+
+/// Limits the number of connections that each user may have open at the same time, see
+/// [`crate::Endpoint::set_connection_quota`].
+pub(crate) struct QuotaLayer(ConnectionQuota);
+
+impl QuotaLayer {
+    pub(crate) fn new(per_user: usize) -> Self {
+        QuotaLayer(ConnectionQuota::new(per_user))
+    }
+}
+
+impl ConnectionLayer for QuotaLayer {
+    fn layer(&self, mut connection: Connection) -> LayerFuture {
+        let quota = self.0.clone();
+        Box::pin(async move {
+            // The client is impersonated rather than identified by its process ID, which may
+            // have been reused by another process since it connected
+            #[cfg(windows)]
+            if connection.peer_identity().is_none()
+                && let Err(error) = connection.identify_client().await
+            {
+                return Err(connection.reject(Rejection::Unidentified(error)).await);
+            }
+            match connection.quota_user().map(|user| quota.acquire(user)) {
+                Ok(Some(guard)) => {
+                    connection._quota = Some(guard);
+                    Ok(connection)
+                }
+                Ok(None) => Err(connection
+                    .reject(Rejection::Rejected(RejectReason::QuotaExceeded))
+                    .await),
+                Err(error) => Err(connection.reject(Rejection::Unidentified(error)).await),
+            }
+        })
+    }
+}
+
+/// Layer that authenticates every connection with `secret`, like
+/// [`FramedConnection::authenticate_client`], and attaches the [`AuthSession`] to its
+/// [`Extensions`](crate::extensions::Extensions). Clients authenticate with
+/// [`FramedConnection::authenticate_for_ticket`][authenticate_for_ticket] or
+/// [`FramedConnection::resume`] before anything else.
+///
+/// [`FramedConnection::authenticate_client`]: crate::frame::FramedConnection::authenticate_client
+/// [`AuthSession`]: crate::auth::AuthSession
+/// [authenticate_for_ticket]: crate::frame::FramedConnection::authenticate_for_ticket
+/// [`FramedConnection::resume`]: crate::frame::FramedConnection::resume
+#[cfg(feature = "auth")]
+pub fn authenticate(
+    secret: Arc<crate::auth::InstallSecret>,
+    issuer: Arc<crate::auth::TicketIssuer>,
+) -> impl ConnectionLayer {
+    move |connection: Connection| {
+        let secret = secret.clone();
+        let issuer = issuer.clone();
+        async move {
+            let mut framed = crate::frame::FramedConnection::new(connection);
+            let session = framed
+                .authenticate_client(&secret, &issuer)
+                .await
+                .map_err(io::Error::other)?;
+            let mut connection = framed.into_connection();
+            connection.extensions_mut().insert(session);
+            Ok(connection)
+        }
+    }
+}
+
+/// Answer a liveness probe on `connection`, and pass it through `layers`, returning it if none
+/// of them closed it and it did so in time.
+pub(crate) async fn apply(
+    layers: Arc<[Arc<dyn ConnectionLayer>]>,
+    connection: Connection,
+) -> Option<Connection> {
+    let id = connection.id();
+    let layered = async {
+        // Probes are answered before the connection is passed on, so watchdogs get an answer
+        // even while the server is not reading
+        let Some(mut connection) = health::answer_probe(connection).await else {
+            return Ok(None);
+        };
+        for layer in layers.iter() {
+            connection = layer.layer(connection).await?;
+        }
+        Ok(Some(connection))
+    };
+    let error = match tokio::time::timeout(LAYER_TIMEOUT, layered).await {
+        Ok(Ok(connection)) => return connection,
+        Ok(Err(error)) => error,
+        Err(_) => io::Error::new(io::ErrorKind::TimedOut, "Timed out"),
+    };
+    log_limit::closed_connection(
+        || "layer",
+        format_args!("IPC connection {id} was closed by a layer: {error}"),
+    );
+    None
+}
+
+#[cfg(all(test, feature = "client"))]
+mod test {
+    use crate::{
+        Error,
+        frame::{FramedConnection, RejectReason},
+        testing::EphemeralEndpoint,
+    };
+    use std::io;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[derive(Debug, PartialEq)]
+    struct Greeting(u8);
+
+    #[tokio::test]
+    async fn test_layers() {
+        let mut endpoint = EphemeralEndpoint::with_options(|endpoint| {
+            endpoint.add_layer(|mut connection: crate::Connection| async move {
+                let greeting = connection.read_u8().await?;
+                connection.extensions_mut().insert(Greeting(greeting));
+                Ok(connection)
+            });
+            // Layers are called in order, so the greeting has been read
+            endpoint.add_layer(|connection: crate::Connection| async move {
+                match connection.extensions().get::<Greeting>() {
+                    Some(Greeting(0)) => Err(io::Error::other("Rude client")),
+                    _ => Ok(connection),
+                }
+            });
+        })
+        .unwrap();
+
+        let mut rude = endpoint.connect().await.unwrap();
+        rude.write_u8(0).await.unwrap();
+        // A client that takes its time does not hold up the others
+        let _slow = endpoint.connect().await.unwrap();
+        let mut polite = endpoint.connect().await.unwrap();
+        polite.write_u8(1).await.unwrap();
+
+        let connection = endpoint.accept().await.unwrap();
+        assert_eq!(connection.extensions().get(), Some(&Greeting(1)));
+        assert_eq!(rude.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_quota() {
+        let mut endpoint =
+            EphemeralEndpoint::with_options(|endpoint| endpoint.set_connection_quota(1)).unwrap();
+        let (_client, _server) = endpoint.connected_pair().await.unwrap();
+
+        // The quota is checked before the other layers, and the peer is told why
+        let mut rejected = FramedConnection::new(endpoint.connect().await.unwrap());
+        tokio::select! {
+            _ = endpoint.accept() => panic!("Connection over the quota was accepted"),
+            result = rejected.read_raw(10) => assert!(matches!(
+                result,
+                Err(Error::Rejected(RejectReason::QuotaExceeded))
+            )),
+        }
+    }
+}
@@ -0,0 +1,2175 @@
+//! Cross-platform IPC transport. Endpoints are Unix domain sockets on Unix and named pipes on
+//! Windows. Connections are plain byte streams, on top of which [`frame`] provides a simple
+//! length-delimited framing.
+//!
+//! Sockets and pipe handles are never inherited by child processes, such as tunnel helpers,
+//! unless [`Endpoint::set_inheritable`] or `Connection::clear_cloexec` says otherwise. On Unix,
+//! they are created with `SOCK_CLOEXEC` or given `FD_CLOEXEC`, and on Windows, they are created
+//! without inheritance.
+//!
+//! On Unix, sockets are driven by the reactor of Tokio, i.e. epoll or kqueue. There is no
+//! io_uring backend, since tokio-uring needs a runtime of its own, and its sockets can neither be
+//! sent between threads nor used from the multi-threaded runtime that the daemon runs on, so they
+//! could not be put behind [`Endpoint`], [`Incoming`] and [`Connection`]. The small writes that
+//! such a backend would save system calls on are already coalesced by
+//! [`frame::FramedConnection::feed_frame`].
+//!
+//! Timeouts, deadlines and delays between retries are all measured with the clock of Tokio, so
+//! tests of them can pause time with `#[tokio::test(start_paused = true)]` rather than sleep.
+//!
+//! Connecting to endpoints requires the `client` feature, and listening on them the `server`
+//! feature. Both are enabled by default. Frontends that only connect to the daemon can disable
+//! `server`, which leaves out the listener, its options and the admission of peers.
+
+use bytes::BytesMut;
+#[cfg(feature = "client")]
+use futures::future::{self, Either};
+#[cfg(feature = "server")]
+use futures::{Stream, StreamExt, stream::FuturesUnordered};
+#[cfg(feature = "client")]
+use std::path::Path;
+#[cfg(any(feature = "client", feature = "server"))]
+use std::pin::pin;
+#[cfg(feature = "server")]
+use std::{cell::LazyCell, future::Future, task::ready};
+use std::{
+    fmt, io,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(feature = "server")]
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+#[cfg(any(feature = "client", feature = "server"))]
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "server")]
+use tokio_util::sync::{PollSemaphore, WaitForCancellationFutureOwned};
+
+// Lets the code generated by `rpc::IpcMessage` refer to this crate in its own tests
+#[cfg(all(test, feature = "derive"))]
+extern crate self as talpid_ipc;
+
+#[cfg(feature = "server")]
+pub mod accept;
+#[cfg(target_os = "android")]
+mod android;
+#[cfg(target_os = "macos")]
+mod app_group;
+#[cfg(feature = "server")]
+pub mod audit;
+#[cfg(feature = "auth")]
+pub mod auth;
+pub mod backoff;
+pub mod budget;
+#[cfg(feature = "capture")]
+pub mod capture;
+mod checksum;
+pub mod chunked;
+#[cfg(all(feature = "client", feature = "rpc"))]
+pub mod client;
+#[cfg(feature = "codec")]
+pub mod codec;
+#[cfg(feature = "compression")]
+pub mod compression;
+mod context;
+#[cfg(unix)]
+pub mod credentials;
+pub mod disconnect;
+#[cfg(all(
+    feature = "client",
+    any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        windows
+    )
+))]
+mod discovery;
+#[cfg(feature = "echo")]
+pub mod echo;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+pub mod events;
+pub mod extensions;
+#[cfg(feature = "file-transfer")]
+pub mod file_transfer;
+pub mod frame;
+#[cfg(any(feature = "fuzz", test))]
+pub mod fuzz;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handshake;
+pub mod health;
+#[cfg(windows)]
+pub mod identity;
+#[cfg(feature = "rpc")]
+pub mod jsonrpc;
+#[cfg(all(target_os = "macos", feature = "server"))]
+mod launchd;
+#[cfg(feature = "server")]
+pub mod layer;
+mod log_limit;
+pub mod metrics;
+#[cfg(feature = "server")]
+pub mod multi;
+#[cfg(any(unix, windows))]
+mod pair;
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+mod per_user;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod pidfd;
+#[cfg(all(target_os = "linux", feature = "polkit"))]
+pub mod polkit;
+mod pool;
+#[cfg(feature = "server")]
+mod quota;
+#[cfg(feature = "replay")]
+pub mod replay;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod sansio;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod seqpacket;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "shared-memory")]
+pub mod shm;
+pub mod shutdown;
+#[cfg(any(feature = "capture", feature = "replay"))]
+mod spool;
+pub mod stats;
+pub mod stdio;
+#[cfg(feature = "server")]
+pub mod supervisor;
+#[cfg(all(target_os = "linux", feature = "server"))]
+mod systemd;
+#[cfg(all(unix, feature = "server"))]
+pub mod takeover;
+#[cfg(feature = "tcp")]
+pub mod tcp;
+#[cfg(any(feature = "testing", test))]
+pub mod testing;
+#[cfg(all(feature = "client", feature = "server"))]
+pub mod transport;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod userns;
+#[cfg(feature = "websocket-bridge")]
+pub mod websocket;
+#[cfg(feature = "wsl-bridge")]
+pub mod wsl;
+#[cfg(all(target_os = "macos", feature = "xpc"))]
+pub mod xpc;
+
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+use unix as imp;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+use windows as imp;
+
+#[cfg(feature = "server")]
+use accept::{AcceptErrorPolicy, AcceptRetry};
+#[cfg(any(feature = "client", feature = "server"))]
+use backoff::Backoff;
+#[cfg(feature = "client")]
+use backoff::{ExponentialBackoff, Jitter};
+#[cfg(feature = "client")]
+use context::ResultExt;
+pub use context::{EndpointError, Operation};
+use disconnect::{Disconnect, DisconnectCallback};
+use frame::{GoodbyeReason, RejectReason};
+pub use imp::SecurityAttributes;
+use metrics::{HandshakeFailure, HandshakeFailureReason, IpcMetrics};
+#[cfg(feature = "server")]
+use quota::QuotaGuard;
+#[cfg(feature = "server")]
+use shutdown::ShutdownHandle;
+use shutdown::ShutdownSignal;
+#[cfg(feature = "server")]
+use stats::ServerCounters;
+use stats::{ConnectionCounters, ConnectionStats};
+#[cfg(windows)]
+pub use windows::{DaclPreset, PipeErrorKind};
+
+/// Errors that can occur while exchanging frames over a connection.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IPC connection I/O error")]
+    Io(#[from] io::Error),
+
+    #[error("Payload of {0} bytes does not fit in a frame")]
+    FrameTooLarge(usize),
+
+    #[error("Received frame of {len} bytes, which exceeds the limit of {max} bytes")]
+    FrameExceedsLimit { len: usize, max: usize },
+
+    #[error(
+        "{queued} bytes are queued, which reaches the high-water mark of {high_water_mark} bytes"
+    )]
+    WriteQueueFull {
+        queued: usize,
+        high_water_mark: usize,
+    },
+
+    #[error(
+        "{buffered} bytes are buffered for the connection, which exceeds the limit of {limit} bytes"
+    )]
+    MemoryLimitExceeded { buffered: usize, limit: usize },
+
+    #[error(
+        "Connection was closed to keep all connections within their memory budget of {limit} bytes"
+    )]
+    MemoryBudgetExceeded { limit: usize },
+
+    #[error("Frame was not queued, since the memory budget of {limit} bytes is used up")]
+    MemoryBudgetFull { limit: usize },
+
+    #[error("Received frame of unknown kind: {0}")]
+    UnknownFrameKind(u8),
+
+    #[cfg(feature = "rpc")]
+    #[error("Received message of unknown kind: {0}")]
+    UnknownMessageKind(u8),
+
+    #[error("Connection was closed in the middle of a frame")]
+    UnexpectedEof,
+
+    #[error("Peer does not speak the IPC protocol")]
+    UnrecognizedPeer,
+
+    #[error("Peer uses protocol version {theirs}, but version {ours} is required")]
+    IncompatiblePeer { theirs: u16, ours: u16 },
+
+    #[error("Peer does not know the installation secret")]
+    AuthenticationFailed,
+
+    #[error("Peer violated the protocol: {0}")]
+    Protocol(&'static str),
+
+    #[error("Bulk transfer of {len} bytes exceeds the limit of {max} bytes")]
+    BulkTooLarge { len: u64, max: u64 },
+
+    #[error("Peer rejected the bulk transfer")]
+    BulkRejected,
+
+    #[error("Peer aborted the chunked transfer")]
+    TransferAborted,
+
+    #[error("Transferred file does not match its digest")]
+    IntegrityCheckFailed,
+
+    #[error("Timed out while {0} a frame")]
+    Deadline(&'static str),
+
+    #[error("Server rejected the connection: {0}")]
+    Rejected(RejectReason),
+
+    #[error("Received malformed frame: {0}")]
+    Malformed(#[from] frame::MalformedFrame),
+
+    #[error("Received frame that was corrupted in transit")]
+    FrameCorrupted,
+
+    #[cfg(feature = "rpc")]
+    #[error("Failed to encode or decode a message")]
+    Codec(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Peer failed to handle the request: {0}")]
+    Remote(String),
+
+    #[error("Connection was closed")]
+    Closed,
+
+    #[error("Peer closed the connection: {0}")]
+    Goodbye(GoodbyeReason),
+
+    #[error("Timed out waiting for a response")]
+    RequestTimeout,
+}
+
+/// What operations that were cancelled through a [`CancellationToken`] fail with, inside an
+/// [`io::Error`] of the kind [`io::ErrorKind::Other`]. Unlike an interrupted operation, a
+/// cancelled one should not be tried again, so it is neither reported as
+/// [`io::ErrorKind::Interrupted`] nor [transient](Error::is_transient).
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("The operation was cancelled")]
+pub struct Cancelled;
+
+impl Cancelled {
+    /// Whether `error` says that an operation was cancelled.
+    pub fn is(error: &io::Error) -> bool {
+        let error = EndpointError::of(error).map_or(error, EndpointError::io_error);
+        error.get_ref().is_some_and(|inner| inner.is::<Cancelled>())
+    }
+}
+
+/// Suggested delay before retrying once all pipe instances were busy. Instances become free as
+/// soon as the server has accepted the connections that are waiting.
+const BUSY_RETRY_AFTER: Duration = Duration::from_millis(50);
+
+/// Suggested delay before retrying once the server was not listening or shut down, e.g. because
+/// it is being restarted.
+const RESTART_RETRY_AFTER: Duration = Duration::from_millis(500);
+
+impl Error {
+    /// Whether the operation may succeed if it is tried again, e.g. after reconnecting. This is
+    /// the case if the connection was lost, the server was not running or was busy, or something
+    /// timed out. Errors that will happen again, such as an incompatible peer, a violation of the
+    /// protocol or a lack of permission, are not transient.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Io(error) if Cancelled::is(error) => false,
+            Error::Io(error) => is_transient_io(error),
+            Error::WriteQueueFull { .. }
+            | Error::MemoryBudgetFull { .. }
+            | Error::UnexpectedEof
+            | Error::Deadline(_)
+            | Error::FrameCorrupted
+            | Error::Rejected(RejectReason::QuotaExceeded)
+            | Error::Closed
+            | Error::Goodbye(GoodbyeReason::Unspecified | GoodbyeReason::ShuttingDown)
+            | Error::RequestTimeout => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the operation was cancelled, see [`Cancelled`].
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, Error::Io(error) if Cancelled::is(error))
+    }
+
+    /// How long to wait before retrying, if the cause of the error suggests it. Returns `None`
+    /// if it does not, in which case a transient error should be retried with the usual backoff.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::Io(error) if is_busy(error) => Some(BUSY_RETRY_AFTER),
+            Error::Io(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                Some(RESTART_RETRY_AFTER)
+            }
+            Error::Goodbye(GoodbyeReason::ShuttingDown) => Some(RESTART_RETRY_AFTER),
+            _ => None,
+        }
+    }
+}
+
+fn is_transient_io(error: &io::Error) -> bool {
+    #[cfg(windows)]
+    match PipeErrorKind::of(error) {
+        Some(PipeErrorKind::NotFound | PipeErrorKind::Busy | PipeErrorKind::TimedOut) => {
+            return true;
+        }
+        Some(PipeErrorKind::AccessDenied) => return false,
+        None => (),
+    }
+    matches!(
+        error.kind(),
+        io::ErrorKind::NotFound
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::OutOfMemory
+    )
+}
+
+/// Whether all instances of a pipe were busy.
+#[cfg(windows)]
+fn is_busy(error: &io::Error) -> bool {
+    PipeErrorKind::of(error) == Some(PipeErrorKind::Busy)
+}
+
+/// Sockets are never busy.
+#[cfg(not(windows))]
+fn is_busy(_error: &io::Error) -> bool {
+    false
+}
+
+/// Time between attempts to connect while waiting for a server to start listening.
+#[cfg(feature = "client")]
+const WAIT_FOR_SERVER_BACKOFF: Jitter<ExponentialBackoff> = Jitter::new(
+    ExponentialBackoff::new(Duration::from_millis(20), Duration::from_millis(500)),
+    0.5,
+);
+
+/// Source of [`ConnectionId`]s.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identifies a connection within this process. IDs are assigned in increasing order, to both
+/// accepted connections and connections established by clients, and are never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    fn next() -> Self {
+        ConnectionId(NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Return the ID as a number, e.g. for use as a metrics label.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Address of the peer of a connection, see [`Connection::peer_address`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerAddress {
+    /// A socket that is bound to a path. Only servers usually bind their sockets.
+    Path(PathBuf),
+    /// A socket that is bound to a name in the abstract namespace of Linux.
+    Abstract(Vec<u8>),
+    /// The other end of the named pipe with this name.
+    Pipe(String),
+    /// A client that connected over loopback TCP, see [`tcp`].
+    Tcp(std::net::SocketAddr),
+    /// A socket that is not bound to an address, as is the case for most clients.
+    Unnamed,
+}
+
+impl fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddress::Path(path) => path.display().fmt(f),
+            PeerAddress::Abstract(name) => write!(f, "@{}", name.escape_ascii()),
+            PeerAddress::Pipe(name) => f.write_str(name),
+            PeerAddress::Tcp(address) => address.fmt(f),
+            PeerAddress::Unnamed => f.write_str("(unnamed)"),
+        }
+    }
+}
+
+/// Checks to perform before connecting, see [`Endpoint::connect_with_options`].
+#[cfg(feature = "client")]
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    #[cfg(unix)]
+    owner: Option<u32>,
+    #[cfg(windows)]
+    server: identity::ServerRequirements,
+}
+
+#[cfg(feature = "client")]
+impl ConnectOptions {
+    pub fn new() -> Self {
+        ConnectOptions::default()
+    }
+
+    /// Only talk to a server process that runs as `uid`, e.g. root or the user that the daemon
+    /// runs as. The user is taken from the peer credentials that the kernel records for the
+    /// connection, not from the owner of the socket file, which could be swapped while
+    /// connecting. Otherwise, connecting fails with [`io::ErrorKind::PermissionDenied`], so that
+    /// a socket that another user has put in the place of the daemon's is never sent anything.
+    #[cfg(unix)]
+    pub fn require_owner(&mut self, uid: u32) {
+        self.owner = Some(uid);
+    }
+
+    /// Only talk to a server process that runs as the user with the string SID `sid`, e.g.
+    /// `S-1-5-18` for LocalSystem. Since any process may create an instance of a pipe whose
+    /// name is free, the server is checked after connecting, and the connection is closed with
+    /// [`io::ErrorKind::PermissionDenied`] before anything is sent if it does not match.
+    #[cfg(windows)]
+    pub fn require_server_user(&mut self, sid: impl Into<String>) {
+        self.server.user = Some(sid.into());
+    }
+
+    /// Only talk to a server process whose executable is `path`. Paths are compared as the
+    /// kernel reports them, ignoring case.
+    #[cfg(windows)]
+    pub fn require_server_image(&mut self, path: impl Into<std::path::PathBuf>) {
+        self.server.image = Some(path.into());
+    }
+
+    /// Only talk to a server process whose executable has a valid Authenticode signature by
+    /// `signer`, e.g. [`identity::ServerSigner::mullvad`]. Any signature that chains to a
+    /// trusted root would not do, since anyone can buy a code signing certificate.
+    #[cfg(windows)]
+    pub fn require_server_signer(&mut self, signer: identity::ServerSigner) {
+        self.server.signer = Some(signer);
+    }
+}
+
+/// An IPC endpoint that can be listened on or connected to.
+pub struct Endpoint {
+    path: String,
+    #[cfg(feature = "server")]
+    security_attributes: SecurityAttributes,
+    #[cfg(feature = "server")]
+    metrics: Option<Arc<dyn IpcMetrics>>,
+    #[cfg(feature = "server")]
+    event_sink: Option<Arc<dyn audit::IpcEventSink>>,
+    #[cfg(feature = "server")]
+    server_counters: Option<Arc<ServerCounters>>,
+    #[cfg(feature = "server")]
+    permits: Option<Arc<Semaphore>>,
+    #[cfg(feature = "server")]
+    listen_options: imp::ListenOptions,
+    #[cfg(feature = "server")]
+    shutdown: Option<ShutdownHandle>,
+    #[cfg(feature = "server")]
+    on_disconnect: Option<DisconnectCallback>,
+    #[cfg(feature = "server")]
+    on_ready: Option<Box<dyn FnOnce(&str) + Send>>,
+    #[cfg(all(target_os = "linux", feature = "server"))]
+    notify_systemd: bool,
+    #[cfg(feature = "server")]
+    cancel: Option<CancellationToken>,
+    #[cfg(feature = "server")]
+    quota: Option<usize>,
+    #[cfg(all(unix, feature = "server"))]
+    allowlist: Option<credentials::PeerAllowlist>,
+    #[cfg(all(windows, feature = "server"))]
+    sid_allowlist: Option<Arc<identity::SidAllowlist>>,
+    #[cfg(feature = "server")]
+    accept_error_policy: AcceptErrorPolicy,
+    #[cfg(feature = "server")]
+    accept_backoff: Option<Arc<dyn Backoff>>,
+    #[cfg(feature = "server")]
+    layers: Vec<Arc<dyn layer::ConnectionLayer>>,
+    #[cfg(feature = "server")]
+    inheritable: bool,
+    #[cfg(feature = "server")]
+    restricted: bool,
+    #[cfg(feature = "server")]
+    liveness_responder: bool,
+    #[cfg(feature = "server")]
+    memory_limit: Option<usize>,
+    /// Listener that was bound before the endpoint was created, e.g. by socket activation.
+    #[cfg(all(unix, feature = "server"))]
+    prebound: Option<std::os::unix::net::UnixListener>,
+    /// Pipe instance that was created before the endpoint was created.
+    #[cfg(all(windows, feature = "server"))]
+    prebound: Option<std::os::windows::io::OwnedHandle>,
+}
+
+impl Endpoint {
+    /// Create an endpoint for the given socket path or pipe name.
+    pub fn new(path: String) -> Self {
+        Endpoint {
+            path,
+            #[cfg(feature = "server")]
+            security_attributes: SecurityAttributes::empty(),
+            #[cfg(feature = "server")]
+            metrics: None,
+            #[cfg(feature = "server")]
+            event_sink: None,
+            #[cfg(feature = "server")]
+            server_counters: None,
+            #[cfg(feature = "server")]
+            permits: None,
+            #[cfg(feature = "server")]
+            listen_options: imp::ListenOptions::default(),
+            #[cfg(feature = "server")]
+            shutdown: None,
+            #[cfg(feature = "server")]
+            on_disconnect: None,
+            #[cfg(feature = "server")]
+            on_ready: None,
+            #[cfg(all(target_os = "linux", feature = "server"))]
+            notify_systemd: false,
+            #[cfg(feature = "server")]
+            cancel: None,
+            #[cfg(feature = "server")]
+            quota: None,
+            #[cfg(all(unix, feature = "server"))]
+            allowlist: None,
+            #[cfg(all(windows, feature = "server"))]
+            sid_allowlist: None,
+            #[cfg(feature = "server")]
+            accept_error_policy: AcceptErrorPolicy::default(),
+            #[cfg(feature = "server")]
+            accept_backoff: None,
+            #[cfg(feature = "server")]
+            layers: Vec::new(),
+            #[cfg(feature = "server")]
+            inheritable: false,
+            #[cfg(feature = "server")]
+            restricted: false,
+            #[cfg(feature = "server")]
+            liveness_responder: false,
+            #[cfg(feature = "server")]
+            memory_limit: None,
+            #[cfg(feature = "server")]
+            prebound: None,
+        }
+    }
+
+    /// Create an endpoint at a pipe name that is not in use, `\\.\pipe\mullvad-<guid>` with a
+    /// random GUID. Clients must be told the name, e.g. on the command line of a helper.
+    #[cfg(windows)]
+    pub fn ephemeral() -> io::Result<Self> {
+        Ok(Endpoint::new(imp::ephemeral_pipe_name()?))
+    }
+
+    /// Socket path or pipe name of this endpoint.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+#[cfg(feature = "server")]
+impl Endpoint {
+    /// Set the security attributes that are applied to the socket or pipe when listening.
+    pub fn set_security_attributes(&mut self, security_attributes: SecurityAttributes) {
+        self.security_attributes = security_attributes;
+    }
+
+    /// Like [`Self::set_security_attributes`], for constructors whose clients only need the
+    /// path.
+    pub(crate) fn with_security_attributes(mut self, attributes: SecurityAttributes) -> Self {
+        self.security_attributes = attributes;
+        self
+    }
+
+    /// Secure the pipe with one of the ready-made security descriptors, instead of writing an
+    /// SDDL string for [`SecurityAttributes::from_sddl`].
+    #[cfg(windows)]
+    pub fn set_dacl_preset(&mut self, preset: &DaclPreset) -> io::Result<()> {
+        self.security_attributes = SecurityAttributes::from_preset(preset)?;
+        Ok(())
+    }
+
+    /// Set the maximum number of connections that may be waiting to be accepted. Further clients
+    /// fail to connect with `ECONNREFUSED`. The default is `SOMAXCONN`, and the value is capped
+    /// by `net.core.somaxconn` on Linux and `kern.ipc.somaxconn` on macOS.
+    #[cfg(unix)]
+    pub fn set_listen_backlog(&mut self, backlog: u32) {
+        self.listen_options.backlog = Some(backlog);
+    }
+
+    /// Set the size of the kernel receive buffer (`SO_RCVBUF`) of the listener and of accepted
+    /// sockets. By default, the system decides. The kernel may round or cap the size, e.g. Linux
+    /// doubles it for its own bookkeeping, and caps it at `net.core.rmem_max`. If the size of an
+    /// accepted socket cannot be set, a warning is logged, and the connection is still accepted.
+    #[cfg(unix)]
+    pub fn set_receive_buffer_size(&mut self, size: usize) {
+        self.listen_options.buffer_sizes.receive = Some(size);
+    }
+
+    /// Set the size of the kernel send buffer (`SO_SNDBUF`) of the listener and of accepted
+    /// sockets. A larger buffer lets the server write a burst of events at once, rather than in
+    /// short writes that each wait for the client to read. By default, the system decides. See
+    /// [`Self::set_receive_buffer_size`].
+    #[cfg(unix)]
+    pub fn set_send_buffer_size(&mut self, size: usize) {
+        self.listen_options.buffer_sizes.send = Some(size);
+    }
+
+    /// Set the number of pipe instances that wait for clients at the same time. With more than
+    /// one, several clients can connect at once without getting `ERROR_PIPE_BUSY`, and are
+    /// yielded as each of them connects. The default is 4.
+    #[cfg(windows)]
+    pub fn set_pending_pipe_instances(&mut self, instances: usize) {
+        self.listen_options.pending_instances = instances;
+    }
+
+    /// Decide how long to wait before creating pipe instances again after it has failed, e.g.
+    /// because the system is out of resources. By default, the delay starts at 100 ms and is
+    /// doubled after each failure, up to 10 s. If `backoff` gives up, the error is yielded by
+    /// the stream of incoming connections once no instances are left.
+    #[cfg(windows)]
+    pub fn set_pipe_create_backoff(&mut self, backoff: Arc<dyn Backoff>) {
+        self.listen_options.create_backoff = backoff;
+    }
+
+    /// Report accepted connections and their traffic to `metrics`.
+    pub fn set_metrics(&mut self, metrics: Arc<dyn IpcMetrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Tell `sink` who connects, who is rejected or fails the handshake, and when connections
+    /// are closed, e.g. [`audit::LogSink`]. See [`audit`].
+    #[cfg(feature = "server")]
+    pub fn set_event_sink(&mut self, sink: Arc<dyn audit::IpcEventSink>) {
+        self.event_sink = Some(sink);
+    }
+
+    /// Add the traffic on every accepted connection to `counters`. See [`stats`].
+    pub fn set_server_counters(&mut self, counters: Arc<ServerCounters>) {
+        self.server_counters = Some(counters);
+    }
+
+    /// Require a permit from `permits` for every accepted connection. The permit is held until
+    /// the connection is dropped. While no permit is available, no connections are accepted, so
+    /// new clients wait in the listen backlog of the OS instead of being accepted and then
+    /// starved. Closing the semaphore ends the stream of incoming connections.
+    ///
+    /// The daemon can also take permits itself, or call [`Semaphore::forget_permits`], to pause
+    /// accepting while it is overloaded.
+    pub fn set_accept_permits(&mut self, permits: Arc<Semaphore>) {
+        self.permits = Some(permits);
+    }
+
+    /// Allow each user to have at most `per_user` connections open at the same time. Further
+    /// connections of that user are sent a [`frame::FrameKind::Reject`] frame and closed
+    /// immediately, which clients observe as [`Error::Rejected`]. Users are identified by
+    /// their UID, or by their SID on Windows. On Windows, the client is impersonated to find
+    /// its SID, so it has to send something before it is counted, as with an allowlist of
+    /// SIDs.
+    ///
+    /// This keeps one user from exhausting the permits set by [`Self::set_accept_permits`]. The
+    /// quota is a [layer](layer) that connections pass through before any other.
+    pub fn set_connection_quota(&mut self, per_user: usize) {
+        self.quota = Some(per_user);
+    }
+
+    /// Only serve peers that are allowed by `allowlist`. The credentials of every accepted
+    /// connection are checked before it is returned, and connections from other peers are
+    /// closed immediately without reading from them.
+    #[cfg(unix)]
+    pub fn set_peer_allowlist(&mut self, allowlist: credentials::PeerAllowlist) {
+        self.allowlist = Some(allowlist);
+    }
+
+    /// Only serve clients that are allowed by `allowlist`, in addition to the access checks of
+    /// the security descriptor. The token of the client of every accepted connection is checked
+    /// before it is returned, and connections from other clients are closed without the server
+    /// seeing them. The result is available from [`Connection::peer_identity`].
+    ///
+    /// The token is obtained by impersonating the client, which is only possible once something
+    /// has been read from the pipe. Clients must therefore send first, and connections whose
+    /// clients send nothing within a few seconds are closed. What was read is passed on.
+    #[cfg(windows)]
+    pub fn set_sid_allowlist(&mut self, allowlist: identity::SidAllowlist) {
+        self.sid_allowlist = Some(Arc::new(allowlist));
+    }
+
+    /// Decide what the stream of incoming connections does when accepting fails with an error
+    /// that is likely to go away on its own. By default, all errors are yielded. See
+    /// [`accept`].
+    pub fn set_accept_error_policy(&mut self, policy: AcceptErrorPolicy) {
+        self.accept_error_policy = policy;
+    }
+
+    /// Retry transient accept errors after the delays decided by `backoff`, regardless of the
+    /// [`AcceptErrorPolicy`]. Once `backoff` gives up, the error is yielded.
+    pub fn set_accept_backoff(&mut self, backoff: Arc<dyn Backoff>) {
+        self.accept_backoff = Some(backoff);
+    }
+
+    /// Pass every accepted connection through `layer` before yielding it, after the layers
+    /// that were added before. See [`layer`].
+    pub fn add_layer(&mut self, layer: impl layer::ConnectionLayer + 'static) {
+        self.layers.push(Arc::new(layer));
+    }
+
+    /// Let child processes that are spawned while accepted connections are open inherit them,
+    /// e.g. to hand a connection over to a helper process. The listener itself is never
+    /// inherited. By default, nothing is inherited.
+    pub fn set_inheritable(&mut self, inheritable: bool) {
+        self.inheritable = inheritable;
+    }
+
+    /// Mark connections accepted on this endpoint as restricted, see
+    /// [`Connection::is_restricted`]. This is meant for a second endpoint that is exposed to
+    /// sandboxed clients, such as Flatpak or Snap apps, which the server should only let
+    /// perform a limited set of requests, e.g. read-only ones.
+    pub fn set_restricted(&mut self, restricted: bool) {
+        self.restricted = restricted;
+    }
+
+    /// Answer [liveness probes](health::LIVENESS_PROBE) that a peer sends before anything else
+    /// on an accepted connection, instead of passing them on to the server. Unlike a
+    /// [ping](health::check), this works whatever protocol the server speaks on top of the
+    /// connection, e.g. with a [handshake](handshake), so that watchdogs and installers can
+    /// check that the server answers IPC. Accepted connections are given a short time to send
+    /// the probe, which is then answered without passing the connection on, so probes are
+    /// answered even while the server is not reading. A probe that arrives later is answered
+    /// once the server reads, after which the server sees the connection as closed by the peer.
+    ///
+    /// Servers whose clients may start with the byte `0xff` and then wait for an answer should
+    /// not use this, since the bytes that could begin a probe are held back until it is clear
+    /// that they do not. Connections from clients that wait for the server to speak first are
+    /// passed on after [`health::PROBE_WINDOW`].
+    pub fn set_liveness_responder(&mut self, respond: bool) {
+        self.liveness_responder = respond;
+    }
+
+    /// Limit the memory that may be buffered for each accepted connection to `limit` bytes, see
+    /// [`frame::FramedConnection::set_memory_limit`]. The limit is applied when a handler of an
+    /// [`server::IpcServer`] attaches [`Connection::control`] to its framing, and other handlers
+    /// can apply [`Connection::memory_limit`] themselves. [`grpc::serve_incoming`] bounds what
+    /// HTTP/2 buffers for each connection by it instead. There is no limit by default.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+    }
+
+    /// Call `callback` when the peer of an accepted connection turns out to have disappeared
+    /// while writing to it.
+    pub fn set_disconnect_callback(&mut self, callback: DisconnectCallback) {
+        self.on_disconnect = Some(callback);
+    }
+
+    /// Call `callback` with the path of the endpoint once it is listening, i.e. once the
+    /// socket is bound, or the first pipe instance has been created, and clients can connect.
+    /// This is the time to report that the service is running, e.g. `SERVICE_RUNNING` to the
+    /// Windows service control manager. It is not called if listening fails.
+    pub fn set_ready_callback(&mut self, callback: impl FnOnce(&str) + Send + 'static) {
+        self.on_ready = Some(Box::new(callback));
+    }
+
+    /// Tell systemd that the service is ready once the endpoint is listening, for services of
+    /// `Type=notify`. `READY=1` is sent to `$NOTIFY_SOCKET`, along with a status that names the
+    /// path. Nothing is sent if the process was not started by systemd.
+    #[cfg(target_os = "linux")]
+    pub fn set_notify_systemd(&mut self, notify: bool) {
+        self.notify_systemd = notify;
+    }
+
+    /// Stop listening as soon as `cancel` is cancelled, even if an accept is pending. The
+    /// stream of incoming connections ends, and the listener is closed immediately. Use
+    /// [`Self::set_shutdown_handle`] to close it gracefully instead.
+    pub fn set_cancellation_token(&mut self, cancel: CancellationToken) {
+        self.cancel = Some(cancel);
+    }
+
+    /// Shut down the server gracefully when [`ShutdownHandle::drain`] is called.
+    pub fn set_shutdown_handle(&mut self, shutdown: ShutdownHandle) {
+        self.shutdown = Some(shutdown);
+    }
+
+    /// Listen on `listener`, which was bound by the caller, e.g. before dropping privileges or
+    /// in another process that passed it on. The path of the endpoint is the one that the
+    /// listener is bound to.
+    ///
+    /// As with socket activation, security attributes are not applied to the socket, and the
+    /// socket file is not removed when the endpoint stops listening, since it belongs to whoever
+    /// bound it.
+    #[cfg(unix)]
+    pub fn from_std_listener(listener: std::os::unix::net::UnixListener) -> io::Result<Endpoint> {
+        let path = listener
+            .local_addr()?
+            .as_pathname()
+            .map(|path| path.to_string_lossy().into_owned())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Listener is not bound to a path",
+                )
+            })?;
+        let mut endpoint = Endpoint::new(path);
+        endpoint.prebound = Some(listener);
+        Ok(endpoint)
+    }
+
+    /// Listen on the pipe at `path`, starting with the server instance `handle`, which was
+    /// created by the caller, e.g. with `FILE_FLAG_FIRST_PIPE_INSTANCE` to claim the name before
+    /// dropping privileges. Further instances are created as usual, with the security attributes
+    /// of the endpoint.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be an open server instance of the pipe at `path`, created with
+    /// `FILE_FLAG_OVERLAPPED`. The endpoint takes ownership of it, so it must not be used or
+    /// closed elsewhere.
+    #[cfg(windows)]
+    pub unsafe fn from_raw_pipe_handle(
+        path: String,
+        handle: std::os::windows::io::RawHandle,
+    ) -> Endpoint {
+        let mut endpoint = Endpoint::new(path);
+        // SAFETY: The caller passes on ownership of the handle
+        endpoint.prebound =
+            Some(unsafe { std::os::windows::io::FromRawHandle::from_raw_handle(handle) });
+        endpoint
+    }
+
+    /// Bind the socket and apply the security attributes, but do not start listening yet. This
+    /// is the first half of [`Self::incoming`], for servers that drop privileges: bind while
+    /// privileged, e.g. to create the socket in a directory that only root may write to or to
+    /// change its owner, then call [`BoundEndpoint::incoming`] once privileges have been
+    /// dropped. On Unix, neither half needs an async runtime, so the first can run early in
+    /// `main`.
+    ///
+    /// On Windows, this creates the first instance of the pipe with the security attributes,
+    /// which keeps anyone else from creating it, and must be called from within a Tokio
+    /// runtime.
+    ///
+    /// Clients may connect as soon as the socket is bound, and are accepted once the endpoint
+    /// is listened on.
+    pub fn bind(self) -> io::Result<BoundEndpoint> {
+        let socket = imp::BoundSocket::bind(
+            self.path.clone(),
+            self.security_attributes.clone(),
+            &self.listen_options,
+        )?;
+        Ok(BoundEndpoint {
+            endpoint: self,
+            socket,
+        })
+    }
+
+    /// Start listening on the endpoint, returning a stream of incoming connections.
+    pub fn incoming(self) -> io::Result<Incoming> {
+        self.listen(imp::Incoming::bind)
+    }
+
+    /// Like [`Self::incoming`], but if the path is occupied by a socket whose server has died,
+    /// remove it and bind again. The PID and executable of this process are recorded in a file
+    /// next to the socket, so that a later takeover can tell whether this process is still
+    /// running. See [`takeover`].
+    ///
+    /// Returns how the socket came to be listened on, e.g. to report that the server recovered
+    /// from an unclean shutdown.
+    #[cfg(unix)]
+    pub fn bind_or_takeover(self) -> io::Result<(Incoming, takeover::BindOutcome)> {
+        let mut outcome = takeover::BindOutcome::Inherited;
+        let incoming = self.listen(|path, security_attributes, options| {
+            let (inner, bound) = takeover::bind_or_takeover(path, security_attributes, options)?;
+            outcome = bound;
+            Ok(inner)
+        })?;
+        Ok((incoming, outcome))
+    }
+
+    /// Start listening, using `bind` unless a listener has been inherited.
+    fn listen<B>(self, bind: B) -> io::Result<Incoming>
+    where
+        B: FnOnce(String, SecurityAttributes, &imp::ListenOptions) -> io::Result<imp::Incoming>,
+    {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("ipc_listener", path = %self.path);
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+
+        #[cfg(unix)]
+        let inner = match self.prebound {
+            Some(listener) => imp::Incoming::from_std(listener, self.path, &self.listen_options),
+            None => bind(self.path, self.security_attributes, &self.listen_options),
+        };
+        #[cfg(windows)]
+        let inner = match self.prebound {
+            // SAFETY: The caller of `from_raw_pipe_handle` guarantees that this is an overlapped
+            // server instance of the pipe
+            Some(instance) => unsafe {
+                imp::Incoming::from_instance(
+                    instance,
+                    self.path,
+                    self.security_attributes,
+                    &self.listen_options,
+                )
+            },
+            None => bind(self.path, self.security_attributes, &self.listen_options),
+        };
+
+        #[cfg(feature = "tracing")]
+        match &inner {
+            Ok(_) => tracing::debug!("Listening"),
+            Err(error) => tracing::error!(%error, "Failed to listen"),
+        }
+        let inner = inner?;
+        if let Some(on_ready) = self.on_ready {
+            on_ready(inner.path());
+        }
+        #[cfg(target_os = "linux")]
+        if self.notify_systemd {
+            systemd::notify_ready(inner.path());
+        }
+        Ok(Incoming {
+            inner: Some(inner),
+            draining: self
+                .shutdown
+                .as_ref()
+                .map(|shutdown| Box::pin(shutdown.draining_owned())),
+            shutdown: self.shutdown,
+            on_disconnect: self.on_disconnect,
+            cancelled: self.cancel.map(|cancel| Box::pin(cancel.cancelled_owned())),
+            metrics: self.metrics,
+            event_sink: self.event_sink,
+            server_counters: self.server_counters,
+            #[cfg(unix)]
+            allowlist: self.allowlist,
+            #[cfg(windows)]
+            sid_allowlist: self.sid_allowlist,
+            accept_retry: AcceptRetry::new(self.accept_error_policy, self.accept_backoff),
+            // The quota is checked before the connection is passed to any other layer
+            layers: self
+                .quota
+                .map(|per_user| Arc::new(layer::QuotaLayer::new(per_user)) as _)
+                .into_iter()
+                .chain(self.layers)
+                .collect(),
+            layering: FuturesUnordered::new(),
+            #[cfg(windows)]
+            impersonating: FuturesUnordered::new(),
+            inheritable: self.inheritable,
+            restricted: self.restricted,
+            liveness_responder: self.liveness_responder,
+            memory_limit: self.memory_limit,
+            permits: self.permits.map(PollSemaphore::new),
+            permit: None,
+            #[cfg(feature = "tracing")]
+            span: span.clone(),
+        })
+    }
+}
+
+#[cfg(feature = "client")]
+impl Endpoint {
+    /// Like [`Self::connect`], but give up with [`Cancelled`] as soon as `cancel` is
+    /// cancelled. This also aborts waiting for a busy pipe on Windows. Nothing is connected if
+    /// `cancel` has already been cancelled.
+    pub async fn connect_cancellable(
+        path: impl AsRef<Path>,
+        cancel: &CancellationToken,
+    ) -> io::Result<Connection> {
+        let cancelled = pin!(cancel.cancelled());
+        let connect = pin!(Self::connect(path));
+        match future::select(cancelled, connect).await {
+            Either::Left(((), _)) => Err(io::Error::other(Cancelled)),
+            Either::Right((result, _)) => result,
+        }
+    }
+
+    /// Like [`Self::connect`], but if no server is listening yet, keep trying until one is or
+    /// `timeout` has passed. This lets a client that is started at the same time as the server
+    /// connect once the server is ready, instead of failing immediately.
+    ///
+    /// Only errors that mean that the endpoint does not exist yet are retried. If the timeout
+    /// passes, the last error is returned.
+    pub async fn connect_when_ready(
+        path: impl AsRef<Path>,
+        timeout: Duration,
+    ) -> io::Result<Connection> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        Self::connect_retrying(
+            path.as_ref(),
+            &WAIT_FOR_SERVER_BACKOFF,
+            None,
+            Some(deadline),
+        )
+        .await
+    }
+
+    /// Like [`Self::connect_when_ready`], but wait between attempts as decided by `backoff`
+    /// instead of until a timeout has passed. On Windows, `backoff` also decides how long to
+    /// wait while all pipe instances are busy.
+    pub async fn connect_with_backoff(
+        path: impl AsRef<Path>,
+        backoff: &dyn Backoff,
+    ) -> io::Result<Connection> {
+        Self::connect_retrying(path.as_ref(), backoff, Some(backoff), None).await
+    }
+
+    /// Connect, retrying after the delays decided by `backoff` while no server is listening.
+    /// `busy` is passed on to [`imp::connect`].
+    async fn connect_retrying(
+        path: &Path,
+        backoff: &dyn Backoff,
+        busy: Option<&dyn Backoff>,
+        deadline: Option<tokio::time::Instant>,
+    ) -> io::Result<Connection> {
+        let mut attempt = 0u32;
+        loop {
+            let error = match Self::connect_inner(path, busy).await {
+                Ok(connection) => return Ok(connection),
+                Err(error) if is_not_listening(&error) => error,
+                Err(error) => return Err(error),
+            };
+            attempt = attempt.saturating_add(1);
+            let Some(delay) = backoff.delay(attempt) else {
+                return Err(error);
+            };
+            let now = tokio::time::Instant::now();
+            let retry_at = match deadline {
+                Some(deadline) if now >= deadline => return Err(error),
+                Some(deadline) => (now + delay).min(deadline),
+                None => now + delay,
+            };
+            tokio::time::sleep_until(retry_at).await;
+        }
+    }
+
+    /// Connect to an endpoint that is being listened on. On Windows, [`PipeErrorKind::of`] tells
+    /// the common reasons for failing apart, e.g. to tell the user that the daemon is not running.
+    pub async fn connect(path: impl AsRef<Path>) -> io::Result<Connection> {
+        Self::connect_inner(path.as_ref(), None).await
+    }
+
+    /// Like [`Self::connect`], but check the endpoint as required by `options` first.
+    pub async fn connect_with_options(
+        path: impl AsRef<Path>,
+        options: &ConnectOptions,
+    ) -> io::Result<Connection> {
+        let path = path.as_ref();
+        let connection = Self::connect_inner(path, None).await?;
+        #[cfg(unix)]
+        if let Some(uid) = options.owner {
+            imp::verify_server_user(&connection.inner, uid)
+                .context(Operation::Connect, &path.to_string_lossy())?;
+        }
+        #[cfg(windows)]
+        options
+            .server
+            .verify(&connection.inner)
+            .context(Operation::Connect, &path.to_string_lossy())?;
+        Ok(connection)
+    }
+
+    async fn connect_inner(path: &Path, busy: Option<&dyn Backoff>) -> io::Result<Connection> {
+        let id = ConnectionId::next();
+        #[cfg(feature = "tracing")]
+        let span =
+            tracing::debug_span!("ipc_connection", %id, side = "client", path = %path.display());
+
+        let connect = imp::connect(path, busy);
+        #[cfg(feature = "tracing")]
+        let connect = tracing::Instrument::instrument(connect, span.clone());
+        let inner = connect
+            .await
+            .context(Operation::Connect, &path.to_string_lossy());
+
+        #[cfg(feature = "tracing")]
+        match &inner {
+            Ok(_) => tracing::debug!(parent: &span, "Connected"),
+            Err(error) => tracing::debug!(parent: &span, %error, "Failed to connect"),
+        }
+        let mut connection = Connection::unaccepted(
+            inner?,
+            id,
+            #[cfg(feature = "tracing")]
+            span,
+        );
+        connection.endpoint = Some(path.to_string_lossy().into());
+        Ok(connection)
+    }
+}
+
+/// Whether connecting failed because no server is listening on the endpoint, as opposed to e.g.
+/// lacking permission to connect.
+#[cfg(feature = "client")]
+fn is_not_listening(error: &io::Error) -> bool {
+    // A socket file without a listener, e.g. one left behind by a server that crashed, refuses
+    // connections
+    matches!(
+        error.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+    )
+}
+
+/// An endpoint whose socket has been bound, but that is not listened on yet. See
+/// [`Endpoint::bind`]. The socket is removed, or the pipe closed, if this is dropped.
+#[cfg(feature = "server")]
+pub struct BoundEndpoint {
+    endpoint: Endpoint,
+    socket: imp::BoundSocket,
+}
+
+#[cfg(feature = "server")]
+impl BoundEndpoint {
+    pub fn path(&self) -> &str {
+        self.socket.path()
+    }
+
+    /// Start listening, returning a stream of incoming connections. This fails with
+    /// [`io::ErrorKind::PermissionDenied`] if the socket file, or a directory that leads to it,
+    /// has been replaced since it was bound, or if its owner or mode no longer match the
+    /// security attributes, since someone else could then be listening in the place of the
+    /// server, or be let in by it. A pipe cannot be replaced, so this is only checked on Unix.
+    pub fn incoming(self) -> io::Result<Incoming> {
+        let socket = self.socket;
+        self.endpoint
+            .listen(move |_path, _security_attributes, options| {
+                imp::Incoming::from_bound(socket, options)
+            })
+    }
+}
+
+/// Stream of connections accepted on an [`Endpoint`].
+#[cfg(feature = "server")]
+pub struct Incoming {
+    /// The listener, or `None` once the server has started shutting down.
+    inner: Option<imp::Incoming>,
+    shutdown: Option<ShutdownHandle>,
+    draining: Option<Pin<Box<WaitForCancellationFutureOwned>>>,
+    cancelled: Option<Pin<Box<WaitForCancellationFutureOwned>>>,
+    on_disconnect: Option<DisconnectCallback>,
+    metrics: Option<Arc<dyn IpcMetrics>>,
+    event_sink: Option<Arc<dyn audit::IpcEventSink>>,
+    server_counters: Option<Arc<ServerCounters>>,
+    #[cfg(unix)]
+    allowlist: Option<credentials::PeerAllowlist>,
+    #[cfg(windows)]
+    sid_allowlist: Option<Arc<identity::SidAllowlist>>,
+    accept_retry: AcceptRetry,
+    layers: Arc<[Arc<dyn layer::ConnectionLayer>]>,
+    /// Accepted connections that are passing through the layers.
+    layering: FuturesUnordered<Pin<Box<dyn Future<Output = Option<Connection>> + Send>>>,
+    /// Accepted connections whose clients are yet to send something, so that they can be
+    /// impersonated, with the permits that were acquired for them.
+    #[cfg(windows)]
+    impersonating: FuturesUnordered<Pin<Box<dyn Future<Output = Impersonated> + Send>>>,
+    /// Whether accepted connections may be inherited by child processes.
+    inheritable: bool,
+    /// Whether accepted connections are restricted.
+    restricted: bool,
+    /// Whether liveness probes are answered on accepted connections.
+    liveness_responder: bool,
+    memory_limit: Option<usize>,
+    permits: Option<PollSemaphore>,
+    /// Permit acquired for the next connection to be accepted.
+    permit: Option<OwnedSemaphorePermit>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+#[cfg(feature = "server")]
+impl Stream for Incoming {
+    type Item = io::Result<Connection>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if let Some(cancelled) = &mut this.cancelled {
+            if cancelled.as_mut().poll(cx).is_ready() {
+                this.cancelled = None;
+                this.inner = None;
+                this.drop_layering();
+            }
+        }
+        if let Some(draining) = &mut this.draining {
+            if draining.as_mut().poll(cx).is_ready() {
+                this.draining = None;
+                this.park();
+            }
+        }
+        if this.inner.is_none() {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            if let Poll::Ready(Some(layered)) = this.layering.poll_next_unpin(cx) {
+                match layered {
+                    Some(connection) => return Poll::Ready(Some(Ok(connection))),
+                    None => continue,
+                }
+            }
+            // The layers wake this up once a connection has passed through them
+            if this.layering.len() >= layer::MAX_LAYERING {
+                return Poll::Pending;
+            }
+            match ready!(this.poll_accept(cx)) {
+                Some(Ok(connection))
+                    if connection.liveness.is_some() || !this.layers.is_empty() =>
+                {
+                    let layers = this.layers.clone();
+                    this.layering
+                        .push(Box::pin(layer::apply(layers, connection)));
+                }
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Incoming {
+    /// Accept the next connection that is admitted, before passing it through the layers.
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<Connection>>> {
+        loop {
+            #[cfg(windows)]
+            while let Poll::Ready(Some((inner, permit, impersonated))) =
+                self.impersonating.poll_next_unpin(cx)
+            {
+                let admission = Self::admit_impersonated(&inner, impersonated);
+                if let Some(result) = self.admitted(inner, permit, admission) {
+                    return Poll::Ready(Some(result));
+                }
+            }
+            #[cfg(windows)]
+            if self.impersonating.len() >= identity::MAX_IMPERSONATING {
+                return Poll::Pending;
+            }
+
+            if let (Some(permits), None) = (&mut self.permits, &self.permit) {
+                match ready!(permits.poll_acquire(cx)) {
+                    Some(permit) => self.permit = Some(permit),
+                    None => return Poll::Ready(None),
+                }
+            }
+            let Some(inner) = &mut self.inner else {
+                return Poll::Ready(None);
+            };
+            let result = ready!(self.accept_retry.poll_accept(inner, cx, |error| {
+                if let Some(metrics) = &self.metrics {
+                    metrics.connection_rejected(error);
+                }
+                #[cfg(feature = "tracing")]
+                tracing::warn!(parent: &self.span, %error, "Failed to accept connection");
+            }));
+            let accepted = match result {
+                Some(Ok(accepted)) => accepted,
+                Some(Err(error)) => {
+                    let error = context::with_context(
+                        error,
+                        Operation::Accept,
+                        Some(inner.shared_path().clone()),
+                    );
+                    self.failed_to_accept(&error);
+                    return Poll::Ready(Some(Err(error)));
+                }
+                None => return Poll::Ready(None),
+            };
+            let permit = self.permit.take();
+
+            #[cfg(windows)]
+            if let Some(allowlist) = self.sid_allowlist.clone() {
+                self.impersonating.push(Box::pin(async move {
+                    let mut inner = accepted;
+                    let impersonated = identity::impersonate_first(&mut inner, &allowlist).await;
+                    (inner, permit, impersonated)
+                }));
+                continue;
+            }
+
+            #[cfg(unix)]
+            let admission = self.admit(&accepted);
+            #[cfg(not(unix))]
+            let admission = Ok(Admission::default());
+            if let Some(result) = self.admitted(accepted, permit, admission) {
+                return Poll::Ready(Some(result));
+            }
+        }
+    }
+
+    /// Set up a connection that has been admitted, or close it if it has been rejected. Returns
+    /// `None` if it was rejected.
+    fn admitted(
+        &mut self,
+        inner: imp::Connection,
+        permit: Option<OwnedSemaphorePermit>,
+        admission: Result<Admission, Rejection>,
+    ) -> Option<io::Result<Connection>> {
+        let admission = match admission {
+            Ok(admission) => admission,
+            Err(rejection) => {
+                self.reject(inner, rejection);
+                return None;
+            }
+        };
+        if self.inheritable
+            && let Err(error) = imp::set_inheritable(&inner, true)
+        {
+            self.failed_to_accept(&error);
+            return Some(Err(error));
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.connection_accepted();
+        }
+        Some(Ok(self.accepted(inner, permit, admission)))
+    }
+
+    /// Close the connections that are still passing through the layers, since they can no
+    /// longer be yielded.
+    fn drop_layering(&mut self) {
+        if self.layering.is_empty() {
+            return;
+        }
+        log::debug!(
+            "Closing {} IPC connections that were passing through the layers",
+            self.layering.len()
+        );
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            parent: &self.span,
+            count = self.layering.len(),
+            "Closing connections in the layers"
+        );
+        self.layering.clear();
+    }
+
+    /// Count and trace a connection that could not be accepted.
+    fn failed_to_accept(&self, error: &io::Error) {
+        if let Some(metrics) = &self.metrics {
+            metrics.connection_rejected(error);
+        }
+        #[cfg(feature = "tracing")]
+        tracing::warn!(parent: &self.span, %error, "Failed to accept connection");
+    }
+
+    /// Memory limit of accepted connections, see [`Endpoint::set_memory_limit`].
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    /// Stop accepting connections, and close the listener, removing the socket before returning.
+    /// Dropping does the same, but cannot report failures, and does not happen at all if the
+    /// process exits before the task that owns the listener is dropped. Connections that have
+    /// been accepted stay open, but those that are still being passed through the layers are
+    /// closed.
+    ///
+    /// If the server is being drained, the listener is handed over to the shutdown handle
+    /// instead, which closes it once the server has been drained. This then returns once that
+    /// has happened, so that the socket is gone either way.
+    pub async fn close(mut self) -> io::Result<()> {
+        self.drop_layering();
+        #[cfg(windows)]
+        self.impersonating.clear();
+        if let Some(shutdown) = self.shutdown.clone()
+            && shutdown.is_draining()
+        {
+            self.park();
+            shutdown.drained().await;
+            return Ok(());
+        }
+        self.inner.take().map_or(Ok(()), imp::Incoming::close)
+    }
+
+    /// Stop accepting, and hand the listener over to the shutdown handle, which closes it once
+    /// the server has been drained.
+    fn park(&mut self) {
+        if let (Some(shutdown), Some(inner)) = (&self.shutdown, self.inner.take()) {
+            shutdown.park(inner);
+        }
+    }
+
+    /// Decide whether an accepted connection may be served.
+    #[cfg(unix)]
+    fn admit(&self, inner: &imp::Connection) -> Result<Admission, Rejection> {
+        if let Some(allowlist) = &self.allowlist {
+            let socket = std::os::fd::AsRawFd::as_raw_fd(inner);
+            let credentials =
+                credentials::peer_credentials(socket).map_err(Rejection::Unidentified)?;
+            if !allowlist.allows(&credentials) {
+                return Err(Rejection::Denied {
+                    reason: format!(
+                        "UID {} and GID {} are not allowed",
+                        credentials.uid(),
+                        credentials.gid()
+                    ),
+                    peer: imp::credentials_peer_info(&credentials),
+                });
+            }
+        }
+        Ok(Admission::default())
+    }
+
+    /// Decide whether a connection whose client has been impersonated may be served.
+    #[cfg(windows)]
+    fn admit_impersonated(
+        inner: &imp::Connection,
+        impersonated: io::Result<(identity::PeerIdentity, u8)>,
+    ) -> Result<Admission, Rejection> {
+        let (identity, first_byte) = impersonated.map_err(Rejection::Unidentified)?;
+        if identity.allowed() == Some(false) {
+            return Err(Rejection::Denied {
+                reason: format!("SID {} is not allowed", identity.user()),
+                peer: metrics::PeerInfo {
+                    user: Some(identity.user().to_owned()),
+                    ..imp::peer_info(inner)
+                },
+            });
+        }
+        Ok(Admission {
+            identity: Some(identity),
+            first_byte: Some(first_byte),
+        })
+    }
+
+    /// Close a connection that may not be served. Peers that are told why are turned away by
+    /// layers instead, see [`Connection::reject`], so that nothing is written to them here.
+    fn reject(&self, inner: imp::Connection, rejection: Rejection) {
+        let error = rejection.to_error();
+        // Only looked up if it is reported at all, and not if it is already known
+        let peer = LazyCell::new(|| match &rejection {
+            Rejection::Denied { peer, .. } => peer.clone(),
+            _ => imp::peer_info(&inner),
+        });
+        log_limit::closed_connection(
+            || log_limit::peer_key(&peer),
+            format_args!("Rejecting IPC connection: {error}"),
+        );
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &self.span, %error, "Rejected connection");
+        if let Some(metrics) = &self.metrics {
+            metrics.connection_rejected(&error);
+            metrics.handshake_failed(&HandshakeFailure {
+                peer: (*peer).clone(),
+                reason: rejection.failure_reason(),
+            });
+        }
+        if let Some(sink) = &self.event_sink {
+            sink.event(&audit::AuditEvent::new(
+                None,
+                (*peer).clone(),
+                audit::AuditEventKind::Rejected(rejection.failure_reason()),
+            ));
+        }
+    }
+
+    fn accepted(
+        &mut self,
+        inner: imp::Connection,
+        permit: Option<OwnedSemaphorePermit>,
+        admission: Admission,
+    ) -> Connection {
+        let id = ConnectionId::next();
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(parent: &self.span, "ipc_connection", %id, side = "server");
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &span, "Accepted connection");
+        let audit = self.event_sink.as_ref().map(|sink| {
+            let peer = imp::peer_info(&inner);
+            #[cfg(windows)]
+            let peer = metrics::PeerInfo {
+                user: admission
+                    .identity
+                    .as_ref()
+                    .map(|identity| identity.user().to_owned()),
+                ..peer
+            };
+            sink.event(&audit::AuditEvent::new(
+                Some(id),
+                peer.clone(),
+                audit::AuditEventKind::Connected,
+            ));
+            (sink.clone(), peer)
+        });
+        #[cfg(windows)]
+        let mut first_byte = admission.first_byte;
+        // The liveness responder looks at the start of the connection, including what was read
+        let liveness = self.liveness_responder.then(|| {
+            #[cfg(windows)]
+            if let Some(byte) = first_byte.take() {
+                return health::LivenessResponder::starting_with(&[byte]);
+            }
+            health::LivenessResponder::new()
+        });
+        Connection {
+            endpoint: self
+                .inner
+                .as_ref()
+                .map(|listener| listener.shared_path().clone()),
+            inner,
+            id,
+            extensions: extensions::Extensions::new(),
+            counters: ConnectionCounters::new(self.server_counters.clone()),
+            metrics: self.metrics.clone(),
+            audit,
+            on_disconnect: self.on_disconnect.clone(),
+            disconnected: false,
+            _permit: permit,
+            _quota: None,
+            #[cfg(windows)]
+            identity: admission.identity,
+            #[cfg(windows)]
+            unread: first_byte.map_or_else(BytesMut::new, |byte| BytesMut::from(&[byte][..])),
+            #[cfg(not(windows))]
+            unread: BytesMut::new(),
+            #[cfg(windows)]
+            sid_allowlist: self.sid_allowlist.clone(),
+            shutdown: self.shutdown.as_ref().map(ShutdownHandle::register),
+            #[cfg(feature = "server")]
+            control: None,
+            restricted: self.restricted,
+            memory_limit: self.memory_limit,
+            liveness,
+            #[cfg(feature = "encryption")]
+            records: None,
+            #[cfg(feature = "tracing")]
+            span,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        // The socket must not be removed while the server is being drained
+        if self
+            .shutdown
+            .as_ref()
+            .is_some_and(ShutdownHandle::is_draining)
+        {
+            self.park();
+        }
+    }
+}
+
+/// What was found out about the peer of an accepted connection while admitting it.
+#[cfg(feature = "server")]
+#[derive(Default)]
+struct Admission {
+    #[cfg(windows)]
+    identity: Option<identity::PeerIdentity>,
+    /// Byte that was read to impersonate the client.
+    #[cfg(windows)]
+    first_byte: Option<u8>,
+}
+
+/// A connection whose client has been impersonated, see [`identity::impersonate_first`].
+#[cfg(all(windows, feature = "server"))]
+type Impersonated = (
+    imp::Connection,
+    Option<OwnedSemaphorePermit>,
+    io::Result<(identity::PeerIdentity, u8)>,
+);
+
+/// Why an accepted connection is not served.
+#[cfg(feature = "server")]
+enum Rejection {
+    /// The user of the peer could not be determined.
+    Unidentified(io::Error),
+    /// The peer is not allowed to connect, and is disconnected without being told why. Who it
+    /// is was found out while deciding so.
+    Denied {
+        reason: String,
+        peer: metrics::PeerInfo,
+    },
+    /// The peer is told why it was rejected before the connection is closed.
+    Rejected(RejectReason),
+}
+
+#[cfg(feature = "server")]
+impl Rejection {
+    /// Error that the connection is reported to have been rejected with.
+    fn to_error(&self) -> io::Error {
+        match self {
+            Rejection::Unidentified(error) => {
+                io::Error::new(error.kind(), format!("Failed to identify peer: {error}"))
+            }
+            Rejection::Denied { reason, .. } => {
+                io::Error::new(io::ErrorKind::PermissionDenied, reason.as_str())
+            }
+            Rejection::Rejected(reason) => io::Error::other(reason.to_string()),
+        }
+    }
+
+    fn failure_reason(&self) -> HandshakeFailureReason {
+        match self {
+            Rejection::Unidentified(error) => {
+                HandshakeFailureReason::Unidentified(error.to_string())
+            }
+            Rejection::Denied { reason, .. } => HandshakeFailureReason::Denied(reason.clone()),
+            Rejection::Rejected(RejectReason::QuotaExceeded) => {
+                HandshakeFailureReason::QuotaExceeded
+            }
+            Rejection::Rejected(reason) => HandshakeFailureReason::Denied(reason.to_string()),
+        }
+    }
+}
+
+/// A connected IPC stream, either accepted by a server or established by a client.
+pub struct Connection {
+    inner: imp::Connection,
+    id: ConnectionId,
+    /// Socket path or pipe name of the endpoint, if the connection belongs to one.
+    endpoint: Option<Arc<str>>,
+    extensions: extensions::Extensions,
+    counters: Arc<ConnectionCounters>,
+    metrics: Option<Arc<dyn IpcMetrics>>,
+    /// Sink that the end of the connection is reported to, and the peer as it was admitted.
+    #[cfg(feature = "server")]
+    audit: Option<(Arc<dyn audit::IpcEventSink>, metrics::PeerInfo)>,
+    on_disconnect: Option<DisconnectCallback>,
+    /// Whether `on_disconnect` has been called.
+    disconnected: bool,
+    /// Permit that was required to accept this connection.
+    #[cfg(feature = "server")]
+    _permit: Option<OwnedSemaphorePermit>,
+    /// Counts against the quota of the user of the peer until dropped.
+    #[cfg(feature = "server")]
+    _quota: Option<QuotaGuard>,
+    #[cfg(windows)]
+    identity: Option<identity::PeerIdentity>,
+    /// Input that has been read ahead and has yet to be read by the server, e.g. the byte that
+    /// was read to impersonate the client, or what the framing of a layer read.
+    unread: BytesMut,
+    #[cfg(windows)]
+    sid_allowlist: Option<Arc<identity::SidAllowlist>>,
+    shutdown: Option<ShutdownSignal>,
+    /// Set while the connection is served by an [`server::IpcServer`].
+    #[cfg(feature = "server")]
+    control: Option<server::ConnectionControl>,
+    /// Whether the connection was accepted on a restricted endpoint.
+    restricted: bool,
+    /// See [`Endpoint::set_memory_limit`].
+    memory_limit: Option<usize>,
+    /// Answers a liveness probe, see [`Endpoint::set_liveness_responder`].
+    #[cfg(feature = "server")]
+    liveness: Option<health::LivenessResponder>,
+    /// Encrypts what is sent and received, see [`encryption`].
+    #[cfg(feature = "encryption")]
+    records: Option<Box<encryption::RecordLayer>>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl Connection {
+    /// Wrap a connection that was not accepted on an endpoint, e.g. one established by a
+    /// client, without any of the settings of an endpoint.
+    fn unaccepted(
+        inner: imp::Connection,
+        id: ConnectionId,
+        #[cfg(feature = "tracing")] span: tracing::Span,
+    ) -> Self {
+        Connection {
+            inner,
+            id,
+            endpoint: None,
+            extensions: extensions::Extensions::new(),
+            counters: ConnectionCounters::new(None),
+            metrics: None,
+            #[cfg(feature = "server")]
+            audit: None,
+            on_disconnect: None,
+            disconnected: false,
+            #[cfg(feature = "server")]
+            _permit: None,
+            #[cfg(feature = "server")]
+            _quota: None,
+            #[cfg(windows)]
+            identity: None,
+            unread: BytesMut::new(),
+            #[cfg(windows)]
+            sid_allowlist: None,
+            shutdown: None,
+            #[cfg(feature = "server")]
+            control: None,
+            restricted: false,
+            memory_limit: None,
+            #[cfg(feature = "server")]
+            liveness: None,
+            #[cfg(feature = "encryption")]
+            records: None,
+            #[cfg(feature = "tracing")]
+            span,
+        }
+    }
+
+    /// Flush and shut down the connection, so that the peer sees it end once it has read what
+    /// was written. Dropping the connection closes it without waiting. Frames are not flushed by
+    /// this, and nothing is said to the peer, since the connection does not know what protocol
+    /// is spoken over it. See [`Self::close_with_goodbye`] and
+    /// [`frame::FramedConnection::close`].
+    pub async fn close(mut self) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        self.flush().await?;
+        self.shutdown().await
+    }
+
+    /// Say goodbye to the peer in the [framing](frame), telling it why, and then close the
+    /// connection like [`Self::close`]. Only use this on connections whose peer reads frames,
+    /// e.g. to turn a client away before the connection has been framed.
+    pub async fn close_with_goodbye(self, reason: frame::GoodbyeReason) -> Result<(), Error> {
+        frame::FramedConnection::new(self)
+            .close_with_reason(reason)
+            .await
+    }
+
+    /// Encrypt what is sent and received from now on with `records`. Probes can only be sent
+    /// before anything else, so they are no longer answered.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn set_records(&mut self, records: encryption::RecordLayer) {
+        #[cfg(feature = "server")]
+        {
+            self.liveness = None;
+        }
+        self.records = Some(Box::new(records));
+    }
+
+    /// ID of this connection. It is also recorded in the tracing span of the connection, and
+    /// reported in [`Disconnect`] events.
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Socket path or pipe name of the endpoint that the connection was accepted on or
+    /// connected to. `None` for connections that do not belong to an endpoint, such as
+    /// [pairs](Endpoint::socketpair).
+    pub fn endpoint_path(&self) -> Option<&str> {
+        self.endpoint.as_deref()
+    }
+
+    /// Address of the peer. On Unix, this is what the socket of the peer is bound to, which is
+    /// nothing for most clients. On Windows, this is the name of the pipe.
+    pub fn peer_address(&self) -> io::Result<PeerAddress> {
+        imp::peer_address(&self.inner, self.endpoint_path())
+    }
+
+    /// Find out who the peer is, as far as possible. This is only meant for reporting, see
+    /// [`metrics::PeerInfo`]. Looking up the executable may block.
+    pub fn peer_info(&self) -> metrics::PeerInfo {
+        let mut peer = self.known_peer_info();
+        peer.complete();
+        peer
+    }
+
+    /// Who the peer is, as far as is known without looking the process up.
+    fn known_peer_info(&self) -> metrics::PeerInfo {
+        #[cfg(windows)]
+        if let Some(identity) = &self.identity {
+            return metrics::PeerInfo {
+                user: Some(identity.user().to_owned()),
+                ..imp::peer_info(&self.inner)
+            };
+        }
+        imp::peer_info(&self.inner)
+    }
+
+    /// User of the peer, as counted by [`Endpoint::set_connection_quota`].
+    #[cfg(feature = "server")]
+    fn quota_user(&self) -> io::Result<imp::PeerUser> {
+        #[cfg(windows)]
+        if let Some(identity) = &self.identity {
+            return Ok(identity.user().to_owned());
+        }
+        imp::peer_user(&self.inner)
+    }
+
+    /// Turn away the peer of a connection that has already been accepted, e.g. by a
+    /// [layer](layer). It is reported like a peer that was not admitted, told why if
+    /// appropriate, and the connection is closed. Returns the error to close it with.
+    #[cfg(feature = "server")]
+    async fn reject(mut self, rejection: Rejection) -> io::Error {
+        let error = rejection.to_error();
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &self.span, %error, "Rejected connection");
+        // Reported as rejected rather than as disconnected
+        if let Some((sink, peer)) = self.audit.take() {
+            sink.event(&audit::AuditEvent::new(
+                Some(self.id),
+                peer,
+                audit::AuditEventKind::Rejected(rejection.failure_reason()),
+            ));
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.connection_rejected(&error);
+            metrics.handshake_failed(&HandshakeFailure {
+                peer: self.known_peer_info(),
+                reason: rejection.failure_reason(),
+            });
+        }
+        if let Rejection::Rejected(reason) = rejection {
+            let mut connection = frame::FramedConnection::new(self);
+            let _ = connection.write_frame(&frame::Frame::reject(reason)).await;
+        }
+        error
+    }
+
+    /// Report that the peer failed the handshake to the metrics of the endpoint, if any.
+    fn report_handshake_failure(&self, reason: HandshakeFailureReason) {
+        #[cfg(feature = "server")]
+        if let Some((sink, peer)) = &self.audit {
+            sink.event(&audit::AuditEvent::new(
+                Some(self.id),
+                peer.clone(),
+                audit::AuditEventKind::HandshakeFailed(reason.clone()),
+            ));
+        }
+        let Some(metrics) = &self.metrics else {
+            return;
+        };
+        let failure = HandshakeFailure {
+            peer: self.known_peer_info(),
+            reason,
+        };
+        log_limit::log(
+            log::Level::Debug,
+            || log_limit::peer_key(&failure.peer),
+            format_args!("IPC handshake failed: {failure}"),
+        );
+        metrics.handshake_failed(&failure);
+    }
+
+    /// Traffic on this connection so far. Frames are only counted if the counters have been
+    /// given to the framing, see [`Self::counters`].
+    pub fn stats(&self) -> ConnectionStats {
+        self.counters.snapshot()
+    }
+
+    /// Counters of the traffic on this connection. Pass them to
+    /// [`frame::FramedConnection::set_counters`] to count frames as well.
+    pub fn counters(&self) -> &Arc<ConnectionCounters> {
+        &self.counters
+    }
+
+    /// Data that the layers serving this connection have attached to it. See [`extensions`].
+    pub fn extensions(&self) -> &extensions::Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut extensions::Extensions {
+        &mut self.extensions
+    }
+
+    /// Whether the connection was accepted on an endpoint that was marked as restricted with
+    /// [`Endpoint::set_restricted`]. The server should only let such peers perform the requests
+    /// that it deems safe for sandboxed clients. Always `false` for connections established by a
+    /// client.
+    pub fn is_restricted(&self) -> bool {
+        self.restricted
+    }
+
+    /// Memory limit that was set with [`Endpoint::set_memory_limit`] on the endpoint that
+    /// accepted the connection. Pass it to [`frame::FramedConnection::set_memory_limit`] unless
+    /// the framing is controlled by an [`server::IpcServer`], which applies it.
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    /// Signal that tells an accepted connection that the server is shutting down. `None` for
+    /// connections established by a client, or if no [`shutdown::ShutdownHandle`] has been
+    /// installed.
+    pub fn shutdown_signal(&self) -> Option<&ShutdownSignal> {
+        self.shutdown.as_ref()
+    }
+
+    /// Span that all events of this connection are recorded in. Instrument tasks that serve the
+    /// connection with it to correlate their events with the connection.
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Lets the [`server::IpcServer`] that serves this connection say goodbye to the peer and
+    /// probe it. Pass it to [`frame::FramedConnection::set_control`]. `None` unless the
+    /// connection is served by an `IpcServer`.
+    #[cfg(feature = "server")]
+    pub fn control(&self) -> Option<&server::ConnectionControl> {
+        self.control.as_ref()
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn set_control(&mut self, control: server::ConnectionControl) {
+        self.control = Some(control);
+    }
+
+    /// Report a failed I/O operation to the metrics, if any.
+    fn record_error<T>(&self, result: &Poll<io::Result<T>>) {
+        let Poll::Ready(Err(error)) = result else {
+            return;
+        };
+        if let Some(metrics) = &self.metrics {
+            metrics.error(error);
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &self.span, %error, "I/O error");
+    }
+
+    /// Like [`Self::record_error`], and also report a disconnected peer to the callback.
+    fn record_write_error<T>(&mut self, result: &Poll<io::Result<T>>) {
+        self.record_error(result);
+        let Poll::Ready(Err(error)) = result else {
+            return;
+        };
+        if self.disconnected || !disconnect::is_peer_gone(error) {
+            return;
+        }
+        self.disconnected = true;
+        if let Some(on_disconnect) = &self.on_disconnect {
+            on_disconnect(&Disconnect {
+                connection: self.id,
+                kind: error.kind(),
+            });
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let this = &mut *self;
+        let result = 'read: {
+            if !this.unread.is_empty() && buf.remaining() > 0 {
+                let len = this.unread.len().min(buf.remaining());
+                buf.put_slice(&this.unread.split_to(len));
+                break 'read Poll::Ready(Ok(()));
+            }
+            #[cfg(feature = "encryption")]
+            if let Some(records) = &mut this.records {
+                break 'read records.poll_read(&mut this.inner, cx, buf);
+            }
+            #[cfg(feature = "server")]
+            if let Some(liveness) = &mut this.liveness {
+                break 'read liveness.poll_read(&mut this.inner, cx, buf);
+            }
+            Pin::new(&mut this.inner).poll_read(cx, buf)
+        };
+        if let Poll::Ready(Ok(())) = &result {
+            let len = buf.filled().len() - filled_before;
+            self.counters.record_received(len);
+            if let Some(metrics) = &self.metrics {
+                metrics.bytes_read(len);
+            }
+            #[cfg(feature = "tracing")]
+            tracing::trace!(parent: &self.span, len, "Read");
+        }
+        self.record_error(&result);
+        result
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let result = 'write: {
+            #[cfg(feature = "encryption")]
+            if let Some(records) = &mut this.records {
+                break 'write records.poll_write(&mut this.inner, cx, buf);
+            }
+            Pin::new(&mut this.inner).poll_write(cx, buf)
+        };
+        if let Poll::Ready(Ok(len)) = &result {
+            self.counters.record_sent(*len);
+            if let Some(metrics) = &self.metrics {
+                metrics.bytes_written(*len);
+            }
+            #[cfg(feature = "tracing")]
+            tracing::trace!(parent: &self.span, len, "Wrote");
+        }
+        self.record_write_error(&result);
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let result = 'flush: {
+            #[cfg(feature = "encryption")]
+            if let Some(records) = &mut this.records {
+                break 'flush records.poll_flush(&mut this.inner, cx);
+            }
+            Pin::new(&mut this.inner).poll_flush(cx)
+        };
+        self.record_write_error(&result);
+        result
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let result = 'shutdown: {
+            #[cfg(feature = "encryption")]
+            if let Some(records) = &mut this.records {
+                break 'shutdown records.poll_shutdown(&mut this.inner, cx);
+            }
+            Pin::new(&mut this.inner).poll_shutdown(cx)
+        };
+        self.record_write_error(&result);
+        result
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.connection_closed();
+        }
+        #[cfg(feature = "server")]
+        if let Some((sink, peer)) = self.audit.take() {
+            sink.event(&audit::AuditEvent::new(
+                Some(self.id),
+                peer,
+                audit::AuditEventKind::Disconnected,
+            ));
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &self.span, "Closed connection");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_transient() {
+        let refused = Error::Io(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert!(refused.is_transient());
+        assert_eq!(refused.retry_after(), Some(RESTART_RETRY_AFTER));
+
+        let denied = Error::Io(context::with_context(
+            io::Error::from(io::ErrorKind::PermissionDenied),
+            Operation::Connect,
+            Some("/run/daemon.sock".into()),
+        ));
+        assert!(!denied.is_transient());
+        assert_eq!(denied.retry_after(), None);
+
+        assert!(Error::Goodbye(GoodbyeReason::ShuttingDown).is_transient());
+        assert!(!Error::Goodbye(GoodbyeReason::Evicted).is_transient());
+        assert!(Error::Rejected(RejectReason::QuotaExceeded).is_transient());
+        assert!(!Error::IncompatiblePeer { theirs: 1, ours: 2 }.is_transient());
+        assert!(!Error::Protocol("Expected a response").is_transient());
+
+        let cancelled = Error::Io(io::Error::other(Cancelled));
+        assert!(cancelled.is_cancelled());
+        assert!(!cancelled.is_transient());
+        assert!(Error::Io(io::Error::from(io::ErrorKind::Interrupted)).is_transient());
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_cancel_accept() {
+        let cancel = CancellationToken::new();
+        let mut endpoint = testing::EphemeralEndpoint::with_options(|endpoint| {
+            endpoint.set_cancellation_token(cancel.clone());
+        })
+        .unwrap();
+
+        let accept = endpoint.incoming().next();
+        let ((), accepted) = tokio::join!(async { cancel.cancel() }, accept);
+        assert!(accepted.is_none());
+        // The listener is closed immediately
+        #[cfg(unix)]
+        assert!(!Path::new(endpoint.path()).exists());
+        assert!(endpoint.connect().await.is_err());
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_connect_cancellable() {
+        let mut endpoint = testing::EphemeralEndpoint::new().unwrap();
+        let cancel = CancellationToken::new();
+        let (client, server) = tokio::join!(
+            Endpoint::connect_cancellable(endpoint.path(), &cancel),
+            endpoint.accept()
+        );
+        client.unwrap();
+        server.unwrap();
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_connect_cancelled() {
+        let path = testing::EphemeralPath::new().unwrap();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let error = Endpoint::connect_cancellable(path.path(), &cancel)
+            .await
+            .err()
+            .unwrap();
+        assert!(Cancelled::is(&error));
+        assert_ne!(error.kind(), io::ErrorKind::Interrupted);
+    }
+}
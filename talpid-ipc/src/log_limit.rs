@@ -0,0 +1,169 @@
+//! Rate limiting of log messages about misbehaving peers.
+//!
+//! A client that keeps reconnecting and failing, e.g. because it sends malformed frames or is
+//! not allowed to connect, would otherwise have a message logged for every attempt, and could
+//! fill the log of the daemon. Messages are instead limited to [`BURST`] per [`WINDOW`] for each
+//! key, usually the [`peer_key`] of the peer. How many messages were left out is logged together
+//! with the next one that is not.
+
+use crate::metrics::PeerInfo;
+use std::{collections::BTreeMap, fmt, mem, sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+/// Period over which messages are counted.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Number of messages that are logged for a key per [`WINDOW`].
+const BURST: u32 = 5;
+
+/// Number of keys that are counted separately. Any further keys share a count, so that peers
+/// cannot use up memory by using many keys.
+const MAX_KEYS: usize = 256;
+
+/// Key that is shared once there are [`MAX_KEYS`] keys.
+const OVERFLOW_KEY: &str = "";
+
+static LIMITER: LogLimiter = LogLimiter::new();
+
+/// Log `message` at `level`, unless too many messages have been logged for the key returned by
+/// `key` lately. The key is only computed if messages at `level` are logged at all.
+pub(crate) fn log<K: AsRef<str>>(
+    level: log::Level,
+    key: impl FnOnce() -> K,
+    message: fmt::Arguments<'_>,
+) {
+    if !log::log_enabled!(level) {
+        return;
+    }
+    match LIMITER.admit(key().as_ref(), Instant::now()) {
+        Some(0) => log::log!(level, "{message}"),
+        Some(suppressed) => {
+            log::log!(
+                level,
+                "{message} ({suppressed} similar messages were suppressed)"
+            )
+        }
+        None => (),
+    }
+}
+
+/// Log a debug message about a connection that is closed before it is served. Clients that keep
+/// reconnecting would otherwise have one logged for every attempt.
+#[cfg(feature = "server")]
+pub(crate) fn closed_connection<K: AsRef<str>>(
+    key: impl FnOnce() -> K,
+    message: fmt::Arguments<'_>,
+) {
+    log(log::Level::Debug, key, message);
+}
+
+/// Key that the messages about `peer` are limited by: its user, or its process if the user is
+/// not known, e.g. on Windows before the client has been identified.
+pub(crate) fn peer_key(peer: &PeerInfo) -> String {
+    match (&peer.user, peer.pid) {
+        (Some(user), _) => user.clone(),
+        (None, Some(pid)) => format!("PID {pid}"),
+        (None, None) => "unknown".to_owned(),
+    }
+}
+
+struct LogLimiter {
+    keys: Mutex<BTreeMap<String, Window>>,
+}
+
+/// What has been logged for a key in the current window.
+struct Window {
+    started: Instant,
+    logged: u32,
+    /// Number of messages that have been left out since the last one was logged.
+    suppressed: u64,
+}
+
+impl LogLimiter {
+    const fn new() -> Self {
+        LogLimiter {
+            keys: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Return the number of messages that were suppressed before this one, or `None` if this
+    /// one should be suppressed as well.
+    fn admit(&self, key: &str, now: Instant) -> Option<u64> {
+        let mut keys = self.keys.lock().unwrap();
+        let mut key = key;
+        if !keys.contains_key(key) && keys.len() >= MAX_KEYS {
+            keys.retain(|_, window| now.duration_since(window.started) < WINDOW);
+            if keys.len() >= MAX_KEYS {
+                key = OVERFLOW_KEY;
+            }
+        }
+        let window = keys.entry(key.to_owned()).or_insert(Window {
+            started: now,
+            logged: 0,
+            suppressed: 0,
+        });
+        if now.duration_since(window.started) >= WINDOW {
+            window.started = now;
+            window.logged = 0;
+        }
+        if window.logged >= BURST {
+            window.suppressed += 1;
+            return None;
+        }
+        window.logged += 1;
+        Some(mem::take(&mut window.suppressed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_burst() {
+        let limiter = LogLimiter::new();
+        for _ in 0..BURST {
+            assert_eq!(limiter.admit("1000", Instant::now()), Some(0));
+        }
+        assert_eq!(limiter.admit("1000", Instant::now()), None);
+        assert_eq!(limiter.admit("1000", Instant::now()), None);
+        // Other peers are counted separately
+        assert_eq!(limiter.admit("1001", Instant::now()), Some(0));
+
+        tokio::time::advance(WINDOW).await;
+        assert_eq!(limiter.admit("1000", Instant::now()), Some(2));
+        assert_eq!(limiter.admit("1000", Instant::now()), Some(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_many_keys() {
+        let limiter = LogLimiter::new();
+        for key in 0..MAX_KEYS {
+            limiter.admit(&key.to_string(), Instant::now());
+        }
+        for _ in 0..BURST {
+            assert_eq!(limiter.admit("new", Instant::now()), Some(0));
+        }
+        // New keys share a count once there are too many
+        assert_eq!(limiter.admit("newer", Instant::now()), None);
+        assert_eq!(limiter.keys.lock().unwrap().len(), MAX_KEYS + 1);
+
+        // Until the old ones expire
+        tokio::time::advance(WINDOW).await;
+        assert_eq!(limiter.admit("newer", Instant::now()), Some(0));
+    }
+
+    #[test]
+    fn test_peer_key() {
+        let mut peer = PeerInfo {
+            user: Some("1000".to_owned()),
+            pid: Some(42),
+            exe: None,
+        };
+        assert_eq!(peer_key(&peer), "1000");
+        peer.user = None;
+        assert_eq!(peer_key(&peer), "PID 42");
+        peer.pid = None;
+        assert_eq!(peer_key(&peer), "unknown");
+    }
+}
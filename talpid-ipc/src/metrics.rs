@@ -0,0 +1,265 @@
+//! Hooks for observing the health of an IPC server.
+//!
+//! Install an [`IpcMetrics`] implementation with [`Endpoint::set_metrics`] to be told about
+//! every accepted connection and the traffic on it. [`IpcCounters`] is a ready-made
+//! implementation that keeps running totals.
+//!
+//! Peers that are turned away, or that fail to negotiate a protocol version, are reported to
+//! [`IpcMetrics::handshake_failed`] together with who they are, so that administrators can see
+//! who is probing the endpoint.
+//!
+//! [`Endpoint::set_metrics`]: crate::Endpoint::set_metrics
+
+use std::{
+    fmt, io,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Receives events from the connections accepted on an endpoint. All methods have empty default
+/// implementations. They are called from within `poll` functions, so they must not block.
+pub trait IpcMetrics: Send + Sync {
+    /// A connection was accepted.
+    fn connection_accepted(&self) {}
+
+    /// Accepting a connection failed.
+    fn connection_rejected(&self, _error: &io::Error) {}
+
+    /// A peer was not allowed to connect, or failed to negotiate a protocol version. Peers that
+    /// are not allowed are also reported to [`Self::connection_rejected`].
+    fn handshake_failed(&self, _failure: &HandshakeFailure) {}
+
+    /// An accepted connection was dropped.
+    fn connection_closed(&self) {}
+
+    /// `len` bytes were read from a connection.
+    fn bytes_read(&self, _len: usize) {}
+
+    /// `len` bytes were written to a connection.
+    fn bytes_written(&self, _len: usize) {}
+
+    /// Reading from, writing to, or shutting down a connection failed.
+    fn error(&self, _error: &io::Error) {}
+}
+
+/// Who the process on the other end of a connection is, as far as could be found out. Looking
+/// the process up by its ID is racy, since the process may have exited and its ID been reused,
+/// so this is only meant for reporting.
+///
+/// What is reported while accepting connections is only what is known without looking the
+/// process up, see [`Self::complete`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// UID of the peer on Unix, or the SID of its user on Windows.
+    pub user: Option<String>,
+    pub pid: Option<u32>,
+    /// Path of the executable of the peer.
+    pub exe: Option<PathBuf>,
+}
+
+impl PeerInfo {
+    /// Look up what is missing by the process ID: the executable, and on Windows the user. This
+    /// reads `/proc` or opens the process, so it should be called where blocking is fine, e.g.
+    /// on a thread that writes the events to a log.
+    pub fn complete(&mut self) {
+        let Some(pid) = self.pid else {
+            return;
+        };
+        if self.exe.is_none() {
+            self.exe = crate::imp::process_exe(pid);
+        }
+        #[cfg(windows)]
+        if self.user.is_none() {
+            self.user = crate::identity::process_user(pid).ok();
+        }
+    }
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = || "unknown".to_owned();
+        write!(
+            f,
+            "user {}, PID {}, executable {}",
+            self.user.clone().unwrap_or_else(unknown),
+            self.pid.map_or_else(unknown, |pid| pid.to_string()),
+            self.exe
+                .as_ref()
+                .map_or_else(unknown, |exe| exe.display().to_string()),
+        )
+    }
+}
+
+/// Why a peer failed to get through the handshake, in the widest sense.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeFailureReason {
+    /// The peer could not be identified, so whether it is allowed could not be decided.
+    Unidentified(String),
+    /// The peer is not allowed to connect, e.g. by a [`crate::credentials::PeerAllowlist`].
+    Denied(String),
+    /// The user of the peer already has as many connections as it is allowed.
+    QuotaExceeded,
+    /// The peer does not speak the IPC protocol.
+    UnrecognizedPeer,
+    /// The peer uses another protocol version.
+    IncompatibleVersion { theirs: u16, ours: u16 },
+    /// The peer does not know the secret of the installation, or presented a ticket that is not
+    /// valid. Only reported for [`FramedConnection::authenticate_client`][authenticate_client].
+    ///
+    /// [authenticate_client]: crate::frame::FramedConnection::authenticate_client
+    AuthenticationFailed,
+}
+
+impl fmt::Display for HandshakeFailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeFailureReason::Unidentified(error) => {
+                write!(f, "failed to identify peer: {error}")
+            }
+            HandshakeFailureReason::Denied(reason) => write!(f, "not allowed: {reason}"),
+            HandshakeFailureReason::QuotaExceeded => f.write_str("too many connections"),
+            HandshakeFailureReason::AuthenticationFailed => f.write_str("failed to authenticate"),
+            HandshakeFailureReason::UnrecognizedPeer => {
+                f.write_str("does not speak the IPC protocol")
+            }
+            HandshakeFailureReason::IncompatibleVersion { theirs, ours } => {
+                write!(f, "uses protocol version {theirs} instead of {ours}")
+            }
+        }
+    }
+}
+
+/// Reported to [`IpcMetrics::handshake_failed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeFailure {
+    pub peer: PeerInfo,
+    pub reason: HandshakeFailureReason,
+}
+
+impl fmt::Display for HandshakeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Peer ({}) {}", self.peer, self.reason)
+    }
+}
+
+/// [`IpcMetrics`] implementation that counts events.
+#[derive(Debug, Default)]
+pub struct IpcCounters {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    handshake_failures: AtomicU64,
+    closed: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Totals reported by [`IpcCounters::snapshot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IpcCountersSnapshot {
+    pub accepted: u64,
+    pub rejected: u64,
+    pub handshake_failures: u64,
+    /// Number of accepted connections that are still open.
+    pub active: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub errors: u64,
+}
+
+impl IpcCounters {
+    /// Return the current totals.
+    pub fn snapshot(&self) -> IpcCountersSnapshot {
+        let closed = self.closed.load(Ordering::Relaxed);
+        let accepted = self.accepted.load(Ordering::Relaxed);
+        IpcCountersSnapshot {
+            accepted,
+            rejected: self.rejected.load(Ordering::Relaxed),
+            handshake_failures: self.handshake_failures.load(Ordering::Relaxed),
+            active: accepted.saturating_sub(closed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl IpcMetrics for IpcCounters {
+    fn connection_accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn connection_rejected(&self, _error: &io::Error) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn handshake_failed(&self, _failure: &HandshakeFailure) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn connection_closed(&self) {
+        self.closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn bytes_read(&self, len: usize) {
+        self.bytes_read.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn bytes_written(&self, len: usize) {
+        self.bytes_written.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn error(&self, _error: &io::Error) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_counters() {
+        use std::sync::Arc;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let counters = Arc::new(IpcCounters::default());
+        let mut endpoint = crate::testing::EphemeralEndpoint::with_options(|endpoint| {
+            endpoint.set_metrics(counters.clone())
+        })
+        .unwrap();
+        let (mut client, mut server) = endpoint.connected_pair().await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        server.read_exact(&mut [0u8; 5]).await.unwrap();
+        server.write_all(b"hi").await.unwrap();
+        client.read_exact(&mut [0u8; 2]).await.unwrap();
+
+        // Only the accepted end reports to the metrics of the endpoint
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.accepted, 1);
+        assert_eq!(snapshot.active, 1);
+        assert_eq!(snapshot.bytes_read, 5);
+        assert_eq!(snapshot.bytes_written, 2);
+        assert_eq!(snapshot.errors, 0);
+
+        drop(server);
+        assert_eq!(counters.snapshot().active, 0);
+    }
+
+    #[test]
+    fn test_display() {
+        let failure = HandshakeFailure {
+            peer: PeerInfo {
+                user: Some("1000".to_owned()),
+                pid: Some(42),
+                exe: None,
+            },
+            reason: HandshakeFailureReason::IncompatibleVersion { theirs: 2, ours: 3 },
+        };
+        assert_eq!(
+            failure.to_string(),
+            "Peer (user 1000, PID 42, executable unknown) uses protocol version 2 instead of 3"
+        );
+    }
+}
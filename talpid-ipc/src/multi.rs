@@ -0,0 +1,117 @@
+//! Listening on several endpoints at once.
+//!
+//! During a migration, a server may have to be reachable both at a legacy path and at a new
+//! one, e.g. a per-user endpoint. [`MultiIncoming`] listens on all of them and merges the
+//! accepted connections into one stream, in which each connection is tagged with the endpoint
+//! that it was accepted on. Each endpoint keeps its own settings, such as security attributes
+//! and allowlists.
+
+use crate::{Connection, Endpoint};
+use futures::{
+    Stream, StreamExt,
+    stream::{BoxStream, SelectAll},
+};
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// The endpoint that a connection was accepted on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EndpointTag {
+    index: usize,
+    path: Arc<str>,
+}
+
+impl EndpointTag {
+    /// Position of the endpoint among those passed to [`MultiIncoming::listen`].
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Socket path or pipe name of the endpoint.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// Stream of connections accepted on any of several endpoints. A failure to accept on one
+/// endpoint is yielded tagged with it, and does not affect the others. The stream ends once
+/// every endpoint has stopped accepting.
+pub struct MultiIncoming {
+    incoming: SelectAll<BoxStream<'static, (EndpointTag, io::Result<Connection>)>>,
+}
+
+impl MultiIncoming {
+    /// Start listening on all `endpoints`. If listening on any of them fails, those that were
+    /// already listening stop again, and the error is returned.
+    pub fn listen(endpoints: impl IntoIterator<Item = Endpoint>) -> io::Result<Self> {
+        let mut incoming = SelectAll::new();
+        for (index, endpoint) in endpoints.into_iter().enumerate() {
+            let tag = EndpointTag {
+                index,
+                path: Arc::from(endpoint.path()),
+            };
+            let listening = endpoint.incoming().inspect_err(|error| {
+                log::error!("Failed to listen on IPC endpoint {}: {error}", tag.path);
+            })?;
+            incoming.push(
+                listening
+                    .map(move |connection| (tag.clone(), connection))
+                    .boxed(),
+            );
+        }
+        Ok(MultiIncoming { incoming })
+    }
+}
+
+impl Stream for MultiIncoming {
+    type Item = (EndpointTag, io::Result<Connection>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_connections_are_tagged() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = dir.path().join("legacy").to_string_lossy().into_owned();
+        let current = dir.path().join("current").to_string_lossy().into_owned();
+        let mut incoming = MultiIncoming::listen([
+            Endpoint::new(legacy.clone()),
+            Endpoint::new(current.clone()),
+        ])
+        .unwrap();
+
+        let _client = Endpoint::connect(&current).await.unwrap();
+        let (tag, connection) = incoming.next().await.unwrap();
+        connection.unwrap();
+        assert_eq!(tag.index(), 1);
+        assert_eq!(tag.path(), current);
+
+        let _client = Endpoint::connect(&legacy).await.unwrap();
+        let (tag, _connection) = incoming.next().await.unwrap();
+        assert_eq!(tag.index(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_failure_stops_listening() {
+        let dir = tempfile::tempdir().unwrap();
+        let bound = dir.path().join("bound").to_string_lossy().into_owned();
+        let missing = dir.path().join("missing").join("socket");
+        let endpoints = [
+            Endpoint::new(bound.clone()),
+            Endpoint::new(missing.to_string_lossy().into_owned()),
+        ];
+        assert!(MultiIncoming::listen(endpoints).is_err());
+        assert!(!std::path::Path::new(&bound).exists());
+    }
+}
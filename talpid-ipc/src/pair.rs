@@ -0,0 +1,508 @@
+//! Connected pairs, for talking to child processes without creating an endpoint.
+//!
+//! The daemon can create a pair, keep one end, and let a helper that it spawns inherit the
+//! other. Both ends are ordinary [`Connection`]s, so the same framing is used as for
+//! connections accepted on an endpoint.
+//!
+//! On Unix, the pair is a `socketpair(2)`. Anonymous pipes on Windows are one-way and cannot be
+//! used asynchronously, so there the pair is the only instance of a duplex named pipe with an
+//! unguessable name, which only the current user may open. The pair is only returned once the
+//! client end is known to belong to this process.
+//!
+//! The helper wraps the end that it inherited with [`Connection::from_raw_fd`] or
+//! [`Connection::from_raw_handle`]. Connections that were established synchronously, e.g. with
+//! the standard library, are wrapped with `Connection::from_std`.
+
+use crate::{Connection, ConnectionId, Endpoint};
+use std::io;
+
+impl Endpoint {
+    /// Create a pair of connections that are connected to each other.
+    ///
+    /// Like every socket of this crate, neither end is inherited by child processes. To hand
+    /// one end to a helper, call [`Connection::clear_cloexec`] on it before spawning the helper,
+    /// pass its descriptor number, e.g. as an argument, and drop it in this process once the
+    /// helper has been spawned.
+    #[cfg(unix)]
+    pub fn socketpair() -> io::Result<(Connection, Connection)> {
+        let (first, second) = tokio::net::UnixStream::pair()?;
+        #[cfg(not(target_os = "linux"))]
+        {
+            use std::os::fd::AsRawFd;
+            crate::imp::set_cloexec(first.as_raw_fd())?;
+            crate::imp::set_cloexec(second.as_raw_fd())?;
+        }
+        Ok((wrap(first, "pair"), wrap(second, "pair")))
+    }
+
+    /// Create a pair of connections that are connected to each other. The first is the server
+    /// end of the pipe, and the second the client end.
+    ///
+    /// Neither end is inherited by child processes. To hand one end to a helper, pass
+    /// [`Connection::handle_arg`] to it, and call [`Connection::set_handle_inheritable`] right
+    /// before spawning it. Child processes inherit every inheritable handle, so clear the flag
+    /// again once the helper has been spawned.
+    #[cfg(windows)]
+    pub fn socketpair() -> io::Result<(Connection, Connection)> {
+        use crate::{SecurityAttributes, imp};
+        use std::os::windows::io::AsRawHandle;
+        use tokio::net::windows::named_pipe::{ClientOptions, ServerOptions};
+        use windows_sys::Win32::{Foundation::HANDLE, System::Pipes::GetNamedPipeClientProcessId};
+
+        let path = imp::ephemeral_pipe_name()?;
+        let sid = crate::identity::current_user()?;
+        let security_attributes = SecurityAttributes::from_sddl(&format!("D:P(A;;GA;;;{sid})"))?;
+        let Some(mut attributes) = security_attributes.as_raw() else {
+            return Err(io::Error::other("Missing security descriptor"));
+        };
+        let mut options = ServerOptions::new();
+        options
+            .first_pipe_instance(true)
+            .max_instances(1)
+            .reject_remote_clients(true);
+        // SAFETY: `attributes` points to a valid security descriptor that outlives the call
+        let server = unsafe {
+            options.create_with_security_attributes_raw(
+                &path,
+                (&mut attributes as *mut windows_sys::Win32::Security::SECURITY_ATTRIBUTES).cast(),
+            )?
+        };
+        // The server end is connected as soon as the client has opened the pipe
+        let client = ClientOptions::new().open(&path)?;
+
+        let mut client_pid = 0;
+        // SAFETY: The handle is a valid named pipe handle for the lifetime of `server`, and
+        // `client_pid` is a valid out pointer
+        if unsafe { GetNamedPipeClientProcessId(server.as_raw_handle() as HANDLE, &mut client_pid) }
+            == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        if client_pid != std::process::id() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Another process connected to the pipe",
+            ));
+        }
+        Ok((
+            wrap(imp::Connection::Server(server), "pair"),
+            wrap(imp::Connection::Client(client), "pair"),
+        ))
+    }
+}
+
+#[cfg(unix)]
+impl Connection {
+    /// Wrap a connected socket that this process inherited, e.g. the end of a
+    /// [pair](Endpoint::socketpair) that the parent handed to it. See [`Self::from_std`].
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open, connected stream socket that nothing else owns. The connection
+    /// takes ownership of it, also if this fails, and closes it when dropped.
+    pub unsafe fn from_raw_fd(fd: std::os::fd::RawFd) -> io::Result<Connection> {
+        use std::os::fd::FromRawFd;
+
+        // SAFETY: The caller passes on ownership of the descriptor
+        Connection::from_std(unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) })
+    }
+
+    /// Wrap a connected socket of the standard library, e.g. one that was connected
+    /// synchronously. The socket is made non-blocking, and is not inherited by child processes.
+    /// This must be called from within a Tokio runtime.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the descriptor of `stream` is not a
+    /// connected Unix domain stream socket, which is possible if it was created from a raw
+    /// descriptor.
+    pub fn from_std(stream: std::os::unix::net::UnixStream) -> io::Result<Connection> {
+        use socket2::{SockRef, Type};
+        use std::os::fd::AsRawFd;
+
+        let invalid = |error| io::Error::new(io::ErrorKind::InvalidInput, error);
+        // This fails for anything but a Unix domain socket
+        stream.local_addr().map_err(invalid)?;
+        let socket = SockRef::from(&stream);
+        if socket.r#type().map_err(invalid)? != Type::STREAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Not a stream socket",
+            ));
+        }
+        // This fails for listening sockets, and for sockets that were never connected
+        socket.peer_addr().map_err(invalid)?;
+        stream.set_nonblocking(true)?;
+        crate::imp::set_cloexec(stream.as_raw_fd())?;
+        Ok(wrap(tokio::net::UnixStream::from_std(stream)?, "external"))
+    }
+}
+
+#[cfg(windows)]
+impl Connection {
+    /// Wrap a connected pipe handle that this process inherited, e.g. the end of a
+    /// [pair](Endpoint::socketpair) whose [`Self::handle_arg`] the parent passed to it. See
+    /// [`Self::from_std`].
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be an open handle of a named pipe that was opened for overlapped I/O, and
+    /// that nothing else owns. The connection takes ownership of it, also if this fails, and
+    /// closes it when dropped.
+    pub unsafe fn from_raw_handle(
+        handle: std::os::windows::io::RawHandle,
+    ) -> io::Result<Connection> {
+        use std::os::windows::io::{FromRawHandle, OwnedHandle};
+
+        // SAFETY: The caller passes on ownership of the handle, and guarantees the rest
+        unsafe { Connection::from_std(OwnedHandle::from_raw_handle(handle)) }
+    }
+
+    /// Wrap a connected pipe handle, e.g. of a pipe that was opened synchronously with
+    /// [`std::fs::OpenOptions`]. Whether it is the server or the client end is looked up. The
+    /// handle is not inherited by child processes. This must be called from within a Tokio
+    /// runtime.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if `handle` is not a named pipe handle.
+    ///
+    /// # Safety
+    ///
+    /// Unlike sockets, pipes cannot be switched to asynchronous I/O once opened, so `handle`
+    /// must have been opened with `FILE_FLAG_OVERLAPPED`, e.g. with
+    /// [`std::os::windows::fs::OpenOptionsExt::custom_flags`] for a client end.
+    pub unsafe fn from_std(handle: std::os::windows::io::OwnedHandle) -> io::Result<Connection> {
+        use crate::imp;
+        use std::{
+            os::windows::io::{AsRawHandle, IntoRawHandle},
+            ptr,
+        };
+        use tokio::net::windows::named_pipe::{NamedPipeClient, NamedPipeServer};
+        use windows_sys::Win32::{
+            Foundation::HANDLE,
+            System::Pipes::{GetNamedPipeInfo, PIPE_SERVER_END},
+        };
+
+        let mut flags = 0;
+        // SAFETY: The handle is valid for the lifetime of `handle`, `flags` is a valid out
+        // pointer, and the other information is not asked for
+        if unsafe {
+            GetNamedPipeInfo(
+                handle.as_raw_handle() as HANDLE,
+                &mut flags,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        } == 0
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                io::Error::last_os_error(),
+            ));
+        }
+        let handle = handle.into_raw_handle();
+        // SAFETY: The handle is an open named pipe handle that was opened for overlapped I/O, and
+        // is owned by nothing else
+        let inner = if flags & PIPE_SERVER_END != 0 {
+            imp::Connection::Server(unsafe { NamedPipeServer::from_raw_handle(handle) }?)
+        } else {
+            imp::Connection::Client(unsafe { NamedPipeClient::from_raw_handle(handle) }?)
+        };
+        imp::set_inheritable(&inner, false)?;
+        Ok(wrap(inner, "external"))
+    }
+
+    /// Hand the connection over to `process`, e.g. a helper that should serve a client that has
+    /// already been authenticated. Returns the value of its handle in that process, and the
+    /// bytes that the runtime had already read from the pipe. Pass both to the helper, e.g. the
+    /// handle as formatted by [`Self::handle_arg`], which it wraps with
+    /// [`Self::from_raw_handle`]. The helper has to process the bytes before anything that it
+    /// reads itself. Unlike inheritance, this works with a helper that is already running.
+    /// `process` needs the `PROCESS_DUP_HANDLE` access right, which a [`std::process::Child`]
+    /// has.
+    ///
+    /// What was written before is written out first, so that it is not interleaved with what the
+    /// helper writes. The runtime keeps a read pending on the pipe, which is cancelled, so the
+    /// peer must not send anything while this runs, e.g. because it waits for the server.
+    /// Otherwise, what the cancelled read received is lost. The end of the connection is
+    /// reported as [`crate::audit::AuditEventKind::HandedOver`] rather than as a disconnect.
+    ///
+    /// The handle is owned by `process`, and leaks there if it is never used. Only the pipe is
+    /// passed on. Anything that was read ahead from it by this process, e.g. by a
+    /// [`crate::frame::FramedConnection`], or any state of the protocol, such as session keys,
+    /// has to be sent to the helper separately.
+    pub async fn duplicate_into(
+        mut self,
+        process: impl std::os::windows::io::AsHandle,
+    ) -> io::Result<(std::os::windows::io::RawHandle, Vec<u8>)> {
+        use std::os::windows::io::{AsRawHandle, FromRawHandle, IntoRawHandle, OwnedHandle};
+        use windows_sys::Win32::{
+            Foundation::{DUPLICATE_CLOSE_SOURCE, DUPLICATE_SAME_ACCESS, DuplicateHandle, HANDLE},
+            System::Threading::GetCurrentProcess,
+        };
+
+        let duplicate = |source, process, options| {
+            let mut target: HANDLE = 0;
+            // SAFETY: The handles are valid for the duration of the call, and `target` is a
+            // valid out pointer
+            if unsafe {
+                DuplicateHandle(
+                    GetCurrentProcess(),
+                    source,
+                    process,
+                    &mut target,
+                    0,
+                    0,
+                    options,
+                )
+            } == 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(target)
+        };
+
+        self.inner.writable().await?;
+        let mut read_ahead = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            match self.inner.try_read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => read_ahead.extend_from_slice(&buf[..n]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error),
+            }
+        }
+
+        let source = match &self.inner {
+            crate::imp::Connection::Server(server) => server.as_raw_handle(),
+            crate::imp::Connection::Client(client) => client.as_raw_handle(),
+        };
+        // SAFETY: Getting the pseudo handle of the current process has no memory safety
+        // implications, and it needs no closing
+        let current = unsafe { GetCurrentProcess() };
+        let spare = duplicate(source as HANDLE, current, DUPLICATE_SAME_ACCESS)?;
+        // SAFETY: The spare handle was just created, and is owned by nothing else
+        let spare = unsafe { OwnedHandle::from_raw_handle(spare as _) };
+        // Dropping the connection cancels the read that the runtime keeps pending on the pipe.
+        // The spare handle keeps the pipe open, but it stays associated with the completion
+        // port of this runtime, which would keep the helper from using it.
+        #[cfg(feature = "server")]
+        if let Some((sink, peer)) = self.audit.take() {
+            sink.event(&crate::audit::AuditEvent::new(
+                Some(self.id),
+                peer,
+                crate::audit::AuditEventKind::HandedOver,
+            ));
+        }
+        drop(self);
+        crate::imp::detach_completion_port(&spare)?;
+        let target = duplicate(
+            spare.into_raw_handle() as HANDLE,
+            process.as_handle().as_raw_handle() as HANDLE,
+            DUPLICATE_SAME_ACCESS | DUPLICATE_CLOSE_SOURCE,
+        )?;
+        Ok((target as std::os::windows::io::RawHandle, read_ahead))
+    }
+
+    /// Let child processes spawned from now on inherit the handle of the connection, or stop
+    /// letting them. See [`Endpoint::socketpair`].
+    pub fn set_handle_inheritable(&self, inheritable: bool) -> io::Result<()> {
+        crate::imp::set_inheritable(&self.inner, inheritable)
+    }
+
+    /// Return the value of the handle of the connection, formatted for the command line of a
+    /// child process that inherits it.
+    pub fn handle_arg(&self) -> String {
+        use std::os::windows::io::AsRawHandle;
+        let handle = match &self.inner {
+            crate::imp::Connection::Server(server) => server.as_raw_handle(),
+            crate::imp::Connection::Client(client) => client.as_raw_handle(),
+        };
+        (handle as usize).to_string()
+    }
+}
+
+fn wrap(inner: crate::imp::Connection, side: &'static str) -> Connection {
+    let id = ConnectionId::next();
+    #[cfg(feature = "tracing")]
+    let span = tracing::debug_span!("ipc_connection", %id, side);
+    Connection::unaccepted(
+        inner,
+        id,
+        #[cfg(feature = "tracing")]
+        span,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame::{Frame, FramedConnection};
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_socketpair() {
+        use std::os::fd::AsRawFd;
+
+        let (first, second) = Endpoint::socketpair().unwrap();
+        assert_ne!(first.id(), second.id());
+        for connection in [&first, &second] {
+            // SAFETY: Getting the flags of a descriptor has no memory safety implications
+            let flags = unsafe { libc::fcntl(connection.as_raw_fd(), libc::F_GETFD) };
+            assert_ne!(flags & libc::FD_CLOEXEC, 0);
+        }
+
+        let mut first = FramedConnection::new(first);
+        let mut second = FramedConnection::new(second);
+        first
+            .write_frame(&Frame::data(b"hello".to_vec()))
+            .await
+            .unwrap();
+        let frame = second.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame, Frame::data(b"hello".to_vec()));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_from_raw_fd() {
+        use std::os::fd::IntoRawFd;
+
+        let (first, second) = std::os::unix::net::UnixStream::pair().unwrap();
+        // SAFETY: The descriptors are connected sockets that are owned by nothing else
+        let first = unsafe { Connection::from_raw_fd(first.into_raw_fd()) }.unwrap();
+        let second = unsafe { Connection::from_raw_fd(second.into_raw_fd()) }.unwrap();
+
+        let mut first = FramedConnection::new(first);
+        let mut second = FramedConnection::new(second);
+        first
+            .write_frame(&Frame::data(b"hello".to_vec()))
+            .await
+            .unwrap();
+        let frame = second.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame, Frame::data(b"hello".to_vec()));
+
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let listener = std::os::unix::net::UnixListener::bind(dir.path()).unwrap();
+        let (datagram, _) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        for fd in [
+            tempfile::tempfile().unwrap().into_raw_fd(),
+            listener.into_raw_fd(),
+            datagram.into_raw_fd(),
+        ] {
+            // SAFETY: The descriptor is owned by nothing else
+            let error = unsafe { Connection::from_raw_fd(fd) }.err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_from_std() {
+        use std::io::{Read, Write};
+
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let mut client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let mut server = FramedConnection::new(Connection::from_std(server).unwrap());
+        server
+            .write_frame(&Frame::data(b"hello".to_vec()))
+            .await
+            .unwrap();
+        let mut received = [0u8; crate::frame::HEADER_LEN + 5];
+        tokio::task::spawn_blocking(move || {
+            client.read_exact(&mut received).unwrap();
+            client.write_all(&received).unwrap();
+        })
+        .await
+        .unwrap();
+        let frame = server.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame, Frame::data(b"hello".to_vec()));
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_socketpair() {
+        let (first, second) = Endpoint::socketpair().unwrap();
+        assert!(second.handle_arg().parse::<usize>().is_ok());
+
+        let mut first = FramedConnection::new(first);
+        let mut second = FramedConnection::new(second);
+        second
+            .write_frame(&Frame::data(b"hello".to_vec()))
+            .await
+            .unwrap();
+        let frame = first.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame, Frame::data(b"hello".to_vec()));
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_duplicate_into() {
+        use std::os::windows::io::BorrowedHandle;
+        use windows_sys::Win32::System::Threading::GetCurrentProcess;
+
+        let (first, second) = Endpoint::socketpair().unwrap();
+        // SAFETY: The pseudo handle of the current process is always valid
+        let process = unsafe { BorrowedHandle::borrow_raw(GetCurrentProcess() as _) };
+        let (duplicate, read_ahead) = second.duplicate_into(process).await.unwrap();
+        assert!(read_ahead.is_empty());
+        // SAFETY: The duplicate is owned by nothing else, and was opened for overlapped I/O
+        let second = unsafe { Connection::from_raw_handle(duplicate) }.unwrap();
+
+        let mut first = FramedConnection::new(first);
+        let mut second = FramedConnection::new(second);
+        second
+            .write_frame(&Frame::data(b"hello".to_vec()))
+            .await
+            .unwrap();
+        let frame = first.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame, Frame::data(b"hello".to_vec()));
+    }
+
+    /// Set in the child process of [`test_duplicate_into_child`].
+    #[cfg(windows)]
+    const HELPER_ENV: &str = "TALPID_IPC_TEST_DUPLICATE_HELPER";
+
+    /// Hand a connection to a child process, which is this test running as a helper. The helper
+    /// reads the value of the handle from its standard input, and echoes a frame.
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_duplicate_into_child() {
+        use std::io::{BufRead, Write};
+
+        if std::env::var_os(HELPER_ENV).is_some() {
+            let mut line = String::new();
+            std::io::stdin().lock().read_line(&mut line).unwrap();
+            let handle = line.trim().parse::<usize>().unwrap();
+            // SAFETY: The parent duplicated the handle into this process, and owns it no more
+            let connection = unsafe { Connection::from_raw_handle(handle as _) }.unwrap();
+            let mut connection = FramedConnection::new(connection);
+            let frame = connection.read_frame().await.unwrap().unwrap();
+            connection.write_frame(&frame).await.unwrap();
+            return;
+        }
+
+        let mut helper = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "pair::test::test_duplicate_into_child"])
+            .env(HELPER_ENV, "1")
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let (first, second) = Endpoint::socketpair().unwrap();
+        let (duplicate, read_ahead) = second.duplicate_into(&helper).await.unwrap();
+        assert!(read_ahead.is_empty());
+        let mut stdin = helper.stdin.take().unwrap();
+        writeln!(stdin, "{}", duplicate as usize).unwrap();
+
+        let mut first = FramedConnection::new(first);
+        first
+            .write_frame(&Frame::data(b"hello".to_vec()))
+            .await
+            .unwrap();
+        let frame = first.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame, Frame::data(b"hello".to_vec()));
+        assert!(helper.wait().unwrap().success());
+    }
+}
@@ -0,0 +1,248 @@
+//! Endpoints that belong to a single user, for helper processes that run as that user next to
+//! the system daemon.
+//!
+//! On Windows, the name of the pipe contains the SID of the user, and only that user may open it.
+//! Helpers that are told the name of their endpoint when they are spawned should use
+//! [`Endpoint::ephemeral_per_user`] instead, whose name cannot be guessed in advance.
+//! On Unix, the socket is placed in the runtime directory of the user: `$XDG_RUNTIME_DIR` or
+//! `/run/user/<UID>` on Linux, and the per-user `$TMPDIR` on macOS.
+//!
+//! Another user must not be able to take the name first and impersonate the helper. The first
+//! pipe instance is always created as such, so creating it fails if someone else got there
+//! first. On Unix, the runtime directory must belong to the user, and must not be accessible to
+//! others, so nobody else can place a socket in it. A file that already occupies the path is
+//! refused unless it is a socket of the same user, e.g. one left behind by an earlier helper.
+
+use crate::Endpoint;
+#[cfg(feature = "server")]
+use crate::SecurityAttributes;
+use std::io;
+
+/// Only the user may connect to the socket.
+#[cfg(all(unix, feature = "server"))]
+const PER_USER_SOCKET_MODE: u32 = 0o600;
+
+impl Endpoint {
+    /// Create an endpoint named `name` that belongs to the user that runs this process. The
+    /// name must be a single path component other than `.` and `..`. Clients running as the
+    /// same user find the same endpoint by calling this with the same name.
+    pub fn per_user(name: &str) -> io::Result<Endpoint> {
+        check_name(name)?;
+        imp::per_user(name)
+    }
+
+    /// Create an endpoint at a pipe name that is not in use, like [`Endpoint::ephemeral`], which
+    /// only the user that runs this process may open. Clients must be told the name, e.g. on the
+    /// command line of a helper that is spawned as the user.
+    #[cfg(all(windows, feature = "server"))]
+    pub fn ephemeral_per_user() -> io::Result<Endpoint> {
+        let sid = crate::identity::current_user()?;
+        imp::restrict_to(Endpoint::ephemeral()?, &sid)
+    }
+
+    /// Create a restricted endpoint named `name` in the runtime directory of the user `uid`,
+    /// for clients of that user that are confined to a sandbox. Flatpak apps can be given access
+    /// to it with `--filesystem=xdg-run/<name>`. Connections accepted on it are marked as
+    /// restricted, see [`Endpoint::set_restricted`].
+    ///
+    /// The daemon runs as root, so the socket is given to `uid` and its group once it is bound,
+    /// for only that user to be able to connect to it.
+    #[cfg(all(target_os = "linux", feature = "server"))]
+    pub fn restricted(name: &str, uid: u32) -> io::Result<Endpoint> {
+        check_name(name)?;
+        let mut endpoint = imp::for_user(name, uid)?;
+        endpoint.set_restricted(true);
+        Ok(endpoint)
+    }
+}
+
+/// Fail unless `name` names a file in the directory that it is joined to.
+fn check_name(name: &str) -> io::Result<()> {
+    if matches!(name, "" | "." | "..") || name.contains(['/', '\\', '\0']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Endpoint name must be a single path component",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use std::{
+        fs,
+        os::unix::fs::{FileTypeExt, MetadataExt},
+        path::{Path, PathBuf},
+    };
+
+    pub(super) fn per_user(name: &str) -> io::Result<Endpoint> {
+        for_user(name, current_uid())
+    }
+
+    /// Create an endpoint named `name` in the runtime directory of `uid`, which is given to
+    /// `uid` if this process runs as another user.
+    pub(super) fn for_user(name: &str, uid: u32) -> io::Result<Endpoint> {
+        let dir = runtime_dir(uid)?;
+        let endpoint = Endpoint::new(socket_path(&dir, uid, name)?);
+        #[cfg(feature = "server")]
+        let endpoint = endpoint.with_security_attributes(attributes(&dir, uid)?);
+        Ok(endpoint)
+    }
+
+    /// Let only `uid` connect to a socket in its runtime directory `dir`.
+    #[cfg(feature = "server")]
+    fn attributes(dir: &Path, uid: u32) -> io::Result<SecurityAttributes> {
+        let attributes = SecurityAttributes::empty().set_mode(PER_USER_SOCKET_MODE)?;
+        // SAFETY: `geteuid` has no preconditions and cannot fail
+        if uid == unsafe { libc::geteuid() } {
+            return Ok(attributes);
+        }
+        // The directory belongs to the user, and to the primary group of the user
+        Ok(attributes.set_owner(uid, fs::metadata(dir)?.gid()))
+    }
+
+    fn current_uid() -> u32 {
+        // SAFETY: `getuid` has no preconditions and cannot fail
+        unsafe { libc::getuid() }
+    }
+
+    /// Return the runtime directory of `uid`. `$XDG_RUNTIME_DIR` belongs to the user that runs
+    /// this process, so it is only used for that user.
+    #[cfg(target_os = "linux")]
+    pub(super) fn runtime_dir(uid: u32) -> io::Result<PathBuf> {
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) if uid == current_uid() && Path::new(&dir).is_absolute() => {
+                Ok(PathBuf::from(dir))
+            }
+            _ => Ok(PathBuf::from(format!("/run/user/{uid}"))),
+        }
+    }
+
+    /// Return the temporary directory of `uid`, which is only known to processes of that user.
+    #[cfg(target_os = "macos")]
+    fn runtime_dir(uid: u32) -> io::Result<PathBuf> {
+        match std::env::var_os("TMPDIR") {
+            Some(dir) if uid == current_uid() && Path::new(&dir).is_absolute() => {
+                Ok(PathBuf::from(dir))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "TMPDIR is not set to the temporary directory of the user",
+            )),
+        }
+    }
+
+    /// Return the path of the socket `name` in `dir`, if `dir` is private to `uid` and the path
+    /// is not taken by someone else.
+    pub(super) fn socket_path(dir: &Path, uid: u32, name: &str) -> io::Result<String> {
+        let metadata = fs::metadata(dir)?;
+        if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "{} is not a directory that only the user may access",
+                    dir.display()
+                ),
+            ));
+        }
+
+        let path = dir.join(name);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if !metadata.file_type().is_socket() || metadata.uid() != uid => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is taken by something else", path.display()),
+                ));
+            }
+            Ok(_) => (),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+            Err(error) => return Err(error),
+        }
+        path.into_os_string().into_string().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Socket path is not valid UTF-8",
+            )
+        })
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use super::*;
+
+    pub(super) fn per_user(name: &str) -> io::Result<Endpoint> {
+        let sid = crate::identity::current_user()?;
+        let endpoint = Endpoint::new(format!(r"\\.\pipe\mullvad-{name}-{sid}"));
+        #[cfg(feature = "server")]
+        let endpoint = restrict_to(endpoint, &sid)?;
+        Ok(endpoint)
+    }
+
+    /// Let only the user `sid` open the pipe of `endpoint` and create new instances of it.
+    #[cfg(feature = "server")]
+    pub(super) fn restrict_to(endpoint: Endpoint, sid: &str) -> io::Result<Endpoint> {
+        let attributes = SecurityAttributes::from_sddl(&format!("D:P(A;;GA;;;{sid})"))?;
+        Ok(endpoint.with_security_attributes(attributes))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::{
+        fs,
+        os::unix::fs::{MetadataExt, PermissionsExt},
+        path::Path,
+    };
+
+    #[test]
+    fn test_socket_path() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+        let uid = fs::metadata(dir.path()).unwrap().uid();
+
+        let path = imp::socket_path(dir.path(), uid, "helper").unwrap();
+        assert_eq!(Path::new(&path), dir.path().join("helper"));
+        assert!(imp::socket_path(dir.path(), uid + 1, "helper").is_err());
+
+        fs::write(dir.path().join("taken"), b"").unwrap();
+        let error = imp::socket_path(dir.path(), uid, "taken").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        let error = imp::socket_path(dir.path(), uid, "helper").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_check_name() {
+        check_name("helper").unwrap();
+        check_name("..helper").unwrap();
+        for name in [
+            "",
+            ".",
+            "..",
+            "../helper",
+            "dir/helper",
+            r"dir\helper",
+            "helper\0",
+        ] {
+            let error = check_name(name).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert!(Endpoint::per_user(name).is_err());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_runtime_dir() {
+        // The runtime directory of this process is not the one of anyone else
+        let other = fs::metadata("/proc/self").unwrap().uid() + 1;
+        assert_eq!(
+            imp::runtime_dir(other).unwrap(),
+            Path::new(&format!("/run/user/{other}"))
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! Handles to the process on the other end of a connection, on Linux.
+//!
+//! The PID reported by [`crate::credentials::PeerCredentials::pid`] can be recycled once the
+//! peer exits, so checking e.g. `/proc/<PID>/exe` may end up inspecting an unrelated process.
+//! A pidfd keeps referring to the original process. Anything read through the PID is only
+//! trusted if the process is still alive afterwards, at which point the PID cannot have been
+//! reused.
+//!
+//! Since Linux 6.5, the kernel hands out a pidfd for the peer of a socket (`SO_PEERPIDFD`),
+//! which refers to the process that connected. Older kernels fall back to opening a pidfd for
+//! the PID from `SO_PEERCRED`, which is only exact if the peer has not exited before that. See
+//! [`PidFd::is_exact`].
+
+use std::{
+    fs, io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    path::PathBuf,
+};
+
+/// `SO_PEERPIDFD`, which is missing from older libc versions.
+const SO_PEERPIDFD: libc::c_int = 77;
+
+/// A handle to a process, which cannot come to refer to another process.
+#[derive(Debug)]
+pub struct PidFd {
+    fd: OwnedFd,
+    exact: bool,
+}
+
+impl PidFd {
+    /// Open a pidfd for the process `pid`. The process may already have been replaced by
+    /// another one with the same PID, so the result is never exact.
+    pub fn open(pid: i32) -> io::Result<Self> {
+        // SAFETY: `pidfd_open` only takes integer arguments
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PidFd {
+            // SAFETY: The kernel just created the descriptor, with `O_CLOEXEC`, and nothing
+            // else owns it
+            fd: unsafe { OwnedFd::from_raw_fd(fd as RawFd) },
+            exact: false,
+        })
+    }
+
+    /// Return a pidfd for the peer of a connected Unix socket.
+    pub(crate) fn of_peer(socket: RawFd) -> io::Result<Self> {
+        let mut fd: libc::c_int = -1;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: `fd` and `len` describe a buffer of the size expected by `SO_PEERPIDFD`
+        let result = unsafe {
+            libc::getsockopt(
+                socket,
+                libc::SOL_SOCKET,
+                SO_PEERPIDFD,
+                (&mut fd as *mut libc::c_int).cast(),
+                &mut len,
+            )
+        };
+        if result == 0 {
+            return Ok(PidFd {
+                // SAFETY: The kernel just created the descriptor, with `O_CLOEXEC`, and nothing
+                // else owns it
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                exact: true,
+            });
+        }
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::ENOPROTOOPT) {
+            return Err(error);
+        }
+
+        let credentials = crate::credentials::peer_credentials(socket)?;
+        let pid = credentials
+            .pid()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "The peer PID is unknown"))?;
+        PidFd::open(pid)
+    }
+
+    /// Whether the handle is known to refer to the peer process itself. If not, the peer may
+    /// have exited and its PID been reused before the handle was opened.
+    pub fn is_exact(&self) -> bool {
+        self.exact
+    }
+
+    /// Return the current PID of the process. Fails with [`io::ErrorKind::NotFound`] if the
+    /// process has exited.
+    pub fn pid(&self) -> io::Result<i32> {
+        let fdinfo = fs::read_to_string(format!("/proc/self/fdinfo/{}", self.fd.as_raw_fd()))?;
+        let pid = fdinfo
+            .lines()
+            .find_map(|line| line.strip_prefix("Pid:"))
+            .and_then(|pid| pid.trim().parse::<i32>().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "No PID in the pidfd information",
+                )
+            })?;
+        if pid <= 0 {
+            return Err(process_exited());
+        }
+        Ok(pid)
+    }
+
+    /// Whether the process is still running. A pidfd becomes readable once its process exits.
+    pub fn is_alive(&self) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `pollfd` is a single valid entry, and the call does not block
+        if unsafe { libc::poll(&mut pollfd, 1, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(pollfd.revents & libc::POLLIN == 0)
+    }
+
+    /// Return the path of the executable of the process. The path is only returned if the
+    /// process is still running after it has been read, so it cannot belong to a process that
+    /// reused the PID.
+    pub fn exe_path(&self) -> io::Result<PathBuf> {
+        let path = fs::read_link(format!("/proc/{}/exe", self.pid()?))?;
+        if !self.is_alive()? {
+            return Err(process_exited());
+        }
+        Ok(path)
+    }
+
+    /// Return when the process started, in clock ticks since boot. Like the PID, this is only
+    /// returned if the process is still running after it has been read.
+    pub fn start_time(&self) -> io::Result<u64> {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", self.pid()?))?;
+        // The command name may contain spaces and parentheses, but nothing after it does. The
+        // start time is the 22nd field, and the 20th after the name.
+        let start_time = stat
+            .rsplit_once(')')
+            .and_then(|(_, fields)| fields.split_whitespace().nth(19))
+            .and_then(|start_time| start_time.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "No start time in the process status",
+                )
+            })?;
+        if !self.is_alive()? {
+            return Err(process_exited());
+        }
+        Ok(start_time)
+    }
+}
+
+impl AsFd for PidFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl From<PidFd> for OwnedFd {
+    fn from(pidfd: PidFd) -> Self {
+        pidfd.fd
+    }
+}
+
+impl crate::Connection {
+    /// Return a pidfd for the peer process. See [`crate::pidfd`].
+    pub fn peer_pidfd(&self) -> io::Result<PidFd> {
+        PidFd::of_peer(self.inner.as_raw_fd())
+    }
+}
+
+fn process_exited() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "The process has exited")
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use crate::testing::EphemeralEndpoint;
+
+    #[tokio::test]
+    async fn test_peer_pidfd() {
+        let mut endpoint = EphemeralEndpoint::new().unwrap();
+        let (_client, server) = endpoint.connected_pair().await.unwrap();
+
+        let pidfd = server.peer_pidfd().unwrap();
+        assert_eq!(pidfd.pid().unwrap(), std::process::id() as i32);
+        assert!(pidfd.is_alive().unwrap());
+        assert_eq!(
+            pidfd.exe_path().unwrap(),
+            std::env::current_exe().unwrap().canonicalize().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_exited_process() {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pidfd = PidFd::open(child.id() as i32).unwrap();
+        assert!(!pidfd.is_exact());
+        child.wait().unwrap();
+
+        assert!(!pidfd.is_alive().unwrap());
+        assert_eq!(
+            pidfd.exe_path().unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! Authorization of privileged requests through polkit, for Linux desktops.
+//!
+//! The daemon can ask polkit whether the peer of a connection may perform an action, such as
+//! changing settings. Depending on the policy of the action, polkit may let the standard
+//! authentication agent of the desktop ask the user for their password first. The peer process
+//! is identified to polkit by its pidfd, so that the check cannot end up concerning another
+//! process that has reused its PID. Versions of polkit that do not take a pidfd identify it by
+//! its PID and start time instead, which are read through the pidfd. See [`crate::pidfd`].
+
+use crate::Connection;
+use std::io;
+use talpid_dbus::polkit::{self, UnixProcess};
+
+/// Checks polkit actions on behalf of the peers of connections.
+#[derive(Debug, Clone)]
+pub struct PolkitAuthorizer {
+    allow_user_interaction: bool,
+}
+
+impl PolkitAuthorizer {
+    pub fn new() -> Self {
+        PolkitAuthorizer {
+            allow_user_interaction: true,
+        }
+    }
+
+    /// Whether the user may be asked to authenticate. If not, actions that require
+    /// authentication are denied. This is allowed by default.
+    pub fn set_allow_user_interaction(&mut self, allow: bool) {
+        self.allow_user_interaction = allow;
+    }
+
+    /// Return whether the peer of `connection` is authorized to perform the polkit action
+    /// `action_id`, such as `net.mullvad.settings.change`. This waits for the user to
+    /// authenticate, if polkit asks them to.
+    pub async fn authorize(&self, connection: &Connection, action_id: &str) -> io::Result<bool> {
+        let process = peer_process(connection)?;
+        let pid = process.pid;
+        let action = action_id.to_owned();
+        let allow_user_interaction = self.allow_user_interaction;
+        let authorized = tokio::task::spawn_blocking(move || {
+            polkit::check_authorization(&process, &action, allow_user_interaction)
+        })
+        .await
+        .map_err(io::Error::other)?
+        .map_err(io::Error::other)?;
+        if !authorized {
+            log::debug!("IPC peer with PID {pid} is not authorized to perform {action_id}");
+        }
+        Ok(authorized)
+    }
+}
+
+impl Default for PolkitAuthorizer {
+    fn default() -> Self {
+        PolkitAuthorizer::new()
+    }
+}
+
+/// Identify the peer of `connection` as a polkit subject.
+fn peer_process(connection: &Connection) -> io::Result<UnixProcess> {
+    let credentials = connection.peer_credentials()?;
+    let pidfd = connection.peer_pidfd()?;
+    Ok(UnixProcess {
+        pid: pidfd.pid()? as u32,
+        start_time: pidfd.start_time()?,
+        uid: credentials.uid(),
+        pidfd: Some(pidfd.into()),
+    })
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use crate::{pidfd::PidFd, testing::EphemeralEndpoint};
+
+    #[tokio::test]
+    async fn test_peer_process() {
+        let mut endpoint = EphemeralEndpoint::new().unwrap();
+        let (_client, server) = endpoint.connected_pair().await.unwrap();
+
+        let process = peer_process(&server).unwrap();
+        let this = PidFd::open(std::process::id() as i32).unwrap();
+        assert_eq!(process.pid, std::process::id());
+        assert_eq!(process.start_time, this.start_time().unwrap());
+        // SAFETY: `getuid` has no preconditions and cannot fail
+        assert_eq!(process.uid, unsafe { libc::getuid() });
+        assert!(process.pidfd.is_some());
+    }
+}
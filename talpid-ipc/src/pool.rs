@@ -0,0 +1,139 @@
+//! Pools of read and write buffers shared by all framed connections.
+//!
+//! A server may accept and drop dozens of connections in quick succession, for example when
+//! several frontends reconnect at once. Reusing the buffers of closed connections avoids
+//! allocating them again for every accepted connection.
+
+use bytes::BytesMut;
+use std::{
+    mem,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+/// Capacity of newly allocated read buffers.
+pub const BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// Capacity of newly allocated write buffers. Most frames are small, and the buffer grows for
+/// those that are not.
+pub const WRITE_BUFFER_CAPACITY: usize = 8 * 1024;
+
+/// Maximum number of idle buffers retained by a pool.
+const MAX_IDLE_BUFFERS: usize = 32;
+
+/// Pools used by [`crate::frame::FramedConnection`].
+pub(crate) static READ_BUFFERS: BufferPool = BufferPool::new(BUFFER_CAPACITY);
+pub(crate) static WRITE_BUFFERS: BufferPool = BufferPool::new(WRITE_BUFFER_CAPACITY);
+
+pub(crate) struct BufferPool {
+    idle: Mutex<Vec<BytesMut>>,
+    /// Capacity of newly allocated buffers, and that buffers need to be kept.
+    capacity: usize,
+}
+
+impl BufferPool {
+    const fn new(capacity: usize) -> Self {
+        BufferPool {
+            idle: Mutex::new(Vec::new()),
+            capacity,
+        }
+    }
+
+    /// Take an idle buffer from the pool, or allocate a new one if there is none.
+    pub fn take(&'static self) -> PooledBuffer {
+        let buffer = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(self.capacity));
+        PooledBuffer { buffer, pool: self }
+    }
+
+    fn give_back(&self, mut buffer: BytesMut) {
+        buffer.clear();
+        // What has been written or split off into frames is reclaimed, unless the frames are
+        // still alive, in which case the buffer is not worth keeping
+        if !buffer.try_reclaim(self.capacity) {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_BUFFERS {
+            idle.push(buffer);
+        }
+    }
+}
+
+/// Buffer that is returned to its pool when dropped.
+pub(crate) struct PooledBuffer {
+    buffer: BytesMut,
+    pool: &'static BufferPool,
+}
+
+impl Deref for PooledBuffer {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.give_back(mem::take(&mut self.buffer));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Buf;
+
+    #[test]
+    fn test_reuse() {
+        static POOL: BufferPool = BufferPool::new(BUFFER_CAPACITY);
+
+        let mut buffer = POOL.take();
+        buffer.extend_from_slice(b"hello");
+        let ptr = buffer.as_ptr();
+        drop(buffer);
+
+        let buffer = POOL.take();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn test_reclaim() {
+        static POOL: BufferPool = BufferPool::new(WRITE_BUFFER_CAPACITY);
+
+        // Like a write buffer, whose frames have all been written
+        let mut buffer = POOL.take();
+        let ptr = buffer.as_ptr();
+        buffer.extend_from_slice(&[0; WRITE_BUFFER_CAPACITY]);
+        buffer.advance(WRITE_BUFFER_CAPACITY);
+        drop(buffer);
+
+        let buffer = POOL.take();
+        assert!(buffer.capacity() >= WRITE_BUFFER_CAPACITY);
+        assert_eq!(buffer.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn test_discard_shared() {
+        static POOL: BufferPool = BufferPool::new(BUFFER_CAPACITY);
+
+        let mut buffer = POOL.take();
+        buffer.extend_from_slice(&[0; BUFFER_CAPACITY]);
+        let _frame = buffer.split_to(BUFFER_CAPACITY);
+        drop(buffer);
+
+        assert!(POOL.idle.lock().unwrap().is_empty());
+    }
+}
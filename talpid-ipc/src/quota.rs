@@ -0,0 +1,73 @@
+//! Limits on the number of connections that each user may have open at the same time.
+
+use crate::imp::PeerUser;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Counts the active connections of every user.
+#[derive(Clone)]
+pub(crate) struct ConnectionQuota {
+    limit: usize,
+    active: Arc<Mutex<HashMap<PeerUser, usize>>>,
+}
+
+impl ConnectionQuota {
+    pub(crate) fn new(limit: usize) -> Self {
+        ConnectionQuota {
+            limit,
+            active: Arc::default(),
+        }
+    }
+
+    /// Count a new connection of `user`. Returns `None` if the user is already at the limit.
+    pub(crate) fn acquire(&self, user: PeerUser) -> Option<QuotaGuard> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(user.clone()).or_default();
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(QuotaGuard {
+            user,
+            active: self.active.clone(),
+        })
+    }
+}
+
+/// Counts as one connection of a user until dropped.
+pub(crate) struct QuotaGuard {
+    user: PeerUser,
+    active: Arc<Mutex<HashMap<PeerUser, usize>>>,
+}
+
+impl Drop for QuotaGuard {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.user) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.user);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quota() {
+        let quota = ConnectionQuota::new(2);
+        let user = PeerUser::default();
+
+        let first = quota.acquire(user.clone()).unwrap();
+        let _second = quota.acquire(user.clone()).unwrap();
+        assert!(quota.acquire(user.clone()).is_none());
+
+        drop(first);
+        assert!(quota.acquire(user).is_some());
+    }
+}
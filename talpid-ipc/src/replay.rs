@@ -0,0 +1,469 @@
+//! Exact recordings of the frames of a session, and replaying them against a peer.
+//!
+//! A [`SessionRecorder`] set on a [`FramedConnection`] writes every frame that is sent and
+//! received to a file, together with when it happened and which connection it belongs to. The
+//! file is written on a thread of its own, so that recording never holds up a connection.
+//! [`replay`] later plays the part of the end that made the recording: it sends the frames that
+//! that end sent, to a server for a recording made by a client or vice versa, and reports what
+//! the peer sent back. A bug that depends on what was exchanged can then be reproduced from the
+//! recording alone.
+//!
+//! Unlike a [`crate::capture`], nothing is redacted, since replaying requires the exact frames.
+//! Recordings therefore contain any secrets that were exchanged, and should only be made and
+//! shared with the consent of the user.
+//!
+//! The handshake is not framed, and is not part of the recording. If the recorded session
+//! performed one, the connection has to perform it as well before it is replayed.
+
+use crate::{
+    Error,
+    frame::{Direction, Frame, FrameKind, FramedConnection},
+    spool::{self, Spool},
+};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Magic constant that starts every recording, followed by the format version.
+const MAGIC: [u8; 8] = *b"TIPCREC\x02";
+
+/// Size of the header of every entry: what it records, the connection, its offset from the
+/// start of the recording in microseconds, the kind of the frame and the length of its payload.
+const ENTRY_HEADER_LEN: usize = 1 + 8 + 8 + 1 + 8;
+
+/// Tags of the entries of frames that were sent and received.
+const ENTRY_SENT: u8 = 0;
+const ENTRY_RECEIVED: u8 = 1;
+/// Tag of an entry that stands in for frames that were dropped. It has no payload, and its
+/// length is the number of frames instead.
+const ENTRY_DROPPED: u8 = 2;
+
+/// How long [`replay`] waits for the peer by default, see [`ReplayOptions::set_idle_timeout`].
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Writes the frames of one or more connections to a recording. Frames that are recorded
+/// faster than they can be written are dropped once [`spool::DEFAULT_QUEUE_SIZE`] bytes are
+/// waiting, and the recording says how many were dropped in their place, see
+/// [`Recording::dropped`]. Everything that was recorded has been written once it is dropped.
+pub struct SessionRecorder {
+    spool: Spool,
+    started: Instant,
+    next_connection: AtomicU64,
+}
+
+impl SessionRecorder {
+    /// Record to a newly created file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Self::new(Box::new(BufWriter::new(file)))
+    }
+
+    /// Record to `output`.
+    pub fn new(mut output: Box<dyn Write + Send>) -> io::Result<Self> {
+        output.write_all(&MAGIC)?;
+        output.flush()?;
+        let started = Instant::now();
+        let spool = Spool::spawn(
+            "ipc-session-recorder",
+            "IPC session recording",
+            output,
+            spool::DEFAULT_QUEUE_SIZE,
+            Box::new(move |count| entry_header(ENTRY_DROPPED, 0, offset(started), 0, count)),
+        )?;
+        Ok(SessionRecorder {
+            spool,
+            started,
+            next_connection: AtomicU64::new(1),
+        })
+    }
+
+    /// Number a connection that starts being recorded. Connections are numbered from 1, in the
+    /// order in which they start being recorded.
+    pub(crate) fn next_connection(&self) -> u64 {
+        self.next_connection.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Write a frame of `connection` to the recording. Failing to do so is logged, but
+    /// otherwise ignored.
+    pub fn record(&self, connection: u64, direction: Direction, frame: &Frame) {
+        let tag = match direction {
+            Direction::Sent => ENTRY_SENT,
+            Direction::Received => ENTRY_RECEIVED,
+        };
+        let len = frame.payload.len() as u64;
+        let mut entry = entry_header(tag, connection, offset(self.started), frame.kind as u8, len);
+        entry.extend_from_slice(&frame.payload);
+        self.spool.write(entry);
+    }
+
+    /// Number of frames that were dropped because too many were waiting to be written.
+    pub fn dropped(&self) -> u64 {
+        self.spool.dropped()
+    }
+}
+
+/// Offset from `started` in microseconds.
+fn offset(started: Instant) -> u64 {
+    u64::try_from(started.elapsed().as_micros()).unwrap_or(u64::MAX)
+}
+
+fn entry_header(tag: u8, connection: u64, offset: u64, kind: u8, len: u64) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(ENTRY_HEADER_LEN);
+    entry.push(tag);
+    entry.extend_from_slice(&connection.to_be_bytes());
+    entry.extend_from_slice(&offset.to_be_bytes());
+    entry.push(kind);
+    entry.extend_from_slice(&len.to_be_bytes());
+    entry
+}
+
+/// A frame in a [`Recording`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedFrame {
+    /// Number of the connection that the frame was sent or received on.
+    pub connection: u64,
+    pub direction: Direction,
+    /// When the frame was sent or received, relative to the start of the recording.
+    pub offset: Duration,
+    pub frame: Frame,
+}
+
+/// The frames written by a [`SessionRecorder`].
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    frames: Vec<RecordedFrame>,
+    dropped: u64,
+}
+
+impl Recording {
+    /// Read the recording at `path`.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+
+    /// Read a recording from `reader`. A frame that was only partially written, e.g. because
+    /// the recording process crashed, ends the recording.
+    pub fn read_from(mut reader: impl Read) -> io::Result<Self> {
+        let mut magic = [0u8; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("Not an IPC session recording"));
+        }
+
+        let mut recording = Recording::default();
+        let mut header = [0u8; ENTRY_HEADER_LEN];
+        loop {
+            match reader.read_exact(&mut header) {
+                Ok(()) => (),
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(error) => return Err(error),
+            }
+            let connection = u64::from_be_bytes(header[1..9].try_into().unwrap());
+            let offset = u64::from_be_bytes(header[9..17].try_into().unwrap());
+            let len = u64::from_be_bytes(header[18..].try_into().unwrap());
+            let direction = match header[0] {
+                ENTRY_SENT => Direction::Sent,
+                ENTRY_RECEIVED => Direction::Received,
+                ENTRY_DROPPED => {
+                    recording.dropped += len;
+                    continue;
+                }
+                _ => return Err(invalid_data("Invalid entry")),
+            };
+            let kind =
+                FrameKind::try_from(header[17]).map_err(|_| invalid_data("Invalid frame kind"))?;
+            // Read rather than allocated up front, so that a corrupted length is not trusted
+            let mut payload = Vec::new();
+            reader.by_ref().take(len).read_to_end(&mut payload)?;
+            if (payload.len() as u64) < len {
+                break;
+            }
+            recording.frames.push(RecordedFrame {
+                connection,
+                direction,
+                offset: Duration::from_micros(offset),
+                frame: Frame::new(kind, payload),
+            });
+        }
+        Ok(recording)
+    }
+
+    /// Return the frames in the order that they were recorded.
+    pub fn frames(&self) -> &[RecordedFrame] {
+        &self.frames
+    }
+
+    /// Return the numbers of the connections that were recorded, in the order in which they
+    /// first sent or received a frame.
+    pub fn connections(&self) -> Vec<u64> {
+        let mut connections = Vec::new();
+        for frame in &self.frames {
+            if !connections.contains(&frame.connection) {
+                connections.push(frame.connection);
+            }
+        }
+        connections
+    }
+
+    /// Return the frames of `connection` only, e.g. to [`replay`] it. Frames that were dropped
+    /// are counted for every connection, since it is not known which ones they belonged to.
+    pub fn connection(&self, connection: u64) -> Recording {
+        Recording {
+            frames: self
+                .frames
+                .iter()
+                .filter(|frame| frame.connection == connection)
+                .cloned()
+                .collect(),
+            dropped: self.dropped,
+        }
+    }
+
+    /// Number of frames that were left out of the recording, because they were recorded faster
+    /// than they could be written. Replaying a recording that left out frames may not be
+    /// faithful.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Options for [`replay`].
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    realtime: bool,
+    idle_timeout: Duration,
+}
+
+impl ReplayOptions {
+    pub fn new() -> Self {
+        ReplayOptions::default()
+    }
+
+    /// Send every frame at the same time after the start as when it was recorded, rather than
+    /// as soon as possible. Either way, a frame is only sent once the frames that were received
+    /// before it in the recording have been received. The default is `false`.
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+    }
+
+    /// Set how long to wait for the peer to send a frame that is expected from the recording.
+    /// Once that has passed, the replay carries on as if the frame had been received. The
+    /// default is [`DEFAULT_IDLE_TIMEOUT`].
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = timeout;
+    }
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions {
+            realtime: false,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+/// What happened when a recording was replayed.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayReport {
+    /// The frames that the peer sent in the recording.
+    pub expected: Vec<Frame>,
+    /// The frames that the peer sent when it was replayed.
+    pub received: Vec<Frame>,
+}
+
+impl ReplayReport {
+    /// Whether the peer sent the same frames as in the recording, in the same order.
+    pub fn is_faithful(&self) -> bool {
+        self.expected == self.received
+    }
+}
+
+/// Play the part of the end that made `recording` on `connection`, and return what the peer
+/// sent. Returns once everything has been sent and the peer has sent as many frames as in the
+/// recording, has been idle for too long, or has closed the connection.
+///
+/// Pings and pongs are left out, since they are sent and answered by the connection itself.
+/// A recording of several connections should be replayed one connection at a time, see
+/// [`Recording::connection`].
+pub async fn replay<T: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut FramedConnection<T>,
+    recording: &Recording,
+    options: &ReplayOptions,
+) -> Result<ReplayReport, Error> {
+    let started = tokio::time::Instant::now();
+    let mut report = ReplayReport::default();
+    let recorded = recording
+        .frames()
+        .iter()
+        .filter(|recorded| !matches!(recorded.frame.kind, FrameKind::Ping | FrameKind::Pong));
+
+    for recorded in recorded {
+        match recorded.direction {
+            Direction::Received => report.expected.push(recorded.frame.clone()),
+            Direction::Sent => {
+                if !receive_expected(connection, &mut report, options.idle_timeout).await? {
+                    return Ok(report);
+                }
+                if options.realtime {
+                    tokio::time::sleep_until(started + recorded.offset).await;
+                }
+                connection.write_frame(&recorded.frame).await?;
+            }
+        }
+    }
+    receive_expected(connection, &mut report, options.idle_timeout).await?;
+    Ok(report)
+}
+
+/// Receive frames until as many have been received as are expected, or the peer has not sent
+/// anything for `idle_timeout`. Returns `false` if the peer closed the connection.
+async fn receive_expected<T: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut FramedConnection<T>,
+    report: &mut ReplayReport,
+    idle_timeout: Duration,
+) -> Result<bool, Error> {
+    while report.received.len() < report.expected.len() {
+        match tokio::time::timeout(idle_timeout, connection.read_frame()).await {
+            Ok(Ok(Some(frame))) => report.received.push(frame),
+            Ok(Ok(None)) => return Ok(false),
+            Ok(Err(error)) => return Err(error),
+            Err(_) => {
+                log::debug!("Peer did not send an expected frame in time");
+                break;
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::io::DuplexStream;
+
+    /// Answer every frame with its payload reversed, until the connection is closed.
+    async fn reverser(mut connection: FramedConnection<DuplexStream>) {
+        while let Ok(Some(frame)) = connection.read_frame().await {
+            let mut payload = frame.payload.to_vec();
+            payload.reverse();
+            if connection.write_frame(&Frame::data(payload)).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session");
+
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(reverser(FramedConnection::new(server)));
+        let mut client = FramedConnection::new(client);
+        client.set_recorder(Arc::new(SessionRecorder::create(&path).unwrap()));
+        for payload in ["abc", "hello"] {
+            client.write_frame(&Frame::data(payload)).await.unwrap();
+            client.read_frame().await.unwrap().unwrap();
+        }
+        drop(client);
+
+        let recording = Recording::open(&path).unwrap();
+        assert_eq!(recording.connections(), [1]);
+        assert_eq!(recording.dropped(), 0);
+        let directions: Vec<_> = recording.frames().iter().map(|f| f.direction).collect();
+        assert_eq!(
+            directions,
+            [
+                Direction::Sent,
+                Direction::Received,
+                Direction::Sent,
+                Direction::Received
+            ]
+        );
+        assert_eq!(recording.frames()[3].frame, Frame::data("olleh"));
+
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(reverser(FramedConnection::new(server)));
+        let mut client = FramedConnection::new(client);
+        let report = replay(&mut client, &recording, &ReplayOptions::new())
+            .await
+            .unwrap();
+        assert!(report.is_faithful());
+        assert_eq!(report.received, [Frame::data("cba"), Frame::data("olleh")]);
+    }
+
+    #[tokio::test]
+    async fn test_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session");
+
+        let recorder = Arc::new(SessionRecorder::create(&path).unwrap());
+        let (first, _first_peer) = tokio::io::duplex(1024);
+        let (second, _second_peer) = tokio::io::duplex(1024);
+        let mut first = FramedConnection::new(first);
+        let mut second = FramedConnection::new(second);
+        first.set_recorder(recorder.clone());
+        second.set_recorder(recorder);
+        second.write_frame(&Frame::data("second")).await.unwrap();
+        first.write_frame(&Frame::data("first")).await.unwrap();
+        drop((first, second));
+
+        let recording = Recording::open(&path).unwrap();
+        assert_eq!(recording.connections(), [2, 1]);
+        let first = recording.connection(1);
+        assert_eq!(first.frames().len(), 1);
+        assert_eq!(first.frames()[0].frame, Frame::data("first"));
+    }
+
+    #[test]
+    fn test_dropped() {
+        let mut recording = MAGIC.to_vec();
+        recording.extend(entry_header(ENTRY_DROPPED, 0, 0, 0, 3));
+        recording.extend(entry_header(ENTRY_SENT, 1, 0, FrameKind::Data as u8, 2));
+        recording.extend_from_slice(b"hi");
+        let recording = Recording::read_from(&recording[..]).unwrap();
+        assert_eq!(recording.dropped(), 3);
+        assert_eq!(recording.frames().len(), 1);
+    }
+
+    #[test]
+    fn test_truncated_recording() {
+        let mut recording = MAGIC.to_vec();
+        recording.extend(entry_header(ENTRY_SENT, 1, 0, FrameKind::Data as u8, 2));
+        recording.extend_from_slice(b"hi");
+        recording.extend_from_slice(&[1, 0, 0, 0, 0]);
+        let recording = Recording::read_from(&recording[..]).unwrap();
+        assert_eq!(recording.frames().len(), 1);
+        assert_eq!(recording.frames()[0].frame, Frame::data("hi"));
+
+        // A payload that was cut short ends the recording too
+        let mut recording = MAGIC.to_vec();
+        recording.extend(entry_header(
+            ENTRY_SENT,
+            1,
+            0,
+            FrameKind::Data as u8,
+            u64::MAX,
+        ));
+        recording.extend_from_slice(b"hi");
+        assert!(
+            Recording::read_from(&recording[..])
+                .unwrap()
+                .frames()
+                .is_empty()
+        );
+
+        assert!(Recording::read_from(&b"not a recording"[..]).is_err());
+    }
+}
@@ -0,0 +1,898 @@
+//! Requests, responses and events on top of data frames.
+//!
+//! Every message is carried in one [`FrameKind::Data`] frame. Its payload is an envelope that
+//! starts with a one-byte [`ENVELOPE_VERSION`], a one-byte [`MessageKind`] and the ID of the
+//! request that the message belongs to as a big-endian `u64`, followed by the body encoded by a
+//! [`Codec`]. Clients pick the IDs of their requests, and the server repeats them in its
+//! responses. Events are pushed by the server. Their ID is their sequence number if they come
+//! from an [`EventBroadcaster`], and 0 otherwise.
+//!
+//! Peers of different versions must be able to talk to each other, e.g. an older GUI connected
+//! to a newer daemon. Later envelope versions therefore keep this layout. Messages of unknown
+//! kinds are skipped, as are events that cannot be decoded, e.g. because they were added in a
+//! later version. Requests that cannot be decoded are answered with an error. Fields that a peer
+//! does not know about are ignored by [`crate::codec::JsonCodec`].
+//!
+//! A request is answered either by a single response or error, or by a stream of responses. A
+//! stream starts with a [`MessageKind::StreamStart`] message, continues with one
+//! [`MessageKind::StreamChunk`] per item, and ends with a [`MessageKind::StreamEnd`] message, or
+//! with an error. This lets large results be delivered incrementally rather than in one huge
+//! frame.
+//!
+//! Any number of requests may be outstanding at once, up to a window that both ends enforce, and
+//! they are answered in whatever order they complete. Responses, including individual chunks of
+//! different streams, are matched with their requests by ID. A slow request therefore does not
+//! hold up other requests on the same connection.
+//!
+//! A client that gives up on a request sends a [`MessageKind::Cancel`] message with its ID. The
+//! server then stops handling the request, and sends nothing more for it. Requests that are
+//! still being handled when the client disconnects are cancelled as well. Handlers that hand
+//! work off to other tasks can stop it once the token of [`cancellation_token`] is cancelled.
+//!
+//! Clients may limit which events they receive with a [`MessageKind::Subscribe`] message, whose
+//! body is the list of [`EventKind`]s to receive, or `None` for all of them. The server skips
+//! other events before encoding them. Events are flushed as the [`frame::FlushMode`] of the
+//! connection says, while responses are flushed at once.
+//!
+//! A client that reconnects to a server that uses [`serve_broadcast`] can send a
+//! [`MessageKind::Resume`] message with the sequence number of the last event that it received.
+//! The server then replays the retained events that the client missed, and answers with whether
+//! it could replay all of them. The client skips events that it has already received.
+//!
+//! When the server shuts down, it sends a goodbye frame with [`GoodbyeReason::ShuttingDown`],
+//! stops reading requests, and closes the connection once the requests in flight have been
+//! answered. See [`ServeOptions::set_shutdown_signal`].
+//!
+//! [`serve`] answers the requests on a connection, and [`crate::client::IpcClient`] sends them.
+//! With the `tower` feature, requests can instead be handled by a [`tower::Service`], see
+//! [`serve_service`].
+
+pub use crate::events::EventKind;
+use crate::{
+    Error,
+    codec::Codec,
+    events::{EventBroadcaster, ResumeToken, Resumption, Subscription},
+    frame::{self, Frame, FrameKind, FramedConnection, GoodbyeReason},
+    shutdown::ShutdownSignal,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{
+    Stream, StreamExt, TryFutureExt,
+    future::{self, Either},
+    stream::{self, AbortHandle, LocalBoxStream, SelectAll},
+};
+#[cfg(feature = "derive")]
+pub use ipc_message_derive::IpcMessage;
+use serde::{Serialize, de::DeserializeOwned};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    pin::Pin,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "tower")]
+use tower::{Layer, Service, ServiceExt};
+
+/// Version of the envelope that messages are sent in.
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// Size of the message header in bytes.
+pub const MESSAGE_HEADER_LEN: usize = 1 + 1 + 8;
+
+/// ID of messages that do not belong to a request.
+pub const NO_REQUEST: u64 = 0;
+
+/// Number of requests that may be outstanding on a connection unless configured otherwise.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+
+/// Number of frames that a connection may read before yielding, unless configured otherwise.
+pub const DEFAULT_READ_BUDGET_FRAMES: usize = 32;
+
+/// Number of bytes that a connection may read before yielding, unless configured otherwise.
+pub const DEFAULT_READ_BUDGET_BYTES: usize = 64 * 1024;
+
+/// Identifies what a message contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageKind {
+    /// A request from the client.
+    Request = 0,
+    /// The successful result of a request.
+    Response = 1,
+    /// The request failed. The body is a UTF-8 description of the error.
+    Error = 2,
+    /// An event pushed by the server.
+    Event = 3,
+    /// The request is answered by a stream of responses.
+    StreamStart = 4,
+    /// The next response in a stream.
+    StreamChunk = 5,
+    /// There are no more responses in the stream.
+    StreamEnd = 6,
+    /// The client is no longer interested in the request.
+    Cancel = 7,
+    /// The client only wants to receive some kinds of events.
+    Subscribe = 8,
+    /// The client wants the events that it missed after the one with the sequence number in the
+    /// body. Answered by a response whose body is whether all of them were replayed.
+    Resume = 9,
+}
+
+impl MessageKind {
+    /// Whether nothing more is sent for a request after a message of this kind.
+    pub(crate) fn is_final(self) -> bool {
+        matches!(
+            self,
+            MessageKind::Response | MessageKind::Error | MessageKind::StreamEnd
+        )
+    }
+}
+
+impl TryFrom<u8> for MessageKind {
+    type Error = Error;
+
+    fn try_from(kind: u8) -> Result<Self, Error> {
+        match kind {
+            0 => Ok(MessageKind::Request),
+            1 => Ok(MessageKind::Response),
+            2 => Ok(MessageKind::Error),
+            3 => Ok(MessageKind::Event),
+            4 => Ok(MessageKind::StreamStart),
+            5 => Ok(MessageKind::StreamChunk),
+            6 => Ok(MessageKind::StreamEnd),
+            7 => Ok(MessageKind::Cancel),
+            8 => Ok(MessageKind::Subscribe),
+            9 => Ok(MessageKind::Resume),
+            other => Err(Error::UnknownMessageKind(other)),
+        }
+    }
+}
+
+/// A request, response or event type whose variants have stable tags. Each message is encoded
+/// as its tag followed by the content of its variant, so variants can be renamed and reordered
+/// without breaking older peers, as long as their tags stay the same.
+///
+/// Derive it with `#[derive(IpcMessage)]`, which requires the `derive` feature, and tag every
+/// variant with `#[ipc(tag = N)]`. The derive fails to compile if two variants share a tag. This
+/// also implements `Serialize` and `Deserialize` for the type, so the crate that derives it must
+/// depend on `serde`.
+///
+/// ```ignore
+/// #[derive(IpcMessage)]
+/// enum Request {
+///     #[ipc(tag = 1)]
+///     GetTunnelState,
+///     #[ipc(tag = 2)]
+///     SetAllowLan(bool),
+/// }
+/// ```
+pub trait IpcMessage: Serialize + DeserializeOwned {
+    /// Tags of all variants.
+    const TAGS: &'static [u16];
+
+    /// Tag of this message.
+    fn tag(&self) -> u16;
+}
+
+/// A single message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// Envelope version of the sender.
+    pub version: u8,
+    pub kind: MessageKind,
+    pub id: u64,
+    pub body: Bytes,
+}
+
+impl Message {
+    pub fn new(kind: MessageKind, id: u64, body: impl Into<Bytes>) -> Self {
+        Message {
+            version: ENVELOPE_VERSION,
+            kind,
+            id,
+            body: body.into(),
+        }
+    }
+
+    /// Put the message in a data frame.
+    pub fn to_frame(&self) -> Frame {
+        let mut payload = BytesMut::with_capacity(MESSAGE_HEADER_LEN + self.body.len());
+        payload.put_u8(self.version);
+        payload.put_u8(self.kind as u8);
+        payload.put_u64(self.id);
+        payload.extend_from_slice(&self.body);
+        Frame::data(payload.freeze())
+    }
+
+    /// Take the message out of a data frame. Fails with [`Error::UnknownMessageKind`] if the
+    /// message is of a kind that was added in a later version.
+    pub fn from_frame(frame: &Frame) -> Result<Self, Error> {
+        if frame.kind != FrameKind::Data {
+            return Err(Error::Protocol("Expected a data frame"));
+        }
+        if frame.payload.len() < MESSAGE_HEADER_LEN {
+            return Err(Error::Protocol("Message is too short"));
+        }
+        let mut payload = frame.payload.clone();
+        let version = payload.get_u8();
+        if version == 0 {
+            return Err(Error::Protocol("Unsupported message envelope version"));
+        }
+        let kind = MessageKind::try_from(payload.get_u8())?;
+        let id = payload.get_u64();
+        Ok(Message {
+            version,
+            kind,
+            id,
+            body: payload,
+        })
+    }
+}
+
+/// What a frame from the server means to a client. [`crate::client::IpcClient`] and
+/// [`crate::sansio::RpcCalls`] both read the frames of the server through this.
+#[derive(Debug)]
+pub(crate) enum FromServer {
+    /// The server is about to close the connection.
+    Goodbye(GoodbyeReason),
+    /// An event pushed by the server.
+    Event(Message),
+    /// An answer to the request with the ID of the message.
+    Answer(Message),
+}
+
+impl FromServer {
+    /// Read `frame`. Returns `None` for messages that clients skip: those of kinds that were
+    /// added in a later version, and answers that do not belong to a request. Fails if the
+    /// server sends a request.
+    pub(crate) fn of(frame: &Frame) -> Result<Option<Self>, Error> {
+        if frame.kind == FrameKind::Goodbye {
+            return Ok(Some(FromServer::Goodbye(frame.goodbye_reason())));
+        }
+        let message = match Message::from_frame(frame) {
+            Ok(message) => message,
+            Err(Error::UnknownMessageKind(kind)) => {
+                log::debug!("Ignoring message of unknown kind {kind}");
+                return Ok(None);
+            }
+            Err(error) => return Err(error),
+        };
+        match message.kind {
+            MessageKind::Event => Ok(Some(FromServer::Event(message))),
+            MessageKind::Request
+            | MessageKind::Cancel
+            | MessageKind::Subscribe
+            | MessageKind::Resume => Err(Error::Protocol("Server sent a request")),
+            _ if message.id == NO_REQUEST => Ok(None),
+            _ => Ok(Some(FromServer::Answer(message))),
+        }
+    }
+}
+
+/// Responses that a streaming handler yields. An error ends the stream.
+pub type ResponseStream<Resp> = Pin<Box<dyn Stream<Item = Result<Resp, String>> + Send>>;
+
+/// How a request is answered.
+pub enum Reply<Resp> {
+    /// A single response.
+    Single(Resp),
+    /// Any number of responses, which are sent as they become available.
+    Stream(ResponseStream<Resp>),
+}
+
+tokio::task_local! {
+    static CANCELLATION: CancellationToken;
+}
+
+/// Return a token that is cancelled once the request that is being handled is cancelled by the
+/// client, or the client disconnects. The handler itself is dropped then, but work that it
+/// spawned is not. Outside of handlers, the token is never cancelled.
+pub fn cancellation_token() -> CancellationToken {
+    CANCELLATION.try_with(Clone::clone).unwrap_or_default()
+}
+
+/// Options for [`serve_with_options`].
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    max_in_flight: usize,
+    read_budget_frames: usize,
+    read_budget_bytes: usize,
+    shutdown: Option<CancellationToken>,
+}
+
+impl ServeOptions {
+    pub fn new() -> Self {
+        ServeOptions::default()
+    }
+
+    /// Set the number of requests that are handled at the same time. As many further requests
+    /// are queued, after which no more are read until one of them has been answered. The
+    /// default is [`DEFAULT_MAX_IN_FLIGHT`], and values below 1 are treated as 1.
+    pub fn set_max_in_flight(&mut self, max_in_flight: usize) {
+        self.max_in_flight = max_in_flight.max(1);
+    }
+
+    /// Set how much a connection may read before it yields to other tasks. Frames that have
+    /// already been buffered are decoded, and reads from a socket that is ready complete,
+    /// without waiting, so without a budget, a client that sends many requests back to back
+    /// could keep the server from serving other clients. The connection yields after `frames`
+    /// frames or `bytes` bytes of payload, whichever comes first. The defaults are
+    /// [`DEFAULT_READ_BUDGET_FRAMES`] and [`DEFAULT_READ_BUDGET_BYTES`], and values below 1 are
+    /// treated as 1.
+    pub fn set_read_budget(&mut self, frames: usize, bytes: usize) {
+        self.read_budget_frames = frames.max(1);
+        self.read_budget_bytes = bytes.max(1);
+    }
+
+    /// Wrap up once `signal` says that the server is shutting down. The client is told why the
+    /// connection is being closed, e.g. so that it can tell its user that the daemon is
+    /// shutting down rather than report a lost connection.
+    pub fn set_shutdown_signal(&mut self, signal: &ShutdownSignal) {
+        self.shutdown = Some(signal.draining_token());
+    }
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        ServeOptions {
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            read_budget_frames: DEFAULT_READ_BUDGET_FRAMES,
+            read_budget_bytes: DEFAULT_READ_BUDGET_BYTES,
+            shutdown: None,
+        }
+    }
+}
+
+/// Answer requests on `connection` with `handler`, and push `events` to the client, until the
+/// client closes the connection or says goodbye. Up to [`DEFAULT_MAX_IN_FLIGHT`] requests are
+/// handled concurrently, and each is answered as soon as it completes.
+///
+/// A handler that fails is reported to the client as an error message, which the client
+/// observes as [`Error::Remote`]. Use [`serve_streaming`] to answer some requests with a stream
+/// of responses.
+pub async fn serve<T, C, Req, Resp, Event, H, F, E>(
+    connection: FramedConnection<T>,
+    codec: C,
+    mut handler: H,
+    events: E,
+) -> Result<(), Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    Event: Serialize + EventKind,
+    H: FnMut(Req) -> F,
+    F: Future<Output = Result<Resp, String>>,
+    E: Stream<Item = Event> + Unpin,
+{
+    let handler = move |request| handler(request).map_ok(Reply::Single);
+    serve_streaming(connection, codec, handler, events).await
+}
+
+/// Like [`serve`], but `handler` decides whether to answer each request with a single response
+/// or with a stream of them.
+pub async fn serve_streaming<T, C, Req, Resp, Event, H, F, E>(
+    connection: FramedConnection<T>,
+    codec: C,
+    handler: H,
+    events: E,
+) -> Result<(), Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    Event: Serialize + EventKind,
+    H: FnMut(Req) -> F,
+    F: Future<Output = Result<Reply<Resp>, String>>,
+    E: Stream<Item = Event> + Unpin,
+{
+    let options = ServeOptions::default();
+    serve_with_options(connection, codec, &options, handler, events).await
+}
+
+/// Like [`serve_streaming`], but configured by `options`.
+pub async fn serve_with_options<T, C, Req, Resp, Event, H, F, E>(
+    connection: FramedConnection<T>,
+    codec: C,
+    options: &ServeOptions,
+    handler: H,
+    events: E,
+) -> Result<(), Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    Event: Serialize + EventKind,
+    H: FnMut(Req) -> F,
+    F: Future<Output = Result<Reply<Resp>, String>>,
+    E: Stream<Item = Event> + Unpin,
+{
+    let events = EventSource::Stream(events);
+    serve_events(connection, codec, options, handler, events).await
+}
+
+/// Like [`serve_with_options`], but push the events sent by `broadcaster`. Clients that
+/// reconnect can then resume receiving events where they left off. See
+/// [`EventBroadcaster::resume`].
+pub async fn serve_broadcast<T, C, Req, Resp, Event, H, F>(
+    connection: FramedConnection<T>,
+    codec: C,
+    options: &ServeOptions,
+    handler: H,
+    broadcaster: &EventBroadcaster<Event>,
+) -> Result<(), Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    Event: Clone + Send + Serialize + EventKind,
+    H: FnMut(Req) -> F,
+    F: Future<Output = Result<Reply<Resp>, String>>,
+{
+    let events: EventSource<'_, stream::Pending<Event>, Event> = EventSource::Broadcast {
+        subscription: broadcaster.subscribe(),
+        resume: Box::new(|token| broadcaster.resume(token)),
+    };
+    serve_events(connection, codec, options, handler, events).await
+}
+
+/// Like [`serve_with_options`], but handle every request by calling a clone of `service`, once it
+/// is ready. Errors of the service are reported to the client like those of a handler.
+///
+/// This lets the daemon reuse tower middleware for its requests, such as timeouts, limits on
+/// how many requests are handled at once, and shedding of requests while overloaded. A request
+/// waits for the service to become ready without holding up other requests, and once cancelled
+/// by the client, its clone of the service is dropped.
+#[cfg(feature = "tower")]
+pub async fn serve_service<T, C, Req, Resp, Event, S, E>(
+    connection: FramedConnection<T>,
+    codec: C,
+    options: &ServeOptions,
+    service: S,
+    events: E,
+) -> Result<(), Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    Event: Serialize + EventKind,
+    S: Service<Req, Response = Reply<Resp>> + Clone,
+    S::Error: Into<tower::BoxError>,
+    E: Stream<Item = Event> + Unpin,
+{
+    let handler = move |request| {
+        service
+            .clone()
+            .oneshot(request)
+            .map_err(|error| error.into().to_string())
+    };
+    serve_with_options(connection, codec, options, handler, events).await
+}
+
+/// Like [`serve_service`], but with `handler` wrapped in the middleware of `layer`, e.g. a
+/// [`tower::ServiceBuilder`].
+#[cfg(feature = "tower")]
+pub async fn serve_layered<T, C, Req, Resp, Event, L, H, F, E>(
+    connection: FramedConnection<T>,
+    codec: C,
+    options: &ServeOptions,
+    layer: L,
+    handler: H,
+    events: E,
+) -> Result<(), Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    Event: Serialize + EventKind,
+    L: Layer<tower::util::ServiceFn<H>>,
+    L::Service: Service<Req, Response = Reply<Resp>> + Clone,
+    <L::Service as Service<Req>>::Error: Into<tower::BoxError>,
+    H: FnMut(Req) -> F + Clone,
+    F: Future<Output = Result<Reply<Resp>, String>>,
+    E: Stream<Item = Event> + Unpin,
+{
+    let service = layer.layer(tower::service_fn(handler));
+    serve_service(connection, codec, options, service, events).await
+}
+
+/// Where the events of a connection come from.
+enum EventSource<'a, E, Event> {
+    Stream(E),
+    Broadcast {
+        subscription: Subscription<Event>,
+        resume: Box<dyn Fn(ResumeToken) -> (Subscription<Event>, Resumption) + Send + 'a>,
+    },
+}
+
+impl<E, Event> EventSource<'_, E, Event>
+where
+    E: Stream<Item = Event> + Unpin,
+{
+    /// Return the next event and its sequence number, or [`NO_REQUEST`] if it has none.
+    async fn next(&mut self) -> Option<(u64, Event)> {
+        match self {
+            EventSource::Stream(events) => events.next().await.map(|event| (NO_REQUEST, event)),
+            EventSource::Broadcast { subscription, .. } => {
+                future::poll_fn(|cx| subscription.poll_next_sequenced(cx)).await
+            }
+        }
+    }
+
+    /// Whether the subscription ended because the client did not keep up with the events.
+    fn disconnected(&self) -> bool {
+        match self {
+            EventSource::Stream(_) => false,
+            EventSource::Broadcast { subscription, .. } => subscription.is_disconnected(),
+        }
+    }
+
+    /// Replay the events after `token`, and answer request `id` with whether all of them were
+    /// replayed.
+    fn resume<C: Codec>(
+        &mut self,
+        codec: &C,
+        id: u64,
+        token: ResumeToken,
+    ) -> Result<Message, Error> {
+        let EventSource::Broadcast {
+            subscription,
+            resume,
+        } = self
+        else {
+            return Ok(Message::new(
+                MessageKind::Error,
+                id,
+                "Events cannot be resumed on this connection",
+            ));
+        };
+        let (resumed, resumption) = resume(token);
+        *subscription = resumed;
+        let complete = resumption == Resumption::Complete;
+        Ok(Message::new(
+            MessageKind::Response,
+            id,
+            codec.encode(&complete)?,
+        ))
+    }
+}
+
+async fn serve_events<T, C, Req, Resp, Event, H, F, E>(
+    mut connection: FramedConnection<T>,
+    codec: C,
+    options: &ServeOptions,
+    mut handler: H,
+    mut events: EventSource<'_, E, Event>,
+) -> Result<(), Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    Event: Serialize + EventKind,
+    H: FnMut(Req) -> F,
+    F: Future<Output = Result<Reply<Resp>, String>>,
+    E: Stream<Item = Event> + Unpin,
+{
+    // Messages of the requests that are being handled, in the order in which they are ready
+    let mut in_flight = SelectAll::new();
+    // Abort handles and cancellation tokens of the requests that are being handled, by ID
+    let mut handlers: HashMap<u64, (AbortHandle, CancellationToken)> = HashMap::new();
+    // Cancels the requests that are still being handled once the connection is closed
+    let closed = CancellationToken::new();
+    let _cancel_on_close = closed.clone().drop_guard();
+    // Requests that have been read while the window was full. Reading on lets cancellations
+    // through.
+    let mut queued: VecDeque<Message> = VecDeque::new();
+    // Kinds of events that the client wants, or `None` for all of them
+    let mut event_filter: Option<HashSet<String>> = None;
+    let mut events_ended = false;
+    let shutdown = options.shutdown.clone().unwrap_or_default();
+    // Set once the client has been told that the server is shutting down
+    let mut draining = false;
+    // What has been read since the connection last yielded. Only yielding resets it, since
+    // reading does not yield if the socket is ready.
+    let mut frames_read = 0;
+    let mut bytes_read = 0;
+    loop {
+        if frames_read >= options.read_budget_frames || bytes_read >= options.read_budget_bytes {
+            frames_read = 0;
+            bytes_read = 0;
+            tokio::task::yield_now().await;
+        }
+
+        while handlers.len() < options.max_in_flight {
+            let Some(request) = queued.pop_front() else {
+                break;
+            };
+            let cancel = closed.child_token();
+            let reply = match codec.decode(&request.body) {
+                Ok(body) => Either::Left(CANCELLATION.sync_scope(cancel.clone(), || handler(body))),
+                Err(error) => {
+                    Either::Right(future::ready(Err(format!("Invalid request: {error}"))))
+                }
+            };
+            let reply = CANCELLATION.scope(cancel.clone(), reply);
+            let (messages, abort) = stream::abortable(reply_messages(&codec, request.id, reply));
+            handlers.insert(request.id, (abort, cancel));
+            in_flight.push(messages);
+        }
+        if draining && handlers.is_empty() {
+            return connection.flush().await;
+        }
+
+        tokio::select! {
+            // Events are fed to the framing, which only keeps the flush deadline by itself while
+            // it waits for a request
+            () = frame::flush_due(connection.flush_deadline()) => connection.flush().await?,
+            () = shutdown.cancelled(), if !draining => {
+                draining = true;
+                // Requests that have been read are still answered. The framing may have told
+                // the client already if the server attached its control.
+                if !connection.goodbye_sent() {
+                    connection
+                        .send_goodbye_with_reason(GoodbyeReason::ShuttingDown)
+                        .await?;
+                }
+            }
+            frame = connection.read_frame(),
+                if !draining && queued.len() < options.max_in_flight =>
+            {
+                let Some(frame) = frame? else {
+                    return Ok(());
+                };
+                if frame.kind == FrameKind::Goodbye {
+                    return Ok(());
+                }
+                frames_read += 1;
+                bytes_read += frame.payload.len();
+                let message = match Message::from_frame(&frame) {
+                    Err(Error::UnknownMessageKind(kind)) => {
+                        log::debug!("Ignoring message of unknown kind {kind}");
+                        continue;
+                    }
+                    message => message?,
+                };
+                match message.kind {
+                    MessageKind::Request => queued.push_back(message),
+                    MessageKind::Cancel => {
+                        // The request may already have been answered
+                        if let Some((abort, cancel)) = handlers.remove(&message.id) {
+                            abort.abort();
+                            cancel.cancel();
+                        }
+                        queued.retain(|request| request.id != message.id);
+                    }
+                    MessageKind::Subscribe => {
+                        let kinds: Option<Vec<String>> = codec.decode(&message.body)?;
+                        event_filter = kinds.map(HashSet::from_iter);
+                    }
+                    MessageKind::Resume => {
+                        let token = ResumeToken::from_u64(codec.decode(&message.body)?);
+                        let reply = events.resume(&codec, message.id, token)?;
+                        events_ended = false;
+                        connection.write_frame(&reply.to_frame()).await?;
+                    }
+                    _ => return Err(Error::Protocol("Expected a request")),
+                }
+            }
+            message = in_flight.next(), if !in_flight.is_empty() => {
+                let Some(message) = message else {
+                    continue;
+                };
+                let message = message?;
+                if message.kind.is_final() {
+                    handlers.remove(&message.id);
+                }
+                connection.write_frame(&message.to_frame()).await?;
+            }
+            event = events.next(), if !events_ended => {
+                let Some((seq, event)) = event else {
+                    if events.disconnected() {
+                        log::debug!("Disconnecting IPC client that did not keep up with events");
+                        connection
+                            .send_goodbye_with_reason(GoodbyeReason::Evicted)
+                            .await?;
+                        return Ok(());
+                    }
+                    events_ended = true;
+                    continue;
+                };
+                if let Some(filter) = &event_filter
+                    && !filter.contains(event.event_kind())
+                {
+                    continue;
+                }
+                let event = Message::new(MessageKind::Event, seq, codec.encode(&event)?);
+                connection.feed_frame(&event.to_frame()).await?;
+            }
+        }
+    }
+}
+
+/// Return the messages that answer request `id` once `reply` is ready.
+fn reply_messages<'a, C, Resp>(
+    codec: &'a C,
+    id: u64,
+    reply: impl Future<Output = Result<Reply<Resp>, String>> + 'a,
+) -> LocalBoxStream<'a, Result<Message, Error>>
+where
+    C: Codec,
+    Resp: Serialize + 'a,
+{
+    stream::once(reply)
+        .flat_map(move |reply| match reply {
+            Ok(Reply::Single(response)) => {
+                let response = codec
+                    .encode(&response)
+                    .map(|body| Message::new(MessageKind::Response, id, body));
+                stream::iter([response]).boxed_local()
+            }
+            Ok(Reply::Stream(responses)) => {
+                let start = Message::new(MessageKind::StreamStart, id, Bytes::new());
+                let rest = responses.map(Some).chain(stream::iter([None])).scan(
+                    false,
+                    move |ended, response| {
+                        if *ended {
+                            return future::ready(None);
+                        }
+                        let message = match response {
+                            Some(Ok(response)) => codec
+                                .encode(&response)
+                                .map(|body| Message::new(MessageKind::StreamChunk, id, body)),
+                            Some(Err(error)) => {
+                                *ended = true;
+                                Ok(Message::new(MessageKind::Error, id, error))
+                            }
+                            None => Ok(Message::new(MessageKind::StreamEnd, id, Bytes::new())),
+                        };
+                        future::ready(Some(message))
+                    },
+                );
+                stream::iter([Ok(start)]).chain(rest).boxed_local()
+            }
+            Err(error) => {
+                stream::iter([Ok(Message::new(MessageKind::Error, id, error))]).boxed_local()
+            }
+        })
+        .boxed_local()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let message = Message::new(MessageKind::Response, 42, &b"body"[..]);
+        assert_eq!(Message::from_frame(&message.to_frame()).unwrap(), message);
+        assert!(Message::from_frame(&Frame::data(&b"short"[..])).is_err());
+    }
+
+    #[test]
+    fn test_later_envelope_version() {
+        let mut payload = BytesMut::new();
+        payload.put_u8(ENVELOPE_VERSION + 1);
+        payload.put_u8(MessageKind::Event as u8);
+        payload.put_u64(NO_REQUEST);
+        payload.extend_from_slice(b"body");
+        let message = Message::from_frame(&Frame::data(payload.clone())).unwrap();
+        assert_eq!(message.version, ENVELOPE_VERSION + 1);
+        assert_eq!(&message.body[..], b"body");
+
+        payload[1] = 0xff;
+        assert!(matches!(
+            Message::from_frame(&Frame::data(payload)),
+            Err(Error::UnknownMessageKind(0xff))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_token() {
+        use crate::codec::JsonCodec;
+
+        let (client, server) = tokio::io::duplex(1024);
+        let (started_tx, mut started) = tokio::sync::mpsc::unbounded_channel();
+        let served = tokio::spawn(serve(
+            FramedConnection::new(server),
+            JsonCodec,
+            move |_: u32| {
+                started_tx.send(cancellation_token()).unwrap();
+                future::pending::<Result<u32, String>>()
+            },
+            stream::pending::<()>(),
+        ));
+
+        let mut client = FramedConnection::new(client);
+        for id in 1..=2 {
+            let request = Message::new(MessageKind::Request, id, JsonCodec.encode(&id).unwrap());
+            client.write_frame(&request.to_frame()).await.unwrap();
+        }
+        let first = started.recv().await.unwrap();
+        let second = started.recv().await.unwrap();
+        let cancel = Message::new(MessageKind::Cancel, 1, Bytes::new());
+        client.write_frame(&cancel.to_frame()).await.unwrap();
+        first.cancelled().await;
+        assert!(!second.is_cancelled());
+
+        // Requests that are still being handled are cancelled once the client is gone
+        drop(client);
+        served.await.unwrap().unwrap();
+        assert!(second.is_cancelled());
+        assert!(!cancellation_token().is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_slow_consumer() {
+        use crate::{codec::JsonCodec, events::SlowConsumerPolicy};
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut broadcaster = EventBroadcaster::new();
+        broadcaster.set_queue_len(1);
+        broadcaster.set_slow_consumer_policy(SlowConsumerPolicy::Disconnect);
+        let served = serve_broadcast(
+            FramedConnection::new(server),
+            JsonCodec,
+            &ServeOptions::default(),
+            |_: u32| future::pending::<Result<Reply<u32>, String>>(),
+            &broadcaster,
+        );
+
+        let mut client = FramedConnection::new(client);
+        let consume = async {
+            // Nothing is read until the events have filled the pipe, and then the queue
+            let event = "event".repeat(100);
+            let mut subscribed = false;
+            loop {
+                let queued = broadcaster.send(event.clone());
+                if subscribed && queued == 0 {
+                    break;
+                }
+                subscribed |= queued > 0;
+                tokio::task::yield_now().await;
+            }
+            loop {
+                let frame = client.next_frame().await.unwrap().unwrap();
+                if frame.kind == FrameKind::Goodbye {
+                    return frame.goodbye_reason();
+                }
+            }
+        };
+        let (served, reason) = tokio::join!(served, consume);
+        served.unwrap();
+        assert_eq!(reason, GoodbyeReason::Evicted);
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_derive_ipc_message() {
+        use crate::codec::JsonCodec;
+
+        #[derive(Debug, PartialEq, IpcMessage)]
+        enum Request {
+            #[ipc(tag = 2)]
+            GetState,
+            #[ipc(tag = 1)]
+            SetAllowLan(bool),
+        }
+
+        assert_eq!(Request::TAGS, &[2, 1]);
+        assert_eq!(Request::SetAllowLan(true).tag(), 1);
+        for request in [Request::GetState, Request::SetAllowLan(true)] {
+            let encoded = JsonCodec.encode(&request).unwrap();
+            assert_eq!(JsonCodec.decode::<Request>(&encoded).unwrap(), request);
+        }
+        assert_eq!(
+            &JsonCodec.encode(&Request::SetAllowLan(false)).unwrap()[..],
+            b"[1,false]"
+        );
+        assert!(JsonCodec.decode::<Request>(b"[3,null]").is_err());
+    }
+}
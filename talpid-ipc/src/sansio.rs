@@ -0,0 +1,578 @@
+//! The protocol without any I/O.
+//!
+//! [`Session`] is the handshake and the framing of one connection as a state machine. It is fed
+//! the bytes received from the peer and hands out the bytes to send to it, and the current time
+//! is passed in explicitly instead of being read from a clock. Nothing is spawned or awaited, so
+//! the FFI layers of the mobile apps can drive a connection from their own event loops, and
+//! tests can step through a connection deterministically without Tokio.
+//!
+//! [`RpcCalls`] keeps track of the calls of an RPC client on top of a session. It requires the
+//! `rpc` feature.
+//!
+//! Hellos and frames are encoded and decoded by the same code as in [`FramedConnection`], and
+//! pings are answered by the same rules, so a session can talk to a peer that uses it. Calls are
+//! matched with their answers by the same rules as in [`crate::client::IpcClient`].
+//!
+//! [`FramedConnection`]: crate::frame::FramedConnection
+
+use crate::{
+    Error,
+    frame::{self, Frame, FrameCodec, Received},
+    handshake::{self, Capabilities},
+};
+use bytes::{Bytes, BytesMut};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Something that happened on a [`Session`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The hellos have been exchanged. Contains the capabilities supported by both ends.
+    Handshake(Capabilities),
+    /// A frame was received. Pings are answered by the session itself, and pongs are discarded,
+    /// like [`FramedConnection::read_frame`] does.
+    ///
+    /// [`FramedConnection::read_frame`]: crate::frame::FramedConnection::read_frame
+    Frame(Frame),
+    /// The peer closed the connection between two frames.
+    Closed,
+}
+
+enum State {
+    /// Waiting for the hello of the peer.
+    Handshake {
+        version: u16,
+        capabilities: Capabilities,
+    },
+    Open,
+    /// The peer has closed the connection, or the session has failed.
+    Closed,
+}
+
+/// The state of one connection.
+///
+/// Call [`Self::receive`] with everything that is read from the peer, and [`Self::receive_eof`]
+/// once the peer has closed the connection. Afterwards, take the [`Event`]s from
+/// [`Self::poll_event`], and write out what [`Self::poll_transmit`] returns. If
+/// [`Self::poll_timeout`] returns a time, call [`Self::handle_timeout`] once it has passed.
+///
+/// Once any of these has failed, the framing may be out of sync, so the session is closed, and
+/// all further input fails with [`Error::Closed`].
+pub struct Session {
+    state: State,
+    read_buf: BytesMut,
+    /// The hello of this end, until it has been handed out.
+    hello: Option<BytesMut>,
+    write_buf: BytesMut,
+    /// Encoded priority frames, which are sent ahead of `write_buf`.
+    priority_buf: BytesMut,
+    events: VecDeque<Event>,
+    codec: FrameCodec,
+    read_timeout: Option<Duration>,
+    /// When the partial frame in the read buffer must be complete.
+    read_deadline: Option<Instant>,
+    failed: bool,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            state: State::Open,
+            read_buf: BytesMut::new(),
+            hello: None,
+            write_buf: BytesMut::new(),
+            priority_buf: BytesMut::new(),
+            events: VecDeque::new(),
+            codec: FrameCodec::default(),
+            read_timeout: None,
+            read_deadline: None,
+            failed: false,
+        }
+    }
+}
+
+impl Session {
+    /// Create a session that exchanges frames right away, without a handshake.
+    pub fn new() -> Self {
+        Session::default()
+    }
+
+    /// Create a session that starts with a handshake, like
+    /// [`FramedConnection::handshake`]. The hello of this end is handed out by the first call
+    /// to [`Self::poll_transmit`], and [`Event::Handshake`] is reported once the hello of the
+    /// peer has been received. Frames may be sent before that.
+    ///
+    /// [`FramedConnection::handshake`]: crate::frame::FramedConnection::handshake
+    pub fn with_handshake(version: u16, capabilities: Capabilities) -> Self {
+        let capabilities = handshake::offered(capabilities);
+        Session {
+            state: State::Handshake {
+                version,
+                capabilities,
+            },
+            hello: Some(handshake::encode_hello(version, capabilities)),
+            ..Session::default()
+        }
+    }
+
+    /// Reject frames whose payload exceeds `max_len` bytes. The default is
+    /// [`frame::DEFAULT_MAX_PAYLOAD_LEN`].
+    pub fn set_max_payload_len(&mut self, max_len: usize) {
+        self.codec.max_payload_len = max_len;
+    }
+
+    /// Also reject frames that no correct peer sends. See [`crate::frame`].
+    pub fn set_strict(&mut self, strict: bool) {
+        self.codec.strict = strict;
+    }
+
+    /// Fail once the rest of a frame has not arrived `timeout` after its first bytes. By
+    /// default, there is no limit.
+    pub fn set_read_frame_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Capabilities supported by both ends. This is empty until the handshake has completed,
+    /// and if there is no handshake.
+    pub fn capabilities(&self) -> Capabilities {
+        self.codec.capabilities
+    }
+
+    /// Handle `data` received from the peer at `now`.
+    pub fn receive(&mut self, data: &[u8], now: Instant) -> Result<(), Error> {
+        if self.failed || matches!(self.state, State::Closed) {
+            return Err(Error::Closed);
+        }
+        self.read_buf.extend_from_slice(data);
+        let result = self.process(now);
+        if result.is_err() {
+            self.fail();
+        }
+        result
+    }
+
+    fn process(&mut self, now: Instant) -> Result<(), Error> {
+        if let State::Handshake {
+            version,
+            capabilities,
+        } = self.state
+        {
+            if self.read_buf.len() < handshake::HELLO_LEN {
+                return Ok(());
+            }
+            let hello = self.read_buf.split_to(handshake::HELLO_LEN);
+            self.codec.capabilities = handshake::check_hello(&hello, version, capabilities)?;
+            self.state = State::Open;
+            self.events
+                .push_back(Event::Handshake(self.codec.capabilities));
+        }
+
+        let mut decoded = false;
+        while let Some(frame) = self.codec.decode(&mut self.read_buf)? {
+            decoded = true;
+            match Received::of(frame)? {
+                Received::Answer(pong) => self.send_frame(&pong)?,
+                Received::Discard => (),
+                Received::Deliver(frame) => self.events.push_back(Event::Frame(frame)),
+            }
+        }
+
+        // The timeout starts over with every frame
+        if self.read_buf.is_empty() {
+            self.read_deadline = None;
+        } else if decoded || self.read_deadline.is_none() {
+            self.read_deadline = self.read_timeout.map(|timeout| now + timeout);
+        }
+        Ok(())
+    }
+
+    /// Handle the peer closing the connection. Fails if it did so in the middle of the
+    /// handshake or of a frame.
+    pub fn receive_eof(&mut self) -> Result<(), Error> {
+        if self.failed {
+            return Err(Error::Closed);
+        }
+        let error = match self.state {
+            State::Closed => return Ok(()),
+            State::Open if self.read_buf.is_empty() => {
+                self.state = State::Closed;
+                self.read_deadline = None;
+                self.events.push_back(Event::Closed);
+                return Ok(());
+            }
+            State::Open if self.codec.strict => frame::truncated(&self.read_buf).into(),
+            State::Open => Error::UnexpectedEof,
+            State::Handshake { .. } => frame::rejection(&self.read_buf, self.codec.max_payload_len)
+                .unwrap_or(Error::UnexpectedEof),
+        };
+        self.fail();
+        Err(error)
+    }
+
+    /// Return the next event, if any.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Queue `frame` for sending. Priority frames are sent ahead of other frames that have not
+    /// been handed out by [`Self::poll_transmit`] yet.
+    pub fn send_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        if self.failed {
+            return Err(Error::Closed);
+        }
+        let compressed = self.codec.compress(&frame.payload);
+        let dst = if frame.kind.is_priority() {
+            &mut self.priority_buf
+        } else {
+            &mut self.write_buf
+        };
+        self.codec
+            .encode(frame.kind, &frame.payload, compressed.as_deref(), dst)
+    }
+
+    /// Take the bytes that should be sent to the peer next, if any. They must be written out
+    /// entirely, and in order, before the bytes returned by the next call.
+    pub fn poll_transmit(&mut self) -> Option<Bytes> {
+        let mut transmit = self.hello.take().unwrap_or_default();
+        transmit.unsplit(self.priority_buf.split());
+        transmit.unsplit(self.write_buf.split());
+        (!transmit.is_empty()).then(|| transmit.freeze())
+    }
+
+    /// When [`Self::handle_timeout`] should be called next, if ever.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.read_deadline
+    }
+
+    /// Handle the passing of time. Fails with [`Error::Deadline`] if a frame has not been
+    /// completed in time.
+    pub fn handle_timeout(&mut self, now: Instant) -> Result<(), Error> {
+        match self.read_deadline {
+            Some(deadline) if now >= deadline => {
+                self.fail();
+                Err(Error::Deadline("reading"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn fail(&mut self) {
+        self.failed = true;
+        self.state = State::Closed;
+        self.read_buf.clear();
+        self.read_deadline = None;
+    }
+}
+
+#[cfg(feature = "rpc")]
+pub use self::calls::{RpcCalls, RpcEvent};
+
+#[cfg(feature = "rpc")]
+mod calls {
+    use crate::{
+        Error,
+        frame::{Frame, GoodbyeReason},
+        rpc::{FromServer, Message, MessageKind},
+    };
+    use bytes::Bytes;
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    /// What a frame from the server means to the client.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum RpcEvent {
+        /// An answer to the call with the ID of the message. `last` is whether the call has
+        /// ended with it, so that no more answers follow.
+        Answer { message: Message, last: bool },
+        /// An event pushed by the server.
+        Event(Message),
+        /// The server is about to close the connection. Answers to the calls that it has
+        /// already received may still follow.
+        Goodbye(GoodbyeReason),
+    }
+
+    /// A call that has not been answered completely.
+    struct Call {
+        /// When the call times out, if ever.
+        deadline: Option<Instant>,
+    }
+
+    /// The calls of an RPC client, like those of [`crate::client::IpcClient`]. Send the frames
+    /// that it returns on a [`super::Session`], and pass the frames that are received on it to
+    /// [`Self::handle_frame`].
+    pub struct RpcCalls {
+        next_id: u64,
+        calls: HashMap<u64, Call>,
+        timeout: Option<Duration>,
+    }
+
+    impl Default for RpcCalls {
+        fn default() -> Self {
+            RpcCalls {
+                // 0 is reserved for messages that do not belong to a request
+                next_id: 1,
+                calls: HashMap::new(),
+                timeout: None,
+            }
+        }
+    }
+
+    impl RpcCalls {
+        pub fn new() -> Self {
+            RpcCalls::default()
+        }
+
+        /// Cancel calls that the server takes longer than `timeout` to answer. For streaming
+        /// calls, this limits the time until each answer. By default, calls wait indefinitely.
+        pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+            self.timeout = timeout;
+        }
+
+        /// Number of calls that have not been answered completely.
+        pub fn outstanding(&self) -> usize {
+            self.calls.len()
+        }
+
+        /// Start a call of kind `kind`, usually [`MessageKind::Request`], at `now`. Returns the
+        /// ID of the call and the frame to send.
+        pub fn start(&mut self, kind: MessageKind, body: Bytes, now: Instant) -> (u64, Frame) {
+            let id = self.next_id;
+            self.next_id += 1;
+            let deadline = self.timeout.map(|timeout| now + timeout);
+            self.calls.insert(id, Call { deadline });
+            (id, Message::new(kind, id, body).to_frame())
+        }
+
+        /// Give up on the call `id`. Returns the frame that cancels it on the server, unless it
+        /// has already been answered completely.
+        pub fn cancel(&mut self, id: u64) -> Option<Frame> {
+            self.calls.remove(&id)?;
+            Some(Message::new(MessageKind::Cancel, id, Bytes::new()).to_frame())
+        }
+
+        /// Handle a frame received at `now`. Returns `None` for frames that do not concern the
+        /// client, such as answers to calls that have been cancelled. Fails if the server sends
+        /// a request.
+        pub fn handle_frame(
+            &mut self,
+            frame: &Frame,
+            now: Instant,
+        ) -> Result<Option<RpcEvent>, Error> {
+            let message = match FromServer::of(frame)? {
+                Some(FromServer::Goodbye(reason)) => return Ok(Some(RpcEvent::Goodbye(reason))),
+                Some(FromServer::Event(message)) => return Ok(Some(RpcEvent::Event(message))),
+                Some(FromServer::Answer(message)) => message,
+                None => return Ok(None),
+            };
+            let Some(call) = self.calls.get_mut(&message.id) else {
+                log::debug!("Discarding response to unknown request {}", message.id);
+                return Ok(None);
+            };
+            let last = message.kind.is_final();
+            if last {
+                self.calls.remove(&message.id);
+            } else {
+                call.deadline = self.timeout.map(|timeout| now + timeout);
+            }
+            Ok(Some(RpcEvent::Answer { message, last }))
+        }
+
+        /// When [`Self::handle_timeout`] should be called next, if ever.
+        pub fn poll_timeout(&self) -> Option<Instant> {
+            self.calls.values().filter_map(|call| call.deadline).min()
+        }
+
+        /// Cancel the calls that have timed out by `now`. Returns their IDs along with the
+        /// frames that cancel them on the server.
+        pub fn handle_timeout(&mut self, now: Instant) -> Vec<(u64, Frame)> {
+            let expired: Vec<u64> = self
+                .calls
+                .iter()
+                .filter(|(_, call)| call.deadline.is_some_and(|deadline| now >= deadline))
+                .map(|(id, _)| *id)
+                .collect();
+            expired
+                .into_iter()
+                .filter_map(|id| Some((id, self.cancel(id)?)))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame::{FrameKind, FramedConnection};
+
+    /// Hand everything that `from` has to send to `to`.
+    fn pump(from: &mut Session, to: &mut Session, now: Instant) -> Result<(), Error> {
+        while let Some(bytes) = from.poll_transmit() {
+            to.receive(&bytes, now)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_handshake_and_frames() {
+        let now = Instant::now();
+        let app = Capabilities::application(0);
+        let mut client = Session::with_handshake(1, app);
+        let mut server = Session::with_handshake(1, app | Capabilities::application(1));
+
+        client.send_frame(&Frame::data(&b"hello"[..])).unwrap();
+        pump(&mut client, &mut server, now).unwrap();
+        assert_eq!(server.poll_event(), Some(Event::Handshake(app)));
+        assert_eq!(
+            server.poll_event(),
+            Some(Event::Frame(Frame::data(&b"hello"[..])))
+        );
+        assert_eq!(server.poll_event(), None);
+
+        // Pings are answered without involving the application
+        server
+            .send_frame(&Frame::new(FrameKind::Ping, &b"1"[..]))
+            .unwrap();
+        pump(&mut server, &mut client, now).unwrap();
+        assert_eq!(client.poll_event(), Some(Event::Handshake(app)));
+        assert_eq!(client.poll_event(), None);
+        pump(&mut client, &mut server, now).unwrap();
+        assert_eq!(server.poll_event(), None);
+
+        client.receive_eof().unwrap();
+        assert_eq!(client.poll_event(), Some(Event::Closed));
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let now = Instant::now();
+        let mut client = Session::with_handshake(1, Capabilities::empty());
+        let mut server = Session::with_handshake(2, Capabilities::empty());
+        assert!(matches!(
+            pump(&mut client, &mut server, now),
+            Err(Error::IncompatiblePeer { theirs: 1, ours: 2 })
+        ));
+        assert!(matches!(server.receive(&[], now), Err(Error::Closed)));
+    }
+
+    #[test]
+    fn test_rejection_instead_of_hello() {
+        let mut client = Session::with_handshake(1, Capabilities::empty());
+        let mut reject = BytesMut::new();
+        frame::encode(
+            &Frame::reject(frame::RejectReason::QuotaExceeded),
+            &mut reject,
+        )
+        .unwrap();
+        client.receive(&reject, Instant::now()).unwrap();
+        assert!(matches!(
+            client.receive_eof(),
+            Err(Error::Rejected(frame::RejectReason::QuotaExceeded))
+        ));
+    }
+
+    #[test]
+    fn test_read_frame_timeout() {
+        let start = Instant::now();
+        let mut encoded = BytesMut::new();
+        frame::encode(&Frame::data(&b"hello"[..]), &mut encoded).unwrap();
+
+        let mut session = Session::new();
+        session.set_read_frame_timeout(Some(Duration::from_secs(1)));
+        session.receive(&encoded[..3], start).unwrap();
+        let deadline = session.poll_timeout().unwrap();
+        assert_eq!(deadline, start + Duration::from_secs(1));
+        session
+            .handle_timeout(deadline - Duration::from_millis(1))
+            .unwrap();
+        assert!(matches!(
+            session.handle_timeout(deadline),
+            Err(Error::Deadline(_))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let mut encoded = BytesMut::new();
+        frame::encode(&Frame::data(&b"hello"[..]), &mut encoded).unwrap();
+        let mut session = Session::new();
+        session.receive(&encoded[..8], Instant::now()).unwrap();
+        assert!(matches!(session.receive_eof(), Err(Error::UnexpectedEof)));
+    }
+
+    #[tokio::test]
+    async fn test_interoperates_with_framed_connection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut io, peer) = tokio::io::duplex(1024);
+        let mut peer = FramedConnection::new(peer);
+        let peer = tokio::spawn(async move {
+            peer.handshake(1, Capabilities::empty()).await.unwrap();
+            let frame = peer.read_frame().await.unwrap().unwrap();
+            peer.write_frame(&frame).await.unwrap();
+        });
+
+        let mut session = Session::with_handshake(1, Capabilities::empty());
+        session.send_frame(&Frame::data(&b"echo"[..])).unwrap();
+        while let Some(bytes) = session.poll_transmit() {
+            io.write_all(&bytes).await.unwrap();
+        }
+        let mut buf = [0u8; 1024];
+        let mut events = vec![];
+        while events.len() < 2 {
+            let len = io.read(&mut buf).await.unwrap();
+            session.receive(&buf[..len], Instant::now()).unwrap();
+            events.extend(std::iter::from_fn(|| session.poll_event()));
+        }
+        assert_eq!(events[0], Event::Handshake(Capabilities::empty()));
+        assert_eq!(events[1], Event::Frame(Frame::data(&b"echo"[..])));
+        peer.await.unwrap();
+    }
+
+    #[cfg(feature = "rpc")]
+    #[test]
+    fn test_rpc_calls() {
+        use crate::rpc::{Message, MessageKind};
+
+        let start = Instant::now();
+        let mut calls = RpcCalls::new();
+        calls.set_request_timeout(Some(Duration::from_secs(5)));
+        let (first, _) = calls.start(MessageKind::Request, Bytes::from_static(b"1"), start);
+        let (second, _) = calls.start(MessageKind::Request, Bytes::from_static(b"2"), start);
+        assert_ne!(first, second);
+        assert_eq!(calls.outstanding(), 2);
+
+        let response = Message::new(MessageKind::Response, first, Bytes::from_static(b"ok"));
+        assert_eq!(
+            calls.handle_frame(&response.to_frame(), start).unwrap(),
+            Some(RpcEvent::Answer {
+                message: response.clone(),
+                last: true,
+            })
+        );
+        // Answered calls are forgotten
+        assert_eq!(
+            calls.handle_frame(&response.to_frame(), start).unwrap(),
+            None
+        );
+
+        let deadline = calls.poll_timeout().unwrap();
+        assert!(
+            calls
+                .handle_timeout(deadline - Duration::from_secs(1))
+                .is_empty()
+        );
+        let expired = calls.handle_timeout(deadline);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, second);
+        let cancel = Message::from_frame(&expired[0].1).unwrap();
+        assert_eq!(cancel.kind, MessageKind::Cancel);
+        assert_eq!(calls.outstanding(), 0);
+
+        let request = Message::new(MessageKind::Request, 1, Bytes::new());
+        assert!(calls.handle_frame(&request.to_frame(), start).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Message-oriented connections over `SOCK_SEQPACKET` Unix domain sockets.
+//!
+//! Unlike the byte stream of a [`crate::Connection`], every message is delivered whole, so no
+//! framing is needed. Both ends enable `SO_PASSCRED`, so that the kernel attaches the
+//! credentials of the sending process to every message.
+//!
+//! The credentials returned by [`SeqpacketConnection::peer_credentials`] describe whoever
+//! established the connection. The descriptor may since have been shared with threads or
+//! processes that run with other effective UIDs, so a server that authorizes individual
+//! requests should use the credentials of the message that carried the request instead.
+
+use crate::credentials::PeerCredentials;
+use nix::sys::socket::{ControlMessageOwned, MsgFlags, recvmsg, setsockopt, sockopt};
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::{
+    io::{self, IoSliceMut},
+    os::fd::AsRawFd,
+    path::Path,
+};
+use tokio::io::{Interest, unix::AsyncFd};
+
+/// Listens for `SOCK_SEQPACKET` connections on a socket path. The socket file is not removed
+/// when the listener is dropped.
+pub struct SeqpacketListener {
+    inner: AsyncFd<Socket>,
+}
+
+impl SeqpacketListener {
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        // The socket is created with `SOCK_CLOEXEC` by socket2
+        let socket = Socket::new(Domain::UNIX, Type::SEQPACKET, None)?;
+        socket.bind(&SockAddr::unix(path)?)?;
+        socket.listen(crate::imp::DEFAULT_BACKLOG)?;
+        socket.set_nonblocking(true)?;
+        Ok(SeqpacketListener {
+            inner: AsyncFd::new(socket)?,
+        })
+    }
+
+    /// Wait for a client to connect.
+    pub async fn accept(&self) -> io::Result<SeqpacketConnection> {
+        let (socket, _addr) = self
+            .inner
+            .async_io(Interest::READABLE, |listener| listener.accept())
+            .await?;
+        SeqpacketConnection::new(socket)
+    }
+}
+
+/// A message received by [`SeqpacketConnection::recv`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReceivedMessage {
+    /// Length of the message, which was written to the start of the buffer.
+    pub len: usize,
+    /// Credentials of the process that sent the message. `None` if the kernel did not attach
+    /// any, which does not happen while `SO_PASSCRED` is enabled.
+    pub credentials: Option<PeerCredentials>,
+}
+
+/// A connected `SOCK_SEQPACKET` socket.
+pub struct SeqpacketConnection {
+    inner: AsyncFd<Socket>,
+}
+
+impl SeqpacketConnection {
+    /// Connect to a [`SeqpacketListener`] bound to `path`.
+    pub async fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        let socket = Socket::new(Domain::UNIX, Type::SEQPACKET, None)?;
+        // Connecting to a Unix socket never blocks. It fails immediately if the backlog is full.
+        socket.set_nonblocking(true)?;
+        socket.connect(&SockAddr::unix(path)?)?;
+        SeqpacketConnection::new(socket)
+    }
+
+    fn new(socket: Socket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        setsockopt(&socket, sockopt::PassCred, &true)?;
+        Ok(SeqpacketConnection {
+            inner: AsyncFd::new(socket)?,
+        })
+    }
+
+    /// Send `message` as a single message.
+    pub async fn send(&self, message: &[u8]) -> io::Result<()> {
+        let sent = self
+            .inner
+            .async_io(Interest::WRITABLE, |socket| socket.send(message))
+            .await?;
+        if sent != message.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "Message was only partially sent",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Receive the next message into `buf`, together with the credentials of its sender.
+    /// Returns `None` once the peer has closed the connection. Fails with
+    /// [`io::ErrorKind::InvalidData`] if the message did not fit in `buf`, in which case the
+    /// rest of it is lost.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<Option<ReceivedMessage>> {
+        let message = self
+            .inner
+            .async_io(Interest::READABLE, |socket| {
+                let mut iov = [IoSliceMut::new(&mut *buf)];
+                let mut cmsg_buffer = nix::cmsg_space!(libc::ucred);
+                let msg = recvmsg::<()>(
+                    socket.as_raw_fd(),
+                    &mut iov,
+                    Some(&mut cmsg_buffer),
+                    MsgFlags::MSG_CMSG_CLOEXEC,
+                )
+                .map_err(io::Error::from)?;
+                if msg.flags.contains(MsgFlags::MSG_TRUNC) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Message does not fit in the buffer",
+                    ));
+                }
+                let mut credentials = None;
+                for cmsg in msg.cmsgs().map_err(io::Error::from)? {
+                    if let ControlMessageOwned::ScmCredentials(ucred) = cmsg {
+                        credentials = Some(PeerCredentials::from_unix_credentials(&ucred));
+                    }
+                }
+                Ok(ReceivedMessage {
+                    len: msg.bytes,
+                    credentials,
+                })
+            })
+            .await?;
+        // Unlike the end of the connection, an empty message comes with credentials
+        Ok((message.len > 0 || message.credentials.is_some()).then_some(message))
+    }
+
+    /// Return the credentials of the process that established the connection.
+    pub fn peer_credentials(&self) -> io::Result<PeerCredentials> {
+        crate::credentials::peer_credentials(self.inner.as_raw_fd())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_message_credentials() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let listener = SeqpacketListener::bind(&path).unwrap();
+        let client = SeqpacketConnection::connect(&path).await.unwrap();
+        let server = listener.accept().await.unwrap();
+
+        client.send(b"hello").await.unwrap();
+        client.send(b"world").await.unwrap();
+        let mut buf = [0u8; 16];
+        let message = server.recv(&mut buf).await.unwrap().unwrap();
+        assert_eq!(&buf[..message.len], b"hello");
+        let message = server.recv(&mut buf).await.unwrap().unwrap();
+        assert_eq!(&buf[..message.len], b"world");
+
+        // SAFETY: Getting the IDs of the current process has no preconditions
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let credentials = message.credentials.unwrap();
+        assert_eq!(credentials.uid(), uid);
+        assert_eq!(credentials.gid(), gid);
+        assert_eq!(credentials.pid(), Some(std::process::id() as i32));
+
+        client.send(&[0; 32]).await.unwrap();
+        let error = server.recv(&mut buf).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        drop(client);
+        assert_eq!(server.recv(&mut buf).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,942 @@
+//! Accept loop that serves every connection in a task of its own.
+//!
+//! [`IpcServer::serve`] takes care of what every server built on [`Endpoint::incoming`] needs:
+//! a task for each connection, limiting how many connections are served at once, surviving
+//! handlers that panic, and waiting for connections in flight when shutting down. Stopping the
+//! server is done through the endpoint, with [`Endpoint::set_shutdown_handle`] or
+//! [`Endpoint::set_cancellation_token`].
+//!
+//! The server also keeps track of the connections that it is serving, see
+//! [`IpcServer::connections`], and totals of the traffic on them, see [`IpcServer::stats`].
+//! Misbehaving clients can be disconnected with [`IpcServer::disconnect`], and clients that have
+//! gone away without closing their end, e.g. frontends that were killed, are disconnected by
+//! the reaper, see [`IpcServer::set_reaper`].
+//!
+//! [`IpcServer::serve_supervised`] also binds the endpoint again if its listener fails, see
+//! [`crate::supervisor`].
+
+#[cfg(unix)]
+use crate::credentials::PeerCredentials;
+#[cfg(windows)]
+use crate::identity::PeerIdentity;
+use crate::{
+    Connection, ConnectionId, Endpoint,
+    frame::FlushMode,
+    log_limit,
+    shutdown::ShutdownSignal,
+    stats::{ConnectionCounters, ConnectionStats, ServerCounters, ServerStats},
+    supervisor::ListenerSupervisor,
+};
+use futures::{
+    FutureExt, Stream, StreamExt,
+    future::{self, Either},
+    task::AtomicWaker,
+};
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    io,
+    panic::AssertUnwindSafe,
+    pin::pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{sync::Semaphore, task::JoinSet, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Serves connections accepted on an [`Endpoint`] with a handler.
+#[derive(Debug, Clone, Default)]
+pub struct IpcServer {
+    max_connections: Option<usize>,
+    reaper: Option<ReaperOptions>,
+    flush_mode: Option<FlushMode>,
+    memory_limit: Option<usize>,
+    counters: Arc<ServerCounters>,
+    connections: Arc<Mutex<BTreeMap<ConnectionId, Served>>>,
+}
+
+/// A connection that is being served, as reported by [`IpcServer::connections`].
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    /// Credentials of the client, if they could be determined.
+    #[cfg(unix)]
+    pub peer: Option<PeerCredentials>,
+    /// Identity of the client, if it could be determined.
+    #[cfg(windows)]
+    pub peer: Option<PeerIdentity>,
+    /// Whether the connection was accepted on a restricted endpoint.
+    pub restricted: bool,
+    pub stats: ConnectionStats,
+}
+
+/// What is known about a connection while it is being served.
+#[derive(Debug)]
+struct Served {
+    #[cfg(unix)]
+    peer: Option<PeerCredentials>,
+    #[cfg(windows)]
+    peer: Option<PeerIdentity>,
+    restricted: bool,
+    counters: Arc<ConnectionCounters>,
+    control: ConnectionControl,
+}
+
+/// Called with a connection that the reaper has disconnected.
+pub type ReapCallback = Arc<dyn Fn(&ConnectionInfo) + Send + Sync>;
+
+/// How the reaper probes connections, see [`IpcServer::set_reaper`].
+#[derive(Clone)]
+pub struct ReaperOptions {
+    idle: Duration,
+    timeout: Duration,
+    on_reap: Option<ReapCallback>,
+}
+
+impl ReaperOptions {
+    pub fn new() -> Self {
+        ReaperOptions::default()
+    }
+
+    /// Probe connections on which nothing has been read or written for `idle`. The default is
+    /// one minute.
+    pub fn set_idle(&mut self, idle: Duration) {
+        self.idle = idle;
+    }
+
+    /// Disconnect probed connections if nothing is received within `timeout`. The default is
+    /// ten seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Call `callback` with every connection that is disconnected by the reaper.
+    pub fn set_reap_callback(&mut self, callback: ReapCallback) {
+        self.on_reap = Some(callback);
+    }
+
+    /// How often connections are checked.
+    fn period(&self) -> Duration {
+        (self.idle.min(self.timeout) / 2).max(Duration::from_millis(1))
+    }
+}
+
+impl Default for ReaperOptions {
+    fn default() -> Self {
+        ReaperOptions {
+            idle: Duration::from_secs(60),
+            timeout: Duration::from_secs(10),
+            on_reap: None,
+        }
+    }
+}
+
+impl fmt::Debug for ReaperOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReaperOptions")
+            .field("idle", &self.idle)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+/// How long the handler of a connection with an attached [`ConnectionControl`] may take to say
+/// goodbye to the peer once the connection is disconnected, before it is dropped.
+const EVICTION_GRACE: Duration = Duration::from_secs(1);
+
+/// Lets the server reach a connection through the framing that its handler reads frames with,
+/// so that the goodbye frame of [`IpcServer::disconnect`] and the keepalive pings of the reaper
+/// are written between frames rather than in the middle of one. Attach it to the framing with
+/// [`crate::frame::FramedConnection::set_control`].
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionControl {
+    eviction: CancellationToken,
+    /// Cancelled when the server starts shutting down, see [`crate::shutdown`].
+    draining: Option<CancellationToken>,
+    probe: Arc<Probe>,
+    /// Set once the control has been attached to the framing.
+    attached: Arc<AtomicBool>,
+    flush_mode: Option<FlushMode>,
+    memory_limit: Option<usize>,
+    /// Who the peer is, for limiting what the framing logs about it.
+    log_key: Option<Arc<str>>,
+}
+
+impl ConnectionControl {
+    pub(crate) fn attach(&self) {
+        self.attached.store(true, Ordering::Release);
+    }
+
+    fn is_attached(&self) -> bool {
+        self.attached.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn flush_mode(&self) -> Option<FlushMode> {
+        self.flush_mode
+    }
+
+    pub(crate) fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    pub(crate) fn log_key(&self) -> Option<&str> {
+        self.log_key.as_deref()
+    }
+
+    /// Cancelled when the server disconnects the connection.
+    pub(crate) fn eviction(&self) -> &CancellationToken {
+        &self.eviction
+    }
+
+    pub(crate) fn draining(&self) -> Option<&CancellationToken> {
+        self.draining.as_ref()
+    }
+
+    pub(crate) fn probe(&self) -> &Probe {
+        &self.probe
+    }
+}
+
+/// Request for a keepalive ping, which the framing sends while its handler waits for the peer.
+#[derive(Debug, Default)]
+pub(crate) struct Probe {
+    requested: AtomicBool,
+    waker: AtomicWaker,
+    /// Set once the ping has been written.
+    sent: AtomicBool,
+    /// Set once a frame has been received after the ping was written.
+    answered: AtomicBool,
+}
+
+impl Probe {
+    /// Ask for a ping, and wake the handler if it is waiting for the peer.
+    fn request(&self) {
+        self.sent.store(false, Ordering::Release);
+        self.answered.store(false, Ordering::Release);
+        self.requested.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+
+    /// Return whether a ping has been asked for, and wake `cx` when the next one is.
+    pub(crate) fn poll_request(&self, cx: &Context<'_>) -> Poll<()> {
+        self.waker.register(cx.waker());
+        match self.requested.swap(false, Ordering::AcqRel) {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    }
+
+    pub(crate) fn mark_sent(&self) {
+        self.sent.store(true, Ordering::Release);
+    }
+
+    /// Note that a frame has been received, which answers the ping if it has been sent.
+    pub(crate) fn mark_received(&self) {
+        if self.sent.load(Ordering::Acquire) {
+            self.answered.store(true, Ordering::Release);
+        }
+    }
+
+    fn is_sent(&self) -> bool {
+        self.sent.load(Ordering::Acquire)
+    }
+
+    fn is_answered(&self) -> bool {
+        self.answered.load(Ordering::Acquire)
+    }
+}
+
+impl Served {
+    fn new(connection: &Connection, control: ConnectionControl) -> Self {
+        #[cfg(unix)]
+        let peer = connection.peer_credentials();
+        #[cfg(windows)]
+        let peer = match connection.peer_identity() {
+            Some(identity) => Ok(identity.clone()),
+            None => crate::identity::client_identity(&connection.inner, None),
+        };
+        let peer = peer
+            .inspect_err(|error| {
+                log::debug!(
+                    "Failed to identify peer of IPC connection {}: {error}",
+                    connection.id()
+                )
+            })
+            .ok();
+        Served {
+            peer,
+            restricted: connection.is_restricted(),
+            counters: connection.counters().clone(),
+            control,
+        }
+    }
+
+    fn info(&self, id: ConnectionId) -> ConnectionInfo {
+        ConnectionInfo {
+            id,
+            peer: self.peer.as_ref().cloned(),
+            restricted: self.restricted,
+            stats: self.counters.snapshot(),
+        }
+    }
+}
+
+impl IpcServer {
+    /// Create a server that does not limit the number of connections.
+    pub fn new() -> Self {
+        IpcServer::default()
+    }
+
+    /// Serve at most `max_connections` connections at the same time. Further clients wait in
+    /// the listen backlog until a connection is closed. There is no limit by default.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = Some(max_connections);
+    }
+
+    /// Probe idle connections with keepalive pings, and disconnect those that do not answer in
+    /// time, as counted in [`ServerStats::reaped`]. This cleans up after clients that went away
+    /// without closing their end, which would otherwise be served until the server stops.
+    ///
+    /// The pings are written by the framing of the handler while it waits for the client in
+    /// [`crate::frame::FramedConnection::read_frame`], so only connections whose handler has
+    /// attached [`Connection::control`] to its framing are probed and reaped. Clients must read
+    /// frames with `read_frame` as well, which answers the pings.
+    pub fn set_reaper(&mut self, options: ReaperOptions) {
+        self.reaper = Some(options);
+    }
+
+    /// Flush events and other frames that handlers feed to their framing according to `mode`,
+    /// e.g. [`FlushMode::AutoFlush`] for a server whose clients are CLIs. The mode is applied
+    /// when a handler attaches [`Connection::control`] to its framing, and replaces the one that
+    /// the handler set before. By default, handlers keep the mode of their framing.
+    pub fn set_flush_mode(&mut self, mode: FlushMode) {
+        self.flush_mode = Some(mode);
+    }
+
+    /// Limit the memory that may be buffered for each connection to `limit` bytes, see
+    /// [`crate::frame::FramedConnection::set_memory_limit`]. Like the flush mode, the limit is
+    /// applied when a handler attaches [`Connection::control`] to its framing. It replaces the
+    /// one set with [`Endpoint::set_memory_limit`], which applies otherwise.
+    pub fn set_memory_limit(&mut self, limit: usize) {
+        self.memory_limit = Some(limit);
+    }
+
+    /// Totals of the traffic on all connections served so far. Frames are only counted by
+    /// handlers that pass [`Connection::counters`] on to their framing. Clones of the server
+    /// share the totals, so they can be read while the server is running.
+    pub fn stats(&self) -> ServerStats {
+        self.counters.snapshot()
+    }
+
+    /// Connections whose handlers are running, in the order in which they were accepted. Like
+    /// [`Self::stats`], this can be called on a clone while the server is running.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, served)| served.info(*id))
+            .collect()
+    }
+
+    /// Disconnect a client, e.g. because it misbehaves or is no longer authorized. `reason` is
+    /// logged.
+    ///
+    /// If the handler has attached [`Connection::control`] to its framing, the framing sends the
+    /// client a goodbye frame the next time that it waits for a frame, and the handler is given
+    /// a moment to return. Otherwise, or if it does not return in time, the handler is dropped,
+    /// which closes the connection without a goodbye.
+    ///
+    /// Returns `false` if the connection is not being served, e.g. because it has already been
+    /// closed.
+    pub fn disconnect(&self, id: ConnectionId, reason: &str) -> bool {
+        let connections = self.connections.lock().unwrap();
+        let Some(served) = connections.get(&id) else {
+            return false;
+        };
+        log::info!("Disconnecting IPC connection {id}: {reason}");
+        served.control.eviction.cancel();
+        true
+    }
+
+    /// Accept connections on `endpoint`, and call `handler` with each of them in a new task.
+    ///
+    /// Returns once the endpoint stops accepting, e.g. because it is shutting down, and all
+    /// handlers have returned. If accepting fails, no further connections are accepted, and the
+    /// error is returned once all handlers have returned. Use
+    /// [`Endpoint::set_accept_error_policy`] to keep serving despite transient errors.
+    ///
+    /// A handler that panics, whether while creating its future or while it runs, only affects
+    /// its own connection, which is closed. The panic is logged with the ID of the connection and
+    /// counted in [`ServerStats::handler_panics`].
+    pub async fn serve<H, F>(self, mut endpoint: Endpoint, handler: H) -> io::Result<()>
+    where
+        H: Fn(Connection) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if let Some(max_connections) = self.max_connections {
+            endpoint.set_accept_permits(Arc::new(Semaphore::new(max_connections)));
+        }
+        endpoint.set_server_counters(self.counters.clone());
+        let incoming = endpoint.incoming()?;
+        self.serve_incoming(incoming, handler).await
+    }
+
+    /// Like [`Self::serve`], but bind an endpoint created by `make_endpoint` again whenever the
+    /// listener fails, as decided by `supervisor`. Returns once an endpoint stops accepting and
+    /// all handlers have returned, or with the last error once the supervisor gives up. The
+    /// limit on connections applies to the connections of all endpoints together.
+    pub async fn serve_supervised<M, H, F>(
+        self,
+        supervisor: ListenerSupervisor,
+        mut make_endpoint: M,
+        handler: H,
+    ) -> io::Result<()>
+    where
+        M: FnMut() -> Endpoint + Send + 'static,
+        H: Fn(Connection) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let permits = self
+            .max_connections
+            .map(|max_connections| Arc::new(Semaphore::new(max_connections)));
+        let counters = self.counters.clone();
+        let incoming = supervisor.incoming(move || {
+            let mut endpoint = make_endpoint();
+            if let Some(permits) = &permits {
+                endpoint.set_accept_permits(permits.clone());
+            }
+            endpoint.set_server_counters(counters.clone());
+            endpoint
+        });
+        self.serve_incoming(incoming, handler).await
+    }
+
+    pub(crate) async fn serve_incoming<S, H, F>(self, mut incoming: S, handler: H) -> io::Result<()>
+    where
+        S: Stream<Item = io::Result<Connection>> + Unpin,
+        H: Fn(Connection) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let mut tasks = JoinSet::new();
+        // Connections are also reaped while the remaining ones are being waited for
+        let reaper = self.reaper.clone().map(|options| {
+            tokio::spawn(reap(
+                self.connections.clone(),
+                self.counters.clone(),
+                options,
+            ))
+        });
+
+        let mut result = Ok(());
+        while let Some(connection) = incoming.next().await {
+            // Forget about handlers that have returned
+            while tasks.try_join_next().is_some() {}
+
+            let mut connection = match connection {
+                Ok(connection) => connection,
+                Err(error) => {
+                    log::error!("Failed to accept IPC connection: {error}");
+                    result = Err(error);
+                    break;
+                }
+            };
+            let id = connection.id();
+            let control = ConnectionControl {
+                flush_mode: self.flush_mode,
+                memory_limit: self.memory_limit.or(connection.memory_limit()),
+                log_key: Some(log_limit::peer_key(&connection.known_peer_info()).into()),
+                draining: connection
+                    .shutdown_signal()
+                    .map(ShutdownSignal::draining_token),
+                ..ConnectionControl::default()
+            };
+            connection.set_control(control.clone());
+            let connections = self.connections.clone();
+            connections
+                .lock()
+                .unwrap()
+                .insert(id, Served::new(&connection, control.clone()));
+            let counters = self.counters.clone();
+            #[cfg(feature = "tracing")]
+            let span = connection.span().clone();
+            let handler = handler.clone();
+            // Calling the handler is part of the task, so that a handler that panics before
+            // returning its future does not stop the accept loop
+            let handle = AssertUnwindSafe(async move { handler(connection).await }).catch_unwind();
+            #[cfg(feature = "tracing")]
+            let handle = tracing::Instrument::instrument(handle, span);
+            tasks.spawn(async move {
+                let mut handle = pin!(handle);
+                let evicted = pin!(control.eviction.cancelled());
+                let result = match future::select(handle.as_mut(), evicted).await {
+                    Either::Left((result, _)) => Some(result),
+                    // The framing says goodbye to the client, after which the handler returns
+                    Either::Right(((), _)) if control.is_attached() => {
+                        tokio::time::timeout(EVICTION_GRACE, handle).await.ok()
+                    }
+                    Either::Right(((), _)) => None,
+                };
+                if let Some(Err(panic)) = result {
+                    log::error!(
+                        "Handler of IPC connection {id} panicked: {}",
+                        panic_message(&*panic)
+                    );
+                    counters.record_handler_panic();
+                }
+                connections.lock().unwrap().remove(&id);
+            });
+        }
+        // Stop listening before waiting for the remaining connections
+        drop(incoming);
+
+        while tasks.join_next().await.is_some() {}
+        if let Some(reaper) = reaper {
+            reaper.abort();
+        }
+        result
+    }
+}
+
+/// Probe the idle connections in `connections`, and disconnect those that have not answered
+/// within the timeout.
+async fn reap(
+    connections: Arc<Mutex<BTreeMap<ConnectionId, Served>>>,
+    counters: Arc<ServerCounters>,
+    options: ReaperOptions,
+) {
+    // When the pings that are waiting for an answer were seen to have been sent, or `None` if
+    // they have been asked for but the framing has not sent them yet
+    let mut probed = BTreeMap::<ConnectionId, Option<Instant>>::new();
+    let mut interval = tokio::time::interval(options.period());
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let mut reaped = Vec::new();
+        {
+            let connections = connections.lock().unwrap();
+            probed.retain(|id, _| connections.contains_key(id));
+            for (id, served) in connections.iter() {
+                // Already being disconnected
+                if served.control.eviction.is_cancelled() {
+                    continue;
+                }
+                let probe = served.control.probe();
+                if probe.is_answered() {
+                    probed.remove(id);
+                }
+                let Some(sent) = probed.get_mut(id) else {
+                    if served.control.is_attached() && served.counters.idle() >= options.idle {
+                        probe.request();
+                        probed.insert(*id, None);
+                    }
+                    continue;
+                };
+                // A busy handler only sends the ping once it waits for the client again, so the
+                // timeout starts when the ping has been sent
+                if sent.is_none() && probe.is_sent() {
+                    *sent = Some(Instant::now());
+                }
+                if let Some(sent) = sent
+                    && sent.elapsed() >= options.timeout
+                {
+                    log::info!(
+                        "Disconnecting IPC connection {id}: No answer to keepalive within {:?}",
+                        options.timeout
+                    );
+                    served.control.eviction.cancel();
+                    reaped.push(served.info(*id));
+                }
+            }
+        }
+        for info in reaped {
+            probed.remove(&info.id);
+            counters.record_reaped();
+            if let Some(on_reap) = &options.on_reap {
+                on_reap(&info);
+            }
+        }
+    }
+}
+
+/// Return the message that a panic was raised with, if it has one.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "no message"
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod test {
+    use super::*;
+    use crate::frame::{Frame, FrameKind, FramedConnection, GoodbyeReason};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn test_serve() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let cancel = CancellationToken::new();
+        let mut endpoint = Endpoint::new(path.clone());
+        endpoint.set_cancellation_token(cancel.clone());
+
+        let server = tokio::spawn(
+            IpcServer::new().serve(endpoint, |mut connection| async move {
+                let mut request = [0u8; 1];
+                connection.read_exact(&mut request).await.unwrap();
+                if request[0] == 0 {
+                    panic!("Bad request");
+                }
+                connection.write_all(&request).await.unwrap();
+            }),
+        );
+
+        // A panicking handler does not bring down the server
+        let mut client = Endpoint::connect_when_ready(&path, Duration::from_secs(10))
+            .await
+            .unwrap();
+        client.write_all(&[0]).await.unwrap();
+        assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+
+        let mut client = Endpoint::connect(&path).await.unwrap();
+        client.write_all(&[1]).await.unwrap();
+        let mut response = [0u8; 1];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [1]);
+
+        cancel.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handler_panics() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let cancel = CancellationToken::new();
+        let mut endpoint = Endpoint::new(path.clone());
+        endpoint.set_cancellation_token(cancel.clone());
+
+        let calls = std::sync::atomic::AtomicUsize::new(0);
+        let ipc_server = IpcServer::new();
+        let server = tokio::spawn(ipc_server.clone().serve(endpoint, move |mut connection| {
+            let call = calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            // The first call panics before returning a future, and the second one in it
+            assert_ne!(call, 0, "Bad handler");
+            async move {
+                assert_ne!(call, 1, "Bad future");
+                connection.write_all(&[1]).await.unwrap();
+            }
+        }));
+
+        for _ in 0..2 {
+            let mut client = Endpoint::connect_when_ready(&path, Duration::from_secs(10))
+                .await
+                .unwrap();
+            assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+        }
+        let mut client = Endpoint::connect(&path).await.unwrap();
+        let mut response = [0u8; 1];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [1]);
+
+        while !ipc_server.connections().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(ipc_server.stats().handler_panics, 2);
+        cancel.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connections() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let cancel = CancellationToken::new();
+        let mut endpoint = Endpoint::new(path.clone());
+        endpoint.set_cancellation_token(cancel.clone());
+
+        let ipc_server = IpcServer::new();
+        let server = tokio::spawn(ipc_server.clone().serve(
+            endpoint,
+            |mut connection| async move {
+                let mut request = [0u8; 1];
+                while connection.read_exact(&mut request).await.is_ok() {
+                    connection.write_all(&request).await.unwrap();
+                }
+            },
+        ));
+
+        let mut client = Endpoint::connect_when_ready(&path, Duration::from_secs(10))
+            .await
+            .unwrap();
+        client.write_all(&[1]).await.unwrap();
+        client.read_exact(&mut [0u8; 1]).await.unwrap();
+
+        let connections = ipc_server.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].stats.bytes_received, 1);
+        assert_eq!(connections[0].stats.bytes_sent, 1);
+        // The socket was created by this process, so it is owned by the same user as the client
+        #[cfg(unix)]
+        assert_eq!(
+            connections[0].peer.map(|peer| peer.uid()),
+            Some(std::os::unix::fs::MetadataExt::uid(
+                &std::fs::metadata(&path).unwrap()
+            ))
+        );
+
+        drop(client);
+        while !ipc_server.connections().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(ipc_server.stats().accepted, 1);
+        cancel.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disconnect() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let cancel = CancellationToken::new();
+        let mut endpoint = Endpoint::new(path.clone());
+        endpoint.set_cancellation_token(cancel.clone());
+
+        let ipc_server = IpcServer::new();
+        let server = tokio::spawn(ipc_server.clone().serve(endpoint, |connection| async move {
+            let control = connection.control().unwrap().clone();
+            let mut connection = FramedConnection::new(connection);
+            connection.set_control(control);
+            while let Ok(Some(frame)) = connection.read_frame().await {
+                connection.write_frame(&frame).await.unwrap();
+            }
+        }));
+
+        let client = Endpoint::connect_when_ready(&path, Duration::from_secs(10))
+            .await
+            .unwrap();
+        let mut client = FramedConnection::new(client);
+        client.write_frame(&Frame::data(&b"1"[..])).await.unwrap();
+        client.read_frame().await.unwrap().unwrap();
+
+        let id = ipc_server.connections()[0].id;
+        assert!(ipc_server.disconnect(id, "Misbehaving"));
+        assert_eq!(
+            client.next_frame().await.unwrap().unwrap().goodbye_reason(),
+            GoodbyeReason::Evicted
+        );
+        assert!(client.read_frame().await.unwrap().is_none());
+
+        while !ipc_server.connections().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!ipc_server.disconnect(id, "Misbehaving"));
+        cancel.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_goodbye_when_draining() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let shutdown = crate::shutdown::ShutdownHandle::new();
+        let mut endpoint = Endpoint::new(path.clone());
+        endpoint.set_shutdown_handle(shutdown.clone());
+
+        let server = tokio::spawn(IpcServer::new().serve(endpoint, |connection| async move {
+            let control = connection.control().unwrap().clone();
+            let mut connection = FramedConnection::new(connection);
+            connection.set_control(control);
+            while let Ok(Some(frame)) = connection.read_frame().await {
+                connection.write_frame(&frame).await.unwrap();
+            }
+        }));
+
+        let client = Endpoint::connect_when_ready(&path, Duration::from_secs(10))
+            .await
+            .unwrap();
+        let mut client = FramedConnection::new(client);
+        client.write_frame(&Frame::data(&b"1"[..])).await.unwrap();
+        client.read_frame().await.unwrap().unwrap();
+
+        let drain = tokio::spawn(async move { shutdown.drain(Duration::from_secs(10)).await });
+        assert_eq!(
+            client.next_frame().await.unwrap().unwrap().goodbye_reason(),
+            GoodbyeReason::ShuttingDown
+        );
+        // Requests are still answered until the client goes away
+        client.write_frame(&Frame::data(&b"2"[..])).await.unwrap();
+        assert_eq!(
+            &client.read_frame().await.unwrap().unwrap().payload[..],
+            b"2"
+        );
+
+        drop(client);
+        assert!(drain.await.unwrap());
+        server.await.unwrap().unwrap();
+    }
+
+    /// Without the control, the server cannot say goodbye in between frames, and only closes
+    /// the connection.
+    #[tokio::test]
+    async fn test_disconnect_without_control() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let cancel = CancellationToken::new();
+        let mut endpoint = Endpoint::new(path.clone());
+        endpoint.set_cancellation_token(cancel.clone());
+
+        let ipc_server = IpcServer::new();
+        let server = tokio::spawn(ipc_server.clone().serve(
+            endpoint,
+            |mut connection| async move {
+                let mut request = [0u8; 1];
+                while connection.read_exact(&mut request).await.is_ok() {
+                    connection.write_all(&request).await.unwrap();
+                }
+            },
+        ));
+
+        let mut client = Endpoint::connect_when_ready(&path, Duration::from_secs(10))
+            .await
+            .unwrap();
+        client.write_all(&[1]).await.unwrap();
+        client.read_exact(&mut [0u8; 1]).await.unwrap();
+
+        let id = ipc_server.connections()[0].id;
+        assert!(ipc_server.disconnect(id, "Misbehaving"));
+        assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+
+        cancel.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    /// Pings are sent through the framing, so they are encoded like any other frame, e.g. with a
+    /// checksum.
+    #[tokio::test]
+    async fn test_probe_is_framed() {
+        use crate::handshake::Capabilities;
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        client.set_capabilities(Capabilities::CHECKSUM);
+        server.set_capabilities(Capabilities::CHECKSUM);
+        let control = ConnectionControl::default();
+        server.set_control(control.clone());
+        let server = tokio::spawn(async move {
+            let frame = server.read_frame().await;
+            (server, frame)
+        });
+
+        control.probe().request();
+        assert_eq!(
+            client.next_frame().await.unwrap().unwrap().kind,
+            FrameKind::Ping
+        );
+        assert!(control.probe().is_sent());
+        assert!(!control.probe().is_answered());
+        client.write_frame(&Frame::data(&b"1"[..])).await.unwrap();
+        let (mut server, frame) = server.await.unwrap();
+        assert_eq!(frame.unwrap(), Some(Frame::data(&b"1"[..])));
+        assert!(control.probe().is_answered());
+
+        // Once disconnected, the framing says goodbye instead of waiting for the peer
+        control.eviction().cancel();
+        assert!(matches!(
+            server.read_frame().await,
+            Err(crate::Error::Closed)
+        ));
+        assert_eq!(
+            client.next_frame().await.unwrap().unwrap().goodbye_reason(),
+            GoodbyeReason::Evicted
+        );
+    }
+
+    /// Advance the paused clock a second at a time until `done` returns `true`, so that the
+    /// tasks that run in between see the time pass as it would.
+    async fn advance_until(mut done: impl FnMut() -> bool) {
+        while !done() {
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reaper() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let cancel = CancellationToken::new();
+        let mut endpoint = Endpoint::new(path.clone());
+        endpoint.set_cancellation_token(cancel.clone());
+
+        let reaped = Arc::new(Mutex::new(Vec::new()));
+        let mut options = ReaperOptions::new();
+        options.set_idle(Duration::from_secs(60));
+        options.set_timeout(Duration::from_secs(10));
+        options.set_reap_callback({
+            let reaped = reaped.clone();
+            Arc::new(move |info: &ConnectionInfo| reaped.lock().unwrap().push(info.id))
+        });
+        let mut ipc_server = IpcServer::new();
+        ipc_server.set_reaper(options);
+        let server = tokio::spawn(ipc_server.clone().serve(endpoint, |connection| async move {
+            let control = connection.control().unwrap().clone();
+            let mut connection = FramedConnection::new(connection);
+            connection.set_control(control);
+            while let Ok(Some(_)) = connection.read_frame().await {}
+        }));
+
+        // This client answers pings, and the other one does not
+        let alive = Endpoint::connect_when_ready(&path, Duration::from_secs(10))
+            .await
+            .unwrap();
+        let mut alive = FramedConnection::new(alive);
+        let alive =
+            tokio::spawn(async move { while let Ok(Some(_)) = alive.read_frame().await {} });
+        let dead = Endpoint::connect(&path).await.unwrap();
+        advance_until(|| ipc_server.connections().len() == 2).await;
+        let ids: Vec<_> = ipc_server
+            .connections()
+            .iter()
+            .map(|info| info.id)
+            .collect();
+
+        advance_until(|| ipc_server.connections().len() == 1).await;
+        assert_eq!(*reaped.lock().unwrap(), [ids[1]]);
+        let mut dead = FramedConnection::new(dead);
+        assert_eq!(
+            dead.next_frame().await.unwrap().unwrap().kind,
+            FrameKind::Ping
+        );
+        assert_eq!(
+            dead.next_frame().await.unwrap().unwrap().goodbye_reason(),
+            GoodbyeReason::Evicted
+        );
+
+        // The client that answers is probed again, but not reaped once the timeout has passed
+        let received = ipc_server.connections()[0].stats.bytes_received;
+        advance_until(|| ipc_server.connections()[0].stats.bytes_received > received).await;
+        for _ in 0..20 {
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+        assert_eq!(ipc_server.connections()[0].id, ids[0]);
+        assert_eq!(ipc_server.stats().reaped, 1);
+
+        alive.abort();
+        cancel.cancel();
+        server.await.unwrap().unwrap();
+    }
+}
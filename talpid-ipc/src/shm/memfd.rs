@@ -0,0 +1,135 @@
+use crate::Connection;
+use std::{
+    ffi::{CStr, c_int},
+    fs, io,
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+    ptr::{self, NonNull},
+};
+
+const REGION_NAME: &CStr = c"talpid-ipc-bulk";
+
+/// Seals that must be present on a region received from the peer. Without them, the peer could
+/// shrink the file while it is mapped, and accessing the mapping would raise `SIGBUS`.
+const REQUIRED_SEALS: c_int = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL;
+
+/// A sealed `memfd` mapped into memory.
+pub struct SharedRegion {
+    fd: OwnedFd,
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: The mapping is exclusively owned by this value
+unsafe impl Send for SharedRegion {}
+
+impl SharedRegion {
+    /// Create a writable region of `len` bytes, sealed so that its size can no longer change.
+    pub fn create(len: usize) -> io::Result<Self> {
+        // SAFETY: `REGION_NAME` is a valid null-terminated string
+        let fd = unsafe {
+            libc::memfd_create(
+                REGION_NAME.as_ptr(),
+                libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just created and is not owned by anything else
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        fs::File::from(fd.try_clone()?).set_len(len as u64)?;
+
+        // SAFETY: `fd` is a valid memfd that allows sealing
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_ADD_SEALS, REQUIRED_SEALS) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Self::map(fd, len, libc::PROT_READ | libc::PROT_WRITE)
+    }
+
+    /// Pass the region to the peer.
+    pub async fn send_to(&self, connection: &Connection) -> io::Result<()> {
+        connection.send_fd(self.fd.as_fd()).await
+    }
+
+    /// Receive a region of at least `len` bytes from the peer, and map it read-only.
+    pub async fn receive_from(connection: &Connection, len: usize) -> io::Result<Self> {
+        let fd = connection.recv_fd().await?;
+
+        // SAFETY: `fd` is a valid file descriptor
+        let seals = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GET_SEALS) };
+        if seals < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if seals & REQUIRED_SEALS != REQUIRED_SEALS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Shared memory region is not sealed",
+            ));
+        }
+
+        let file = fs::File::from(fd);
+        if file.metadata()?.len() < len as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Shared memory region is smaller than announced",
+            ));
+        }
+
+        Self::map(OwnedFd::from(file), len, libc::PROT_READ)
+    }
+
+    fn map(fd: OwnedFd, len: usize, prot: c_int) -> io::Result<Self> {
+        // SAFETY: The file is at least `len` bytes large, and its seals prevent it from shrinking
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let ptr = NonNull::new(ptr.cast::<u8>())
+            .ok_or_else(|| io::Error::other("mmap returned a null pointer"))?;
+        Ok(SharedRegion { fd, ptr, len })
+    }
+
+    /// Size of the region in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Copy `src` to the beginning of the region. The region must have been created by
+    /// [`Self::create`].
+    pub fn write(&mut self, src: &[u8]) {
+        assert!(src.len() <= self.len);
+        // SAFETY: The mapping is writable and at least `src.len()` bytes large
+        unsafe { ptr::copy_nonoverlapping(src.as_ptr(), self.ptr.as_ptr(), src.len()) };
+    }
+
+    /// Append the first `len` bytes of the region to `dst`.
+    pub fn read(&self, len: usize, dst: &mut Vec<u8>) {
+        assert!(len <= self.len);
+        dst.reserve(len);
+        // SAFETY: The mapping is at least `len` bytes large, and `dst` has room for `len` more
+        // bytes. The bytes are copied without creating a reference to the shared memory, since
+        // the peer may write to it concurrently.
+        unsafe {
+            ptr::copy_nonoverlapping(self.ptr.as_ptr(), dst.as_mut_ptr().add(dst.len()), len);
+            dst.set_len(dst.len() + len);
+        }
+    }
+}
+
+impl Drop for SharedRegion {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` describe a mapping created by `mmap` that is no longer used
+        unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.len) };
+    }
+}
@@ -0,0 +1,405 @@
+//! Bulk transfers through shared memory.
+//!
+//! Large payloads, such as the relay list or problem reports, are expensive to push through the
+//! connection itself. A bulk transfer starts with the sender offering a shared memory region. If
+//! the receiver accepts it, the region is passed over the connection and the data is streamed
+//! through it one chunk at a time, while the connection only carries small control frames. If
+//! either side does not support shared memory, or the payload is small, the data is sent in-band
+//! instead.
+//!
+//! Shared memory is currently only supported on Linux and Android, where `memfd` seals guarantee
+//! that the peer cannot resize the region while it is mapped.
+
+use crate::{
+    Connection, Error,
+    frame::{Frame, FrameKind, FramedConnection},
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod memfd;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use memfd::SharedRegion;
+
+/// Whether this platform can receive and map a shared memory region.
+const SHARED_MEMORY_SUPPORTED: bool = cfg!(any(target_os = "linux", target_os = "android"));
+
+/// Payloads smaller than this are always sent in-band.
+const SHARED_MEMORY_THRESHOLD: usize = 256 * 1024;
+/// Size of the shared memory region. Larger payloads are streamed through it in several chunks.
+const REGION_LEN: usize = 1024 * 1024;
+/// Size of the chunks that payloads sent in-band are split into.
+const INLINE_CHUNK_LEN: usize = 64 * 1024;
+
+const OP_OFFER: u8 = 0;
+const OP_READY: u8 = 1;
+const OP_INLINE: u8 = 2;
+const OP_CHUNK: u8 = 3;
+const OP_ACK: u8 = 4;
+const OP_REJECT: u8 = 5;
+
+/// Control messages of a bulk transfer, sent in [`FrameKind::Bulk`] frames.
+#[derive(Debug, PartialEq, Eq)]
+enum Message {
+    /// Sent by the sender to start a transfer. `region_len` is zero if the sender does not offer
+    /// a shared memory region.
+    Offer { total_len: u64, region_len: u32 },
+    /// The receiver accepts the region, which is passed to it next.
+    Ready,
+    /// The receiver wants the data to be sent in-band.
+    Inline,
+    /// `len` bytes are available, either at the start of the region or in `data`.
+    Chunk { len: u32, data: Bytes },
+    /// The receiver has consumed the chunk in the region, which may now be overwritten.
+    Ack,
+    /// The receiver refuses the transfer.
+    Reject,
+}
+
+impl Message {
+    fn into_frame(self) -> Frame {
+        let mut payload = BytesMut::new();
+        match self {
+            Message::Offer {
+                total_len,
+                region_len,
+            } => {
+                payload.put_u8(OP_OFFER);
+                payload.put_u64(total_len);
+                payload.put_u32(region_len);
+            }
+            Message::Ready => payload.put_u8(OP_READY),
+            Message::Inline => payload.put_u8(OP_INLINE),
+            Message::Chunk { len, data } => {
+                payload.put_u8(OP_CHUNK);
+                payload.put_u32(len);
+                payload.extend_from_slice(&data);
+            }
+            Message::Ack => payload.put_u8(OP_ACK),
+            Message::Reject => payload.put_u8(OP_REJECT),
+        }
+        Frame::new(FrameKind::Bulk, payload.freeze())
+    }
+
+    fn from_frame(frame: Frame) -> Result<Self, Error> {
+        if frame.kind != FrameKind::Bulk {
+            return Err(Error::Protocol("Expected a bulk transfer frame"));
+        }
+        let mut payload = frame.payload;
+        if !payload.has_remaining() {
+            return Err(Error::Protocol("Empty bulk transfer frame"));
+        }
+        let message = match payload.get_u8() {
+            OP_OFFER if payload.remaining() == 12 => Message::Offer {
+                total_len: payload.get_u64(),
+                region_len: payload.get_u32(),
+            },
+            OP_READY => Message::Ready,
+            OP_INLINE => Message::Inline,
+            OP_CHUNK if payload.remaining() >= 4 => Message::Chunk {
+                len: payload.get_u32(),
+                data: payload,
+            },
+            OP_ACK => Message::Ack,
+            OP_REJECT => Message::Reject,
+            _ => return Err(Error::Protocol("Malformed bulk transfer frame")),
+        };
+        Ok(message)
+    }
+}
+
+/// Send `data` to the peer, which must call [`receive`].
+pub async fn send(connection: &mut FramedConnection<Connection>, data: &[u8]) -> Result<(), Error> {
+    let mut region = None;
+    if SHARED_MEMORY_SUPPORTED && data.len() >= SHARED_MEMORY_THRESHOLD {
+        match create_region(data.len().min(REGION_LEN)) {
+            Ok(created) => region = Some(created),
+            Err(error) => {
+                log::debug!("Falling back to in-band bulk transfer: {error}");
+            }
+        }
+    }
+
+    write(
+        connection,
+        Message::Offer {
+            total_len: data.len() as u64,
+            region_len: region.as_ref().map(region_len).unwrap_or(0),
+        },
+    )
+    .await?;
+
+    match (read(connection).await?, region) {
+        (Message::Ready, Some(region)) => send_shared(connection, region, data).await,
+        (Message::Inline, _) => send_inline(connection, data).await,
+        (Message::Reject, _) => Err(Error::BulkRejected),
+        _ => Err(Error::Protocol("Unexpected reply to bulk transfer offer")),
+    }
+}
+
+/// Receive data sent by the peer using [`send`]. Transfers larger than `max_len` are rejected.
+pub async fn receive(
+    connection: &mut FramedConnection<Connection>,
+    max_len: u64,
+) -> Result<Vec<u8>, Error> {
+    let Message::Offer {
+        total_len,
+        region_len,
+    } = read(connection).await?
+    else {
+        return Err(Error::Protocol("Expected a bulk transfer offer"));
+    };
+
+    let total_len = match usize::try_from(total_len) {
+        Ok(len) if total_len <= max_len => len,
+        _ => {
+            write(connection, Message::Reject).await?;
+            return Err(Error::BulkTooLarge {
+                len: total_len,
+                max: max_len,
+            });
+        }
+    };
+
+    if SHARED_MEMORY_SUPPORTED && region_len > 0 {
+        write(connection, Message::Ready).await?;
+        if connection.has_buffered_input() {
+            return Err(Error::Protocol(
+                "Received data before the shared memory region",
+            ));
+        }
+        let region = receive_region(connection.get_ref(), region_len as usize).await?;
+        receive_shared(connection, region, total_len).await
+    } else {
+        write(connection, Message::Inline).await?;
+        receive_inline(connection, total_len).await
+    }
+}
+
+async fn send_inline(
+    connection: &mut FramedConnection<Connection>,
+    data: &[u8],
+) -> Result<(), Error> {
+    for chunk in data.chunks(INLINE_CHUNK_LEN) {
+        let message = Message::Chunk {
+            len: chunk.len() as u32,
+            data: Bytes::copy_from_slice(chunk),
+        };
+        write(connection, message).await?;
+    }
+    Ok(())
+}
+
+async fn receive_inline(
+    connection: &mut FramedConnection<Connection>,
+    total_len: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut received = Vec::with_capacity(total_len);
+    while received.len() < total_len {
+        let (len, data) = read_chunk(connection, total_len - received.len()).await?;
+        if data.len() != len {
+            return Err(Error::Protocol("In-band chunk does not match its length"));
+        }
+        received.extend_from_slice(&data);
+    }
+    Ok(received)
+}
+
+/// Read a chunk of at most `remaining` bytes. Returns its length and in-band data.
+async fn read_chunk(
+    connection: &mut FramedConnection<Connection>,
+    remaining: usize,
+) -> Result<(usize, Bytes), Error> {
+    let Message::Chunk { len, data } = read(connection).await? else {
+        return Err(Error::Protocol("Expected a bulk transfer chunk"));
+    };
+    let len = len as usize;
+    if len == 0 || len > remaining {
+        return Err(Error::Protocol("Invalid bulk transfer chunk length"));
+    }
+    Ok((len, data))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn create_region(len: usize) -> io::Result<SharedRegion> {
+    SharedRegion::create(len)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn region_len(region: &SharedRegion) -> u32 {
+    region.len() as u32
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+async fn receive_region(connection: &Connection, len: usize) -> io::Result<SharedRegion> {
+    SharedRegion::receive_from(connection, len).await
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+async fn send_shared(
+    connection: &mut FramedConnection<Connection>,
+    mut region: SharedRegion,
+    data: &[u8],
+) -> Result<(), Error> {
+    region.send_to(connection.get_ref()).await?;
+    for chunk in data.chunks(region.len()) {
+        region.write(chunk);
+        let message = Message::Chunk {
+            len: chunk.len() as u32,
+            data: Bytes::new(),
+        };
+        write(connection, message).await?;
+        if read(connection).await? != Message::Ack {
+            return Err(Error::Protocol("Expected a bulk transfer acknowledgement"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+async fn receive_shared(
+    connection: &mut FramedConnection<Connection>,
+    region: SharedRegion,
+    total_len: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut received = Vec::with_capacity(total_len);
+    while received.len() < total_len {
+        let remaining = (total_len - received.len()).min(region.len());
+        let (len, data) = read_chunk(connection, remaining).await?;
+        if !data.is_empty() {
+            return Err(Error::Protocol(
+                "Unexpected in-band data in shared memory chunk",
+            ));
+        }
+        region.read(len, &mut received);
+        write(connection, Message::Ack).await?;
+    }
+    Ok(received)
+}
+
+/// Placeholder for platforms without shared memory support. It can never be constructed.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+enum SharedRegion {}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn create_region(_len: usize) -> io::Result<SharedRegion> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn region_len(region: &SharedRegion) -> u32 {
+    match *region {}
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+async fn receive_region(_connection: &Connection, _len: usize) -> io::Result<SharedRegion> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+async fn send_shared(
+    _connection: &mut FramedConnection<Connection>,
+    region: SharedRegion,
+    _data: &[u8],
+) -> Result<(), Error> {
+    match region {}
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+async fn receive_shared(
+    _connection: &mut FramedConnection<Connection>,
+    region: SharedRegion,
+    _total_len: usize,
+) -> Result<Vec<u8>, Error> {
+    match region {}
+}
+
+async fn write(
+    connection: &mut FramedConnection<Connection>,
+    message: Message,
+) -> Result<(), Error> {
+    connection.write_frame(&message.into_frame()).await
+}
+
+async fn read(connection: &mut FramedConnection<Connection>) -> Result<Message, Error> {
+    let frame = connection.read_frame().await?.ok_or(Error::UnexpectedEof)?;
+    Message::from_frame(frame)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::EphemeralEndpoint;
+
+    /// Send `data` from one end of a new connection to the other.
+    async fn transfer(data: &[u8], max_len: u64) -> (Result<(), Error>, Result<Vec<u8>, Error>) {
+        let mut endpoint = EphemeralEndpoint::new().unwrap();
+        let (client, server) = endpoint.connected_pair().await.unwrap();
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        tokio::join!(send(&mut client, data), receive(&mut server, max_len))
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let messages = [
+            Message::Offer {
+                total_len: 1 << 40,
+                region_len: 4096,
+            },
+            Message::Ready,
+            Message::Inline,
+            Message::Chunk {
+                len: 3,
+                data: Bytes::from_static(b"abc"),
+            },
+            Message::Ack,
+            Message::Reject,
+        ];
+        for message in messages {
+            let frame = Message::into_frame(message);
+            let decoded = Message::from_frame(frame.clone()).unwrap();
+            assert_eq!(decoded.into_frame(), frame);
+        }
+    }
+
+    #[test]
+    fn test_truncated_offer() {
+        let frame = Frame::new(FrameKind::Bulk, Bytes::from_static(&[OP_OFFER, 0, 0]));
+        assert!(matches!(
+            Message::from_frame(frame),
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_inline_transfer() {
+        let data: Vec<u8> = (0..SHARED_MEMORY_THRESHOLD - 1).map(|i| i as u8).collect();
+        let (sent, received) = transfer(&data, u64::MAX).await;
+        sent.unwrap();
+        assert_eq!(received.unwrap(), data);
+    }
+
+    /// The payload does not fit in the region, so it has to be streamed through it in several
+    /// chunks.
+    #[tokio::test]
+    async fn test_shared_transfer() {
+        let data: Vec<u8> = (0..REGION_LEN * 2 + 12345)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let (sent, received) = transfer(&data, u64::MAX).await;
+        sent.unwrap();
+        assert_eq!(received.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_too_large() {
+        let (sent, received) = transfer(&[0; 100], 99).await;
+        assert!(matches!(sent, Err(Error::BulkRejected)));
+        assert!(matches!(
+            received,
+            Err(Error::BulkTooLarge { len: 100, max: 99 })
+        ));
+    }
+}
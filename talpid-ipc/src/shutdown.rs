@@ -0,0 +1,238 @@
+//! Graceful shutdown of a server.
+//!
+//! Install a [`ShutdownHandle`] with [`Endpoint::set_shutdown_handle`]. Calling
+//! [`ShutdownHandle::drain`] then stops accepting connections, tells every accepted connection to
+//! wrap up through its [`ShutdownSignal`], and waits for the connections to be dropped. The
+//! listener is only closed, and the socket file removed, once the connections are gone or the
+//! timeout has passed, so clients do not observe the endpoint disappearing while they are still
+//! being served.
+//!
+//! Connections are expected to send a [goodbye frame](crate::frame::FrameKind::Goodbye) once
+//! they are signaled, finish any requests that are in flight, and then drop the connection.
+//! Connections that are served by an [`IpcServer`](crate::server::IpcServer), and that have its
+//! control attached to their framing, send the goodbye by themselves, see
+//! [`FramedConnection::set_control`](crate::frame::FramedConnection::set_control).
+//!
+//! [`Endpoint::set_shutdown_handle`]: crate::Endpoint::set_shutdown_handle
+
+use crate::imp;
+use std::{
+    pin::pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+/// Initiates a graceful shutdown. Clones refer to the same server.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    draining: CancellationToken,
+    /// Number of accepted connections that have not been dropped yet.
+    active: AtomicUsize,
+    /// Notified when `active` drops to zero.
+    idle: Notify,
+    /// Listeners that stopped accepting but are kept open until draining has finished. This is
+    /// `None` once it has finished.
+    #[cfg(feature = "server")]
+    parked: Mutex<Option<Vec<imp::Incoming>>>,
+    /// Cancelled once draining has finished, and the parked listeners have been closed.
+    drained: CancellationToken,
+}
+
+impl Default for Shared {
+    fn default() -> Self {
+        Shared {
+            draining: CancellationToken::new(),
+            active: AtomicUsize::new(0),
+            idle: Notify::new(),
+            #[cfg(feature = "server")]
+            parked: Mutex::new(Some(vec![])),
+            drained: CancellationToken::new(),
+        }
+    }
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        ShutdownHandle::default()
+    }
+
+    /// Whether [`Self::drain`] has been called.
+    pub fn is_draining(&self) -> bool {
+        self.shared.draining.is_cancelled()
+    }
+
+    /// Number of accepted connections that are still open.
+    pub fn active_connections(&self) -> usize {
+        self.shared.active.load(Ordering::SeqCst)
+    }
+
+    /// Stop accepting connections, signal all open connections to finish, and wait up to
+    /// `timeout` for them to be dropped. The listeners are closed afterwards. Returns whether
+    /// all connections finished in time.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.shared.draining.cancel();
+        let finished = tokio::time::timeout(timeout, self.wait_idle())
+            .await
+            .is_ok();
+        if !finished {
+            log::warn!(
+                "{} IPC connections did not finish before the shutdown timeout",
+                self.active_connections()
+            );
+        }
+        #[cfg(feature = "server")]
+        self.close_parked();
+        self.shared.drained.cancel();
+        finished
+    }
+
+    /// Close the listeners that were kept open while draining, removing their sockets.
+    #[cfg(feature = "server")]
+    fn close_parked(&self) {
+        let parked = self.shared.parked.lock().unwrap().take();
+        for incoming in parked.into_iter().flatten() {
+            if let Err(error) = incoming.close() {
+                log::warn!("Failed to close IPC listener after draining: {error}");
+            }
+        }
+    }
+
+    /// Wait until draining has finished, and the listeners have been closed.
+    #[cfg(feature = "server")]
+    pub(crate) async fn drained(&self) {
+        self.shared.drained.cancelled().await
+    }
+
+    async fn wait_idle(&self) {
+        loop {
+            let mut notified = pin!(self.shared.idle.notified());
+            notified.as_mut().enable();
+            if self.active_connections() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Future that completes once draining begins.
+    pub(crate) fn draining_owned(&self) -> tokio_util::sync::WaitForCancellationFutureOwned {
+        self.shared.draining.clone().cancelled_owned()
+    }
+
+    /// Keep a listener open until draining has finished.
+    #[cfg(feature = "server")]
+    pub(crate) fn park(&self, incoming: imp::Incoming) {
+        if let Some(parked) = &mut *self.shared.parked.lock().unwrap() {
+            parked.push(incoming);
+        }
+    }
+
+    /// Track a newly accepted connection.
+    pub(crate) fn register(&self) -> ShutdownSignal {
+        self.shared.active.fetch_add(1, Ordering::SeqCst);
+        ShutdownSignal {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// Tells an accepted connection that the server is shutting down. The connection counts as
+/// open until this is dropped along with the [`Connection`](crate::Connection).
+pub struct ShutdownSignal {
+    shared: Arc<Shared>,
+}
+
+impl ShutdownSignal {
+    /// Whether the server is shutting down.
+    pub fn is_draining(&self) -> bool {
+        self.shared.draining.is_cancelled()
+    }
+
+    /// Wait until the server starts shutting down.
+    pub async fn draining(&self) {
+        self.shared.draining.cancelled().await
+    }
+
+    /// Token that is cancelled when the server starts shutting down. Unlike the signal, it does
+    /// not keep the connection counted as open.
+    pub(crate) fn draining_token(&self) -> CancellationToken {
+        self.shared.draining.clone()
+    }
+}
+
+impl Drop for ShutdownSignal {
+    fn drop(&mut self) {
+        if self.shared.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_waits_for_connections() {
+        let handle = ShutdownHandle::new();
+        let signal = handle.register();
+        assert_eq!(handle.active_connections(), 1);
+
+        let connection = tokio::spawn(async move {
+            signal.draining().await;
+            // Finish the request in flight before going away
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            drop(signal);
+        });
+
+        assert!(handle.drain(Duration::from_secs(10)).await);
+        assert_eq!(handle.active_connections(), 0);
+        connection.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_timeout() {
+        let handle = ShutdownHandle::new();
+        let _signal = handle.register();
+        let started = tokio::time::Instant::now();
+        assert!(!handle.drain(Duration::from_secs(10)).await);
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+        assert!(handle.is_draining());
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_close_while_draining() {
+        let handle = ShutdownHandle::new();
+        let path = crate::testing::EphemeralPath::new().unwrap();
+        let mut endpoint = crate::Endpoint::new(path.path().to_owned());
+        endpoint.set_shutdown_handle(handle.clone());
+        let incoming = endpoint.incoming().unwrap();
+        let signal = handle.register();
+
+        let drain = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.drain(Duration::from_secs(10)).await }
+        });
+        signal.draining().await;
+        let close = tokio::spawn(incoming.close());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // The listener is kept open until the connection has finished
+        assert!(!close.is_finished());
+
+        drop(signal);
+        close.await.unwrap().unwrap();
+        assert!(drain.await.unwrap());
+        #[cfg(unix)]
+        assert!(!std::path::Path::new(path.path()).exists());
+    }
+}
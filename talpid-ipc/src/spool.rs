@@ -0,0 +1,209 @@
+//! Writing entries to a file on a thread of its own, for recordings that are made from the
+//! async code that sends and receives frames.
+//!
+//! Entries are queued and written in the order in which they were queued, so that recording a
+//! frame never blocks on the file. Entries that are queued while too many bytes are waiting to
+//! be written are dropped, so that a connection that is faster than the file cannot use up
+//! memory. How many were dropped is written in their place.
+
+use std::{
+    io::{self, Write},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Number of bytes that may wait to be written by default.
+pub const DEFAULT_QUEUE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Returns the entry that stands in for `count` entries that were dropped.
+pub(crate) type DroppedEntry = Box<dyn Fn(u64) -> Vec<u8> + Send>;
+
+/// Writes entries to an output on a thread of its own. Entries that have been queued are all
+/// written when it is dropped.
+pub(crate) struct Spool {
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+    /// Number of bytes that have been queued but not yet written.
+    queued: Arc<AtomicUsize>,
+    queue_size: usize,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Spool {
+    /// Write to `output` on a thread named `name`. `description` says what is written in log
+    /// messages, e.g. "IPC session recording".
+    pub fn spawn(
+        name: &str,
+        description: &'static str,
+        output: Box<dyn Write + Send>,
+        queue_size: usize,
+        dropped_entry: DroppedEntry,
+    ) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let mut writer = Writer {
+            output,
+            description,
+            queued: queued.clone(),
+            dropped: dropped.clone(),
+            reported_dropped: 0,
+            dropped_entry,
+        };
+        let writer = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                while let Ok(entry) = receiver.recv() {
+                    writer.write(&entry);
+                    while let Ok(entry) = receiver.try_recv() {
+                        writer.write(&entry);
+                    }
+                    // Flushed whenever nothing is waiting, so that little is lost if the
+                    // process crashes
+                    writer.flush();
+                }
+                writer.write_dropped();
+                writer.flush();
+            })?;
+        Ok(Spool {
+            sender: Some(sender),
+            writer: Some(writer),
+            queued,
+            queue_size,
+            dropped,
+        })
+    }
+
+    /// Queue `entry` to be written, unless too many bytes are waiting already.
+    pub fn write(&self, entry: Vec<u8>) {
+        let len = entry.len();
+        let admitted = self
+            .queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                Some(queued + len).filter(|&queued| queued <= self.queue_size)
+            })
+            .is_ok();
+        if !admitted {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if let Some(sender) = &self.sender
+            && sender.send(entry).is_err()
+        {
+            self.queued.fetch_sub(len, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of entries that were dropped because too many bytes were waiting to be written.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        // Let the writer finish what has been queued
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+struct Writer {
+    output: Box<dyn Write + Send>,
+    description: &'static str,
+    queued: Arc<AtomicUsize>,
+    dropped: Arc<AtomicU64>,
+    /// Value of `dropped` when it was last written.
+    reported_dropped: u64,
+    dropped_entry: DroppedEntry,
+}
+
+impl Writer {
+    fn write(&mut self, entry: &[u8]) {
+        self.write_dropped();
+        self.write_all(entry);
+        self.queued.fetch_sub(entry.len(), Ordering::Relaxed);
+    }
+
+    /// Write how many entries have been dropped since this was last called, if any.
+    fn write_dropped(&mut self) {
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped != self.reported_dropped {
+            let count = dropped - self.reported_dropped;
+            self.reported_dropped = dropped;
+            self.write_all(&(self.dropped_entry)(count));
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) {
+        if let Err(error) = self.output.write_all(buf) {
+            self.warn(error);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(error) = self.output.flush() {
+            self.warn(error);
+        }
+    }
+
+    fn warn(&self, error: io::Error) {
+        crate::log_limit::log(
+            log::Level::Warn,
+            || self.description,
+            format_args!("Failed to write {}: {error}", self.description),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Output that can be read while it is being written to.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Write `entries` through a spool that lets `queue_size` bytes wait, and return what was
+    /// written.
+    fn spool(queue_size: usize, entries: &[&[u8]]) -> Vec<u8> {
+        let output = SharedOutput::default();
+        let spool = Spool::spawn(
+            "test-spool",
+            "test output",
+            Box::new(output.clone()),
+            queue_size,
+            Box::new(|count| format!("[{count}]").into_bytes()),
+        )
+        .unwrap();
+        for entry in entries {
+            spool.write(entry.to_vec());
+        }
+        drop(spool);
+        output.0.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn test_dropped() {
+        // Entries that do not fit are written as how many they were, in their place
+        assert_eq!(spool(2, &[b"abc", b"de"]), b"[1]de");
+        assert_eq!(spool(2, &[b"abc", b"fgh"]), b"[2]");
+    }
+}
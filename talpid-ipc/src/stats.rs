@@ -0,0 +1,237 @@
+//! Statistics about the traffic on connections, e.g. for diagnostics output and problem reports.
+//!
+//! Every [`Connection`] keeps [`ConnectionCounters`], which are summarized by
+//! [`Connection::stats`]. Bytes are counted as they are read and written. Frames are only
+//! counted by a [`FramedConnection`] that has been given the counters with
+//! [`FramedConnection::set_counters`], since the connection itself is a plain byte stream.
+//!
+//! Accepted connections also add to the [`ServerCounters`] of their endpoint, if any. See
+//! [`Endpoint::set_server_counters`] and [`IpcServer::stats`].
+//!
+//! [`Connection`]: crate::Connection
+//! [`Connection::stats`]: crate::Connection::stats
+//! [`FramedConnection`]: crate::frame::FramedConnection
+//! [`FramedConnection::set_counters`]: crate::frame::FramedConnection::set_counters
+//! [`Endpoint::set_server_counters`]: crate::Endpoint::set_server_counters
+//! [`IpcServer::stats`]: crate::server::IpcServer::stats
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+/// Traffic on a single connection, as reported by [`ConnectionCounters::snapshot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    /// When the connection was established or accepted.
+    pub connected_at: SystemTime,
+    /// When something was last read from or written to the connection.
+    pub last_activity: SystemTime,
+}
+
+/// Totals of all connections accepted on an endpoint, as reported by
+/// [`ServerCounters::snapshot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ServerStats {
+    pub accepted: u64,
+    /// Number of accepted connections that are still open.
+    pub active: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    /// Number of connection handlers that panicked, see [`IpcServer::serve`].
+    ///
+    /// [`IpcServer::serve`]: crate::server::IpcServer::serve
+    pub handler_panics: u64,
+    /// Number of connections that were closed because they did not answer keepalive probes, see
+    /// [`IpcServer::set_reaper`].
+    ///
+    /// [`IpcServer::set_reaper`]: crate::server::IpcServer::set_reaper
+    pub reaped: u64,
+}
+
+/// Running totals of the traffic on a connection.
+#[derive(Debug)]
+pub struct ConnectionCounters {
+    server: Option<Arc<ServerCounters>>,
+    connected_at: SystemTime,
+    started: tokio::time::Instant,
+    traffic: Traffic,
+    /// Time of the last read or write, in milliseconds since `started`.
+    last_activity: AtomicU64,
+}
+
+impl ConnectionCounters {
+    /// Create counters for a new connection, which also add to `server`.
+    pub(crate) fn new(server: Option<Arc<ServerCounters>>) -> Arc<Self> {
+        if let Some(server) = &server {
+            server.accepted.fetch_add(1, Ordering::Relaxed);
+            server.active.fetch_add(1, Ordering::Relaxed);
+        }
+        Arc::new(ConnectionCounters {
+            server,
+            connected_at: SystemTime::now(),
+            started: tokio::time::Instant::now(),
+            traffic: Traffic::default(),
+            last_activity: AtomicU64::new(0),
+        })
+    }
+
+    /// Return the current totals.
+    pub fn snapshot(&self) -> ConnectionStats {
+        let last_activity = Duration::from_millis(self.last_activity.load(Ordering::Relaxed));
+        ConnectionStats {
+            bytes_sent: self.traffic.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.traffic.bytes_received.load(Ordering::Relaxed),
+            frames_sent: self.traffic.frames_sent.load(Ordering::Relaxed),
+            frames_received: self.traffic.frames_received.load(Ordering::Relaxed),
+            connected_at: self.connected_at,
+            last_activity: self.connected_at + last_activity,
+        }
+    }
+
+    pub(crate) fn record_sent(&self, len: usize) {
+        self.record(|traffic| &traffic.bytes_sent, len as u64);
+    }
+
+    pub(crate) fn record_received(&self, len: usize) {
+        self.record(|traffic| &traffic.bytes_received, len as u64);
+    }
+
+    pub(crate) fn record_frame_sent(&self) {
+        self.record(|traffic| &traffic.frames_sent, 1);
+    }
+
+    pub(crate) fn record_frame_received(&self) {
+        self.record(|traffic| &traffic.frames_received, 1);
+    }
+
+    /// Time since something was last read from or written to the connection.
+    pub(crate) fn idle(&self) -> Duration {
+        let last_activity = Duration::from_millis(self.last_activity.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last_activity)
+    }
+
+    fn record(&self, counter: fn(&Traffic) -> &AtomicU64, n: u64) {
+        counter(&self.traffic).fetch_add(n, Ordering::Relaxed);
+        if let Some(server) = &self.server {
+            counter(&server.traffic).fetch_add(n, Ordering::Relaxed);
+        }
+        let elapsed = self.started.elapsed().as_millis();
+        self.last_activity.fetch_max(
+            u64::try_from(elapsed).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+impl Drop for ConnectionCounters {
+    fn drop(&mut self) {
+        if let Some(server) = &self.server {
+            server.active.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Running totals of the traffic on all connections accepted on an endpoint.
+#[derive(Debug, Default)]
+pub struct ServerCounters {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    traffic: Traffic,
+    handler_panics: AtomicU64,
+    reaped: AtomicU64,
+}
+
+impl ServerCounters {
+    pub fn new() -> Self {
+        ServerCounters::default()
+    }
+
+    pub(crate) fn record_handler_panic(&self) {
+        self.handler_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_reaped(&self) {
+        self.reaped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Return the current totals.
+    pub fn snapshot(&self) -> ServerStats {
+        ServerStats {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            bytes_sent: self.traffic.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.traffic.bytes_received.load(Ordering::Relaxed),
+            frames_sent: self.traffic.frames_sent.load(Ordering::Relaxed),
+            frames_received: self.traffic.frames_received.load(Ordering::Relaxed),
+            handler_panics: self.handler_panics.load(Ordering::Relaxed),
+            reaped: self.reaped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Traffic {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    frames_sent: AtomicU64,
+    frames_received: AtomicU64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame::{Frame, FramedConnection};
+
+    #[tokio::test]
+    async fn test_counters() {
+        let server = Arc::new(ServerCounters::new());
+        let counters = ConnectionCounters::new(Some(server.clone()));
+
+        let (client, peer) = tokio::io::duplex(64);
+        let mut client = FramedConnection::new(client);
+        let mut peer = FramedConnection::new(peer);
+        client.set_counters(counters.clone());
+        // The byte stream does not count by itself, unlike `Connection`
+        counters.record_sent(10);
+
+        client
+            .write_frame(&Frame::data(&b"hello"[..]))
+            .await
+            .unwrap();
+        peer.write_frame(&Frame::data(&b"world"[..])).await.unwrap();
+        client.read_frame().await.unwrap().unwrap();
+
+        let stats = counters.snapshot();
+        assert_eq!(stats.bytes_sent, 10);
+        assert_eq!(stats.frames_sent, 1);
+        assert_eq!(stats.frames_received, 1);
+        assert!(stats.last_activity >= stats.connected_at);
+
+        assert_eq!(
+            server.snapshot(),
+            ServerStats {
+                accepted: 1,
+                active: 1,
+                bytes_sent: 10,
+                bytes_received: 0,
+                frames_sent: 1,
+                frames_received: 1,
+                handler_panics: 0,
+                reaped: 0,
+            }
+        );
+        drop(client);
+        drop(counters);
+        assert_eq!(server.snapshot().active, 0);
+    }
+}
@@ -0,0 +1,96 @@
+//! Connections over the standard streams of a process.
+//!
+//! A small helper that the daemon launches can speak the framed protocol over its stdin and
+//! stdout, with [`Connection::from_stdio`], instead of connecting to an endpoint. The daemon
+//! wraps the other ends, e.g. the `stdout` and `stdin` of a [`tokio::process::Child`], with
+//! [`StdioConnection::new`]. Everything that the helper logs must then go to stderr.
+
+use crate::Connection;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, Stdin, Stdout};
+
+/// A byte stream made of a reader and a writer, such as the standard streams of a process.
+#[derive(Debug)]
+pub struct StdioConnection<R = Stdin, W = Stdout> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> StdioConnection<R, W> {
+    /// Read from `reader` and write to `writer`.
+    pub fn new(reader: R, writer: W) -> Self {
+        StdioConnection { reader, writer }
+    }
+
+    /// Return the reader and the writer.
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl Connection {
+    /// Use the stdin and stdout of this process as a connection. Shutting the connection down
+    /// only flushes stdout, which is closed when the process exits.
+    pub fn from_stdio() -> StdioConnection {
+        StdioConnection::new(tokio::io::stdin(), tokio::io::stdout())
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for StdioConnection<R, W> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for StdioConnection<R, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame::{Frame, FramedConnection};
+
+    #[tokio::test]
+    async fn test_framing_over_streams() {
+        let (helper_stdin, daemon_writer) = tokio::io::duplex(1024);
+        let (daemon_reader, helper_stdout) = tokio::io::duplex(1024);
+        let mut daemon = FramedConnection::new(StdioConnection::new(daemon_reader, daemon_writer));
+        let mut helper = FramedConnection::new(StdioConnection::new(helper_stdin, helper_stdout));
+
+        daemon
+            .write_frame(&Frame::data(b"ping".to_vec()))
+            .await
+            .unwrap();
+        let frame = helper.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame, Frame::data(b"ping".to_vec()));
+        helper
+            .write_frame(&Frame::data(b"pong".to_vec()))
+            .await
+            .unwrap();
+        let frame = daemon.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame, Frame::data(b"pong".to_vec()));
+    }
+}
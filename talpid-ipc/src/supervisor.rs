@@ -0,0 +1,314 @@
+//! Binding the listener of a server again when it stops working.
+//!
+//! Accept errors that only concern a single connection are handled by the
+//! [`AcceptErrorPolicy`](crate::accept::AcceptErrorPolicy). Other errors mean that the listener
+//! itself is unusable, e.g. because its descriptor was revoked, or the pipe namespace was
+//! unavailable, and binding may fail for a while for the same reasons, or because the directory
+//! of the socket was removed. Without supervision, the server stops until the daemon is
+//! restarted.
+//!
+//! A [`ListenerSupervisor`] instead creates a new [`Endpoint`] and binds it again, waiting
+//! longer after each consecutive failure, and reports what it does as a [`ListenerState`]. Use
+//! it with [`IpcServer::serve_supervised`], or on its own with [`ListenerSupervisor::incoming`].
+//!
+//! [`IpcServer::serve_supervised`]: crate::server::IpcServer::serve_supervised
+
+use crate::{
+    Connection, Endpoint, Incoming,
+    accept::{self, AcceptErrorPolicy},
+    backoff::{Backoff, ExponentialBackoff},
+};
+use futures::{Stream, StreamExt};
+use std::{
+    io,
+    ops::ControlFlow,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+/// Delays between attempts to bind, unless [`ListenerSupervisor::set_backoff`] is called.
+const DEFAULT_BACKOFF: ExponentialBackoff =
+    ExponentialBackoff::new(Duration::from_millis(100), Duration::from_secs(30));
+
+/// How transient accept errors are handled on endpoints that would otherwise yield them, since
+/// those do not mean that the listener has to be bound again.
+const ACCEPT_ERROR_POLICY: AcceptErrorPolicy = AcceptErrorPolicy::RetryWithBackoff {
+    initial: Duration::from_millis(10),
+    max: Duration::from_secs(1),
+};
+
+/// What a [`ListenerSupervisor`] is doing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerState {
+    /// The endpoint has not been bound yet.
+    Starting,
+    /// The endpoint is bound and accepting connections.
+    Listening,
+    /// Binding or accepting failed, and the endpoint is about to be bound again. `attempt`
+    /// counts the consecutive failures, starting at 1.
+    Degraded { error: String, attempt: u32 },
+    /// The backoff gave up after too many consecutive failures. No more connections are
+    /// accepted.
+    Failed { error: String },
+    /// The endpoint stopped accepting, e.g. because the server is shutting down.
+    Stopped,
+}
+
+/// Binds an endpoint again whenever its listener fails.
+#[derive(Debug)]
+pub struct ListenerSupervisor {
+    backoff: Arc<dyn Backoff>,
+    cancel: Option<CancellationToken>,
+    state: watch::Sender<ListenerState>,
+}
+
+impl ListenerSupervisor {
+    /// Create a supervisor that keeps binding again, waiting from 100 ms up to 30 s between
+    /// attempts.
+    pub fn new() -> Self {
+        ListenerSupervisor {
+            backoff: Arc::new(DEFAULT_BACKOFF),
+            cancel: None,
+            state: watch::Sender::new(ListenerState::Starting),
+        }
+    }
+
+    /// Wait between attempts to bind as decided by `backoff`. The attempts are counted from the
+    /// first failure after the endpoint was last bound. Once the backoff gives up, the state
+    /// becomes [`ListenerState::Failed`].
+    pub fn set_backoff(&mut self, backoff: Arc<dyn Backoff>) {
+        self.backoff = backoff;
+    }
+
+    /// Stop accepting, and stop trying to bind, when `cancel` is cancelled. The token is passed
+    /// on to every endpoint with [`Endpoint::set_cancellation_token`].
+    pub fn set_cancellation_token(&mut self, cancel: CancellationToken) {
+        self.cancel = Some(cancel);
+    }
+
+    /// The current state.
+    pub fn state(&self) -> ListenerState {
+        self.state.borrow().clone()
+    }
+
+    /// Watch the state change. Receivers can be created before the supervisor is started.
+    pub fn subscribe(&self) -> watch::Receiver<ListenerState> {
+        self.state.subscribe()
+    }
+
+    /// Bind an endpoint created by `make_endpoint`, and again whenever binding or accepting
+    /// fails, returning a stream of the connections accepted on any of them. The stream ends
+    /// once an endpoint stops accepting, or yields the last error once the backoff gives up.
+    ///
+    /// Errors that [`accept::is_transient`] considers transient only concern a single
+    /// connection, so they never cause the endpoint to be bound again. Endpoints that use
+    /// [`AcceptErrorPolicy::Fail`] retry them after a short pause instead, and the errors that
+    /// are still yielded, e.g. because the accept backoff gave up, are yielded from the stream
+    /// while the listener is kept.
+    ///
+    /// On Unix, the socket of the failed listener is removed before binding again, as when the
+    /// listener is dropped.
+    pub fn incoming<F>(self, make_endpoint: F) -> SupervisedIncoming
+    where
+        F: FnMut() -> Endpoint + Send + 'static,
+    {
+        let supervised = Supervised {
+            make_endpoint,
+            supervisor: self,
+            incoming: None,
+            failures: 0,
+            done: false,
+        };
+        let stream = futures::stream::unfold(supervised, |mut supervised| async move {
+            let connection = supervised.next().await?;
+            Some((connection, supervised))
+        });
+        SupervisedIncoming {
+            stream: Box::pin(stream),
+        }
+    }
+}
+
+impl Default for ListenerSupervisor {
+    fn default() -> Self {
+        ListenerSupervisor::new()
+    }
+}
+
+/// Connections accepted on a supervised endpoint. See [`ListenerSupervisor::incoming`].
+pub struct SupervisedIncoming {
+    stream: Pin<Box<dyn Stream<Item = io::Result<Connection>> + Send>>,
+}
+
+impl Stream for SupervisedIncoming {
+    type Item = io::Result<Connection>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stream.as_mut().poll_next(cx)
+    }
+}
+
+struct Supervised<F> {
+    make_endpoint: F,
+    supervisor: ListenerSupervisor,
+    incoming: Option<Incoming>,
+    /// Number of consecutive failures.
+    failures: u32,
+    done: bool,
+}
+
+impl<F: FnMut() -> Endpoint> Supervised<F> {
+    async fn next(&mut self) -> Option<io::Result<Connection>> {
+        while !self.done {
+            let mut incoming = match self.incoming.take() {
+                Some(incoming) => incoming,
+                None => match self.bind() {
+                    Ok(incoming) => incoming,
+                    Err(error) => match self.failed(error).await {
+                        ControlFlow::Continue(()) => continue,
+                        ControlFlow::Break(error) => return error.map(Err),
+                    },
+                },
+            };
+            match incoming.next().await {
+                Some(Ok(connection)) => {
+                    self.incoming = Some(incoming);
+                    return Some(Ok(connection));
+                }
+                Some(Err(error)) if accept::is_transient(&error) => {
+                    self.incoming = Some(incoming);
+                    return Some(Err(error));
+                }
+                Some(Err(error)) => {
+                    // Drop the listener first, so that its socket is removed
+                    drop(incoming);
+                    if let ControlFlow::Break(error) = self.failed(error).await {
+                        return error.map(Err);
+                    }
+                }
+                None => self.stop(ListenerState::Stopped),
+            }
+        }
+        None
+    }
+
+    fn bind(&mut self) -> io::Result<Incoming> {
+        let mut endpoint = (self.make_endpoint)();
+        if let Some(cancel) = &self.supervisor.cancel {
+            endpoint.set_cancellation_token(cancel.clone());
+        }
+        if endpoint.accept_error_policy == AcceptErrorPolicy::Fail {
+            endpoint.set_accept_error_policy(ACCEPT_ERROR_POLICY);
+        }
+        if self.failures > 0 {
+            log::info!("Binding IPC endpoint {} again", endpoint.path());
+        }
+        let incoming = endpoint.incoming()?;
+        self.failures = 0;
+        self.supervisor.state.send_replace(ListenerState::Listening);
+        Ok(incoming)
+    }
+
+    /// Wait before binding again after `error`. Breaks with the error to yield, if any, once
+    /// the backoff gives up or the supervisor is cancelled.
+    async fn failed(&mut self, error: io::Error) -> ControlFlow<Option<io::Error>> {
+        self.failures = self.failures.saturating_add(1);
+        let Some(delay) = self.supervisor.backoff.delay(self.failures) else {
+            log::error!("Giving up on IPC endpoint: {error}");
+            self.stop(ListenerState::Failed {
+                error: error.to_string(),
+            });
+            return ControlFlow::Break(Some(error));
+        };
+        log::warn!("IPC endpoint failed, binding again in {delay:?}: {error}");
+        self.supervisor.state.send_replace(ListenerState::Degraded {
+            error: error.to_string(),
+            attempt: self.failures,
+        });
+
+        let sleep = tokio::time::sleep(delay);
+        let Some(cancel) = &self.supervisor.cancel else {
+            sleep.await;
+            return ControlFlow::Continue(());
+        };
+        tokio::select! {
+            () = sleep => ControlFlow::Continue(()),
+            () = cancel.cancelled() => {
+                self.stop(ListenerState::Stopped);
+                ControlFlow::Break(None)
+            }
+        }
+    }
+
+    fn stop(&mut self, state: ListenerState) {
+        self.done = true;
+        self.supervisor.state.send_replace(state);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::backoff::ConstantBackoff;
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_rebind() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_dir = dir.path().join("run");
+        let path = socket_dir.join("socket").to_string_lossy().into_owned();
+        let cancel = CancellationToken::new();
+        let mut supervisor = ListenerSupervisor::new();
+        supervisor.set_backoff(Arc::new(ConstantBackoff::new(Duration::from_millis(10))));
+        supervisor.set_cancellation_token(cancel.clone());
+        let mut state = supervisor.subscribe();
+
+        let endpoint_path = path.clone();
+        let mut incoming = supervisor.incoming(move || Endpoint::new(endpoint_path.clone()));
+        let (accepted_tx, mut accepted) = tokio::sync::mpsc::unbounded_channel();
+        let accept = tokio::spawn(async move {
+            while let Some(connection) = incoming.next().await {
+                accepted_tx.send(connection.unwrap()).unwrap();
+            }
+        });
+
+        // The directory of the socket does not exist yet
+        state
+            .wait_for(|state| matches!(state, ListenerState::Degraded { .. }))
+            .await
+            .unwrap();
+        std::fs::create_dir(&socket_dir).unwrap();
+        state
+            .wait_for(|state| *state == ListenerState::Listening)
+            .await
+            .unwrap();
+        let _client = Endpoint::connect(&path).await.unwrap();
+        accepted.recv().await.unwrap();
+
+        cancel.cancel();
+        accept.await.unwrap();
+        assert_eq!(*state.borrow(), ListenerState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn test_give_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("socket");
+        let path = path.to_string_lossy().into_owned();
+        let mut backoff = ConstantBackoff::new(Duration::from_millis(1));
+        backoff.set_max_attempts(2);
+        let mut supervisor = ListenerSupervisor::new();
+        supervisor.set_backoff(Arc::new(backoff));
+        let state = supervisor.subscribe();
+
+        let mut incoming = supervisor.incoming(move || Endpoint::new(path.clone()));
+        let error = incoming.next().await.unwrap().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(matches!(*state.borrow(), ListenerState::Failed { .. }));
+        assert!(incoming.next().await.is_none());
+    }
+}
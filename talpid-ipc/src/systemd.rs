@@ -0,0 +1,204 @@
+//! Socket activation by systemd, and readiness notification. See `sd_listen_fds(3)` and
+//! `sd_notify(3)`.
+
+use crate::Endpoint;
+use nix::sys::socket::{SockType, getsockopt, sockopt};
+use std::{
+    env, io,
+    os::{
+        fd::{BorrowedFd, FromRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram, UnixListener},
+    },
+    path::Path,
+    process,
+};
+
+/// The first file descriptor passed by systemd.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+impl Endpoint {
+    /// Use a listening socket passed to the process by systemd socket activation. The first
+    /// Unix stream socket in `LISTEN_FDS` is used. Returns `None` if the process was not
+    /// socket-activated.
+    ///
+    /// The permissions of the socket are managed by the socket unit, so security attributes are
+    /// not applied to it, and the socket file is not removed when the returned endpoint stops
+    /// listening.
+    ///
+    /// Like `sd_listen_fds(3)` with `unset_environment`, this removes `LISTEN_PID`, `LISTEN_FDS`
+    /// and `LISTEN_FDNAMES` from the environment, so that child processes do not take the
+    /// descriptors to be theirs. It should therefore be called before other threads are spawned.
+    pub fn from_systemd() -> io::Result<Option<Endpoint>> {
+        let pid = env::var("LISTEN_PID").ok();
+        let fds = env::var("LISTEN_FDS").ok();
+        let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            // SAFETY: The caller is told to call this before other threads are spawned, which
+            // could otherwise read the environment while it is being changed
+            unsafe { env::remove_var(name) };
+        }
+        let Some(fds) = listen_fds(pid.as_deref(), fds.as_deref(), process::id())? else {
+            return Ok(None);
+        };
+        let mut names = names.split(':');
+
+        for fd in fds {
+            let name = names.next().unwrap_or("unknown");
+            // SAFETY: systemd passes `LISTEN_FDS` open descriptors that stay open until claimed
+            let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+            if !is_listening_stream_socket(borrowed) {
+                log::debug!("Ignoring socket-activated descriptor {fd} ({name})");
+                continue;
+            }
+
+            // SAFETY: See above. This is the only place where the descriptor is claimed.
+            let listener = unsafe { UnixListener::from_raw_fd(fd) };
+            let address = match listener.local_addr() {
+                Ok(address) => address,
+                Err(error) => {
+                    // Not a Unix socket. Give the descriptor back without closing it.
+                    log::debug!("Ignoring socket-activated descriptor {fd} ({name}): {error}");
+                    std::mem::forget(listener);
+                    continue;
+                }
+            };
+            crate::imp::set_cloexec(fd)?;
+
+            let path = address
+                .as_pathname()
+                .map(|path| path.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            log::debug!("Using socket-activated IPC endpoint {path} ({name})");
+
+            let mut endpoint = Endpoint::new(path);
+            endpoint.prebound = Some(listener);
+            return Ok(Some(endpoint));
+        }
+
+        Ok(None)
+    }
+}
+
+/// Tell systemd that the service is ready, if it is waiting to be told, i.e. `Type=notify`.
+pub(crate) fn notify_ready(path: &str) {
+    match notify(&format!("READY=1\nSTATUS=IPC listening on {path}")) {
+        Ok(true) => log::debug!("Notified systemd that the service is ready"),
+        Ok(false) => (),
+        Err(error) => log::warn!("Failed to notify systemd that the service is ready: {error}"),
+    }
+}
+
+/// Send `state` to the notification socket of the service manager. Returns `false` if there
+/// is none, e.g. because the process was not started by systemd.
+fn notify(state: &str) -> io::Result<bool> {
+    let Some(socket_path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let address = match socket_path.as_encoded_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None if Path::new(&socket_path).is_absolute() => SocketAddr::from_pathname(&socket_path)?,
+        None => return Err(invalid_env("NOTIFY_SOCKET")),
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &address)?;
+    Ok(true)
+}
+
+/// Return the file descriptors passed by systemd, given the values of `LISTEN_PID` and
+/// `LISTEN_FDS`, if they are intended for the process `own_pid`.
+fn listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    own_pid: u32,
+) -> io::Result<Option<std::ops::Range<RawFd>>> {
+    let Some(pid) = pid else {
+        return Ok(None);
+    };
+    let pid: u32 = pid.parse().map_err(|_| invalid_env("LISTEN_PID"))?;
+    if pid != own_pid {
+        return Ok(None);
+    }
+
+    let count: RawFd = fds
+        .ok_or_else(|| invalid_env("LISTEN_FDS"))?
+        .parse()
+        .map_err(|_| invalid_env("LISTEN_FDS"))?;
+    if count < 0 {
+        return Err(invalid_env("LISTEN_FDS"));
+    }
+
+    Ok(Some(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count))
+}
+
+fn is_listening_stream_socket(fd: BorrowedFd<'_>) -> bool {
+    matches!(getsockopt(&fd, sockopt::SockType), Ok(SockType::Stream))
+        && matches!(getsockopt(&fd, sockopt::AcceptConn), Ok(true))
+}
+
+fn invalid_env(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid value of {name}"),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_notify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        // SAFETY: No other test reads or writes `NOTIFY_SOCKET`
+        unsafe { env::set_var("NOTIFY_SOCKET", &path) };
+        notify_ready("/run/test.sock");
+        // SAFETY: See above
+        unsafe { env::remove_var("NOTIFY_SOCKET") };
+
+        let mut buf = [0u8; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            b"READY=1\nSTATUS=IPC listening on /run/test.sock"
+        );
+        assert!(!notify("READY=1").unwrap());
+    }
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42).unwrap(), Some(3..5));
+        assert_eq!(listen_fds(Some("42"), Some("0"), 42).unwrap(), Some(3..3));
+        // Not socket-activated, or the descriptors are meant for another process
+        assert_eq!(listen_fds(None, Some("2"), 42).unwrap(), None);
+        assert_eq!(listen_fds(Some("43"), Some("2"), 42).unwrap(), None);
+        assert_eq!(listen_fds(Some("43"), None, 42).unwrap(), None);
+
+        for (pid, fds) in [
+            ("pid", Some("2")),
+            ("-42", Some("2")),
+            ("42", None),
+            ("42", Some("two")),
+            ("42", Some("-1")),
+        ] {
+            let error = listen_fds(Some(pid), fds, 42).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_not_activated() {
+        // SAFETY: No other test reads or writes `LISTEN_*`
+        unsafe {
+            env::set_var("LISTEN_PID", "0");
+            env::set_var("LISTEN_FDS", "1");
+        }
+        assert!(Endpoint::from_systemd().unwrap().is_none());
+        // The variables are removed even if they were meant for another process
+        assert!(env::var_os("LISTEN_PID").is_none());
+        assert!(env::var_os("LISTEN_FDS").is_none());
+    }
+}
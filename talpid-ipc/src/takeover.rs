@@ -0,0 +1,488 @@
+//! Binding to a path where a server that has died left its socket behind.
+//!
+//! A Unix socket file outlives the process that bound it if the process crashes, and binding to
+//! the path then fails with `EADDRINUSE`. [`crate::Endpoint::bind_or_takeover`] removes such a
+//! socket, but only once it is clear that its server is gone: nothing may be listening on it,
+//! and the process recorded in the PID file next to it must no longer be running. A process
+//! that started at another time, or runs another executable, than the one that was recorded
+//! only got the same PID, and does not count.
+//!
+//! The PID file is locked for as long as the socket is listened on, so that servers that start
+//! at the same time cannot both take over the path and remove each other's sockets, and it is
+//! removed along with the socket when the server stops. Sockets are never taken over without
+//! the lock. A stale socket is replaced by renaming the new socket over it, so that other
+//! servers never find the path free in between. Takeovers are reported as
+//! [`BindOutcome::TookOver`], for the daemon to log and report that it recovered from an unclean
+//! shutdown.
+
+use crate::{
+    SecurityAttributes,
+    context::{Operation, ResultExt},
+    imp,
+};
+use std::{
+    fmt, fs,
+    io::{self, Read, Seek, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{
+            fs::{MetadataExt, OpenOptionsExt},
+            net::UnixStream,
+        },
+    },
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
+};
+
+/// How long to wait for the lock of the PID file while another server holds it without
+/// listening, e.g. because it is shutting down or still binding.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time between attempts to lock the PID file.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How the socket of an endpoint came to be listened on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindOutcome {
+    /// The socket was bound without any conflict.
+    Bound,
+    /// A stale socket was removed and the path was bound again.
+    TookOver(UncleanShutdown),
+    /// The socket was inherited through socket activation, so nothing was bound.
+    Inherited,
+}
+
+/// A server that did not shut down cleanly, and whose socket was taken over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UncleanShutdown {
+    /// PID of the server, if it was recorded.
+    pub previous_pid: Option<u32>,
+    /// Executable of the server, if it was recorded.
+    pub previous_executable: Option<PathBuf>,
+    pub reason: StaleReason,
+}
+
+/// Why the server of a socket is considered to be gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaleReason {
+    /// Nothing is listening on the socket, and no PID was recorded.
+    NotRecorded,
+    /// The recorded process is not running anymore.
+    NotRunning,
+    /// The recorded PID belongs to a process that started at another time, or runs another
+    /// executable.
+    PidReused,
+}
+
+impl fmt::Display for UncleanShutdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(pid) = self.previous_pid else {
+            return f.write_str("Recovered from an unclean shutdown of an unknown process");
+        };
+        write!(f, "Recovered from an unclean shutdown of process {pid}")?;
+        match self.reason {
+            StaleReason::NotRecorded => Ok(()),
+            StaleReason::NotRunning => f.write_str(", which is no longer running"),
+            StaleReason::PidReused => f.write_str(", whose PID now belongs to another process"),
+        }
+    }
+}
+
+/// Bind to `path`, taking it over if it is occupied by the socket of a server that has died.
+pub(crate) fn bind_or_takeover(
+    path: String,
+    security_attributes: SecurityAttributes,
+    options: &imp::ListenOptions,
+) -> io::Result<(imp::Incoming, BindOutcome)> {
+    // Held for as long as the socket is listened on
+    let mut pid_file = match PidFile::lock(&path) {
+        Ok(pid_file) => Some(pid_file),
+        Err(error) if error.kind() == io::ErrorKind::AddrInUse => return Err(error),
+        Err(error) => {
+            log::warn!("Failed to lock {}: {error}", pid_file_path(&path));
+            None
+        }
+    };
+    let error = match imp::Incoming::bind(path.clone(), security_attributes.clone(), options) {
+        Ok(incoming) => return Ok((record(incoming, pid_file), BindOutcome::Bound)),
+        Err(error) if error.kind() == io::ErrorKind::AddrInUse => error,
+        Err(error) => return Err(error),
+    };
+    // Without the lock, another server may be taking over the path at the same time
+    let Some(locked) = &mut pid_file else {
+        return Err(error);
+    };
+
+    // Identify the socket before probing it, so that only the socket that was found to be stale
+    // is replaced
+    let stale = imp::FileIdentity::of(&path).context(Operation::Bind, &path)?;
+    match UnixStream::connect(&path) {
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("A server is already listening on {path}"),
+            ));
+        }
+        Err(probe_error) if probe_error.kind() == io::ErrorKind::ConnectionRefused => (),
+        Err(_) => return Err(error),
+    }
+    let recorded = locked.read();
+    let reason = match &recorded {
+        Some(recorded) => match why_gone(recorded) {
+            Some(reason) => reason,
+            // The server may still be starting up, or be about to remove the socket itself
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!(
+                        "{path} belongs to process {}, which is still running",
+                        recorded.pid
+                    ),
+                ));
+            }
+        },
+        None => StaleReason::NotRecorded,
+    };
+    let shutdown = UncleanShutdown {
+        previous_pid: recorded.as_ref().map(|recorded| recorded.pid),
+        previous_executable: recorded.and_then(|recorded| recorded.executable),
+        reason,
+    };
+
+    log::info!("Replacing stale IPC socket {path}: {shutdown}");
+    let incoming = imp::Incoming::bind_replacing(path, stale, security_attributes, options)?;
+    Ok((record(incoming, pid_file), BindOutcome::TookOver(shutdown)))
+}
+
+/// Record this process in `pid_file`, and keep it locked for as long as `incoming` is listened
+/// on.
+fn record(mut incoming: imp::Incoming, pid_file: Option<PidFile>) -> imp::Incoming {
+    if let Some(mut pid_file) = pid_file {
+        pid_file.write();
+        incoming.set_pid_file(pid_file);
+    }
+    incoming
+}
+
+fn in_use(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("Another server is using {path}"),
+    )
+}
+
+fn pid_file_path(path: &str) -> String {
+    format!("{path}.pid")
+}
+
+/// The PID file next to a socket, locked by this process. If this process has been recorded in
+/// it, it is removed when dropped, which happens after the socket has been removed.
+pub(crate) struct PidFile {
+    file: fs::File,
+    path: String,
+    recorded: bool,
+}
+
+impl PidFile {
+    /// Open the PID file next to the socket at `path`, and lock it. Fails with
+    /// [`io::ErrorKind::AddrInUse`] if another server holds the lock while listening on the
+    /// socket, or for longer than [`LOCK_TIMEOUT`]. A symlink in place of the PID file is not
+    /// followed, since whoever put it there could then have another file locked or written.
+    fn lock(path: &str) -> io::Result<Self> {
+        let pid_path = pid_file_path(path);
+        let deadline = Instant::now() + LOCK_TIMEOUT;
+        loop {
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .mode(0o600)
+                .custom_flags(libc::O_NOFOLLOW | libc::O_CLOEXEC)
+                .open(&pid_path)?;
+            // SAFETY: The descriptor is open for as long as `file` is
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::WouldBlock {
+                    return Err(error);
+                }
+                if UnixStream::connect(path).is_ok() {
+                    return Err(in_use(path));
+                }
+            } else {
+                // The server that held the lock may have removed the file before releasing it,
+                // and a lock on a removed file protects nothing
+                let locked = file.metadata()?;
+                match fs::symlink_metadata(&pid_path) {
+                    Ok(current)
+                        if (current.dev(), current.ino()) == (locked.dev(), locked.ino()) =>
+                    {
+                        return Ok(PidFile {
+                            file,
+                            path: pid_path,
+                            recorded: false,
+                        });
+                    }
+                    Ok(_) => (),
+                    Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+                    Err(error) => return Err(error),
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(in_use(path));
+            }
+            thread::sleep(LOCK_RETRY_INTERVAL);
+        }
+    }
+
+    /// Record the PID, executable and start time of this process. Failing to do so only means
+    /// that a later takeover cannot check whether this process is still running.
+    fn write(&mut self) {
+        let executable = std::env::current_exe()
+            .map(|executable| executable.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let start_time = start_time_of(process::id())
+            .map(|start_time| start_time.to_string())
+            .unwrap_or_default();
+        let file = &mut self.file;
+        let result = file
+            .set_len(0)
+            .and_then(|()| file.rewind())
+            .and_then(|()| writeln!(file, "{}\n{executable}\n{start_time}", process::id()));
+        match result {
+            Ok(()) => self.recorded = true,
+            Err(error) => log::warn!("Failed to write {}: {error}", self.path),
+        }
+    }
+
+    fn read(&mut self) -> Option<Recorded> {
+        read_pid_file(&mut self.file)
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // The file is removed before closing it releases the lock, so that no other server
+        // locks it in between
+        if self.recorded
+            && let Err(error) = fs::remove_file(&self.path)
+            && error.kind() != io::ErrorKind::NotFound
+        {
+            log::warn!("Failed to remove {}: {error}", self.path);
+        }
+    }
+}
+
+/// What a server recorded about itself in its PID file.
+#[derive(Debug)]
+struct Recorded {
+    pid: u32,
+    executable: Option<PathBuf>,
+    /// See [`start_time_of`].
+    start_time: Option<u64>,
+}
+
+/// Return what was recorded in the PID file. Older versions only recorded the PID, or the PID
+/// and the executable.
+fn read_pid_file(pid_file: &mut fs::File) -> Option<Recorded> {
+    let mut contents = String::new();
+    pid_file.read_to_string(&mut contents).ok()?;
+    let mut lines = contents.lines();
+    let pid = lines.next()?.trim().parse().ok()?;
+    let executable = lines
+        .next()
+        .filter(|executable| !executable.is_empty())
+        .map(PathBuf::from);
+    let start_time = lines
+        .next()
+        .and_then(|start_time| start_time.trim().parse().ok());
+    Some(Recorded {
+        pid,
+        executable,
+        start_time,
+    })
+}
+
+/// Return why the process with the recorded PID is not the server that recorded itself, or
+/// `None` if it may be.
+fn why_gone(recorded: &Recorded) -> Option<StaleReason> {
+    let pid = recorded.pid;
+    if !is_running(pid) {
+        return Some(StaleReason::NotRunning);
+    }
+    // A process that started at another time only got the same PID
+    if let (Some(recorded), Some(running)) = (recorded.start_time, start_time_of(pid))
+        && recorded != running
+    {
+        return Some(StaleReason::PidReused);
+    }
+    // Without knowing both executables, assume that it is the same server
+    let (Some(recorded), Some(running)) = (&recorded.executable, imp::process_exe(pid)) else {
+        return None;
+    };
+    (recorded.to_string_lossy() != running.to_string_lossy()).then_some(StaleReason::PidReused)
+}
+
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: Signal 0 only checks whether the process exists
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // The process exists, but belongs to someone else
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Return when process `pid` started, if it can be found out. The unit differs between
+/// platforms, so this is only compared with start times that were found out on the same one.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn start_time_of(pid: u32) -> Option<u64> {
+    let pid = libc::pid_t::try_from(pid).ok()?;
+    crate::pidfd::PidFd::open(pid)
+        .and_then(|pidfd| pidfd.start_time())
+        .ok()
+}
+
+/// Return when process `pid` started, if it can be found out. The unit differs between
+/// platforms, so this is only compared with start times that were found out on the same one.
+#[cfg(target_os = "macos")]
+fn start_time_of(pid: u32) -> Option<u64> {
+    let pid = libc::c_int::try_from(pid).ok()?;
+    // SAFETY: All-zero is a valid value for this plain struct
+    let mut info: libc::proc_bsdinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+    // SAFETY: `info` is valid for writes of `size` bytes
+    let len = unsafe {
+        libc::proc_pidinfo(
+            pid,
+            libc::PROC_PIDTBSDINFO,
+            0,
+            (&mut info as *mut libc::proc_bsdinfo).cast(),
+            size,
+        )
+    };
+    (len == size).then(|| info.pbi_start_tvsec * 1_000_000 + info.pbi_start_tvusec)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn start_time_of(_pid: u32) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Leave a socket behind, as if its server had crashed, with `pid_file` as its PID file.
+    fn leave_socket(path: &str, pid_file: &str) {
+        let _ = fs::remove_file(path);
+        drop(std::os::unix::net::UnixListener::bind(path).unwrap());
+        fs::write(pid_file_path(path), pid_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_takeover() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let options = imp::ListenOptions::default();
+
+        let (live, outcome) =
+            bind_or_takeover(path.clone(), SecurityAttributes::empty(), &options).unwrap();
+        assert_eq!(outcome, BindOutcome::Bound);
+        // The server is still listening, and this process is still running
+        let error = bind_or_takeover(path.clone(), SecurityAttributes::empty(), &options)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+
+        // Stopping cleanly leaves nothing behind
+        drop(live);
+        assert!(!Path::new(&path).exists());
+        assert!(!Path::new(&pid_file_path(&path)).exists());
+
+        leave_socket(&path, &format!("{}\n", u32::MAX));
+        let (_incoming, outcome) =
+            bind_or_takeover(path.clone(), SecurityAttributes::empty(), &options).unwrap();
+        assert_eq!(
+            outcome,
+            BindOutcome::TookOver(UncleanShutdown {
+                previous_pid: Some(u32::MAX),
+                previous_executable: None,
+                reason: StaleReason::NotRunning,
+            })
+        );
+        let recorded = fs::read_to_string(pid_file_path(&path)).unwrap();
+        assert_eq!(recorded.lines().next(), Some(&*process::id().to_string()));
+        // The stale socket was replaced in place
+        assert!(!Path::new(&format!("{path}.{}", process::id())).exists());
+    }
+
+    #[test]
+    fn test_pid_file_symlink() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let target = format!("{path}.target");
+        std::os::unix::fs::symlink(&target, pid_file_path(&path)).unwrap();
+
+        assert!(PidFile::lock(&path).is_err());
+        assert!(!Path::new(&target).exists());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+    #[tokio::test]
+    async fn test_reused_pid() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let options = imp::ListenOptions::default();
+        let executable = std::env::current_exe().unwrap();
+
+        // This process is the one that was recorded
+        leave_socket(
+            &path,
+            &format!("{}\n{}\n", process::id(), executable.display()),
+        );
+        let error = bind_or_takeover(path.clone(), SecurityAttributes::empty(), &options)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+
+        // This process only got the PID of the one that was recorded
+        leave_socket(&path, &format!("{}\n/usr/bin/previous\n", process::id()));
+        let (incoming, outcome) =
+            bind_or_takeover(path.clone(), SecurityAttributes::empty(), &options).unwrap();
+        assert_eq!(
+            outcome,
+            BindOutcome::TookOver(UncleanShutdown {
+                previous_pid: Some(process::id()),
+                previous_executable: Some(PathBuf::from("/usr/bin/previous")),
+                reason: StaleReason::PidReused,
+            })
+        );
+
+        // This process runs the same executable, but started at another time
+        drop(incoming);
+        let start_time = start_time_of(process::id()).unwrap();
+        leave_socket(
+            &path,
+            &format!(
+                "{}\n{}\n{}\n",
+                process::id(),
+                executable.display(),
+                start_time + 1
+            ),
+        );
+        let (_incoming, outcome) =
+            bind_or_takeover(path.clone(), SecurityAttributes::empty(), &options).unwrap();
+        assert_eq!(
+            outcome,
+            BindOutcome::TookOver(UncleanShutdown {
+                previous_pid: Some(process::id()),
+                previous_executable: Some(executable),
+                reason: StaleReason::PidReused,
+            })
+        );
+    }
+}
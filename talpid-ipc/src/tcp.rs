@@ -0,0 +1,571 @@
+//! Loopback TCP connections, next to the native socket or pipe.
+//!
+//! Clients in containers or virtual machines cannot always open the socket or pipe of the
+//! daemon, but can often reach a port on the host. [`Endpoint::incoming_with_tcp`] listens on
+//! the endpoint and on a TCP port at the same time, and yields the connections of both as
+//! [`AnyConnection`]s. Native clients keep using the endpoint.
+//!
+//! TCP ports are not protected by file permissions, so every TCP client must first send an
+//! [`AuthToken`] that has been shared with it out of band, e.g. by writing it to a file that
+//! only the intended user can read. Connections that do not present the token in time are
+//! closed, and never yielded. Nothing is known about the user of a TCP client, so the token is
+//! the only thing that authorizes it.
+//!
+//! TCP connections count against the limits of the endpoint that they are accepted next to: they
+//! need a permit from [`Endpoint::set_accept_permits`], and all TCP clients together count as a
+//! single user against [`Endpoint::set_connection_quota`]. Since they cannot be checked against
+//! an allowlist of users, [`Endpoint::incoming_with_tcp`] fails if the endpoint has one.
+//!
+//! The daemon should generate a new token whenever it starts, and write it with
+//! [`AuthToken::write_to`] to a file that only root may read, preferably on a file system that
+//! is cleared at boot, such as `/run`. The listener only binds to loopback addresses, so the
+//! port cannot be reached from other hosts.
+
+use crate::{Connection, PeerAddress};
+#[cfg(feature = "server")]
+use crate::{Endpoint, Incoming};
+use futures::{Stream, StreamExt, stream::FuturesUnordered};
+use rand::RngCore;
+use std::{
+    fmt, fs,
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tokio_util::sync::PollSemaphore;
+
+/// Length of an [`AuthToken`] in bytes.
+pub const TOKEN_LEN: usize = 32;
+
+/// Time a client has to present its token.
+pub(crate) const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most clients that may be presenting their tokens at the same time. Further connections wait
+/// in the backlog of the listener, so that clients that never present a token cannot make the
+/// server hold on to any number of connections.
+const MAX_AUTHENTICATING: usize = 64;
+
+/// Most connections that a bridge relays at the same time. Further connections wait in the
+/// backlog of the listener until a relayed connection ends.
+#[cfg(any(feature = "wsl-bridge", feature = "websocket-bridge"))]
+pub(crate) const MAX_RELAYED: usize = 64;
+
+/// Secret that TCP clients must present.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthToken([u8; TOKEN_LEN]);
+
+impl AuthToken {
+    /// Generate a random token.
+    pub fn generate() -> Self {
+        let mut token = [0u8; TOKEN_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut token);
+        AuthToken(token)
+    }
+
+    /// Parse a token encoded by [`Self::to_hex`].
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.trim().as_bytes();
+        if hex.len() != TOKEN_LEN * 2 {
+            return None;
+        }
+        let mut token = [0u8; TOKEN_LEN];
+        for (byte, digits) in token.iter_mut().zip(hex.chunks_exact(2)) {
+            let digits = std::str::from_utf8(digits).ok()?;
+            *byte = u8::from_str_radix(digits, 16).ok()?;
+        }
+        Some(AuthToken(token))
+    }
+
+    /// Encode the token as a hex string, suitable for storing in a file.
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// Read a token written by [`Self::write_to`].
+    pub fn read_from(path: impl AsRef<Path>) -> io::Result<Self> {
+        let hex = fs::read_to_string(path)?;
+        AuthToken::from_hex(&hex)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid token file"))
+    }
+
+    /// Write the token to `path`, replacing any previous token. On Unix, only the owner of the
+    /// file, i.e. the user that runs this process, may read it. On Windows, the file inherits
+    /// the permissions of its directory, which must not be readable by other users.
+    pub fn write_to(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        // Write the new token next to the old one, so that clients never read half of it
+        let result = options
+            .open(&temp_path)
+            .and_then(|mut file| io::Write::write_all(&mut file, self.to_hex().as_bytes()))
+            .and_then(|()| fs::rename(&temp_path, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    /// Compare in constant time, so that the token cannot be guessed byte by byte.
+    pub(crate) fn matches(&self, other: &AuthToken) -> bool {
+        self.0
+            .iter()
+            .zip(&other.0)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthToken(..)")
+    }
+}
+
+/// Wait for the client on `stream` to present `token`.
+pub(crate) async fn authenticate(stream: &mut TcpStream, token: &AuthToken) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+
+    let mut presented = [0u8; TOKEN_LEN];
+    tokio::time::timeout(AUTH_TIMEOUT, stream.read_exact(&mut presented))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No token was presented"))??;
+    if !token.matches(&AuthToken(presented)) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Invalid token",
+        ));
+    }
+    Ok(())
+}
+
+/// Connect to a TCP listener at `address` and present `token`.
+pub async fn connect(address: SocketAddr, token: &AuthToken) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(address).await?;
+    stream.set_nodelay(true)?;
+    stream.write_all(&token.0).await?;
+    Ok(stream)
+}
+
+/// A connection whose client is presenting its token, and the accept permit that it holds.
+type Authenticating = Pin<
+    Box<
+        dyn Future<
+                Output = (
+                    SocketAddr,
+                    io::Result<TcpStream>,
+                    Option<OwnedSemaphorePermit>,
+                ),
+            > + Send,
+    >,
+>;
+
+/// Stream of TCP connections whose clients have presented the token.
+pub struct TcpIncoming {
+    listener: TcpListener,
+    token: AuthToken,
+    /// Connections whose clients are yet to present the token.
+    authenticating: FuturesUnordered<Authenticating>,
+    /// Shared with the endpoint, see [`Endpoint::set_accept_permits`].
+    permits: Option<PollSemaphore>,
+    /// Permit acquired for the next connection to be accepted.
+    permit: Option<OwnedSemaphorePermit>,
+    /// Connections that TCP clients may have open together.
+    quota: Option<Arc<Semaphore>>,
+}
+
+impl TcpIncoming {
+    /// Listen on `address`, which must be a loopback address such as `127.0.0.1` or `::1`.
+    /// Fails with [`io::ErrorKind::InvalidInput`] for any other address.
+    pub async fn bind(address: SocketAddr, token: AuthToken) -> io::Result<Self> {
+        if !address.ip().is_loopback() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{address} is not a loopback address"),
+            ));
+        }
+        Ok(TcpIncoming {
+            listener: TcpListener::bind(address).await?,
+            token,
+            authenticating: FuturesUnordered::new(),
+            permits: None,
+            permit: None,
+            quota: None,
+        })
+    }
+
+    /// Return the address that the listener listens on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl Stream for TcpIncoming {
+    type Item = io::Result<TcpConnection>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        // Accept until there is nothing more to accept, so that slow clients cannot hold up
+        // the others while authenticating
+        while this.authenticating.len() < MAX_AUTHENTICATING {
+            if let (Some(permits), None) = (&mut this.permits, &this.permit) {
+                match permits.poll_acquire(cx) {
+                    Poll::Ready(Some(permit)) => this.permit = Some(permit),
+                    Poll::Ready(None) => return Poll::Ready(None),
+                    Poll::Pending => break,
+                }
+            }
+            match this.listener.poll_accept(cx) {
+                Poll::Ready(Ok((mut stream, peer))) => {
+                    let token = this.token.clone();
+                    let permit = this.permit.take();
+                    this.authenticating.push(Box::pin(async move {
+                        let result = authenticate(&mut stream, &token).await;
+                        (peer, result.map(|()| stream), permit)
+                    }));
+                }
+                Poll::Ready(Err(error)) => return Poll::Ready(Some(Err(error))),
+                Poll::Pending => break,
+            }
+        }
+        while let Poll::Ready(Some((peer, result, permit))) =
+            this.authenticating.poll_next_unpin(cx)
+        {
+            let stream = match result {
+                Ok(stream) => stream,
+                Err(error) => {
+                    log::debug!("Rejected TCP connection from {peer}: {error}");
+                    continue;
+                }
+            };
+            let quota = match &this.quota {
+                Some(quota) => match quota.clone().try_acquire_owned() {
+                    Ok(quota) => Some(quota),
+                    Err(_) => {
+                        log::debug!("Rejected TCP connection from {peer}: Quota exceeded");
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            return Poll::Ready(Some(Ok(TcpConnection {
+                stream,
+                _permit: permit,
+                _quota: quota,
+            })));
+        }
+        Poll::Pending
+    }
+}
+
+/// A TCP connection whose client has presented the token. It counts against the limits of the
+/// endpoint that it was accepted next to until it is dropped.
+pub struct TcpConnection {
+    stream: TcpStream,
+    _permit: Option<OwnedSemaphorePermit>,
+    _quota: Option<OwnedSemaphorePermit>,
+}
+
+impl TcpConnection {
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+}
+
+impl AsyncRead for TcpConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TcpConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// A connection accepted either on the native endpoint or over TCP.
+pub enum AnyConnection {
+    Native(Connection),
+    Tcp(TcpConnection),
+}
+
+impl AnyConnection {
+    /// Socket path or pipe name of the endpoint of a native connection, see
+    /// [`Connection::endpoint_path`]. `None` for TCP connections.
+    pub fn endpoint_path(&self) -> Option<&str> {
+        match self {
+            AnyConnection::Native(connection) => connection.endpoint_path(),
+            AnyConnection::Tcp(_) => None,
+        }
+    }
+
+    /// Address of the peer, see [`Connection::peer_address`].
+    pub fn peer_address(&self) -> io::Result<PeerAddress> {
+        match self {
+            AnyConnection::Native(connection) => connection.peer_address(),
+            AnyConnection::Tcp(connection) => {
+                connection.get_ref().peer_addr().map(PeerAddress::Tcp)
+            }
+        }
+    }
+}
+
+impl AsyncRead for AnyConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AnyConnection::Native(connection) => Pin::new(connection).poll_read(cx, buf),
+            AnyConnection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AnyConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AnyConnection::Native(connection) => Pin::new(connection).poll_write(cx, buf),
+            AnyConnection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AnyConnection::Native(connection) => Pin::new(connection).poll_flush(cx),
+            AnyConnection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AnyConnection::Native(connection) => Pin::new(connection).poll_shutdown(cx),
+            AnyConnection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Stream of connections accepted on an endpoint and on a TCP port. See
+/// [`Endpoint::incoming_with_tcp`].
+#[cfg(feature = "server")]
+pub struct DualIncoming {
+    /// `None` once the endpoint has stopped accepting, along with the TCP listener.
+    native: Option<(Incoming, TcpIncoming)>,
+    /// Address of the TCP listener.
+    tcp_addr: SocketAddr,
+}
+
+#[cfg(feature = "server")]
+impl DualIncoming {
+    /// Return the address that the TCP listener listens on, or listened on until the endpoint
+    /// stopped accepting.
+    pub fn tcp_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.tcp_addr)
+    }
+}
+
+#[cfg(feature = "server")]
+impl Stream for DualIncoming {
+    type Item = io::Result<AnyConnection>;
+
+    /// The stream ends once the endpoint stops accepting, e.g. because it is shutting down or
+    /// was cancelled, which also closes the TCP listener and the connections whose clients are
+    /// still presenting their tokens.
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let Some((native, tcp)) = &mut this.native else {
+            return Poll::Ready(None);
+        };
+        match native.poll_next_unpin(cx) {
+            Poll::Ready(Some(result)) => {
+                return Poll::Ready(Some(result.map(AnyConnection::Native)));
+            }
+            Poll::Ready(None) => {
+                this.native = None;
+                return Poll::Ready(None);
+            }
+            Poll::Pending => (),
+        }
+        tcp.poll_next_unpin(cx)
+            .map(|result| result.map(|result| result.map(AnyConnection::Tcp)))
+    }
+}
+
+#[cfg(feature = "server")]
+impl Endpoint {
+    /// Like [`Self::incoming`], but also accept TCP connections on the loopback address
+    /// `address` from clients that present `token`. See [`crate::tcp`]. Fails with
+    /// [`io::ErrorKind::InvalidInput`] if only some users may connect, since nothing is known
+    /// about the users of TCP clients.
+    pub async fn incoming_with_tcp(
+        self,
+        address: SocketAddr,
+        token: AuthToken,
+    ) -> io::Result<DualIncoming> {
+        #[cfg(unix)]
+        let restricted = self.allowlist.is_some();
+        #[cfg(windows)]
+        let restricted = self.sid_allowlist.is_some();
+        if restricted {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TCP clients cannot be checked against the allowlist of the endpoint",
+            ));
+        }
+        let mut tcp = TcpIncoming::bind(address, token).await?;
+        tcp.permits = self.permits.clone().map(PollSemaphore::new);
+        tcp.quota = self.quota.map(|quota| Arc::new(Semaphore::new(quota)));
+        let tcp_addr = tcp.local_addr()?;
+        let native = self.incoming()?;
+        Ok(DualIncoming {
+            native: Some((native, tcp)),
+            tcp_addr,
+        })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_token_hex() {
+        let token = AuthToken::generate();
+        assert_eq!(AuthToken::from_hex(&token.to_hex()), Some(token));
+        assert_eq!(AuthToken::from_hex("00"), None);
+    }
+
+    #[test]
+    fn test_token_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        AuthToken::generate().write_to(&path).unwrap();
+        let token = AuthToken::generate();
+        token.write_to(&path).unwrap();
+        assert_eq!(AuthToken::read_from(&path).unwrap(), token);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn test_only_loopback() {
+        let address = (std::net::Ipv4Addr::UNSPECIFIED, 0).into();
+        let error = TcpIncoming::bind(address, AuthToken::generate())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_dual_incoming() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let token = AuthToken::generate();
+        let mut incoming = Endpoint::new(path.clone())
+            .incoming_with_tcp((Ipv4Addr::LOCALHOST, 0).into(), token.clone())
+            .await
+            .unwrap();
+        let address = incoming.tcp_addr().unwrap();
+
+        // A client with the wrong token is disconnected without being yielded
+        let mut rejected = connect(address, &AuthToken::generate()).await.unwrap();
+        let mut buf = [0u8; 1];
+        tokio::select! {
+            _ = incoming.next() => panic!("Rejected client was yielded"),
+            read = rejected.read(&mut buf) => assert_eq!(read.unwrap(), 0),
+        }
+
+        let mut client = connect(address, &token).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut server = incoming.next().await.unwrap().unwrap();
+        assert!(matches!(server, AnyConnection::Tcp(_)));
+        let mut received = [0u8; 5];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+
+        let _client = Endpoint::connect(&path).await.unwrap();
+        let server = incoming.next().await.unwrap().unwrap();
+        assert!(matches!(server, AnyConnection::Native(_)));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_tcp_limits() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let token = AuthToken::generate();
+        let tcp_address = (Ipv4Addr::LOCALHOST, 0).into();
+
+        let mut endpoint = Endpoint::new(path.clone());
+        endpoint.set_peer_allowlist(crate::credentials::PeerAllowlist::new().allow_uid(0));
+        let error = endpoint
+            .incoming_with_tcp(tcp_address, token.clone())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        let mut endpoint = Endpoint::new(path);
+        endpoint.set_connection_quota(1);
+        let mut incoming = endpoint
+            .incoming_with_tcp(tcp_address, token.clone())
+            .await
+            .unwrap();
+        let address = incoming.tcp_addr().unwrap();
+        let _client = connect(address, &token).await.unwrap();
+        let _server = incoming.next().await.unwrap().unwrap();
+
+        // All TCP clients count as the same user
+        let mut rejected = connect(address, &token).await.unwrap();
+        let mut buf = [0u8; 1];
+        tokio::select! {
+            _ = incoming.next() => panic!("Client over the quota was yielded"),
+            read = rejected.read(&mut buf) => assert_eq!(read.unwrap(), 0),
+        }
+    }
+}
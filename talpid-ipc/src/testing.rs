@@ -0,0 +1,144 @@
+//! Helpers for tests of code that talks over endpoints.
+//!
+//! Tests that listen on a fixed path fail when they run in parallel, or when an earlier run
+//! crashed and left its socket behind. [`EphemeralEndpoint`] listens on a path that no other
+//! endpoint uses: a socket in a new temporary directory on Unix, and an
+//! [ephemeral pipe](crate::Endpoint::ephemeral) on Windows. Both are gone once it is dropped.
+//! Tests that bind the endpoint themselves, e.g. through a server, use an [`EphemeralPath`].
+
+#[cfg(all(feature = "client", feature = "server"))]
+use crate::{Connection, Endpoint, Incoming};
+#[cfg(all(feature = "client", feature = "server"))]
+use futures::{Stream, StreamExt};
+use std::io;
+#[cfg(all(feature = "client", feature = "server"))]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A unique socket path or pipe name that nothing listens on yet. On Unix, the socket is placed
+/// in a temporary directory, which is removed when this is dropped.
+pub struct EphemeralPath {
+    path: String,
+    #[cfg(unix)]
+    _dir: tempfile::TempDir,
+}
+
+impl EphemeralPath {
+    pub fn new() -> io::Result<Self> {
+        #[cfg(unix)]
+        let dir = tempfile::Builder::new().prefix("talpid-ipc-").tempdir()?;
+        #[cfg(unix)]
+        let path = dir.path().join("socket").to_string_lossy().into_owned();
+        #[cfg(windows)]
+        let path = crate::imp::ephemeral_pipe_name()?;
+        Ok(EphemeralPath {
+            path,
+            #[cfg(unix)]
+            _dir: dir,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// An endpoint that is listened on at a unique path, and removed when dropped.
+#[cfg(all(feature = "client", feature = "server"))]
+pub struct EphemeralEndpoint {
+    // Dropped before the directory that contains the socket
+    incoming: Incoming,
+    path: EphemeralPath,
+}
+
+#[cfg(all(feature = "client", feature = "server"))]
+impl EphemeralEndpoint {
+    /// Listen on a new endpoint with the default options.
+    pub fn new() -> io::Result<Self> {
+        Self::with_options(|_| ())
+    }
+
+    /// Listen on a new endpoint, after letting `configure` set its options, e.g. its security
+    /// attributes.
+    pub fn with_options(configure: impl FnOnce(&mut Endpoint)) -> io::Result<Self> {
+        let path = EphemeralPath::new()?;
+        let mut endpoint = Endpoint::new(path.path().to_owned());
+        configure(&mut endpoint);
+        Ok(EphemeralEndpoint {
+            incoming: endpoint.incoming()?,
+            path,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.path()
+    }
+
+    /// Connect to the endpoint.
+    pub async fn connect(&self) -> io::Result<Connection> {
+        Endpoint::connect(self.path()).await
+    }
+
+    /// Accept the next connection.
+    pub async fn accept(&mut self) -> io::Result<Connection> {
+        self.incoming
+            .next()
+            .await
+            .unwrap_or_else(|| Err(io::ErrorKind::ConnectionAborted.into()))
+    }
+
+    /// Connect to the endpoint, and accept the connection. Returns the client end first.
+    pub async fn connected_pair(&mut self) -> io::Result<(Connection, Connection)> {
+        let path = self.path().to_owned();
+        let (client, server) = tokio::join!(Endpoint::connect(&path), self.accept());
+        Ok((client?, server?))
+    }
+
+    pub fn incoming(&mut self) -> &mut Incoming {
+        &mut self.incoming
+    }
+}
+
+/// Accepted connections, so that the endpoint can be passed to servers that take a stream of
+/// connections, while keeping the path alive.
+#[cfg(all(feature = "client", feature = "server"))]
+impl Stream for EphemeralEndpoint {
+    type Item = io::Result<Connection>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use crate::frame::{Frame, FramedConnection};
+
+    #[tokio::test]
+    async fn test_ephemeral_endpoint() {
+        let mut first = EphemeralEndpoint::new().unwrap();
+        let second = EphemeralEndpoint::new().unwrap();
+        assert_ne!(first.path(), second.path());
+
+        let (client, server) = first.connected_pair().await.unwrap();
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        client
+            .write_frame(&Frame::data(&b"hello"[..]))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.read_frame().await.unwrap(),
+            Some(Frame::data(&b"hello"[..]))
+        );
+
+        let path = first.path().to_owned();
+        drop((client, server, first));
+        assert!(Endpoint::connect(&path).await.is_err());
+        #[cfg(unix)]
+        assert!(!std::path::Path::new(&path).exists());
+    }
+}
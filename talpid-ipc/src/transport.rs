@@ -0,0 +1,245 @@
+//! Backends that servers and clients can be written against without knowing which one is used.
+//!
+//! [`IpcTransport`] binds a listener and connects to it, with the backend chosen at runtime:
+//!
+//! - [`NativeTransport`], an [`Endpoint`], i.e. a Unix domain socket or a named pipe.
+//! - [`TcpTransport`], loopback TCP authenticated by a token. This requires the `tcp` feature.
+//! - [`MemoryTransport`], which never leaves the process, for tests.
+//!
+//! Windows also has Unix domain sockets, but Tokio cannot use them asynchronously, so named
+//! pipes remain the native transport there.
+//!
+//! Connections are boxed byte streams, so everything that is built on the framing works with
+//! every backend. What is specific to a backend, such as peer credentials, is only available
+//! through the backend itself.
+
+use crate::Endpoint;
+use futures::{
+    FutureExt, StreamExt, TryStreamExt,
+    future::BoxFuture,
+    stream::{self, BoxStream},
+};
+use std::{
+    fmt, io,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+
+/// A connection established through any transport.
+pub trait IpcStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> IpcStream for T {}
+
+pub type BoxConnection = Box<dyn IpcStream>;
+
+/// Stream of connections accepted by a transport. Dropping it stops listening.
+pub type BoxIncoming = BoxStream<'static, io::Result<BoxConnection>>;
+
+/// A way of listening for and establishing connections.
+pub trait IpcTransport: fmt::Debug + Send + Sync {
+    /// Start listening, returning a stream of incoming connections.
+    fn bind(&self) -> BoxFuture<'_, io::Result<BoxIncoming>>;
+
+    /// Connect to a listener bound by [`Self::bind`], possibly in another process.
+    fn connect(&self) -> BoxFuture<'_, io::Result<BoxConnection>>;
+}
+
+type ConfigureEndpoint = Arc<dyn Fn(&mut Endpoint) + Send + Sync>;
+
+/// The native endpoint of the platform.
+#[derive(Clone)]
+pub struct NativeTransport {
+    path: String,
+    configure: Option<ConfigureEndpoint>,
+}
+
+impl NativeTransport {
+    /// Listen on and connect to the socket path or pipe name `path`.
+    pub fn new(path: impl Into<String>) -> Self {
+        NativeTransport {
+            path: path.into(),
+            configure: None,
+        }
+    }
+
+    /// Call `configure` with the endpoint before listening on it, e.g. to set its security
+    /// attributes.
+    pub fn set_configure(&mut self, configure: impl Fn(&mut Endpoint) + Send + Sync + 'static) {
+        self.configure = Some(Arc::new(configure));
+    }
+}
+
+impl fmt::Debug for NativeTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativeTransport")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl IpcTransport for NativeTransport {
+    fn bind(&self) -> BoxFuture<'_, io::Result<BoxIncoming>> {
+        let mut endpoint = Endpoint::new(self.path.clone());
+        if let Some(configure) = &self.configure {
+            configure(&mut endpoint);
+        }
+        let incoming = endpoint.incoming().map(|incoming| {
+            incoming
+                .map_ok(|connection| Box::new(connection) as BoxConnection)
+                .boxed()
+        });
+        futures::future::ready(incoming).boxed()
+    }
+
+    fn connect(&self) -> BoxFuture<'_, io::Result<BoxConnection>> {
+        async move {
+            let connection = Endpoint::connect(&self.path).await?;
+            Ok(Box::new(connection) as BoxConnection)
+        }
+        .boxed()
+    }
+}
+
+/// Loopback TCP, see [`crate::tcp`].
+#[cfg(feature = "tcp")]
+#[derive(Debug, Clone)]
+pub struct TcpTransport {
+    address: std::net::SocketAddr,
+    token: crate::tcp::AuthToken,
+}
+
+#[cfg(feature = "tcp")]
+impl TcpTransport {
+    /// Listen on and connect to the loopback address `address`, presenting `token`.
+    pub fn new(address: std::net::SocketAddr, token: crate::tcp::AuthToken) -> Self {
+        TcpTransport { address, token }
+    }
+}
+
+#[cfg(feature = "tcp")]
+impl IpcTransport for TcpTransport {
+    fn bind(&self) -> BoxFuture<'_, io::Result<BoxIncoming>> {
+        async move {
+            let incoming = crate::tcp::TcpIncoming::bind(self.address, self.token.clone()).await?;
+            Ok(incoming
+                .map_ok(|stream| Box::new(stream) as BoxConnection)
+                .boxed())
+        }
+        .boxed()
+    }
+
+    fn connect(&self) -> BoxFuture<'_, io::Result<BoxConnection>> {
+        async move {
+            let stream = crate::tcp::connect(self.address, &self.token).await?;
+            Ok(Box::new(stream) as BoxConnection)
+        }
+        .boxed()
+    }
+}
+
+/// In-process connections. Clones share the same listener, so a test can bind one clone and
+/// connect with another.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryTransport {
+    listener: Arc<Mutex<Option<mpsc::UnboundedSender<BoxConnection>>>>,
+}
+
+/// Size of the buffer of each direction of a connection.
+const MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        MemoryTransport::default()
+    }
+}
+
+impl IpcTransport for MemoryTransport {
+    fn bind(&self) -> BoxFuture<'_, io::Result<BoxIncoming>> {
+        let mut listener = self.listener.lock().unwrap();
+        if listener
+            .as_ref()
+            .is_some_and(|listener| !listener.is_closed())
+        {
+            let error = io::Error::new(io::ErrorKind::AddrInUse, "Already listening");
+            return futures::future::ready(Err(error)).boxed();
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *listener = Some(sender);
+        let incoming = stream::unfold(receiver, |mut receiver| async move {
+            let connection = receiver.recv().await?;
+            Some((Ok(connection), receiver))
+        });
+        futures::future::ready(Ok(incoming.boxed())).boxed()
+    }
+
+    fn connect(&self) -> BoxFuture<'_, io::Result<BoxConnection>> {
+        let (client, server) = tokio::io::duplex(MEMORY_BUFFER_SIZE);
+        let sent = match &*self.listener.lock().unwrap() {
+            Some(listener) => listener.send(Box::new(server)).is_ok(),
+            None => false,
+        };
+        let result = if sent {
+            Ok(Box::new(client) as BoxConnection)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "Nothing is listening",
+            ))
+        };
+        futures::future::ready(result).boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame::{Frame, FramedConnection};
+
+    /// Written once, and run against every backend.
+    async fn exchange(transport: &dyn IpcTransport) {
+        let mut incoming = transport.bind().await.unwrap();
+        let client = transport.connect().await.unwrap();
+        let server = incoming.next().await.unwrap().unwrap();
+
+        let mut client = FramedConnection::new(client);
+        let mut server = FramedConnection::new(server);
+        client
+            .write_frame(&Frame::data(b"hello".to_vec()))
+            .await
+            .unwrap();
+        let frame = server.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame, Frame::data(b"hello".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_memory_transport() {
+        let transport = MemoryTransport::new();
+        let error = transport.connect().await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        exchange(&transport).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_native_transport() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        exchange(&NativeTransport::new(path)).await;
+    }
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test]
+    async fn test_tcp_transport() {
+        let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        exchange(&TcpTransport::new(
+            address,
+            crate::tcp::AuthToken::generate(),
+        ))
+        .await;
+    }
+}
@@ -0,0 +1,1243 @@
+#[cfg(feature = "client")]
+use crate::backoff::Backoff;
+#[cfg(feature = "server")]
+use crate::context::{Operation, ResultExt};
+use crate::{PeerAddress, metrics::PeerInfo};
+use socket2::SockRef;
+#[cfg(feature = "server")]
+use socket2::{Domain, SockAddr, Socket, Type};
+#[cfg(all(feature = "server", any(target_os = "linux", target_os = "android")))]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(any(
+    feature = "client",
+    all(
+        feature = "server",
+        not(any(target_os = "linux", target_os = "android"))
+    )
+))]
+use std::path::Path;
+#[cfg(feature = "server")]
+use std::{
+    fs,
+    os::{fd::OwnedFd, unix::fs::MetadataExt},
+    sync::Arc,
+    task::{Context, Poll},
+};
+use std::{
+    io,
+    os::fd::{AsRawFd, RawFd},
+    path::PathBuf,
+};
+#[cfg(feature = "server")]
+use tokio::net::UnixListener;
+use tokio::net::UnixStream;
+
+pub type Connection = UnixStream;
+
+/// Identifies the user of a peer.
+#[cfg(feature = "server")]
+pub type PeerUser = u32;
+
+/// Used when no listen backlog has been configured. The system caps it at its own maximum, so
+/// this is as many pending connections as the system allows.
+#[cfg(any(feature = "server", target_os = "linux", target_os = "android"))]
+pub(crate) const DEFAULT_BACKLOG: i32 = libc::SOMAXCONN;
+
+/// Options used when binding the socket.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Default)]
+pub struct ListenOptions {
+    /// Maximum number of pending connections.
+    pub backlog: Option<u32>,
+    /// Kernel buffer sizes of the listener and of accepted sockets.
+    pub buffer_sizes: BufferSizes,
+}
+
+/// Sizes of the kernel buffers of a socket, `SO_RCVBUF` and `SO_SNDBUF`. `None` leaves the
+/// default of the system.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BufferSizes {
+    pub receive: Option<usize>,
+    pub send: Option<usize>,
+}
+
+#[cfg(feature = "server")]
+impl BufferSizes {
+    fn apply(&self, socket: SockRef<'_>) -> io::Result<()> {
+        if let Some(size) = self.receive {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Permissions applied to the socket file once it has been bound.
+#[derive(Debug, Clone, Default)]
+pub struct SecurityAttributes {
+    mode: Option<u32>,
+    owner: Option<(u32, u32)>,
+}
+
+impl SecurityAttributes {
+    /// Leave the socket permissions as determined by the process umask.
+    pub fn empty() -> Self {
+        SecurityAttributes::default()
+    }
+
+    /// Allow everyone to connect to the socket.
+    pub fn allow_everyone_connect() -> io::Result<Self> {
+        Ok(SecurityAttributes {
+            mode: Some(0o777),
+            owner: None,
+        })
+    }
+
+    /// Allow everyone to create new instances of the endpoint. This is only meaningful on
+    /// Windows, and does not change the socket permissions.
+    pub fn allow_everyone_create() -> io::Result<Self> {
+        Ok(SecurityAttributes::default())
+    }
+
+    /// Set the file mode of the socket.
+    pub fn set_mode(mut self, mode: u32) -> io::Result<Self> {
+        self.mode = Some(mode);
+        Ok(self)
+    }
+
+    /// Change the owner of the socket. This requires the process to be privileged.
+    pub fn set_owner(mut self, uid: u32, gid: u32) -> Self {
+        self.owner = Some((uid, gid));
+        self
+    }
+
+    /// Apply the owner and mode to the socket at `path`, but only if it is still the socket
+    /// identified by `identity`. Symbolic links are never followed, so that someone who can
+    /// write to the directory cannot redirect the changes to another file by replacing the
+    /// socket.
+    #[cfg(feature = "server")]
+    fn apply_permissions(&self, path: &str, identity: Option<FileIdentity>) -> io::Result<()> {
+        if self.owner.is_none() && self.mode.is_none() {
+            return Ok(());
+        }
+        let operation = match self.owner {
+            Some(_) => Operation::Chown,
+            None => Operation::Chmod,
+        };
+        let identity = identity
+            .ok_or_else(|| io::Error::other("The socket could not be identified"))
+            .context(operation, path)?;
+        let socket = SocketFile::open(path, identity).context(operation, path)?;
+        if let Some((uid, gid)) = self.owner {
+            socket.chown(uid, gid).context(Operation::Chown, path)?;
+        }
+        if let Some(mode) = self.mode {
+            socket.chmod(mode).context(Operation::Chmod, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// A socket file whose owner and mode can be changed without following symbolic links.
+#[cfg(all(feature = "server", any(target_os = "linux", target_os = "android")))]
+struct SocketFile {
+    /// An `O_PATH` descriptor, which refers to the socket itself however its path changes.
+    fd: OwnedFd,
+}
+
+#[cfg(all(feature = "server", any(target_os = "linux", target_os = "android")))]
+impl SocketFile {
+    fn open(path: &str, identity: FileIdentity) -> io::Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_NOFOLLOW)
+            .open(path)?;
+        check_socket(&file.metadata()?, identity)?;
+        Ok(SocketFile { fd: file.into() })
+    }
+
+    fn chown(&self, uid: u32, gid: u32) -> io::Result<()> {
+        // SAFETY: The descriptor is valid, and the path is a NUL-terminated empty string
+        let result = unsafe {
+            libc::fchownat(
+                self.fd.as_raw_fd(),
+                c"".as_ptr(),
+                uid,
+                gid,
+                libc::AT_EMPTY_PATH,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn chmod(&self, mode: u32) -> io::Result<()> {
+        // `fchmod` does not accept `O_PATH` descriptors, but the link in `/proc` resolves to the
+        // socket that the descriptor refers to
+        let fd_path = format!("/proc/self/fd/{}", self.fd.as_raw_fd());
+        fs::set_permissions(fd_path, fs::Permissions::from_mode(mode))
+    }
+}
+
+/// A socket file whose owner and mode can be changed without following symbolic links.
+#[cfg(all(
+    feature = "server",
+    not(any(target_os = "linux", target_os = "android"))
+))]
+struct SocketFile {
+    /// The directory that contains the socket.
+    dir: fs::File,
+    name: std::ffi::CString,
+    identity: FileIdentity,
+}
+
+#[cfg(all(
+    feature = "server",
+    not(any(target_os = "linux", target_os = "android"))
+))]
+impl SocketFile {
+    fn open(path: &str, identity: FileIdentity) -> io::Result<Self> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(path);
+        let (dir, name) = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return Err(io::ErrorKind::InvalidInput.into()),
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        let socket = SocketFile {
+            dir: fs::File::open(dir)?,
+            name: std::ffi::CString::new(name.as_bytes())?,
+            identity,
+        };
+        socket.check()?;
+        Ok(socket)
+    }
+
+    /// Fail unless the entry in the directory is still the socket.
+    fn check(&self) -> io::Result<()> {
+        let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+        // SAFETY: The descriptor is valid, the name is NUL-terminated, and `stat` is valid for
+        // writes
+        let result = unsafe {
+            libc::fstatat(
+                self.dir.as_raw_fd(),
+                self.name.as_ptr(),
+                stat.as_mut_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fstatat` succeeded, so it initialized `stat`
+        let stat = unsafe { stat.assume_init() };
+        #[allow(
+            clippy::unnecessary_cast,
+            reason = "The types differ between platforms"
+        )]
+        let current = FileIdentity {
+            dev: stat.st_dev as u64,
+            ino: stat.st_ino as u64,
+        };
+        if stat.st_mode & libc::S_IFMT != libc::S_IFSOCK || current != self.identity {
+            return Err(replaced());
+        }
+        Ok(())
+    }
+
+    fn chown(&self, uid: u32, gid: u32) -> io::Result<()> {
+        self.check()?;
+        // SAFETY: The descriptor is valid, and the name is NUL-terminated
+        let result = unsafe {
+            libc::fchownat(
+                self.dir.as_raw_fd(),
+                self.name.as_ptr(),
+                uid,
+                gid,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn chmod(&self, mode: u32) -> io::Result<()> {
+        self.check()?;
+        // SAFETY: The descriptor is valid, and the name is NUL-terminated
+        let result = unsafe {
+            libc::fchmodat(
+                self.dir.as_raw_fd(),
+                self.name.as_ptr(),
+                mode as libc::mode_t,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Fail unless `metadata` belongs to the socket identified by `identity`.
+#[cfg(all(feature = "server", any(target_os = "linux", target_os = "android")))]
+fn check_socket(metadata: &fs::Metadata, identity: FileIdentity) -> io::Result<()> {
+    if !metadata.file_type().is_socket() || FileIdentity::from_metadata(metadata) != identity {
+        return Err(replaced());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+fn replaced() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "The socket was replaced after it was bound",
+    )
+}
+
+#[cfg(feature = "server")]
+pub struct Incoming {
+    /// Shared with the errors of the listener, so that they do not copy it.
+    path: Arc<str>,
+    listener: UnixListener,
+    /// Identity of the socket file if it belongs to us, and should be removed when we stop
+    /// listening.
+    bound: Option<FileIdentity>,
+    /// Applied to every accepted socket, since Linux does not let them inherit the sizes of the
+    /// listener.
+    buffer_sizes: BufferSizes,
+    /// Locked for as long as the socket is listened on, and removed after it. Dropped after the
+    /// listener by being declared after it.
+    pid_file: Option<crate::takeover::PidFile>,
+}
+
+/// Identifies a file independently of its path.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileIdentity {
+    dev: u64,
+    ino: u64,
+}
+
+#[cfg(feature = "server")]
+impl FileIdentity {
+    pub(crate) fn of(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        fs::symlink_metadata(path).map(|metadata| FileIdentity::from_metadata(&metadata))
+    }
+
+    fn from_metadata(metadata: &fs::Metadata) -> Self {
+        FileIdentity {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        }
+    }
+}
+
+/// A socket that has been bound, and had its permissions applied, but is not listened on yet.
+/// See [`crate::Endpoint::bind`].
+#[cfg(feature = "server")]
+pub struct BoundSocket {
+    path: String,
+    /// Taken once the socket is listened on.
+    listener: Option<std::os::unix::net::UnixListener>,
+    identity: FileIdentity,
+    /// The directories that lead to the socket, innermost first.
+    directories: Vec<FileIdentity>,
+    security_attributes: SecurityAttributes,
+}
+
+#[cfg(feature = "server")]
+impl BoundSocket {
+    pub fn bind(
+        path: String,
+        security_attributes: SecurityAttributes,
+        options: &ListenOptions,
+    ) -> io::Result<Self> {
+        let listener = bind_std_listener(&path, options).context(Operation::Bind, &path)?;
+        let identity = FileIdentity::of(&path).context(Operation::Bind, &path)?;
+        let directories = directory_identities(&path)
+            .collect::<io::Result<_>>()
+            .context(Operation::Bind, &path)?;
+        // The socket is removed again if the permissions cannot be applied
+        let bound = BoundSocket {
+            path,
+            listener: Some(listener),
+            identity,
+            directories,
+            security_attributes,
+        };
+        bound
+            .security_attributes
+            .apply_permissions(&bound.path, Some(bound.identity))?;
+        Ok(bound)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Fail if the socket file, or any directory that leads to it, is not the one that was bound
+    /// anymore, or if the owner or mode of the socket differ from those that were applied.
+    fn verify(&self) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(&self.path)?;
+        if FileIdentity::from_metadata(&metadata) != self.identity {
+            return Err(replaced());
+        }
+        for (directory, &identity) in directory_identities(&self.path).zip(&self.directories) {
+            if directory? != identity {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "A directory of the socket was replaced after it was bound",
+                ));
+            }
+        }
+        if let Some((uid, gid)) = self.security_attributes.owner
+            && (metadata.uid(), metadata.gid()) != (uid, gid)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "The owner of the socket was changed after it was bound",
+            ));
+        }
+        if let Some(mode) = self.security_attributes.mode
+            && metadata.mode() & 0o7777 != mode & 0o7777
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "The mode of the socket was changed after it was bound",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Identify the directories that lead to `path`, innermost first.
+#[cfg(feature = "server")]
+fn directory_identities(path: &str) -> impl Iterator<Item = io::Result<FileIdentity>> + '_ {
+    std::path::Path::new(path)
+        .ancestors()
+        .skip(1)
+        .map(|directory| match directory.as_os_str().is_empty() {
+            true => FileIdentity::of("."),
+            false => FileIdentity::of(directory),
+        })
+}
+
+#[cfg(feature = "server")]
+impl Drop for BoundSocket {
+    fn drop(&mut self) {
+        if self.listener.is_some()
+            && let Err(error) = remove_socket(&self.path, self.identity)
+        {
+            log::warn!("Failed to remove IPC socket {}: {error}", self.path);
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Incoming {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The path, to attach to errors without copying it.
+    pub fn shared_path(&self) -> &Arc<str> {
+        &self.path
+    }
+
+    pub fn bind(
+        path: String,
+        security_attributes: SecurityAttributes,
+        options: &ListenOptions,
+    ) -> io::Result<Self> {
+        let listener = bind_listener(&path, options).context(Operation::Bind, &path)?;
+        let bound = match FileIdentity::of(&path) {
+            Ok(identity) => Some(identity),
+            Err(error) => {
+                log::warn!("Failed to identify IPC socket {path}, it will not be removed: {error}");
+                None
+            }
+        };
+        // Do not leave a socket with the wrong permissions behind if they cannot be applied
+        let incoming = Incoming {
+            path: path.into(),
+            listener,
+            bound,
+            buffer_sizes: options.buffer_sizes,
+            pid_file: None,
+        };
+        security_attributes.apply_permissions(&incoming.path, incoming.bound)?;
+        Ok(incoming)
+    }
+
+    /// Bind to `path` in place of the socket identified by `stale`, which a server that has died
+    /// left behind. The new socket is bound next to it and renamed over it, so that the path is
+    /// never without a socket for another server to bind in between, and only the stale socket
+    /// is replaced. If the path is too long for that, the stale socket is removed first.
+    pub(crate) fn bind_replacing(
+        path: String,
+        stale: FileIdentity,
+        security_attributes: SecurityAttributes,
+        options: &ListenOptions,
+    ) -> io::Result<Self> {
+        let temp_path = format!("{path}.{}", std::process::id());
+        let mut incoming =
+            match Incoming::bind(temp_path.clone(), security_attributes.clone(), options) {
+                Ok(incoming) => incoming,
+                // Socket paths are limited to around 100 bytes
+                Err(error) if error.kind() == io::ErrorKind::InvalidInput => {
+                    remove_socket(&path, stale).context(Operation::Remove, &path)?;
+                    return Incoming::bind(path, security_attributes, options);
+                }
+                Err(error) => return Err(error),
+            };
+        // Dropping `incoming` removes the new socket if it is not renamed
+        if FileIdentity::of(&path).context(Operation::Bind, &path)? != stale {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("The stale socket at {path} was replaced by another server"),
+            ));
+        }
+        fs::rename(&temp_path, &path).context(Operation::Bind, &path)?;
+        incoming.path = path.into();
+        Ok(incoming)
+    }
+
+    /// Keep `pid_file` locked until the socket is no longer listened on, and remove it then.
+    pub(crate) fn set_pid_file(&mut self, pid_file: crate::takeover::PidFile) {
+        self.pid_file = Some(pid_file);
+    }
+
+    /// Listen on a socket that was bound by [`BoundSocket::bind`], after checking that it has
+    /// not been tampered with since.
+    pub fn from_bound(mut bound: BoundSocket, options: &ListenOptions) -> io::Result<Self> {
+        bound.verify().context(Operation::Bind, &bound.path)?;
+        let Some(listener) = bound.listener.take() else {
+            unreachable!("The listener is only taken here");
+        };
+        let path = std::mem::take(&mut bound.path);
+        let listener = listener
+            .set_nonblocking(true)
+            .and_then(|()| UnixListener::from_std(listener))
+            .context(Operation::Bind, &path)?;
+        Ok(Incoming {
+            path: path.into(),
+            listener,
+            bound: Some(bound.identity),
+            buffer_sizes: options.buffer_sizes,
+            pid_file: None,
+        })
+    }
+
+    /// Listen on a socket that was bound by someone else, such as the service manager. Only the
+    /// buffer sizes of `options` are used, for the accepted sockets.
+    pub fn from_std(
+        listener: std::os::unix::net::UnixListener,
+        path: String,
+        options: &ListenOptions,
+    ) -> io::Result<Self> {
+        // The service manager may have left `FD_CLOEXEC` unset for us to inherit the socket, and
+        // whoever bound it may have left it blocking
+        let listener = set_cloexec(listener.as_raw_fd())
+            .and_then(|()| listener.set_nonblocking(true))
+            .and_then(|()| UnixListener::from_std(listener))
+            .context(Operation::Bind, &path)?;
+        Ok(Incoming {
+            path: path.into(),
+            listener,
+            bound: None,
+            buffer_sizes: options.buffer_sizes,
+            pid_file: None,
+        })
+    }
+
+    /// Stop listening, and remove the socket if it belongs to us. Unlike dropping, this reports
+    /// if the socket could not be removed.
+    pub fn close(mut self) -> io::Result<()> {
+        match self.bound.take() {
+            Some(bound) => remove_socket(&self.path, bound).context(Operation::Remove, &self.path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<Connection>>> {
+        self.listener.poll_accept(cx).map(|result| {
+            Some(result.and_then(|(stream, _addr)| {
+                // Only `accept4` sets `SOCK_CLOEXEC` atomically. Elsewhere, set it explicitly
+                // instead of relying on how the runtime accepts.
+                #[cfg(not(any(target_os = "linux", target_os = "android")))]
+                set_cloexec(stream.as_raw_fd())?;
+                // The connection still works with the default sizes. Failing the accept would stop
+                // the listener, since every later connection would most likely fail the same way.
+                if let Err(error) = self.buffer_sizes.apply(SockRef::from(&stream)) {
+                    crate::log_limit::log(
+                        log::Level::Warn,
+                        || "buffer sizes",
+                        format_args!(
+                            "Failed to set the buffer sizes of an IPC connection: {error}"
+                        ),
+                    );
+                }
+                Ok(stream)
+            }))
+        })
+    }
+}
+
+#[cfg(feature = "server")]
+fn bind_listener(path: &str, options: &ListenOptions) -> io::Result<UnixListener> {
+    let listener = bind_std_listener(path, options)?;
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener)
+}
+
+/// Bind a listening socket, which can be used without a runtime.
+#[cfg(feature = "server")]
+fn bind_std_listener(
+    path: &str,
+    options: &ListenOptions,
+) -> io::Result<std::os::unix::net::UnixListener> {
+    let backlog = options
+        .backlog
+        .map(|backlog| i32::try_from(backlog).unwrap_or(i32::MAX))
+        .unwrap_or(DEFAULT_BACKLOG);
+
+    // The socket is created with `SOCK_CLOEXEC` by socket2
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    options.buffer_sizes.apply(SockRef::from(&socket))?;
+    socket.bind(&SockAddr::unix(path)?)?;
+    socket.listen(backlog)?;
+    Ok(std::os::unix::net::UnixListener::from(OwnedFd::from(
+        socket,
+    )))
+}
+
+#[cfg(feature = "server")]
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        if let Some(bound) = self.bound
+            && let Err(error) = remove_socket(&self.path, bound)
+        {
+            log::warn!("Failed to remove IPC socket {}: {error}", self.path);
+        }
+    }
+}
+
+/// Remove the socket at `path`, unless it is not the one identified by `bound` anymore.
+#[cfg(feature = "server")]
+fn remove_socket(path: &str, bound: FileIdentity) -> io::Result<()> {
+    // Another server may have replaced the socket since, e.g. if it considered this one dead.
+    // Leave its socket alone.
+    match FileIdentity::of(path) {
+        Ok(current) if current == bound => fs::remove_file(path),
+        Ok(_) => {
+            log::debug!("Not removing IPC socket {path}, it was replaced");
+            Ok(())
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Return the UID of the peer of `connection`.
+#[cfg(feature = "server")]
+pub fn peer_user(connection: &Connection) -> io::Result<PeerUser> {
+    crate::credentials::peer_credentials(connection.as_raw_fd())
+        .map(|credentials| credentials.uid())
+}
+
+/// Find out who the peer of `connection` is from its credentials, see [`PeerInfo::complete`].
+pub fn peer_info(connection: &Connection) -> PeerInfo {
+    crate::credentials::peer_credentials(connection.as_raw_fd())
+        .map(|credentials| credentials_peer_info(&credentials))
+        .unwrap_or_default()
+}
+
+/// Who the peer with `credentials` is, without looking the process up.
+pub fn credentials_peer_info(credentials: &crate::credentials::PeerCredentials) -> PeerInfo {
+    PeerInfo {
+        user: Some(credentials.uid().to_string()),
+        pid: credentials.pid().and_then(|pid| u32::try_from(pid).ok()),
+        exe: None,
+    }
+}
+
+/// Return the path of the executable of the process `pid`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn process_exe(pid: u32) -> Option<PathBuf> {
+    let exe = std::fs::read_link(format!("/proc/{pid}/exe")).ok()?;
+    // The executable may have been replaced since the process started, e.g. by an upgrade
+    let exe = exe.to_string_lossy();
+    Some(PathBuf::from(
+        exe.strip_suffix(" (deleted)").unwrap_or(&exe),
+    ))
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn process_exe(pid: u32) -> Option<PathBuf> {
+    use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+    let mut path = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+    let pid = libc::c_int::try_from(pid).ok()?;
+    // SAFETY: `path` is valid for writes of its length
+    let len = unsafe { libc::proc_pidpath(pid, path.as_mut_ptr().cast(), path.len() as u32) };
+    if len <= 0 {
+        return None;
+    }
+    path.truncate(len as usize);
+    Some(PathBuf::from(OsString::from_vec(path)))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+pub(crate) fn process_exe(_pid: u32) -> Option<PathBuf> {
+    None
+}
+
+/// Fail unless the process at the other end of `connection` runs as `uid`. The kernel records
+/// the credentials of the peer when the connection is made, so unlike the owner of the socket
+/// file, they cannot change between checking them and talking to the server.
+#[cfg(feature = "client")]
+pub(crate) fn verify_server_user(connection: &Connection, uid: u32) -> io::Result<()> {
+    let server = crate::credentials::peer_credentials(connection.as_raw_fd())?.uid();
+    if server != uid {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("The server runs as UID {server}, not {uid}"),
+        ));
+    }
+    Ok(())
+}
+
+/// Connect to the socket at `path`. Sockets are never busy, so `_busy` is only used on Windows.
+#[cfg(feature = "client")]
+pub async fn connect(path: &Path, _busy: Option<&dyn Backoff>) -> io::Result<Connection> {
+    UnixStream::connect(path).await
+}
+
+/// Return the address that the socket of the peer of `connection` is bound to.
+pub fn peer_address(connection: &Connection, _endpoint: Option<&str>) -> io::Result<PeerAddress> {
+    let address = SockRef::from(connection).peer_addr()?;
+    if let Some(path) = address.as_pathname() {
+        return Ok(PeerAddress::Path(path.to_owned()));
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(name) = address.as_abstract_namespace() {
+        return Ok(PeerAddress::Abstract(name.to_vec()));
+    }
+    Ok(PeerAddress::Unnamed)
+}
+
+/// Prevent a descriptor from leaking into child processes.
+pub fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    set_fd_inheritable(fd, false)
+}
+
+/// Let child processes inherit the socket of `connection`, or prevent it. Sockets are created
+/// with `FD_CLOEXEC` set.
+pub fn set_inheritable(connection: &Connection, inheritable: bool) -> io::Result<()> {
+    set_fd_inheritable(connection.as_raw_fd(), inheritable)
+}
+
+/// Number of bytes of physical memory of the machine.
+pub(crate) fn physical_memory() -> io::Result<u64> {
+    // SAFETY: `sysconf` has no preconditions
+    let (pages, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_PHYS_PAGES),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+    if pages <= 0 || page_size <= 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((pages as u64).saturating_mul(page_size as u64))
+}
+
+impl crate::Connection {
+    /// Clear `FD_CLOEXEC` on the socket, so that it is inherited by processes that are executed
+    /// from now on. This is only meant for the rare case where a connection is deliberately
+    /// passed to a helper. Every other socket of this crate, including the listener and all
+    /// accepted and received sockets, has `FD_CLOEXEC` set. See also
+    /// [`crate::Endpoint::set_inheritable`].
+    pub fn clear_cloexec(&self) -> io::Result<()> {
+        set_inheritable(&self.inner, true)
+    }
+}
+
+impl AsRawFd for crate::Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+fn set_fd_inheritable(fd: RawFd, inheritable: bool) -> io::Result<()> {
+    // SAFETY: Getting and setting flags on an arbitrary descriptor has no memory safety
+    // implications
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if inheritable {
+        flags & !libc::FD_CLOEXEC
+    } else {
+        flags | libc::FD_CLOEXEC
+    };
+    // SAFETY: See above
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "fd-passing")]
+mod fd_passing {
+    use nix::sys::socket::{ControlMessage, ControlMessageOwned, MsgFlags, recvmsg, sendmsg};
+    use std::{
+        io::{self, IoSlice, IoSliceMut},
+        os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    };
+    use tokio::io::Interest;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const RECV_FLAGS: MsgFlags = MsgFlags::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const RECV_FLAGS: MsgFlags = MsgFlags::empty();
+
+    impl crate::Connection {
+        /// Send a file descriptor to the peer. It is carried by a single byte outside of any
+        /// framing, so the peer must call [`Self::recv_fd`] at the corresponding point in the
+        /// byte stream.
+        pub async fn send_fd(&self, fd: BorrowedFd<'_>) -> io::Result<()> {
+            let socket = self.inner.as_raw_fd();
+            let fds = [fd.as_raw_fd()];
+            self.inner
+                .async_io(Interest::WRITABLE, || {
+                    let iov = [IoSlice::new(&[0])];
+                    let cmsgs = [ControlMessage::ScmRights(&fds)];
+                    sendmsg::<()>(socket, &iov, &cmsgs, MsgFlags::empty(), None)
+                        .map_err(io::Error::from)
+                })
+                .await
+                .map(|_| ())
+        }
+
+        /// Receive a file descriptor sent by the peer using [`Self::send_fd`].
+        pub async fn recv_fd(&self) -> io::Result<OwnedFd> {
+            let socket = self.inner.as_raw_fd();
+            self.inner
+                .async_io(Interest::READABLE, || {
+                    let mut byte = [0u8; 1];
+                    let mut iov = [IoSliceMut::new(&mut byte)];
+                    let mut cmsg_buffer = nix::cmsg_space!([RawFd; 1]);
+                    let msg = recvmsg::<()>(socket, &mut iov, Some(&mut cmsg_buffer), RECV_FLAGS)
+                        .map_err(io::Error::from)?;
+                    if msg.bytes == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                    }
+                    let mut received = vec![];
+                    for cmsg in msg.cmsgs().map_err(io::Error::from)? {
+                        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                            // SAFETY: The kernel installed these descriptors in our descriptor
+                            // table, and nothing else owns them. Any surplus ones are closed.
+                            received.extend(
+                                fds.into_iter()
+                                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                            );
+                        }
+                    }
+                    // Without `MSG_CMSG_CLOEXEC`, the descriptors are inheritable until now
+                    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+                    for fd in &received {
+                        super::set_cloexec(fd.as_raw_fd())?;
+                    }
+                    received.into_iter().next().ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Expected a file descriptor from the peer",
+                        )
+                    })
+                })
+                .await
+        }
+
+        /// Hand `connection` over to the peer, e.g. a helper that should serve a client that
+        /// has already been authenticated. The peer receives it with [`Self::recv_connection`].
+        /// Both processes then share the socket, so `connection` should be dropped once this
+        /// returns.
+        ///
+        /// Only the socket is passed on. Anything that was read ahead from it, e.g. by a
+        /// [`crate::frame::FramedConnection`], or any state of the protocol, such as session
+        /// keys, has to be sent to the peer separately.
+        pub async fn send_connection(&self, connection: &crate::Connection) -> io::Result<()> {
+            self.send_fd(connection.inner.as_fd()).await
+        }
+
+        /// Receive a connection that the peer handed over with [`Self::send_connection`].
+        pub async fn recv_connection(&self) -> io::Result<crate::Connection> {
+            let fd = self.recv_fd().await?;
+            crate::Connection::from_std(std::os::unix::net::UnixStream::from(fd))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+    use std::{
+        fs,
+        os::unix::fs::{MetadataExt, PermissionsExt},
+        path::Path,
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn test_replaced_socket_is_not_removed() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+
+        let first = Incoming::bind(
+            path.clone(),
+            SecurityAttributes::empty(),
+            &Default::default(),
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+        let second = Incoming::bind(
+            path.clone(),
+            SecurityAttributes::empty(),
+            &Default::default(),
+        )
+        .unwrap();
+
+        drop(first);
+        assert!(Path::new(&path).exists());
+        drop(second);
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn test_permissions_do_not_follow_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("socket").to_string_lossy().into_owned();
+        let target = dir.path().join("target");
+        fs::write(&target, "").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let identity = FileIdentity::of(&path).unwrap();
+        let attributes = SecurityAttributes::empty().set_mode(0o666).unwrap();
+
+        // Someone who can write to the directory replaces the socket before its mode is set
+        fs::remove_file(&path).unwrap();
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+        let error = attributes
+            .apply_permissions(&path, Some(identity))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fs::metadata(&target).unwrap().mode() & 0o777, 0o600);
+
+        // Nor is another socket at the path changed
+        fs::remove_file(&path).unwrap();
+        let _replacement = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let error = attributes
+            .apply_permissions(&path, Some(identity))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    fn cloexec(fd: RawFd) -> bool {
+        // SAFETY: Getting the flags of a descriptor has no memory safety implications
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        assert!(flags >= 0);
+        flags & libc::FD_CLOEXEC != 0
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_sockets_are_not_inherited() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+
+        let mut incoming = Incoming::bind(
+            path.clone(),
+            SecurityAttributes::empty(),
+            &Default::default(),
+        )
+        .unwrap();
+        let client = connect(Path::new(&path), None).await.unwrap();
+        let server = std::future::poll_fn(|cx| incoming.poll_accept(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(cloexec(incoming.listener.as_raw_fd()));
+        assert!(cloexec(client.as_raw_fd()));
+        assert!(cloexec(server.as_raw_fd()));
+
+        set_inheritable(&server, true).unwrap();
+        assert!(!cloexec(server.as_raw_fd()));
+        set_inheritable(&server, false).unwrap();
+        assert!(cloexec(server.as_raw_fd()));
+    }
+
+    #[tokio::test]
+    async fn test_clear_cloexec() {
+        let (inherited, other) = crate::Endpoint::socketpair().unwrap();
+        assert!(cloexec(inherited.as_raw_fd()));
+        inherited.clear_cloexec().unwrap();
+        assert!(!cloexec(inherited.as_raw_fd()));
+        assert!(cloexec(other.as_raw_fd()));
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_buffer_sizes() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let options = ListenOptions {
+            buffer_sizes: BufferSizes {
+                receive: Some(64 * 1024),
+                send: Some(256 * 1024),
+            },
+            ..Default::default()
+        };
+
+        let mut incoming =
+            Incoming::bind(path.clone(), SecurityAttributes::empty(), &options).unwrap();
+        let _client = connect(Path::new(&path), None).await.unwrap();
+        let server = std::future::poll_fn(|cx| incoming.poll_accept(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        // The kernel may round the sizes up, e.g. Linux doubles them
+        let server = SockRef::from(&server);
+        assert!(server.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert!(server.send_buffer_size().unwrap() >= 256 * 1024);
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_when_ready_timeout() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let started = tokio::time::Instant::now();
+        let error = crate::Endpoint::connect_when_ready(&path, Duration::from_secs(30))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(started.elapsed(), Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn test_ready_callback() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+
+        let mut endpoint = crate::Endpoint::new(path.clone());
+        endpoint.set_ready_callback(move |path| {
+            // Clients must be able to connect by the time the callback is called
+            ready_tx
+                .send(std::os::unix::net::UnixStream::connect(path).is_ok())
+                .unwrap();
+        });
+        let _incoming = endpoint.incoming().unwrap();
+        assert!(ready_rx.try_recv().unwrap());
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_close() {
+        use futures::StreamExt;
+        use tokio::io::AsyncReadExt;
+
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let mut incoming = crate::Endpoint::new(path.clone()).incoming().unwrap();
+        let mut client = crate::Endpoint::connect(&path).await.unwrap();
+        let server = incoming.next().await.unwrap().unwrap();
+
+        incoming.close().unwrap();
+        assert!(!Path::new(&path).exists());
+        // Accepted connections are not closed along with the listener
+        server.close().await.unwrap();
+        assert_eq!(client.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_restricted_endpoint() {
+        use futures::StreamExt;
+
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let mut endpoint = crate::Endpoint::new(path.clone());
+        endpoint.set_restricted(true);
+        let mut incoming = endpoint.incoming().unwrap();
+
+        let client = crate::Endpoint::connect(&path).await.unwrap();
+        let server = incoming.next().await.unwrap().unwrap();
+        assert!(server.is_restricted());
+        assert!(!client.is_restricted());
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_bind_before_listening() {
+        use futures::StreamExt;
+
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let mut endpoint = crate::Endpoint::new(path.clone());
+        endpoint.set_security_attributes(SecurityAttributes::empty().set_mode(0o600).unwrap());
+        let bound = endpoint.bind().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+
+        let mut incoming = bound.incoming().unwrap();
+        let _client = crate::Endpoint::connect(&path).await.unwrap();
+        incoming.next().await.unwrap().unwrap();
+        drop(incoming);
+        assert!(!Path::new(&path).exists());
+
+        // Only the attributes that were applied are checked
+        let bound = crate::Endpoint::new(path.clone()).bind().unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+        assert!(bound.incoming().is_ok());
+
+        let mut endpoint = crate::Endpoint::new(path.clone());
+        endpoint.set_security_attributes(SecurityAttributes::empty().set_mode(0o600).unwrap());
+        let bound = endpoint.bind().unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+        let error = bound.incoming().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        // The socket was replaced in between
+        let bound = crate::Endpoint::new(path.clone()).bind().unwrap();
+        fs::remove_file(&path).unwrap();
+        let _replacement = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let error = bound.incoming().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(Path::new(&path).exists());
+        fs::remove_file(&path).unwrap();
+
+        // A directory that leads to the socket was replaced, with the socket moved into it
+        let sub = Path::new(&path).join("sub");
+        let old = Path::new(&path).join("old");
+        fs::create_dir_all(&sub).unwrap();
+        let bound = crate::Endpoint::new(sub.join("socket").to_str().unwrap().to_owned())
+            .bind()
+            .unwrap();
+        fs::rename(&sub, &old).unwrap();
+        fs::create_dir(&sub).unwrap();
+        fs::rename(old.join("socket"), sub.join("socket")).unwrap();
+        let error = bound.incoming().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_require_owner() {
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let _incoming = crate::Endpoint::new(path.clone()).incoming().unwrap();
+        // SAFETY: `geteuid` has no preconditions
+        let uid = unsafe { libc::geteuid() };
+
+        let mut options = crate::ConnectOptions::new();
+        options.require_owner(uid);
+        crate::Endpoint::connect_with_options(&path, &options)
+            .await
+            .unwrap();
+
+        // The server runs in this process, so never as another user
+        options.require_owner(uid.wrapping_add(1));
+        let error = crate::Endpoint::connect_with_options(&path, &options)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_from_std_listener() {
+        use futures::StreamExt;
+
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        // As left by a service manager for the process to inherit it
+        let fd = listener.as_raw_fd();
+        set_fd_inheritable(fd, true).unwrap();
+        let endpoint = crate::Endpoint::from_std_listener(listener).unwrap();
+        assert_eq!(endpoint.path(), path);
+        let mut incoming = endpoint.incoming().unwrap();
+        assert!(cloexec(fd));
+
+        let _client = crate::Endpoint::connect(&path).await.unwrap();
+        incoming.next().await.unwrap().unwrap();
+        // The socket belongs to whoever bound it
+        drop(incoming);
+        assert!(Path::new(&path).exists());
+    }
+
+    #[cfg(feature = "fd-passing")]
+    #[tokio::test]
+    async fn test_send_connection() {
+        use crate::frame::{Frame, FramedConnection};
+
+        let (parent, helper) = crate::Endpoint::socketpair().unwrap();
+        let (client, delegated) = crate::Endpoint::socketpair().unwrap();
+        parent.send_connection(&delegated).await.unwrap();
+        drop(delegated);
+        let delegated = helper.recv_connection().await.unwrap();
+        assert!(cloexec(delegated.as_raw_fd()));
+
+        let mut client = FramedConnection::new(client);
+        let mut delegated = FramedConnection::new(delegated);
+        client
+            .write_frame(&Frame::data(b"hello".to_vec()))
+            .await
+            .unwrap();
+        let frame = delegated.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame, Frame::data(b"hello".to_vec()));
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_addresses() {
+        use futures::StreamExt;
+
+        let dir = crate::testing::EphemeralPath::new().unwrap();
+        let path = dir.path().to_owned();
+        let mut incoming = crate::Endpoint::new(path.clone()).incoming().unwrap();
+        let client = crate::Endpoint::connect(&path).await.unwrap();
+        let server = incoming.next().await.unwrap().unwrap();
+
+        assert_eq!(client.endpoint_path(), Some(&*path));
+        assert_eq!(server.endpoint_path(), Some(&*path));
+        assert_eq!(
+            client.peer_address().unwrap(),
+            PeerAddress::Path(PathBuf::from(&path))
+        );
+        assert_eq!(server.peer_address().unwrap(), PeerAddress::Unnamed);
+        let (pair, _) = crate::Endpoint::socketpair().unwrap();
+        assert_eq!(pair.endpoint_path(), None);
+    }
+}
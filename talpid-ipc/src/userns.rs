@@ -0,0 +1,187 @@
+//! Interpreting peer credentials across user namespaces, on Linux.
+//!
+//! The kernel translates the UID and GID reported by `SO_PEERCRED` into the user namespace of
+//! the process that asks, i.e. the daemon. A client in a container therefore shows up with the
+//! IDs that its user is mapped to on the host, which may be a range of unprivileged IDs
+//! allocated to the container rather than anything that the client itself calls root. IDs
+//! that are not mapped into the namespace of the daemon at all are reported as the overflow
+//! IDs, usually 65534, which would otherwise be mistaken for `nobody`.
+//!
+//! [`PeerCredentials::is_unmapped`] detects the latter, and [`PeerAllowlist`] never allows
+//! such a peer. [`IdMap`] translates between the IDs inside the namespace of a peer and the
+//! IDs of the daemon, e.g. to log which user a container client claims to be.
+//!
+//! [`PeerAllowlist`]: crate::credentials::PeerAllowlist
+
+use crate::credentials::PeerCredentials;
+use std::{fs, io, os::unix::fs::MetadataExt, sync::OnceLock};
+
+/// Used when `/proc/sys/kernel/overflow{u,g}id` cannot be read. This is the kernel default.
+const DEFAULT_OVERFLOW_ID: u32 = 65534;
+
+/// Which kind of ID a map translates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdKind {
+    User,
+    Group,
+}
+
+/// One line of an ID map: `count` IDs starting at `inside` in the namespace of the process are
+/// mapped to IDs starting at `outside` in the namespace of the reader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IdRange {
+    inside: u32,
+    outside: u32,
+    count: u32,
+}
+
+/// The UID or GID map of a process, as seen from the user namespace of this process. See
+/// `user_namespaces(7)`. The map of a process in the same namespace as this process is relative
+/// to the parent namespace instead.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    ranges: Vec<IdRange>,
+}
+
+impl IdMap {
+    /// Read the map of the process `pid` from `/proc/<pid>/uid_map` or `gid_map`.
+    pub fn of_process(pid: i32, kind: IdKind) -> io::Result<Self> {
+        let file = match kind {
+            IdKind::User => "uid_map",
+            IdKind::Group => "gid_map",
+        };
+        let contents = fs::read_to_string(format!("/proc/{pid}/{file}"))?;
+        IdMap::parse(&contents).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid contents of {file}"),
+            )
+        })
+    }
+
+    /// Parse the contents of an ID map file.
+    pub fn parse(contents: &str) -> Option<Self> {
+        let ranges = contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let mut fields = line.split_whitespace().map(|field| field.parse::<u32>());
+                let range = IdRange {
+                    inside: fields.next()?.ok()?,
+                    outside: fields.next()?.ok()?,
+                    count: fields.next()?.ok()?,
+                };
+                fields.next().is_none().then_some(range)
+            })
+            .collect::<Option<_>>()?;
+        Some(IdMap { ranges })
+    }
+
+    /// Translate an ID of this namespace, such as one reported by `SO_PEERCRED`, into the
+    /// namespace of the process. Returns `None` if the ID is not mapped into it.
+    pub fn to_inside(&self, outside: u32) -> Option<u32> {
+        self.ranges.iter().find_map(|range| {
+            let offset = outside.checked_sub(range.outside)?;
+            (offset < range.count).then(|| range.inside + offset)
+        })
+    }
+
+    /// Translate an ID in the namespace of the process into the namespace of this process.
+    /// Returns `None` if the ID is not mapped into it.
+    pub fn to_outside(&self, inside: u32) -> Option<u32> {
+        self.ranges.iter().find_map(|range| {
+            let offset = inside.checked_sub(range.inside)?;
+            (offset < range.count).then(|| range.outside + offset)
+        })
+    }
+
+    /// Whether every ID is mapped to itself, as in the initial user namespace.
+    pub fn is_identity(&self) -> bool {
+        self.ranges
+            .iter()
+            .all(|range| range.inside == range.outside)
+    }
+}
+
+/// Whether the process `pid` is in the same user namespace as this process.
+pub fn same_user_namespace(pid: i32) -> io::Result<bool> {
+    let ours = fs::metadata("/proc/self/ns/user")?;
+    let theirs = fs::metadata(format!("/proc/{pid}/ns/user"))?;
+    Ok((ours.dev(), ours.ino()) == (theirs.dev(), theirs.ino()))
+}
+
+impl PeerCredentials {
+    /// Whether the UID or GID of the peer has no counterpart in the user namespace of this
+    /// process, in which case the kernel reports the overflow ID instead. Nothing is known
+    /// about the user of such a peer.
+    pub fn is_unmapped(&self) -> bool {
+        self.uid() == overflow_id(IdKind::User) || self.gid() == overflow_id(IdKind::Group)
+    }
+
+    /// Return the UID of the peer as seen inside its own user namespace, e.g. 0 for root in a
+    /// container whose root is mapped to an unprivileged UID of the host. Fails if the PID of
+    /// the peer is unknown, or if the UID is not mapped into its namespace.
+    ///
+    /// The peer may have exited and its PID been reused, so use this for diagnostics rather
+    /// than authorization.
+    pub fn uid_in_peer_namespace(&self) -> io::Result<u32> {
+        let pid = self
+            .pid()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "The peer PID is unknown"))?;
+        if same_user_namespace(pid)? {
+            return Ok(self.uid());
+        }
+        IdMap::of_process(pid, IdKind::User)?
+            .to_inside(self.uid())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "The UID is not mapped into the namespace of the peer",
+                )
+            })
+    }
+}
+
+/// Return the ID that the kernel reports for IDs that are not mapped into this namespace.
+fn overflow_id(kind: IdKind) -> u32 {
+    static UID: OnceLock<u32> = OnceLock::new();
+    static GID: OnceLock<u32> = OnceLock::new();
+    let (id, file) = match kind {
+        IdKind::User => (&UID, "/proc/sys/kernel/overflowuid"),
+        IdKind::Group => (&GID, "/proc/sys/kernel/overflowgid"),
+    };
+    *id.get_or_init(|| {
+        fs::read_to_string(file)
+            .ok()
+            .and_then(|id| id.trim().parse().ok())
+            .unwrap_or(DEFAULT_OVERFLOW_ID)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_id_map() {
+        let map = IdMap::parse("         0     100000      65536\n").unwrap();
+        assert_eq!(map.to_inside(100000), Some(0));
+        assert_eq!(map.to_inside(101000), Some(1000));
+        assert_eq!(map.to_inside(1000), None);
+        assert_eq!(map.to_outside(0), Some(100000));
+        assert_eq!(map.to_outside(65536), None);
+        assert!(!map.is_identity());
+
+        let map = IdMap::parse("0 0 4294967295").unwrap();
+        assert!(map.is_identity());
+        assert!(IdMap::parse("0 0").is_none());
+    }
+
+    #[test]
+    fn test_own_namespace() {
+        let pid = std::process::id() as i32;
+        assert!(same_user_namespace(pid).unwrap());
+        IdMap::of_process(pid, IdKind::User).unwrap();
+        IdMap::of_process(pid, IdKind::Group).unwrap();
+    }
+}
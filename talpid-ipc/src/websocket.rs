@@ -0,0 +1,216 @@
+//! Bridge that exposes an endpoint over WebSocket on a loopback port, for debugging.
+//!
+//! Browsers cannot open sockets or pipes, so a debug console running in a browser connects to
+//! the bridge instead, which relays each WebSocket connection to the endpoint. The endpoint and
+//! its protocol are unchanged, and the bridge is just another client of it: binary messages
+//! carry the bytes of the connection, i.e. the handshake and frames of the native protocol,
+//! split at arbitrary points. Text messages are not accepted.
+//!
+//! Any web page may connect to a loopback port, so clients must present an [`AuthToken`] in
+//! the query of the URL, e.g. `ws://127.0.0.1:<port>/?token=<hex>`, see [`AuthToken::to_hex`].
+//! Requests without the token are refused before the endpoint is connected to. The bridge is
+//! meant to be opt-in, and should only be started when the user has asked for it.
+
+pub use crate::tcp::AuthToken;
+use crate::{Endpoint, tcp};
+use futures::{SinkExt, StreamExt};
+use std::{io, net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+use tokio_tungstenite::tungstenite::{
+    self, Message,
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+};
+
+/// Maximum number of bytes from the endpoint that are sent in one message.
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// Relays authenticated WebSocket connections to an IPC endpoint.
+pub struct WebSocketBridge {
+    listener: TcpListener,
+    endpoint: String,
+    token: AuthToken,
+}
+
+impl WebSocketBridge {
+    /// Listen on `address` and relay connections to the endpoint at `endpoint`. `address` must
+    /// be a loopback address such as `127.0.0.1` or `::1`. Fails with
+    /// [`io::ErrorKind::InvalidInput`] for any other address.
+    pub async fn bind(
+        address: SocketAddr,
+        endpoint: impl Into<String>,
+        token: AuthToken,
+    ) -> io::Result<Self> {
+        if !address.ip().is_loopback() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{address} is not a loopback address"),
+            ));
+        }
+        Ok(WebSocketBridge {
+            listener: TcpListener::bind(address).await?,
+            endpoint: endpoint.into(),
+            token,
+        })
+    }
+
+    /// Return the address that the bridge listens on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept and relay connections until accepting fails. At most [`tcp::MAX_RELAYED`]
+    /// connections are relayed at the same time.
+    pub async fn run(self) -> io::Result<()> {
+        let relayed = Arc::new(Semaphore::new(tcp::MAX_RELAYED));
+        loop {
+            let permit = relayed
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(io::Error::other)?;
+            let (stream, peer) = self.listener.accept().await?;
+            let endpoint = self.endpoint.clone();
+            let token = self.token.clone();
+            tokio::spawn(async move {
+                if let Err(error) = relay(stream, &endpoint, &token).await {
+                    log::debug!("WebSocket bridge connection from {peer} ended: {error}");
+                }
+                drop(permit);
+            });
+        }
+    }
+}
+
+async fn relay(stream: TcpStream, endpoint: &str, token: &AuthToken) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let authorize = |request: &Request, response: Response| {
+        if presented_token(request).is_some_and(|presented| token.matches(&presented)) {
+            Ok(response)
+        } else {
+            let mut response = ErrorResponse::new(Some("Invalid token".to_owned()));
+            *response.status_mut() = StatusCode::UNAUTHORIZED;
+            Err(response)
+        }
+    };
+    let websocket = tokio::time::timeout(
+        tcp::AUTH_TIMEOUT,
+        tokio_tungstenite::accept_hdr_async(stream, authorize),
+    )
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No handshake was received"))?
+    .map_err(into_io_error)?;
+
+    let connection = Endpoint::connect(endpoint).await?;
+    let (mut reader, mut writer) = tokio::io::split(connection);
+    let (mut sink, mut messages) = websocket.split();
+
+    let to_endpoint = async {
+        while let Some(message) = messages.next().await {
+            match message.map_err(into_io_error)? {
+                Message::Binary(data) => writer.write_all(&data).await?,
+                Message::Text(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Text messages are not supported",
+                    ));
+                }
+                Message::Close(_) => break,
+                // Pings are answered by tungstenite
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => (),
+            }
+        }
+        Ok(())
+    };
+    let from_endpoint = async {
+        let mut buffer = vec![0u8; MAX_MESSAGE_LEN];
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            sink.send(Message::binary(buffer[..read].to_vec()))
+                .await
+                .map_err(into_io_error)?;
+        }
+        sink.close().await.map_err(into_io_error)
+    };
+
+    // Either side closing ends the relay, which closes the other side
+    tokio::select! {
+        result = to_endpoint => result,
+        result = from_endpoint => result,
+    }
+}
+
+/// Return the token in the query of the request URI, if any.
+fn presented_token(request: &Request) -> Option<AuthToken> {
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .and_then(AuthToken::from_hex)
+}
+
+fn into_io_error(error: tungstenite::Error) -> io::Error {
+    match error {
+        tungstenite::Error::Io(error) => error,
+        error => io::Error::other(error),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_relay() {
+        let mut endpoint = crate::testing::EphemeralEndpoint::new().unwrap();
+
+        let token = AuthToken::generate();
+        let path = endpoint.path().to_owned();
+        let bridge = WebSocketBridge::bind((Ipv4Addr::LOCALHOST, 0).into(), path, token.clone())
+            .await
+            .unwrap();
+        let address = bridge.local_addr().unwrap();
+        tokio::spawn(bridge.run());
+
+        let url = format!("ws://{address}/?token={}", token.to_hex());
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        client
+            .send(Message::binary(b"hello".to_vec()))
+            .await
+            .unwrap();
+        let mut server = endpoint.accept().await.unwrap();
+        let mut received = [0u8; 5];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+
+        server.write_all(b"world").await.unwrap();
+        let message = client.next().await.unwrap().unwrap();
+        assert_eq!(message.into_data().as_ref(), b"world");
+
+        // A client without the right token is refused
+        let url = format!("ws://{address}/?token={}", AuthToken::generate().to_hex());
+        assert!(tokio_tungstenite::connect_async(url).await.is_err());
+        let url = format!("ws://{address}/");
+        assert!(tokio_tungstenite::connect_async(url).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_non_loopback() {
+        let address = (Ipv4Addr::UNSPECIFIED, 0).into();
+        let error = WebSocketBridge::bind(address, "unused", AuthToken::generate())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
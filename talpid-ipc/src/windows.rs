@@ -0,0 +1,945 @@
+#[cfg(any(feature = "client", feature = "server"))]
+use crate::backoff::Backoff;
+#[cfg(feature = "client")]
+use crate::backoff::{ConstantBackoff, Jitter};
+use crate::{EndpointError, PeerAddress, metrics::PeerInfo};
+#[cfg(feature = "server")]
+use crate::{
+    backoff::ExponentialBackoff,
+    context::{Operation, ResultExt},
+};
+#[cfg(feature = "server")]
+use futures::{StreamExt, stream::FuturesUnordered};
+#[cfg(feature = "server")]
+use std::future::Future;
+#[cfg(feature = "client")]
+use std::path::Path;
+#[cfg(any(feature = "client", feature = "server"))]
+use std::time::Duration;
+use std::{
+    ffi::{OsStr, c_void},
+    io, iter, mem,
+    os::windows::{
+        ffi::OsStrExt,
+        io::{AsRawHandle, FromRawHandle, IntoRawHandle, OwnedHandle},
+    },
+    path::PathBuf,
+    pin::Pin,
+    ptr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+#[cfg(feature = "client")]
+use tokio::net::windows::named_pipe::ClientOptions;
+#[cfg(feature = "server")]
+use tokio::net::windows::named_pipe::ServerOptions;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::windows::named_pipe::{NamedPipeClient, NamedPipeServer},
+};
+#[cfg(feature = "server")]
+use windows_sys::Win32::Security::SECURITY_ATTRIBUTES;
+use windows_sys::Win32::{
+    Foundation::{
+        ERROR_ACCESS_DENIED, ERROR_FILE_NOT_FOUND, ERROR_PIPE_BUSY, ERROR_SEM_TIMEOUT, HANDLE,
+        HANDLE_FLAG_INHERIT, LocalFree, STATUS_SUCCESS, SetHandleInformation,
+    },
+    Security::{
+        Authorization::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1},
+        Cryptography::{BCRYPT_USE_SYSTEM_PREFERRED_RNG, BCryptGenRandom},
+        PSECURITY_DESCRIPTOR, RevertToSelf,
+    },
+    System::{
+        Pipes::{ImpersonateNamedPipeClient, WaitNamedPipeW},
+        SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX},
+    },
+};
+
+/// Time to wait before retrying to connect when all pipe instances are busy. Clients that
+/// reconnect at the same time, e.g. after the daemon has restarted, retry at different times.
+#[cfg(feature = "client")]
+const PIPE_BUSY_BACKOFF: Jitter<ConstantBackoff> =
+    Jitter::new(ConstantBackoff::new(Duration::from_millis(50)), 0.5);
+
+/// Number of pipe instances that wait for clients at the same time, unless set otherwise. Clients
+/// that start together, such as the GUI, the CLI and the tray icon, get one each, rather than
+/// waiting for the server to accept the previous one and create the next instance.
+#[cfg(feature = "server")]
+const DEFAULT_PENDING_INSTANCES: usize = 4;
+
+/// Time to wait before retrying to create a pipe instance after it has failed.
+#[cfg(feature = "server")]
+const CREATE_BACKOFF: ExponentialBackoff =
+    ExponentialBackoff::new(Duration::from_millis(100), Duration::from_secs(10));
+
+/// Everyone may read from and write to the pipe, including creating new instances of it.
+const SDDL_EVERYONE_CREATE: &str = "D:(A;;GRGW;;;WD)";
+/// Everyone may read from and write to the pipe, but not create new instances of it.
+/// `0x12008b` is `FILE_GENERIC_READ | FILE_WRITE_DATA`.
+const SDDL_EVERYONE_CONNECT: &str = "D:(A;;0x12008b;;;WD)";
+/// SYSTEM and administrators have full access. Inherited entries are ignored.
+const SDDL_ADMINS_ONLY: &str = "D:P(A;;GA;;;SY)(A;;GA;;;BA)";
+
+/// Ready-made security descriptors for the pipe, for use with
+/// [`crate::Endpoint::set_dacl_preset`]. SYSTEM and administrators always have full access.
+/// Everyone else that is let in may only connect, not create new instances of the pipe, so they
+/// cannot impersonate the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DaclPreset {
+    /// Only SYSTEM and administrators may connect.
+    AdminsOnly,
+    /// Every user that has logged on may connect, but not anonymous users or guests.
+    AuthenticatedUsers,
+    /// Members of the group with the given SID may connect, such as `S-1-5-32-545` for the
+    /// local users group. This may also be the SID of a single user.
+    CustomGroup(String),
+}
+
+impl DaclPreset {
+    fn sddl(&self) -> io::Result<String> {
+        let connect = match self {
+            DaclPreset::AdminsOnly => return Ok(SDDL_ADMINS_ONLY.to_owned()),
+            DaclPreset::AuthenticatedUsers => "AU",
+            DaclPreset::CustomGroup(sid) => {
+                // Anything else could add entries of its own to the descriptor
+                let valid = sid.strip_prefix("S-").is_some_and(|rest| {
+                    !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit() || c == '-')
+                });
+                if !valid {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Invalid SID: {sid}"),
+                    ));
+                }
+                sid
+            }
+        };
+        Ok(format!("{SDDL_ADMINS_ONLY}(A;;0x12008b;;;{connect})"))
+    }
+}
+
+/// Security descriptor applied to the named pipe when it is created.
+#[derive(Clone, Default)]
+pub struct SecurityAttributes {
+    descriptor: Option<Arc<SecurityDescriptor>>,
+}
+
+impl SecurityAttributes {
+    /// Use the default security descriptor of the process.
+    pub fn empty() -> Self {
+        SecurityAttributes::default()
+    }
+
+    /// Allow everyone to connect to the pipe.
+    pub fn allow_everyone_connect() -> io::Result<Self> {
+        Self::from_sddl(SDDL_EVERYONE_CONNECT)
+    }
+
+    /// Allow everyone to connect to the pipe and to create new instances of it.
+    pub fn allow_everyone_create() -> io::Result<Self> {
+        Self::from_sddl(SDDL_EVERYONE_CREATE)
+    }
+
+    /// Use one of the ready-made security descriptors.
+    pub fn from_preset(preset: &DaclPreset) -> io::Result<Self> {
+        Self::from_sddl(&preset.sddl()?)
+    }
+
+    /// Use a security descriptor described by an SDDL string.
+    pub fn from_sddl(sddl: &str) -> io::Result<Self> {
+        Ok(SecurityAttributes {
+            descriptor: Some(Arc::new(SecurityDescriptor::from_sddl(sddl)?)),
+        })
+    }
+
+    /// File modes do not exist on Windows. This is a no-op.
+    pub fn set_mode(self, _mode: u32) -> io::Result<Self> {
+        Ok(self)
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn as_raw(&self) -> Option<SECURITY_ATTRIBUTES> {
+        self.descriptor
+            .as_ref()
+            .map(|descriptor| SECURITY_ATTRIBUTES {
+                nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+                lpSecurityDescriptor: descriptor.0,
+                // Child processes, such as tunnel helpers, must never get the pipe
+                bInheritHandle: 0,
+            })
+    }
+}
+
+/// Self-relative security descriptor allocated by `ConvertStringSecurityDescriptorToSecurityDescriptorW`.
+struct SecurityDescriptor(PSECURITY_DESCRIPTOR);
+
+// SAFETY: The descriptor is never mutated after creation, and is freed exactly once
+unsafe impl Send for SecurityDescriptor {}
+// SAFETY: The descriptor is never mutated after creation
+unsafe impl Sync for SecurityDescriptor {}
+
+impl SecurityDescriptor {
+    fn from_sddl(sddl: &str) -> io::Result<Self> {
+        let sddl: Vec<u16> = OsStr::new(sddl)
+            .encode_wide()
+            .chain(iter::once(0))
+            .collect();
+        let mut descriptor: PSECURITY_DESCRIPTOR = ptr::null_mut();
+        // SAFETY: `sddl` is a null-terminated wide string, and `descriptor` is a valid out pointer
+        let result = unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                sddl.as_ptr(),
+                SDDL_REVISION_1,
+                &mut descriptor,
+                ptr::null_mut(),
+            )
+        };
+        if result == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SecurityDescriptor(descriptor))
+    }
+}
+
+impl Drop for SecurityDescriptor {
+    fn drop(&mut self) {
+        // SAFETY: The descriptor was allocated with `LocalAlloc` and is not used after this
+        unsafe { LocalFree(self.0 as _) };
+    }
+}
+
+/// Prefix of the names of [ephemeral pipes](ephemeral_pipe_name).
+const EPHEMERAL_PIPE_PREFIX: &str = r"\\.\pipe\mullvad-";
+
+/// Number of random names to try before giving up on finding one that is not in use.
+const EPHEMERAL_PIPE_ATTEMPTS: usize = 8;
+
+/// Return the name of a pipe that does not exist yet, `\\.\pipe\mullvad-<guid>` with a random
+/// GUID, e.g. for pipes between the daemon and a helper that it spawns. Unlike a name derived
+/// from the PID, another process cannot guess it and create the pipe first.
+///
+/// The pipe may still be created by someone else between the check and its creation, so it must
+/// be created as the first instance, as endpoints and [pairs](crate::Endpoint::socketpair) do.
+pub fn ephemeral_pipe_name() -> io::Result<String> {
+    for _ in 0..EPHEMERAL_PIPE_ATTEMPTS {
+        let path = format!("{EPHEMERAL_PIPE_PREFIX}{}", random_guid()?);
+        if !pipe_exists(&path)? {
+            return Ok(path);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "Failed to find a pipe name that is not in use",
+    ))
+}
+
+/// Whether a pipe with the given name exists, without connecting to it.
+fn pipe_exists(path: &str) -> io::Result<bool> {
+    let wide: Vec<u16> = OsStr::new(path)
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect();
+    // SAFETY: `wide` is a null-terminated wide string
+    if unsafe { WaitNamedPipeW(wide.as_ptr(), 1) } != 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error
+        .raw_os_error()
+        .and_then(|code| u32::try_from(code).ok())
+    {
+        Some(ERROR_FILE_NOT_FOUND) => Ok(false),
+        // All instances are busy
+        Some(ERROR_SEM_TIMEOUT) => Ok(true),
+        _ => Err(error),
+    }
+}
+
+/// A random GUID from the random number generator of the system, formatted like
+/// `0f8fad5b-d9cb-469f-a165-70867728950e`.
+fn random_guid() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    // SAFETY: `bytes` is valid for writes of its length, and no algorithm handle is needed with
+    // `BCRYPT_USE_SYSTEM_PREFERRED_RNG`
+    let status = unsafe {
+        BCryptGenRandom(
+            0,
+            bytes.as_mut_ptr(),
+            bytes.len() as u32,
+            BCRYPT_USE_SYSTEM_PREFERRED_RNG,
+        )
+    };
+    if status != STATUS_SUCCESS {
+        return Err(io::Error::other(format!(
+            "Failed to generate random bytes: {status:#x}"
+        )));
+    }
+    let high = u64::from_be_bytes(bytes[..8].try_into().unwrap());
+    let low = u64::from_be_bytes(bytes[8..].try_into().unwrap());
+    // Mark it as a version 4 GUID of the RFC 4122 variant
+    let high = (high & !0xf000) | 0x4000;
+    let low = (low & !(0xc << 60)) | (0x8 << 60);
+    Ok(format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    ))
+}
+
+/// Options used when creating the pipe.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone)]
+pub struct ListenOptions {
+    /// Number of pipe instances that wait for clients at the same time.
+    pub pending_instances: usize,
+    /// Decides when to retry creating pipe instances after it has failed.
+    pub create_backoff: Arc<dyn Backoff>,
+}
+
+#[cfg(feature = "server")]
+impl Default for ListenOptions {
+    fn default() -> Self {
+        ListenOptions {
+            pending_instances: DEFAULT_PENDING_INSTANCES,
+            create_backoff: Arc::new(CREATE_BACKOFF),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+type PendingConnect = Pin<Box<dyn Future<Output = io::Result<NamedPipeServer>> + Send>>;
+
+/// The first instance of a pipe, which has been created with the security attributes, but is
+/// not listened on yet. See [`crate::Endpoint::bind`]. Nobody else can create the pipe while
+/// its first instance exists, so unlike a socket file, it cannot be replaced in between.
+#[cfg(feature = "server")]
+pub struct BoundSocket {
+    path: String,
+    instance: NamedPipeServer,
+    security_attributes: SecurityAttributes,
+}
+
+#[cfg(feature = "server")]
+impl BoundSocket {
+    pub fn bind(
+        path: String,
+        security_attributes: SecurityAttributes,
+        _options: &ListenOptions,
+    ) -> io::Result<Self> {
+        let instance =
+            create_listener(&path, &security_attributes, true).context(Operation::Bind, &path)?;
+        Ok(BoundSocket {
+            path,
+            instance,
+            security_attributes,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+#[cfg(feature = "server")]
+pub struct Incoming {
+    path: Arc<str>,
+    security_attributes: SecurityAttributes,
+    /// Pipe instances waiting for a client to connect.
+    pending: FuturesUnordered<PendingConnect>,
+    /// Number of pipe instances that could not be created, and are yet to be retried.
+    missing: usize,
+    /// Fires when creating the missing instances should be retried.
+    retry: Option<Pin<Box<tokio::time::Sleep>>>,
+    create_backoff: Arc<dyn Backoff>,
+    /// Number of consecutive failures to create an instance.
+    create_failures: u32,
+    /// Error to yield once no instances are left, after the backoff has given up.
+    failed: Option<io::Error>,
+}
+
+#[cfg(feature = "server")]
+impl Incoming {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The path, to attach to errors without copying it.
+    pub fn shared_path(&self) -> &Arc<str> {
+        &self.path
+    }
+
+    pub fn bind(
+        path: String,
+        security_attributes: SecurityAttributes,
+        options: &ListenOptions,
+    ) -> io::Result<Self> {
+        let mut incoming = Incoming::new(path, security_attributes, options);
+        for i in 0..options.pending_instances.max(1) {
+            incoming.add_instance(i == 0)?;
+        }
+        Ok(incoming)
+    }
+
+    /// Listen on `instance`, a pipe instance that was created by someone else, and create the
+    /// other instances like [`Self::bind`].
+    ///
+    /// # Safety
+    ///
+    /// `instance` must be a server instance of the pipe at `path` that was created in overlapped
+    /// mode, and that is not used elsewhere.
+    pub unsafe fn from_instance(
+        instance: OwnedHandle,
+        path: String,
+        security_attributes: SecurityAttributes,
+        options: &ListenOptions,
+    ) -> io::Result<Self> {
+        // SAFETY: The caller guarantees that this is an overlapped pipe server instance, and
+        // ownership of it is passed on
+        let server = unsafe { NamedPipeServer::from_raw_handle(instance.into_raw_handle()) }
+            .context(Operation::Bind, &path)?;
+        Incoming::with_first_instance(server, path, security_attributes, options)
+    }
+
+    /// Listen on the first instance of a pipe, which was created by [`BoundSocket::bind`], and
+    /// create the other instances like [`Self::bind`].
+    pub fn from_bound(bound: BoundSocket, options: &ListenOptions) -> io::Result<Self> {
+        Incoming::with_first_instance(
+            bound.instance,
+            bound.path,
+            bound.security_attributes,
+            options,
+        )
+    }
+
+    fn with_first_instance(
+        server: NamedPipeServer,
+        path: String,
+        security_attributes: SecurityAttributes,
+        options: &ListenOptions,
+    ) -> io::Result<Self> {
+        let mut incoming = Incoming::new(path, security_attributes, options);
+        incoming.push_instance(server);
+        for _ in 1..options.pending_instances.max(1) {
+            incoming.add_instance(false)?;
+        }
+        Ok(incoming)
+    }
+
+    fn new(path: String, security_attributes: SecurityAttributes, options: &ListenOptions) -> Self {
+        Incoming {
+            path: path.into(),
+            security_attributes,
+            pending: FuturesUnordered::new(),
+            missing: 0,
+            retry: None,
+            create_backoff: options.create_backoff.clone(),
+            create_failures: 0,
+            failed: None,
+        }
+    }
+
+    /// Stop listening. The pipe is gone once its instances are closed, so unlike on Unix, there
+    /// is nothing to remove.
+    pub fn close(self) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<Connection>>> {
+        self.poll_retry(cx);
+        if self.pending.is_empty()
+            && let Some(error) = self.failed.take()
+        {
+            return Poll::Ready(Some(Err(error)));
+        }
+        // With no instances left, wait for the retry timer instead of ending the stream
+        let Poll::Ready(Some(result)) = self.pending.poll_next_unpin(cx) else {
+            return Poll::Pending;
+        };
+        // Replace the instance that was used up, whether it was connected to or failed
+        self.missing += 1;
+        self.create_missing();
+        Poll::Ready(Some(result.map(Connection::Server)))
+    }
+
+    /// Retry creating the missing instances once the retry timer has fired.
+    fn poll_retry(&mut self, cx: &mut Context<'_>) {
+        let Some(retry) = &mut self.retry else {
+            return;
+        };
+        if retry.as_mut().poll(cx).is_pending() {
+            return;
+        }
+        self.retry = None;
+        self.create_missing();
+        // Register the new timer, if any, with the waker
+        if let Some(retry) = &mut self.retry {
+            let _ = retry.as_mut().poll(cx);
+        }
+    }
+
+    /// Create the missing instances. If that fails, e.g. because the system is out of
+    /// resources, retry later with exponential backoff, so that the server recovers on its own.
+    fn create_missing(&mut self) {
+        if self.retry.is_some() {
+            return;
+        }
+        while self.missing > 0 {
+            if let Err(error) = self.add_instance(false) {
+                self.create_failures = self.create_failures.saturating_add(1);
+                match self.create_backoff.delay(self.create_failures) {
+                    Some(delay) => {
+                        log::error!(
+                            "Failed to create named pipe instance, retrying in {delay:?}: {error}"
+                        );
+                        self.retry = Some(Box::pin(tokio::time::sleep(delay)));
+                    }
+                    None => {
+                        log::error!("Failed to create named pipe instance, giving up: {error}");
+                        self.failed = Some(error);
+                    }
+                }
+                return;
+            }
+            self.missing -= 1;
+        }
+        self.create_failures = 0;
+        self.failed = None;
+    }
+
+    fn add_instance(&mut self, first_pipe_instance: bool) -> io::Result<()> {
+        let server = create_listener(&self.path, &self.security_attributes, first_pipe_instance)
+            .context(Operation::Bind, &self.path)?;
+        self.push_instance(server);
+        Ok(())
+    }
+
+    /// Wait for a client to connect to `server`.
+    fn push_instance(&mut self, server: NamedPipeServer) {
+        self.pending.push(Box::pin(async move {
+            server.connect().await?;
+            Ok(server)
+        }));
+    }
+}
+
+#[cfg(feature = "server")]
+fn create_listener(
+    path: &str,
+    security_attributes: &SecurityAttributes,
+    first_pipe_instance: bool,
+) -> io::Result<NamedPipeServer> {
+    let mut options = ServerOptions::new();
+    options
+        .first_pipe_instance(first_pipe_instance)
+        .reject_remote_clients(true);
+
+    match security_attributes.as_raw() {
+        Some(mut attributes) => {
+            // SAFETY: `attributes` points to a valid security descriptor that outlives the call
+            unsafe {
+                options.create_with_security_attributes_raw(
+                    path,
+                    &mut attributes as *mut SECURITY_ATTRIBUTES as *mut c_void,
+                )
+            }
+        }
+        None => options.create(path),
+    }
+}
+
+/// Connect to the pipe at `path`. While all instances are busy, retry after the delays decided
+/// by `busy`, or every 25 to 50 ms if it is `None`.
+#[cfg(feature = "client")]
+pub async fn connect(path: &Path, busy: Option<&dyn Backoff>) -> io::Result<Connection> {
+    let busy = busy.unwrap_or(&PIPE_BUSY_BACKOFF);
+    let mut attempt = 0u32;
+    loop {
+        let error = match ClientOptions::new().open(path) {
+            Ok(client) => return Ok(Connection::Client(client)),
+            Err(error) if error.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => error,
+            Err(error) => return Err(error),
+        };
+        attempt = attempt.saturating_add(1);
+        let Some(delay) = busy.delay(attempt) else {
+            return Err(error);
+        };
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Why connecting to a pipe failed, for the errors that frontends are likely to see and
+/// should explain to the user. See [`PipeErrorKind::of`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PipeErrorKind {
+    /// No pipe exists by that name, i.e. the daemon is not running (`ERROR_FILE_NOT_FOUND`).
+    NotFound,
+    /// The DACL of the pipe does not let the client connect (`ERROR_ACCESS_DENIED`).
+    AccessDenied,
+    /// All pipe instances stayed busy for as long as the client was willing to wait, i.e. the
+    /// daemon is overloaded (`ERROR_PIPE_BUSY`).
+    Busy,
+    /// Waiting for a pipe instance timed out (`ERROR_SEM_TIMEOUT`).
+    TimedOut,
+}
+
+impl PipeErrorKind {
+    /// Classify an error returned when connecting to a pipe, e.g. by
+    /// [`crate::Endpoint::connect`]. Returns `None` for any other error.
+    pub fn of(error: &io::Error) -> Option<Self> {
+        let error = EndpointError::of(error).map_or(error, EndpointError::io_error);
+        let code = u32::try_from(error.raw_os_error()?).ok()?;
+        match code {
+            ERROR_FILE_NOT_FOUND => Some(PipeErrorKind::NotFound),
+            ERROR_ACCESS_DENIED => Some(PipeErrorKind::AccessDenied),
+            ERROR_PIPE_BUSY => Some(PipeErrorKind::Busy),
+            ERROR_SEM_TIMEOUT => Some(PipeErrorKind::TimedOut),
+            _ => None,
+        }
+    }
+}
+
+pub enum Connection {
+    Server(NamedPipeServer),
+    Client(NamedPipeClient),
+}
+
+/// `FILE_COMPLETION_INFORMATION` of the native API, which `windows-sys` only has with the driver
+/// kit bindings.
+#[repr(C)]
+struct FileCompletionInformation {
+    port: HANDLE,
+    key: *mut c_void,
+}
+
+/// `IO_STATUS_BLOCK` of the native API.
+#[repr(C)]
+struct IoStatusBlock {
+    status: isize,
+    information: usize,
+}
+
+/// `FileReplaceCompletionInformation` of `FILE_INFORMATION_CLASS`.
+const FILE_REPLACE_COMPLETION_INFORMATION: i32 = 61;
+
+#[link(name = "ntdll")]
+unsafe extern "system" {
+    fn NtSetInformationFile(
+        handle: HANDLE,
+        io_status: *mut IoStatusBlock,
+        information: *const c_void,
+        length: u32,
+        class: i32,
+    ) -> i32;
+    fn RtlNtStatusToDosError(status: i32) -> u32;
+}
+
+/// Remove the association of `handle` with an I/O completion port, so that it can be registered
+/// with another one, e.g. by a runtime in another process. A handle can otherwise only ever be
+/// associated with a single completion port. This requires Windows 8.1.
+pub fn detach_completion_port(handle: &OwnedHandle) -> io::Result<()> {
+    let information = FileCompletionInformation {
+        port: 0,
+        key: ptr::null_mut(),
+    };
+    let mut io_status = IoStatusBlock {
+        status: 0,
+        information: 0,
+    };
+    // SAFETY: The handle is valid for the lifetime of `handle`, and the pointers point to
+    // structures of the expected layout that outlive the call
+    let status = unsafe {
+        NtSetInformationFile(
+            handle.as_raw_handle() as HANDLE,
+            &mut io_status,
+            (&information as *const FileCompletionInformation).cast(),
+            mem::size_of::<FileCompletionInformation>() as u32,
+            FILE_REPLACE_COMPLETION_INFORMATION,
+        )
+    };
+    if status < 0 {
+        // SAFETY: Converting a status code has no memory safety implications
+        let code = unsafe { RtlNtStatusToDosError(status) };
+        return Err(io::Error::from_raw_os_error(code as i32));
+    }
+    Ok(())
+}
+
+/// Identifies the user of a peer by its SID, such as `S-1-5-18`.
+pub type PeerUser = String;
+
+/// Return the SID of the user that runs the client process of `connection`.
+pub fn peer_user(connection: &Connection) -> io::Result<PeerUser> {
+    crate::identity::client_identity(connection, None).map(|identity| identity.user().to_owned())
+}
+
+/// Return the name of the pipe of `connection`, which is all that is known about its peer.
+pub fn peer_address(_connection: &Connection, endpoint: Option<&str>) -> io::Result<PeerAddress> {
+    Ok(endpoint.map_or(PeerAddress::Unnamed, |name| {
+        PeerAddress::Pipe(name.to_owned())
+    }))
+}
+
+/// Find out which process the client of `connection` is, without opening it, see
+/// [`PeerInfo::complete`].
+pub fn peer_info(connection: &Connection) -> PeerInfo {
+    PeerInfo {
+        user: None,
+        pid: crate::identity::client_process_id(connection).ok(),
+        exe: None,
+    }
+}
+
+/// Return the path of the executable of the process `pid`.
+pub(crate) fn process_exe(pid: u32) -> Option<PathBuf> {
+    crate::identity::process_image(pid).ok()
+}
+
+/// Let child processes inherit the handle of `connection`, or prevent it. Handles are created
+/// without inheritance.
+pub fn set_inheritable(connection: &Connection, inheritable: bool) -> io::Result<()> {
+    let handle = match connection {
+        Connection::Server(server) => server.as_raw_handle(),
+        Connection::Client(client) => client.as_raw_handle(),
+    };
+    let flags = if inheritable { HANDLE_FLAG_INHERIT } else { 0 };
+    // SAFETY: The handle is valid for the lifetime of `connection`
+    if unsafe { SetHandleInformation(handle as HANDLE, HANDLE_FLAG_INHERIT, flags) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Number of bytes of physical memory of the machine.
+pub(crate) fn physical_memory() -> io::Result<u64> {
+    // SAFETY: All-zero is a valid value for this plain struct
+    let mut status: MEMORYSTATUSEX = unsafe { std::mem::zeroed() };
+    status.dwLength = std::mem::size_of::<MEMORYSTATUSEX>() as u32;
+    // SAFETY: `status` is valid for writes, and its length has been set
+    if unsafe { GlobalMemoryStatusEx(&mut status) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(status.ullTotalPhys)
+}
+
+impl Connection {
+    /// Read what has already been received, without waiting.
+    pub fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Server(server) => server.try_read(buf),
+            Connection::Client(client) => client.try_read(buf),
+        }
+    }
+
+    /// Wait until the pipe can be written to, i.e. until the runtime has finished writing what
+    /// was written before.
+    pub async fn writable(&self) -> io::Result<()> {
+        match self {
+            Connection::Server(server) => server.writable().await,
+            Connection::Client(client) => client.writable().await,
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Server(server) => Pin::new(server).poll_read(cx, buf),
+            Connection::Client(client) => Pin::new(client).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Server(server) => Pin::new(server).poll_write(cx, buf),
+            Connection::Client(client) => Pin::new(client).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Server(server) => Pin::new(server).poll_flush(cx),
+            Connection::Client(client) => Pin::new(client).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Server(server) => Pin::new(server).poll_shutdown(cx),
+            Connection::Client(client) => Pin::new(client).poll_shutdown(cx),
+        }
+    }
+}
+
+impl crate::Connection {
+    /// Run `f` in the security context of the client connected to this pipe. This can be used to
+    /// perform operations "as the client", such as resolving paths in the user profile of the
+    /// client. The thread reverts to its own security context before this function returns, even
+    /// if `f` panics.
+    ///
+    /// Impersonation applies to the current thread only, which is why `f` cannot be async.
+    /// Some data must have been read from the pipe before the client can be impersonated. This
+    /// fails for connections established by a client.
+    pub fn impersonate_client<T>(&self, f: impl FnOnce() -> T) -> io::Result<T> {
+        impersonate(&self.inner, f)
+    }
+}
+
+/// Run `f` in the security context of the client of `connection`, see
+/// [`crate::Connection::impersonate_client`].
+pub(crate) fn impersonate<T>(connection: &Connection, f: impl FnOnce() -> T) -> io::Result<T> {
+    let Connection::Server(server) = connection else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Only the server end of a pipe can impersonate its client",
+        ));
+    };
+
+    // SAFETY: The handle is a valid named pipe handle for the lifetime of `server`
+    if unsafe { ImpersonateNamedPipeClient(server.as_raw_handle() as HANDLE) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let _revert = RevertOnDrop;
+    Ok(f())
+}
+
+/// Reverts the current thread to its own security context when dropped.
+struct RevertOnDrop;
+
+impl Drop for RevertOnDrop {
+    fn drop(&mut self) {
+        // SAFETY: Reverting impersonation has no preconditions
+        if unsafe { RevertToSelf() } == 0 {
+            // Continuing to run with the client's token would be a privilege problem
+            log::error!(
+                "Failed to revert impersonation of IPC client: {}",
+                io::Error::last_os_error()
+            );
+            std::process::abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dacl_presets() {
+        assert_eq!(
+            DaclPreset::AuthenticatedUsers.sddl().unwrap(),
+            "D:P(A;;GA;;;SY)(A;;GA;;;BA)(A;;0x12008b;;;AU)"
+        );
+        let users = DaclPreset::CustomGroup("S-1-5-32-545".to_owned());
+        assert!(
+            users
+                .sddl()
+                .unwrap()
+                .ends_with("(A;;0x12008b;;;S-1-5-32-545)")
+        );
+        let injected = DaclPreset::CustomGroup("S-1-5-32-545)(A;;GA;;;WD".to_owned());
+        assert!(injected.sddl().is_err());
+
+        for preset in [
+            DaclPreset::AdminsOnly,
+            DaclPreset::AuthenticatedUsers,
+            users,
+        ] {
+            SecurityAttributes::from_preset(&preset).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_pipe_error_kind() {
+        let busy = io::Error::from_raw_os_error(ERROR_PIPE_BUSY as i32);
+        assert_eq!(PipeErrorKind::of(&busy), Some(PipeErrorKind::Busy));
+        let busy =
+            crate::context::with_context(busy, Operation::Connect, Some(r"\\.\pipe\x".into()));
+        assert_eq!(PipeErrorKind::of(&busy), Some(PipeErrorKind::Busy));
+        assert_eq!(
+            PipeErrorKind::of(&io::Error::from(io::ErrorKind::Other)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_pipe_name() {
+        let path = ephemeral_pipe_name().unwrap();
+        let guid = path.strip_prefix(EPHEMERAL_PIPE_PREFIX).unwrap();
+        assert_eq!(guid.len(), 36);
+        assert_eq!(guid.as_bytes()[14], b'4');
+        assert_ne!(ephemeral_pipe_name().unwrap(), path);
+
+        assert!(!pipe_exists(&path).unwrap());
+        let _server = tokio::net::windows::named_pipe::ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&path)
+            .unwrap();
+        assert!(pipe_exists(&path).unwrap());
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_bind_before_listening() {
+        use futures::StreamExt;
+
+        let path = ephemeral_pipe_name().unwrap();
+        let bound = crate::Endpoint::new(path.clone()).bind().unwrap();
+        // Nobody else can take the name once the pipe is bound
+        assert!(pipe_exists(&path).unwrap());
+        assert!(create_listener(&path, &SecurityAttributes::empty(), true).is_err());
+
+        let mut incoming = bound.incoming().unwrap();
+        let _client = crate::Endpoint::connect(&path).await.unwrap();
+        incoming.next().await.unwrap().unwrap();
+        // The other instances are created once the pipe is listened on
+        let _client = crate::Endpoint::connect(&path).await.unwrap();
+        incoming.next().await.unwrap().unwrap();
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_pending_instances() {
+        use futures::StreamExt;
+
+        let path = ephemeral_pipe_name().unwrap();
+        let options = ListenOptions::default();
+        let mut incoming =
+            Incoming::bind(path.clone(), SecurityAttributes::empty(), &options).unwrap();
+        // Every client gets an instance at once, without the server accepting in between
+        let clients: Vec<_> = (0..DEFAULT_PENDING_INSTANCES)
+            .map(|_| ClientOptions::new().open(&path).unwrap())
+            .collect();
+        let error = ClientOptions::new().open(&path).err().unwrap();
+        assert_eq!(error.raw_os_error(), Some(ERROR_PIPE_BUSY as i32));
+
+        let accepted = futures::stream::poll_fn(|cx| incoming.poll_accept(cx))
+            .take(clients.len())
+            .collect::<Vec<_>>()
+            .await;
+        assert!(accepted.iter().all(Result::is_ok));
+        // The instances that were used up have been replaced
+        ClientOptions::new().open(&path).unwrap();
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_connect_not_found() {
+        // Nothing listens on a new ephemeral name
+        let path = ephemeral_pipe_name().unwrap();
+        let error = connect(Path::new(&path), None).await.err().unwrap();
+        assert_eq!(PipeErrorKind::of(&error), Some(PipeErrorKind::NotFound));
+    }
+}
@@ -0,0 +1,110 @@
+//! Bridge that lets clients inside WSL reach an endpoint on the Windows host.
+//!
+//! Named pipes cannot be opened from WSL, so the bridge listens on a loopback TCP port and relays
+//! each connection to the endpoint. TCP ports are not protected by file permissions, so every
+//! client must first send an [`AuthToken`] that has been shared with it out of band, e.g. by
+//! writing it to a file that only the intended user can read. Connections that do not present
+//! the token are closed before anything is relayed.
+
+pub use crate::tcp::{AuthToken, TOKEN_LEN};
+use crate::{Endpoint, tcp};
+use std::{io, net::SocketAddr, sync::Arc};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+
+/// Relays authenticated TCP connections to an IPC endpoint.
+pub struct WslBridge {
+    listener: TcpListener,
+    endpoint: String,
+    token: AuthToken,
+}
+
+impl WslBridge {
+    /// Listen on `address` and relay connections to the endpoint at `endpoint`. `address` should
+    /// be a loopback address, or the address of the virtual WSL network adapter. Never expose
+    /// the bridge on other interfaces.
+    pub async fn bind(
+        address: SocketAddr,
+        endpoint: impl Into<String>,
+        token: AuthToken,
+    ) -> io::Result<Self> {
+        Ok(WslBridge {
+            listener: TcpListener::bind(address).await?,
+            endpoint: endpoint.into(),
+            token,
+        })
+    }
+
+    /// Return the address that the bridge listens on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept and relay connections until accepting fails. At most [`tcp::MAX_RELAYED`]
+    /// connections are relayed at the same time.
+    pub async fn run(self) -> io::Result<()> {
+        let relayed = Arc::new(Semaphore::new(tcp::MAX_RELAYED));
+        loop {
+            let permit = relayed
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(io::Error::other)?;
+            let (stream, peer) = self.listener.accept().await?;
+            let endpoint = self.endpoint.clone();
+            let token = self.token.clone();
+            tokio::spawn(async move {
+                if let Err(error) = relay(stream, &endpoint, &token).await {
+                    log::debug!("WSL bridge connection from {peer} ended: {error}");
+                }
+                drop(permit);
+            });
+        }
+    }
+}
+
+async fn relay(mut stream: TcpStream, endpoint: &str, token: &AuthToken) -> io::Result<()> {
+    tcp::authenticate(&mut stream, token).await?;
+    let mut connection = Endpoint::connect(endpoint).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut connection).await?;
+    Ok(())
+}
+
+/// Connect to a bridge at `address`, e.g. from inside WSL. The returned stream is relayed to
+/// the endpoint on the other side of the bridge.
+pub async fn connect(address: SocketAddr, token: &AuthToken) -> io::Result<TcpStream> {
+    tcp::connect(address, token).await
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_relay() {
+        let mut endpoint = crate::testing::EphemeralEndpoint::new().unwrap();
+
+        let token = AuthToken::generate();
+        let path = endpoint.path().to_owned();
+        let bridge = WslBridge::bind((Ipv4Addr::LOCALHOST, 0).into(), path, token.clone())
+            .await
+            .unwrap();
+        let address = bridge.local_addr().unwrap();
+        tokio::spawn(bridge.run());
+
+        let mut client = connect(address, &token).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut server = endpoint.accept().await.unwrap();
+        let mut received = [0u8; 5];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+
+        // A client with the wrong token is disconnected
+        let mut client = connect(address, &AuthToken::generate()).await.unwrap();
+        assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
+}
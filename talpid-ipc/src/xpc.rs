@@ -0,0 +1,630 @@
+//! Connections over XPC, the IPC mechanism of launchd, on macOS.
+//!
+//! The daemon advertises a Mach service in the `MachServices` dictionary of its launchd job,
+//! and launchd starts it on demand when a client first connects. [`XpcListener`] is a stream of
+//! [`XpcConnection`]s, like [`crate::Incoming`], and connections are byte streams that
+//! implement [`AsyncRead`] and [`AsyncWrite`], so that [`crate::frame`] and the layers on top of
+//! it work unchanged. Every write is sent as one XPC message.
+//!
+//! Servers that are built on [`Connection`] serve XPC connections with
+//! [`XpcListener::into_incoming`] or [`IpcServer::serve_xpc`], which bridge each of them to a
+//! [`Connection`], see [`XpcConnection::into_connection`].
+//!
+//! Peers are identified by the kernel, see [`XpcConnection::audit_token`]. A listener can also
+//! require its peers to satisfy a code signing requirement, which is checked by XPC before any
+//! message is delivered.
+//!
+//! Nothing is buffered without limit. Connections that are not accepted while
+//! [`INCOMING_CAPACITY`] others are waiting are closed. Messages are handed over
+//! [`RECEIVE_CAPACITY`] at a time, after which XPC holds the rest back until the connection is
+//! read again.
+//!
+//! XPC delivers events to handlers that are Objective-C blocks, which it copies as the block
+//! runtime does. The blocks are built here as stack blocks with copy and dispose helpers, so
+//! that each copy holds a reference to the handler, and the handler is dropped once XPC is done
+//! with its connection.
+//!
+//! [`IpcServer::serve_xpc`]: crate::server::IpcServer::serve_xpc
+
+use crate::{Connection, Endpoint, credentials::PeerCredentials};
+use bytes::{Buf, Bytes};
+use futures::{Stream, StreamExt};
+use std::{
+    ffi::{CString, c_char, c_int, c_ulong, c_void},
+    io,
+    pin::Pin,
+    ptr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc,
+};
+
+#[allow(non_camel_case_types)]
+type xpc_object_t = *mut c_void;
+#[allow(non_camel_case_types)]
+type xpc_type_t = *const c_void;
+
+unsafe extern "C" {
+    static _xpc_type_connection: c_void;
+    static _xpc_type_dictionary: c_void;
+    static _xpc_error_connection_invalid: c_void;
+    static _NSConcreteStackBlock: c_void;
+
+    fn xpc_connection_create_mach_service(
+        name: *const c_char,
+        targetq: *mut c_void,
+        flags: u64,
+    ) -> xpc_object_t;
+    fn xpc_connection_set_event_handler(connection: xpc_object_t, handler: *mut c_void);
+    fn xpc_connection_set_peer_code_signing_requirement(
+        connection: xpc_object_t,
+        requirement: *const c_char,
+    ) -> c_int;
+    fn xpc_connection_resume(connection: xpc_object_t);
+    fn xpc_connection_cancel(connection: xpc_object_t);
+    fn xpc_connection_send_message(connection: xpc_object_t, message: xpc_object_t);
+    fn xpc_connection_get_audit_token(connection: xpc_object_t, token: *mut AuditToken);
+
+    fn xpc_get_type(object: xpc_object_t) -> xpc_type_t;
+    fn xpc_retain(object: xpc_object_t) -> xpc_object_t;
+    fn xpc_release(object: xpc_object_t);
+    fn xpc_dictionary_create(
+        keys: *const *const c_char,
+        values: *const xpc_object_t,
+        count: usize,
+    ) -> xpc_object_t;
+    fn xpc_dictionary_set_data(
+        dictionary: xpc_object_t,
+        key: *const c_char,
+        bytes: *const c_void,
+        length: usize,
+    );
+    fn xpc_dictionary_get_data(
+        dictionary: xpc_object_t,
+        key: *const c_char,
+        length: *mut usize,
+    ) -> *const c_void;
+}
+
+const XPC_CONNECTION_MACH_SERVICE_LISTENER: u64 = 1 << 0;
+const XPC_CONNECTION_MACH_SERVICE_PRIVILEGED: u64 = 1 << 1;
+
+/// Key of the bytes in every message.
+const DATA_KEY: &[u8] = b"data\0";
+/// Largest number of bytes sent in one message.
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// Number of connections that may wait to be accepted.
+pub const INCOMING_CAPACITY: usize = 64;
+
+/// Number of received messages that may wait to be read from a connection.
+pub const RECEIVE_CAPACITY: usize = 16;
+
+/// The audit token of a peer, as recorded by the kernel. Unlike a PID, it also records the
+/// version of the PID, which changes when the PID is reused by another process, so it is what
+/// should be passed on to APIs that check the peer, e.g. `SecCodeCreateWithXPCMessage`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AuditToken(pub [u32; 8]);
+
+impl AuditToken {
+    /// Effective user ID of the process.
+    pub fn euid(&self) -> u32 {
+        self.0[1]
+    }
+
+    /// Effective group ID of the process.
+    pub fn egid(&self) -> u32 {
+        self.0[2]
+    }
+
+    pub fn pid(&self) -> i32 {
+        self.0[5] as i32
+    }
+
+    /// Version of the PID, which tells processes that got the same PID apart.
+    pub fn pid_version(&self) -> u32 {
+        self.0[7]
+    }
+}
+
+/// The peer of a [`Connection`] that was bridged from XPC, which is found in its
+/// [extensions](Connection::extensions).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XpcPeer {
+    pub credentials: PeerCredentials,
+    pub audit_token: AuditToken,
+}
+
+/// Listens for connections to a Mach service.
+pub struct XpcListener {
+    listener: XpcObject,
+    incoming: mpsc::Receiver<XpcConnection>,
+}
+
+impl XpcListener {
+    /// Listen on the Mach service `name`, which must be registered in the `MachServices`
+    /// dictionary of the launchd job of this process.
+    pub fn bind(name: &str) -> io::Result<Self> {
+        Self::bind_inner(name, None)
+    }
+
+    /// Like [`Self::bind`], but only let in peers that satisfy the code signing `requirement`,
+    /// in the language of `csreq(1)`, such as `anchor apple generic and certificate leaf
+    /// [subject.OU] = "TEAMID"`. Other peers are disconnected before any messages are
+    /// delivered. Requirements can also demand entitlements, such as
+    /// `entitlement["com.example.ipc-client"] exists`. This requires macOS 12.
+    pub fn bind_with_requirement(name: &str, requirement: &str) -> io::Result<Self> {
+        Self::bind_inner(name, Some(c_string(requirement)?))
+    }
+
+    fn bind_inner(name: &str, requirement: Option<CString>) -> io::Result<Self> {
+        let name = c_string(name)?;
+        // SAFETY: `name` is a valid C string, and a null queue means the default target queue
+        let listener = unsafe {
+            xpc_connection_create_mach_service(
+                name.as_ptr(),
+                ptr::null_mut(),
+                XPC_CONNECTION_MACH_SERVICE_LISTENER,
+            )
+        };
+        Ok(Self::listen(XpcObject::new(listener)?, requirement))
+    }
+
+    /// Start accepting peers on `listener`, which must not have been resumed yet.
+    fn listen(listener: XpcObject, requirement: Option<CString>) -> Self {
+        let (incoming_tx, incoming) = mpsc::channel(INCOMING_CAPACITY);
+        let incoming_tx = Mutex::new(Some(incoming_tx));
+        set_event_handler(&listener, move |event| {
+            // SAFETY: Events are valid XPC objects for the duration of the handler
+            let event_type = unsafe { xpc_get_type(event) };
+            if event_type != &raw const _xpc_type_connection {
+                // The listener has been cancelled or its service has gone away
+                log::debug!("XPC listener was invalidated");
+                incoming_tx.lock().unwrap().take();
+                return;
+            }
+            // SAFETY: The peer connection is only valid during the handler unless retained
+            let peer = XpcObject(unsafe { xpc_retain(event) });
+            if let Some(requirement) = &requirement {
+                // SAFETY: `peer` has not been resumed yet, and `requirement` is a valid C string
+                let result = unsafe {
+                    xpc_connection_set_peer_code_signing_requirement(peer.0, requirement.as_ptr())
+                };
+                if result != 0 {
+                    log::error!("Failed to set code signing requirement of XPC peer: {result}");
+                    reject(&peer);
+                    return;
+                }
+            }
+            let incoming_tx = incoming_tx.lock().unwrap();
+            let Some(incoming_tx) = &*incoming_tx else {
+                reject(&peer);
+                return;
+            };
+            match incoming_tx.try_reserve() {
+                Ok(permit) => permit.send(XpcConnection::new(peer)),
+                Err(_) => {
+                    log::warn!("Closing XPC connection, since too many are waiting to be accepted");
+                    reject(&peer);
+                }
+            }
+        });
+        // SAFETY: The event handler has been set
+        unsafe { xpc_connection_resume(listener.0) };
+        XpcListener { listener, incoming }
+    }
+
+    /// Bridge every accepted connection to a [`Connection`], see
+    /// [`XpcConnection::into_connection`]. This must be polled within a Tokio runtime.
+    pub fn into_incoming(self) -> impl Stream<Item = io::Result<Connection>> + Send + Unpin {
+        self.map(|connection| connection?.into_connection())
+    }
+}
+
+#[cfg(feature = "server")]
+impl crate::server::IpcServer {
+    /// Like [`Self::serve`], but serve the connections that `listener` accepts, bridged to
+    /// [`Connection`]s.
+    pub async fn serve_xpc<H, F>(self, listener: XpcListener, handler: H) -> io::Result<()>
+    where
+        H: Fn(Connection) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.serve_incoming(listener.into_incoming(), handler).await
+    }
+}
+
+impl Stream for XpcListener {
+    type Item = io::Result<XpcConnection>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming
+            .poll_recv(cx)
+            .map(|connection| connection.map(Ok))
+    }
+}
+
+impl Drop for XpcListener {
+    fn drop(&mut self) {
+        // SAFETY: The listener is a valid connection, and cancelling it more than once is fine
+        unsafe { xpc_connection_cancel(self.listener.0) };
+    }
+}
+
+/// A connection to the peer of an XPC service.
+pub struct XpcConnection {
+    connection: XpcObject,
+    received: mpsc::Receiver<Bytes>,
+    /// Received bytes that have not been read yet.
+    read_buf: Bytes,
+}
+
+impl XpcConnection {
+    /// Connect to the Mach service `name` advertised by a launch agent of the current user.
+    pub fn connect(name: &str) -> io::Result<Self> {
+        Self::connect_inner(name, 0)
+    }
+
+    /// Connect to the Mach service `name` advertised by a launch daemon, such as the daemon.
+    pub fn connect_privileged(name: &str) -> io::Result<Self> {
+        Self::connect_inner(name, XPC_CONNECTION_MACH_SERVICE_PRIVILEGED)
+    }
+
+    fn connect_inner(name: &str, flags: u64) -> io::Result<Self> {
+        let name = c_string(name)?;
+        // SAFETY: `name` is a valid C string, and a null queue means the default target queue
+        let connection =
+            unsafe { xpc_connection_create_mach_service(name.as_ptr(), ptr::null_mut(), flags) };
+        Ok(XpcConnection::new(XpcObject::new(connection)?))
+    }
+
+    /// Start receiving messages on a connection that has not been resumed yet.
+    fn new(connection: XpcObject) -> Self {
+        let (received_tx, received) = mpsc::channel(RECEIVE_CAPACITY);
+        let received_tx = Mutex::new(Some(received_tx));
+        set_event_handler(&connection, move |event| {
+            // SAFETY: Events are valid XPC objects for the duration of the handler
+            if unsafe { xpc_get_type(event) } != &raw const _xpc_type_dictionary {
+                // An error, after which no more messages are delivered. The peer may also
+                // have exited, which interrupts the connection.
+                if event.cast_const() == &raw const _xpc_error_connection_invalid {
+                    log::debug!("XPC connection was invalidated");
+                } else {
+                    log::debug!("XPC connection was interrupted");
+                }
+                received_tx.lock().unwrap().take();
+                return;
+            }
+            let mut len = 0;
+            // SAFETY: `event` is a dictionary, and the key is a valid C string
+            let data =
+                unsafe { xpc_dictionary_get_data(event, DATA_KEY.as_ptr().cast(), &mut len) };
+            if data.is_null() {
+                log::warn!("Ignoring XPC message without data");
+                return;
+            }
+            // SAFETY: The data is `len` bytes long, and valid until the handler returns
+            let data = Bytes::copy_from_slice(unsafe {
+                std::slice::from_raw_parts(data.cast::<u8>(), len)
+            });
+            let received_tx = received_tx.lock().unwrap().clone();
+            if let Some(received_tx) = received_tx {
+                // Handlers run on a dispatch queue of the connection, so waiting here holds
+                // back the next messages of this connection until it is read again
+                let _ = received_tx.blocking_send(data);
+            }
+        });
+        // SAFETY: The event handler has been set
+        unsafe { xpc_connection_resume(connection.0) };
+        XpcConnection {
+            connection,
+            received,
+            read_buf: Bytes::new(),
+        }
+    }
+
+    /// Return the audit token of the peer, as recorded by the kernel when it connected, or in
+    /// the last message that was received on a connection that this end established.
+    pub fn audit_token(&self) -> AuditToken {
+        let mut token = AuditToken::default();
+        // SAFETY: The connection is valid for the lifetime of `self`, and `token` has the layout
+        // of `audit_token_t`
+        unsafe { xpc_connection_get_audit_token(self.connection.0, &mut token) };
+        token
+    }
+
+    /// Return the effective user and group and the process ID of the peer, from its
+    /// [audit token](Self::audit_token).
+    pub fn peer_credentials(&self) -> PeerCredentials {
+        let token = self.audit_token();
+        PeerCredentials::from_xpc(token.euid(), token.egid(), token.pid())
+    }
+
+    /// Bridge the connection to a [`Connection`], so that it can be served like connections
+    /// that are accepted on an [`Endpoint`]. A task that is spawned on the current Tokio runtime
+    /// copies the bytes between them, until either end is closed. The peer is added to the
+    /// extensions of the connection as an [`XpcPeer`].
+    pub fn into_connection(mut self) -> io::Result<Connection> {
+        let peer = XpcPeer {
+            credentials: self.peer_credentials(),
+            audit_token: self.audit_token(),
+        };
+        let (mut connection, mut bridge) = Endpoint::socketpair()?;
+        connection.extensions_mut().insert(peer);
+        tokio::spawn(async move {
+            if let Err(error) = tokio::io::copy_bidirectional(&mut self, &mut bridge).await {
+                log::debug!("Bridged XPC connection failed: {error}");
+            }
+        });
+        Ok(connection)
+    }
+}
+
+impl AsyncRead for XpcConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read_buf.is_empty() {
+            match ready!(self.received.poll_recv(cx)) {
+                Some(data) => self.read_buf = data,
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let len = self.read_buf.len().min(buf.remaining());
+        buf.put_slice(&self.read_buf[..len]);
+        self.read_buf.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for XpcConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let len = buf.len().min(MAX_MESSAGE_LEN);
+        // SAFETY: An empty dictionary is created, and then owned by `message`
+        let message =
+            XpcObject::new(unsafe { xpc_dictionary_create(ptr::null(), ptr::null(), 0) })?;
+        // SAFETY: `message` is a dictionary, the key is a valid C string, and `buf` holds at
+        // least `len` bytes, which are copied
+        unsafe {
+            xpc_dictionary_set_data(
+                message.0,
+                DATA_KEY.as_ptr().cast(),
+                buf.as_ptr().cast(),
+                len,
+            );
+            // Messages are queued by XPC, so sending never has to wait
+            xpc_connection_send_message(self.connection.0, message.0);
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Cancel the connection. XPC connections cannot be half closed, so nothing more is
+    /// received either.
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // SAFETY: The connection is valid, and cancelling it more than once is fine
+        unsafe { xpc_connection_cancel(self.connection.0) };
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for XpcConnection {
+    fn drop(&mut self) {
+        // SAFETY: The connection is valid, and cancelling it more than once is fine
+        unsafe { xpc_connection_cancel(self.connection.0) };
+    }
+}
+
+/// An XPC object that we hold a reference to.
+struct XpcObject(xpc_object_t);
+
+// SAFETY: XPC objects are reference counted atomically and may be used from any thread
+unsafe impl Send for XpcObject {}
+// SAFETY: See above
+unsafe impl Sync for XpcObject {}
+
+impl XpcObject {
+    /// Take ownership of an object returned by a function that creates one.
+    fn new(object: xpc_object_t) -> io::Result<Self> {
+        if object.is_null() {
+            return Err(io::Error::other("Failed to create XPC object"));
+        }
+        Ok(XpcObject(object))
+    }
+}
+
+impl Drop for XpcObject {
+    fn drop(&mut self) {
+        // SAFETY: We own one reference to the object
+        unsafe { xpc_release(self.0) };
+    }
+}
+
+type EventHandler = dyn Fn(xpc_object_t) + Send + Sync;
+
+/// The layout of a block, as defined by the Clang block ABI, that calls `handler`.
+#[repr(C)]
+struct EventBlock {
+    isa: *const c_void,
+    flags: c_int,
+    reserved: c_int,
+    invoke: unsafe extern "C" fn(*const EventBlock, xpc_object_t),
+    descriptor: *const BlockDescriptor,
+    handler: Arc<EventHandler>,
+}
+
+#[repr(C)]
+struct BlockDescriptor {
+    reserved: c_ulong,
+    size: c_ulong,
+    copy: unsafe extern "C" fn(*mut EventBlock, *const EventBlock),
+    dispose: unsafe extern "C" fn(*mut EventBlock),
+}
+
+/// The block has helpers that the block runtime calls when it copies the block to the heap, and
+/// when it frees a copy.
+const BLOCK_HAS_COPY_DISPOSE: c_int = 1 << 25;
+
+static EVENT_BLOCK_DESCRIPTOR: BlockDescriptor = BlockDescriptor {
+    reserved: 0,
+    size: std::mem::size_of::<EventBlock>() as c_ulong,
+    copy: copy_event_block,
+    dispose: dispose_event_block,
+};
+
+unsafe extern "C" fn invoke_event_block(block: *const EventBlock, event: xpc_object_t) {
+    // SAFETY: XPC only calls copies of the block, which it keeps until it is done calling them
+    let handler = unsafe { &(*block).handler };
+    handler(event);
+}
+
+unsafe extern "C" fn copy_event_block(dst: *mut EventBlock, src: *const EventBlock) {
+    // SAFETY: The runtime has copied `src` to `dst` bit by bit, so the handler of `dst` is a
+    // reference that has not been counted. It is replaced by a counted one without dropping it.
+    unsafe { ptr::write(&raw mut (*dst).handler, Arc::clone(&(*src).handler)) };
+}
+
+unsafe extern "C" fn dispose_event_block(block: *mut EventBlock) {
+    // SAFETY: The runtime is freeing a copy, whose reference was counted by `copy_event_block`
+    unsafe { ptr::drop_in_place(&raw mut (*block).handler) };
+}
+
+fn event_block(handler: impl Fn(xpc_object_t) + Send + Sync + 'static) -> EventBlock {
+    EventBlock {
+        isa: &raw const _NSConcreteStackBlock,
+        flags: BLOCK_HAS_COPY_DISPOSE,
+        reserved: 0,
+        invoke: invoke_event_block,
+        descriptor: &EVENT_BLOCK_DESCRIPTOR,
+        handler: Arc::new(handler),
+    }
+}
+
+/// Let `handler` handle the events of `connection`, which must not have been resumed yet.
+fn set_event_handler(
+    connection: &XpcObject,
+    handler: impl Fn(xpc_object_t) + Send + Sync + 'static,
+) {
+    let block = event_block(handler);
+    // SAFETY: `block` is a valid stack block, which XPC copies before this returns, and
+    // `connection` is valid
+    unsafe { xpc_connection_set_event_handler(connection.0, (&raw const block).cast_mut().cast()) };
+}
+
+/// Close a peer connection that will not be accepted. XPC requires connections to be resumed
+/// before they are released, and resuming requires a handler.
+fn reject(peer: &XpcObject) {
+    set_event_handler(peer, |_| ());
+    // SAFETY: The event handler has been set, and the connection is cancelled once resumed
+    unsafe {
+        xpc_connection_resume(peer.0);
+        xpc_connection_cancel(peer.0);
+    }
+}
+
+fn c_string(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid name"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame::{Frame, FramedConnection};
+    use std::mem::MaybeUninit;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    unsafe extern "C" {
+        fn xpc_connection_create(name: *const c_char, targetq: *mut c_void) -> xpc_object_t;
+        fn xpc_endpoint_create(connection: xpc_object_t) -> xpc_object_t;
+        fn xpc_connection_create_from_endpoint(endpoint: xpc_object_t) -> xpc_object_t;
+    }
+
+    /// Listen anonymously, and connect to the listener, which needs no launchd job.
+    fn anonymous_pair() -> (XpcListener, XpcConnection) {
+        // SAFETY: A null name creates an anonymous listener
+        let listener =
+            XpcObject::new(unsafe { xpc_connection_create(ptr::null(), ptr::null_mut()) }).unwrap();
+        // SAFETY: `listener` is a listener connection
+        let endpoint = XpcObject::new(unsafe { xpc_endpoint_create(listener.0) }).unwrap();
+        let listener = XpcListener::listen(listener, None);
+        // SAFETY: `endpoint` is an endpoint of a listener
+        let client =
+            XpcObject::new(unsafe { xpc_connection_create_from_endpoint(endpoint.0) }).unwrap();
+        (listener, XpcConnection::new(client))
+    }
+
+    #[tokio::test]
+    async fn test_anonymous() {
+        let (listener, mut client) = anonymous_pair();
+        let mut incoming = listener.into_incoming();
+        client.write_all(b"hello").await.unwrap();
+        let connection = incoming.next().await.unwrap().unwrap();
+        let peer = *connection.extensions().get::<XpcPeer>().unwrap();
+        assert_eq!(peer.audit_token.pid(), std::process::id() as i32);
+        assert_eq!(peer.credentials.pid(), Some(peer.audit_token.pid()));
+
+        let mut server = FramedConnection::new(connection);
+        let mut greeting = [0u8; 5];
+        server.get_mut().read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hello");
+
+        // More than fits in one message
+        let large = Frame::data(vec![7u8; 2 * MAX_MESSAGE_LEN + 1]);
+        let mut client = FramedConnection::new(client);
+        let (written, received) = tokio::join!(client.write_frame(&large), server.read_frame());
+        written.unwrap();
+        assert_eq!(received.unwrap(), Some(large));
+
+        drop(client);
+        assert_eq!(server.read_frame().await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_block_lifetime() {
+        let captured = Arc::new(());
+        let called = Arc::new(Mutex::new(0));
+        let block = {
+            let captured = captured.clone();
+            let called = called.clone();
+            event_block(move |_| {
+                let _ = &captured;
+                *called.lock().unwrap() += 1;
+            })
+        };
+
+        // Copy the block as the block runtime does
+        let mut copy = MaybeUninit::<EventBlock>::uninit();
+        // SAFETY: `copy` is valid for writes of one block, and the helpers are called as the
+        // runtime would call them
+        let copy = unsafe {
+            ptr::copy_nonoverlapping(&block, copy.as_mut_ptr(), 1);
+            (EVENT_BLOCK_DESCRIPTOR.copy)(copy.as_mut_ptr(), &block);
+            copy.assume_init()
+        };
+        let copy = std::mem::ManuallyDrop::new(copy);
+        drop(block);
+        assert_eq!(Arc::strong_count(&captured), 2);
+
+        // SAFETY: The copy is valid until it is disposed of
+        unsafe { (copy.invoke)(&*copy, ptr::null_mut()) };
+        assert_eq!(*called.lock().unwrap(), 1);
+
+        let mut copy = copy;
+        // SAFETY: The copy is disposed of once, and not used afterwards
+        unsafe { (EVENT_BLOCK_DESCRIPTOR.dispose)(&mut *copy) };
+        assert_eq!(Arc::strong_count(&captured), 1);
+    }
+}
@@ -19,7 +19,7 @@ pub enum Error {
     CreateRuntime(#[source] io::Error),
 
     #[error("Unable to create IPC transport")]
-    CreateTransport(#[source] tonic::transport::Error),
+    CreateTransport(#[source] talpid_ipc::grpc::Error),
 
     #[error("Unable to parse environment variables from OpenVPN")]
     ParseEnvFailed(#[source] std::str::Utf8Error),
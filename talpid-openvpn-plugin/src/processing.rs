@@ -1,11 +1,7 @@
 use super::{Arguments, Error};
 use std::collections::HashMap;
 
-use futures::TryFutureExt;
-use parity_tokio_ipc::Endpoint as IpcEndpoint;
 use tokio::runtime::{self, Runtime};
-use tonic::transport::{Endpoint, Uri};
-use tower::service_fn;
 
 #[allow(clippy::derive_partial_eq_without_eq)]
 mod proto {
@@ -38,14 +34,8 @@ impl EventProcessor {
 
     async fn spawn_client(
         ipc_path: String,
-    ) -> Result<OpenvpnEventProxyClient<tonic::transport::Channel>, tonic::transport::Error> {
-        // The URI will be ignored
-        let channel = Endpoint::from_static("lttp://[::]:50051")
-            .connect_with_connector(service_fn(move |_: Uri| {
-                IpcEndpoint::connect(ipc_path.clone()).map_ok(hyper_util::rt::tokio::TokioIo::new)
-            }))
-            .await?;
-
+    ) -> Result<OpenvpnEventProxyClient<tonic::transport::Channel>, talpid_ipc::grpc::Error> {
+        let channel = talpid_ipc::grpc::connect(ipc_path).await?;
         Ok(OpenvpnEventProxyClient::new(channel))
     }
 
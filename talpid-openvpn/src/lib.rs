@@ -761,22 +761,13 @@ async fn join_return_first<R>(
 }
 
 mod event_server {
-    use futures::stream::TryStreamExt;
-    use parity_tokio_ipc::Endpoint as IpcEndpoint;
-    use std::{
-        collections::{HashMap, HashSet},
-        pin::Pin,
-        task::{Context, Poll},
-    };
+    use std::collections::{HashMap, HashSet};
+    use talpid_ipc::Endpoint as IpcEndpoint;
     use talpid_tunnel::{EventHook, TunnelMetadata};
     use talpid_types::ErrorExt;
     #[cfg(any(target_os = "macos", target_os = "windows"))]
     use talpid_types::net::proxy::CustomProxy;
-    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-    use tonic::{
-        Request, Response,
-        transport::{Server, server::Connected},
-    };
+    use tonic::{Request, Response};
 
     #[allow(clippy::derive_partial_eq_without_eq)]
     mod proto {
@@ -794,8 +785,8 @@ mod event_server {
         StartServer(#[from] std::io::Error),
 
         /// An error occurred while the server was running.
-        #[error("Tonic error")]
-        TonicError(#[from] tonic::transport::Error),
+        #[error("gRPC server error")]
+        Grpc(#[from] talpid_ipc::grpc::Error),
     }
 
     /// Implements a gRPC service used to process events sent to by OpenVPN.
@@ -1015,54 +1006,17 @@ mod event_server {
         let incoming = endpoint.incoming().map_err(Error::StartServer)?;
         Ok((
             tokio::spawn(async move {
-                Server::builder()
-                    .add_service(OpenvpnEventProxyServer::new(event_proxy))
-                    .serve_with_incoming_shutdown(incoming.map_ok(StreamBox), abort_rx)
-                    .await
-                    .map_err(Error::TonicError)
+                talpid_ipc::grpc::serve_incoming(
+                    incoming,
+                    OpenvpnEventProxyServer::new(event_proxy),
+                    abort_rx,
+                )
+                .await
+                .map_err(Error::Grpc)
             }),
             ipc_path,
         ))
     }
-
-    #[derive(Debug)]
-    pub struct StreamBox<T: AsyncRead + AsyncWrite>(pub T);
-    impl<T: AsyncRead + AsyncWrite> Connected for StreamBox<T> {
-        type ConnectInfo = Option<()>;
-
-        fn connect_info(&self) -> Self::ConnectInfo {
-            None
-        }
-    }
-    impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for StreamBox<T> {
-        fn poll_read(
-            mut self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
-            buf: &mut ReadBuf<'_>,
-        ) -> Poll<std::io::Result<()>> {
-            Pin::new(&mut self.0).poll_read(cx, buf)
-        }
-    }
-    impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for StreamBox<T> {
-        fn poll_write(
-            mut self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
-            buf: &[u8],
-        ) -> Poll<std::io::Result<usize>> {
-            Pin::new(&mut self.0).poll_write(cx, buf)
-        }
-
-        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-            Pin::new(&mut self.0).poll_flush(cx)
-        }
-
-        fn poll_shutdown(
-            mut self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
-        ) -> Poll<std::io::Result<()>> {
-            Pin::new(&mut self.0).poll_shutdown(cx)
-        }
-    }
 }
 
 #[cfg(test)]